@@ -0,0 +1,22 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+use nw_pckt_fwd::bench::{lan_frames, ReceivePath};
+
+/// Throughput of the receive path on a LAN where most traffic is not
+/// forwarded
+fn process_packet(c: &mut Criterion) {
+    let path = ReceivePath::new();
+    let frames = lan_frames();
+    let mut group = c.benchmark_group("capture");
+    group.throughput(Throughput::Elements(frames.len() as u64));
+    group.bench_function("process_packet", |b| {
+        b.iter(|| {
+            for frame in &frames {
+                path.process(black_box(frame));
+            }
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, process_packet);
+criterion_main!(benches);
@@ -0,0 +1,181 @@
+//! Allowlist of the hosts on the internal side that may send through the
+//! forwarder.
+
+use crate::filter::{Decision, Filter, PacketContext};
+use crate::logging::ThrottledWarning;
+use crate::pair::Direction;
+use pnet::util::MacAddr;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::time::Duration;
+use tracing::warn;
+
+/// Minimum time between two warnings about frames from unknown sources
+const WARNING_INTERVAL: Duration = Duration::from_secs(10);
+
+/// IPv4 or IPv6 network in CIDR notation; a bare address is a single host
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct IpNetwork {
+    address: IpAddr,
+    prefix_len: u8,
+}
+
+impl IpNetwork {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.address, ip) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - u32::from(self.prefix_len));
+                let mask = mask.unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - u32::from(self.prefix_len));
+                let mask = mask.unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for IpNetwork {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (address, prefix_len) = match value.split_once('/') {
+            Some((address, prefix_len)) => (address, Some(prefix_len)),
+            None => (value, None),
+        };
+        let address: IpAddr = address
+            .parse()
+            .map_err(|_| format!("'{}' is not an IP address", address))?;
+        let max = if address.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => prefix_len
+                .parse()
+                .ok()
+                .filter(|prefix_len| *prefix_len <= max)
+                .ok_or_else(|| format!("'{}' is not a prefix length up to {}", prefix_len, max))?,
+            None => max,
+        };
+        Ok(IpNetwork {
+            address,
+            prefix_len,
+        })
+    }
+}
+
+impl TryFrom<String> for IpNetwork {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<IpNetwork> for String {
+    fn from(network: IpNetwork) -> Self {
+        network.to_string()
+    }
+}
+
+impl fmt::Display for IpNetwork {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.address, self.prefix_len)
+    }
+}
+
+/// Drops frames entering from the internal side unless their source MAC
+/// and source IP are allowed. An empty list allows any value. Only the
+/// Ethernet and IP headers are looked at, so the filter goes first in the
+/// chain; frames from the external side are not affected.
+pub struct SourceAllowlist {
+    macs: Vec<MacAddr>,
+    networks: Vec<IpNetwork>,
+    warning: ThrottledWarning,
+}
+
+impl SourceAllowlist {
+    pub fn new(macs: Vec<MacAddr>, networks: Vec<IpNetwork>) -> Self {
+        SourceAllowlist {
+            macs,
+            networks,
+            warning: ThrottledWarning::new(WARNING_INTERVAL),
+        }
+    }
+
+    fn allowed(&self, mac: MacAddr, ip: Option<IpAddr>) -> bool {
+        let mac_allowed = self.macs.is_empty() || self.macs.contains(&mac);
+        let ip_allowed = self.networks.is_empty()
+            || ip.is_some_and(|ip| self.networks.iter().any(|network| network.contains(ip)));
+        mac_allowed && ip_allowed
+    }
+
+    /// Warns about `mac` unless a warning was logged within
+    /// [`WARNING_INTERVAL`], in which case it is only counted
+    fn warn(&self, ctx: &PacketContext, mac: MacAddr) {
+        let Some(suppressed) = self.warning.occurred() else {
+            return;
+        };
+        let source = ctx
+            .source_ip()
+            .map_or_else(String::new, |ip| format!(" ({})", ip));
+        warn!(
+            "Frame from {}{} on {} is not in the source allowlist, dropped{}",
+            mac, source, ctx.ingress, suppressed
+        );
+    }
+}
+
+impl Filter for SourceAllowlist {
+    fn name(&self) -> &str {
+        "source-allowlist"
+    }
+
+    fn evaluate(&self, ctx: &PacketContext) -> Decision {
+        if ctx.direction != Direction::Outbound {
+            return Decision::Continue;
+        }
+        let mac = ctx.ethernet.get_source();
+        if self.allowed(mac, ctx.source_ip()) {
+            Decision::Continue
+        } else {
+            self.warn(ctx, mac);
+            Decision::Drop
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_networks_and_macs() {
+        let network: IpNetwork = "192.168.100.0/24".parse().unwrap();
+        assert!(network.contains("192.168.100.7".parse().unwrap()));
+        assert!(!network.contains("192.168.101.7".parse().unwrap()));
+        assert!(!network.contains("::1".parse().unwrap()));
+        assert_eq!(
+            "fd00::1".parse::<IpNetwork>().unwrap().to_string(),
+            "fd00::1/128"
+        );
+        assert!("0.0.0.0/0"
+            .parse::<IpNetwork>()
+            .unwrap()
+            .contains("10.0.0.1".parse().unwrap()));
+        assert!("10.0.0.0/33".parse::<IpNetwork>().is_err());
+        assert!("vm1/24".parse::<IpNetwork>().is_err());
+
+        let mac = MacAddr::new(0x52, 0x54, 0, 0x12, 0x34, 0x56);
+        let allowlist = SourceAllowlist::new(vec![mac], vec![network]);
+        assert!(allowlist.allowed(mac, Some("192.168.100.7".parse().unwrap())));
+        assert!(!allowlist.allowed(mac, Some("10.0.0.1".parse().unwrap())));
+        assert!(!allowlist.allowed(mac, None));
+        assert!(!allowlist.allowed(MacAddr::broadcast(), Some("192.168.100.7".parse().unwrap())));
+        assert!(SourceAllowlist::new(vec![mac], Vec::new()).allowed(mac, None));
+    }
+}
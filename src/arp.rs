@@ -0,0 +1,303 @@
+//! ARP between the interfaces, either forwarded as it is or answered for
+//! the hosts on the external side (proxy ARP).
+
+use crate::filter::{Decision, Filter, PacketContext};
+use crate::hostmac::{ipv4_source, HostMacTable};
+use crate::responder::{Cache, Responder};
+use crate::rewrite::Rewrite;
+use clap::ValueEnum;
+use pnet::ipnetwork::IpNetwork;
+use pnet::packet::arp::{ArpHardwareTypes, ArpOperations, ArpPacket, MutableArpPacket};
+use pnet::packet::ethernet::{EtherTypes, EthernetPacket, MutableEthernetPacket};
+use pnet::packet::ipv4::Ipv4Packet;
+use pnet::packet::Packet;
+use pnet::util::MacAddr;
+use serde::{Deserialize, Serialize};
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tracing::debug;
+
+const ETHERNET_HEADER_LEN: usize = 14;
+const ARP_PACKET_LEN: usize = 28;
+
+/// How ARP frames are handled
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ArpMode {
+    /// Drop ARP like any frame no filter claims
+    Off,
+    /// Forward ARP requests and replies between the interfaces
+    Forward,
+    /// Answer requests from the internal side for hosts learned on the
+    /// external side with the internal interface's MAC
+    Proxy,
+}
+
+/// Sender and target addresses of an Ethernet/IPv4 ARP request
+fn request(frame: &[u8]) -> Option<(MacAddr, Ipv4Addr, Ipv4Addr)> {
+    let eth = EthernetPacket::new(frame)?;
+    if eth.get_ethertype() != EtherTypes::Arp {
+        return None;
+    }
+    let arp = ArpPacket::new(eth.payload())?;
+    let ethernet_ipv4 = arp.get_hardware_type() == ArpHardwareTypes::Ethernet
+        && arp.get_protocol_type() == EtherTypes::Ipv4
+        && arp.get_hw_addr_len() == 6
+        && arp.get_proto_addr_len() == 4;
+    if !ethernet_ipv4 || arp.get_operation() != ArpOperations::Request {
+        return None;
+    }
+    Some((
+        arp.get_sender_hw_addr(),
+        arp.get_sender_proto_addr(),
+        arp.get_target_proto_addr(),
+    ))
+}
+
+/// ARP reply telling `to` that `ip` is at `mac`
+fn reply(mac: MacAddr, ip: Ipv4Addr, to: (MacAddr, Ipv4Addr)) -> Vec<u8> {
+    let mut frame = vec![0; ETHERNET_HEADER_LEN + ARP_PACKET_LEN];
+    let mut eth = MutableEthernetPacket::new(&mut frame).expect("frame is large enough");
+    eth.set_destination(to.0);
+    eth.set_source(mac);
+    eth.set_ethertype(EtherTypes::Arp);
+    let mut arp =
+        MutableArpPacket::new(&mut frame[ETHERNET_HEADER_LEN..]).expect("frame is large enough");
+    arp.set_hardware_type(ArpHardwareTypes::Ethernet);
+    arp.set_protocol_type(EtherTypes::Ipv4);
+    arp.set_hw_addr_len(6);
+    arp.set_proto_addr_len(4);
+    arp.set_operation(ArpOperations::Reply);
+    arp.set_sender_hw_addr(mac);
+    arp.set_sender_proto_addr(ip);
+    arp.set_target_hw_addr(to.0);
+    arp.set_target_proto_addr(to.1);
+    frame
+}
+
+/// Hosts learned from the frames forwarded inwards, answered for on the
+/// internal side with the internal interface's MAC. Addresses on the
+/// internal interface's own subnets are never answered for, as the hosts
+/// there answer themselves.
+pub struct ArpProxy {
+    internal_iface: String,
+    internal_networks: Vec<IpNetwork>,
+    /// MAC the frames sent to a proxied host leave the external interface
+    /// with
+    external_mac: MacAddr,
+    hosts: HostMacTable,
+    responder: Responder,
+    answered: AtomicU64,
+    refused: AtomicU64,
+}
+
+impl ArpProxy {
+    pub fn new(
+        internal_iface: String,
+        internal_networks: Vec<IpNetwork>,
+        external_mac: MacAddr,
+        hosts: HostMacTable,
+        responder: Responder,
+    ) -> Self {
+        ArpProxy {
+            internal_iface,
+            internal_networks,
+            external_mac,
+            hosts,
+            responder,
+            answered: AtomicU64::new(0),
+            refused: AtomicU64::new(0),
+        }
+    }
+
+    fn is_internal(&self, ip: Ipv4Addr) -> bool {
+        self.internal_networks
+            .iter()
+            .any(|network| network.contains(IpAddr::V4(ip)))
+    }
+
+    /// Whether a request from `sender` for `target` is answered; gratuitous
+    /// ARP and requests for internal addresses are not
+    fn answers_for(&self, sender: Ipv4Addr, target: Ipv4Addr) -> bool {
+        sender != target && !self.is_internal(target) && self.hosts.lookup(target).is_some()
+    }
+}
+
+impl Cache for ArpProxy {
+    fn name(&self) -> &str {
+        "arp-proxy"
+    }
+
+    fn answer(&self, ctx: &PacketContext) -> bool {
+        let Some((sender_mac, sender, target)) = request(ctx.ethernet.packet()) else {
+            return false;
+        };
+        if !self.answers_for(sender, target) {
+            return false;
+        }
+        let frame = reply(self.responder.mac(), target, (sender_mac, sender));
+        if !self.responder.send_frame(frame) {
+            self.refused.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+        self.answered.fetch_add(1, Ordering::Relaxed);
+        debug!("ARP request from {} for {} answered", sender, target);
+        true
+    }
+
+    /// One line per learned external host, followed by the request counters
+    fn state(&self) -> Vec<String> {
+        let mut lines = self.hosts.hosts();
+        lines.push(format!(
+            "{} request(s) answered, {} refused",
+            self.answered.load(Ordering::Relaxed),
+            self.refused.load(Ordering::Relaxed)
+        ));
+        lines
+    }
+}
+
+/// Lets ARP through. Without a proxy every ARP frame is forwarded; with
+/// one, only the requests from the internal side it answers for are, and
+/// it answers them in place of forwarding.
+pub struct ArpFilter {
+    proxy: Option<Arc<ArpProxy>>,
+}
+
+impl ArpFilter {
+    pub fn new(proxy: Option<Arc<ArpProxy>>) -> Self {
+        ArpFilter { proxy }
+    }
+}
+
+impl Filter for ArpFilter {
+    fn name(&self) -> &str {
+        "arp"
+    }
+
+    fn evaluate(&self, ctx: &PacketContext) -> Decision {
+        if ctx.ethertype() != EtherTypes::Arp {
+            return Decision::Continue;
+        }
+        let Some(proxy) = &self.proxy else {
+            return Decision::Forward;
+        };
+        if ctx.ingress != proxy.internal_iface {
+            return Decision::Drop;
+        }
+        match request(ctx.ethernet.packet()) {
+            Some((_, sender, target)) if proxy.answers_for(sender, target) => Decision::Forward,
+            Some((_, _, target)) => {
+                proxy.refused.fetch_add(1, Ordering::Relaxed);
+                debug!("ARP request for {} not answered", target);
+                Decision::Drop
+            }
+            None => Decision::Drop,
+        }
+    }
+}
+
+/// Learns the MAC the external hosts are reached at from the IPv4 frames
+/// forwarded inwards. Goes first, ahead of any rewrite of their source.
+pub struct LearnExternalHosts {
+    proxy: Arc<ArpProxy>,
+}
+
+impl LearnExternalHosts {
+    pub fn new(proxy: Arc<ArpProxy>) -> Self {
+        LearnExternalHosts { proxy }
+    }
+}
+
+impl Rewrite for LearnExternalHosts {
+    fn name(&self) -> &str {
+        "arp-proxy"
+    }
+
+    fn apply(&self, frame: &mut Vec<u8>) -> bool {
+        if let Some((mac, ip)) = ipv4_source(frame) {
+            let unicast_ip = !(ip.is_unspecified() || ip.is_multicast() || ip.is_broadcast());
+            if unicast_ip && !mac.is_multicast() && !self.proxy.is_internal(ip) {
+                self.proxy.hosts.learn(ip, mac);
+            }
+        }
+        true
+    }
+}
+
+/// Addresses IPv4 frames the internal hosts sent to the internal interface's
+/// MAC, as answered by the proxy, to the learned MAC of their destination,
+/// with the external interface as source.
+pub struct ProxiedMac {
+    proxy: Arc<ArpProxy>,
+}
+
+impl ProxiedMac {
+    pub fn new(proxy: Arc<ArpProxy>) -> Self {
+        ProxiedMac { proxy }
+    }
+}
+
+impl Rewrite for ProxiedMac {
+    fn name(&self) -> &str {
+        "arp-proxy"
+    }
+
+    fn apply(&self, frame: &mut Vec<u8>) -> bool {
+        let Some(eth) = EthernetPacket::new(frame) else {
+            return false;
+        };
+        let proxied = eth.get_ethertype() == EtherTypes::Ipv4
+            && eth.get_destination() == self.proxy.responder.mac();
+        if !proxied {
+            return true;
+        }
+        let Some(ip) = Ipv4Packet::new(&frame[ETHERNET_HEADER_LEN..]) else {
+            return true;
+        };
+        let Some(mac) = self.proxy.hosts.lookup(ip.get_destination()) else {
+            return true;
+        };
+        let mut eth = MutableEthernetPacket::new(frame).expect("header was parsed above");
+        eth.set_destination(mac);
+        eth.set_source(self.proxy.external_mac);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replies_to_requests() {
+        let client = (
+            MacAddr::new(0x52, 0x54, 0, 0, 0, 7),
+            Ipv4Addr::new(192, 168, 100, 7),
+        );
+        let own = MacAddr::new(2, 0, 0, 0, 0, 1);
+        let cast = Ipv4Addr::new(192, 0, 2, 5);
+
+        let answer = reply(own, cast, client);
+        assert_eq!(request(&answer), None);
+        let eth = EthernetPacket::new(&answer).unwrap();
+        assert_eq!((eth.get_source(), eth.get_destination()), (own, client.0));
+        let arp = ArpPacket::new(eth.payload()).unwrap();
+        assert_eq!(arp.get_operation(), ArpOperations::Reply);
+        assert_eq!(
+            (arp.get_sender_hw_addr(), arp.get_sender_proto_addr()),
+            (own, cast)
+        );
+        assert_eq!(arp.get_target_proto_addr(), client.1);
+
+        // A request is the reply with the roles swapped
+        let mut asked = reply(client.0, client.1, (MacAddr::broadcast(), cast));
+        MutableArpPacket::new(&mut asked[ETHERNET_HEADER_LEN..])
+            .unwrap()
+            .set_operation(ArpOperations::Request);
+        assert_eq!(request(&asked), Some((client.0, client.1, cast)));
+        asked.truncate(ETHERNET_HEADER_LEN + 20);
+        assert_eq!(request(&asked), None);
+    }
+}
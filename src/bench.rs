@@ -0,0 +1,100 @@
+//! Entry points for the benchmarks in `benches/`, which only see the public
+//! API. Compiled with the `bench` feature.
+
+use crate::capture::{process_packet, ForwardPath};
+use crate::filter::{FilterChain, UdpPortFilter, SSDP_PORT};
+use crate::link::PacketSink;
+use crate::sender::{spawn_sender, QueuePolicy};
+use pnet::packet::ethernet::{EtherTypes, MutableEthernetPacket};
+use pnet::packet::ip::IpNextHeaderProtocols;
+use pnet::packet::ipv4::MutableIpv4Packet;
+use pnet::packet::udp::MutableUdpPacket;
+use std::collections::HashSet;
+use std::io;
+use std::net::Ipv4Addr;
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::runtime::Runtime;
+use tokio_util::sync::CancellationToken;
+
+/// Sink of a send loop that discards what it is given
+struct Discard;
+
+impl PacketSink for Discard {
+    fn send(&mut self, _frame: &[u8]) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Path forwarding SSDP from `ext0` to `int0`, whose send loop runs on a
+/// runtime of its own and discards the frames
+pub struct ReceivePath {
+    path: ForwardPath,
+    _runtime: Runtime,
+}
+
+impl ReceivePath {
+    pub fn new() -> Self {
+        let runtime = Runtime::new().expect("runtime started");
+        let _guard = runtime.enter();
+        let (queue, _sender) = spawn_sender(
+            "int0",
+            Box::new(Discard),
+            1024,
+            QueuePolicy::DropNewest,
+            false,
+            Arc::default(),
+            None,
+            None,
+            CancellationToken::new(),
+        );
+        let mut filters = FilterChain::new();
+        filters.push(UdpPortFilter::new(HashSet::from([SSDP_PORT])));
+        let path = ForwardPath::plain("ext0", "int0", filters, queue);
+        ReceivePath {
+            path,
+            _runtime: runtime,
+        }
+    }
+
+    /// Handles `frame` as the capture loop of `ext0` does
+    pub fn process(&self, frame: &[u8]) {
+        process_packet(frame, &self.path, Instant::now());
+    }
+}
+
+impl Default for ReceivePath {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Traffic of a LAN where most frames are not forwarded: one in twenty is
+/// SSDP, the rest other UDP
+pub fn lan_frames() -> Vec<Vec<u8>> {
+    (0..100)
+        .map(|i| udp_frame(if i % 20 == 0 { SSDP_PORT } else { 5000 }))
+        .collect()
+}
+
+/// IPv4 multicast frame with an empty UDP datagram to `port`
+fn udp_frame(port: u16) -> Vec<u8> {
+    let mut frame = vec![0; 14 + 20 + 8];
+    let mut eth = MutableEthernetPacket::new(&mut frame).unwrap();
+    eth.set_source([0x02, 0, 0, 0, 0, 1].into());
+    eth.set_destination([0x01, 0, 0x5e, 0x7f, 0xff, 0xfa].into());
+    eth.set_ethertype(EtherTypes::Ipv4);
+    let mut ip = MutableIpv4Packet::new(&mut frame[14..]).unwrap();
+    ip.set_version(4);
+    ip.set_header_length(5);
+    ip.set_total_length(20 + 8);
+    ip.set_ttl(4);
+    ip.set_next_level_protocol(IpNextHeaderProtocols::Udp);
+    ip.set_source(Ipv4Addr::new(192, 168, 100, 5));
+    ip.set_destination(Ipv4Addr::new(239, 255, 255, 250));
+    let mut udp = MutableUdpPacket::new(&mut frame[34..]).unwrap();
+    udp.set_source(50000);
+    udp.set_destination(port);
+    udp.set_length(8);
+    frame
+}
@@ -0,0 +1,381 @@
+//! MAC learning for bridge mode, steering frames to the interface their
+//! destination was last seen on.
+
+use crate::filter::{Decision, Filter, PacketContext};
+use crate::state;
+use pnet::util::MacAddr;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use tracing::debug;
+
+/// Learned address as kept in the state file
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct SavedMac {
+    pub mac: MacAddr,
+    pub iface: String,
+    #[serde(with = "humantime_serde")]
+    pub seen: SystemTime,
+}
+
+/// Source MAC to interface mappings learned from received frames
+pub struct MacTable {
+    max_entries: usize,
+    ttl: Duration,
+    entries: Mutex<HashMap<MacAddr, (Arc<str>, Instant)>>,
+}
+
+impl MacTable {
+    pub fn new(max_entries: usize, ttl: Duration) -> Self {
+        MacTable {
+            max_entries,
+            ttl,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Learned addresses that have not aged out, one line each
+    pub fn state(&self) -> Vec<String> {
+        let entries = self.entries.lock().unwrap();
+        entries
+            .iter()
+            .filter(|(_, (_, seen))| seen.elapsed() < self.ttl)
+            .map(|(mac, (iface, seen))| {
+                let age = Duration::from_secs(seen.elapsed().as_secs());
+                format!(
+                    "{} on {}, seen {} ago",
+                    mac,
+                    iface,
+                    humantime::format_duration(age)
+                )
+            })
+            .collect()
+    }
+
+    /// Learned addresses that have not aged out, for the state file
+    pub fn save(&self, now: Instant, wall: SystemTime) -> Vec<SavedMac> {
+        let entries = self.entries.lock().unwrap();
+        entries
+            .iter()
+            .filter(|(_, (_, seen))| now.duration_since(*seen) < self.ttl)
+            .map(|(mac, (iface, seen))| SavedMac {
+                mac: *mac,
+                iface: iface.to_string(),
+                seen: state::wall_time(*seen, now, wall),
+            })
+            .collect()
+    }
+
+    /// Adds the saved addresses that have not aged out meanwhile, those
+    /// seen last first while there is room
+    pub fn restore(&self, mut saved: Vec<SavedMac>, now: Instant, wall: SystemTime) {
+        saved.sort_by_key(|entry| std::cmp::Reverse(entry.seen));
+        let mut entries = self.entries.lock().unwrap();
+        for entry in saved {
+            let Some(seen) = state::instant(entry.seen, now, wall) else {
+                continue;
+            };
+            if now.duration_since(seen) >= self.ttl || entries.len() >= self.max_entries {
+                continue;
+            }
+            entries
+                .entry(entry.mac)
+                .or_insert((entry.iface.into(), seen));
+        }
+    }
+
+    /// Records that `source` sent a frame on `ingress` and returns the
+    /// interface `destination` was learned on, if it has not aged out.
+    fn learn_and_lookup(
+        &self,
+        source: MacAddr,
+        ingress: &str,
+        destination: MacAddr,
+    ) -> Option<Arc<str>> {
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+        if !is_group(source) {
+            match entries.get_mut(&source) {
+                Some((iface, seen)) if &**iface == ingress => *seen = now,
+                Some((iface, seen)) => {
+                    debug!("MAC {} moved from {} to {}", source, iface, ingress);
+                    *iface = ingress.into();
+                    *seen = now;
+                }
+                None => {
+                    if entries.len() >= self.max_entries {
+                        entries.retain(|_, (_, seen)| now.duration_since(*seen) < self.ttl);
+                    }
+                    if entries.len() >= self.max_entries {
+                        // Evict the least recently seen address to keep the table bounded
+                        if let Some(oldest) = entries
+                            .iter()
+                            .min_by_key(|(_, (_, seen))| *seen)
+                            .map(|(mac, _)| *mac)
+                        {
+                            entries.remove(&oldest);
+                        }
+                    }
+                    debug!("Learned MAC {} on {}", source, ingress);
+                    entries.insert(source, (ingress.into(), now));
+                }
+            }
+        }
+        entries
+            .get(&destination)
+            .filter(|(_, seen)| now.duration_since(*seen) < self.ttl)
+            .map(|(iface, _)| iface.clone())
+    }
+}
+
+/// Drops frames towards `egress` whose destination MAC was learned on a
+/// different interface. Group addresses and unknown destinations are
+/// flooded, so the other filters still decide whether they are forwarded.
+pub struct BridgeFilter {
+    egress: String,
+    table: Arc<MacTable>,
+}
+
+impl BridgeFilter {
+    pub fn new(egress: String, table: Arc<MacTable>) -> Self {
+        BridgeFilter { egress, table }
+    }
+}
+
+impl Filter for BridgeFilter {
+    fn name(&self) -> &str {
+        "bridge"
+    }
+
+    fn evaluate(&self, ctx: &PacketContext) -> Decision {
+        let destination = ctx.ethernet.get_destination();
+        let learned =
+            self.table
+                .learn_and_lookup(ctx.ethernet.get_source(), ctx.ingress, destination);
+        match learned {
+            Some(iface) if *iface != *self.egress => {
+                debug!(
+                    "Frame to {} learned on {}, not sent to {}",
+                    destination, iface, self.egress
+                );
+                Decision::Drop
+            }
+            _ => Decision::Continue,
+        }
+    }
+}
+
+/// Multicast and broadcast addresses have the group bit set
+fn is_group(mac: MacAddr) -> bool {
+    mac.0 & 0x01 != 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::capture::{spawn_capture, ForwardPath};
+    use crate::filter::{FilterChain, UdpPortFilter};
+    use crate::link::memory::{self, VecSink};
+    use crate::sender::{spawn_sender, QueuePolicy};
+    use crate::stats::InterfaceStats;
+    use crate::supervise::Supervision;
+    use crate::testutil::{self, multicast_mac, SSDP_IPV4_GROUP};
+    use std::collections::HashSet;
+    use std::net::Ipv4Addr;
+    use tokio_util::sync::CancellationToken;
+
+    const PORTS: [&str; 3] = ["eth0", "eth1", "vm0"];
+    const PORT: u16 = 5000;
+    const TIMEOUT: Duration = Duration::from_secs(1);
+
+    const HOST_A: MacAddr = MacAddr(0x02, 0, 0, 0, 0, 0x0a);
+    const HOST_B: MacAddr = MacAddr(0x02, 0, 0, 0, 0, 0x0b);
+    const HOST_C: MacAddr = MacAddr(0x02, 0, 0, 0, 0, 0x0c);
+
+    /// UDP frame from `source` to `destination` told apart by `payload`
+    fn frame(source: MacAddr, destination: MacAddr, payload: &str) -> Vec<u8> {
+        testutil::udp_frame(
+            source,
+            destination,
+            Ipv4Addr::new(192, 168, 100, source.5),
+            Ipv4Addr::new(192, 168, 100, 255),
+            50000,
+            PORT,
+            payload.as_bytes(),
+        )
+    }
+
+    /// Waits until each port's sink holds as many frames as `expected` says
+    async fn wait_for(sinks: &[VecSink], expected: [usize; 3]) {
+        let start = Instant::now();
+        while sinks.iter().map(|sink| sink.frames().len()).ne(expected) {
+            assert!(start.elapsed() < TIMEOUT, "frames did not arrive");
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn learns_macs_and_floods_only_what_it_has_not_learned() {
+        let token = CancellationToken::new();
+        let table = Arc::new(MacTable::new(16, Duration::from_secs(60)));
+        let mut sinks = Vec::new();
+        let mut queues = Vec::new();
+        let mut tasks = Vec::new();
+        for port in PORTS {
+            let sink = VecSink::default();
+            let (queue, sender) = spawn_sender(
+                port,
+                Box::new(sink.clone()),
+                16,
+                QueuePolicy::DropNewest,
+                false,
+                Arc::default(),
+                None,
+                None,
+                token.clone(),
+            );
+            sinks.push(sink);
+            queues.push(queue);
+            tasks.push(sender);
+        }
+        let mut inputs = Vec::new();
+        for ingress in PORTS {
+            let paths = PORTS
+                .iter()
+                .zip(&queues)
+                .filter(|(egress, _)| **egress != ingress)
+                .map(|(egress, queue)| {
+                    let mut filters = FilterChain::new();
+                    filters.push(BridgeFilter::new(egress.to_string(), table.clone()));
+                    filters.push(UdpPortFilter::new(HashSet::from([PORT])));
+                    ForwardPath::plain(ingress, egress, filters, queue.clone())
+                })
+                .collect();
+            let (input, source) = memory::source();
+            tasks.push(spawn_capture(
+                Box::new(source),
+                Arc::new(InterfaceStats::new(ingress.to_string())),
+                paths,
+                None,
+                Supervision::default(),
+                None,
+                token.clone(),
+            ));
+            inputs.push(input);
+        }
+        let [eth0, eth1, _] = &inputs[..] else {
+            unreachable!()
+        };
+
+        // Nothing learned about B yet
+        eth0.send(frame(HOST_A, HOST_B, "a-b 1")).unwrap();
+        wait_for(&sinks, [0, 1, 1]).await;
+        // A was learned on eth0
+        eth1.send(frame(HOST_B, HOST_A, "b-a")).unwrap();
+        wait_for(&sinks, [1, 1, 1]).await;
+        // And B on eth1
+        eth0.send(frame(HOST_A, HOST_B, "a-b 2")).unwrap();
+        wait_for(&sinks, [1, 2, 1]).await;
+        // Unknown, broadcast and multicast destinations are flooded
+        eth0.send(frame(HOST_A, HOST_C, "a-c")).unwrap();
+        wait_for(&sinks, [1, 3, 2]).await;
+        eth0.send(frame(HOST_A, MacAddr::broadcast(), "a-all"))
+            .unwrap();
+        wait_for(&sinks, [1, 4, 3]).await;
+        let group = multicast_mac(SSDP_IPV4_GROUP.into());
+        eth1.send(frame(HOST_B, group, "b-group")).unwrap();
+        wait_for(&sinks, [2, 4, 4]).await;
+        // Anything sent where it should not have been is queued before these
+        eth0.send(frame(HOST_A, MacAddr::broadcast(), "a-end"))
+            .unwrap();
+        eth1.send(frame(HOST_B, MacAddr::broadcast(), "b-end"))
+            .unwrap();
+        wait_for(&sinks, [3, 5, 6]).await;
+
+        let expected = [
+            vec![
+                frame(HOST_B, HOST_A, "b-a"),
+                frame(HOST_B, group, "b-group"),
+                frame(HOST_B, MacAddr::broadcast(), "b-end"),
+            ],
+            vec![
+                frame(HOST_A, HOST_B, "a-b 1"),
+                frame(HOST_A, HOST_B, "a-b 2"),
+                frame(HOST_A, HOST_C, "a-c"),
+                frame(HOST_A, MacAddr::broadcast(), "a-all"),
+                frame(HOST_A, MacAddr::broadcast(), "a-end"),
+            ],
+        ];
+        assert_eq!(sinks[0].frames(), expected[0]);
+        assert_eq!(sinks[1].frames(), expected[1]);
+        let mut flooded = sinks[2].frames();
+        let mut expected = vec![
+            frame(HOST_A, HOST_B, "a-b 1"),
+            frame(HOST_A, HOST_C, "a-c"),
+            frame(HOST_A, MacAddr::broadcast(), "a-all"),
+            frame(HOST_B, group, "b-group"),
+            frame(HOST_A, MacAddr::broadcast(), "a-end"),
+            frame(HOST_B, MacAddr::broadcast(), "b-end"),
+        ];
+        // The last two came from different ports at once
+        flooded.sort();
+        expected.sort();
+        assert_eq!(flooded, expected);
+
+        token.cancel();
+        drop(queues);
+        for task in tasks {
+            task.await.unwrap();
+        }
+    }
+
+    #[test]
+    fn forgets_the_least_recently_seen_mac_when_full() {
+        let table = MacTable::new(2, Duration::from_secs(60));
+        table.learn_and_lookup(HOST_A, "eth0", HOST_B);
+        std::thread::sleep(Duration::from_millis(1));
+        table.learn_and_lookup(HOST_B, "eth1", HOST_A);
+        std::thread::sleep(Duration::from_millis(1));
+        assert_eq!(
+            table.learn_and_lookup(HOST_C, "vm0", HOST_A).as_deref(),
+            None
+        );
+        assert_eq!(table.state().len(), 2);
+        assert_eq!(
+            table.learn_and_lookup(HOST_C, "vm0", HOST_B).as_deref(),
+            Some("eth1")
+        );
+    }
+
+    #[test]
+    fn ages_out_macs_not_seen_within_the_ttl() {
+        let table = MacTable::new(16, Duration::from_millis(20));
+        table.learn_and_lookup(HOST_A, "eth0", HOST_B);
+        assert_eq!(
+            table.learn_and_lookup(HOST_B, "eth1", HOST_A).as_deref(),
+            Some("eth0")
+        );
+        std::thread::sleep(Duration::from_millis(30));
+        assert_eq!(table.learn_and_lookup(HOST_C, "vm0", HOST_A), None);
+        assert_eq!(table.learn_and_lookup(HOST_C, "vm0", HOST_B), None);
+        assert_eq!(table.state().len(), 1);
+    }
+
+    #[test]
+    fn follows_a_mac_that_moved() {
+        let table = MacTable::new(16, Duration::from_secs(60));
+        table.learn_and_lookup(HOST_A, "eth0", HOST_B);
+        table.learn_and_lookup(HOST_A, "vm0", HOST_B);
+        assert_eq!(
+            table.learn_and_lookup(HOST_B, "eth1", HOST_A).as_deref(),
+            Some("vm0")
+        );
+        // Group source addresses are never learned
+        table.learn_and_lookup(MacAddr::broadcast(), "eth1", HOST_A);
+        assert_eq!(
+            table.learn_and_lookup(HOST_C, "vm0", MacAddr::broadcast()),
+            None
+        );
+    }
+}
@@ -0,0 +1,1397 @@
+//! Capture loops receiving frames on one interface and feeding them through
+//! the filter and rewrite stages to the send queue of the other interface.
+
+use crate::checksum;
+use crate::filter::{IpHeader, PacketContext, SharedFilterChain};
+use crate::fmt::{FrameSummary, Hexdump};
+use crate::iface::{find_interface, open_channel, ChannelConfig};
+use crate::kernelfilter::KernelFilter;
+use crate::latency::{self, Stage, Tracer};
+use crate::link::PacketSource;
+use crate::logging::{self, RepeatedMessages};
+use crate::loopguard::LoopGuard;
+use crate::mdnsmerge::MdnsAggregator;
+use crate::oversize::{Fit, Oversize};
+use crate::passthrough::{ControlProtocol, PassThrough};
+use crate::pause::Pause;
+use crate::pcap::{PcapReader, PcapSinks};
+use crate::pool::{BufferPool, PacketBuffer};
+use crate::quota::Quotas;
+use crate::ratelimit::RateLimiter;
+use crate::responder::Cache;
+use crate::rewrite::RewriteChain;
+use crate::sender::{MirrorQueue, Origin, SendQueue};
+use crate::stats::{DropReason, InterfaceStats, PathStats};
+use crate::storm::{self, StormControl};
+use crate::summary::{packet_event, PacketSummary};
+use crate::supervise::Supervision;
+use crate::talkers::Protocol;
+use crate::threads;
+use crate::vlan::{self, Tags, VlanPath};
+use pnet::datalink;
+use std::io;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, enabled, error, info, info_span, trace, warn, Level, Span};
+
+/// How often a blocked receive returns to check for cancellation
+pub const RX_POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// Consecutive receive errors after which the interface is considered lost
+const LOST_AFTER_ERRORS: u32 = 10;
+/// Consecutive receive errors retried at once before the loop pauses
+const BACKOFF_AFTER_ERRORS: u32 = 3;
+const ERROR_BACKOFF_MIN: Duration = Duration::from_millis(10);
+const ERROR_BACKOFF_MAX: Duration = Duration::from_secs(1);
+const RECONNECT_BACKOFF_MIN: Duration = Duration::from_millis(100);
+const RECONNECT_BACKOFF_MAX: Duration = Duration::from_secs(10);
+
+/// One forwarding direction of a pair: frames received on `ingress` are
+/// filtered, rewritten and queued on `tx`.
+pub struct ForwardPath {
+    pub ingress: String,
+    pub filters: SharedFilterChain,
+    pub rewrites: RewriteChain,
+    /// Loop guard shared by all paths, if enabled
+    pub loop_guard: Option<Arc<LoopGuard>>,
+    /// Limiter shared by all paths, if rate limiting is enabled
+    pub limiter: Option<Arc<RateLimiter>>,
+    /// Quotas shared by all paths, charged with the frames forwarded
+    pub quotas: Arc<Quotas>,
+    /// Caches answering queries in place of forwarding them, on the path
+    /// leaving the internal side if enabled
+    pub caches: Vec<Arc<dyn Cache>>,
+    pub tx: SendQueue,
+    pub stats: Arc<PathStats>,
+    pub pcap: PcapSinks,
+    pub vlan: VlanPath,
+    /// Queue of the mirror interface, if frames are mirrored
+    pub mirror: Option<MirrorQueue>,
+    /// Pool the copies of accepted frames are taken from, if pooled
+    pub pool: Option<Arc<BufferPool>>,
+    /// Numbers accepted frames and logs their stages, with `--trace-packets`
+    pub tracer: Option<Arc<Tracer>>,
+    /// Set while received frames are only counted as dropped
+    pub paused: Arc<Pause>,
+    /// MTU of the egress interface, enforced if set
+    pub oversize: Option<Oversize>,
+    /// Storm control of broadcast and multicast, if enabled
+    pub storm: Option<StormControl>,
+    /// Control protocols queued as they are, ahead of everything else
+    pub passthrough: PassThrough,
+    /// Holds back mDNS responses to merge them, on the path to the internal
+    /// side if enabled
+    pub mdns_aggregator: Option<Arc<MdnsAggregator>>,
+    /// Drop decisions logged at debug level, summed up per drop reason
+    pub decisions: RepeatedMessages,
+}
+
+impl ForwardPath {
+    /// Span the decisions about frames on this path are logged in
+    fn span(&self) -> Span {
+        info_span!(
+            "path",
+            pair = self.stats.pair.as_str(),
+            direction = self.stats.direction.name(),
+            egress = self.stats.egress.as_str()
+        )
+    }
+}
+
+/// Pairs each path with its span, created within the current one
+fn with_spans(paths: Vec<ForwardPath>) -> Vec<(ForwardPath, Span)> {
+    paths
+        .into_iter()
+        .map(|path| {
+            let span = path.span();
+            (path, span)
+        })
+        .collect()
+}
+
+/// Hands `frame`, received at `at`, to each of `paths` within its span
+fn dispatch(frame: &[u8], paths: &[(ForwardPath, Span)], at: Instant) {
+    for (path, span) in paths {
+        let _entered = span.enter();
+        process_packet(frame, path, at);
+    }
+}
+
+/// Logs a received frame: its summary at trace level, followed by a
+/// hexdump of its first bytes at debug level with `--dump-payload`. Nothing
+/// is formatted unless the level is enabled.
+fn log_received(ingress: &str, frame: &[u8]) {
+    let dump = logging::payload_dump();
+    let dumped = dump > 0 && enabled!(Level::DEBUG);
+    if !dumped && !enabled!(Level::TRACE) {
+        return;
+    }
+    let summary = FrameSummary {
+        at: SystemTime::now(),
+        interface: ingress,
+        frame,
+    };
+    if dumped {
+        debug!("{}\n{}", summary, Hexdump(&frame[..dump.min(frame.len())]));
+    } else {
+        trace!("{}", summary);
+    }
+}
+
+/// What is needed to re-open the ingress interface after it disappeared
+pub struct Reconnect {
+    pub config: ChannelConfig,
+    pub kernel_filter: Option<Arc<KernelFilter>>,
+    /// Send queue of the ingress interface, which gets the new sending half
+    pub own_queue: SendQueue,
+}
+
+#[cfg(any(test, feature = "bench"))]
+impl ForwardPath {
+    /// Inbound path from `ingress` to `egress` that only filters, queueing
+    /// the frames it accepts on `tx` as they are
+    pub(crate) fn plain(
+        ingress: &str,
+        egress: &str,
+        filters: crate::filter::FilterChain,
+        tx: SendQueue,
+    ) -> Self {
+        ForwardPath {
+            ingress: ingress.to_string(),
+            filters: Arc::new(arc_swap::ArcSwap::from_pointee(filters)),
+            rewrites: RewriteChain::new(),
+            loop_guard: None,
+            limiter: None,
+            quotas: Arc::default(),
+            caches: Vec::new(),
+            tx,
+            stats: Arc::new(PathStats::new(
+                format!("{}<->{}", ingress, egress),
+                crate::pair::Direction::Inbound,
+                ingress.to_string(),
+                egress.to_string(),
+            )),
+            pcap: PcapSinks::default(),
+            vlan: VlanPath::default(),
+            mirror: None,
+            pool: None,
+            tracer: None,
+            paused: Arc::default(),
+            oversize: None,
+            storm: None,
+            passthrough: PassThrough::default(),
+            mdns_aggregator: None,
+            decisions: RepeatedMessages::default(),
+        }
+    }
+}
+
+/// Runs the capture loop for interface `iface` on a thread of its own,
+/// pinned to `cpus` if given, and hands every frame to each of `paths`, one
+/// per pair using the interface.
+/// The receiver must have a read timeout so the token is checked regularly.
+/// Consecutive receive errors make the loop pause with exponential backoff,
+/// logging once per pause; with `reconnect` set, persistent ones make it
+/// wait for the interface to come back and re-open its channel. A panic in
+/// the loop is handled as `supervision` says.
+pub fn spawn_capture(
+    mut rx: Box<dyn PacketSource>,
+    iface: Arc<InterfaceStats>,
+    paths: Vec<ForwardPath>,
+    reconnect: Option<Reconnect>,
+    supervision: Supervision,
+    cpus: Option<&[usize]>,
+    token: CancellationToken,
+) -> JoinHandle<()> {
+    let name = format!("rx-{}", iface.name);
+    threads::spawn(name, cpus, move || {
+        let _span = info_span!("capture", iface = iface.name.as_str()).entered();
+        let paths = with_spans(paths);
+        let ingress = &iface.name;
+        let task = format!("Capture on {}", ingress);
+        supervision.run(&task, &iface.restarts, &token, || {
+            let mut backoff = ErrorBackoff::default();
+            while !token.is_cancelled() {
+                match rx.next() {
+                    Ok(frame) => {
+                        let at = Instant::now();
+                        backoff.succeeded();
+                        iface.frame_received();
+                        log_received(ingress, frame);
+                        dispatch(frame, &paths, at);
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::TimedOut => {}
+                    // Signals such as the one glibc uses to switch the user of
+                    // every thread interrupt the read, which is then retried
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                    Err(e) => {
+                        let pause = backoff.failed();
+                        if backoff.errors == 1 {
+                            error!("Error receiving packet on {}: {}", ingress, e);
+                        }
+                        if let (Some(reconnect), true) =
+                            (&reconnect, backoff.errors >= LOST_AFTER_ERRORS)
+                        {
+                            match reopen(&iface, reconnect, &token) {
+                                Some(new_rx) => rx = new_rx,
+                                None => break,
+                            }
+                            backoff.succeeded();
+                        } else if let Some(pause) = pause {
+                            warn!(
+                                "{} consecutive errors receiving on {}, last: {}; retrying in {:?}",
+                                backoff.errors, ingress, e, pause
+                            );
+                            if !sleep_unless_cancelled(pause, &token) {
+                                break;
+                            }
+                        }
+                    }
+                }
+            }
+        });
+        debug!("Capture on {} stopped", ingress);
+    })
+}
+
+/// Consecutive receive errors of a capture loop, and the pause before the
+/// next read they call for
+#[derive(Debug, Default)]
+struct ErrorBackoff {
+    errors: u32,
+}
+
+impl ErrorBackoff {
+    /// Counts an error. Returns the pause before the next read, which
+    /// doubles with every error after the first few, which get none.
+    fn failed(&mut self) -> Option<Duration> {
+        self.errors += 1;
+        let step = self.errors.checked_sub(BACKOFF_AFTER_ERRORS)?;
+        let pause = ERROR_BACKOFF_MIN.saturating_mul(1 << step.min(16));
+        Some(pause.min(ERROR_BACKOFF_MAX))
+    }
+
+    /// Starts over after a successful read
+    fn succeeded(&mut self) {
+        self.errors = 0;
+    }
+}
+
+/// Feeds the frames of a pcap file to `paths` as if they were received on
+/// `iface`, as fast as possible or, with `timing`, keeping the gaps between
+/// them. The thread ends at the end of the file or once `token` is cancelled.
+pub fn spawn_replay(
+    mut reader: PcapReader,
+    iface: Arc<InterfaceStats>,
+    paths: Vec<ForwardPath>,
+    timing: bool,
+    cpus: Option<&[usize]>,
+    token: CancellationToken,
+) -> JoinHandle<()> {
+    let name = format!("rx-{}", iface.name);
+    threads::spawn(name, cpus, move || {
+        let _span = info_span!("replay", iface = iface.name.as_str()).entered();
+        let paths = with_spans(paths);
+        let mut previous = None;
+        let mut frames = 0;
+        while !token.is_cancelled() {
+            let (at, frame) = match reader.next_frame() {
+                Ok(Some(record)) => record,
+                Ok(None) => break,
+                Err(e) => {
+                    error!("Failed to read {}: {}", reader.path().display(), e);
+                    break;
+                }
+            };
+            if let (true, Some(previous)) = (timing, previous) {
+                if !sleep_unless_cancelled(at.saturating_sub(previous), &token) {
+                    break;
+                }
+            }
+            previous = Some(at);
+            iface.frame_received();
+            log_received(&iface.name, &frame);
+            dispatch(&frame, &paths, Instant::now());
+            frames += 1;
+        }
+        info!(
+            "Replayed {} frame(s) from {} on {}",
+            frames,
+            reader.path().display(),
+            iface.name
+        );
+    })
+}
+
+/// Waits with exponential backoff until the interface exists again and its
+/// channel can be opened. Returns `None` if cancelled while waiting.
+fn reopen(
+    stats: &InterfaceStats,
+    reconnect: &Reconnect,
+    token: &CancellationToken,
+) -> Option<Box<dyn PacketSource>> {
+    let name = &stats.name;
+    info!("Interface {} lost, waiting for it to come back", name);
+    let mut delay = RECONNECT_BACKOFF_MIN;
+    loop {
+        if !sleep_unless_cancelled(delay, token) {
+            return None;
+        }
+        if let Ok(iface) = find_interface(&datalink::interfaces(), name) {
+            match open_channel(
+                &iface,
+                reconnect.config,
+                reconnect.kernel_filter.as_deref(),
+                Some(&stats.kernel),
+            ) {
+                Ok((tx, rx)) => {
+                    reconnect.own_queue.replace_sender(tx);
+                    let count = stats.reconnects.fetch_add(1, Ordering::Relaxed) + 1;
+                    info!("Interface {} reconnected ({} reconnects)", name, count);
+                    return Some(rx);
+                }
+                Err(e) => debug!("Re-opening {} failed: {}", name, e),
+            }
+        }
+        delay = (delay * 2).min(RECONNECT_BACKOFF_MAX);
+    }
+}
+
+/// Sleeps for `duration` in small steps. Returns `false` if cancelled.
+pub fn sleep_unless_cancelled(duration: Duration, token: &CancellationToken) -> bool {
+    let mut remaining = duration;
+    while !remaining.is_zero() {
+        if token.is_cancelled() {
+            return false;
+        }
+        let step = remaining.min(RX_POLL_INTERVAL);
+        std::thread::sleep(step);
+        remaining -= step;
+    }
+    !token.is_cancelled()
+}
+
+/// Filters the borrowed frame and only copies, rewrites and queues it for
+/// sending if it is accepted. VLAN tags are taken off ahead of the filters,
+/// which copies tagged frames. Each decision is logged at debug level as a
+/// [`PacketSummary`], drops only up to the threshold of their reason per
+/// window unless trace level is on. `at` is when the frame was received,
+/// the start of the latency recorded once it is sent. While forwarding is
+/// paused frames are only counted as dropped.
+pub fn process_packet(received: &[u8], path: &ForwardPath, at: Instant) {
+    path.stats.received(received.len());
+    if path.paused.is_paused() {
+        path.stats.dropped(DropReason::Paused);
+        return;
+    }
+    let (frame, tags) = vlan::untag(received);
+    if let Some(protocol) = path.passthrough.protocol(&frame) {
+        if path.vlan.accepts(&tags) {
+            pass_through(&frame, &tags, protocol, path, at);
+            return;
+        }
+    }
+    let parsed = if storm::reserved(&frame) {
+        Err(DropReason::Reserved)
+    } else if !path.vlan.accepts(&tags) {
+        Err(DropReason::Vlan)
+    } else if path
+        .storm
+        .as_ref()
+        .is_some_and(|storm| storm.suppressed(&frame, at))
+    {
+        Err(DropReason::Storm)
+    } else {
+        PacketContext::parse(&path.ingress, path.stats.direction, &frame)
+            .ok_or(DropReason::Filter("malformed"))
+    };
+    let mut ctx = match parsed {
+        Ok(ctx) => ctx,
+        Err(reason) => {
+            path.stats.dropped(reason);
+            if let Some(sink) = &path.pcap.dropped {
+                sink.write(SystemTime::now(), received.to_vec());
+            }
+            if let Some(mirror) = &path.mirror {
+                mirror.dropped(received);
+            }
+            return;
+        }
+    };
+    ctx.vlan = tags.id();
+    if let Some(source) = ctx.source_ip() {
+        let protocol = Protocol::of(&ctx);
+        path.stats.talkers.record(source, protocol, received.len());
+    }
+    let filters = path.filters.load();
+    let result = match filters.evaluate(&ctx) {
+        // Fragments carry no ports, so the port filters cannot claim them
+        Err(_) if ctx.ip.as_ref().is_some_and(IpHeader::is_fragment) => {
+            Err((DropReason::Fragment, "fragment"))
+        }
+        Err(filter) => Err((DropReason::Filter(filter), filter)),
+        Ok(_)
+            if path
+                .loop_guard
+                .as_ref()
+                .is_some_and(|g| g.looped(&ctx, &path.stats.egress)) =>
+        {
+            Err((DropReason::Loop, "loop-guard"))
+        }
+        Ok(_) if path.limiter.as_ref().is_some_and(|l| !l.allow(&ctx)) => {
+            Err((DropReason::RateLimit, "rate-limit"))
+        }
+        Ok(filter) => match path.caches.iter().find(|cache| cache.answer(&ctx)) {
+            Some(cache) => Err((DropReason::Cached, cache.name())),
+            None if !path.quotas.allow(&ctx, frame.len()) => Err((DropReason::Quota, "quota")),
+            None => forward(&frame, &tags, path, at).map(|forwarded| {
+                // Held responses are accounted for once the aggregator sends them
+                if forwarded == Forwarded::Sent {
+                    filters.forwarded(&ctx);
+                    path.stats.claimed(filter);
+                    path.quotas.charge(&ctx, frame.len());
+                    if let Some(guard) = &path.loop_guard {
+                        guard.sent(&ctx, &path.stats.egress);
+                    }
+                }
+            }),
+        },
+    };
+    if let Err((reason, _)) = result {
+        path.stats.dropped(reason);
+        if let Some(sink) = &path.pcap.dropped {
+            sink.write(SystemTime::now(), received.to_vec());
+        }
+        if let Some(mirror) = &path.mirror {
+            mirror.dropped(received);
+        }
+    }
+    if enabled!(Level::DEBUG) {
+        let stats = &path.stats;
+        let result = result.map_err(|(_, name)| name);
+        let summary = PacketSummary::new(&ctx, &stats.egress, &stats.pair, stats.direction, result);
+        match result {
+            Err(reason) if !enabled!(Level::TRACE) => {
+                let occurrence = path.decisions.occurred(reason, &summary);
+                for repeated in occurrence.summaries {
+                    debug!("Frames dropped by {}: {}", repeated.kind, repeated);
+                }
+                if occurrence.log {
+                    packet_event!(Level::DEBUG, summary, "{}", summary);
+                }
+            }
+            _ => packet_event!(Level::DEBUG, summary, "{}", summary),
+        }
+    }
+}
+
+/// Queues a control protocol frame passed through as it is, retagged for
+/// the egress interface only. It is neither filtered nor rewritten, and not
+/// mirrored or written to the pcap files.
+fn pass_through(
+    frame: &[u8],
+    tags: &Tags,
+    protocol: ControlProtocol,
+    path: &ForwardPath,
+    at: Instant,
+) {
+    let mut packet = BufferPool::copy(path.pool.as_ref(), frame);
+    path.vlan.retag(&mut packet, tags);
+    let origin = Origin {
+        stats: path.stats.clone(),
+        received: Some(at),
+        trace: None,
+    };
+    if path.tx.enqueue(packet, origin) {
+        path.stats.passed(protocol);
+        debug!(
+            "Passed {} frame through from {} to {}",
+            protocol, path.ingress, path.stats.egress
+        );
+    } else {
+        path.stats.dropped(DropReason::QueueFull);
+    }
+}
+
+/// What became of a frame [`forward`] took
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Forwarded {
+    /// Queued for sending
+    Sent,
+    /// Held back by the mDNS aggregator, to be sent merged with others
+    Held,
+}
+
+/// Copies an accepted frame into a pooled buffer, completes the checksums
+/// its sender left to offload, rewrites, tags and queues it, then hands
+/// copies of it to the pcap file and mirror interface
+fn forward<'a>(
+    frame: &[u8],
+    tags: &Tags,
+    path: &'a ForwardPath,
+    at: Instant,
+) -> Result<Forwarded, (DropReason<'a>, &'a str)> {
+    let trace = path.tracer.as_ref().map(|tracer| tracer.start());
+    let reached = |stage| {
+        if let Some(id) = trace {
+            latency::trace(id, stage, &path.ingress, at);
+        }
+    };
+    reached(Stage::Received);
+    reached(Stage::Filtered);
+    let mut packet = BufferPool::copy(path.pool.as_ref(), frame);
+    // Ahead of the rewrites, whose incremental updates need full checksums
+    if checksum::complete_offloaded(&mut packet) {
+        path.stats.offloaded();
+    }
+    path.rewrites
+        .apply(&mut packet)
+        .map_err(|stage| (DropReason::Rewrite(stage), stage))?;
+    reached(Stage::Rewritten);
+    if let Some(aggregator) = &path.mdns_aggregator {
+        if aggregator.hold(&packet, tags, at) {
+            return Ok(Forwarded::Held);
+        }
+    }
+    let mut packets = match path.oversize.as_ref().map(|oversize| oversize.fit(&packet)) {
+        None | Some(Fit::Fits) => vec![packet],
+        Some(Fit::Fragments(fragments)) => {
+            path.stats.fragmented();
+            fragments.into_iter().map(PacketBuffer::from).collect()
+        }
+        Some(Fit::TooBig) => return Err((DropReason::Oversize, "oversize")),
+    };
+    for packet in &mut packets {
+        path.vlan.retag(packet, tags);
+    }
+    let copies = |packets: &[PacketBuffer]| -> Vec<Vec<u8>> {
+        packets.iter().map(|packet| packet.to_vec()).collect()
+    };
+    let copy = path
+        .pcap
+        .forwarded
+        .as_ref()
+        .map(|sink| (sink, copies(&packets)));
+    let mirrored = path
+        .mirror
+        .as_ref()
+        .map(|mirror| (mirror, copies(&packets)));
+    let origin = Origin {
+        stats: path.stats.clone(),
+        received: Some(at),
+        trace,
+    };
+    // Fragments go all or none, a datagram missing one is lost anyway
+    if !path.tx.enqueue_all(packets.into_iter(), origin) {
+        return Err((DropReason::QueueFull, "queue-full"));
+    }
+    if let Some((sink, packets)) = copy {
+        for packet in packets {
+            sink.write(SystemTime::now(), packet);
+        }
+    }
+    if let Some((mirror, packets)) = mirrored {
+        for packet in packets {
+            mirror.forwarded(packet);
+        }
+    }
+    reached(Stage::Queued);
+    Ok(Forwarded::Sent)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filter::{
+        Decision, Filter, FilterChain, UdpPortFilter, MDNS_IPV4_GROUP, MDNS_PORT, SSDP_PORT,
+    };
+    use crate::link::memory::{self, BusySink, StalledSink, VecSink};
+    use crate::link::PacketSink;
+    use crate::mdns::TYPE_A;
+    use crate::mdnsmerge;
+    use crate::oversize::OversizePolicy;
+    use crate::quota::Quota;
+    use crate::sender::{spawn_sender, QueuePolicy};
+    use crate::stats::PathSnapshot;
+    use crate::supervise::{self, OnTaskFailure};
+    use crate::testutil::{self, multicast_mac, HOST_IP, HOST_MAC, SSDP_IPV4_GROUP};
+    use crate::vlan::VlanEgress;
+    use arc_swap::ArcSwap;
+    use pnet::util::MacAddr;
+    use std::collections::{HashSet, VecDeque};
+    use std::net::{Ipv4Addr, Ipv6Addr};
+    use std::sync::Mutex;
+    use std::time::Instant;
+    use tokio::sync::mpsc;
+
+    const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(1);
+
+    /// Receiver on an idle network: every read times out
+    struct IdleReceiver;
+
+    impl PacketSource for IdleReceiver {
+        fn next(&mut self) -> io::Result<&[u8]> {
+            std::thread::sleep(RX_POLL_INTERVAL);
+            Err(io::Error::new(io::ErrorKind::TimedOut, "Timed out"))
+        }
+    }
+
+    /// Receiver whose reads fail or return a frame as `script` says, in
+    /// turn, recording when each one happened; idle once it is through
+    struct ScriptedReceiver {
+        script: VecDeque<bool>,
+        frame: Vec<u8>,
+        reads: Arc<Mutex<Vec<Instant>>>,
+    }
+
+    impl PacketSource for ScriptedReceiver {
+        fn next(&mut self) -> io::Result<&[u8]> {
+            let Some(fails) = self.script.pop_front() else {
+                std::thread::sleep(RX_POLL_INTERVAL);
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "Timed out"));
+            };
+            self.reads.lock().unwrap().push(Instant::now());
+            if fails {
+                Err(io::Error::new(
+                    io::ErrorKind::NetworkDown,
+                    "Network is down",
+                ))
+            } else {
+                Ok(&self.frame)
+            }
+        }
+    }
+
+    struct NullSender;
+
+    impl PacketSink for NullSender {
+        fn send(&mut self, _frame: &[u8]) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Ethernet/IPv4/UDP frame with the given destination port
+    fn udp_frame(dport: u16) -> Vec<u8> {
+        testutil::udp_frame(
+            HOST_MAC,
+            multicast_mac(SSDP_IPV4_GROUP.into()),
+            HOST_IP,
+            SSDP_IPV4_GROUP,
+            50000,
+            dport,
+            &[0; 64],
+        )
+    }
+
+    /// Path from test0 to test1 queueing on `tx`
+    fn test_path(filters: FilterChain, tx: SendQueue, vlan: VlanPath) -> ForwardPath {
+        let mut path = ForwardPath::plain("test0", "test1", filters, tx);
+        path.vlan = vlan;
+        path
+    }
+
+    /// Feeds `frames` to a capture loop on an in-memory interface and
+    /// returns the frames that arrived at the other one once `expected` did,
+    /// with the path counters
+    async fn run_pipeline(
+        filters: FilterChain,
+        vlan: VlanPath,
+        oversize: Option<Oversize>,
+        frames: Vec<Vec<u8>>,
+        expected: usize,
+    ) -> (Vec<Vec<u8>>, PathSnapshot) {
+        let token = CancellationToken::new();
+        let sink = VecSink::default();
+        let (queue, sender) = spawn_sender(
+            "test1",
+            Box::new(sink.clone()),
+            16,
+            QueuePolicy::DropNewest,
+            false,
+            Arc::default(),
+            None,
+            None,
+            token.clone(),
+        );
+        let mut path = test_path(filters, queue, vlan);
+        path.oversize = oversize;
+        let stats = path.stats.clone();
+        let (source_tx, source) = memory::source();
+        let capture = spawn_capture(
+            Box::new(source),
+            Arc::new(InterfaceStats::new("test0".to_string())),
+            vec![path],
+            None,
+            Supervision::default(),
+            None,
+            token.clone(),
+        );
+        let received = frames.len() as u64;
+        for frame in frames {
+            source_tx.send(frame).unwrap();
+        }
+        let start = Instant::now();
+        while stats.snapshot().received < received || sink.frames().len() < expected {
+            assert!(start.elapsed() < SHUTDOWN_TIMEOUT, "frames did not arrive");
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        token.cancel();
+        capture.await.unwrap();
+        sender.await.unwrap();
+        (sink.frames(), stats.snapshot())
+    }
+
+    #[tokio::test]
+    async fn forwards_only_accepted_frames() {
+        let mut filters = FilterChain::new();
+        filters.push(UdpPortFilter::new(HashSet::from([SSDP_PORT])));
+        let mut other_ssdp = udp_frame(SSDP_PORT);
+        other_ssdp[6..12].copy_from_slice(&[0x02, 0, 0, 0, 0, 2]);
+        let mut truncated = udp_frame(SSDP_PORT);
+        truncated.truncate(20);
+        let frames = vec![
+            udp_frame(SSDP_PORT),
+            udp_frame(5000),
+            truncated,
+            other_ssdp.clone(),
+        ];
+
+        let (sent, stats) = run_pipeline(filters, VlanPath::default(), None, frames, 2).await;
+        assert_eq!(sent, [udp_frame(SSDP_PORT), other_ssdp]);
+        assert_eq!((stats.forwarded, stats.port_mismatch), (2, 1));
+        assert_eq!(stats.unmatched_protocol + stats.filtered, 1);
+    }
+
+    #[tokio::test]
+    async fn counts_fragments_the_port_filters_cannot_accept() {
+        let mut filters = FilterChain::new();
+        filters.push(UdpPortFilter::new(HashSet::from([SSDP_PORT])));
+        let group = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 0xc);
+        let whole_v6 = testutil::udp_frame(
+            HOST_MAC,
+            multicast_mac(group.into()),
+            Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 5),
+            group,
+            50000,
+            SSDP_PORT,
+            &[0; 64],
+        );
+        let first_v6 = testutil::ipv6_fragment(&whole_v6, 0, true);
+        let frames = vec![
+            testutil::ipv4_fragment(&udp_frame(SSDP_PORT), 0, true),
+            testutil::ipv4_fragment(&udp_frame(SSDP_PORT), 1480, false),
+            first_v6.clone(),
+            testutil::ipv6_fragment(&whole_v6, 1448, false),
+            udp_frame(SSDP_PORT),
+        ];
+        let (sent, stats) = run_pipeline(filters, VlanPath::default(), None, frames, 2).await;
+        // Only the IPv6 first fragment carries the UDP header
+        assert_eq!(sent, [first_v6, udp_frame(SSDP_PORT)]);
+        assert_eq!((stats.forwarded, stats.fragments), (2, 3));
+        assert_eq!(stats.port_mismatch + stats.unmatched_protocol, 0);
+    }
+
+    #[tokio::test]
+    async fn forwards_only_the_ingress_vlan_and_retags() {
+        let tagged = |id: u16, dport: u16| {
+            let mut frame = udp_frame(dport);
+            vlan::tag(&mut frame, id, 0xa000);
+            frame
+        };
+        let mut filters = FilterChain::new();
+        filters.push(UdpPortFilter::new(HashSet::from([SSDP_PORT])));
+        let path = VlanPath::new(Some(10), Some(20), VlanEgress::Strip);
+        let frames = vec![
+            tagged(10, SSDP_PORT),
+            tagged(11, SSDP_PORT),
+            udp_frame(SSDP_PORT),
+            tagged(10, 5000),
+        ];
+
+        let (sent, stats) = run_pipeline(filters, path, None, frames, 1).await;
+        assert_eq!(sent, [tagged(20, SSDP_PORT)]);
+        assert_eq!((stats.other_vlan, stats.port_mismatch), (2, 1));
+    }
+
+    #[tokio::test]
+    async fn fragments_or_drops_jumbo_frames() {
+        let jumbo = |dont_fragment: bool| {
+            let mut frame = testutil::udp_frame(
+                HOST_MAC,
+                multicast_mac(SSDP_IPV4_GROUP.into()),
+                HOST_IP,
+                SSDP_IPV4_GROUP,
+                50000,
+                SSDP_PORT,
+                &[0x5a; 8000],
+            );
+            if dont_fragment {
+                frame[testutil::ETHERNET_HEADER_LEN + 6] |= 0x40;
+            }
+            frame
+        };
+        let mut filters = FilterChain::new();
+        filters.push(UdpPortFilter::new(HashSet::from([SSDP_PORT])));
+        let oversize = Oversize::new(1500, OversizePolicy::Fragment, None);
+        let frames = vec![jumbo(false), jumbo(true), udp_frame(SSDP_PORT)];
+
+        let (sent, stats) =
+            run_pipeline(filters, VlanPath::default(), Some(oversize), frames, 7).await;
+        assert_eq!(sent.len(), 7);
+        assert!(sent.iter().all(|frame| frame.len() <= 1514));
+        assert_eq!(sent[6], udp_frame(SSDP_PORT));
+        let carried: usize = sent[..6]
+            .iter()
+            .map(|frame| frame.len() - testutil::ETHERNET_HEADER_LEN - testutil::IPV4_HEADER_LEN)
+            .sum();
+        assert_eq!(carried, testutil::UDP_HEADER_LEN + 8000);
+        assert_eq!((stats.fragmented, stats.oversize), (1, 1));
+        assert_eq!(stats.forwarded, 7);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn queues_all_fragments_of_a_datagram_or_none() {
+        let (frames, filters) = numbered_frames(4);
+        let filters = Arc::new(filters);
+        let jumbo = testutil::udp_frame(
+            HOST_MAC,
+            multicast_mac(SSDP_IPV4_GROUP.into()),
+            HOST_IP,
+            SSDP_IPV4_GROUP,
+            50000,
+            10000,
+            &[0x5a; 8000],
+        );
+        // Queue capacity and policy, with the frames ahead of the six
+        // fragments that are sent
+        let cases = [
+            (8, QueuePolicy::DropNewest, &frames[1..4], 0),
+            (8, QueuePolicy::DropOldest, &frames[2..4], 6),
+            (4, QueuePolicy::DropOldest, &frames[1..4], 0),
+        ];
+        for (capacity, policy, ahead, fragments) in cases {
+            let token = CancellationToken::new();
+            let stalled = StalledSink::default();
+            let (queue, sender) = spawn_sender(
+                "test1",
+                Box::new(stalled.clone()),
+                capacity,
+                policy,
+                false,
+                Arc::default(),
+                None,
+                None,
+                token.clone(),
+            );
+            let mut path = test_path(FilterChain::new(), queue, VlanPath::default());
+            path.filters = Arc::new(ArcSwap::new(filters.clone()));
+            path.oversize = Some(Oversize::new(1500, OversizePolicy::Fragment, None));
+
+            // The first frame holds up the send task, three more wait
+            process_packet(&frames[0], &path, Instant::now());
+            let start = Instant::now();
+            while stalled.pending() == 0 {
+                assert!(start.elapsed() < SHUTDOWN_TIMEOUT, "send did not start");
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+            for frame in &frames[1..] {
+                process_packet(frame, &path, Instant::now());
+            }
+            process_packet(&jumbo, &path, Instant::now());
+            let stats = path.stats.snapshot();
+            assert_eq!((stats.queued, stats.queue_full), (4 + fragments, 1));
+
+            stalled.release();
+            let expected = 1 + ahead.len() + fragments as usize;
+            wait_for(&stalled.sent(), expected).await;
+            let sent = stalled.sent().frames();
+            assert_eq!(sent[0], frames[0]);
+            assert_eq!(sent[1..=ahead.len()], *ahead);
+            assert!(sent[1 + ahead.len()..].iter().all(|f| f.len() <= 1514));
+
+            token.cancel();
+            drop(path);
+            sender.await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn capture_stops_promptly_on_idle_network() {
+        let token = CancellationToken::new();
+        let (queue, sender) = spawn_sender(
+            "test1",
+            Box::new(NullSender),
+            16,
+            QueuePolicy::DropNewest,
+            false,
+            Arc::default(),
+            None,
+            None,
+            token.clone(),
+        );
+        let path = test_path(FilterChain::new(), queue, VlanPath::default());
+        let task = spawn_capture(
+            Box::new(IdleReceiver),
+            Arc::new(InterfaceStats::new("test0".to_string())),
+            vec![path],
+            None,
+            Supervision::default(),
+            None,
+            token.clone(),
+        );
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let start = Instant::now();
+        token.cancel();
+        let tasks = async { tokio::join!(task, sender) };
+        let (capture, sender) = tokio::time::timeout(SHUTDOWN_TIMEOUT, tasks)
+            .await
+            .expect("forwarding tasks did not stop in time");
+        capture.unwrap();
+        sender.unwrap();
+        assert!(start.elapsed() < SHUTDOWN_TIMEOUT);
+    }
+
+    #[tokio::test]
+    async fn backs_off_on_repeated_receive_errors() {
+        let token = CancellationToken::new();
+        let (queue, sender) = spawn_sender(
+            "test1",
+            Box::new(NullSender),
+            16,
+            QueuePolicy::DropNewest,
+            false,
+            Arc::default(),
+            None,
+            None,
+            token.clone(),
+        );
+        let path = test_path(FilterChain::new(), queue, VlanPath::default());
+        // Five errors, a frame, then two errors again
+        let script = [true, true, true, true, true, false, true, true];
+        let reads = Arc::new(Mutex::new(Vec::new()));
+        let receiver = ScriptedReceiver {
+            script: script.into(),
+            frame: udp_frame(SSDP_PORT),
+            reads: reads.clone(),
+        };
+        let task = spawn_capture(
+            Box::new(receiver),
+            Arc::new(InterfaceStats::new("test0".to_string())),
+            vec![path],
+            None,
+            Supervision::default(),
+            None,
+            token.clone(),
+        );
+        let start = Instant::now();
+        while reads.lock().unwrap().len() < script.len() {
+            assert!(start.elapsed() < SHUTDOWN_TIMEOUT, "reads stalled");
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        token.cancel();
+        task.await.unwrap();
+        sender.await.unwrap();
+
+        let reads = reads.lock().unwrap();
+        let gap = |read: usize| reads[read + 1] - reads[read];
+        // The first errors are retried at once, the next ones after doubling
+        // pauses, and the frame starts over
+        let prompt = ERROR_BACKOFF_MIN * 4;
+        assert!(reads[2] - reads[0] < prompt);
+        assert!(gap(2) >= ERROR_BACKOFF_MIN);
+        assert!(gap(3) >= ERROR_BACKOFF_MIN * 2);
+        assert!(gap(4) >= ERROR_BACKOFF_MIN * 4);
+        assert!(reads[7] - reads[5] < prompt);
+
+        let mut backoff = ErrorBackoff::default();
+        let pauses: Vec<_> = (0..12).map(|_| backoff.failed()).collect();
+        assert_eq!(pauses[..3], [None, None, Some(ERROR_BACKOFF_MIN)]);
+        assert_eq!(pauses[11], Some(ERROR_BACKOFF_MAX));
+    }
+
+    /// Frames to distinct ports, all of them accepted by the returned chain
+    fn numbered_frames(count: u16) -> (Vec<Vec<u8>>, FilterChain) {
+        let ports = 10000..10000 + count;
+        let mut filters = FilterChain::new();
+        filters.push(UdpPortFilter::new(ports.clone().collect()));
+        (ports.map(udp_frame).collect(), filters)
+    }
+
+    async fn wait_for(sink: &VecSink, count: usize) {
+        let start = Instant::now();
+        while sink.frames().len() < count {
+            assert!(start.elapsed() < SHUTDOWN_TIMEOUT, "frames did not arrive");
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn full_queue_sheds_by_policy_while_the_other_direction_flows() {
+        const CAPACITY: usize = 8;
+        const FRAMES: u16 = 1000;
+        let (frames, filters) = numbered_frames(FRAMES);
+        let filters = Arc::new(filters);
+        for policy in [QueuePolicy::DropNewest, QueuePolicy::DropOldest] {
+            let token = CancellationToken::new();
+            let stalled = StalledSink::default();
+            let (queue, stalled_sender) = spawn_sender(
+                "test1",
+                Box::new(stalled.clone()),
+                CAPACITY,
+                policy,
+                false,
+                Arc::default(),
+                None,
+                None,
+                token.clone(),
+            );
+            let mut congested = test_path(FilterChain::new(), queue, VlanPath::default());
+            congested.filters = Arc::new(ArcSwap::new(filters.clone()));
+            let flowing_sink = VecSink::default();
+            let (queue, flowing_sender) = spawn_sender(
+                "test0",
+                Box::new(flowing_sink.clone()),
+                usize::from(FRAMES),
+                policy,
+                false,
+                Arc::default(),
+                None,
+                None,
+                token.clone(),
+            );
+            let mut flowing = test_path(FilterChain::new(), queue, VlanPath::default());
+            flowing.filters = Arc::new(ArcSwap::new(filters.clone()));
+
+            // The first frame holds up the send task, the rest pile up behind
+            process_packet(&frames[0], &congested, Instant::now());
+            let start = Instant::now();
+            while stalled.pending() == 0 {
+                assert!(start.elapsed() < SHUTDOWN_TIMEOUT, "send did not start");
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+            let start = Instant::now();
+            for frame in &frames[1..] {
+                process_packet(frame, &congested, Instant::now());
+                process_packet(frame, &flowing, Instant::now());
+            }
+            assert!(start.elapsed() < SHUTDOWN_TIMEOUT, "capture blocked");
+            wait_for(&flowing_sink, frames.len() - 1).await;
+            assert!(stalled.sent().frames().is_empty());
+
+            stalled.release();
+            let kept = match policy {
+                QueuePolicy::DropNewest => &frames[1..=CAPACITY],
+                QueuePolicy::DropOldest => &frames[frames.len() - CAPACITY..],
+            };
+            wait_for(&stalled.sent(), 1 + CAPACITY).await;
+            assert_eq!(stalled.sent().frames()[0], frames[0]);
+            assert_eq!(stalled.sent().frames()[1..], *kept);
+            let stats = congested.stats.snapshot();
+            let shed = u64::from(FRAMES) - 1 - CAPACITY as u64;
+            assert_eq!(
+                (stats.forwarded, stats.queue_full),
+                (1 + CAPACITY as u64, shed)
+            );
+            let queued = match policy {
+                QueuePolicy::DropNewest => 1 + CAPACITY as u64,
+                QueuePolicy::DropOldest => u64::from(FRAMES),
+            };
+            assert_eq!(stats.queued, queued);
+            assert_eq!(flowing.stats.snapshot().queue_full, 0);
+
+            token.cancel();
+            drop((congested, flowing));
+            stalled_sender.await.unwrap();
+            flowing_sender.await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn retries_busy_sends_with_backoff() {
+        let (frames, filters) = numbered_frames(2);
+        let token = CancellationToken::new();
+        let busy = BusySink::default();
+        let (queue, sender) = spawn_sender(
+            "test1",
+            Box::new(busy.clone()),
+            16,
+            QueuePolicy::DropNewest,
+            false,
+            Arc::default(),
+            None,
+            None,
+            token.clone(),
+        );
+        let path = test_path(filters, queue, VlanPath::default());
+
+        busy.fail(2);
+        process_packet(&frames[0], &path, Instant::now());
+        wait_for(&busy.sent(), 1).await;
+        let stats = path.stats.snapshot();
+        assert_eq!((stats.forwarded, stats.retried), (1, 2));
+
+        busy.fail(usize::MAX);
+        process_packet(&frames[1], &path, Instant::now());
+        let start = Instant::now();
+        while path.stats.snapshot().send_error == 0 {
+            assert!(start.elapsed() < SHUTDOWN_TIMEOUT, "send was not given up");
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        let stats = path.stats.snapshot();
+        assert_eq!((stats.forwarded, stats.retried), (1, 2 + 5));
+
+        token.cancel();
+        drop(path);
+        sender.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn passes_enabled_control_protocols_ahead_of_filters() {
+        let token = CancellationToken::new();
+        let sink = VecSink::default();
+        let (queue, sender) = spawn_sender(
+            "test1",
+            Box::new(sink.clone()),
+            16,
+            QueuePolicy::DropNewest,
+            false,
+            Arc::default(),
+            None,
+            None,
+            token.clone(),
+        );
+        let mut filters = FilterChain::new();
+        filters.push(UdpPortFilter::new(HashSet::from([SSDP_PORT])));
+        let mut path = test_path(filters, queue, VlanPath::default());
+        path.passthrough = PassThrough {
+            eapol: true,
+            lldp: false,
+        };
+        // EAPOL-Start and LLDP to their reserved group addresses
+        let control_frame = |group: u8, ethertype: [u8; 2], payload: &[u8]| {
+            let mut frame = vec![0x01, 0x80, 0xc2, 0x00, 0x00, group];
+            frame.extend_from_slice(&HOST_MAC.octets());
+            frame.extend_from_slice(&ethertype);
+            frame.extend_from_slice(payload);
+            frame.resize(60, 0);
+            frame
+        };
+        let eapol = control_frame(0x03, [0x88, 0x8e], &[0x02, 0x01, 0x00, 0x00]);
+        let lldp = control_frame(0x0e, [0x88, 0xcc], &[0x02, 0x07, 0x04]);
+
+        process_packet(&eapol, &path, Instant::now());
+        process_packet(&lldp, &path, Instant::now());
+        wait_for(&sink, 1).await;
+        let stats = path.stats.snapshot();
+        assert_eq!(sink.frames(), [eapol]);
+        assert_eq!((stats.eapol_passed, stats.lldp_passed), (1, 0));
+        assert_eq!(stats.reserved, 1);
+
+        token.cancel();
+        drop(path);
+        sender.await.unwrap();
+    }
+
+    /// Filter failing on frames to `port`, as a bug in a filter would
+    struct PanickingFilter {
+        port: u16,
+    }
+
+    impl Filter for PanickingFilter {
+        fn name(&self) -> &str {
+            "panicking"
+        }
+
+        fn evaluate(&self, ctx: &PacketContext) -> Decision {
+            if ctx
+                .udp()
+                .is_some_and(|udp| udp.get_destination() == self.port)
+            {
+                panic!("filter failed");
+            }
+            Decision::Continue
+        }
+    }
+
+    #[tokio::test]
+    async fn pausing_drops_received_and_queued_frames() {
+        let mut filters = FilterChain::new();
+        filters.push(UdpPortFilter::new(HashSet::from([SSDP_PORT])));
+        let token = CancellationToken::new();
+        let pause = Arc::new(Pause::default());
+        let stalled = StalledSink::default();
+        let (queue, sender) = spawn_sender(
+            "test1",
+            Box::new(stalled.clone()),
+            16,
+            QueuePolicy::DropNewest,
+            false,
+            pause.clone(),
+            None,
+            None,
+            token.clone(),
+        );
+        let mut path = test_path(filters, queue, VlanPath::default());
+        path.paused = pause.clone();
+        let frame = udp_frame(SSDP_PORT);
+
+        // The first frame is being sent while two more wait in the queue
+        process_packet(&frame, &path, Instant::now());
+        let start = Instant::now();
+        while stalled.pending() == 0 {
+            assert!(start.elapsed() < SHUTDOWN_TIMEOUT, "send did not start");
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        process_packet(&frame, &path, Instant::now());
+        process_packet(&frame, &path, Instant::now());
+        let pausing = {
+            let pause = pause.clone();
+            std::thread::spawn(move || pause.set(true))
+        };
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!pausing.is_finished(), "pausing did not wait for the send");
+        stalled.release();
+        assert!(pausing.join().unwrap());
+        process_packet(&frame, &path, Instant::now());
+
+        let stats = path.stats.clone();
+        drop(path);
+        sender.await.unwrap();
+        let stats = stats.snapshot();
+        assert_eq!(stalled.sent().frames(), [frame]);
+        assert_eq!((stats.received, stats.forwarded, stats.paused), (4, 1, 3));
+    }
+
+    #[tokio::test]
+    async fn restarts_or_gives_up_on_panicking_capture_loops() {
+        const FAILING_PORT: u16 = 6666;
+        for policy in [OnTaskFailure::Restart, OnTaskFailure::Exit] {
+            let token = CancellationToken::new();
+            let sink = VecSink::default();
+            let (queue, _sender) = spawn_sender(
+                "test1",
+                Box::new(sink.clone()),
+                16,
+                QueuePolicy::DropNewest,
+                false,
+                Arc::default(),
+                None,
+                None,
+                token.clone(),
+            );
+            let mut chain = FilterChain::new();
+            chain.push(PanickingFilter { port: FAILING_PORT });
+            chain.push(UdpPortFilter::new(HashSet::from([SSDP_PORT])));
+            let path = test_path(chain, queue, VlanPath::default());
+            let iface = Arc::new(InterfaceStats::new("test0".to_string()));
+            let (source_tx, source) = memory::source();
+            let supervision = Supervision {
+                policy,
+                max_restarts: 1,
+            };
+            let capture = spawn_capture(
+                Box::new(source),
+                iface.clone(),
+                vec![path],
+                None,
+                supervision,
+                None,
+                token.clone(),
+            );
+            let (failures, mut failed) = mpsc::unbounded_channel();
+            supervise::watch("capture".to_string(), capture, token.clone(), failures);
+            source_tx.send(udp_frame(FAILING_PORT)).unwrap();
+
+            if policy == OnTaskFailure::Restart {
+                source_tx.send(udp_frame(SSDP_PORT)).unwrap();
+                let start = Instant::now();
+                while sink.frames().is_empty() {
+                    assert!(start.elapsed() < SHUTDOWN_TIMEOUT, "capture not restarted");
+                    tokio::time::sleep(Duration::from_millis(5)).await;
+                }
+                assert_eq!(iface.snapshot().restarts, 1);
+                assert!(failed.try_recv().is_err());
+                // Out of restarts
+                source_tx.send(udp_frame(FAILING_PORT)).unwrap();
+            }
+            let failure = tokio::time::timeout(SHUTDOWN_TIMEOUT, failed.recv()).await;
+            assert_eq!(failure.unwrap().as_deref(), Some("capture"), "{:?}", policy);
+            token.cancel();
+        }
+    }
+
+    #[tokio::test]
+    async fn charges_merged_mdns_responses_once_sent() {
+        let token = CancellationToken::new();
+        let sink = VecSink::default();
+        let (queue, sender) = spawn_sender(
+            "test1",
+            Box::new(sink.clone()),
+            16,
+            QueuePolicy::DropNewest,
+            false,
+            Arc::default(),
+            None,
+            None,
+            token.clone(),
+        );
+        let mut filters = FilterChain::new();
+        filters.push(UdpPortFilter::new(HashSet::from([MDNS_PORT])));
+        let mut path = test_path(filters, queue.clone(), VlanPath::default());
+        let quota: Quota = "any mdns packets 10 per 1h".parse().unwrap();
+        path.quotas = Arc::new(Quotas::new(&[quota]));
+        let output = mdnsmerge::Output {
+            tx: queue,
+            stats: path.stats.clone(),
+            vlan: VlanPath::default(),
+            pcap: PcapSinks::default(),
+            mirror: None,
+            quotas: path.quotas.clone(),
+            loop_guard: None,
+            mtu: 1500,
+        };
+        let window = Duration::from_millis(20);
+        path.mdns_aggregator = Some(Arc::new(MdnsAggregator::new(window, output)));
+        let response = |host: u8| {
+            let payload = testutil::mdns_response(
+                &format!("host{}.local", host),
+                TYPE_A,
+                120,
+                &[192, 168, 100, host],
+            );
+            testutil::udp_frame(
+                MacAddr(0x02, 0, 0, 0, 0, host),
+                multicast_mac(MDNS_IPV4_GROUP.into()),
+                Ipv4Addr::new(192, 168, 100, host),
+                MDNS_IPV4_GROUP,
+                MDNS_PORT,
+                MDNS_PORT,
+                &payload,
+            )
+        };
+
+        process_packet(&response(10), &path, Instant::now());
+        process_packet(&response(11), &path, Instant::now());
+        // Both are held back, nothing went out yet
+        assert_eq!(path.quotas.snapshot()[0].packets, 0);
+        let start = Instant::now();
+        while sink.frames().is_empty() {
+            assert!(
+                start.elapsed() < SHUTDOWN_TIMEOUT,
+                "merged response not sent"
+            );
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        let sent = sink.frames();
+        assert_eq!(sent.len(), 1);
+        let used = &path.quotas.snapshot()[0];
+        assert_eq!((used.packets, used.bytes), (1, sent[0].len() as u64));
+
+        token.cancel();
+        drop(path);
+        sender.await.unwrap();
+    }
+}
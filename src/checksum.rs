@@ -0,0 +1,357 @@
+//! Checksum recomputation for frames modified by rewrite stages, and
+//! verification of received frames, telling checksums left to offload by
+//! the sender from broken ones.
+
+use pnet::packet::ethernet::{EtherTypes, EthernetPacket};
+use pnet::packet::ip::{IpNextHeaderProtocol, IpNextHeaderProtocols};
+use pnet::packet::ipv4::{self, Ipv4Packet, MutableIpv4Packet};
+use pnet::packet::ipv6::Ipv6Packet;
+use pnet::util;
+use std::net::IpAddr;
+
+const ETHERNET_HEADER_LEN: usize = 14;
+const IPV6_HEADER_LEN: usize = 40;
+
+/// Recomputes the IPv4 header checksum and the UDP/TCP checksum of an
+/// Ethernet frame carrying IPv4. UDP datagrams sent without a checksum keep
+/// it zero, and fragmented datagrams only get their header checksum fixed
+/// since the transport checksum spans all fragments.
+/// Returns `false` if the frame is not a well-formed IPv4 frame.
+pub fn update_ipv4(frame: &mut [u8]) -> bool {
+    let Some(l3) = frame.get_mut(ETHERNET_HEADER_LEN..) else {
+        return false;
+    };
+    let Some(mut ip) = MutableIpv4Packet::new(l3) else {
+        return false;
+    };
+    let header_len = usize::from(ip.get_header_length()) * 4;
+    let total_len = usize::from(ip.get_total_length());
+    if header_len < Ipv4Packet::minimum_packet_size() || total_len < header_len {
+        return false;
+    }
+    ip.set_checksum(ipv4::checksum(&ip.to_immutable()));
+
+    let fragmented =
+        ip.get_fragment_offset() != 0 || ip.get_flags() & ipv4::Ipv4Flags::MoreFragments != 0;
+    if fragmented {
+        return true;
+    }
+    let (src, dst, protocol) = (
+        ip.get_source(),
+        ip.get_destination(),
+        ip.get_next_level_protocol(),
+    );
+    let Some(l4) = l3.get_mut(header_len..total_len) else {
+        return false;
+    };
+    match protocol {
+        IpNextHeaderProtocols::Udp if l4.len() >= 8 => {
+            if l4[6..8] == [0, 0] {
+                return true;
+            }
+            let sum = match util::ipv4_checksum(l4, 3, &[], &src, &dst, protocol) {
+                0 => 0xffff,
+                sum => sum,
+            };
+            l4[6..8].copy_from_slice(&sum.to_be_bytes());
+        }
+        IpNextHeaderProtocols::Tcp if l4.len() >= 20 => {
+            let sum = util::ipv4_checksum(l4, 8, &[], &src, &dst, protocol);
+            l4[16..18].copy_from_slice(&sum.to_be_bytes());
+        }
+        _ => {}
+    }
+    true
+}
+
+/// Recomputes the UDP/TCP checksum of an Ethernet frame carrying IPv6 with
+/// the transport header right after the fixed header. Returns `false` for
+/// any other frame, including ones with extension headers.
+pub fn update_ipv6(frame: &mut [u8]) -> bool {
+    let Some(l3) = frame.get_mut(ETHERNET_HEADER_LEN..) else {
+        return false;
+    };
+    let Some(ip) = Ipv6Packet::new(l3) else {
+        return false;
+    };
+    let (src, dst, protocol) = (ip.get_source(), ip.get_destination(), ip.get_next_header());
+    let end = IPV6_HEADER_LEN + usize::from(ip.get_payload_length());
+    let Some(l4) = l3.get_mut(IPV6_HEADER_LEN..end) else {
+        return false;
+    };
+    match protocol {
+        IpNextHeaderProtocols::Udp if l4.len() >= 8 => {
+            let sum = match util::ipv6_checksum(l4, 3, &[], &src, &dst, protocol) {
+                0 => 0xffff,
+                sum => sum,
+            };
+            l4[6..8].copy_from_slice(&sum.to_be_bytes());
+        }
+        IpNextHeaderProtocols::Tcp if l4.len() >= 20 => {
+            let sum = util::ipv6_checksum(l4, 8, &[], &src, &dst, protocol);
+            l4[16..18].copy_from_slice(&sum.to_be_bytes());
+        }
+        _ => return false,
+    }
+    true
+}
+
+/// Updates a ones' complement checksum for one 16-bit word of the covered
+/// data changing from `old` to `new` (RFC 1624, eqn. 3)
+pub fn adjust(checksum: u16, old: u16, new: u16) -> u16 {
+    let mut sum = u32::from(!checksum) + u32::from(!old) + u32::from(new);
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// What the checksums of a received frame say about it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    /// Correct, or not covered by a checksum that can be checked
+    Valid,
+    /// The UDP/TCP checksum was left for the NIC to fill in by a sender
+    /// using checksum offload, as captured on virtio interfaces
+    Offloaded,
+    Invalid,
+}
+
+/// UDP or TCP datagram of an unfragmented IPv4 or IPv6 packet
+struct Datagram<'a> {
+    source: IpAddr,
+    destination: IpAddr,
+    protocol: IpNextHeaderProtocol,
+    /// Transport header and payload
+    bytes: &'a [u8],
+    /// Whether the IPv4 header checksum is right, true for IPv6
+    header_valid: bool,
+}
+
+impl<'a> Datagram<'a> {
+    fn parse(frame: &'a [u8]) -> Option<Self> {
+        let eth = EthernetPacket::new(frame)?;
+        let l3 = frame.get(ETHERNET_HEADER_LEN..)?;
+        let (source, destination, protocol, bytes, header_valid) = match eth.get_ethertype() {
+            EtherTypes::Ipv4 => {
+                let ip = Ipv4Packet::new(l3)?;
+                let header_len = usize::from(ip.get_header_length()) * 4;
+                let total_len = usize::from(ip.get_total_length());
+                let fragmented = ip.get_fragment_offset() != 0
+                    || ip.get_flags() & ipv4::Ipv4Flags::MoreFragments != 0;
+                if header_len < Ipv4Packet::minimum_packet_size() || fragmented {
+                    return None;
+                }
+                (
+                    IpAddr::V4(ip.get_source()),
+                    IpAddr::V4(ip.get_destination()),
+                    ip.get_next_level_protocol(),
+                    l3.get(header_len..total_len)?,
+                    ipv4::checksum(&ip) == ip.get_checksum(),
+                )
+            }
+            EtherTypes::Ipv6 => {
+                let ip = Ipv6Packet::new(l3)?;
+                let end = IPV6_HEADER_LEN + usize::from(ip.get_payload_length());
+                (
+                    IpAddr::V6(ip.get_source()),
+                    IpAddr::V6(ip.get_destination()),
+                    ip.get_next_header(),
+                    l3.get(IPV6_HEADER_LEN..end)?,
+                    true,
+                )
+            }
+            _ => return None,
+        };
+        let min_len = match protocol {
+            IpNextHeaderProtocols::Udp => 8,
+            IpNextHeaderProtocols::Tcp => 20,
+            _ => return None,
+        };
+        (bytes.len() >= min_len).then_some(Datagram {
+            source,
+            destination,
+            protocol,
+            bytes,
+            header_valid,
+        })
+    }
+
+    /// Offset of the checksum in the transport header
+    fn checksum_at(&self) -> usize {
+        match self.protocol {
+            IpNextHeaderProtocols::Udp => 6,
+            _ => 16,
+        }
+    }
+
+    fn checksum(&self) -> u16 {
+        let at = self.checksum_at();
+        u16::from_be_bytes([self.bytes[at], self.bytes[at + 1]])
+    }
+
+    /// Checksum the datagram should carry
+    fn expected(&self) -> u16 {
+        let skip = self.checksum_at() / 2;
+        let sum = match (self.source, self.destination) {
+            (IpAddr::V4(source), IpAddr::V4(destination)) => {
+                util::ipv4_checksum(self.bytes, skip, &[], &source, &destination, self.protocol)
+            }
+            (IpAddr::V6(source), IpAddr::V6(destination)) => {
+                util::ipv6_checksum(self.bytes, skip, &[], &source, &destination, self.protocol)
+            }
+            _ => unreachable!("addresses of one packet are of one family"),
+        };
+        match (self.protocol, sum) {
+            (IpNextHeaderProtocols::Udp, 0) => 0xffff,
+            (_, sum) => sum,
+        }
+    }
+
+    /// Ones' complement sum of the pseudo-header, which checksum offload
+    /// leaves in the checksum field for the NIC to add the data to
+    fn pseudo_header_sum(&self) -> u16 {
+        let mut sum = u32::from(self.protocol.0) + self.bytes.len() as u32;
+        let addresses = match (self.source, self.destination) {
+            (IpAddr::V4(source), IpAddr::V4(destination)) => {
+                [source.octets().to_vec(), destination.octets().to_vec()]
+            }
+            (IpAddr::V6(source), IpAddr::V6(destination)) => {
+                [source.octets().to_vec(), destination.octets().to_vec()]
+            }
+            _ => unreachable!("addresses of one packet are of one family"),
+        };
+        for word in addresses.concat().as_chunks::<2>().0 {
+            sum += u32::from(u16::from_be_bytes(*word));
+        }
+        while sum > 0xffff {
+            sum = (sum & 0xffff) + (sum >> 16);
+        }
+        sum as u16
+    }
+
+    /// Whether the sender left the checksum to offload: the pseudo-header
+    /// sum, or zero where UDP over IPv6 may not omit it
+    fn offloaded(&self) -> bool {
+        let checksum = self.checksum();
+        checksum == self.pseudo_header_sum()
+            || (checksum == 0
+                && self.protocol == IpNextHeaderProtocols::Udp
+                && self.source.is_ipv6())
+    }
+}
+
+/// Checks the IPv4 header checksum and the UDP/TCP checksum of an untagged
+/// frame. UDP over IPv4 without a checksum and frames that are not
+/// unfragmented UDP or TCP are valid as far as this goes.
+pub fn verify(frame: &[u8]) -> Verdict {
+    let Some(datagram) = Datagram::parse(frame) else {
+        return Verdict::Valid;
+    };
+    let checksum = datagram.checksum();
+    let omitted = datagram.protocol == IpNextHeaderProtocols::Udp
+        && checksum == 0
+        && datagram.source.is_ipv4();
+    if !datagram.header_valid {
+        Verdict::Invalid
+    } else if omitted || checksum == datagram.expected() {
+        Verdict::Valid
+    } else if datagram.offloaded() {
+        Verdict::Offloaded
+    } else {
+        Verdict::Invalid
+    }
+}
+
+/// Fills in the checksums of an untagged frame whose sender left them to
+/// checksum offload, which a frame sent through a packet socket does not
+/// get. Returns whether it did.
+pub fn complete_offloaded(frame: &mut [u8]) -> bool {
+    let offloaded = Datagram::parse(frame)
+        .is_some_and(|datagram| datagram.offloaded() && datagram.checksum() != datagram.expected());
+    if !offloaded {
+        return false;
+    }
+    match EthernetPacket::new(frame).map(|eth| eth.get_ethertype()) {
+        Some(EtherTypes::Ipv4) => update_ipv4(frame),
+        _ => update_ipv6(frame),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{
+        bad_ip_checksum, bad_udp_checksum, ssdp_search_frame, udp_frame, HOST_MAC, IPV4_HEADER_LEN,
+        UDP_HEADER_LEN,
+    };
+    use pnet::util::MacAddr;
+    use std::net::Ipv6Addr;
+
+    /// Copy of an untagged UDP `frame` with the pseudo-header sum in its
+    /// checksum, as a sender using checksum offload hands it to the NIC
+    fn offloaded(frame: &[u8]) -> Vec<u8> {
+        let datagram = Datagram::parse(frame).unwrap();
+        let sum = datagram.pseudo_header_sum();
+        let at = frame.len() - datagram.bytes.len() + datagram.checksum_at();
+        let mut frame = frame.to_vec();
+        frame[at..at + 2].copy_from_slice(&sum.to_be_bytes());
+        frame
+    }
+
+    fn ipv6_frame() -> Vec<u8> {
+        let source = Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 5);
+        let group = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 0xfb);
+        let destination = MacAddr(0x33, 0x33, 0, 0, 0, 0xfb);
+        udp_frame(HOST_MAC, destination, source, group, 5353, 5353, b"payload")
+    }
+
+    #[test]
+    fn adjusts_checksums_like_a_recomputation() {
+        let mut frame = ssdp_search_frame("ssdp:all");
+        let ip = ETHERNET_HEADER_LEN;
+        let word = |frame: &[u8], at: usize| u16::from_be_bytes([frame[at], frame[at + 1]]);
+        for ttl in [0, 1, 64, 255] {
+            let old = word(&frame, ip + 8);
+            frame[ip + 8] = ttl;
+            let adjusted = adjust(word(&frame, ip + 10), old, word(&frame, ip + 8));
+            let packet = Ipv4Packet::new(&frame[ip..]).unwrap();
+            assert_eq!(adjusted, ipv4::checksum(&packet));
+            frame[ip + 10..ip + 12].copy_from_slice(&adjusted.to_be_bytes());
+        }
+    }
+
+    #[test]
+    fn tells_offloaded_checksums_from_broken_ones() {
+        for frame in [ssdp_search_frame("ssdp:all"), ipv6_frame()] {
+            assert_eq!(verify(&frame), Verdict::Valid);
+            assert_eq!(verify(&offloaded(&frame)), Verdict::Offloaded);
+            assert_eq!(verify(&bad_udp_checksum(&frame)), Verdict::Invalid);
+        }
+        let frame = ssdp_search_frame("ssdp:all");
+        assert_eq!(verify(&bad_ip_checksum(&frame)), Verdict::Invalid);
+        // Only IPv4 lets UDP go without a checksum
+        let at = ETHERNET_HEADER_LEN + IPV4_HEADER_LEN + UDP_HEADER_LEN - 2;
+        let mut unchecked = frame.clone();
+        unchecked[at..at + 2].fill(0);
+        assert_eq!(verify(&unchecked), Verdict::Valid);
+        let mut unchecked = ipv6_frame();
+        let at = ETHERNET_HEADER_LEN + IPV6_HEADER_LEN + UDP_HEADER_LEN - 2;
+        unchecked[at..at + 2].fill(0);
+        assert_eq!(verify(&unchecked), Verdict::Offloaded);
+    }
+
+    #[test]
+    fn completes_offloaded_checksums_only() {
+        for frame in [ssdp_search_frame("ssdp:all"), ipv6_frame()] {
+            let mut completed = offloaded(&frame);
+            assert!(complete_offloaded(&mut completed));
+            assert_eq!(completed, frame);
+            assert!(!complete_offloaded(&mut completed));
+
+            let mut broken = bad_udp_checksum(&frame);
+            assert!(!complete_offloaded(&mut broken));
+            assert_eq!(broken, bad_udp_checksum(&frame));
+        }
+    }
+}
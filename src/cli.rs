@@ -0,0 +1,615 @@
+//! Command line front end of the `nw-pckt-fwd` binary: option parsing, the
+//! configuration file, and the signals and control socket requests
+//! controlling a running forwarder.
+
+use crate::control::{self, Handler, Request};
+use crate::error::Error;
+use crate::forward::Forwarder;
+use crate::iface::{interface_table, InterfaceInfo};
+use crate::logging::{self, Suppression};
+use crate::systemd::Notifier;
+use crate::{config, schema, Args};
+use clap::builder::PossibleValuesParser;
+use clap::parser::ValueSource;
+use clap::{ArgMatches, CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum, ValueHint};
+use clap_complete::Shell;
+use pnet::datalink;
+use serde_json::{json, Map, Value as Json};
+use std::ffi::OsStr;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, warn};
+
+const BIN_NAME: &str = "nw-pckt-fwd";
+/// Options taking an interface name, completed with the present interfaces
+const INTERFACE_OPTIONS: [&str; 4] = ["external_iface", "internal_iface", "bridge", "mirror_iface"];
+
+/// How often the status shown by `systemctl status` is updated when the
+/// watchdog is off
+const STATUS_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Packet forwarder between external and internal network interfaces
+#[derive(Parser, Debug)]
+#[command(
+    name = BIN_NAME,
+    author,
+    version,
+    about,
+    long_about = None,
+    args_conflicts_with_subcommands = true
+)]
+pub(crate) struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Options of `run`, which is the default when no command is given
+    #[command(flatten)]
+    run: Args,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Forward packets between the interfaces
+    Run(Box<Args>),
+    /// List the network interfaces with their addresses and flags
+    ListInterfaces(ListInterfaces),
+    /// Send a command to a running forwarder over its control socket
+    Ctl(Ctl),
+    /// Check a configuration file as the forwarder would at startup, or
+    /// print the JSON Schema of the format
+    CheckConfig(CheckConfig),
+    /// Print a completion script for a shell, e.g. for bash
+    /// `source <(nw-pckt-fwd completions bash)`
+    Completions(Completions),
+    /// Print the man page, or write one per command to a directory
+    Man(Man),
+}
+
+#[derive(clap::Args, Debug)]
+struct Completions {
+    /// Shell to print the script for
+    shell: Shell,
+}
+
+#[derive(clap::Args, Debug)]
+struct Man {
+    /// Write nw-pckt-fwd.1 and a page for each command to this directory
+    /// instead
+    #[arg(long, value_name = "DIR", value_hint = ValueHint::DirPath)]
+    out_dir: Option<PathBuf>,
+}
+
+#[derive(clap::Args, Debug)]
+struct ListInterfaces {
+    /// Print JSON instead of a table
+    #[arg(long)]
+    json: bool,
+}
+
+#[derive(clap::Args, Debug)]
+struct CheckConfig {
+    /// Configuration file to check
+    #[arg(value_hint = ValueHint::FilePath, required_unless_present = "schema")]
+    path: Option<PathBuf>,
+
+    /// Print the JSON Schema of the configuration file instead
+    #[arg(long, conflicts_with = "path")]
+    schema: bool,
+}
+
+#[derive(clap::Args, Debug)]
+struct Ctl {
+    /// Control socket of the forwarder
+    #[arg(long, value_name = "PATH", value_hint = ValueHint::FilePath, default_value = control::DEFAULT_SOCKET)]
+    socket: PathBuf,
+
+    #[command(subcommand)]
+    request: Request,
+}
+
+/// Logs at the configured level. RUST_LOG, when set, takes precedence over
+/// the config file but not over `--log-level` on the command line.
+fn init_logger(args: &Args, matches: &ArgMatches) {
+    let filters = if matches.value_source("log_level") == Some(ValueSource::CommandLine) {
+        None
+    } else {
+        std::env::var("RUST_LOG").ok()
+    };
+    logging::init(
+        args.log_level,
+        filters.as_deref(),
+        args.log_format,
+        args.log_target,
+    );
+    logging::set_suppression(Suppression {
+        window: args.log_suppress_window,
+        threshold: args.log_suppress_threshold,
+    });
+    logging::set_payload_dump(args.dump_payload);
+}
+
+/// Prints the system interfaces; needs no privileges as no channel is opened
+fn list_interfaces(list: &ListInterfaces) -> ExitCode {
+    let interfaces: Vec<InterfaceInfo> = datalink::interfaces()
+        .iter()
+        .map(InterfaceInfo::new)
+        .collect();
+    if list.json {
+        let json = serde_json::to_string_pretty(&interfaces).expect("interfaces serialize to JSON");
+        println!("{}", json);
+    } else {
+        print!("{}", interface_table(&interfaces));
+    }
+    ExitCode::SUCCESS
+}
+
+/// Prints the completion script for `shell`
+fn completions(completions: &Completions) -> ExitCode {
+    let names = datalink::interfaces()
+        .into_iter()
+        .map(|iface| iface.name)
+        .collect();
+    let script = completion_script(completions.shell, names);
+    match io::stdout().write_all(&script) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Failed to write the completion script: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Completion script for `shell`, in which the interface options offer
+/// `interfaces`, the ones present when the script is generated
+fn completion_script(shell: Shell, interfaces: Vec<String>) -> Vec<u8> {
+    let offer_interfaces = |mut command: clap::Command| {
+        for id in INTERFACE_OPTIONS {
+            command = command.mut_arg(id, |arg| {
+                arg.value_parser(PossibleValuesParser::new(interfaces.clone()))
+            });
+        }
+        command
+    };
+    let mut command = offer_interfaces(Cli::command()).mut_subcommand("run", offer_interfaces);
+    // Generated in full first, as the generator panics on write errors
+    let mut script = Vec::new();
+    clap_complete::generate(shell, &mut command, BIN_NAME, &mut script);
+    if shell == Shell::Bash {
+        // The bash generator mangles the dashes of the binary name
+        // differently in the cases of the subcommands than in the names it
+        // matches them against
+        let script_text = String::from_utf8(script).expect("completion scripts are UTF-8");
+        script = script_text
+            .replace(
+                &BIN_NAME.replace('-', "__subcmd__"),
+                &BIN_NAME.replace('-', "__"),
+            )
+            .into_bytes();
+    }
+    script
+}
+
+/// Prints the man page of the binary, or writes it and those of the
+/// commands to a directory
+fn man(man: &Man) -> ExitCode {
+    let command = Cli::command();
+    let written = match &man.out_dir {
+        Some(dir) => clap_mangen::generate_to(command, dir),
+        None => clap_mangen::Man::new(command).render(&mut io::stdout()),
+    };
+    match written {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Failed to write the man page: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Loads a configuration file as `--config` with no other options would,
+/// and checks it as at startup. Exits with 1 on the first problem found,
+/// described on stderr.
+fn check_config(check: &CheckConfig) -> ExitCode {
+    let Some(path) = &check.path else {
+        let json = serde_json::to_string_pretty(&schema::config()).expect("schemas are JSON");
+        println!("{}", json);
+        return ExitCode::SUCCESS;
+    };
+    let argv = [
+        OsStr::new(BIN_NAME),
+        OsStr::new("--config"),
+        path.as_os_str(),
+    ];
+    let matches = Cli::command().get_matches_from(argv);
+    let mut args =
+        Args::from_arg_matches(&matches).expect("--config alone is a valid command line");
+    if let Err(e) = config::apply_file(&mut args, &matches) {
+        eprintln!("{}", e);
+        return ExitCode::FAILURE;
+    }
+    if let Err((_, problem)) = config::check(&args) {
+        eprintln!("invalid configuration file {}: {}", path.display(), problem);
+        return ExitCode::FAILURE;
+    }
+    println!("{} is valid", path.display());
+    ExitCode::SUCCESS
+}
+
+/// Sends one request to a running forwarder and prints the result as JSON
+async fn ctl(ctl: &Ctl) -> ExitCode {
+    let response = match control::request(&ctl.socket, &ctl.request).await {
+        Ok(response) => response,
+        Err(e) => {
+            eprintln!(
+                "Failed to reach the forwarder at {}: {}",
+                ctl.socket.display(),
+                e
+            );
+            return ExitCode::FAILURE;
+        }
+    };
+    if let Some(error) = response.error {
+        eprintln!("{}", error);
+        return ExitCode::FAILURE;
+    }
+    if !response.result.is_null() {
+        let json =
+            serde_json::to_string_pretty(&response.result).expect("results serialize to JSON");
+        println!("{}", json);
+    }
+    ExitCode::SUCCESS
+}
+
+/// Entry point of the binary: parses the command line, merges the
+/// configuration file and forwards until interrupted
+pub async fn main() -> ExitCode {
+    let matches = Cli::command().get_matches();
+    let cli = Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    let (mut args, matches) = match cli.command {
+        Some(Command::ListInterfaces(list)) => return list_interfaces(&list),
+        Some(Command::Ctl(request)) => return ctl(&request).await,
+        Some(Command::CheckConfig(check)) => return check_config(&check),
+        Some(Command::Completions(shell)) => return completions(&shell),
+        Some(Command::Man(pages)) => return man(&pages),
+        Some(Command::Run(args)) => {
+            let matches = matches.subcommand_matches("run").expect("run was given");
+            (*args, matches.clone())
+        }
+        None => (cli.run, matches),
+    };
+    let loaded = config::apply_file(&mut args, &matches);
+    init_logger(&args, &matches);
+    if args.debug {
+        std::env::set_var("RUST_BACKTRACE", "1");
+    }
+    if let Err(e) = loaded {
+        error!("{}", e);
+        return e.exit_code();
+    }
+    if let Err(e) = config::validate(&args) {
+        e.exit();
+    }
+    if args.dump_config {
+        print!("{}", config::dump(&args));
+        return ExitCode::SUCCESS;
+    }
+
+    match run(args, matches).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            error!("{}", e);
+            e.exit_code()
+        }
+    }
+}
+
+/// Forwards until SIGINT, reloading the configuration file on SIGHUP,
+/// logging the state on SIGUSR1 and pausing or resuming on SIGUSR2.
+/// With `--control-socket` requests on the socket are answered meanwhile.
+async fn run(args: Args, matches: ArgMatches) -> Result<(), Error> {
+    let config = args.config.clone();
+    let listener = match &args.control_socket {
+        Some(path) => Some((control::bind(path)?, path.clone())),
+        None => None,
+    };
+    let forwarder = Arc::new(Forwarder::new(args));
+    let stop = CancellationToken::new();
+    let server = listener.map(|(listener, path)| {
+        let controller = Controller {
+            forwarder: forwarder.clone(),
+            config: config.clone(),
+            matches: matches.clone(),
+        };
+        tokio::spawn(control::serve(
+            listener,
+            path,
+            Arc::new(controller),
+            stop.clone(),
+        ))
+    });
+    let mut hangup = signal(SignalKind::hangup()).map_err(Error::Signal)?;
+    let mut dump = signal(SignalKind::user_defined1()).map_err(Error::Signal)?;
+    let mut toggle = signal(SignalKind::user_defined2()).map_err(Error::Signal)?;
+    let mut terminate = signal(SignalKind::terminate()).map_err(Error::Signal)?;
+    let notifier = Notifier::from_env();
+    let mut interrupted = Ok(());
+    let signals = async {
+        loop {
+            tokio::select! {
+                signal = tokio::signal::ctrl_c() => {
+                    interrupted = signal;
+                    break;
+                }
+                _ = terminate.recv() => break,
+                _ = hangup.recv() => {
+                    let _ = reload(&forwarder, config.as_deref(), &matches).await;
+                }
+                _ = dump.recv() => forwarder.dump_state(),
+                _ = toggle.recv() => {
+                    forwarder.toggle_pause();
+                }
+            }
+        }
+        if let Some(notifier) = &notifier {
+            notifier.notify("STOPPING=1");
+        }
+        forwarder.shutdown();
+        // The forwarder finishes on its own once shut down
+        std::future::pending::<()>().await
+    };
+    let result = tokio::select! {
+        result = forwarder.run(CancellationToken::new()) => result,
+        _ = signals => unreachable!("signal handling never finishes"),
+        _ = supervise(&forwarder, notifier.as_ref()) => unreachable!("supervision never finishes"),
+    };
+    stop.cancel();
+    if let Some(server) = server {
+        let _ = server.await;
+    }
+    result.and(interrupted.map_err(Error::Signal))
+}
+
+/// Carries out control socket requests on the running forwarder
+struct Controller {
+    forwarder: Arc<Forwarder>,
+    config: Option<PathBuf>,
+    matches: ArgMatches,
+}
+
+impl Handler for Controller {
+    async fn handle(&self, request: Request) -> Result<Json, String> {
+        let not_running = || "the forwarder is not running".to_string();
+        match request {
+            Request::Stats => {
+                let stats = self.forwarder.stats().await.ok_or_else(not_running)?;
+                serde_json::to_value(stats).map_err(|e| e.to_string())
+            }
+            Request::Devices => {
+                let discovered = self.forwarder.devices().await.ok_or_else(not_running)?;
+                let inventory = self.forwarder.inventory().await.ok_or_else(not_running)?;
+                let learned = self.forwarder.learned().await.ok_or_else(not_running)?;
+                let learned: Map<String, Json> = learned
+                    .into_iter()
+                    .map(|(title, lines)| (title, lines.into()))
+                    .collect();
+                Ok(json!({ "discovered": discovered, "inventory": inventory, "state": learned }))
+            }
+            Request::Pause => Ok(json!({ "changed": self.forwarder.pause() })),
+            Request::Resume => Ok(json!({ "changed": self.forwarder.resume() })),
+            Request::SetLogLevel { level } => {
+                logging::set_level(level)?;
+                let name = level.to_possible_value().expect("levels have names");
+                info!("Log level set to {}", name.get_name());
+                Ok(Json::Null)
+            }
+            Request::Reload => {
+                let ignored =
+                    reload(&self.forwarder, self.config.as_deref(), &self.matches).await?;
+                Ok(json!({ "ignored": ignored }))
+            }
+            Request::ResetStats => {
+                self.forwarder.reset_stats();
+                Ok(Json::Null)
+            }
+            Request::ResetQuotas => {
+                self.forwarder.reset_quotas();
+                Ok(Json::Null)
+            }
+        }
+    }
+}
+
+/// Tells systemd once the forwarder is ready, then keeps its status line
+/// current, at once when forwarding is paused or resumed, and pings the
+/// watchdog for as long as the forwarder answers
+async fn supervise(forwarder: &Forwarder, notifier: Option<&Notifier>) {
+    let Some(notifier) = notifier else {
+        return std::future::pending().await;
+    };
+    forwarder.ready().await;
+    notifier.notify("READY=1\nSTATUS=Forwarding");
+    let watchdog = notifier.watchdog();
+    let mut interval = tokio::time::interval(watchdog.unwrap_or(STATUS_INTERVAL));
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = forwarder.pause_changed() => {}
+        }
+        let Some(status) = forwarder.status().await else {
+            continue;
+        };
+        match watchdog {
+            Some(_) => notifier.notify(&format!("WATCHDOG=1\nSTATUS={}", status)),
+            None => notifier.notify(&format!("STATUS={}", status)),
+        }
+    }
+}
+
+/// Re-reads the configuration file and hands it to the forwarder, which
+/// applies the options that can change while running and returns the keys
+/// of those changed that need a restart. A file that fails to load or
+/// validate leaves the running configuration as it is. Failures are logged
+/// and returned for the control socket.
+async fn reload(
+    forwarder: &Forwarder,
+    config: Option<&Path>,
+    matches: &ArgMatches,
+) -> Result<Vec<String>, String> {
+    let Some(path) = config else {
+        warn!("Reload requested but no --config file is in use, nothing to reload");
+        return Err("no --config file is in use, nothing to reload".to_string());
+    };
+    info!("Reloading configuration from {}", path.display());
+    let mut new = Args::from_arg_matches(matches).expect("command line was parsed at startup");
+    if let Err(e) = config::apply_file(&mut new, matches) {
+        error!("Keeping the current configuration, {}", e);
+        return Err(e.to_string());
+    }
+    forwarder.reload(new).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::logging::LogLevel;
+    use std::fs;
+
+    /// Forwarder started from the configuration file at `path`, answering
+    /// reloads, and the command line it was started with
+    fn forwarder(path: &Path) -> (Arc<Forwarder>, ArgMatches) {
+        let argv = [
+            OsStr::new(BIN_NAME),
+            OsStr::new("--config"),
+            path.as_os_str(),
+        ];
+        let matches = Cli::command().try_get_matches_from(argv).unwrap();
+        let mut args = Args::from_arg_matches(&matches).unwrap();
+        config::apply_file(&mut args, &matches).unwrap();
+        let forwarder = Arc::new(Forwarder::new(args));
+        forwarder.serve_reloads();
+        (forwarder, matches)
+    }
+
+    fn write_config(path: &Path, external: &str, ports: &str) {
+        let text = format!(
+            "external-iface = \"{}\"\ninternal-iface = \"vm0\"\nports = {}\n",
+            external, ports
+        );
+        fs::write(path, text).unwrap();
+    }
+
+    #[test]
+    fn logs_at_info_unless_asked_otherwise() {
+        let pair = ["--external-iface", "eth0", "--internal-iface", "vm1"];
+        let cli = Cli::try_parse_from([BIN_NAME].iter().chain(&pair)).unwrap();
+        assert_eq!(cli.run.log_level, LogLevel::Info);
+        let quiet = [BIN_NAME, "--log-level", "warn"];
+        let cli = Cli::try_parse_from(quiet.iter().chain(&pair)).unwrap();
+        assert_eq!(cli.run.log_level, LogLevel::Warn);
+    }
+
+    #[test]
+    fn bash_completions_match_every_command_they_name() {
+        let script = completion_script(Shell::Bash, vec!["eth0".to_string(), "vm1".to_string()]);
+        let script = String::from_utf8(script).unwrap();
+        let named: Vec<&str> = script
+            .lines()
+            .filter_map(|line| line.trim().strip_prefix("cmd=\""))
+            .map(|name| name.trim_end_matches('"'))
+            .filter(|name| !name.is_empty())
+            .collect();
+        assert!(named.contains(&"nw__pckt__fwd__subcmd__run"));
+        for name in named {
+            assert!(
+                script.contains(&format!("\n        {})\n", name)),
+                "{}",
+                name
+            );
+        }
+        assert!(script.contains(
+            "--external-iface)\n                    COMPREPLY=($(compgen -W \"eth0 vm1\""
+        ));
+    }
+
+    #[test]
+    fn writes_a_man_page_per_command() {
+        let dir = std::env::temp_dir().join(format!("nw-pckt-fwd-man-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let written = man(&Man {
+            out_dir: Some(dir.clone()),
+        });
+        let pages: Vec<_> = ["nw-pckt-fwd.1", "nw-pckt-fwd-run.1", "nw-pckt-fwd-ctl.1"]
+            .iter()
+            .map(|page| fs::read_to_string(dir.join(page)))
+            .collect();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(written, ExitCode::SUCCESS);
+        for page in pages {
+            assert!(page.unwrap().starts_with(".ie \\n(.g .ds Aq"));
+        }
+    }
+
+    #[tokio::test]
+    async fn reload_reports_files_that_fail_and_changes_it_ignores() {
+        let dir = std::env::temp_dir().join(format!("nw-pckt-fwd-reload-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("forwarder.toml");
+        write_config(&path, "eth0", "[5000]");
+        let (forwarder, matches) = forwarder(&path);
+
+        write_config(&path, "eth0", "[5000");
+        let error = reload(&forwarder, Some(&path), &matches).await.unwrap_err();
+        assert!(error.contains("invalid configuration file"), "{}", error);
+        // Loads, but does not validate
+        fs::write(&path, "external-iface = \"eth0\"\n").unwrap();
+        let error = reload(&forwarder, Some(&path), &matches).await.unwrap_err();
+        assert!(error.contains("must be given together"), "{}", error);
+
+        write_config(&path, "eth1", "[6000]");
+        let ignored = reload(&forwarder, Some(&path), &matches).await;
+        assert_eq!(ignored.unwrap(), ["external-iface"]);
+        assert!(reload(&forwarder, None, &matches).await.is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn control_socket_reload_fails_for_an_invalid_file() {
+        let dir = std::env::temp_dir().join(format!("nw-pckt-fwd-ctl-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("forwarder.toml");
+        write_config(&path, "eth0", "[5000]");
+        let (forwarder, matches) = forwarder(&path);
+        let controller = Controller {
+            forwarder,
+            config: Some(path.clone()),
+            matches,
+        };
+        let socket = dir.join("control.sock");
+        let listener = control::bind(&socket).unwrap();
+        let token = CancellationToken::new();
+        let server = tokio::spawn(control::serve(
+            listener,
+            socket.clone(),
+            Arc::new(controller),
+            token.clone(),
+        ));
+
+        let answer = control::request(&socket, &Request::Reload).await.unwrap();
+        assert!(answer.ok, "{:?}", answer.error);
+        assert_eq!(answer.result, json!({ "ignored": [] }));
+        fs::write(&path, "external-iface = \"eth0\"\nbridge = [\"vm0\"]\n").unwrap();
+        let answer = control::request(&socket, &Request::Reload).await.unwrap();
+        assert!(!answer.ok);
+        let error = answer.error.unwrap();
+        assert!(error.contains("only one of"), "{}", error);
+
+        token.cancel();
+        server.await.unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,208 @@
+//! CoAP (RFC 7252) specific handling, used by OCF devices and Thread border
+//! routers to discover each other with multicast requests to 224.0.1.187
+//! and ff0x::fd.
+
+use crate::filter::{Decision, Filter, PacketContext, COAP_PORT};
+use crate::logging::{debug_repeated, RepeatedMessages};
+use crate::pair::Direction;
+use pnet::packet::Packet;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+const HEADER_LEN: usize = 4;
+const VERSION: u8 = 1;
+const MAX_TOKEN_LEN: usize = 8;
+
+/// What a CoAP message is, as far as its direction is concerned
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    /// A method such as GET, sent by clients
+    Request,
+    /// Success or error answer, sent by servers
+    Response,
+    /// Acknowledgement, reset or ping without a method or answer
+    Empty,
+}
+
+/// Classifies a CoAP message by its code. Returns `None` if the header is
+/// truncated, of another version or has a reserved token length or code
+/// class.
+fn kind(payload: &[u8]) -> Option<Kind> {
+    let header = payload.get(..HEADER_LEN)?;
+    let token_len = usize::from(header[0] & 0x0f);
+    if header[0] >> 6 != VERSION
+        || token_len > MAX_TOKEN_LEN
+        || payload.len() < HEADER_LEN + token_len
+    {
+        return None;
+    }
+    match (header[1] >> 5, header[1] & 0x1f) {
+        (0, 0) => Some(Kind::Empty),
+        (0, _) => Some(Kind::Request),
+        (2 | 4 | 5, _) => Some(Kind::Response),
+        _ => None,
+    }
+}
+
+/// Lets through well-formed CoAP messages. Requests are only accepted from
+/// the internal interface and responses only from the external one, unless
+/// relaxed; empty messages go both ways. In bridge mode direction is not
+/// checked.
+pub struct CoapMessageFilter {
+    external_request: bool,
+    internal_response: bool,
+    malformed: AtomicU64,
+    wrong_direction: AtomicU64,
+    log: RepeatedMessages,
+}
+
+impl CoapMessageFilter {
+    pub fn new(external_request: bool, internal_response: bool) -> Self {
+        CoapMessageFilter {
+            external_request,
+            internal_response,
+            malformed: AtomicU64::new(0),
+            wrong_direction: AtomicU64::new(0),
+            log: RepeatedMessages::default(),
+        }
+    }
+
+    fn dropped(&self, counter: &AtomicU64, what: &str) -> Decision {
+        counter.fetch_add(1, Ordering::Relaxed);
+        debug_repeated!(
+            self.log,
+            "CoAP messages dropped",
+            "CoAP message dropped: {}",
+            what
+        );
+        Decision::Drop
+    }
+}
+
+impl Filter for CoapMessageFilter {
+    fn name(&self) -> &str {
+        "coap-messages"
+    }
+
+    fn evaluate(&self, ctx: &PacketContext) -> Decision {
+        let Some(udp) = ctx.udp() else {
+            return Decision::Continue;
+        };
+        if udp.get_source() != COAP_PORT && udp.get_destination() != COAP_PORT {
+            return Decision::Continue;
+        }
+        let Some(kind) = kind(udp.payload()) else {
+            return self.dropped(&self.malformed, "malformed");
+        };
+        let expected = match (kind, ctx.direction) {
+            (Kind::Empty, _) | (_, Direction::Bridged) => true,
+            (Kind::Request, Direction::Outbound) | (Kind::Response, Direction::Inbound) => true,
+            (Kind::Request, Direction::Inbound) => self.external_request,
+            (Kind::Response, Direction::Outbound) => self.internal_response,
+        };
+        if !expected {
+            return self.dropped(&self.wrong_direction, "wrong direction");
+        }
+        Decision::Continue
+    }
+
+    fn state(&self) -> Option<Vec<String>> {
+        Some(vec![format!(
+            "dropped malformed={} wrong-direction={}",
+            self.malformed.load(Ordering::Relaxed),
+            self.wrong_direction.load(Ordering::Relaxed)
+        )])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{multicast_mac, udp_frame, HOST_MAC};
+    use pnet::util::MacAddr;
+    use std::net::{IpAddr, Ipv6Addr};
+
+    /// All CoAP Nodes, site-local scope
+    const COAP_SITE_GROUP: Ipv6Addr = Ipv6Addr::new(0xff05, 0, 0, 0, 0, 0, 0, 0xfd);
+
+    /// Non-confirmable GET of /.well-known/core?rt=oic.wk.d with a 2-byte
+    /// token, as sent by an OCF client discovering devices
+    const DISCOVERY: &[u8] = b"\x52\x01\x7a\x31\xc4\x1e\
+        \xbb.well-known\x04core\x4brt=oic.wk.d";
+    /// Content answer to it, link format with the token echoed
+    const DISCOVERY_RESPONSE: &[u8] = b"\x52\x45\x51\x0e\xc4\x1e\
+        \xc1\x28\xff</oic/d>;rt=\"oic.wk.d\"";
+
+    fn frame(payload: &[u8], to_group: bool) -> Vec<u8> {
+        let client = Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 0x10);
+        let device = Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 0x20);
+        if to_group {
+            let group = multicast_mac(IpAddr::V6(COAP_SITE_GROUP));
+            udp_frame(
+                HOST_MAC,
+                group,
+                client,
+                COAP_SITE_GROUP,
+                49200,
+                COAP_PORT,
+                payload,
+            )
+        } else {
+            let client_mac = MacAddr(0x02, 0, 0, 0, 0, 0x10);
+            udp_frame(
+                HOST_MAC, client_mac, device, client, COAP_PORT, 49200, payload,
+            )
+        }
+    }
+
+    fn evaluate(filter: &CoapMessageFilter, direction: Direction, frame: &[u8]) -> Decision {
+        filter.evaluate(&PacketContext::parse("eth0", direction, frame).unwrap())
+    }
+
+    #[test]
+    fn checks_direction_of_discovery() {
+        assert_eq!(kind(DISCOVERY), Some(Kind::Request));
+        assert_eq!(kind(DISCOVERY_RESPONSE), Some(Kind::Response));
+        assert_eq!(kind(b"\x60\x00\x7a\x31"), Some(Kind::Empty));
+        assert_eq!(kind(b"\x52\x01\x7a\x31\xc4"), None);
+        assert_eq!(kind(b"\x91\x01\x7a\x31\xc4"), None);
+        assert_eq!(kind(b"\x50\xe0\x7a\x31"), None);
+
+        let filter = CoapMessageFilter::new(false, false);
+        let request = frame(DISCOVERY, true);
+        let response = frame(DISCOVERY_RESPONSE, false);
+        assert_eq!(
+            evaluate(&filter, Direction::Outbound, &request),
+            Decision::Continue
+        );
+        assert_eq!(
+            evaluate(&filter, Direction::Inbound, &response),
+            Decision::Continue
+        );
+        assert_eq!(
+            evaluate(&filter, Direction::Inbound, &request),
+            Decision::Drop
+        );
+        assert_eq!(
+            evaluate(&filter, Direction::Outbound, &response),
+            Decision::Drop
+        );
+        assert_eq!(
+            evaluate(&filter, Direction::Bridged, &request),
+            Decision::Continue
+        );
+        assert_eq!(
+            evaluate(&filter, Direction::Outbound, &frame(b"\x52", true)),
+            Decision::Drop
+        );
+        assert_eq!(
+            filter.state().unwrap(),
+            ["dropped malformed=1 wrong-direction=2"]
+        );
+
+        let relaxed = CoapMessageFilter::new(true, true);
+        assert_eq!(
+            evaluate(&relaxed, Direction::Inbound, &request),
+            Decision::Continue
+        );
+    }
+}
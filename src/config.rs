@@ -0,0 +1,1362 @@
+//! TOML configuration file mirroring the command line options.
+
+use crate::allowlist::IpNetwork;
+use crate::arp::ArpMode;
+use crate::cli::Cli;
+use crate::dscp::{DscpMark, MarkDirection};
+use crate::error::Error;
+use crate::expression::Expression;
+use crate::iface::{is_valid_name, Backend, LinkDown, PerInterface};
+use crate::iplink::LinkType;
+use crate::logging::{LogFormat, LogLevel, LogTarget};
+use crate::mdnsmerge::RESPONSE_DELAY;
+use crate::nameservice::NameServiceMode;
+use crate::ndp::NdpMode;
+use crate::otel::{OtlpHeader, OtlpProtocol};
+use crate::oversize::OversizePolicy;
+use crate::pair::Pair;
+use crate::pcap::parse_size;
+use crate::profile::Profile;
+use crate::quota::Quota;
+use crate::rules::Rule;
+use crate::seccomp::SeccompMode;
+use crate::sender::QueuePolicy;
+use crate::shaper::Shape;
+use crate::snooping::UnknownGroups;
+use crate::ssdp::LocationMapping;
+use crate::supervise::OnTaskFailure;
+use crate::threads::Affinity;
+use crate::vlan::VlanEgress;
+use crate::wsd::WsdAction;
+use crate::{Args, Promiscuous};
+use clap::error::ErrorKind;
+use clap::parser::ValueSource;
+use clap::{ArgMatches, CommandFactory};
+use pnet::util::MacAddr;
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer, Serialize};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddrV4};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Settings read from `--config`. Keys are named after the long command
+/// line options; anything left out keeps the command line default.
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Config {
+    pub log_level: Option<LogLevel>,
+    pub log_format: Option<LogFormat>,
+    pub log_target: Option<LogTarget>,
+    #[serde(default, with = "humantime_serde")]
+    pub log_suppress_window: Option<Duration>,
+    pub log_suppress_threshold: Option<u64>,
+    pub dump_payload: Option<usize>,
+    pub debug: Option<bool>,
+    pub external_iface: Option<String>,
+    pub internal_iface: Option<String>,
+    pub bridge: Option<Vec<String>>,
+    pub external_vlan: Option<u16>,
+    pub internal_vlan: Option<u16>,
+    pub internal_link_type: Option<LinkType>,
+    pub external_netns: Option<String>,
+    pub internal_netns: Option<String>,
+    pub vlan_egress: Option<VlanEgress>,
+    #[serde(default, deserialize_with = "at_least_one")]
+    pub mac_table_size: Option<usize>,
+    #[serde(default, with = "humantime_serde")]
+    pub mac_ttl: Option<Duration>,
+    pub wait_for_iface: Option<bool>,
+    #[serde(default, with = "humantime_serde")]
+    pub wait_timeout: Option<Duration>,
+    pub on_link_down: Option<LinkDown>,
+    pub profile: Option<Vec<Profile>>,
+    #[serde(default, deserialize_with = "nonzero_ports")]
+    pub ports: Option<Vec<u16>>,
+    #[serde(default, deserialize_with = "nonzero_ports")]
+    pub tcp_ports: Option<Vec<u16>>,
+    pub rule: Option<Vec<Rule>>,
+    pub filter: Option<Vec<Expression>>,
+    pub no_kernel_filter: Option<bool>,
+    pub allow_src_mac: Option<Vec<MacAddr>>,
+    pub allow_src_ip: Option<Vec<IpNetwork>>,
+    #[serde(default, deserialize_with = "at_least_one")]
+    pub max_pps: Option<u32>,
+    #[serde(default, deserialize_with = "at_least_one")]
+    pub max_pps_per_host: Option<u32>,
+    pub mdns_max_pps_per_host: Option<u32>,
+    pub storm_threshold: Option<u32>,
+    #[serde(default, with = "humantime_serde")]
+    pub rate_limit_burst: Option<Duration>,
+    #[serde(default, deserialize_with = "at_least_one")]
+    pub rate_limit_hosts: Option<usize>,
+    pub quota: Option<Vec<Quota>>,
+    pub shape: Option<Vec<Shape>>,
+    #[serde(default, with = "humantime_serde")]
+    pub shape_max_delay: Option<Duration>,
+    #[serde(default, with = "humantime_serde")]
+    pub loop_window: Option<Duration>,
+    pub no_loop_drop_repeats: Option<bool>,
+    pub no_loop_guard: Option<bool>,
+    pub enable_mdns: Option<bool>,
+    pub mdns_services: Option<Vec<String>>,
+    pub no_mdns_filtering: Option<bool>,
+    pub chromecast_strict: Option<bool>,
+    #[serde(default, with = "humantime_serde")]
+    pub mdns_max_ttl: Option<Duration>,
+    pub mdns_enforce_ttl: Option<bool>,
+    pub mdns_strip_txt: Option<bool>,
+    pub mdns_strip_txt_key: Option<Vec<String>>,
+    pub mdns_cache: Option<bool>,
+    #[serde(default, deserialize_with = "at_least_one")]
+    pub mdns_cache_size: Option<usize>,
+    pub no_mdns_tracking: Option<bool>,
+    #[serde(default, with = "humantime_serde")]
+    pub mdns_response_window: Option<Duration>,
+    #[serde(default, with = "humantime_serde")]
+    pub mdns_aggregate: Option<Duration>,
+    pub mdns_max_queries: Option<usize>,
+    pub disable_ssdp: Option<bool>,
+    pub disable_ipv6: Option<bool>,
+    pub masquerade_mac: Option<bool>,
+    pub decrement_ttl: Option<bool>,
+    pub ttl_exempt_port: Option<Vec<u16>>,
+    pub dscp: Option<Vec<DscpMark>>,
+    pub dscp_direction: Option<MarkDirection>,
+    pub dscp_pcp: Option<bool>,
+    pub rewrite_unicast_mac: Option<bool>,
+    pub snat: Option<Snat>,
+    pub promiscuous: Option<Promiscuous>,
+    #[serde(default, deserialize_with = "multicast_groups")]
+    pub join_group: Option<Vec<IpAddr>>,
+    #[serde(default, deserialize_with = "at_least_one")]
+    pub send_queue_capacity: Option<usize>,
+    pub queue_policy: Option<QueuePolicy>,
+    #[serde(default, deserialize_with = "at_least_one")]
+    pub buffer_pool_size: Option<usize>,
+    pub rx_affinity: Option<Vec<Affinity>>,
+    pub tx_affinity: Option<Vec<Affinity>>,
+    pub backend: Option<Backend>,
+    pub batch_size: Option<usize>,
+    pub rx_buffer_size: Option<Vec<PerInterface<u64>>>,
+    pub tx_buffer_size: Option<Vec<PerInterface<u64>>>,
+    pub read_timeout: Option<Vec<PerInterface<Duration>>>,
+    pub on_task_failure: Option<OnTaskFailure>,
+    pub max_task_restarts: Option<u32>,
+    pub oversize_policy: Option<OversizePolicy>,
+    pub icmp_too_big: Option<bool>,
+    pub verify_checksums: Option<bool>,
+    pub no_ssdp_tracking: Option<bool>,
+    #[serde(default, with = "humantime_serde")]
+    pub ssdp_response_window: Option<Duration>,
+    pub ssdp_max_searches: Option<usize>,
+    pub ssdp_targets: Option<Vec<String>>,
+    pub no_ssdp_filtering: Option<bool>,
+    pub ssdp_external_search: Option<bool>,
+    pub ssdp_internal_announce: Option<bool>,
+    pub ssdp_location_map: Option<Vec<LocationMapping>>,
+    pub ssdp_location_fail_closed: Option<bool>,
+    pub ssdp_cache: Option<bool>,
+    #[serde(default, deserialize_with = "at_least_one")]
+    pub ssdp_cache_size: Option<usize>,
+    pub ssdp_server_string: Option<String>,
+    pub device_inventory: Option<bool>,
+    #[serde(default, deserialize_with = "at_least_one")]
+    pub device_inventory_size: Option<usize>,
+    #[serde(default, with = "humantime_serde")]
+    pub device_retention: Option<Duration>,
+    pub probe: Option<bool>,
+    #[serde(default, with = "humantime_serde")]
+    pub probe_interval: Option<Duration>,
+    #[serde(default, with = "humantime_serde")]
+    pub probe_jitter: Option<Duration>,
+    pub probe_ssdp_targets: Option<Vec<String>>,
+    pub probe_mdns_services: Option<Vec<String>>,
+    pub enable_wsd: Option<bool>,
+    pub wsd_actions: Option<Vec<WsdAction>>,
+    pub no_wsd_filtering: Option<bool>,
+    pub wsd_external_probe: Option<bool>,
+    pub wsd_internal_announce: Option<bool>,
+    pub enable_coap: Option<bool>,
+    pub no_coap_filtering: Option<bool>,
+    pub coap_external_request: Option<bool>,
+    pub coap_internal_response: Option<bool>,
+    pub llmnr: Option<NameServiceMode>,
+    pub llmnr_names: Option<Vec<String>>,
+    pub llmnr_enforce_ttl: Option<bool>,
+    pub netbios_ns: Option<NameServiceMode>,
+    pub enable_wol: Option<bool>,
+    pub wol_targets: Option<Vec<MacAddr>>,
+    pub pass_eapol: Option<bool>,
+    pub pass_lldp: Option<bool>,
+    pub dhcp_relay: Option<Ipv4Addr>,
+    pub dhcp_relay_option82: Option<bool>,
+    pub forward_ra: Option<bool>,
+    pub ra_rewrite_source: Option<bool>,
+    pub dhcpv6_relay: Option<Ipv6Addr>,
+    pub arp_mode: Option<ArpMode>,
+    pub ndp_mode: Option<NdpMode>,
+    pub no_snooping: Option<bool>,
+    pub snooping_unknown: Option<UnknownGroups>,
+    #[serde(default, with = "humantime_serde")]
+    pub stats_interval: Option<Duration>,
+    pub pcap_forwarded: Option<PathBuf>,
+    pub pcap_dropped: Option<PathBuf>,
+    #[serde(default, deserialize_with = "size")]
+    pub pcap_max_size: Option<u64>,
+    pub pcap_in: Option<PathBuf>,
+    pub replay_timing: Option<bool>,
+    pub state_file: Option<PathBuf>,
+    #[serde(default, with = "humantime_serde")]
+    pub state_save_interval: Option<Duration>,
+    pub mirror_iface: Option<String>,
+    pub mirror_dropped: Option<bool>,
+    #[serde(default, deserialize_with = "standby_group")]
+    pub standby_group: Option<SocketAddrV4>,
+    pub standby_iface: Option<String>,
+    pub standby_lock: Option<PathBuf>,
+    pub standby_priority: Option<u8>,
+    #[serde(default, with = "humantime_serde")]
+    pub standby_interval: Option<Duration>,
+    #[serde(default, deserialize_with = "at_least_one")]
+    pub standby_missed: Option<u32>,
+    pub user: Option<String>,
+    pub group: Option<String>,
+    pub seccomp: Option<SeccompMode>,
+    pub control_socket: Option<PathBuf>,
+    pub dbus: Option<bool>,
+    pub otel: Option<bool>,
+    pub otel_endpoint: Option<String>,
+    pub otel_protocol: Option<OtlpProtocol>,
+    pub otel_header: Option<Vec<OtlpHeader>>,
+    #[serde(default, with = "humantime_serde")]
+    pub otel_export_interval: Option<Duration>,
+    pub dry_run: Option<bool>,
+    pub trace_packets: Option<bool>,
+    pub pair: Option<Vec<Pair>>,
+}
+
+/// `snat = true` translates to the first IPv4 address of the external
+/// interface, `snat = "192.0.2.1"` to the given address
+#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum Snat {
+    Enabled(bool),
+    Address(Ipv4Addr),
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        let text = std::fs::read_to_string(path).map_err(|source| Error::ConfigRead {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        toml::from_str(&text).map_err(|source| Error::ConfigParse {
+            path: path.to_path_buf(),
+            source,
+        })
+    }
+}
+
+/// Fills every option not given on the command line from `config`. The
+/// interface selection is taken as a whole from one place, so
+/// `--external-iface` on the command line is never combined with pairs
+/// from the file.
+fn merge(args: &mut Args, config: Config, matches: &ArgMatches) {
+    let from_cli = |id: &str| matches.value_source(id) == Some(ValueSource::CommandLine);
+    macro_rules! fill {
+        ($($field:ident),* $(,)?) => {$(
+            if let (false, Some(value)) = (from_cli(stringify!($field)), config.$field) {
+                args.$field = value;
+            }
+        )*};
+    }
+
+    let interfaces = ["external_iface", "internal_iface", "pair", "bridge"];
+    if !interfaces.iter().any(|id| from_cli(id)) {
+        args.external_iface = config.external_iface;
+        args.internal_iface = config.internal_iface;
+        args.pair = config.pair.unwrap_or_default();
+        args.bridge = config.bridge.unwrap_or_default();
+    }
+    if let (false, Some(timeout)) = (from_cli("wait_timeout"), config.wait_timeout) {
+        args.wait_timeout = Some(timeout);
+    }
+    if let (false, Some(window)) = (from_cli("mdns_aggregate"), config.mdns_aggregate) {
+        args.mdns_aggregate = Some(window);
+    }
+    if let (false, Some(interval)) = (from_cli("stats_interval"), config.stats_interval) {
+        args.stats_interval = Some(interval);
+    }
+    if let (false, Some(server)) = (from_cli("ssdp_server_string"), config.ssdp_server_string) {
+        args.ssdp_server_string = Some(server);
+    }
+    if let (false, Some(path)) = (from_cli("pcap_forwarded"), config.pcap_forwarded) {
+        args.pcap_forwarded = Some(path);
+    }
+    if let (false, Some(path)) = (from_cli("pcap_dropped"), config.pcap_dropped) {
+        args.pcap_dropped = Some(path);
+    }
+    if let (false, Some(size)) = (from_cli("pcap_max_size"), config.pcap_max_size) {
+        args.pcap_max_size = Some(size);
+    }
+    if let (false, Some(path)) = (from_cli("pcap_in"), config.pcap_in) {
+        args.pcap_in = Some(path);
+    }
+    if let (false, Some(path)) = (from_cli("state_file"), config.state_file) {
+        args.state_file = Some(path);
+    }
+    if let (false, Some(name)) = (from_cli("mirror_iface"), config.mirror_iface) {
+        args.mirror_iface = Some(name);
+    }
+    if let (false, Some(group)) = (from_cli("standby_group"), config.standby_group) {
+        args.standby_group = Some(group);
+    }
+    if let (false, Some(name)) = (from_cli("standby_iface"), config.standby_iface) {
+        args.standby_iface = Some(name);
+    }
+    if let (false, Some(path)) = (from_cli("standby_lock"), config.standby_lock) {
+        args.standby_lock = Some(path);
+    }
+    if let (false, Some(path)) = (from_cli("control_socket"), config.control_socket) {
+        args.control_socket = Some(path);
+    }
+    if let (false, Some(name)) = (from_cli("user"), config.user) {
+        args.user = Some(name);
+    }
+    if let (false, Some(name)) = (from_cli("group"), config.group) {
+        args.group = Some(name);
+    }
+    if let (false, Some(rate)) = (from_cli("max_pps"), config.max_pps) {
+        args.max_pps = Some(rate);
+    }
+    if let (false, Some(rate)) = (from_cli("max_pps_per_host"), config.max_pps_per_host) {
+        args.max_pps_per_host = Some(rate);
+    }
+    if let (false, Some(ttl)) = (from_cli("mdns_max_ttl"), config.mdns_max_ttl) {
+        args.mdns_max_ttl = Some(ttl);
+    }
+    if let (false, Some(snat)) = (from_cli("snat"), config.snat) {
+        args.snat = match snat {
+            Snat::Enabled(false) => None,
+            Snat::Enabled(true) => Some(None),
+            Snat::Address(ip) => Some(Some(ip)),
+        };
+    }
+    if let (false, Some(id)) = (from_cli("external_vlan"), config.external_vlan) {
+        args.external_vlan = Some(id);
+    }
+    if let (false, Some(id)) = (from_cli("internal_vlan"), config.internal_vlan) {
+        args.internal_vlan = Some(id);
+    }
+    if let (false, Some(netns)) = (from_cli("external_netns"), config.external_netns) {
+        args.external_netns = Some(netns);
+    }
+    if let (false, Some(netns)) = (from_cli("internal_netns"), config.internal_netns) {
+        args.internal_netns = Some(netns);
+    }
+    if let (false, Some(server)) = (from_cli("dhcp_relay"), config.dhcp_relay) {
+        args.dhcp_relay = Some(server);
+    }
+    if let (false, Some(server)) = (from_cli("dhcpv6_relay"), config.dhcpv6_relay) {
+        args.dhcpv6_relay = Some(server);
+    }
+    if let (false, Some(url)) = (from_cli("otel_endpoint"), config.otel_endpoint) {
+        args.otel_endpoint = Some(url);
+    }
+    if let (false, Some(protocol)) = (from_cli("otel_protocol"), config.otel_protocol) {
+        args.otel_protocol = Some(protocol);
+    }
+    if let (false, Some(interval)) = (
+        from_cli("otel_export_interval"),
+        config.otel_export_interval,
+    ) {
+        args.otel_export_interval = Some(interval);
+    }
+    fill!(
+        log_level,
+        log_format,
+        log_target,
+        log_suppress_window,
+        log_suppress_threshold,
+        dump_payload,
+        debug,
+        vlan_egress,
+        internal_link_type,
+        mac_table_size,
+        mac_ttl,
+        wait_for_iface,
+        on_link_down,
+        profile,
+        ports,
+        tcp_ports,
+        rule,
+        filter,
+        no_kernel_filter,
+        allow_src_mac,
+        allow_src_ip,
+        mdns_max_pps_per_host,
+        storm_threshold,
+        rate_limit_burst,
+        rate_limit_hosts,
+        quota,
+        shape,
+        shape_max_delay,
+        loop_window,
+        no_loop_drop_repeats,
+        no_loop_guard,
+        enable_mdns,
+        mdns_services,
+        no_mdns_filtering,
+        chromecast_strict,
+        mdns_enforce_ttl,
+        mdns_strip_txt,
+        mdns_strip_txt_key,
+        mdns_cache,
+        mdns_cache_size,
+        no_mdns_tracking,
+        mdns_response_window,
+        mdns_max_queries,
+        disable_ssdp,
+        disable_ipv6,
+        masquerade_mac,
+        decrement_ttl,
+        ttl_exempt_port,
+        dscp,
+        dscp_direction,
+        dscp_pcp,
+        rewrite_unicast_mac,
+        promiscuous,
+        join_group,
+        send_queue_capacity,
+        queue_policy,
+        buffer_pool_size,
+        rx_affinity,
+        tx_affinity,
+        backend,
+        batch_size,
+        rx_buffer_size,
+        tx_buffer_size,
+        read_timeout,
+        on_task_failure,
+        max_task_restarts,
+        oversize_policy,
+        icmp_too_big,
+        verify_checksums,
+        no_ssdp_tracking,
+        ssdp_response_window,
+        ssdp_max_searches,
+        ssdp_targets,
+        no_ssdp_filtering,
+        ssdp_external_search,
+        ssdp_internal_announce,
+        ssdp_location_map,
+        ssdp_location_fail_closed,
+        ssdp_cache,
+        ssdp_cache_size,
+        device_inventory,
+        device_inventory_size,
+        device_retention,
+        probe,
+        probe_interval,
+        probe_jitter,
+        probe_ssdp_targets,
+        probe_mdns_services,
+        enable_wsd,
+        wsd_actions,
+        no_wsd_filtering,
+        wsd_external_probe,
+        wsd_internal_announce,
+        enable_coap,
+        no_coap_filtering,
+        coap_external_request,
+        coap_internal_response,
+        llmnr,
+        llmnr_names,
+        llmnr_enforce_ttl,
+        netbios_ns,
+        enable_wol,
+        wol_targets,
+        pass_eapol,
+        pass_lldp,
+        dhcp_relay_option82,
+        forward_ra,
+        ra_rewrite_source,
+        arp_mode,
+        ndp_mode,
+        no_snooping,
+        snooping_unknown,
+        replay_timing,
+        state_save_interval,
+        mirror_dropped,
+        standby_priority,
+        standby_interval,
+        standby_missed,
+        seccomp,
+        dbus,
+        otel,
+        otel_header,
+        dry_run,
+        trace_packets,
+    );
+}
+
+/// Loads the file given with `--config`, if any, into `args`
+pub fn apply_file(args: &mut Args, matches: &ArgMatches) -> Result<(), Error> {
+    if let Some(path) = args.config.clone() {
+        merge(args, Config::load(&path)?, matches);
+    }
+    Ok(())
+}
+
+/// Checks the option combinations that can only be judged once the file
+/// and the command line are merged
+pub fn validate(args: &Args) -> Result<(), clap::Error> {
+    check(args).map_err(|(kind, message)| Cli::command().error(kind, message))
+}
+
+/// Like [`validate`], returning the bare problem for callers that only log it
+pub fn check(args: &Args) -> Result<(), (ErrorKind, String)> {
+    combinations(args).map_err(|(kind, message)| (kind, message.to_string()))?;
+    values(args)
+}
+
+/// Checks that the options given go together
+fn combinations(args: &Args) -> Result<(), (ErrorKind, &'static str)> {
+    let forms = [
+        args.external_iface.is_some() || args.internal_iface.is_some(),
+        !args.pair.is_empty(),
+        !args.bridge.is_empty(),
+    ];
+    match forms.iter().filter(|given| **given).count() {
+        0 => return Err((
+            ErrorKind::MissingRequiredArgument,
+            "no interfaces given, use --external-iface and --internal-iface, --pair or --bridge",
+        )),
+        1 => {}
+        _ => {
+            return Err((
+                ErrorKind::ArgumentConflict,
+                "only one of external-iface/internal-iface, pair or bridge may be used",
+            ))
+        }
+    }
+    if forms[0] && (args.external_iface.is_none() || args.internal_iface.is_none()) {
+        return Err((
+            ErrorKind::MissingRequiredArgument,
+            "external-iface and internal-iface must be given together",
+        ));
+    }
+    if forms[2] && args.snat.is_some() {
+        return Err((
+            ErrorKind::ArgumentConflict,
+            "snat cannot be used with bridge",
+        ));
+    }
+    if forms[2] && args.rewrite_unicast_mac {
+        return Err((
+            ErrorKind::ArgumentConflict,
+            "rewrite-unicast-mac cannot be used with bridge",
+        ));
+    }
+    if forms[2] && (!args.allow_src_mac.is_empty() || !args.allow_src_ip.is_empty()) {
+        return Err((
+            ErrorKind::ArgumentConflict,
+            "allow-src-mac and allow-src-ip cannot be used with bridge",
+        ));
+    }
+    if forms[2] && args.enable_wol {
+        return Err((
+            ErrorKind::ArgumentConflict,
+            "enable-wol cannot be used with bridge",
+        ));
+    }
+    if forms[2] && (args.pass_eapol || args.pass_lldp) {
+        return Err((
+            ErrorKind::ArgumentConflict,
+            "pass-eapol and pass-lldp cannot be used with bridge",
+        ));
+    }
+    if args.pass_eapol && args.pair.len() > 1 {
+        return Err((
+            ErrorKind::ArgumentConflict,
+            "pass-eapol requires a single pair",
+        ));
+    }
+    if forms[2] && (args.mdns_strip_txt || !args.mdns_strip_txt_key.is_empty()) {
+        return Err((
+            ErrorKind::ArgumentConflict,
+            "mdns-strip-txt and mdns-strip-txt-key cannot be used with bridge",
+        ));
+    }
+    if forms[2] && args.mdns_cache {
+        return Err((
+            ErrorKind::ArgumentConflict,
+            "mdns-cache cannot be used with bridge",
+        ));
+    }
+    if forms[2] && !args.ssdp_location_map.is_empty() {
+        return Err((
+            ErrorKind::ArgumentConflict,
+            "ssdp-location-map cannot be used with bridge",
+        ));
+    }
+    if forms[2] && args.ssdp_cache {
+        return Err((
+            ErrorKind::ArgumentConflict,
+            "ssdp-cache cannot be used with bridge",
+        ));
+    }
+    if args.ssdp_server_string.is_some() && !args.ssdp_cache {
+        return Err((
+            ErrorKind::MissingRequiredArgument,
+            "ssdp-server-string requires ssdp-cache",
+        ));
+    }
+    if args
+        .ssdp_server_string
+        .as_ref()
+        .is_some_and(|server| server.is_empty() || server.chars().any(char::is_control))
+    {
+        return Err((
+            ErrorKind::InvalidValue,
+            "ssdp-server-string must not be empty or contain control characters",
+        ));
+    }
+    if forms[2] && args.device_inventory {
+        return Err((
+            ErrorKind::ArgumentConflict,
+            "device-inventory cannot be used with bridge",
+        ));
+    }
+    if forms[2] && args.probe {
+        return Err((
+            ErrorKind::ArgumentConflict,
+            "probe cannot be used with bridge",
+        ));
+    }
+    if (!args.probe_ssdp_targets.is_empty() || !args.probe_mdns_services.is_empty()) && !args.probe
+    {
+        return Err((
+            ErrorKind::MissingRequiredArgument,
+            "probe-ssdp-targets and probe-mdns-services require probe",
+        ));
+    }
+    if args.probe_interval.is_zero() {
+        return Err((ErrorKind::InvalidValue, "probe-interval must not be zero"));
+    }
+    if forms[2] && (args.external_vlan.is_some() || args.internal_vlan.is_some()) {
+        return Err((
+            ErrorKind::ArgumentConflict,
+            "external-vlan and internal-vlan cannot be used with bridge",
+        ));
+    }
+    if forms[2] && (args.external_netns.is_some() || args.internal_netns.is_some()) {
+        return Err((
+            ErrorKind::ArgumentConflict,
+            "external-netns and internal-netns cannot be used with bridge",
+        ));
+    }
+    if args.internal_link_type == LinkType::Ip {
+        if forms[2] {
+            return Err((
+                ErrorKind::ArgumentConflict,
+                "internal-link-type ip cannot be used with bridge",
+            ));
+        }
+        if args.internal_vlan.is_some() {
+            return Err((
+                ErrorKind::ArgumentConflict,
+                "internal-link-type ip cannot be used with internal-vlan",
+            ));
+        }
+    }
+    if forms[2] && args.dhcp_relay.is_some() {
+        return Err((
+            ErrorKind::ArgumentConflict,
+            "dhcp-relay cannot be used with bridge",
+        ));
+    }
+    if forms[2] && (args.forward_ra || args.dhcpv6_relay.is_some()) {
+        return Err((
+            ErrorKind::ArgumentConflict,
+            "forward-ra and dhcpv6-relay cannot be used with bridge",
+        ));
+    }
+    if args.disable_ipv6 && (args.forward_ra || args.dhcpv6_relay.is_some()) {
+        return Err((
+            ErrorKind::ArgumentConflict,
+            "forward-ra and dhcpv6-relay cannot be used with disable-ipv6",
+        ));
+    }
+    if forms[2] && args.arp_mode == ArpMode::Proxy {
+        return Err((
+            ErrorKind::ArgumentConflict,
+            "arp-mode proxy cannot be used with bridge",
+        ));
+    }
+    if forms[2] && args.ndp_mode == NdpMode::Proxy {
+        return Err((
+            ErrorKind::ArgumentConflict,
+            "ndp-mode proxy cannot be used with bridge",
+        ));
+    }
+    if args.dhcp_relay_option82 && args.dhcp_relay.is_none() {
+        return Err((
+            ErrorKind::MissingRequiredArgument,
+            "dhcp-relay-option82 requires dhcp-relay",
+        ));
+    }
+    if args.ra_rewrite_source && !args.forward_ra {
+        return Err((
+            ErrorKind::MissingRequiredArgument,
+            "ra-rewrite-source requires forward-ra",
+        ));
+    }
+    if !args.wol_targets.is_empty() && !args.enable_wol {
+        return Err((
+            ErrorKind::MissingRequiredArgument,
+            "wol-targets requires enable-wol",
+        ));
+    }
+    let otel_options = args.otel_endpoint.is_some()
+        || args.otel_protocol.is_some()
+        || !args.otel_header.is_empty()
+        || args.otel_export_interval.is_some();
+    if otel_options && !args.otel {
+        return Err((
+            ErrorKind::MissingRequiredArgument,
+            "otel-endpoint, otel-protocol, otel-header and otel-export-interval require otel",
+        ));
+    }
+    if args.state_save_interval.is_zero() {
+        return Err((
+            ErrorKind::InvalidValue,
+            "state-save-interval must not be zero",
+        ));
+    }
+    if args
+        .otel_export_interval
+        .is_some_and(|interval| interval.is_zero())
+    {
+        return Err((
+            ErrorKind::InvalidValue,
+            "otel-export-interval must not be zero",
+        ));
+    }
+    if args.wait_timeout.is_some() && !args.wait_for_iface {
+        return Err((
+            ErrorKind::MissingRequiredArgument,
+            "wait-timeout requires wait-for-iface",
+        ));
+    }
+    if args
+        .mdns_aggregate
+        .is_some_and(|window| !RESPONSE_DELAY.contains(&window))
+    {
+        return Err((
+            ErrorKind::InvalidValue,
+            "mdns-aggregate must be between 20ms and 120ms",
+        ));
+    }
+    if forms[2] && args.mdns_aggregate.is_some() {
+        return Err((
+            ErrorKind::ArgumentConflict,
+            "mdns-aggregate cannot be used with bridge",
+        ));
+    }
+    if args.stats_interval == Some(Duration::ZERO) {
+        return Err((
+            ErrorKind::InvalidValue,
+            "stats-interval must be greater than zero",
+        ));
+    }
+    if args.loop_window.is_zero() && !args.no_loop_guard {
+        return Err((
+            ErrorKind::InvalidValue,
+            "loop-window must be greater than zero",
+        ));
+    }
+    let vlans = [args.external_vlan, args.internal_vlan];
+    if vlans
+        .into_iter()
+        .flatten()
+        .any(|id| !(1..4095).contains(&id))
+    {
+        return Err((
+            ErrorKind::InvalidValue,
+            "external-vlan and internal-vlan must be between 1 and 4094",
+        ));
+    }
+    if !(1..=1024).contains(&args.batch_size) {
+        return Err((
+            ErrorKind::InvalidValue,
+            "batch-size must be between 1 and 1024",
+        ));
+    }
+    if args.mdns_max_ttl.is_some_and(|ttl| ttl.as_secs() == 0) {
+        return Err((
+            ErrorKind::InvalidValue,
+            "mdns-max-ttl must be at least one second",
+        ));
+    }
+    if args.pcap_forwarded.is_some() && args.pcap_forwarded == args.pcap_dropped {
+        return Err((
+            ErrorKind::ArgumentConflict,
+            "pcap-forwarded and pcap-dropped must be different files",
+        ));
+    }
+    if args.pcap_in.is_some() && (forms[2] || args.pair.len() > 1) {
+        return Err((
+            ErrorKind::ArgumentConflict,
+            "pcap-in needs a single external/internal interface pair",
+        ));
+    }
+    if args.replay_timing && args.pcap_in.is_none() {
+        return Err((
+            ErrorKind::MissingRequiredArgument,
+            "replay-timing requires pcap-in",
+        ));
+    }
+    if args.mirror_dropped && args.mirror_iface.is_none() {
+        return Err((
+            ErrorKind::MissingRequiredArgument,
+            "mirror-dropped requires mirror-iface",
+        ));
+    }
+    if args.group.is_some() && args.user.is_none() {
+        return Err((ErrorKind::MissingRequiredArgument, "group requires user"));
+    }
+    if args.standby_group.is_some() && args.standby_lock.is_some() {
+        return Err((
+            ErrorKind::ArgumentConflict,
+            "standby-group and standby-lock cannot be used together",
+        ));
+    }
+    if args.standby_group.is_some() != args.standby_iface.is_some() {
+        return Err((
+            ErrorKind::MissingRequiredArgument,
+            "standby-group and standby-iface must be given together",
+        ));
+    }
+    if args.standby_interval.is_zero() {
+        return Err((
+            ErrorKind::InvalidValue,
+            "standby-interval must be greater than zero",
+        ));
+    }
+    Ok(())
+}
+
+/// Checks the values that parse but cannot work: interface names the kernel
+/// would refuse and rules that an earlier rule keeps from ever matching
+fn values(args: &Args) -> Result<(), (ErrorKind, String)> {
+    let per_interface = args
+        .rx_buffer_size
+        .iter()
+        .chain(&args.tx_buffer_size)
+        .filter_map(|size| size.iface.as_ref())
+        .chain(
+            args.read_timeout
+                .iter()
+                .filter_map(|timeout| timeout.iface.as_ref()),
+        );
+    let mut names = args
+        .external_iface
+        .iter()
+        .chain(&args.internal_iface)
+        .chain(&args.bridge)
+        .chain(
+            args.pair
+                .iter()
+                .flat_map(|pair| [&pair.external, &pair.internal]),
+        )
+        .chain(&args.mirror_iface)
+        .chain(&args.standby_iface)
+        .chain(per_interface);
+    if let Some(name) = names.find(|name| !is_valid_name(name)) {
+        return Err((
+            ErrorKind::InvalidValue,
+            format!("'{}' is not a valid interface name", name),
+        ));
+    }
+    for (at, rule) in args.rule.iter().enumerate() {
+        if let Some(earlier) = args.rule[..at].iter().find(|earlier| earlier.covers(rule)) {
+            return Err((
+                ErrorKind::ArgumentConflict,
+                format!(
+                    "rule '{}' never matches, the earlier rule '{}' matches everything it does",
+                    rule, earlier
+                ),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Keys that take effect when the file is reloaded; everything else needs
+/// a restart
+pub const RELOADABLE: [&str; 36] = [
+    "profile",
+    "ports",
+    "tcp-ports",
+    "rule",
+    "filter",
+    "quota",
+    "allow-src-mac",
+    "allow-src-ip",
+    "enable-mdns",
+    "mdns-services",
+    "no-mdns-filtering",
+    "chromecast-strict",
+    "disable-ssdp",
+    "disable-ipv6",
+    "verify-checksums",
+    "no-ssdp-tracking",
+    "ssdp-response-window",
+    "ssdp-max-searches",
+    "ssdp-targets",
+    "no-ssdp-filtering",
+    "ssdp-external-search",
+    "ssdp-internal-announce",
+    "enable-wsd",
+    "wsd-actions",
+    "no-wsd-filtering",
+    "wsd-external-probe",
+    "wsd-internal-announce",
+    "enable-coap",
+    "no-coap-filtering",
+    "coap-external-request",
+    "coap-internal-response",
+    "llmnr",
+    "llmnr-names",
+    "netbios-ns",
+    "enable-wol",
+    "wol-targets",
+];
+
+/// Copies the options listed in [`RELOADABLE`] from `new` into `current`
+pub fn apply_reloadable(current: &mut Args, new: Args) {
+    current.profile = new.profile;
+    current.ports = new.ports;
+    current.tcp_ports = new.tcp_ports;
+    current.rule = new.rule;
+    current.filter = new.filter;
+    current.quota = new.quota;
+    current.allow_src_mac = new.allow_src_mac;
+    current.allow_src_ip = new.allow_src_ip;
+    current.enable_mdns = new.enable_mdns;
+    current.mdns_services = new.mdns_services;
+    current.no_mdns_filtering = new.no_mdns_filtering;
+    current.chromecast_strict = new.chromecast_strict;
+    current.disable_ssdp = new.disable_ssdp;
+    current.disable_ipv6 = new.disable_ipv6;
+    current.verify_checksums = new.verify_checksums;
+    current.no_ssdp_tracking = new.no_ssdp_tracking;
+    current.ssdp_response_window = new.ssdp_response_window;
+    current.ssdp_max_searches = new.ssdp_max_searches;
+    current.ssdp_targets = new.ssdp_targets;
+    current.no_ssdp_filtering = new.no_ssdp_filtering;
+    current.ssdp_external_search = new.ssdp_external_search;
+    current.ssdp_internal_announce = new.ssdp_internal_announce;
+    current.enable_wsd = new.enable_wsd;
+    current.wsd_actions = new.wsd_actions;
+    current.no_wsd_filtering = new.no_wsd_filtering;
+    current.wsd_external_probe = new.wsd_external_probe;
+    current.wsd_internal_announce = new.wsd_internal_announce;
+    current.enable_coap = new.enable_coap;
+    current.no_coap_filtering = new.no_coap_filtering;
+    current.coap_external_request = new.coap_external_request;
+    current.coap_internal_response = new.coap_internal_response;
+    current.llmnr = new.llmnr;
+    current.llmnr_names = new.llmnr_names;
+    current.netbios_ns = new.netbios_ns;
+    current.enable_wol = new.enable_wol;
+    current.wol_targets = new.wol_targets;
+}
+
+/// One option whose effective value differs between two configurations
+pub struct Change {
+    pub key: String,
+    pub old: String,
+    pub new: String,
+}
+
+/// Compares two effective configurations key by key
+pub fn diff(old: &Args, new: &Args) -> Vec<Change> {
+    let table = |args| {
+        toml::Table::try_from(effective(args)).expect("configuration is representable as TOML")
+    };
+    let (old, new) = (table(old), table(new));
+    let show = |value: Option<&toml::Value>| value.map_or("(unset)".to_string(), |v| v.to_string());
+    let mut keys: Vec<&String> = old.keys().chain(new.keys()).collect();
+    keys.sort();
+    keys.dedup();
+    keys.into_iter()
+        .filter(|key| old.get(*key) != new.get(*key))
+        .map(|key| Change {
+            key: key.clone(),
+            old: show(old.get(key)),
+            new: show(new.get(key)),
+        })
+        .collect()
+}
+
+/// Renders the effective configuration in the file format
+pub fn dump(args: &Args) -> String {
+    toml::to_string(&effective(args)).expect("configuration is representable as TOML")
+}
+
+/// Effective configuration expressed in the file format
+pub fn effective(args: &Args) -> Config {
+    Config {
+        log_level: Some(args.log_level),
+        log_format: Some(args.log_format),
+        log_target: Some(args.log_target),
+        log_suppress_window: Some(args.log_suppress_window),
+        log_suppress_threshold: Some(args.log_suppress_threshold),
+        dump_payload: Some(args.dump_payload),
+        debug: Some(args.debug),
+        external_iface: args.external_iface.clone(),
+        internal_iface: args.internal_iface.clone(),
+        bridge: Some(args.bridge.clone()).filter(|bridge| !bridge.is_empty()),
+        external_vlan: args.external_vlan,
+        internal_vlan: args.internal_vlan,
+        internal_link_type: Some(args.internal_link_type),
+        external_netns: args.external_netns.clone(),
+        internal_netns: args.internal_netns.clone(),
+        vlan_egress: Some(args.vlan_egress),
+        mac_table_size: Some(args.mac_table_size),
+        mac_ttl: Some(args.mac_ttl),
+        wait_for_iface: Some(args.wait_for_iface),
+        wait_timeout: args.wait_timeout,
+        on_link_down: Some(args.on_link_down),
+        profile: Some(args.profile.clone()),
+        ports: Some(args.ports.clone()),
+        tcp_ports: Some(args.tcp_ports.clone()),
+        rule: Some(args.rule.clone()),
+        filter: Some(args.filter.clone()),
+        no_kernel_filter: Some(args.no_kernel_filter),
+        allow_src_mac: Some(args.allow_src_mac.clone()),
+        allow_src_ip: Some(args.allow_src_ip.clone()),
+        max_pps: args.max_pps,
+        max_pps_per_host: args.max_pps_per_host,
+        mdns_max_pps_per_host: Some(args.mdns_max_pps_per_host),
+        storm_threshold: Some(args.storm_threshold),
+        rate_limit_burst: Some(args.rate_limit_burst),
+        rate_limit_hosts: Some(args.rate_limit_hosts),
+        quota: Some(args.quota.clone()),
+        shape: Some(args.shape.clone()),
+        shape_max_delay: Some(args.shape_max_delay),
+        loop_window: Some(args.loop_window),
+        no_loop_drop_repeats: Some(args.no_loop_drop_repeats),
+        no_loop_guard: Some(args.no_loop_guard),
+        enable_mdns: Some(args.enable_mdns),
+        mdns_services: Some(args.mdns_services.clone()),
+        no_mdns_filtering: Some(args.no_mdns_filtering),
+        chromecast_strict: Some(args.chromecast_strict),
+        mdns_max_ttl: args.mdns_max_ttl,
+        mdns_enforce_ttl: Some(args.mdns_enforce_ttl),
+        mdns_strip_txt: Some(args.mdns_strip_txt),
+        mdns_strip_txt_key: Some(args.mdns_strip_txt_key.clone()),
+        mdns_cache: Some(args.mdns_cache),
+        mdns_cache_size: Some(args.mdns_cache_size),
+        no_mdns_tracking: Some(args.no_mdns_tracking),
+        mdns_response_window: Some(args.mdns_response_window),
+        mdns_aggregate: args.mdns_aggregate,
+        mdns_max_queries: Some(args.mdns_max_queries),
+        disable_ssdp: Some(args.disable_ssdp),
+        disable_ipv6: Some(args.disable_ipv6),
+        masquerade_mac: Some(args.masquerade_mac),
+        decrement_ttl: Some(args.decrement_ttl),
+        ttl_exempt_port: Some(args.ttl_exempt_port.clone()),
+        dscp: Some(args.dscp.clone()),
+        dscp_direction: Some(args.dscp_direction),
+        dscp_pcp: Some(args.dscp_pcp),
+        rewrite_unicast_mac: Some(args.rewrite_unicast_mac),
+        snat: Some(match args.snat {
+            None => Snat::Enabled(false),
+            Some(None) => Snat::Enabled(true),
+            Some(Some(ip)) => Snat::Address(ip),
+        }),
+        promiscuous: Some(args.promiscuous),
+        join_group: Some(args.join_group.clone()),
+        send_queue_capacity: Some(args.send_queue_capacity),
+        queue_policy: Some(args.queue_policy),
+        buffer_pool_size: Some(args.buffer_pool_size),
+        rx_affinity: Some(args.rx_affinity.clone()),
+        tx_affinity: Some(args.tx_affinity.clone()),
+        backend: Some(args.backend),
+        batch_size: Some(args.batch_size),
+        rx_buffer_size: Some(args.rx_buffer_size.clone()),
+        tx_buffer_size: Some(args.tx_buffer_size.clone()),
+        read_timeout: Some(args.read_timeout.clone()),
+        on_task_failure: Some(args.on_task_failure),
+        max_task_restarts: Some(args.max_task_restarts),
+        oversize_policy: Some(args.oversize_policy),
+        icmp_too_big: Some(args.icmp_too_big),
+        verify_checksums: Some(args.verify_checksums),
+        no_ssdp_tracking: Some(args.no_ssdp_tracking),
+        ssdp_response_window: Some(args.ssdp_response_window),
+        ssdp_max_searches: Some(args.ssdp_max_searches),
+        ssdp_targets: Some(args.ssdp_targets.clone()),
+        no_ssdp_filtering: Some(args.no_ssdp_filtering),
+        ssdp_external_search: Some(args.ssdp_external_search),
+        ssdp_internal_announce: Some(args.ssdp_internal_announce),
+        ssdp_location_map: Some(args.ssdp_location_map.clone()),
+        ssdp_location_fail_closed: Some(args.ssdp_location_fail_closed),
+        ssdp_cache: Some(args.ssdp_cache),
+        ssdp_cache_size: Some(args.ssdp_cache_size),
+        ssdp_server_string: args.ssdp_server_string.clone(),
+        device_inventory: Some(args.device_inventory),
+        device_inventory_size: Some(args.device_inventory_size),
+        device_retention: Some(args.device_retention),
+        probe: Some(args.probe),
+        probe_interval: Some(args.probe_interval),
+        probe_jitter: Some(args.probe_jitter),
+        probe_ssdp_targets: Some(args.probe_ssdp_targets.clone()),
+        probe_mdns_services: Some(args.probe_mdns_services.clone()),
+        enable_wsd: Some(args.enable_wsd),
+        wsd_actions: Some(args.wsd_actions.clone()),
+        no_wsd_filtering: Some(args.no_wsd_filtering),
+        wsd_external_probe: Some(args.wsd_external_probe),
+        wsd_internal_announce: Some(args.wsd_internal_announce),
+        enable_coap: Some(args.enable_coap),
+        no_coap_filtering: Some(args.no_coap_filtering),
+        coap_external_request: Some(args.coap_external_request),
+        coap_internal_response: Some(args.coap_internal_response),
+        llmnr: Some(args.llmnr),
+        llmnr_names: Some(args.llmnr_names.clone()),
+        llmnr_enforce_ttl: Some(args.llmnr_enforce_ttl),
+        netbios_ns: Some(args.netbios_ns),
+        enable_wol: Some(args.enable_wol),
+        wol_targets: Some(args.wol_targets.clone()),
+        pass_eapol: Some(args.pass_eapol),
+        pass_lldp: Some(args.pass_lldp),
+        dhcp_relay: args.dhcp_relay,
+        dhcp_relay_option82: Some(args.dhcp_relay_option82),
+        forward_ra: Some(args.forward_ra),
+        ra_rewrite_source: Some(args.ra_rewrite_source),
+        dhcpv6_relay: args.dhcpv6_relay,
+        arp_mode: Some(args.arp_mode),
+        ndp_mode: Some(args.ndp_mode),
+        no_snooping: Some(args.no_snooping),
+        snooping_unknown: Some(args.snooping_unknown),
+        stats_interval: args.stats_interval,
+        pcap_forwarded: args.pcap_forwarded.clone(),
+        pcap_dropped: args.pcap_dropped.clone(),
+        pcap_max_size: args.pcap_max_size,
+        pcap_in: args.pcap_in.clone(),
+        replay_timing: Some(args.replay_timing),
+        state_file: args.state_file.clone(),
+        state_save_interval: Some(args.state_save_interval),
+        mirror_iface: args.mirror_iface.clone(),
+        mirror_dropped: Some(args.mirror_dropped),
+        standby_group: args.standby_group,
+        standby_iface: args.standby_iface.clone(),
+        standby_lock: args.standby_lock.clone(),
+        standby_priority: Some(args.standby_priority),
+        standby_interval: Some(args.standby_interval),
+        standby_missed: Some(args.standby_missed),
+        user: args.user.clone(),
+        group: args.group.clone(),
+        seccomp: Some(args.seccomp),
+        control_socket: args.control_socket.clone(),
+        dbus: Some(args.dbus),
+        otel: Some(args.otel),
+        otel_endpoint: args.otel_endpoint.clone(),
+        otel_protocol: args.otel_protocol,
+        otel_header: Some(args.otel_header.clone()),
+        otel_export_interval: args.otel_export_interval,
+        dry_run: Some(args.dry_run),
+        trace_packets: Some(args.trace_packets),
+        pair: Some(args.pair.clone()).filter(|pair| !pair.is_empty()),
+    }
+}
+
+fn nonzero_ports<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Vec<u16>>, D::Error> {
+    let ports = Vec::<u16>::deserialize(deserializer)?;
+    if ports.contains(&0) {
+        return Err(D::Error::custom("port 0 is not allowed"));
+    }
+    Ok(Some(ports))
+}
+
+fn standby_group<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<SocketAddrV4>, D::Error> {
+    let group = SocketAddrV4::deserialize(deserializer)?;
+    if !group.ip().is_multicast() {
+        return Err(D::Error::custom(format!(
+            "{} is not a multicast address",
+            group.ip()
+        )));
+    }
+    Ok(Some(group))
+}
+
+fn multicast_groups<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Vec<IpAddr>>, D::Error> {
+    let groups = Vec::<IpAddr>::deserialize(deserializer)?;
+    if let Some(ip) = groups.iter().find(|ip| !ip.is_multicast()) {
+        return Err(D::Error::custom(format!(
+            "{} is not a multicast address",
+            ip
+        )));
+    }
+    Ok(Some(groups))
+}
+
+/// Accepts a byte count or a string with a `K`, `M` or `G` suffix
+fn size<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u64>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Size {
+        Bytes(u64),
+        Text(String),
+    }
+    match Size::deserialize(deserializer)? {
+        Size::Bytes(0) => Err(D::Error::custom("size must be greater than zero")),
+        Size::Bytes(bytes) => Ok(Some(bytes)),
+        Size::Text(text) => parse_size(&text).map(Some).map_err(D::Error::custom),
+    }
+}
+
+fn at_least_one<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de> + PartialEq + From<u8>,
+{
+    let value = T::deserialize(deserializer)?;
+    if value == T::from(0) {
+        return Err(D::Error::custom("must be at least 1"));
+    }
+    Ok(Some(value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::FromArgMatches;
+    use std::ffi::OsString;
+
+    /// Parses the command line `argv` naming a configuration file holding
+    /// `text`, and merges the two as at startup
+    fn load(name: &str, text: &str, argv: &[&str]) -> Result<Args, Error> {
+        let dir = std::env::temp_dir().join(format!("nw-pckt-fwd-config-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(format!("{}.toml", name));
+        std::fs::write(&path, text).unwrap();
+        let mut command_line: Vec<OsString> = vec!["nw-pckt-fwd".into(), "--config".into()];
+        command_line.push(path.clone().into());
+        command_line.extend(argv.iter().map(OsString::from));
+        let matches = Cli::command().try_get_matches_from(command_line).unwrap();
+        let mut args = Args::from_arg_matches(&matches).unwrap();
+        let loaded = apply_file(&mut args, &matches).map(|()| args);
+        std::fs::remove_file(&path).unwrap();
+        loaded
+    }
+
+    const INTERFACES: &str = "external-iface = \"eth0\"\ninternal-iface = \"vm0\"\n";
+
+    #[test]
+    fn command_line_overrides_the_file_and_defaults_do_not() {
+        let text = format!(
+            "{}storm-threshold = 100\nloop-window = \"2s\"\nports = [5000]\ndry-run = true\n",
+            INTERFACES
+        );
+        let args = load("overrides", &text, &["--storm-threshold", "200"]).unwrap();
+        assert_eq!(args.storm_threshold, 200);
+        assert_eq!(args.loop_window, Duration::from_secs(2));
+        assert_eq!(args.ports, [5000]);
+        assert!(args.dry_run);
+        // Left out of both, so the command line default stays
+        assert_eq!(args.batch_size, 32);
+        assert!(!args.no_loop_guard);
+
+        // Interfaces come from one place as a whole
+        let bridge = "bridge = [\"eth2\", \"eth3\"]\n";
+        let pair = ["--external-iface", "eth1", "--internal-iface", "vm1"];
+        let args = load("interfaces", bridge, &pair).unwrap();
+        assert_eq!(args.external_iface.as_deref(), Some("eth1"));
+        assert!(args.bridge.is_empty());
+        assert!(check(&args).is_ok());
+        let args = load("bridge", &text, &["--bridge", "eth1"]).unwrap();
+        assert_eq!((args.external_iface, args.internal_iface), (None, None));
+        assert_eq!(args.bridge, ["eth1"]);
+    }
+
+    #[test]
+    fn reports_the_line_of_unknown_keys_and_invalid_values() {
+        let text = format!("{}ports = [5000]\nstorm-treshold = 100\n", INTERFACES);
+        let Err(Error::ConfigParse { path, source }) = load("unknown", &text, &[]) else {
+            panic!("unknown key accepted");
+        };
+        assert_eq!(path.file_name().unwrap(), "unknown.toml");
+        let message = source.to_string();
+        assert!(message.contains("line 4"), "{}", message);
+        assert!(
+            message.contains("unknown field `storm-treshold`"),
+            "{}",
+            message
+        );
+
+        let text = format!("{}ports = [5000, 0]\n", INTERFACES);
+        let Err(Error::ConfigParse { source, .. }) = load("zero-port", &text, &[]) else {
+            panic!("port 0 accepted");
+        };
+        assert!(source.to_string().contains("line 3"), "{}", source);
+        let text = format!("{}loop-window = \"soon\"\n", INTERFACES);
+        assert!(matches!(
+            load("duration", &text, &[]),
+            Err(Error::ConfigParse { .. })
+        ));
+
+        assert!(matches!(
+            Config::load(Path::new("/nonexistent/nw-pckt-fwd.toml")),
+            Err(Error::ConfigRead { .. })
+        ));
+    }
+
+    #[test]
+    fn checks_the_merged_options() {
+        let args = load("valid", INTERFACES, &[]).unwrap();
+        assert!(check(&args).is_ok());
+
+        let missing = load("missing", "ports = [5000]\n", &[]).unwrap();
+        let (kind, problem) = check(&missing).unwrap_err();
+        assert_eq!(kind, ErrorKind::MissingRequiredArgument);
+        assert!(problem.starts_with("no interfaces given"), "{}", problem);
+
+        // The command line replaces the interfaces of the file
+        let pair = "external:eth1,internal:vm1";
+        let replaced = load("replaced", INTERFACES, &["--pair", pair]).unwrap();
+        assert_eq!(replaced.external_iface, None);
+        assert!(check(&replaced).is_ok());
+        let text = format!("{}bridge = [\"eth1\", \"eth2\"]\n", INTERFACES);
+        let (kind, _) = check(&load("both", &text, &[]).unwrap()).unwrap_err();
+        assert_eq!(kind, ErrorKind::ArgumentConflict);
+
+        let text = format!("{}loop-window = \"0s\"\n", INTERFACES);
+        let (kind, problem) = check(&load("window", &text, &[]).unwrap()).unwrap_err();
+        assert_eq!(kind, ErrorKind::InvalidValue);
+        assert_eq!(problem, "loop-window must be greater than zero");
+        let args = load("no-guard", &text, &["--no-loop-guard"]).unwrap();
+        assert!(check(&args).is_ok());
+    }
+
+    #[test]
+    fn dumped_configuration_loads_back_unchanged() {
+        let text = format!(
+            "{}storm-threshold = 100\nports = [5000]\nquota = [\"in->out ssdp packets 10 per 1h\"]\n",
+            INTERFACES
+        );
+        let args = load("original", &text, &["--loop-window", "3s", "--dry-run"]).unwrap();
+        let dumped = dump(&args);
+        let reloaded = load("dumped", &dumped, &[]).unwrap();
+        assert_eq!(dump(&reloaded), dumped);
+        assert!(diff(&args, &reloaded).is_empty());
+        assert_eq!(reloaded.loop_window, Duration::from_secs(3));
+        assert!(reloaded.dry_run);
+    }
+}
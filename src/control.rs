@@ -0,0 +1,250 @@
+//! Control socket of a running forwarder. Clients connect to a Unix stream
+//! socket and send one JSON request per line, such as
+//! `{"command":"set-log-level","level":"debug"}`, each answered with one
+//! JSON line `{"ok":true,"result":...}` or `{"ok":false,"error":"..."}`.
+//! Who may connect is decided by the permissions of the socket file.
+
+use crate::error::Error;
+use crate::logging::LogLevel;
+use clap::Subcommand;
+use serde::{Deserialize, Serialize};
+use serde_json::Value as Json;
+use std::fs;
+use std::future::Future;
+use std::io;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info, warn};
+
+/// Socket path when `--control-socket` is given without one
+pub const DEFAULT_SOCKET: &str = "/run/nw-pckt-fwd.sock";
+/// Owner and group may connect
+const SOCKET_MODE: u32 = 0o660;
+
+/// Commands understood on the control socket
+#[derive(Debug, Clone, PartialEq, Eq, Subcommand, Deserialize, Serialize)]
+#[serde(tag = "command", rename_all = "kebab-case", deny_unknown_fields)]
+pub enum Request {
+    /// Counters of all paths, interfaces, the mirror and the buffer pool
+    Stats,
+    /// Devices the SSDP and mDNS caches discovered, and what was learned
+    /// about the rest: host MACs, caches, the MAC table and multicast
+    /// listeners
+    Devices,
+    /// Drop received frames until resumed
+    Pause,
+    /// Forward again after a pause
+    Resume,
+    /// Log at this level from now on, replacing RUST_LOG filters
+    SetLogLevel {
+        #[arg(value_enum)]
+        level: LogLevel,
+    },
+    /// Re-read the configuration file as on SIGHUP
+    Reload,
+    /// Set the counters, top talkers included, back to zero
+    ResetStats,
+    /// Start a new window for every quota, with its full budget
+    ResetQuotas,
+}
+
+/// Answer to one [`Request`]
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct Response {
+    pub ok: bool,
+    #[serde(default, skip_serializing_if = "Json::is_null")]
+    pub result: Json,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl From<Result<Json, String>> for Response {
+    fn from(result: Result<Json, String>) -> Self {
+        match result {
+            Ok(result) => Response {
+                ok: true,
+                result,
+                error: None,
+            },
+            Err(error) => Response {
+                ok: false,
+                result: Json::Null,
+                error: Some(error),
+            },
+        }
+    }
+}
+
+/// Carries out requests for the control socket
+pub trait Handler: Send + Sync + 'static {
+    fn handle(&self, request: Request) -> impl Future<Output = Result<Json, String>> + Send;
+}
+
+/// Listens on `path`, readable and writable by owner and group only. A
+/// socket file left behind by a forwarder that is gone is replaced; one a
+/// forwarder still answers on is not.
+pub fn bind(path: &Path) -> Result<UnixListener, Error> {
+    let error = |source| Error::ControlSocket {
+        path: path.to_path_buf(),
+        source,
+    };
+    if fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket()) {
+        if std::os::unix::net::UnixStream::connect(path).is_ok() {
+            return Err(error(io::ErrorKind::AddrInUse.into()));
+        }
+        fs::remove_file(path).map_err(error)?;
+    }
+    let listener = UnixListener::bind(path).map_err(error)?;
+    fs::set_permissions(path, fs::Permissions::from_mode(SOCKET_MODE)).map_err(error)?;
+    info!("Control socket listening on {}", path.display());
+    Ok(listener)
+}
+
+/// Answers the connections to `listener` at `path` until `token` is
+/// cancelled, then removes the socket file
+pub async fn serve<H: Handler>(
+    listener: UnixListener,
+    path: PathBuf,
+    handler: Arc<H>,
+    token: CancellationToken,
+) {
+    loop {
+        tokio::select! {
+            _ = token.cancelled() => break,
+            accepted = listener.accept() => match accepted {
+                Ok((stream, _)) => {
+                    let handler = handler.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = connection(stream, handler.as_ref()).await {
+                            debug!("Control connection failed: {}", e);
+                        }
+                    });
+                }
+                Err(e) => warn!("Failed to accept on the control socket: {}", e),
+            },
+        }
+    }
+    if let Err(e) = fs::remove_file(&path) {
+        debug!("Failed to remove {}: {}", path.display(), e);
+    }
+}
+
+/// Answers the requests of one client until it hangs up
+async fn connection<H: Handler>(stream: UnixStream, handler: &H) -> io::Result<()> {
+    let (read, mut write) = stream.into_split();
+    let mut lines = BufReader::new(read).lines();
+    while let Some(line) = lines.next_line().await? {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let response = match serde_json::from_str::<Request>(&line) {
+            Ok(request) => {
+                debug!("Control request {:?}", request);
+                Response::from(handler.handle(request).await)
+            }
+            Err(e) => Response::from(Err(format!("invalid request: {}", e))),
+        };
+        let mut answer = serde_json::to_vec(&response)?;
+        answer.push(b'\n');
+        write.write_all(&answer).await?;
+    }
+    Ok(())
+}
+
+/// Sends `request` to the forwarder listening on `path` and waits for the
+/// answer
+pub async fn request(path: &Path, request: &Request) -> io::Result<Response> {
+    let stream = UnixStream::connect(path).await?;
+    let (read, mut write) = stream.into_split();
+    let mut line = serde_json::to_vec(request)?;
+    line.push(b'\n');
+    write.write_all(&line).await?;
+    let answer = BufReader::new(read)
+        .lines()
+        .next_line()
+        .await?
+        .ok_or(io::ErrorKind::UnexpectedEof)?;
+    Ok(serde_json::from_str(&answer)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    /// Records the requests and answers with their command names
+    #[derive(Default)]
+    struct Recorder(Mutex<Vec<Request>>);
+
+    impl Handler for Recorder {
+        async fn handle(&self, request: Request) -> Result<Json, String> {
+            self.0.lock().unwrap().push(request.clone());
+            match request {
+                Request::Reload => Err("no --config file is in use".to_string()),
+                request => Ok(serde_json::to_value(request).unwrap()["command"].clone()),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn dispatches_requests_and_reports_errors() {
+        let path = std::env::temp_dir().join(format!("nw-pckt-fwd-control-{}", std::process::id()));
+        let listener = bind(&path).unwrap();
+        assert!(matches!(bind(&path), Err(Error::ControlSocket { .. })));
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, SOCKET_MODE);
+        let handler = Arc::new(Recorder::default());
+        let token = CancellationToken::new();
+        let server = tokio::spawn(serve(
+            listener,
+            path.clone(),
+            handler.clone(),
+            token.clone(),
+        ));
+
+        let answer = request(
+            &path,
+            &Request::SetLogLevel {
+                level: LogLevel::Debug,
+            },
+        )
+        .await;
+        assert_eq!(answer.unwrap().result, "set-log-level");
+        let answer = request(&path, &Request::Reload).await.unwrap();
+        assert!(!answer.ok);
+        assert_eq!(answer.error.as_deref(), Some("no --config file is in use"));
+
+        let mut stream = UnixStream::connect(&path).await.unwrap();
+        stream
+            .write_all(b"{\"command\":\"pause\"}\n\n{\"command\":\"bogus\"}\n")
+            .await
+            .unwrap();
+        stream.shutdown().await.unwrap();
+        let mut lines = BufReader::new(stream).lines();
+        let pause: Response =
+            serde_json::from_str(&lines.next_line().await.unwrap().unwrap()).unwrap();
+        assert_eq!((pause.ok, pause.result), (true, Json::from("pause")));
+        let bogus: Response =
+            serde_json::from_str(&lines.next_line().await.unwrap().unwrap()).unwrap();
+        assert!(!bogus.ok && bogus.error.unwrap().starts_with("invalid request"));
+        assert_eq!(lines.next_line().await.unwrap(), None);
+
+        assert_eq!(
+            *handler.0.lock().unwrap(),
+            [
+                Request::SetLogLevel {
+                    level: LogLevel::Debug
+                },
+                Request::Reload,
+                Request::Pause
+            ]
+        );
+        token.cancel();
+        server.await.unwrap();
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,192 @@
+//! `org.ghaf.PacketForwarder` service on the system bus: forwarding is
+//! enabled and disabled, the counters read and the devices the caches learn
+//! listed and announced as they appear.
+
+use crate::discovery::Device;
+use crate::error::Error;
+use crate::inventory::{DeviceInventory, InventoryEntry};
+use crate::pause::Pause;
+use crate::responder::Cache;
+use crate::stats::Stats;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info};
+use zbus::object_server::SignalEmitter;
+use zbus::zvariant::Value;
+use zbus::{interface, Connection};
+
+pub const BUS_NAME: &str = "org.ghaf.PacketForwarder";
+pub const OBJECT_PATH: &str = "/org/ghaf/PacketForwarder";
+
+/// Dictionary of `a{sv}` type
+type Dict = HashMap<String, Value<'static>>;
+
+/// Object served at [`OBJECT_PATH`]
+pub struct PacketForwarder {
+    /// Set while received frames are only counted as dropped
+    pub paused: Arc<Pause>,
+    pub stats: Arc<Stats>,
+    /// Caches of every pair, listed for their devices
+    pub caches: Vec<Arc<dyn Cache>>,
+    pub inventories: Vec<Arc<DeviceInventory>>,
+}
+
+#[interface(name = "org.ghaf.PacketForwarder")]
+impl PacketForwarder {
+    /// Forwards received frames again after `Disable`
+    fn enable(&self) {
+        self.paused.set(false);
+    }
+
+    /// Drops received and still queued frames until `Enable`
+    fn disable(&self) {
+        self.paused.set(true);
+    }
+
+    /// Totals of all paths and interfaces, the uptime and whether forwarding
+    /// is enabled
+    fn get_statistics(&self) -> Dict {
+        let snapshot = self.stats.snapshot();
+        let total = |count: fn(&crate::stats::PathSnapshot) -> u64| {
+            Value::from(snapshot.paths.iter().map(count).sum::<u64>())
+        };
+        HashMap::from([
+            ("uptime_secs".to_string(), Value::from(snapshot.uptime_secs)),
+            ("received".to_string(), total(|path| path.received)),
+            (
+                "received_bytes".to_string(),
+                total(|path| path.received_bytes),
+            ),
+            ("forwarded".to_string(), total(|path| path.forwarded)),
+            (
+                "forwarded_bytes".to_string(),
+                total(|path| path.forwarded_bytes),
+            ),
+            ("dropped".to_string(), total(|path| path.dropped())),
+            (
+                "kernel_dropped".to_string(),
+                Value::from(
+                    snapshot
+                        .interfaces
+                        .iter()
+                        .map(|iface| iface.kernel_dropped)
+                        .sum::<u64>(),
+                ),
+            ),
+            ("enabled".to_string(), Value::from(!self.paused.is_paused())),
+        ])
+    }
+
+    /// Devices the SSDP and mDNS caches hold, as listed by the `devices`
+    /// control request
+    fn list_discovered_devices(&self) -> Vec<Dict> {
+        self.caches
+            .iter()
+            .flat_map(|cache| cache.devices())
+            .map(dict)
+            .collect()
+    }
+
+    /// Devices seen announcing themselves with `--device-inventory`
+    fn list_device_inventory(&self) -> Vec<Dict> {
+        let entries = self
+            .inventories
+            .iter()
+            .flat_map(|inventory| inventory.entries());
+        entries.map(inventory_dict).collect()
+    }
+
+    /// A cache learned a USN or service instance it had not seen
+    #[zbus(signal)]
+    async fn device_discovered(emitter: &SignalEmitter<'_>, device: Dict) -> zbus::Result<()>;
+}
+
+/// `protocol`, `id`, `kind`, `expires_in` and, if known, `location`
+fn dict(device: Device) -> Dict {
+    let mut dict = HashMap::from([
+        ("protocol".to_string(), Value::from(device.protocol)),
+        ("id".to_string(), Value::from(device.id)),
+        ("kind".to_string(), Value::from(device.kind)),
+        ("expires_in".to_string(), Value::from(device.expires_in)),
+    ]);
+    if let Some(location) = device.location {
+        dict.insert("location".to_string(), Value::from(location));
+    }
+    dict
+}
+
+/// `protocol`, `id`, `kind`, `ip`, `mac`, `first_seen`, `last_seen`,
+/// `expires_in` and, if known, `name`
+fn inventory_dict(entry: InventoryEntry) -> Dict {
+    let mut dict = HashMap::from([
+        ("protocol".to_string(), Value::from(entry.protocol)),
+        ("id".to_string(), Value::from(entry.id)),
+        ("kind".to_string(), Value::from(entry.kind)),
+        ("ip".to_string(), Value::from(entry.ip.to_string())),
+        ("mac".to_string(), Value::from(entry.mac)),
+        ("first_seen".to_string(), Value::from(entry.first_seen)),
+        ("last_seen".to_string(), Value::from(entry.last_seen)),
+        ("expires_in".to_string(), Value::from(entry.expires_in)),
+    ]);
+    if let Some(name) = entry.name {
+        dict.insert("name".to_string(), Value::from(name));
+    }
+    dict
+}
+
+/// Connects to the system bus, serves `service` and takes [`BUS_NAME`]
+pub async fn connect(service: PacketForwarder) -> Result<Connection, Error> {
+    let connection = zbus::connection::Builder::system()
+        .and_then(|builder| builder.name(BUS_NAME))
+        .and_then(|builder| builder.serve_at(OBJECT_PATH, service))
+        .map_err(Error::DBus)?
+        .build()
+        .await
+        .map_err(Error::DBus)?;
+    info!("Serving {} on the system bus", BUS_NAME);
+    Ok(connection)
+}
+
+/// Emits `DeviceDiscovered` for every device received until `token` is
+/// cancelled
+pub fn spawn_announcer(
+    connection: Connection,
+    mut discovered: mpsc::Receiver<Device>,
+    token: CancellationToken,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let emitter = SignalEmitter::new(&connection, OBJECT_PATH).expect("object path is valid");
+        loop {
+            let device = tokio::select! {
+                _ = token.cancelled() => break,
+                Some(device) = discovered.recv() => device,
+            };
+            if let Err(e) = PacketForwarder::device_discovered(&emitter, dict(device)).await {
+                debug!("DeviceDiscovered not emitted: {}", e);
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn devices_become_dictionaries() {
+        let device = Device {
+            protocol: "ssdp",
+            id: "uuid:tv::upnp:rootdevice".to_string(),
+            kind: "upnp:rootdevice".to_string(),
+            location: None,
+            expires_in: 1800,
+        };
+        let dict = dict(device);
+        assert_eq!(dict["protocol"], Value::from("ssdp"));
+        assert_eq!(dict["expires_in"], Value::from(1800u64));
+        assert!(!dict.contains_key("location"));
+    }
+}
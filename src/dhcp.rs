@@ -0,0 +1,514 @@
+//! DHCP relay agent carrying BOOTP messages between the clients on the
+//! internal side and a server on the external side (RFC 1542, RFC 3046).
+
+use crate::filter::{Decision, Filter, IpHeader, PacketContext};
+use crate::rewrite::{Rewrite, UdpDatagram};
+use pnet::packet::ethernet::MutableEthernetPacket;
+use pnet::packet::ipv4::MutableIpv4Packet;
+use pnet::packet::Packet;
+use pnet::util::MacAddr;
+use std::net::Ipv4Addr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tracing::debug;
+
+pub const DHCP_SERVER_PORT: u16 = 67;
+pub const DHCP_CLIENT_PORT: u16 = 68;
+
+const ETHERNET_HEADER_LEN: usize = 14;
+const BOOTREQUEST: u8 = 1;
+const BOOTREPLY: u8 = 2;
+const HTYPE_ETHERNET: u8 = 1;
+const FLAG_BROADCAST: u16 = 0x8000;
+/// Offset of the options, after the fixed fields and the magic cookie
+const OPTIONS_OFFSET: usize = 240;
+const MAGIC_COOKIE: [u8; 4] = [99, 130, 83, 99];
+const OPTION_PAD: u8 = 0;
+const OPTION_END: u8 = 255;
+const OPTION_RELAY_AGENT_INFO: u8 = 82;
+const SUBOPTION_CIRCUIT_ID: u8 = 1;
+/// Requests relayed this many times already are dropped (RFC 1542
+/// section 4.1.1)
+const MAX_HOPS: u8 = 16;
+
+/// Read access to the fixed fields of a BOOTP message
+struct Bootp<'a>(&'a [u8]);
+
+impl<'a> Bootp<'a> {
+    /// Returns `None` for payloads too short to carry the magic cookie, or
+    /// without it
+    fn new(payload: &'a [u8]) -> Option<Self> {
+        let cookie = payload.get(OPTIONS_OFFSET - 4..OPTIONS_OFFSET)?;
+        (cookie == MAGIC_COOKIE).then_some(Bootp(payload))
+    }
+
+    fn op(&self) -> u8 {
+        self.0[0]
+    }
+
+    fn hops(&self) -> u8 {
+        self.0[3]
+    }
+
+    fn flags(&self) -> u16 {
+        u16::from_be_bytes([self.0[10], self.0[11]])
+    }
+
+    fn address(&self, at: usize) -> Ipv4Addr {
+        Ipv4Addr::new(self.0[at], self.0[at + 1], self.0[at + 2], self.0[at + 3])
+    }
+
+    fn ciaddr(&self) -> Ipv4Addr {
+        self.address(12)
+    }
+
+    fn yiaddr(&self) -> Ipv4Addr {
+        self.address(16)
+    }
+
+    fn giaddr(&self) -> Ipv4Addr {
+        self.address(24)
+    }
+
+    /// Client hardware address, if it is an Ethernet MAC
+    fn chaddr(&self) -> Option<MacAddr> {
+        if self.0[1] != HTYPE_ETHERNET || self.0[2] != 6 {
+            return None;
+        }
+        let b = &self.0[28..34];
+        Some(MacAddr::new(b[0], b[1], b[2], b[3], b[4], b[5]))
+    }
+
+    /// Offset and length of every option but padding, `None` if an option
+    /// runs past the end of the message
+    fn options(&self) -> Option<Vec<(u8, usize, usize)>> {
+        let mut options = Vec::new();
+        let mut at = OPTIONS_OFFSET;
+        while let Some(&code) = self.0.get(at) {
+            match code {
+                OPTION_PAD => at += 1,
+                OPTION_END => break,
+                _ => {
+                    let len = 2 + usize::from(*self.0.get(at + 1)?);
+                    if at + len > self.0.len() {
+                        return None;
+                    }
+                    options.push((code, at, len));
+                    at += len;
+                }
+            }
+        }
+        Some(options)
+    }
+}
+
+/// Relay agent information option naming the circuit a request came in on
+fn relay_agent_option(circuit_id: &[u8]) -> Vec<u8> {
+    let circuit_id = &circuit_id[..circuit_id.len().min(253)];
+    let mut option = vec![
+        OPTION_RELAY_AGENT_INFO,
+        circuit_id.len() as u8 + 2,
+        SUBOPTION_CIRCUIT_ID,
+        circuit_id.len() as u8,
+    ];
+    option.extend_from_slice(circuit_id);
+    option
+}
+
+/// Turns a request from a client into the one relayed to the server: one
+/// more hop, the relay's address as giaddr unless another relay set it
+/// already, and the relay agent information option unless present.
+/// Returns `None` for malformed messages.
+fn relay_request(payload: &[u8], giaddr: Ipv4Addr, circuit_id: Option<&[u8]>) -> Option<Vec<u8>> {
+    let bootp = Bootp::new(payload)?;
+    let options = bootp.options()?;
+    let mut relayed = payload.to_vec();
+    relayed[3] = bootp.hops().saturating_add(1);
+    if bootp.giaddr().is_unspecified() {
+        relayed[24..28].copy_from_slice(&giaddr.octets());
+    }
+    let present = options
+        .iter()
+        .any(|(code, _, _)| *code == OPTION_RELAY_AGENT_INFO);
+    if let (Some(circuit_id), false) = (circuit_id, present) {
+        // Goes last, right before the end option (RFC 3046 section 2.1)
+        let end = options
+            .last()
+            .map_or(OPTIONS_OFFSET, |(_, at, len)| at + len);
+        relayed.truncate(end);
+        relayed.extend(relay_agent_option(circuit_id));
+        relayed.push(OPTION_END);
+    }
+    Some(relayed)
+}
+
+/// Removes the relay agent information option from a reply before it goes
+/// to the client (RFC 3046 section 2.2). Returns `None` for malformed
+/// messages.
+fn strip_relay_option(payload: &[u8]) -> Option<Vec<u8>> {
+    let options = Bootp::new(payload)?.options()?;
+    let mut stripped = payload.to_vec();
+    if let Some(&(_, at, len)) = options
+        .iter()
+        .find(|(code, _, _)| *code == OPTION_RELAY_AGENT_INFO)
+    {
+        stripped.drain(at..at + len);
+    }
+    Some(stripped)
+}
+
+/// Addresses of the relay on both sides, the server requests are relayed
+/// to and the counters shown in the state dump
+pub struct DhcpRelay {
+    internal_iface: String,
+    internal: (MacAddr, Ipv4Addr),
+    external: (MacAddr, Ipv4Addr),
+    /// Server address, or the limited broadcast address
+    server: Ipv4Addr,
+    /// MAC of the last frame a reply came in with, used to reach a unicast
+    /// server; requests are broadcast on the link until a reply was seen
+    server_mac: Mutex<Option<MacAddr>>,
+    circuit_id: Option<Vec<u8>>,
+    requests: AtomicU64,
+    replies: AtomicU64,
+    malformed: AtomicU64,
+}
+
+impl DhcpRelay {
+    /// Relay between `internal_iface` and a server at `server`; with
+    /// `option82` requests carry the internal interface name as circuit ID
+    pub fn new(
+        internal_iface: String,
+        internal: (MacAddr, Ipv4Addr),
+        external: (MacAddr, Ipv4Addr),
+        server: Ipv4Addr,
+        option82: bool,
+    ) -> Self {
+        let circuit_id = option82.then(|| internal_iface.as_bytes().to_vec());
+        DhcpRelay {
+            internal_iface,
+            internal,
+            external,
+            server,
+            server_mac: Mutex::new(None),
+            circuit_id,
+            requests: AtomicU64::new(0),
+            replies: AtomicU64::new(0),
+            malformed: AtomicU64::new(0),
+        }
+    }
+
+    fn malformed(&self) -> bool {
+        self.malformed.fetch_add(1, Ordering::Relaxed);
+        debug!("Malformed DHCP message dropped");
+        false
+    }
+
+    fn state(&self) -> Vec<String> {
+        let server = match *self.server_mac.lock().unwrap() {
+            Some(mac) => format!("{} via {}", self.server, mac),
+            None => self.server.to_string(),
+        };
+        vec![format!(
+            "server {}, relayed {} request(s) and {} reply(ies), dropped malformed={}",
+            server,
+            self.requests.load(Ordering::Relaxed),
+            self.replies.load(Ordering::Relaxed),
+            self.malformed.load(Ordering::Relaxed)
+        )]
+    }
+}
+
+/// Forwards BOOTP requests from clients on the internal interface and the
+/// replies to them, recognised by the relay's address as giaddr, ahead of
+/// the port filters. Other DHCP traffic is left to the rest of the chain.
+pub struct DhcpRelayFilter {
+    relay: Arc<DhcpRelay>,
+}
+
+impl DhcpRelayFilter {
+    pub fn new(relay: Arc<DhcpRelay>) -> Self {
+        DhcpRelayFilter { relay }
+    }
+}
+
+impl Filter for DhcpRelayFilter {
+    fn name(&self) -> &str {
+        "dhcp-relay"
+    }
+
+    fn evaluate(&self, ctx: &PacketContext) -> Decision {
+        let (Some(IpHeader::V4(_)), Some(udp)) = (&ctx.ip, ctx.udp()) else {
+            return Decision::Continue;
+        };
+        let Some(bootp) = Bootp::new(udp.payload()) else {
+            return Decision::Continue;
+        };
+        let ports = (udp.get_source(), udp.get_destination());
+        let relayed = if ctx.ingress == self.relay.internal_iface {
+            ports == (DHCP_CLIENT_PORT, DHCP_SERVER_PORT)
+                && bootp.op() == BOOTREQUEST
+                && bootp.hops() < MAX_HOPS
+        } else {
+            ports == (DHCP_SERVER_PORT, DHCP_SERVER_PORT)
+                && bootp.op() == BOOTREPLY
+                && bootp.giaddr() == self.relay.internal.1
+        };
+        if relayed {
+            Decision::Forward
+        } else {
+            Decision::Continue
+        }
+    }
+
+    fn state(&self) -> Option<Vec<String>> {
+        Some(self.relay.state())
+    }
+}
+
+/// Sets the addresses and ports of a relayed frame
+fn readdress(
+    frame: &mut [u8],
+    datagram: &UdpDatagram,
+    (from_mac, from_ip, from_port): (MacAddr, Ipv4Addr, u16),
+    (to_mac, to_ip, to_port): (MacAddr, Ipv4Addr, u16),
+) {
+    let mut eth = MutableEthernetPacket::new(frame).expect("frame was located as UDP");
+    eth.set_source(from_mac);
+    eth.set_destination(to_mac);
+    let mut ip = MutableIpv4Packet::new(&mut frame[ETHERNET_HEADER_LEN..])
+        .expect("frame was located as UDP");
+    ip.set_source(from_ip);
+    ip.set_destination(to_ip);
+    datagram.set_ports(frame, [from_port, to_port]);
+}
+
+/// Relays requests from clients to the server, from the external
+/// interface's address. Goes ahead of source NAT, which then leaves the
+/// frame alone.
+pub struct RelayRequests {
+    relay: Arc<DhcpRelay>,
+}
+
+impl RelayRequests {
+    pub fn new(relay: Arc<DhcpRelay>) -> Self {
+        RelayRequests { relay }
+    }
+}
+
+impl Rewrite for RelayRequests {
+    fn name(&self) -> &str {
+        "dhcp-relay"
+    }
+
+    fn apply(&self, frame: &mut Vec<u8>) -> bool {
+        let relay = &self.relay;
+        let Some(datagram) = UdpDatagram::locate(frame) else {
+            return true;
+        };
+        if datagram.is_ipv6() || datagram.ports(frame) != [DHCP_CLIENT_PORT, DHCP_SERVER_PORT] {
+            return true;
+        }
+        let payload = &frame[datagram.payload()];
+        let Some(relayed) = relay_request(payload, relay.internal.1, relay.circuit_id.as_deref())
+        else {
+            return relay.malformed();
+        };
+        let server_mac = if relay.server.is_broadcast() {
+            None
+        } else {
+            *relay.server_mac.lock().unwrap()
+        };
+        let (mac, ip) = relay.external;
+        readdress(
+            frame,
+            &datagram,
+            (mac, ip, DHCP_SERVER_PORT),
+            (
+                server_mac.unwrap_or(MacAddr::broadcast()),
+                relay.server,
+                DHCP_SERVER_PORT,
+            ),
+        );
+        relay.requests.fetch_add(1, Ordering::Relaxed);
+        debug!("DHCP request relayed to {}", relay.server);
+        datagram.replace_payload(frame, &relayed)
+    }
+}
+
+/// Relays replies from the server to the client on the internal interface:
+/// broadcast if the client asked for it, else to its MAC and the address
+/// it has or is offered. Goes ahead of the unicast MAC rewrite, which
+/// leaves frames to DHCP clients alone.
+pub struct RelayReplies {
+    relay: Arc<DhcpRelay>,
+}
+
+impl RelayReplies {
+    pub fn new(relay: Arc<DhcpRelay>) -> Self {
+        RelayReplies { relay }
+    }
+}
+
+impl Rewrite for RelayReplies {
+    fn name(&self) -> &str {
+        "dhcp-relay"
+    }
+
+    fn apply(&self, frame: &mut Vec<u8>) -> bool {
+        let relay = &self.relay;
+        let Some(datagram) = UdpDatagram::locate(frame) else {
+            return true;
+        };
+        if datagram.is_ipv6() || datagram.ports(frame) != [DHCP_SERVER_PORT, DHCP_SERVER_PORT] {
+            return true;
+        }
+        let payload = &frame[datagram.payload()];
+        let Some(bootp) = Bootp::new(payload) else {
+            return true;
+        };
+        if bootp.op() != BOOTREPLY || bootp.giaddr() != relay.internal.1 {
+            return true;
+        }
+        let client = match bootp.chaddr() {
+            _ if bootp.flags() & FLAG_BROADCAST != 0 => None,
+            Some(mac) if !bootp.ciaddr().is_unspecified() => Some((mac, bootp.ciaddr())),
+            Some(mac) if !bootp.yiaddr().is_unspecified() => Some((mac, bootp.yiaddr())),
+            _ => None,
+        };
+        let client = client.unwrap_or((MacAddr::broadcast(), Ipv4Addr::BROADCAST));
+        let Some(stripped) = strip_relay_option(payload) else {
+            return relay.malformed();
+        };
+        let server_mac = MutableEthernetPacket::new(frame).map(|eth| eth.get_source());
+        *relay.server_mac.lock().unwrap() = server_mac;
+        let (mac, ip) = relay.internal;
+        readdress(
+            frame,
+            &datagram,
+            (mac, ip, DHCP_SERVER_PORT),
+            (client.0, client.1, DHCP_CLIENT_PORT),
+        );
+        relay.replies.fetch_add(1, Ordering::Relaxed);
+        debug!("DHCP reply relayed to {} at {}", client.1, client.0);
+        datagram.replace_payload(frame, &stripped)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil;
+    use pnet::packet::ethernet::EthernetPacket;
+    use pnet::packet::ipv4::{self, Ipv4Packet};
+    use pnet::packet::udp::{self, UdpPacket};
+
+    fn udp_frame(from: (Ipv4Addr, u16), to: (Ipv4Addr, u16), payload: &[u8]) -> Vec<u8> {
+        let mac = MacAddr::new(2, 0, 0, 0, 0, 9);
+        testutil::udp_frame(
+            mac,
+            MacAddr::broadcast(),
+            from.0,
+            to.0,
+            from.1,
+            to.1,
+            payload,
+        )
+    }
+
+    fn bootp(op: u8, chaddr: MacAddr, giaddr: Ipv4Addr, options: &[u8]) -> Vec<u8> {
+        let mut payload = vec![0; OPTIONS_OFFSET];
+        payload[..3].copy_from_slice(&[op, HTYPE_ETHERNET, 6]);
+        payload[24..28].copy_from_slice(&giaddr.octets());
+        payload[28..34].copy_from_slice(&chaddr.octets());
+        payload[OPTIONS_OFFSET - 4..].copy_from_slice(&MAGIC_COOKIE);
+        payload.extend_from_slice(options);
+        payload
+    }
+
+    fn checksums_valid(frame: &[u8]) -> bool {
+        let ip = Ipv4Packet::new(&frame[ETHERNET_HEADER_LEN..]).unwrap();
+        let udp = UdpPacket::new(ip.payload()).unwrap();
+        ipv4::checksum(&ip) == ip.get_checksum()
+            && udp::ipv4_checksum(&udp, &ip.get_source(), &ip.get_destination())
+                == udp.get_checksum()
+    }
+
+    #[test]
+    fn relays_requests_and_replies() {
+        let client = MacAddr::new(0x52, 0x54, 0, 0, 0, 7);
+        let internal = (
+            MacAddr::new(2, 0, 0, 0, 0, 1),
+            Ipv4Addr::new(192, 168, 100, 1),
+        );
+        let external = (MacAddr::new(2, 0, 0, 0, 0, 2), Ipv4Addr::new(192, 0, 2, 2));
+        let server = Ipv4Addr::new(192, 0, 2, 1);
+        let relay = Arc::new(DhcpRelay::new(
+            "vm1".to_string(),
+            internal,
+            external,
+            server,
+            true,
+        ));
+
+        // DHCPDISCOVER with a message type option
+        let discover = bootp(BOOTREQUEST, client, Ipv4Addr::UNSPECIFIED, &[53, 1, 1, 255]);
+        let mut frame = udp_frame(
+            (Ipv4Addr::UNSPECIFIED, DHCP_CLIENT_PORT),
+            (Ipv4Addr::BROADCAST, DHCP_SERVER_PORT),
+            &discover,
+        );
+        assert!(RelayRequests::new(relay.clone()).apply(&mut frame));
+        let ip = Ipv4Packet::new(&frame[ETHERNET_HEADER_LEN..]).unwrap();
+        assert_eq!(
+            (ip.get_source(), ip.get_destination()),
+            (external.1, server)
+        );
+        let udp = UdpPacket::new(ip.payload()).unwrap();
+        assert_eq!((udp.get_source(), udp.get_destination()), (67, 67));
+        let relayed = Bootp::new(udp.payload()).unwrap();
+        assert_eq!((relayed.hops(), relayed.giaddr()), (1, internal.1));
+        assert_eq!(
+            &udp.payload()[OPTIONS_OFFSET..],
+            [53, 1, 1, 82, 5, 1, 3, b'v', b'm', b'1', 255]
+        );
+        assert_eq!(
+            EthernetPacket::new(&frame).unwrap().get_destination(),
+            MacAddr::broadcast()
+        );
+        assert!(checksums_valid(&frame));
+
+        // DHCPOFFER to the relay, echoing the option
+        let mut offer = bootp(BOOTREPLY, client, internal.1, &[53, 1, 2]);
+        offer.extend(relay_agent_option(b"vm1"));
+        offer.push(255);
+        offer[16..20].copy_from_slice(&[192, 168, 100, 50]);
+        let mut frame = udp_frame(
+            (server, DHCP_SERVER_PORT),
+            (internal.1, DHCP_SERVER_PORT),
+            &offer,
+        );
+        assert!(RelayReplies::new(relay.clone()).apply(&mut frame));
+        let eth = EthernetPacket::new(&frame).unwrap();
+        assert_eq!(
+            (eth.get_source(), eth.get_destination()),
+            (internal.0, client)
+        );
+        let ip = Ipv4Packet::new(&frame[ETHERNET_HEADER_LEN..]).unwrap();
+        let offered = Ipv4Addr::new(192, 168, 100, 50);
+        assert_eq!(
+            (ip.get_source(), ip.get_destination()),
+            (internal.1, offered)
+        );
+        let udp = UdpPacket::new(ip.payload()).unwrap();
+        assert_eq!(udp.get_destination(), DHCP_CLIENT_PORT);
+        assert_eq!(&udp.payload()[OPTIONS_OFFSET..], [53, 1, 2, 255]);
+        assert!(checksums_valid(&frame));
+
+        // Options running past the end are dropped
+        let mut broken = discover.clone();
+        broken.truncate(OPTIONS_OFFSET + 2);
+        assert!(relay_request(&broken, internal.1, None).is_none());
+        assert!(relay_request(&discover, internal.1, None).is_some());
+    }
+}
@@ -0,0 +1,485 @@
+//! DHCPv6 relay agent wrapping the messages of the clients on the internal
+//! side into Relay-Forward messages to a server on the external side, and
+//! unwrapping the server's Relay-Reply messages back to them (RFC 8415
+//! section 19).
+
+use crate::filter::{Decision, Filter, IpHeader, PacketContext};
+use crate::hostmac::HostMacTable;
+use crate::iplink::group_mac;
+use crate::rewrite::{Rewrite, UdpDatagram};
+use pnet::packet::ethernet::{EthernetPacket, MutableEthernetPacket};
+use pnet::packet::ipv6::{Ipv6Packet, MutableIpv6Packet};
+use pnet::packet::Packet;
+use pnet::util::MacAddr;
+use std::net::Ipv6Addr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tracing::debug;
+
+pub const DHCPV6_CLIENT_PORT: u16 = 546;
+pub const DHCPV6_SERVER_PORT: u16 = 547;
+
+const ETHERNET_HEADER_LEN: usize = 14;
+/// Solicit, Request, Confirm, Renew, Rebind, Release, Decline and
+/// Information-request, the messages clients send
+const CLIENT_MESSAGES: [u8; 8] = [1, 3, 4, 5, 6, 8, 9, 11];
+const RELAY_FORW: u8 = 12;
+const RELAY_REPL: u8 = 13;
+/// Message type, hop count, link address and peer address
+const RELAY_HEADER_LEN: usize = 34;
+const OPTION_RELAY_MSG: u16 = 9;
+const OPTION_INTERFACE_ID: u16 = 18;
+/// Hop limit of the relayed requests, which clients send with one as they
+/// only reach their link
+const RELAY_HOP_LIMIT: u8 = 32;
+
+/// Code and data of every option, `None` if an option runs past the end
+fn options(data: &[u8]) -> Option<Vec<(u16, &[u8])>> {
+    let mut options = Vec::new();
+    let mut at = 0;
+    while at < data.len() {
+        let header = data.get(at..at + 4)?;
+        let code = u16::from_be_bytes([header[0], header[1]]);
+        let len = usize::from(u16::from_be_bytes([header[2], header[3]]));
+        options.push((code, data.get(at + 4..at + 4 + len)?));
+        at += 4 + len;
+    }
+    Some(options)
+}
+
+fn option(code: u16, data: &[u8]) -> Vec<u8> {
+    let mut option = code.to_be_bytes().to_vec();
+    option.extend_from_slice(&(data.len() as u16).to_be_bytes());
+    option.extend_from_slice(data);
+    option
+}
+
+/// Relay-Forward message carrying `message` from the client at `peer` on
+/// the link identified by `link` and `interface_id`
+fn relay_forward(message: &[u8], link: Ipv6Addr, peer: Ipv6Addr, interface_id: &[u8]) -> Vec<u8> {
+    let mut relayed = vec![RELAY_FORW, 0];
+    relayed.extend_from_slice(&link.octets());
+    relayed.extend_from_slice(&peer.octets());
+    relayed.extend(option(OPTION_INTERFACE_ID, interface_id));
+    relayed.extend(option(OPTION_RELAY_MSG, message));
+    relayed
+}
+
+/// Peer address and relayed message of a Relay-Reply echoing
+/// `interface_id`. Returns `None` for other messages, and no message for
+/// replies without one.
+fn relay_reply<'a>(payload: &'a [u8], interface_id: &[u8]) -> Option<(Ipv6Addr, Option<&'a [u8]>)> {
+    if payload.first() != Some(&RELAY_REPL) {
+        return None;
+    }
+    let header = payload.get(..RELAY_HEADER_LEN)?;
+    let options = options(&payload[RELAY_HEADER_LEN..])?;
+    let find = |wanted: u16| {
+        options
+            .iter()
+            .find(|(code, _)| *code == wanted)
+            .map(|(_, data)| *data)
+    };
+    if find(OPTION_INTERFACE_ID) != Some(interface_id) {
+        return None;
+    }
+    let peer: [u8; 16] = header[18..].try_into().expect("header is sliced to length");
+    let message = find(OPTION_RELAY_MSG).filter(|message| !message.is_empty());
+    Some((peer.into(), message))
+}
+
+/// Solicited-node multicast group of `ip` (RFC 4291 section 2.7.1)
+fn solicited_node(ip: Ipv6Addr) -> Ipv6Addr {
+    let [.., a, b, c] = ip.octets();
+    Ipv6Addr::new(
+        0xff02,
+        0,
+        0,
+        0,
+        0,
+        1,
+        0xff00 | u16::from(a),
+        u16::from_be_bytes([b, c]),
+    )
+}
+
+/// Addresses of the relay on both sides, the server requests are relayed
+/// to, the clients replies go back to and the counters shown in the state
+/// dump
+pub struct Dhcpv6Relay {
+    internal_iface: String,
+    /// MAC and link-local address replies reach the clients from
+    internal: (MacAddr, Ipv6Addr),
+    /// Address the server tells the clients' link by, unspecified if the
+    /// internal interface has no global one; the interface ID names it then
+    link_address: Ipv6Addr,
+    external: (MacAddr, Ipv6Addr),
+    /// Server address, or a multicast group of relay agents and servers
+    server: Ipv6Addr,
+    /// MAC of the last frame a reply came in with, used to reach a unicast
+    /// server; requests go to its solicited-node group until a reply was
+    /// seen
+    server_mac: Mutex<Option<MacAddr>>,
+    /// MACs of the clients by the address their requests came from
+    clients: HostMacTable<Ipv6Addr>,
+    requests: AtomicU64,
+    replies: AtomicU64,
+    malformed: AtomicU64,
+}
+
+impl Dhcpv6Relay {
+    /// Relay between `internal_iface` and a server at `server`
+    pub fn new(
+        internal_iface: String,
+        internal: (MacAddr, Ipv6Addr),
+        link_address: Ipv6Addr,
+        external: (MacAddr, Ipv6Addr),
+        server: Ipv6Addr,
+        clients: HostMacTable<Ipv6Addr>,
+    ) -> Self {
+        Dhcpv6Relay {
+            internal_iface,
+            internal,
+            link_address,
+            external,
+            server,
+            server_mac: Mutex::new(None),
+            clients,
+            requests: AtomicU64::new(0),
+            replies: AtomicU64::new(0),
+            malformed: AtomicU64::new(0),
+        }
+    }
+
+    fn malformed(&self) -> bool {
+        self.malformed.fetch_add(1, Ordering::Relaxed);
+        debug!("Malformed DHCPv6 message dropped");
+        false
+    }
+
+    fn state(&self) -> Vec<String> {
+        let server = match *self.server_mac.lock().unwrap() {
+            Some(mac) => format!("{} via {}", self.server, mac),
+            None => self.server.to_string(),
+        };
+        vec![format!(
+            "server {}, relayed {} request(s) and {} reply(ies), dropped malformed={}",
+            server,
+            self.requests.load(Ordering::Relaxed),
+            self.replies.load(Ordering::Relaxed),
+            self.malformed.load(Ordering::Relaxed)
+        )]
+    }
+}
+
+/// Forwards messages from clients on the internal interface and the
+/// Relay-Reply messages to them, recognised by the internal interface name
+/// as interface ID, ahead of snooping and the port filters. Other DHCPv6
+/// traffic is left to the rest of the chain.
+pub struct Dhcpv6RelayFilter {
+    relay: Arc<Dhcpv6Relay>,
+}
+
+impl Dhcpv6RelayFilter {
+    pub fn new(relay: Arc<Dhcpv6Relay>) -> Self {
+        Dhcpv6RelayFilter { relay }
+    }
+}
+
+impl Filter for Dhcpv6RelayFilter {
+    fn name(&self) -> &str {
+        "dhcpv6-relay"
+    }
+
+    fn evaluate(&self, ctx: &PacketContext) -> Decision {
+        let (Some(IpHeader::V6(_)), Some(udp)) = (&ctx.ip, ctx.udp()) else {
+            return Decision::Continue;
+        };
+        let ports = (udp.get_source(), udp.get_destination());
+        let payload = udp.payload();
+        let relayed = if ctx.ingress == self.relay.internal_iface {
+            ports == (DHCPV6_CLIENT_PORT, DHCPV6_SERVER_PORT)
+                && payload
+                    .first()
+                    .is_some_and(|kind| CLIENT_MESSAGES.contains(kind))
+        } else {
+            ports == (DHCPV6_SERVER_PORT, DHCPV6_SERVER_PORT)
+                && relay_reply(payload, self.relay.internal_iface.as_bytes()).is_some()
+        };
+        if relayed {
+            Decision::Forward
+        } else {
+            Decision::Continue
+        }
+    }
+
+    fn state(&self) -> Option<Vec<String>> {
+        Some(self.relay.state())
+    }
+}
+
+/// Sets the addresses and ports of a relayed frame
+fn readdress(
+    frame: &mut [u8],
+    datagram: &UdpDatagram,
+    (from_mac, from_ip, from_port): (MacAddr, Ipv6Addr, u16),
+    (to_mac, to_ip, to_port): (MacAddr, Ipv6Addr, u16),
+) {
+    let mut eth = MutableEthernetPacket::new(frame).expect("frame was located as UDP");
+    eth.set_source(from_mac);
+    eth.set_destination(to_mac);
+    let mut ip = MutableIpv6Packet::new(&mut frame[ETHERNET_HEADER_LEN..])
+        .expect("frame was located as UDP");
+    ip.set_source(from_ip);
+    ip.set_destination(to_ip);
+    datagram.set_ports(frame, [from_port, to_port]);
+}
+
+/// Relays messages from clients to the server in Relay-Forward messages,
+/// from the external interface's address, learning the client's MAC for
+/// the reply
+pub struct RelayRequests {
+    relay: Arc<Dhcpv6Relay>,
+}
+
+impl RelayRequests {
+    pub fn new(relay: Arc<Dhcpv6Relay>) -> Self {
+        RelayRequests { relay }
+    }
+}
+
+impl Rewrite for RelayRequests {
+    fn name(&self) -> &str {
+        "dhcpv6-relay"
+    }
+
+    fn apply(&self, frame: &mut Vec<u8>) -> bool {
+        let relay = &self.relay;
+        let Some(datagram) = UdpDatagram::locate(frame) else {
+            return true;
+        };
+        if !datagram.is_ipv6() || datagram.ports(frame) != [DHCPV6_CLIENT_PORT, DHCPV6_SERVER_PORT]
+        {
+            return true;
+        }
+        let payload = &frame[datagram.payload()];
+        if !payload
+            .first()
+            .is_some_and(|kind| CLIENT_MESSAGES.contains(kind))
+        {
+            return true;
+        }
+        let eth = EthernetPacket::new(frame).expect("frame was located as UDP");
+        let ip = Ipv6Packet::new(&frame[ETHERNET_HEADER_LEN..]).expect("frame was located as UDP");
+        let (client_mac, peer) = (eth.get_source(), ip.get_source());
+        let relayed = relay_forward(
+            payload,
+            relay.link_address,
+            peer,
+            relay.internal_iface.as_bytes(),
+        );
+        relay.clients.learn(peer, client_mac);
+        let server_mac = if relay.server.is_multicast() {
+            group_mac(relay.server.into())
+        } else {
+            let learned = *relay.server_mac.lock().unwrap();
+            learned.unwrap_or_else(|| group_mac(solicited_node(relay.server).into()))
+        };
+        let (mac, ip) = relay.external;
+        readdress(
+            frame,
+            &datagram,
+            (mac, ip, DHCPV6_SERVER_PORT),
+            (server_mac, relay.server, DHCPV6_SERVER_PORT),
+        );
+        MutableIpv6Packet::new(&mut frame[ETHERNET_HEADER_LEN..])
+            .expect("frame was located as UDP")
+            .set_hop_limit(RELAY_HOP_LIMIT);
+        relay.requests.fetch_add(1, Ordering::Relaxed);
+        debug!("DHCPv6 message from {} relayed to {}", peer, relay.server);
+        datagram.replace_payload(frame, &relayed)
+    }
+}
+
+/// Relays the messages in Relay-Reply messages from the server to the
+/// client on the internal interface, at its learned MAC or else its
+/// solicited-node group
+pub struct RelayReplies {
+    relay: Arc<Dhcpv6Relay>,
+}
+
+impl RelayReplies {
+    pub fn new(relay: Arc<Dhcpv6Relay>) -> Self {
+        RelayReplies { relay }
+    }
+}
+
+impl Rewrite for RelayReplies {
+    fn name(&self) -> &str {
+        "dhcpv6-relay"
+    }
+
+    fn apply(&self, frame: &mut Vec<u8>) -> bool {
+        let relay = &self.relay;
+        let Some(datagram) = UdpDatagram::locate(frame) else {
+            return true;
+        };
+        if !datagram.is_ipv6() || datagram.ports(frame) != [DHCPV6_SERVER_PORT, DHCPV6_SERVER_PORT]
+        {
+            return true;
+        }
+        let payload = &frame[datagram.payload()];
+        let Some((peer, message)) = relay_reply(payload, relay.internal_iface.as_bytes()) else {
+            return true;
+        };
+        let Some(message) = message.map(<[u8]>::to_vec) else {
+            return relay.malformed();
+        };
+        let server_mac = EthernetPacket::new(frame).map(|eth| eth.get_source());
+        *relay.server_mac.lock().unwrap() = server_mac;
+        let client_mac = relay
+            .clients
+            .lookup(peer)
+            .unwrap_or_else(|| group_mac(solicited_node(peer).into()));
+        let (mac, ip) = relay.internal;
+        readdress(
+            frame,
+            &datagram,
+            (mac, ip, DHCPV6_SERVER_PORT),
+            (client_mac, peer, DHCPV6_CLIENT_PORT),
+        );
+        relay.replies.fetch_add(1, Ordering::Relaxed);
+        debug!("DHCPv6 reply relayed to {} at {}", peer, client_mac);
+        datagram.replace_payload(frame, &message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pair::Direction;
+    use crate::testutil;
+    use pnet::packet::udp::{self, UdpPacket};
+    use std::time::Duration;
+
+    fn udp_frame(from: (Ipv6Addr, u16), to: (Ipv6Addr, u16), payload: &[u8]) -> Vec<u8> {
+        let mac = MacAddr::new(0x52, 0x54, 0, 0, 0, 7);
+        testutil::udp_frame(
+            mac,
+            testutil::multicast_mac(to.0.into()),
+            from.0,
+            to.0,
+            from.1,
+            to.1,
+            payload,
+        )
+    }
+
+    fn checksum_valid(frame: &[u8]) -> bool {
+        let ip = Ipv6Packet::new(&frame[ETHERNET_HEADER_LEN..]).unwrap();
+        let udp = UdpPacket::new(ip.payload()).unwrap();
+        udp::ipv6_checksum(&udp, &ip.get_source(), &ip.get_destination()) == udp.get_checksum()
+    }
+
+    #[test]
+    fn relays_requests_and_replies() {
+        let client: Ipv6Addr = "fe80::5054:ff:fe00:7".parse().unwrap();
+        let internal = (MacAddr::new(2, 0, 0, 0, 0, 1), "fe80::1".parse().unwrap());
+        let external = (
+            MacAddr::new(2, 0, 0, 0, 0, 2),
+            "2001:db8::2".parse().unwrap(),
+        );
+        let server: Ipv6Addr = "2001:db8::1".parse().unwrap();
+        let relay = Arc::new(Dhcpv6Relay::new(
+            "vm1".to_string(),
+            internal,
+            Ipv6Addr::UNSPECIFIED,
+            external,
+            server,
+            HostMacTable::new(16, Duration::from_secs(60)),
+        ));
+        let filter = Dhcpv6RelayFilter::new(relay.clone());
+
+        // Solicit with a transaction ID and an elapsed time option
+        let solicit = [1, 0xaa, 0xbb, 0xcc, 0, 8, 0, 2, 0, 0];
+        let all_agents = "ff02::1:2".parse().unwrap();
+        let mut frame = udp_frame(
+            (client, DHCPV6_CLIENT_PORT),
+            (all_agents, DHCPV6_SERVER_PORT),
+            &solicit,
+        );
+        let ctx = PacketContext::parse("vm1", Direction::Outbound, &frame).unwrap();
+        assert_eq!(filter.evaluate(&ctx), Decision::Forward);
+        let ctx = PacketContext::parse("eth0", Direction::Inbound, &frame).unwrap();
+        assert_eq!(filter.evaluate(&ctx), Decision::Continue);
+
+        assert!(RelayRequests::new(relay.clone()).apply(&mut frame));
+        let eth = EthernetPacket::new(&frame).unwrap();
+        // The server's MAC is not known yet
+        assert_eq!(
+            (eth.get_source(), eth.get_destination()),
+            (external.0, MacAddr::new(0x33, 0x33, 0xff, 0, 0, 1))
+        );
+        let ip = Ipv6Packet::new(&frame[ETHERNET_HEADER_LEN..]).unwrap();
+        assert_eq!(
+            (ip.get_source(), ip.get_destination(), ip.get_hop_limit()),
+            (external.1, server, RELAY_HOP_LIMIT)
+        );
+        let udp = UdpPacket::new(ip.payload()).unwrap();
+        assert_eq!((udp.get_source(), udp.get_destination()), (547, 547));
+        let mut expected = vec![RELAY_FORW, 0];
+        expected.extend([0; 16]);
+        expected.extend(client.octets());
+        expected.extend([0, 18, 0, 3, b'v', b'm', b'1', 0, 9, 0, 10]);
+        expected.extend(solicit);
+        assert_eq!(udp.payload(), expected);
+        assert!(checksum_valid(&frame));
+
+        // Advertise in a Relay-Reply echoing the interface ID
+        let advertise = [2, 0xaa, 0xbb, 0xcc];
+        let mut reply = expected.clone();
+        reply[0] = RELAY_REPL;
+        reply.truncate(RELAY_HEADER_LEN + 7);
+        reply.extend(option(OPTION_RELAY_MSG, &advertise));
+        let mut frame = udp_frame(
+            (server, DHCPV6_SERVER_PORT),
+            (external.1, DHCPV6_SERVER_PORT),
+            &reply,
+        );
+        let ctx = PacketContext::parse("eth0", Direction::Inbound, &frame).unwrap();
+        assert_eq!(filter.evaluate(&ctx), Decision::Forward);
+        assert!(RelayReplies::new(relay.clone()).apply(&mut frame));
+        let eth = EthernetPacket::new(&frame).unwrap();
+        assert_eq!(
+            (eth.get_source(), eth.get_destination()),
+            (internal.0, MacAddr::new(0x52, 0x54, 0, 0, 0, 7))
+        );
+        let ip = Ipv6Packet::new(&frame[ETHERNET_HEADER_LEN..]).unwrap();
+        assert_eq!(
+            (ip.get_source(), ip.get_destination()),
+            (internal.1, client)
+        );
+        let udp = UdpPacket::new(ip.payload()).unwrap();
+        assert_eq!((udp.get_source(), udp.get_destination()), (547, 546));
+        assert_eq!(udp.payload(), advertise);
+        assert!(checksum_valid(&frame));
+
+        // Replies for another link and broken options are left alone
+        let mut other = reply.clone();
+        other[RELAY_HEADER_LEN + 6] = b'2';
+        assert_eq!(relay_reply(&other, b"vm1"), None);
+        assert_eq!(relay_reply(&reply[..reply.len() - 1], b"vm1"), None);
+        assert_eq!(
+            relay_reply(&reply, b"vm1"),
+            Some((client, Some(&advertise[..])))
+        );
+        assert_eq!(
+            relay.state(),
+            [format!(
+                "server {} via {}, relayed 1 request(s) and 1 reply(ies), dropped malformed=0",
+                server,
+                MacAddr::new(0x52, 0x54, 0, 0, 0, 7)
+            )]
+        );
+    }
+}
@@ -0,0 +1,49 @@
+//! Devices the SSDP and mDNS caches learned from announcements forwarded
+//! inwards, listed on request and, over D-Bus, announced as they appear.
+
+use serde::Serialize;
+#[cfg(feature = "dbus")]
+use tokio::sync::mpsc;
+#[cfg(feature = "dbus")]
+use tracing::debug;
+
+/// Newly learned devices waiting to be announced; more are dropped
+#[cfg(feature = "dbus")]
+const QUEUE_CAPACITY: usize = 64;
+
+/// A device announced over SSDP or a service instance over mDNS
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Device {
+    /// `ssdp` or `mdns`
+    pub protocol: &'static str,
+    /// USN, or name of the service instance
+    pub id: String,
+    /// Notification type, or service type
+    pub kind: String,
+    /// LOCATION URL, or host and port of the service if its SRV record is
+    /// cached
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub location: Option<String>,
+    /// Seconds until the announcement expires
+    pub expires_in: u64,
+}
+
+/// Hands the devices a cache learns for the first time to whoever announces
+/// them, without ever blocking the capture loop
+#[cfg(feature = "dbus")]
+#[derive(Debug, Clone)]
+pub struct Discoveries(mpsc::Sender<Device>);
+
+#[cfg(feature = "dbus")]
+impl Discoveries {
+    pub fn channel() -> (Self, mpsc::Receiver<Device>) {
+        let (tx, rx) = mpsc::channel(QUEUE_CAPACITY);
+        (Discoveries(tx), rx)
+    }
+
+    pub fn announce(&self, device: Device) {
+        if let Err(e) = self.0.try_send(device) {
+            debug!("Discovered device not announced: {}", e);
+        }
+    }
+}
@@ -0,0 +1,382 @@
+//! QoS marking of forwarded packets: the DSCP of the IPv4 TOS byte or IPv6
+//! traffic class is set per class of traffic, so switches trusting DSCP
+//! prioritise discovery and control traffic, and optionally the 802.1p
+//! priority of the VLAN tag the frame is sent with.
+
+use crate::checksum;
+use crate::pair::Direction;
+use crate::rewrite::Rewrite;
+use clap::ValueEnum;
+use pnet::packet::ethernet::{EtherTypes, EthernetPacket};
+use pnet::packet::ip::IpNextHeaderProtocols;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+const ETHERNET_HEADER_LEN: usize = 14;
+/// Offset of the IPv4 TOS byte, behind version and header length
+const IPV4_TOS: usize = ETHERNET_HEADER_LEN + 1;
+const IPV4_PROTOCOL: usize = ETHERNET_HEADER_LEN + 9;
+const IPV4_CHECKSUM: usize = ETHERNET_HEADER_LEN + 10;
+/// Offset of the IPv6 next header, that of the traffic class is the start
+const IPV6_NEXT_HEADER: usize = ETHERNET_HEADER_LEN + 6;
+/// Traffic class bits of the first 16 bits of an IPv6 header
+const IPV6_CLASS_SHIFT: u16 = 4;
+/// ECN bits below the DSCP in the TOS byte or traffic class
+const ECN_BITS: u8 = 2;
+const MAX_DSCP: u8 = 63;
+
+/// DSCP value, written as a class selector such as `cs4`, an assured
+/// forwarding class such as `af41`, `ef`, or a number up to 63
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Dscp(u8);
+
+impl Dscp {
+    pub fn value(self) -> u8 {
+        self.0
+    }
+
+    /// 802.1p priority of the same class: the class selector bits
+    pub fn priority(self) -> u8 {
+        self.0 >> 3
+    }
+}
+
+impl FromStr for Dscp {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let name = value.trim().to_ascii_lowercase();
+        let digit = |c: Option<char>, range: std::ops::RangeInclusive<u8>| {
+            c.and_then(|c| c.to_digit(10))
+                .and_then(|d| u8::try_from(d).ok())
+                .filter(|d| range.contains(d))
+        };
+        let dscp = if name == "ef" {
+            Some(46)
+        } else if let Some(class) = name.strip_prefix("cs") {
+            let mut chars = class.chars();
+            digit(chars.next(), 0..=7)
+                .filter(|_| chars.next().is_none())
+                .map(|class| class << 3)
+        } else if let Some(class) = name.strip_prefix("af") {
+            let mut chars = class.chars();
+            match (digit(chars.next(), 1..=4), digit(chars.next(), 1..=3)) {
+                (Some(class), Some(drop)) if chars.next().is_none() => Some(class << 3 | drop << 1),
+                _ => None,
+            }
+        } else {
+            name.parse().ok().filter(|dscp| *dscp <= MAX_DSCP)
+        };
+        dscp.map(Dscp)
+            .ok_or_else(|| format!("'{}' is not a DSCP name or a number up to 63", value))
+    }
+}
+
+impl TryFrom<String> for Dscp {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<Dscp> for String {
+    fn from(dscp: Dscp) -> Self {
+        dscp.to_string()
+    }
+}
+
+impl fmt::Display for Dscp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.0 >> 3, self.0 & 7) {
+            _ if self.0 == 46 => write!(f, "ef"),
+            (class, 0) => write!(f, "cs{}", class),
+            (class @ 1..=4, drop @ (2 | 4 | 6)) => write!(f, "af{}{}", class, drop / 2),
+            _ => write!(f, "{}", self.0),
+        }
+    }
+}
+
+/// Traffic a DSCP is set for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrafficClass {
+    /// UDP, such as SSDP, mDNS and WS-Discovery
+    Discovery,
+    /// TCP, such as the control ports of casting receivers
+    Control,
+}
+
+impl TrafficClass {
+    fn name(self) -> &'static str {
+        match self {
+            TrafficClass::Discovery => "discovery",
+            TrafficClass::Control => "control",
+        }
+    }
+}
+
+/// DSCP for one class of traffic, written as `CLASS=DSCP`, e.g.
+/// `discovery=cs4`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct DscpMark {
+    pub class: TrafficClass,
+    pub dscp: Dscp,
+}
+
+impl FromStr for DscpMark {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (class, dscp) = value
+            .split_once('=')
+            .ok_or_else(|| format!("'{}' is not CLASS=DSCP", value))?;
+        let class = match class.trim() {
+            "discovery" => TrafficClass::Discovery,
+            "control" => TrafficClass::Control,
+            other => {
+                return Err(format!(
+                    "unknown traffic class '{}', expected discovery or control",
+                    other
+                ))
+            }
+        };
+        Ok(DscpMark {
+            class,
+            dscp: dscp.parse()?,
+        })
+    }
+}
+
+impl TryFrom<String> for DscpMark {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<DscpMark> for String {
+    fn from(mark: DscpMark) -> Self {
+        mark.to_string()
+    }
+}
+
+impl fmt::Display for DscpMark {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", self.class.name(), self.dscp)
+    }
+}
+
+/// Directions packets are marked in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MarkDirection {
+    /// Mark packets forwarded either way
+    Both,
+    /// Only mark packets sent towards the external interface
+    Outbound,
+    /// Only mark packets sent towards the internal interface
+    Inbound,
+}
+
+impl MarkDirection {
+    /// Whether packets forwarded in `direction` are marked. Without sides,
+    /// as in bridge mode, only `Both` marks them.
+    pub fn marks(self, direction: Direction) -> bool {
+        match direction {
+            Direction::Inbound => self != MarkDirection::Outbound,
+            Direction::Outbound => self != MarkDirection::Inbound,
+            Direction::Bridged => self == MarkDirection::Both,
+        }
+    }
+}
+
+/// DSCP of each class of traffic, for the classes that are marked
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Marking {
+    discovery: Option<Dscp>,
+    control: Option<Dscp>,
+}
+
+impl Marking {
+    /// Marking by `marks`, of which the last one given for a class counts
+    pub fn new(marks: &[DscpMark]) -> Self {
+        let mut marking = Marking::default();
+        for mark in marks {
+            match mark.class {
+                TrafficClass::Discovery => marking.discovery = Some(mark.dscp),
+                TrafficClass::Control => marking.control = Some(mark.dscp),
+            }
+        }
+        marking
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.discovery.is_none() && self.control.is_none()
+    }
+
+    /// DSCP an untagged Ethernet frame is marked with, by the transport
+    /// protocol of its IP packet
+    pub fn dscp(&self, frame: &[u8]) -> Option<Dscp> {
+        let eth = EthernetPacket::new(frame)?;
+        let protocol = match eth.get_ethertype() {
+            EtherTypes::Ipv4 => *frame.get(IPV4_PROTOCOL)?,
+            EtherTypes::Ipv6 => *frame.get(IPV6_NEXT_HEADER)?,
+            _ => return None,
+        };
+        match protocol {
+            p if p == IpNextHeaderProtocols::Udp.0 => self.discovery,
+            p if p == IpNextHeaderProtocols::Tcp.0 => self.control,
+            _ => None,
+        }
+    }
+}
+
+/// Sets the DSCP of forwarded IP packets of a marked class, keeping their
+/// ECN bits and updating the IPv4 header checksum. Other frames pass
+/// unchanged.
+pub struct MarkDscp {
+    marking: Marking,
+}
+
+impl MarkDscp {
+    pub fn new(marking: Marking) -> Self {
+        MarkDscp { marking }
+    }
+}
+
+impl Rewrite for MarkDscp {
+    fn name(&self) -> &str {
+        "mark-dscp"
+    }
+
+    fn apply(&self, frame: &mut Vec<u8>) -> bool {
+        let Some(dscp) = self.marking.dscp(frame) else {
+            return true;
+        };
+        let dscp = dscp.value() << ECN_BITS;
+        let ecn = (1 << ECN_BITS) - 1;
+        let ipv6 =
+            EthernetPacket::new(frame).is_some_and(|eth| eth.get_ethertype() == EtherTypes::Ipv6);
+        if ipv6 {
+            let start = ETHERNET_HEADER_LEN;
+            let word = u16::from_be_bytes([frame[start], frame[start + 1]]);
+            let class_mask = u16::from(!ecn) << IPV6_CLASS_SHIFT;
+            let word = (word & !class_mask) | u16::from(dscp) << IPV6_CLASS_SHIFT;
+            frame[start..start + 2].copy_from_slice(&word.to_be_bytes());
+            return true;
+        }
+        if frame.len() < IPV4_CHECKSUM + 2 {
+            return true;
+        }
+        let (version, tos) = (frame[ETHERNET_HEADER_LEN], frame[IPV4_TOS]);
+        let marked = dscp | (tos & ecn);
+        if marked == tos {
+            return true;
+        }
+        frame[IPV4_TOS] = marked;
+        let sum = u16::from_be_bytes([frame[IPV4_CHECKSUM], frame[IPV4_CHECKSUM + 1]]);
+        let sum = checksum::adjust(
+            sum,
+            u16::from_be_bytes([version, tos]),
+            u16::from_be_bytes([version, marked]),
+        );
+        frame[IPV4_CHECKSUM..IPV4_CHECKSUM + 2].copy_from_slice(&sum.to_be_bytes());
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::checksum::Verdict;
+    use crate::testutil::{multicast_mac, udp_frame, HOST_IP, HOST_MAC, SSDP_IPV4_GROUP};
+    use std::net::{IpAddr, Ipv6Addr};
+
+    #[test]
+    fn parses_dscp_names() {
+        for (name, value) in [
+            ("cs4", 32),
+            ("AF41", 34),
+            ("ef", 46),
+            ("10", 10),
+            ("cs0", 0),
+        ] {
+            assert_eq!(name.parse::<Dscp>(), Ok(Dscp(value)), "{}", name);
+        }
+        for invalid in ["cs8", "af51", "af44", "af4", "64", "x"] {
+            assert!(invalid.parse::<Dscp>().is_err(), "{}", invalid);
+        }
+        assert_eq!(Dscp(34).to_string(), "af41");
+        assert_eq!(Dscp(5).to_string(), "5");
+        assert_eq!(Dscp(34).priority(), 4);
+        let mark: DscpMark = "control=af41".parse().unwrap();
+        assert_eq!(mark.to_string(), "control=af41");
+        assert!("media=cs4".parse::<DscpMark>().is_err());
+        assert!(!MarkDirection::Inbound.marks(Direction::Outbound));
+        assert!(!MarkDirection::Outbound.marks(Direction::Bridged));
+    }
+
+    #[test]
+    fn marks_only_the_dscp_bits() {
+        let marking = Marking::new(&["discovery=cs4".parse().unwrap()]);
+        let stage = MarkDscp::new(marking);
+        let group = IpAddr::V4(SSDP_IPV4_GROUP);
+        let mut frame = udp_frame(
+            HOST_MAC,
+            multicast_mac(group),
+            HOST_IP,
+            group,
+            1900,
+            1900,
+            b"NOTIFY",
+        );
+        // ECN capable transport, which marking must leave alone
+        frame[IPV4_TOS] = 0b10;
+        assert!(checksum::update_ipv4(&mut frame));
+        let original = frame.clone();
+        assert!(stage.apply(&mut frame));
+        assert_eq!(frame[IPV4_TOS], 32 << 2 | 0b10);
+        assert_eq!(checksum::verify(&frame), Verdict::Valid);
+        let changed: Vec<usize> = (0..frame.len())
+            .filter(|&at| frame[at] != original[at])
+            .collect();
+        assert_eq!(changed, [IPV4_TOS, IPV4_CHECKSUM, IPV4_CHECKSUM + 1]);
+
+        let group = IpAddr::V6(Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 0xc));
+        let source = IpAddr::V6(Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 5));
+        let mut frame = udp_frame(
+            HOST_MAC,
+            multicast_mac(group),
+            source,
+            group,
+            1900,
+            1900,
+            b"NOTIFY",
+        );
+        // Traffic class 0x01 (ECN) and flow label 0xabcde
+        frame[ETHERNET_HEADER_LEN..ETHERNET_HEADER_LEN + 4]
+            .copy_from_slice(&[0x60, 0x1a, 0xbc, 0xde]);
+        let original = frame.clone();
+        assert!(stage.apply(&mut frame));
+        assert_eq!(
+            frame[ETHERNET_HEADER_LEN..ETHERNET_HEADER_LEN + 4],
+            [0x68, 0x1a, 0xbc, 0xde]
+        );
+        assert_eq!(
+            frame[ETHERNET_HEADER_LEN + 4..],
+            original[ETHERNET_HEADER_LEN + 4..]
+        );
+        assert_eq!(checksum::verify(&frame), Verdict::Valid);
+
+        // TCP is not marked without a DSCP for control traffic
+        let mut tcp = original.clone();
+        tcp[IPV6_NEXT_HEADER] = IpNextHeaderProtocols::Tcp.0;
+        let unchanged = tcp.clone();
+        assert!(stage.apply(&mut tcp));
+        assert_eq!(tcp, unchanged);
+    }
+}
@@ -0,0 +1,164 @@
+//! Startup errors and the process exit codes they map to.
+
+use std::io;
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::time::Duration;
+use thiserror::Error;
+
+/// Why a forwarder could not be built or started
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("interface {name} not found, available interfaces: {available} (see `nw-pckt-fwd list-interfaces`)")]
+    InterfaceNotFound { name: String, available: String },
+
+    #[error("interface(s) {names} did not appear within {}", humantime::format_duration(*timeout))]
+    InterfaceWaitTimeout { names: String, timeout: Duration },
+
+    #[error("permission denied opening {iface}, CAP_NET_RAW is required: {source}")]
+    PermissionDenied { iface: String, source: io::Error },
+
+    #[error("unsupported channel type on {0}")]
+    UnsupportedChannel(String),
+
+    #[error("interface {iface} cannot be used for forwarding: {reason} (see `nw-pckt-fwd list-interfaces`)")]
+    UnusableInterface { iface: String, reason: &'static str },
+
+    #[error("network namespace {netns} cannot be used: {source}")]
+    Netns { netns: String, source: io::Error },
+
+    #[error("failed to open channel on {iface}: {source}")]
+    Channel { iface: String, source: io::Error },
+
+    #[error("failed to join multicast groups on {iface}: {source}")]
+    Multicast { iface: String, source: io::Error },
+
+    #[error("interface {iface} has no {what}")]
+    MissingAddress { iface: String, what: &'static str },
+
+    #[error("invalid interface pairs: {0}")]
+    InvalidPairs(String),
+
+    #[error("invalid bridge interfaces: {0}")]
+    InvalidBridge(String),
+
+    #[error("mirror interface {0} is also used for forwarding")]
+    MirrorInUse(String),
+
+    #[error("unknown {what} {name}")]
+    UnknownId { what: &'static str, name: String },
+
+    #[error("failed to look up {what} {name}: {source}")]
+    Lookup {
+        what: &'static str,
+        name: String,
+        source: io::Error,
+    },
+
+    #[error("failed to drop privileges to user {user}: {source}")]
+    DropPrivileges { user: String, source: io::Error },
+
+    #[error("failed to install the seccomp filter: {0}")]
+    Seccomp(seccompiler::Error),
+
+    #[error("failed to read configuration file {}: {source}", path.display())]
+    ConfigRead { path: PathBuf, source: io::Error },
+
+    #[error("invalid configuration file {}: {source}", path.display())]
+    ConfigParse {
+        path: PathBuf,
+        source: toml::de::Error,
+    },
+
+    #[error("failed to create pcap file {}: {source}", path.display())]
+    Pcap { path: PathBuf, source: io::Error },
+
+    #[error("failed to read pcap file {}: {source}", path.display())]
+    PcapRead { path: PathBuf, source: io::Error },
+
+    #[error("failed to set up standby {what}: {source}")]
+    Standby { what: String, source: io::Error },
+
+    #[error("failed to listen on control socket {}: {source}", path.display())]
+    ControlSocket { path: PathBuf, source: io::Error },
+
+    #[cfg(feature = "dbus")]
+    #[error("failed to serve org.ghaf.PacketForwarder on the system bus: {0}")]
+    DBus(zbus::Error),
+
+    #[cfg(feature = "otel")]
+    #[error("failed to set up the OTLP export: {0}")]
+    Otel(opentelemetry_otlp::ExporterBuildError),
+
+    #[error("{0} stopped unexpectedly")]
+    TaskFailed(String),
+
+    #[error("failed to listen for signals: {0}")]
+    Signal(io::Error),
+
+    #[error("invalid options: {0}")]
+    InvalidOptions(String),
+
+    #[error("the forwarder was already started")]
+    AlreadyStarted,
+}
+
+impl Error {
+    /// Exit code reported to the service manager. 2 is left to clap for
+    /// command line usage errors.
+    pub fn exit_code(&self) -> ExitCode {
+        ExitCode::from(match self {
+            Error::InterfaceNotFound { .. } => 3,
+            Error::PermissionDenied { .. } => 4,
+            Error::UnsupportedChannel(_) => 5,
+            Error::InterfaceWaitTimeout { .. } => 6,
+            Error::ConfigRead { .. } | Error::ConfigParse { .. } => 7,
+            _ => 1,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps_startup_failures_to_distinct_exit_codes() {
+        let denied = || io::Error::from(io::ErrorKind::PermissionDenied);
+        let cases = [
+            (
+                Error::InterfaceNotFound {
+                    name: "eth9".to_string(),
+                    available: "eth0, vm1".to_string(),
+                },
+                3,
+            ),
+            (
+                Error::PermissionDenied {
+                    iface: "eth0".to_string(),
+                    source: denied(),
+                },
+                4,
+            ),
+            (Error::UnsupportedChannel("eth0".to_string()), 5),
+            (
+                Error::InterfaceWaitTimeout {
+                    names: "vm1".to_string(),
+                    timeout: Duration::from_secs(30),
+                },
+                6,
+            ),
+            (
+                Error::ConfigRead {
+                    path: PathBuf::from("/etc/nw-pckt-fwd.toml"),
+                    source: denied(),
+                },
+                7,
+            ),
+            (Error::TaskFailed("capture on eth0".to_string()), 1),
+        ];
+        for (error, code) in cases {
+            assert_eq!(error.exit_code(), ExitCode::from(code), "{}", error);
+        }
+    }
+}
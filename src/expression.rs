@@ -0,0 +1,612 @@
+//! Filter expressions in the style of BPF capture filters, e.g.
+//! `udp and (port 1900 or port 5353) and src net 192.168.100.0/24`.
+
+use crate::allowlist::IpNetwork;
+use crate::filter::{Decision, Filter, IpHeader, PacketContext};
+use crate::pair::Direction;
+use pnet::packet::Packet;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+use tracing::debug;
+
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_ARP: u16 = 0x0806;
+const ETHERTYPE_IPV6: u16 = 0x86dd;
+
+/// Characters that end a word and start an operator of their own
+const OPERATOR_CHARS: &[char] = &['(', ')', '!', '<', '>', '=', '&', '|'];
+
+/// Address or port of the source, the destination or either
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Side {
+    Source,
+    Destination,
+    Either,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Comparison {
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+    Equal,
+    NotEqual,
+}
+
+impl Comparison {
+    fn holds(self, value: usize, bound: usize) -> bool {
+        match self {
+            Comparison::Less => value < bound,
+            Comparison::LessOrEqual => value <= bound,
+            Comparison::Greater => value > bound,
+            Comparison::GreaterOrEqual => value >= bound,
+            Comparison::Equal => value == bound,
+            Comparison::NotEqual => value != bound,
+        }
+    }
+}
+
+/// Condition on one field of a frame
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Test {
+    EtherType(u16),
+    /// VLAN tagged, with the given ID if any
+    Vlan(Option<u16>),
+    /// IPv4 protocol or IPv6 next header
+    Protocol(u8),
+    Network(Side, IpNetwork),
+    /// UDP or TCP port
+    Port(Side, u16),
+    /// Length of the whole frame
+    Length(Comparison, usize),
+}
+
+impl Test {
+    fn matches(&self, ctx: &PacketContext) -> bool {
+        match self {
+            Test::EtherType(ethertype) => ctx.ethertype().0 == *ethertype,
+            Test::Vlan(id) => ctx.vlan.is_some_and(|vlan| id.is_none_or(|id| vlan == id)),
+            Test::Protocol(protocol) => match &ctx.ip {
+                Some(IpHeader::V4(ip)) => ip.get_next_level_protocol().0 == *protocol,
+                Some(IpHeader::V6(ip)) => ip.get_next_header().0 == *protocol,
+                None => false,
+            },
+            Test::Network(side, network) => {
+                let (Some(source), Some(destination)) = (ctx.source_ip(), ctx.destination_ip())
+                else {
+                    return false;
+                };
+                side.either(network.contains(source), network.contains(destination))
+            }
+            Test::Port(side, port) => {
+                let ports = match (ctx.udp(), ctx.tcp()) {
+                    (Some(udp), _) => (udp.get_source(), udp.get_destination()),
+                    (_, Some(tcp)) => (tcp.get_source(), tcp.get_destination()),
+                    _ => return false,
+                };
+                side.either(ports.0 == *port, ports.1 == *port)
+            }
+            Test::Length(comparison, bound) => {
+                comparison.holds(ctx.ethernet.packet().len(), *bound)
+            }
+        }
+    }
+}
+
+impl Side {
+    fn either(self, source: bool, destination: bool) -> bool {
+        match self {
+            Side::Source => source,
+            Side::Destination => destination,
+            Side::Either => source || destination,
+        }
+    }
+}
+
+/// Predicate tree of an expression
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Node {
+    And(Box<Node>, Box<Node>),
+    Or(Box<Node>, Box<Node>),
+    Not(Box<Node>),
+    Test(Test),
+}
+
+impl Node {
+    fn matches(&self, ctx: &PacketContext) -> bool {
+        match self {
+            Node::And(left, right) => left.matches(ctx) && right.matches(ctx),
+            Node::Or(left, right) => left.matches(ctx) || right.matches(ctx),
+            Node::Not(node) => !node.matches(ctx),
+            Node::Test(test) => test.matches(ctx),
+        }
+    }
+}
+
+/// Word or operator of an expression, with its byte offset
+#[derive(Debug, Clone, Copy)]
+struct Token<'a> {
+    text: &'a str,
+    at: usize,
+}
+
+fn tokenize(text: &str, offset: usize) -> Vec<Token<'_>> {
+    let mut tokens = Vec::new();
+    let mut rest = text.char_indices().peekable();
+    while let Some((at, c)) = rest.next() {
+        if c.is_whitespace() {
+            continue;
+        }
+        let end = if OPERATOR_CHARS.contains(&c) {
+            // Two character operators: <=, >=, ==, !=, && and ||
+            let pair = matches!(
+                (c, rest.peek().map(|(_, next)| *next)),
+                ('<' | '>' | '=' | '!', Some('=')) | ('&', Some('&')) | ('|', Some('|'))
+            );
+            if pair {
+                rest.next();
+            }
+            at + if pair { 2 } else { 1 }
+        } else {
+            while rest
+                .next_if(|(_, next)| !next.is_whitespace() && !OPERATOR_CHARS.contains(next))
+                .is_some()
+            {}
+            rest.peek().map_or(text.len(), |(end, _)| *end)
+        };
+        tokens.push(Token {
+            text: &text[at..end],
+            at: offset + at,
+        });
+    }
+    tokens
+}
+
+/// Message pointing at byte `at` of `text` with a caret
+fn error_at(text: &str, at: usize, message: String) -> String {
+    let column = text[..at].chars().count();
+    format!("{}\n  {}\n  {}^", message, text, " ".repeat(column))
+}
+
+/// Recursive descent over the tokens: `or` binds loosest, then `and`, then
+/// `not`
+struct Parser<'a> {
+    tokens: Vec<Token<'a>>,
+    next: usize,
+    /// Offset of the end of the expression, for errors at the end
+    end: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<&'a str> {
+        self.tokens.get(self.next).map(|token| token.text)
+    }
+
+    fn bump(&mut self) -> Option<Token<'a>> {
+        let token = self.tokens.get(self.next).copied();
+        self.next += token.is_some() as usize;
+        token
+    }
+
+    /// Offset of the next token, or of the end
+    fn here(&self) -> usize {
+        self.tokens
+            .get(self.next)
+            .map_or(self.end, |token| token.at)
+    }
+
+    fn fail<T>(&self, message: String) -> Result<T, (usize, String)> {
+        Err((self.here(), message))
+    }
+
+    fn or(&mut self) -> Result<Node, (usize, String)> {
+        let mut node = self.and()?;
+        while matches!(self.peek(), Some("or" | "||")) {
+            self.bump();
+            node = Node::Or(Box::new(node), Box::new(self.and()?));
+        }
+        Ok(node)
+    }
+
+    fn and(&mut self) -> Result<Node, (usize, String)> {
+        let mut node = self.not()?;
+        while matches!(self.peek(), Some("and" | "&&")) {
+            self.bump();
+            node = Node::And(Box::new(node), Box::new(self.not()?));
+        }
+        Ok(node)
+    }
+
+    fn not(&mut self) -> Result<Node, (usize, String)> {
+        match self.peek() {
+            Some("not" | "!") => {
+                self.bump();
+                Ok(Node::Not(Box::new(self.not()?)))
+            }
+            Some("(") => {
+                let open = self.bump().expect("token was peeked");
+                let node = self.or()?;
+                match self.bump() {
+                    Some(token) if token.text == ")" => Ok(node),
+                    Some(token) => Err((token.at, format!("expected ')', found '{}'", token.text))),
+                    None => Err((open.at, "unmatched '('".to_string())),
+                }
+            }
+            _ => self.test().map(Node::Test),
+        }
+    }
+
+    /// Number after `word`, parsed by `parse`
+    fn number<T>(
+        &mut self,
+        word: &str,
+        what: &str,
+        parse: impl Fn(&str) -> Option<T>,
+    ) -> Result<T, (usize, String)> {
+        match self.peek().and_then(&parse) {
+            Some(value) => {
+                self.bump();
+                Ok(value)
+            }
+            None => self.fail(format!("expected {} after '{}'", what, word)),
+        }
+    }
+
+    fn test(&mut self) -> Result<Test, (usize, String)> {
+        let Some(token) = self.bump() else {
+            return self.fail("expected a condition".to_string());
+        };
+        let side = match token.text {
+            "src" => Some(Side::Source),
+            "dst" => Some(Side::Destination),
+            _ => None,
+        };
+        let word = match side {
+            Some(_) if matches!(self.peek(), Some("host" | "net" | "port")) => {
+                self.bump().expect("token was peeked")
+            }
+            Some(_) => {
+                return self.fail(format!("expected host, net or port after '{}'", token.text))
+            }
+            None => token,
+        };
+        let side = side.unwrap_or(Side::Either);
+        let test = match word.text {
+            "ip" => Test::EtherType(ETHERTYPE_IPV4),
+            "ip6" => Test::EtherType(ETHERTYPE_IPV6),
+            "arp" => Test::EtherType(ETHERTYPE_ARP),
+            "ether" => {
+                if self.peek() != Some("proto") {
+                    return self.fail("expected 'proto' after 'ether'".to_string());
+                }
+                self.bump();
+                Test::EtherType(self.number("ether proto", "an ethertype", parse_u16)?)
+            }
+            "vlan" => Test::Vlan(match self.peek().and_then(parse_u16) {
+                Some(id) if id < 4096 => {
+                    self.bump();
+                    Some(id)
+                }
+                Some(_) => return self.fail("VLAN IDs go up to 4095".to_string()),
+                None => None,
+            }),
+            "proto" => Test::Protocol(self.number("proto", "a protocol", |word| {
+                protocol(word).or_else(|| parse_u16(word)?.try_into().ok())
+            })?),
+            "host" => Test::Network(
+                side,
+                self.number("host", "an IP address", |word| {
+                    // A bare address parses as a network of one host
+                    (!word.contains('/')).then(|| word.parse().ok()).flatten()
+                })?,
+            ),
+            "net" => {
+                let network = match self.bump() {
+                    Some(token) => token
+                        .text
+                        .parse()
+                        .map_err(|error: String| (token.at, error))?,
+                    None => return self.fail("expected a network after 'net'".to_string()),
+                };
+                Test::Network(side, network)
+            }
+            "port" => Test::Port(
+                side,
+                self.number("port", "a port number", |word| {
+                    word.parse().ok().filter(|port| *port != 0)
+                })?,
+            ),
+            "len" => {
+                let comparison = match self.peek() {
+                    Some("<") => Comparison::Less,
+                    Some("<=") => Comparison::LessOrEqual,
+                    Some(">") => Comparison::Greater,
+                    Some(">=") => Comparison::GreaterOrEqual,
+                    Some("=" | "==") => Comparison::Equal,
+                    Some("!=") => Comparison::NotEqual,
+                    _ => {
+                        return self.fail("expected <, <=, >, >=, = or != after 'len'".to_string())
+                    }
+                };
+                self.bump();
+                Test::Length(
+                    comparison,
+                    self.number("len", "a length", |word| word.parse().ok())?,
+                )
+            }
+            "less" => Test::Length(
+                Comparison::LessOrEqual,
+                self.number("less", "a length", |word| word.parse().ok())?,
+            ),
+            "greater" => Test::Length(
+                Comparison::GreaterOrEqual,
+                self.number("greater", "a length", |word| word.parse().ok())?,
+            ),
+            other => match protocol(other) {
+                Some(protocol) => Test::Protocol(protocol),
+                None => return Err((word.at, format!("unknown word '{}'", other))),
+            },
+        };
+        Ok(test)
+    }
+}
+
+/// Number of a protocol name
+fn protocol(name: &str) -> Option<u8> {
+    match name {
+        "icmp" => Some(1),
+        "igmp" => Some(2),
+        "tcp" => Some(6),
+        "udp" => Some(17),
+        "icmp6" => Some(58),
+        _ => None,
+    }
+}
+
+/// Decimal or `0x` prefixed hexadecimal number
+fn parse_u16(word: &str) -> Option<u16> {
+    match word.strip_prefix("0x") {
+        Some(hex) => u16::from_str_radix(hex, 16).ok(),
+        None => word.parse().ok(),
+    }
+}
+
+/// Filter expression, optionally limited to one direction with an `in:`
+/// (external to internal) or `out:` (internal to external) prefix.
+/// Conditions are `ip`, `ip6`, `arp`, `ether proto N`, `vlan [ID]`, `udp`,
+/// `tcp`, `icmp`, `icmp6`, `igmp`, `proto N`, `[src|dst] host ADDRESS`,
+/// `[src|dst] net CIDR`, `[src|dst] port N`, `len OP N`, `less N` and
+/// `greater N`, combined with `and`, `or`, `not` and parentheses.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Expression {
+    /// `None` matches both directions, including bridged traffic
+    direction: Option<Direction>,
+    root: Node,
+    text: String,
+}
+
+impl Expression {
+    fn matches(&self, ctx: &PacketContext) -> bool {
+        self.root.matches(ctx)
+    }
+
+    fn applies_to(&self, direction: Direction) -> bool {
+        self.direction.is_none_or(|own| own == direction)
+    }
+}
+
+impl FromStr for Expression {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let text = value.trim();
+        let (direction, body, offset) = match text.split_once(':') {
+            Some(("in", body)) => (Some(Direction::Inbound), body, 3),
+            Some(("out", body)) => (Some(Direction::Outbound), body, 4),
+            _ => (None, text, 0),
+        };
+        let mut parser = Parser {
+            tokens: tokenize(body, offset),
+            next: 0,
+            end: text.len(),
+        };
+        let root = parser.or().and_then(|root| match parser.bump() {
+            None => Ok(root),
+            Some(token) if token.text == ")" => Err((token.at, "unexpected ')'".to_string())),
+            Some(token) => Err((
+                token.at,
+                format!("expected 'and' or 'or', found '{}'", token.text),
+            )),
+        });
+        match root {
+            Ok(root) => Ok(Expression {
+                direction,
+                root,
+                text: text.to_string(),
+            }),
+            Err((at, message)) => Err(error_at(text, at, message)),
+        }
+    }
+}
+
+impl TryFrom<String> for Expression {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<Expression> for String {
+    fn from(expression: Expression) -> Self {
+        expression.text
+    }
+}
+
+impl fmt::Display for Expression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.text)
+    }
+}
+
+/// Forwards the frames matching any expression for their direction and
+/// drops the rest. Directions without an expression are left to the
+/// following filters.
+pub struct ExpressionFilter {
+    expressions: Vec<Expression>,
+}
+
+impl ExpressionFilter {
+    pub fn new(expressions: Vec<Expression>) -> Self {
+        ExpressionFilter { expressions }
+    }
+}
+
+impl Filter for ExpressionFilter {
+    fn name(&self) -> &str {
+        "expressions"
+    }
+
+    fn evaluate(&self, ctx: &PacketContext) -> Decision {
+        let mut applicable = self
+            .expressions
+            .iter()
+            .filter(|expression| expression.applies_to(ctx.direction))
+            .peekable();
+        if applicable.peek().is_none() {
+            return Decision::Continue;
+        }
+        match applicable.find(|expression| expression.matches(ctx)) {
+            Some(expression) => {
+                debug!("Filter expression '{}' matched", expression);
+                Decision::Forward
+            }
+            None => Decision::Drop,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{self, multicast_mac, HOST_MAC};
+    use std::net::Ipv4Addr;
+
+    /// Ethernet/IPv4/UDP frame between the given addresses and ports
+    fn udp_frame(from: ([u8; 4], u16), to: ([u8; 4], u16)) -> Vec<u8> {
+        let to_mac = multicast_mac(Ipv4Addr::from(to.0).into());
+        let (from_ip, to_ip) = (Ipv4Addr::from(from.0), Ipv4Addr::from(to.0));
+        testutil::udp_frame(HOST_MAC, to_mac, from_ip, to_ip, from.1, to.1, &[0; 16])
+    }
+
+    fn matches(expression: &str, frame: &[u8], direction: Direction) -> bool {
+        let expression: Expression = expression.parse().unwrap();
+        let ctx = PacketContext::parse("eth0", direction, frame).unwrap();
+        expression.applies_to(direction) && expression.matches(&ctx)
+    }
+
+    #[test]
+    fn binds_not_then_and_then_or() {
+        let parse = |text: &str| text.parse::<Expression>().unwrap().root;
+        let and = |a, b| Node::And(Box::new(a), Box::new(b));
+        let or = |a, b| Node::Or(Box::new(a), Box::new(b));
+        let not = |a| Node::Not(Box::new(a));
+
+        assert_eq!(
+            parse("udp or tcp and port 80"),
+            or(parse("udp"), and(parse("tcp"), parse("port 80")))
+        );
+        assert_eq!(
+            parse("(udp or tcp) and port 80"),
+            and(or(parse("udp"), parse("tcp")), parse("port 80"))
+        );
+        assert_eq!(
+            parse("not udp and tcp"),
+            and(not(parse("udp")), parse("tcp"))
+        );
+        assert_eq!(parse("!!udp"), not(not(parse("udp"))));
+        assert_eq!(parse("udp && tcp || ip"), parse("(udp and tcp) or ip"));
+        assert_eq!(
+            parse("ip or ip6 or arp"),
+            or(or(parse("ip"), parse("ip6")), parse("arp"))
+        );
+        assert_eq!(parse("len<=100"), parse("len <= 100"));
+        assert_eq!(parse("proto 17"), parse("udp"));
+        assert_eq!(parse("ether proto 0x0800"), parse("ip"));
+    }
+
+    #[test]
+    fn matches_frame_fields() {
+        let frame = udp_frame(([192, 168, 100, 5], 50000), ([239, 255, 255, 250], 1900));
+        let outbound = Direction::Outbound;
+        assert!(matches(
+            "udp and (port 1900 or port 5353) and src net 192.168.100.0/24",
+            &frame,
+            outbound
+        ));
+        assert!(matches(
+            "dst port 1900 and src port 50000",
+            &frame,
+            outbound
+        ));
+        assert!(!matches("src port 1900", &frame, outbound));
+        assert!(matches("dst host 239.255.255.250", &frame, outbound));
+        assert!(!matches("host fd00::1 or net 10.0.0.0/8", &frame, outbound));
+        assert!(matches("ip and not ip6 and not tcp", &frame, outbound));
+        assert!(matches(
+            "len = 58 and less 58 and greater 58",
+            &frame,
+            outbound
+        ));
+        assert!(!matches("len > 58 or vlan", &frame, outbound));
+
+        assert!(matches("out: udp", &frame, outbound));
+        assert!(!matches("in: udp", &frame, outbound));
+        assert!(matches("in: udp", &frame, Direction::Inbound));
+
+        // Received on VLAN 100, with the tag taken off
+        let expression: Expression = "vlan and vlan 100 and port 1900".parse().unwrap();
+        let mut ctx = PacketContext::parse("eth0", outbound, &frame).unwrap();
+        assert!(!expression.matches(&ctx));
+        ctx.vlan = Some(100);
+        assert!(expression.matches(&ctx));
+        assert!(!"vlan 101".parse::<Expression>().unwrap().matches(&ctx));
+    }
+
+    #[test]
+    fn reports_errors_with_a_caret() {
+        let error = |text: &str| text.parse::<Expression>().unwrap_err();
+        assert_eq!(
+            error("udp and port x"),
+            "expected a port number after 'port'\n  udp and port x\n               ^"
+        );
+        assert_eq!(
+            error("udp and"),
+            "expected a condition\n  udp and\n         ^"
+        );
+        assert_eq!(
+            error("out: (udp or tcp"),
+            "unmatched '('\n  out: (udp or tcp\n       ^"
+        );
+        assert_eq!(error("udp)"), "unexpected ')'\n  udp)\n     ^");
+        assert_eq!(
+            error("udp tcp"),
+            "expected 'and' or 'or', found 'tcp'\n  udp tcp\n      ^"
+        );
+        assert!(error("").starts_with("expected a condition"));
+        assert!(error("src udp").starts_with("expected host, net or port after 'src'"));
+        assert!(error("net 10.0.0.0/33").starts_with("'33' is not a prefix length"));
+        assert!(error("host 10.0.0.0/8").starts_with("expected an IP address"));
+        assert!(error("port 0").starts_with("expected a port number"));
+        assert!(error("vlan 4096").starts_with("VLAN IDs go up to 4095"));
+        assert!(error("len 5").starts_with("expected <, <="));
+        assert!(error("sctp").starts_with("unknown word 'sctp'"));
+        assert!(error("(udp))").starts_with("unexpected ')'"));
+
+        let expression: Expression = " in: udp and port 5353 ".parse().unwrap();
+        assert_eq!(expression.to_string(), "in: udp and port 5353");
+    }
+}
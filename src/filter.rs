@@ -0,0 +1,591 @@
+//! Packet filter chain deciding which frames cross between the interfaces.
+
+use crate::checksum::{self, Verdict};
+use crate::logging::{debug_repeated, RepeatedMessages};
+use crate::pair::Direction;
+use arc_swap::ArcSwap;
+use pnet::packet::ethernet::{EtherType, EtherTypes, EthernetPacket};
+use pnet::packet::icmpv6::Icmpv6Packet;
+use pnet::packet::ip::{IpNextHeaderProtocol, IpNextHeaderProtocols};
+use pnet::packet::ipv4::{Ipv4Flags, Ipv4Packet};
+use pnet::packet::ipv6::Ipv6Packet;
+use pnet::packet::tcp::TcpPacket;
+use pnet::packet::udp::UdpPacket;
+use pnet::packet::Packet;
+use std::collections::HashSet;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tracing::debug;
+
+pub const SSDP_PORT: u16 = 1900;
+pub const MDNS_PORT: u16 = 5353;
+/// Hop limit of mDNS packets (RFC 6762 section 11)
+pub const MDNS_HOP_LIMIT: u8 = 255;
+pub const WSD_PORT: u16 = 3702;
+pub const LLMNR_PORT: u16 = 5355;
+pub const COAP_PORT: u16 = 5683;
+pub const MDNS_IPV4_GROUP: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+pub const MDNS_IPV6_GROUP: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 0xfb);
+
+const ETHERNET_HEADER_LEN: usize = 14;
+const IPV6_HEADER_LEN: usize = 40;
+
+/// Outcome of evaluating a single filter
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Decision {
+    /// Forward the frame, skipping the remaining filters
+    Forward,
+    /// Drop the frame, skipping the remaining filters
+    Drop,
+    /// No opinion, let the next filter decide
+    Continue,
+}
+
+/// Network layer view of a frame
+pub enum IpHeader<'a> {
+    V4(Ipv4Packet<'a>),
+    V6(Ipv6Packet<'a>),
+}
+
+impl IpHeader<'_> {
+    /// Whether the packet is a fragment of a larger datagram: an IPv4
+    /// packet with the more fragments flag or an offset, or an IPv6 one
+    /// whose fragment header directly follows the fixed header
+    pub fn is_fragment(&self) -> bool {
+        match self {
+            IpHeader::V4(ip) => {
+                ip.get_flags() & Ipv4Flags::MoreFragments != 0 || ip.get_fragment_offset() != 0
+            }
+            IpHeader::V6(ip) => ip.get_next_header() == IpNextHeaderProtocols::Ipv6Frag,
+        }
+    }
+}
+
+/// Transport layer view of a frame
+pub enum Transport<'a> {
+    Udp(UdpPacket<'a>),
+    Tcp(TcpPacket<'a>),
+    Icmpv6(Icmpv6Packet<'a>),
+}
+
+/// Parsed views of a received frame handed to every filter
+pub struct PacketContext<'a> {
+    /// Name of the interface the frame was received on
+    pub ingress: &'a str,
+    /// Direction of the forwarding path evaluating the frame
+    pub direction: Direction,
+    pub ethernet: EthernetPacket<'a>,
+    /// VLAN ID of the outer tag the frame was received with, which is taken
+    /// off ahead of parsing
+    pub vlan: Option<u16>,
+    pub ip: Option<IpHeader<'a>>,
+    pub transport: Option<Transport<'a>>,
+}
+
+impl<'a> PacketContext<'a> {
+    /// Parses as many layers of `frame` as are present and well-formed.
+    /// Returns `None` only if the Ethernet header itself is truncated.
+    pub fn parse(ingress: &'a str, direction: Direction, frame: &'a [u8]) -> Option<Self> {
+        let ethernet = EthernetPacket::new(frame)?;
+        let l3 = &frame[ETHERNET_HEADER_LEN..];
+        let (ip, l4) = match parse_ip(ethernet.get_ethertype(), l3) {
+            Some((ip, l4)) => (Some(ip), l4),
+            None => (None, None),
+        };
+        let transport = l4.and_then(|(protocol, payload)| match protocol {
+            IpNextHeaderProtocols::Udp => UdpPacket::new(payload).map(Transport::Udp),
+            IpNextHeaderProtocols::Tcp => TcpPacket::new(payload).map(Transport::Tcp),
+            IpNextHeaderProtocols::Icmpv6 => Icmpv6Packet::new(payload).map(Transport::Icmpv6),
+            _ => None,
+        });
+        Some(PacketContext {
+            ingress,
+            direction,
+            ethernet,
+            vlan: None,
+            ip,
+            transport,
+        })
+    }
+
+    pub fn ethertype(&self) -> EtherType {
+        self.ethernet.get_ethertype()
+    }
+
+    pub fn source_ip(&self) -> Option<IpAddr> {
+        match &self.ip {
+            Some(IpHeader::V4(ip)) => Some(ip.get_source().into()),
+            Some(IpHeader::V6(ip)) => Some(ip.get_source().into()),
+            None => None,
+        }
+    }
+
+    pub fn destination_ip(&self) -> Option<IpAddr> {
+        match &self.ip {
+            Some(IpHeader::V4(ip)) => Some(ip.get_destination().into()),
+            Some(IpHeader::V6(ip)) => Some(ip.get_destination().into()),
+            None => None,
+        }
+    }
+
+    pub fn udp(&self) -> Option<&UdpPacket<'a>> {
+        match &self.transport {
+            Some(Transport::Udp(udp)) => Some(udp),
+            _ => None,
+        }
+    }
+
+    pub fn tcp(&self) -> Option<&TcpPacket<'a>> {
+        match &self.transport {
+            Some(Transport::Tcp(tcp)) => Some(tcp),
+            _ => None,
+        }
+    }
+
+    /// Source and destination address and port of a UDP or TCP frame
+    pub fn endpoints(&self) -> Option<(SocketAddr, SocketAddr)> {
+        let (src, dst) = match &self.transport {
+            Some(Transport::Udp(udp)) => (udp.get_source(), udp.get_destination()),
+            Some(Transport::Tcp(tcp)) => (tcp.get_source(), tcp.get_destination()),
+            _ => return None,
+        };
+        Some((
+            SocketAddr::new(self.source_ip()?, src),
+            SocketAddr::new(self.destination_ip()?, dst),
+        ))
+    }
+
+    pub fn icmpv6(&self) -> Option<&Icmpv6Packet<'a>> {
+        match &self.transport {
+            Some(Transport::Icmpv6(icmpv6)) => Some(icmpv6),
+            _ => None,
+        }
+    }
+}
+
+type L4<'a> = (IpNextHeaderProtocol, &'a [u8]);
+
+/// IP header of an Ethernet payload of `ethertype`, with the upper-layer
+/// protocol and its payload unless the headers are truncated or the packet
+/// is a fragment
+pub fn parse_ip(ethertype: EtherType, l3: &[u8]) -> Option<(IpHeader<'_>, Option<L4<'_>>)> {
+    match ethertype {
+        EtherTypes::Ipv4 => {
+            let ip = Ipv4Packet::new(l3)?;
+            let start = usize::from(ip.get_header_length()) * 4;
+            let end = usize::from(ip.get_total_length()).min(l3.len());
+            // More fragments flag or a fragment offset: no fragment carries
+            // the whole datagram its transport header describes
+            let fragmented = u16::from_be_bytes([l3[6], l3[7]]) & 0x3fff != 0;
+            let l4 = l3
+                .get(start..end.max(start))
+                .filter(|_| !fragmented)
+                .map(|payload| (ip.get_next_level_protocol(), payload));
+            Some((IpHeader::V4(ip), l4))
+        }
+        EtherTypes::Ipv6 => {
+            let ip = Ipv6Packet::new(l3)?;
+            let end = (IPV6_HEADER_LEN + usize::from(ip.get_payload_length())).min(l3.len());
+            let l4 = skip_ipv6_extensions(ip.get_next_header(), &l3[IPV6_HEADER_LEN..end]);
+            Some((IpHeader::V6(ip), l4))
+        }
+        _ => None,
+    }
+}
+
+/// Walks the IPv6 extension header chain and returns the upper-layer protocol
+/// together with its payload. Returns `None` for truncated chains and for
+/// non-first fragments, which carry no transport header.
+fn skip_ipv6_extensions(
+    mut next: IpNextHeaderProtocol,
+    mut payload: &[u8],
+) -> Option<(IpNextHeaderProtocol, &[u8])> {
+    loop {
+        let len = match next {
+            IpNextHeaderProtocols::Hopopt
+            | IpNextHeaderProtocols::Ipv6Route
+            | IpNextHeaderProtocols::Ipv6Opts => (usize::from(*payload.get(1)?) + 1) * 8,
+            IpNextHeaderProtocols::Ipv6Frag => {
+                let offset = u16::from_be_bytes([*payload.get(2)?, *payload.get(3)?]) >> 3;
+                if offset != 0 {
+                    return None;
+                }
+                8
+            }
+            IpNextHeaderProtocols::Ah => (usize::from(*payload.get(1)?) + 2) * 4,
+            IpNextHeaderProtocols::Ipv6NoNxt => return None,
+            _ => return Some((next, payload)),
+        };
+        next = IpNextHeaderProtocol::new(*payload.first()?);
+        payload = payload.get(len..)?;
+    }
+}
+
+/// A single stage of the filter chain
+pub trait Filter: Send + Sync {
+    /// Short name used in log messages
+    fn name(&self) -> &str;
+
+    fn evaluate(&self, ctx: &PacketContext) -> Decision;
+
+    /// Learned entries or counters, one line each, for filters that keep state
+    fn state(&self) -> Option<Vec<String>> {
+        None
+    }
+
+    /// Called once a frame the chain accepted is queued for sending, for
+    /// filters that learn from what was forwarded rather than from what
+    /// they saw
+    fn forwarded(&self, _ctx: &PacketContext) {}
+}
+
+/// Ordered list of filters; the first non-`Continue` decision wins and
+/// frames no filter claims are dropped.
+#[derive(Default)]
+pub struct FilterChain {
+    filters: Vec<Box<dyn Filter>>,
+}
+
+impl FilterChain {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, filter: impl Filter + 'static) {
+        self.filters.push(Box::new(filter));
+    }
+
+    /// Returns the name of the filter that forwards the frame, otherwise
+    /// the name of the filter that dropped it or `no-match` if none claimed
+    /// it
+    pub fn evaluate(&self, ctx: &PacketContext) -> Result<&str, &str> {
+        for filter in &self.filters {
+            match filter.evaluate(ctx) {
+                Decision::Forward => return Ok(filter.name()),
+                Decision::Drop => return Err(filter.name()),
+                Decision::Continue => {}
+            }
+        }
+        Err("no-match")
+    }
+
+    /// Tells every filter that a frame the chain accepted was forwarded
+    pub fn forwarded(&self, ctx: &PacketContext) {
+        for filter in &self.filters {
+            filter.forwarded(ctx);
+        }
+    }
+
+    pub fn names(&self) -> Vec<&str> {
+        self.filters.iter().map(|f| f.name()).collect()
+    }
+
+    /// State of every filter that keeps any, by filter name
+    pub fn state(&self) -> Vec<(&str, Vec<String>)> {
+        self.filters
+            .iter()
+            .filter_map(|f| Some((f.name(), f.state()?)))
+            .collect()
+    }
+}
+
+/// Filter chain that can be replaced while capture loops are using it
+pub type SharedFilterChain = Arc<ArcSwap<FilterChain>>;
+
+fn protocol_name(port: u16) -> &'static str {
+    match port {
+        SSDP_PORT => "SSDP",
+        MDNS_PORT => "mDNS",
+        WSD_PORT => "WS-Discovery",
+        COAP_PORT => "CoAP",
+        _ => "UDP",
+    }
+}
+
+/// Drops all IPv6 frames
+pub struct Ipv4OnlyFilter;
+
+impl Filter for Ipv4OnlyFilter {
+    fn name(&self) -> &str {
+        "ipv4-only"
+    }
+
+    fn evaluate(&self, ctx: &PacketContext) -> Decision {
+        if ctx.ethertype() == EtherTypes::Ipv6 {
+            Decision::Drop
+        } else {
+            Decision::Continue
+        }
+    }
+}
+
+/// Drops frames with a wrong IPv4 header or UDP/TCP checksum, letting
+/// through those whose sender left the checksum to offload
+pub struct ChecksumFilter;
+
+impl Filter for ChecksumFilter {
+    fn name(&self) -> &str {
+        "checksum"
+    }
+
+    fn evaluate(&self, ctx: &PacketContext) -> Decision {
+        match checksum::verify(ctx.ethernet.packet()) {
+            Verdict::Invalid => Decision::Drop,
+            Verdict::Valid | Verdict::Offloaded => Decision::Continue,
+        }
+    }
+}
+
+/// Forwards UDP datagrams whose source or destination port is allowed
+pub struct UdpPortFilter {
+    ports: HashSet<u16>,
+    log: RepeatedMessages,
+}
+
+impl UdpPortFilter {
+    pub fn new(ports: HashSet<u16>) -> Self {
+        UdpPortFilter {
+            ports,
+            log: RepeatedMessages::default(),
+        }
+    }
+}
+
+impl Filter for UdpPortFilter {
+    fn name(&self) -> &str {
+        "udp-ports"
+    }
+
+    fn evaluate(&self, ctx: &PacketContext) -> Decision {
+        let Some(udp) = ctx.udp() else {
+            return Decision::Continue;
+        };
+        let (src, dst) = (udp.get_source(), udp.get_destination());
+        let matched = [dst, src].into_iter().find(|p| self.ports.contains(p));
+        match matched {
+            // mDNS responders may answer with unicast (RFC 6762 section 5.4),
+            // so anything from or to 5353 is eligible, not only the group.
+            Some(MDNS_PORT) => match ctx.destination_ip() {
+                Some(ip) if ip == MDNS_IPV4_GROUP || ip == MDNS_IPV6_GROUP => {
+                    debug!("mDNS multicast packet forwarded")
+                }
+                _ => debug!("mDNS unicast packet forwarded"),
+            },
+            Some(port) => debug!("{} packet forwarded (port {})", protocol_name(port), port),
+            None => {
+                let (src, dst) = ctx.endpoints().expect("UDP frames have addresses");
+                debug_repeated!(
+                    self.log,
+                    "Non-matching UDP packets dropped",
+                    "Non-matching UDP packet dropped ({} -> {})",
+                    src,
+                    dst
+                );
+                return Decision::Drop;
+            }
+        }
+        Decision::Forward
+    }
+}
+
+/// Forwards TCP segments on allowed ports regardless of flags, so handshake,
+/// data and teardown of a session all cross the boundary.
+pub struct TcpPortFilter {
+    ports: HashSet<u16>,
+    forwarded: AtomicU64,
+    dropped: AtomicU64,
+    log: RepeatedMessages,
+}
+
+impl TcpPortFilter {
+    pub fn new(ports: HashSet<u16>) -> Self {
+        TcpPortFilter {
+            ports,
+            forwarded: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+            log: RepeatedMessages::default(),
+        }
+    }
+}
+
+impl Filter for TcpPortFilter {
+    fn name(&self) -> &str {
+        "tcp-ports"
+    }
+
+    fn evaluate(&self, ctx: &PacketContext) -> Decision {
+        let Some(tcp) = ctx.tcp() else {
+            return Decision::Continue;
+        };
+        let (src, dst) = (tcp.get_source(), tcp.get_destination());
+        if self.ports.contains(&dst) || self.ports.contains(&src) {
+            let forwarded = self.forwarded.fetch_add(1, Ordering::Relaxed) + 1;
+            debug!(
+                "TCP packet forwarded ({} -> {}, flags {:#04x}), tcp forwarded={} dropped={}",
+                src,
+                dst,
+                tcp.get_flags(),
+                forwarded,
+                self.dropped.load(Ordering::Relaxed)
+            );
+            Decision::Forward
+        } else {
+            let dropped = self.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+            debug_repeated!(
+                self.log,
+                "Non-matching TCP packets dropped",
+                "Non-matching TCP packet dropped ({} -> {}), tcp forwarded={} dropped={}",
+                src,
+                dst,
+                self.forwarded.load(Ordering::Relaxed),
+                dropped
+            );
+            Decision::Drop
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::*;
+    use crate::vlan;
+    use pnet::util::MacAddr;
+
+    fn layers(frame: &[u8]) -> Option<(bool, bool)> {
+        PacketContext::parse("eth0", Direction::Outbound, frame)
+            .map(|ctx| (ctx.ip.is_some(), ctx.transport.is_some()))
+    }
+
+    fn forwarded(chain: &FilterChain, frame: &[u8]) -> bool {
+        PacketContext::parse("eth0", Direction::Outbound, frame)
+            .is_some_and(|ctx| chain.evaluate(&ctx).is_ok())
+    }
+
+    #[test]
+    fn parses_golden_frames_layer_by_layer() {
+        let search = ssdp_search_frame("ssdp:all");
+        let ctx = PacketContext::parse("eth0", Direction::Outbound, &search).unwrap();
+        assert_eq!(ctx.source_ip(), Some(HOST_IP.into()));
+        assert_eq!(ctx.udp().unwrap().get_destination(), SSDP_PORT);
+
+        let mdns = udp_frame(
+            HOST_MAC,
+            multicast_mac(MDNS_IPV6_GROUP.into()),
+            "fe80::1".parse::<Ipv6Addr>().unwrap(),
+            MDNS_IPV6_GROUP,
+            MDNS_PORT,
+            MDNS_PORT,
+            &mdns_query("_googlecast._tcp.local", DNS_TYPE_PTR),
+        );
+        // A layer is only there once its whole header is
+        let expected = [
+            None,
+            Some((false, false)),
+            Some((false, false)),
+            Some((true, false)),
+            Some((true, false)),
+            Some((true, true)),
+        ];
+        for frame in [search, mdns] {
+            assert_eq!(layers(&frame), Some((true, true)));
+            let truncated: Vec<_> = truncations(&frame).iter().map(|f| layers(f)).collect();
+            assert_eq!(truncated, expected);
+        }
+    }
+
+    #[test]
+    fn parses_no_transport_header_in_ipv4_fragments() {
+        let search = ssdp_search_frame("ssdp:all");
+        let flags_at = super::ETHERNET_HEADER_LEN + 6;
+        // More fragments flag, fragment offset, and both
+        for flags in [[0x20, 0], [0, 0xb9], [0x20, 0xb9]] {
+            let mut fragment = search.clone();
+            fragment[flags_at..flags_at + 2].copy_from_slice(&flags);
+            assert_eq!(layers(&fragment), Some((true, false)));
+        }
+        // Don't fragment is not a fragment
+        let mut whole = search;
+        whole[flags_at] = 0x40;
+        assert_eq!(layers(&whole), Some((true, true)));
+    }
+
+    #[test]
+    fn tells_offloaded_checksums_from_broken_ones() {
+        let mut chain = FilterChain::new();
+        chain.push(ChecksumFilter);
+        chain.push(UdpPortFilter::new(HashSet::from([SSDP_PORT])));
+        let search = ssdp_search_frame("ssdp:all");
+        // As captured from a sender using checksum offload: only the
+        // pseudo-header is summed into the UDP checksum
+        let mut offloaded = search.clone();
+        let udp_len = search.len() - super::ETHERNET_HEADER_LEN - IPV4_HEADER_LEN;
+        let words = [&HOST_IP.octets()[..], &SSDP_IPV4_GROUP.octets()]
+            .concat()
+            .chunks(2)
+            .map(|word| u32::from(u16::from_be_bytes([word[0], word[1]])))
+            .sum::<u32>();
+        let sum = words + 17 + udp_len as u32;
+        let partial = ((sum & 0xffff) + (sum >> 16)) as u16;
+        let at = super::ETHERNET_HEADER_LEN + IPV4_HEADER_LEN + 6;
+        offloaded[at..at + 2].copy_from_slice(&partial.to_be_bytes());
+
+        assert_eq!(checksum::verify(&search), Verdict::Valid);
+        assert_eq!(checksum::verify(&offloaded), Verdict::Offloaded);
+        assert!(forwarded(&chain, &search));
+        assert!(forwarded(&chain, &offloaded));
+        assert!(!forwarded(&chain, &bad_udp_checksum(&search)));
+        assert!(!forwarded(&chain, &bad_ip_checksum(&search)));
+
+        assert!(!checksum::complete_offloaded(&mut search.clone()));
+        assert!(checksum::complete_offloaded(&mut offloaded));
+        assert_eq!(offloaded, search);
+    }
+
+    #[test]
+    fn forwards_golden_frames_on_allowed_ports() {
+        let mut chain = FilterChain::new();
+        chain.push(UdpPortFilter::new(HashSet::from([SSDP_PORT, MDNS_PORT])));
+        let search = ssdp_search_frame("ssdp:all");
+        let query = mdns_query_frame("_googlecast._tcp.local");
+        let other = udp_frame(
+            HOST_MAC,
+            MacAddr::broadcast(),
+            HOST_IP,
+            Ipv4Addr::BROADCAST,
+            50000,
+            5000,
+            b"hello",
+        );
+        // Unicast answer to a one-shot query from a high port
+        let answer = udp_frame(
+            HOST_MAC,
+            MacAddr::new(0x02, 0, 0, 0, 0, 2),
+            HOST_IP,
+            Ipv4Addr::new(192, 168, 100, 6),
+            MDNS_PORT,
+            50000,
+            &mdns_response("tv.local", 1, 120, &[192, 168, 100, 5]),
+        );
+        assert!(forwarded(&chain, &search));
+        assert!(forwarded(&chain, &query));
+        assert!(forwarded(&chain, &answer));
+        assert!(!forwarded(&chain, &other));
+
+        // Nothing short of a whole UDP header is claimed
+        let truncated = truncations(&search);
+        let (header_only, shorter) = truncated.split_last().unwrap();
+        assert!(shorter.iter().all(|frame| !forwarded(&chain, frame)));
+        assert!(forwarded(&chain, header_only));
+
+        // Checksums are left to the receiving host
+        assert!(forwarded(&chain, &bad_ip_checksum(&search)));
+        assert!(forwarded(&chain, &bad_udp_checksum(&query)));
+
+        // Tags have to come off ahead of the filters
+        let tagged = tagged(&search, 10);
+        assert!(!forwarded(&chain, &tagged));
+        assert!(forwarded(&chain, &vlan::untag(&tagged).0));
+    }
+}
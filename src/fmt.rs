@@ -0,0 +1,228 @@
+//! Renderings of received frames for debug logging: a tcpdump-style one-line
+//! summary and a canonical hexdump of the first bytes.
+
+use crate::filter::{parse_ip, IpHeader};
+use crate::vlan;
+use pnet::packet::ethernet::{EtherTypes, EthernetPacket};
+use pnet::packet::ip::{IpNextHeaderProtocol, IpNextHeaderProtocols};
+use pnet::packet::tcp::TcpPacket;
+use pnet::packet::udp::UdpPacket;
+use std::fmt;
+use std::net::IpAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const ETHERNET_HEADER_LEN: usize = 14;
+/// Bytes shown per line of a hexdump
+const LINE_LEN: usize = 16;
+
+/// `time interface [vlan id] source > destination: protocol, length n` for
+/// one frame, as tcpdump prints it. Addresses carry the port after a dot
+/// for UDP and TCP; frames that are not IP show their MAC addresses.
+pub struct FrameSummary<'a> {
+    pub at: SystemTime,
+    pub interface: &'a str,
+    pub frame: &'a [u8],
+}
+
+impl fmt::Display for FrameSummary<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let since = self.at.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let secs = since.as_secs() % 86_400;
+        write!(
+            f,
+            "{:02}:{:02}:{:02}.{:06} {}",
+            secs / 3600,
+            secs % 3600 / 60,
+            secs % 60,
+            since.subsec_micros(),
+            self.interface
+        )?;
+        let (untagged, tags) = vlan::untag(self.frame);
+        if let Some(id) = tags.id() {
+            write!(f, " vlan {}", id)?;
+        }
+        let length = self.frame.len();
+        let Some(ethernet) = EthernetPacket::new(&untagged) else {
+            return write!(f, " truncated, length {}", length);
+        };
+        let Some((ip, l4)) = parse_ip(ethernet.get_ethertype(), &untagged[ETHERNET_HEADER_LEN..])
+        else {
+            let protocol = match ethernet.get_ethertype() {
+                EtherTypes::Arp => "arp".to_string(),
+                ethertype => format!("ethertype 0x{:04x}", ethertype.0),
+            };
+            return write!(
+                f,
+                " {} > {}: {}, length {}",
+                ethernet.get_source(),
+                ethernet.get_destination(),
+                protocol,
+                length
+            );
+        };
+        let (source, destination): (IpAddr, IpAddr) = match &ip {
+            IpHeader::V4(ip) => (ip.get_source().into(), ip.get_destination().into()),
+            IpHeader::V6(ip) => (ip.get_source().into(), ip.get_destination().into()),
+        };
+        let ports =
+            |protocol: IpNextHeaderProtocol, payload: &[u8]| match protocol {
+                IpNextHeaderProtocols::Udp => UdpPacket::new(payload)
+                    .map(|udp| ("udp", udp.get_source(), udp.get_destination())),
+                IpNextHeaderProtocols::Tcp => TcpPacket::new(payload)
+                    .map(|tcp| ("tcp", tcp.get_source(), tcp.get_destination())),
+                _ => None,
+            };
+        match l4 {
+            Some((protocol, payload)) => match ports(protocol, payload) {
+                Some((name, source_port, destination_port)) => write!(
+                    f,
+                    " {}.{} > {}.{}: {}",
+                    source, source_port, destination, destination_port, name
+                )?,
+                None => write!(
+                    f,
+                    " {} > {}: {}",
+                    source,
+                    destination,
+                    protocol_name(protocol)
+                )?,
+            },
+            // Truncated headers and fragments past the first
+            None => {
+                let version = match ip {
+                    IpHeader::V4(_) => "ipv4",
+                    IpHeader::V6(_) => "ipv6",
+                };
+                write!(f, " {} > {}: {}", source, destination, version)?
+            }
+        }
+        write!(f, ", length {}", length)
+    }
+}
+
+fn protocol_name(protocol: IpNextHeaderProtocol) -> String {
+    match protocol {
+        IpNextHeaderProtocols::Icmp => "icmp".to_string(),
+        IpNextHeaderProtocols::Icmpv6 => "icmpv6".to_string(),
+        IpNextHeaderProtocols::Igmp => "igmp".to_string(),
+        IpNextHeaderProtocols::Udp => "udp".to_string(),
+        IpNextHeaderProtocols::Tcp => "tcp".to_string(),
+        other => format!("proto {}", other.0),
+    }
+}
+
+/// `bytes` as `hexdump -C` prints them: the offset, sixteen bytes in hex in
+/// two groups of eight and the printable ones as ASCII per line, ending
+/// with the offset past the last byte. Lines are separated, not ended, by
+/// line breaks.
+pub struct Hexdump<'a>(pub &'a [u8]);
+
+impl fmt::Display for Hexdump<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (index, line) in self.0.chunks(LINE_LEN).enumerate() {
+            write!(f, "{:08x} ", index * LINE_LEN)?;
+            for column in 0..LINE_LEN {
+                if column % 8 == 0 {
+                    f.write_str(" ")?;
+                }
+                match line.get(column) {
+                    Some(byte) => write!(f, "{:02x} ", byte)?,
+                    None => f.write_str("   ")?,
+                }
+            }
+            f.write_str(" |")?;
+            for &byte in line {
+                let shown = if byte.is_ascii_graphic() || byte == b' ' {
+                    char::from(byte)
+                } else {
+                    '.'
+                };
+                write!(f, "{}", shown)?;
+            }
+            f.write_str("|\n")?;
+        }
+        write!(f, "{:08x}", self.0.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{multicast_mac, tagged, udp_frame, HOST_IP, HOST_MAC, SSDP_IPV4_GROUP};
+    use std::time::Duration;
+
+    #[test]
+    fn formats_summaries_and_hexdumps() {
+        let at = UNIX_EPOCH + Duration::from_micros(784_111_777_000_042);
+        let frame = udp_frame(
+            HOST_MAC,
+            multicast_mac(SSDP_IPV4_GROUP.into()),
+            HOST_IP,
+            SSDP_IPV4_GROUP,
+            50000,
+            1900,
+            b"M-SEARCH",
+        );
+        let summary = FrameSummary {
+            at,
+            interface: "eth0",
+            frame: &frame,
+        };
+        assert_eq!(
+            summary.to_string(),
+            format!(
+                "08:49:37.000042 eth0 192.168.100.5.50000 > 239.255.255.250.1900: udp, length {}",
+                frame.len()
+            )
+        );
+
+        let frame = tagged(&frame, 10);
+        let summary = FrameSummary {
+            at,
+            interface: "eth0",
+            frame: &frame,
+        };
+        assert!(summary
+            .to_string()
+            .starts_with("08:49:37.000042 eth0 vlan 10 192.168.100.5.50000 > "));
+
+        let mut arp = vec![0xff; 6];
+        arp.extend_from_slice(&HOST_MAC.octets());
+        arp.extend_from_slice(&[0x08, 0x06]);
+        arp.resize(42, 0);
+        let summary = FrameSummary {
+            at,
+            interface: "eth1",
+            frame: &arp,
+        };
+        assert_eq!(
+            summary.to_string(),
+            format!(
+                "08:49:37.000042 eth1 {} > ff:ff:ff:ff:ff:ff: arp, length 42",
+                HOST_MAC
+            )
+        );
+        let summary = FrameSummary {
+            at,
+            interface: "eth1",
+            frame: &arp[..10],
+        };
+        assert_eq!(
+            summary.to_string(),
+            "08:49:37.000042 eth1 truncated, length 10"
+        );
+
+        let bytes: Vec<u8> = (0x3c..0x3c + 20).collect();
+        assert_eq!(
+            Hexdump(&bytes).to_string(),
+            "00000000  3c 3d 3e 3f 40 41 42 43  44 45 46 47 48 49 4a 4b  |<=>?@ABCDEFGHIJK|\n\
+             00000010  4c 4d 4e 4f                                       |LMNO|\n\
+             00000014"
+        );
+        assert_eq!(
+            Hexdump(b"a\r\n\x00").to_string(),
+            "00000000  61 0d 0a 00                                       |a...|\n00000004"
+        );
+        assert_eq!(Hexdump(&[]).to_string(), "00000000");
+    }
+}
@@ -0,0 +1,2653 @@
+//! Forwarding engine: opens the interfaces, connects them through filter
+//! chains and runs the capture and send tasks until shut down.
+
+use arc_swap::ArcSwap;
+use pnet::datalink::{self, NetworkInterface};
+use pnet::util::MacAddr;
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::{mpsc, oneshot, watch};
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, info, warn};
+
+use crate::allowlist::SourceAllowlist;
+use crate::arp::{ArpFilter, ArpMode, ArpProxy, LearnExternalHosts, ProxiedMac};
+use crate::bridge::{BridgeFilter, MacTable};
+use crate::capture::{spawn_capture, spawn_replay, ForwardPath, Reconnect, RX_POLL_INTERVAL};
+use crate::coap::CoapMessageFilter;
+#[cfg(feature = "dbus")]
+use crate::dbus::{self, PacketForwarder};
+use crate::dhcp::{
+    DhcpRelay, DhcpRelayFilter, RelayReplies, RelayRequests, DHCP_CLIENT_PORT, DHCP_SERVER_PORT,
+};
+use crate::dhcpv6::{self, Dhcpv6Relay, Dhcpv6RelayFilter, DHCPV6_CLIENT_PORT, DHCPV6_SERVER_PORT};
+use crate::discovery::Device;
+#[cfg(feature = "dbus")]
+use crate::discovery::Discoveries;
+use crate::dscp::{MarkDscp, Marking};
+use crate::error::Error;
+use crate::expression::{Expression, ExpressionFilter};
+use crate::filter::{
+    ChecksumFilter, Filter, FilterChain, Ipv4OnlyFilter, SharedFilterChain, TcpPortFilter,
+    UdpPortFilter, COAP_PORT, LLMNR_PORT, MDNS_PORT, SSDP_PORT, WSD_PORT,
+};
+use crate::hostmac::{HostMacTable, LearnHostMac, UnicastMac};
+use crate::iface::{
+    check_interface, find_interface, mtu, open_channel, open_sink, wait_for_interfaces, Backend,
+    ChannelConfig, MulticastMembership, PerInterface, ETHERNET_MTU, MAX_READ_MTU,
+};
+use crate::inventory::{DeviceInventory, InventoryEntry, LearnDevices};
+use crate::iplink::{EthernetAddressing, LearnNeighbors, LinkType, Neighbors};
+use crate::kernelfilter::{Interest, KernelFilter};
+use crate::latency::Tracer;
+use crate::link::{PacketSink, PacketSource, Unopened};
+use crate::logging::RepeatedMessages;
+use crate::loopguard::LoopGuard;
+use crate::mdns::{MdnsRewrite, MdnsServiceFilter};
+use crate::mdnscache::{LearnMdnsRecords, MdnsCache};
+use crate::mdnsmerge::{self, MdnsAggregator};
+use crate::mdnsunicast::{MdnsQueries, MdnsResponseTracker, RouteUnicastResponses};
+use crate::nameservice::{
+    NameService, NameServiceFilter, NameServiceMode, NetbiosBroadcast, NETBIOS_NS_PORT,
+};
+use crate::nat::{ReverseNat, SourceNat, Translation};
+use crate::ndp::{LearnExternalNeighbors, NdpFilter, NdpMode, NdpProxy, ProxiedNeighborMac};
+use crate::netns::{self, NetNs};
+#[cfg(feature = "otel")]
+use crate::otel;
+use crate::oversize::Oversize;
+use crate::pair::{bridge_roles, interface_roles, Direction, Pair, Role};
+use crate::passthrough::PassThrough;
+use crate::pause::Pause;
+use crate::pcap::{spawn_writer, PcapReader, PcapSinks};
+use crate::pool::BufferPool;
+use crate::privileges::Credentials;
+use crate::prober::{self, AnnounceProbeResponses, ProbeResponses, ProbeTargets, Prober};
+use crate::quota::{Quota, Quotas};
+use crate::ratelimit::{RateLimiter, RateLimits};
+use crate::responder::{Cache, Responder};
+use crate::rewrite::{MasqueradeMac, RewriteChain};
+use crate::routeradvert::{RewriteAdvertSource, RouterAdvertFilter, RouterAdverts};
+use crate::rules::{Action, Protocol, Rule, RuleFilter};
+use crate::seccomp::{self, Features};
+use crate::sender::{spawn_mirror, spawn_sender, MirrorQueue, SendQueue};
+use crate::shaper::{Shape, Shaper};
+use crate::snooping::{MembershipTable, SnoopingFilter};
+use crate::ssdp::{SsdpLocationRewrite, SsdpMessageFilter, SsdpResponseTracker};
+use crate::ssdpcache::{LearnSsdpDevices, SsdpCache};
+use crate::standby::{self, Coordination, Election};
+use crate::state::{self, SavedPair, SavedState};
+use crate::stats::{InterfaceStats, MirrorStats, PathStats, Stats, StatsSnapshot};
+use crate::storm::StormControl;
+use crate::supervise::{self, Supervision};
+use crate::threads::Affinity;
+use crate::ttl::{DecrementTtl, OnLinkTtl};
+use crate::vlan::{self, VlanPath};
+use crate::wol::{WakeOnLanFilter, WOL_PORTS};
+use crate::wsd::WsdMessageFilter;
+use crate::{config, profile, Args};
+
+/// Upper bound on waiting for the capture tasks during shutdown
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(1);
+/// Upper bound on flushing the OTLP export during shutdown
+#[cfg(feature = "otel")]
+const OTLP_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Interface pairs given on the command line
+fn interface_pairs(args: &Args) -> Vec<Pair> {
+    match (&args.external_iface, &args.internal_iface) {
+        (Some(external), Some(internal)) => vec![Pair {
+            external: external.clone(),
+            internal: internal.clone(),
+        }],
+        _ => args.pair.clone(),
+    }
+}
+
+/// mDNS services forwarded when neither a profile nor `--mdns-services`
+/// names any
+const DEFAULT_MDNS_SERVICES: &[&str] = &["_googlecast._tcp.local"];
+/// SSDP targets forwarded when neither a profile nor `--ssdp-targets` names
+/// any
+const DEFAULT_SSDP_TARGETS: &[&str] = &[
+    "ssdp:all",
+    "urn:dial-multiscreen-org:service:dial:1",
+    "urn:dial-multiscreen-org:device:dial:1",
+];
+
+/// UDP ports to forward according to the profiles and command line options
+fn udp_ports(args: &Args) -> HashSet<u16> {
+    let mut udp_ports: HashSet<u16> =
+        profile::combine(&args.profile, |preset| preset.udp_ports, &args.ports)
+            .into_iter()
+            .collect();
+    if udp_ports.is_empty() {
+        udp_ports.insert(SSDP_PORT);
+    }
+    if args.enable_mdns {
+        udp_ports.insert(MDNS_PORT);
+    }
+    if args.enable_wsd {
+        udp_ports.insert(WSD_PORT);
+    }
+    if args.enable_coap {
+        udp_ports.insert(COAP_PORT);
+    }
+    if args.disable_ssdp {
+        udp_ports.remove(&SSDP_PORT);
+    }
+    if args.llmnr != NameServiceMode::Block {
+        udp_ports.insert(LLMNR_PORT);
+    }
+    if args.netbios_ns != NameServiceMode::Block {
+        udp_ports.insert(NETBIOS_NS_PORT);
+    }
+    udp_ports
+}
+
+/// TCP ports to forward according to the profiles and `--tcp-ports`
+fn tcp_ports(args: &Args) -> Vec<u16> {
+    profile::combine(&args.profile, |preset| preset.tcp_ports, &args.tcp_ports)
+}
+
+/// Traffic the kernel filter lets through, `None` if a filter expression or
+/// a forwarding rule without a port may forward any frame
+fn kernel_interest(args: &Args, udp_ports: &HashSet<u16>) -> Option<Interest> {
+    if !args.filter.is_empty() {
+        return None;
+    }
+    let mut interest = Interest {
+        // Blocked name services too, so their drops are counted
+        udp_ports: udp_ports
+            .iter()
+            .copied()
+            .chain([LLMNR_PORT, NETBIOS_NS_PORT])
+            .collect(),
+        tcp_ports: tcp_ports(args).into_iter().collect(),
+        arp: args.arp_mode != ArpMode::Off,
+        ipv6: !args.disable_ipv6,
+        control: !args.no_snooping || args.ndp_mode != NdpMode::Off || args.forward_ra,
+        wol: args.enable_wol,
+        eapol: args.pass_eapol,
+        lldp: args.pass_lldp,
+    };
+    for rule in args
+        .rule
+        .iter()
+        .filter(|rule| rule.action == Action::Forward)
+    {
+        let ports = [rule.source_port, rule.destination_port, rule.port];
+        let ports: Vec<u16> = ports.into_iter().flatten().collect();
+        if ports.is_empty() {
+            return None;
+        }
+        if rule.protocol != Some(Protocol::Tcp) {
+            interest.udp_ports.extend(&ports);
+        }
+        if rule.protocol != Some(Protocol::Udp) {
+            interest.tcp_ports.extend(&ports);
+        }
+    }
+    if args.dhcp_relay.is_some() {
+        interest
+            .udp_ports
+            .extend([DHCP_SERVER_PORT, DHCP_CLIENT_PORT]);
+    }
+    if args.dhcpv6_relay.is_some() {
+        interest
+            .udp_ports
+            .extend([DHCPV6_SERVER_PORT, DHCPV6_CLIENT_PORT]);
+    }
+    if args.enable_wol {
+        interest.udp_ports.extend(WOL_PORTS);
+    }
+    Some(interest)
+}
+
+/// Kernel filter for the channels unless disabled
+fn kernel_filter(args: &Args, udp_ports: &HashSet<u16>) -> Option<Arc<KernelFilter>> {
+    if args.no_kernel_filter {
+        info!("Kernel filter disabled, every frame is received");
+        return None;
+    }
+    let interest = kernel_interest(args, udp_ports);
+    if interest.is_none() {
+        info!("Kernel filter left open, filter expressions or rules may forward any frame");
+    }
+    Some(Arc::new(KernelFilter::new(interest)))
+}
+
+/// mDNS services to forward according to the profiles and `--mdns-services`.
+/// `--chromecast-strict` leaves out the group services the chromecast
+/// profile adds.
+fn mdns_services(args: &Args) -> Vec<String> {
+    let mut services = profile::combine(
+        &args.profile,
+        |preset| preset.mdns_services,
+        &args.mdns_services,
+    );
+    if args.chromecast_strict {
+        services.retain(|service| {
+            !profile::CHROMECAST_GROUP_SERVICES.contains(&service.as_str())
+                || args.mdns_services.contains(service)
+        });
+    }
+    if services.is_empty() {
+        DEFAULT_MDNS_SERVICES
+            .iter()
+            .map(|s| s.to_string())
+            .collect()
+    } else {
+        services
+    }
+}
+
+/// SSDP targets to forward according to the profiles and `--ssdp-targets`
+fn ssdp_targets(args: &Args) -> Vec<String> {
+    let targets = profile::combine(
+        &args.profile,
+        |preset| preset.ssdp_targets,
+        &args.ssdp_targets,
+    );
+    if targets.is_empty() {
+        DEFAULT_SSDP_TARGETS.iter().map(|s| s.to_string()).collect()
+    } else {
+        targets
+    }
+}
+
+/// Rate limits according to the command line options. mDNS frames are
+/// limited per host unless mDNS is not forwarded or the limit is disabled.
+fn rate_limits(args: &Args, udp_ports: &HashSet<u16>) -> RateLimits {
+    let mdns = udp_ports.contains(&MDNS_PORT) && args.mdns_max_pps_per_host > 0;
+    RateLimits {
+        total: args.max_pps,
+        per_host: args.max_pps_per_host,
+        mdns_per_host: mdns.then_some(args.mdns_max_pps_per_host),
+        burst: args.rate_limit_burst,
+        max_hosts: args.rate_limit_hosts,
+    }
+}
+
+/// Builds a filter chain from the command line options. `stage` holds the
+/// stateful filter of the path, evaluated ahead of the port filters.
+fn build_filter_chain(
+    args: &Args,
+    udp_ports: &HashSet<u16>,
+    internal_iface: Option<&str>,
+    snooping: Option<SnoopingFilter>,
+    stage: Option<impl Filter + 'static>,
+    state: Option<&PairState>,
+) -> FilterChain {
+    let mut chain = FilterChain::new();
+    if !args.allow_src_mac.is_empty() || !args.allow_src_ip.is_empty() {
+        chain.push(SourceAllowlist::new(
+            args.allow_src_mac.clone(),
+            args.allow_src_ip.clone(),
+        ));
+    }
+    if args.enable_wol {
+        chain.push(WakeOnLanFilter::new(args.wol_targets.clone()));
+    }
+    if args.disable_ipv6 {
+        chain.push(Ipv4OnlyFilter);
+    }
+    chain.push(NameServiceFilter::new(
+        NameService::Llmnr,
+        args.llmnr,
+        &args.llmnr_names,
+    ));
+    chain.push(NameServiceFilter::new(
+        NameService::NetbiosNs,
+        args.netbios_ns,
+        &[],
+    ));
+    // Ahead of snooping, as the relay and routers take the groups these
+    // go to without joining them
+    if let Some(adverts) = state.and_then(|state| state.router_adverts.as_ref()) {
+        chain.push(RouterAdvertFilter::new(adverts.clone()));
+    }
+    if let Some(relay) = state.and_then(|state| state.dhcpv6_relay.as_ref()) {
+        chain.push(Dhcpv6RelayFilter::new(relay.clone()));
+    }
+    if let Some(snooping) = snooping {
+        chain.push(snooping);
+    }
+    // Ahead of the SSDP response tracking, which knows only forwarded searches
+    if let Some(prober) = state.and_then(|state| state.prober.as_ref()) {
+        chain.push(ProbeResponses::new(prober.clone()));
+    }
+    if let Some(stage) = stage {
+        chain.push(stage);
+    }
+    if let Some(relay) = state.and_then(|state| state.dhcp_relay.as_ref()) {
+        chain.push(DhcpRelayFilter::new(relay.clone()));
+    }
+    if args.arp_mode != ArpMode::Off {
+        let proxy = state.and_then(|state| state.arp_proxy.clone());
+        chain.push(ArpFilter::new(proxy));
+    }
+    if args.ndp_mode != NdpMode::Off {
+        let proxy = state.and_then(|state| state.ndp_proxy.clone());
+        chain.push(NdpFilter::new(proxy));
+    }
+    if let Some(queries) = state.and_then(|state| state.mdns_queries.as_ref()) {
+        if udp_ports.contains(&MDNS_PORT) {
+            let internal_iface = internal_iface.unwrap_or_default().to_string();
+            chain.push(MdnsResponseTracker::new(internal_iface, queries.clone()));
+        }
+    }
+    if udp_ports.contains(&MDNS_PORT) && !args.no_mdns_filtering {
+        chain.push(MdnsServiceFilter::new(
+            &mdns_services(args),
+            !args.chromecast_strict,
+        ));
+    }
+    if udp_ports.contains(&SSDP_PORT) && !args.no_ssdp_filtering {
+        chain.push(SsdpMessageFilter::new(
+            &ssdp_targets(args),
+            internal_iface.map(str::to_string),
+            args.ssdp_external_search,
+            args.ssdp_internal_announce,
+        ));
+    }
+    if udp_ports.contains(&WSD_PORT) && !args.no_wsd_filtering {
+        chain.push(WsdMessageFilter::new(
+            &args.wsd_actions,
+            internal_iface.map(str::to_string),
+            args.wsd_external_probe,
+            args.wsd_internal_announce,
+        ));
+    }
+    if udp_ports.contains(&COAP_PORT) && !args.no_coap_filtering {
+        chain.push(CoapMessageFilter::new(
+            args.coap_external_request,
+            args.coap_internal_response,
+        ));
+    }
+    if !args.rule.is_empty() {
+        chain.push(RuleFilter::new(args.rule.clone()));
+    }
+    if !args.filter.is_empty() {
+        chain.push(ExpressionFilter::new(args.filter.clone()));
+    }
+    chain.push(UdpPortFilter::new(udp_ports.clone()));
+    let tcp_ports = tcp_ports(args);
+    if !tcp_ports.is_empty() {
+        chain.push(TcpPortFilter::new(tcp_ports.into_iter().collect()));
+    }
+    // Last, so only the frames otherwise forwarded are summed up
+    if args.verify_checksums {
+        chain.push(ChecksumFilter);
+    }
+    chain
+}
+
+/// DSCP marking of packets forwarded in `direction`, if any
+fn marking(args: &Args, direction: Direction) -> Option<Marking> {
+    let marking = Marking::new(&args.dscp);
+    (!marking.is_empty() && args.dscp_direction.marks(direction)).then_some(marking)
+}
+
+/// VLAN handling of a path in `direction`, with the priority of marked
+/// frames following their DSCP if asked to
+fn vlan_path(
+    args: &Args,
+    ingress: Option<u16>,
+    egress: Option<u16>,
+    direction: Direction,
+) -> VlanPath {
+    VlanPath {
+        priority: marking(args, direction).filter(|_| args.dscp_pcp),
+        ..VlanPath::new(ingress, egress, args.vlan_egress)
+    }
+}
+
+/// Builds the rewrite stages for both directions, returning the chains for
+/// frames sent towards the internal and towards the external interface
+fn build_rewrite_chains(
+    args: &Args,
+    external: &NetworkInterface,
+    internal: &NetworkInterface,
+    snat: Option<&Translation>,
+    state: &PairState,
+) -> Result<(RewriteChain, RewriteChain), Error> {
+    let mut to_internal = RewriteChain::new();
+    let mut to_external = RewriteChain::new();
+
+    // Off-link and expired packets must not reach the learning stages below
+    to_internal.push(on_link_ttl(args));
+    to_external.push(on_link_ttl(args));
+    if args.decrement_ttl {
+        to_internal.push(DecrementTtl::new(&args.ttl_exempt_port));
+        to_external.push(DecrementTtl::new(&args.ttl_exempt_port));
+    }
+    if let Some(marking) = marking(args, Direction::Inbound) {
+        to_internal.push(MarkDscp::new(marking));
+    }
+    if let Some(marking) = marking(args, Direction::Outbound) {
+        to_external.push(MarkDscp::new(marking));
+    }
+    if let Some(prober) = &state.prober {
+        to_internal.push(AnnounceProbeResponses::new(prober.clone()));
+    }
+    if let Some(proxy) = &state.arp_proxy {
+        to_internal.push(LearnExternalHosts::new(proxy.clone()));
+    }
+    if let Some(proxy) = &state.ndp_proxy {
+        to_internal.push(LearnExternalNeighbors::new(proxy.clone()));
+    }
+    if let Some(inventory) = &state.inventory {
+        to_internal.push(LearnDevices::new(inventory.clone()));
+    }
+    if let Some(neighbors) = &state.neighbors {
+        to_internal.push(LearnNeighbors::new(neighbors.clone()));
+    }
+
+    if let Some(rewrite) = mdns_rewrite(args, false) {
+        to_internal.push(rewrite);
+    }
+    if let Some(rewrite) = mdns_rewrite(args, true) {
+        to_external.push(rewrite);
+    }
+    if let Some(cache) = &state.mdns_cache {
+        to_internal.push(LearnMdnsRecords::new(cache.clone()));
+    }
+    // Whether NetBIOS is forwarded at all can change on reload
+    to_internal.push(NetbiosBroadcast::new(external, internal));
+    to_external.push(NetbiosBroadcast::new(internal, external));
+
+    if !args.ssdp_location_map.is_empty() {
+        for mapping in &args.ssdp_location_map {
+            info!(
+                "SSDP LOCATION {} advertised as {}",
+                mapping.internal, mapping.advertised
+            );
+        }
+        let fail_closed = args.ssdp_location_fail_closed;
+        to_internal.push(SsdpLocationRewrite::new(
+            &args.ssdp_location_map,
+            true,
+            fail_closed,
+        ));
+        to_external.push(SsdpLocationRewrite::new(
+            &args.ssdp_location_map,
+            false,
+            fail_closed,
+        ));
+    }
+    if let Some(cache) = &state.ssdp_cache {
+        to_internal.push(LearnSsdpDevices::new(cache.clone()));
+    }
+
+    if let Some(hosts) = &state.hosts {
+        to_external.push(LearnHostMac::new(hosts.clone()));
+    }
+
+    if let Some(relay) = &state.dhcp_relay {
+        to_external.push(RelayRequests::new(relay.clone()));
+        to_internal.push(RelayReplies::new(relay.clone()));
+    }
+    if let Some(relay) = &state.dhcpv6_relay {
+        to_external.push(dhcpv6::RelayRequests::new(relay.clone()));
+        to_internal.push(dhcpv6::RelayReplies::new(relay.clone()));
+    }
+    if let Some(adverts) = &state.router_adverts {
+        to_internal.push(RewriteAdvertSource::new(adverts.clone()));
+    }
+
+    if let Some(queries) = &state.mdns_queries {
+        to_internal.push(RouteUnicastResponses::new(queries.clone()));
+    }
+
+    if let Some(translation) = snat {
+        info!(
+            "Source NAT to {} on {}",
+            translation.external_ip(),
+            external.name
+        );
+        to_external.push(SourceNat::new(translation.clone()));
+        to_internal.push(ReverseNat::new(translation.clone()));
+    }
+
+    if let Some(proxy) = &state.arp_proxy {
+        to_external.push(ProxiedMac::new(proxy.clone()));
+    }
+    if let Some(proxy) = &state.ndp_proxy {
+        to_external.push(ProxiedNeighborMac::new(proxy.clone()));
+    }
+
+    if let Some(hosts) = &state.hosts {
+        let mac = internal.mac.ok_or_else(|| Error::MissingAddress {
+            iface: internal.name.clone(),
+            what: "MAC address for unicast rewriting",
+        })?;
+        to_internal.push(UnicastMac::new(mac, hosts.clone()));
+    }
+
+    if args.masquerade_mac {
+        to_internal.push(masquerade_mac(internal)?);
+        to_external.push(masquerade_mac(external)?);
+    }
+
+    // Last, frames from a bare IP interface have no MACs until here
+    if let Some(neighbors) = &state.neighbors {
+        let mac = external.mac.ok_or_else(|| Error::MissingAddress {
+            iface: external.name.clone(),
+            what: "MAC address for framing IP packets",
+        })?;
+        to_external.push(EthernetAddressing::new(mac, external, neighbors.clone()));
+    }
+
+    for (chain, egress) in [(&to_internal, internal), (&to_external, external)] {
+        if !chain.is_empty() {
+            info!(
+                "Rewrite stages towards {}: {:?}",
+                egress.name,
+                chain.names()
+            );
+        }
+    }
+    Ok((to_internal, to_external))
+}
+
+/// Stage sending mDNS and LLMNR with the TTL of an on-link sender, first in
+/// every chain so it sees the TTL the frame arrived with
+fn on_link_ttl(args: &Args) -> OnLinkTtl {
+    OnLinkTtl::new(args.mdns_enforce_ttl, args.llmnr_enforce_ttl)
+}
+
+/// mDNS rewrite stage if anything is to be rewritten; TXT data is only
+/// removed from messages sent towards the external interface
+fn mdns_rewrite(args: &Args, to_external: bool) -> Option<MdnsRewrite> {
+    let max_ttl = args
+        .mdns_max_ttl
+        .map(|ttl| u32::try_from(ttl.as_secs()).unwrap_or(u32::MAX));
+    let strip_txt = to_external && args.mdns_strip_txt;
+    let strip_keys: &[String] = if to_external {
+        &args.mdns_strip_txt_key
+    } else {
+        &[]
+    };
+    let rewrite = max_ttl.is_some() || strip_txt || !strip_keys.is_empty();
+    rewrite.then(|| MdnsRewrite::new(max_ttl, strip_txt, strip_keys))
+}
+
+/// Sends the local answers of a pair's caches on its internal interface,
+/// counted separately from the forwarded traffic
+fn responder(pair: &Pair, internal: &Endpoint) -> Result<Responder, Error> {
+    let stats = PathStats::new(
+        pair.to_string(),
+        Direction::Inbound,
+        pair.internal.clone(),
+        pair.internal.clone(),
+    );
+    Responder::new(
+        &internal.iface,
+        internal.vlan,
+        internal.queue.clone(),
+        stats,
+    )
+}
+
+/// Sends the ICMPv6 Packet Too Big messages about the frames received on
+/// `ingress`, counted separately from the forwarded traffic
+fn too_big_responder(ingress: &Endpoint) -> Option<Responder> {
+    let name = ingress.iface.name.clone();
+    let stats = PathStats::new(
+        "icmp-too-big".to_string(),
+        Direction::Bridged,
+        name.clone(),
+        name.clone(),
+    );
+    let responder = Responder::new(&ingress.iface, ingress.vlan, ingress.queue.clone(), stats);
+    match responder {
+        Ok(responder) if responder.ipv6().is_some() => Some(responder),
+        _ => {
+            warn!("No IPv6 address on {}, not sending Packet Too Big", name);
+            None
+        }
+    }
+}
+
+fn mdns_cache(args: &Args, pair: &Pair, internal: &Endpoint) -> Result<MdnsCache, Error> {
+    info!(
+        "Answering mDNS queries on {} from a cache of up to {} records",
+        internal.iface.name, args.mdns_cache_size
+    );
+    let responder = responder(pair, internal)?;
+    let delay = args.mdns_aggregate.is_some();
+    Ok(MdnsCache::new(args.mdns_cache_size, responder, delay))
+}
+
+fn arp_proxy(
+    args: &Args,
+    pair: &Pair,
+    external: &Endpoint,
+    internal: &Endpoint,
+) -> Result<ArpProxy, Error> {
+    let external_mac = external.iface.mac.ok_or_else(|| Error::MissingAddress {
+        iface: external.iface.name.clone(),
+        what: "MAC address for proxy ARP",
+    })?;
+    info!(
+        "Answering ARP requests on {} for hosts learned on {}",
+        internal.iface.name, external.iface.name
+    );
+    let hosts = HostMacTable::new(args.mac_table_size, args.mac_ttl);
+    Ok(ArpProxy::new(
+        internal.iface.name.clone(),
+        internal.iface.ips.clone(),
+        external_mac,
+        hosts,
+        responder(pair, internal)?,
+    ))
+}
+
+fn ndp_proxy(
+    args: &Args,
+    pair: &Pair,
+    external: &Endpoint,
+    internal: &Endpoint,
+) -> Result<NdpProxy, Error> {
+    let external_mac = external.iface.mac.ok_or_else(|| Error::MissingAddress {
+        iface: external.iface.name.clone(),
+        what: "MAC address for NDP proxy",
+    })?;
+    let responder = responder(pair, internal)?;
+    if responder.ipv6().is_none() {
+        return Err(Error::MissingAddress {
+            iface: internal.iface.name.clone(),
+            what: "IPv6 address for NDP proxy",
+        });
+    }
+    info!(
+        "Answering neighbor solicitations on {} for hosts learned on {}",
+        internal.iface.name, external.iface.name
+    );
+    let hosts = HostMacTable::new(args.mac_table_size, args.mac_ttl);
+    Ok(NdpProxy::new(
+        internal.iface.name.clone(),
+        internal.iface.ips.clone(),
+        external_mac,
+        hosts,
+        responder,
+    ))
+}
+
+fn ssdp_cache(args: &Args, pair: &Pair, internal: &Endpoint) -> Result<SsdpCache, Error> {
+    info!(
+        "Answering SSDP searches on {} from a cache of up to {} devices",
+        internal.iface.name, args.ssdp_cache_size
+    );
+    let responder = responder(pair, internal)?;
+    Ok(SsdpCache::new(
+        args.ssdp_cache_size,
+        responder,
+        args.ssdp_server_string.clone(),
+    ))
+}
+
+/// Prober searching on the external interface of a pair, for the targets
+/// that are forwarded at all
+fn prober(
+    args: &Args,
+    udp_ports: &HashSet<u16>,
+    pair: &Pair,
+    external: &Endpoint,
+    limiter: Option<&Arc<RateLimiter>>,
+) -> Result<Prober, Error> {
+    let name = &external.iface.name;
+    let mut ssdp = if args.probe_ssdp_targets.is_empty() {
+        ssdp_targets(args)
+    } else {
+        args.probe_ssdp_targets.clone()
+    };
+    if !ssdp.is_empty() && !udp_ports.contains(&SSDP_PORT) {
+        warn!(
+            "SSDP is not forwarded, not probing for SSDP devices on {}",
+            name
+        );
+        ssdp.clear();
+    }
+    let mut mdns = if args.probe_mdns_services.is_empty() {
+        mdns_services(args)
+    } else {
+        args.probe_mdns_services.clone()
+    };
+    if !mdns.is_empty() && !udp_ports.contains(&MDNS_PORT) {
+        warn!(
+            "mDNS is not forwarded, not probing for mDNS services on {}",
+            name
+        );
+        mdns.clear();
+    }
+    let stats = PathStats::new(
+        format!("probe {}", pair),
+        Direction::Outbound,
+        pair.internal.clone(),
+        name.clone(),
+    );
+    let responder = Responder::new(
+        &external.iface,
+        external.vlan,
+        external.queue.clone(),
+        stats,
+    )?;
+    let targets = ProbeTargets {
+        ssdp,
+        mdns,
+        interval: args.probe_interval,
+        jitter: args.probe_jitter,
+        window: args.ssdp_response_window,
+    };
+    let prober = Prober::new(name.clone(), targets.clone(), responder, limiter.cloned())
+        .ok_or_else(|| Error::MissingAddress {
+            iface: name.clone(),
+            what: "IPv4 address for probing",
+        })?;
+    let (ip, port) = prober.source();
+    info!(
+        "Probing for {} SSDP target(s) and {} mDNS service(s) on {} from {}:{} every {}",
+        targets.ssdp.len(),
+        targets.mdns.len(),
+        name,
+        ip,
+        port,
+        humantime::format_duration(targets.interval)
+    );
+    Ok(prober)
+}
+
+fn masquerade_mac(egress: &NetworkInterface) -> Result<MasqueradeMac, Error> {
+    let mac = egress.mac.ok_or_else(|| Error::MissingAddress {
+        iface: egress.name.clone(),
+        what: "MAC address for masquerading",
+    })?;
+    Ok(MasqueradeMac::new(mac))
+}
+
+/// DHCP relay between the interfaces of a pair, addressed with their MACs
+/// and first IPv4 addresses
+fn dhcp_relay(
+    args: &Args,
+    external: &NetworkInterface,
+    internal: &NetworkInterface,
+) -> Result<Option<DhcpRelay>, Error> {
+    let Some(server) = args.dhcp_relay else {
+        return Ok(None);
+    };
+    let address = |iface: &NetworkInterface| {
+        let mac = iface.mac.ok_or_else(|| Error::MissingAddress {
+            iface: iface.name.clone(),
+            what: "MAC address for DHCP relay",
+        })?;
+        let ip = iface
+            .ips
+            .iter()
+            .find_map(|ip| match ip.ip() {
+                IpAddr::V4(ip) => Some(ip),
+                IpAddr::V6(_) => None,
+            })
+            .ok_or_else(|| Error::MissingAddress {
+                iface: iface.name.clone(),
+                what: "IPv4 address for DHCP relay",
+            })?;
+        Ok((mac, ip))
+    };
+    let (internal_address, external_address) = (address(internal)?, address(external)?);
+    info!(
+        "Relaying DHCP from {} ({}) to {} via {}",
+        internal.name, internal_address.1, server, external.name
+    );
+    Ok(Some(DhcpRelay::new(
+        internal.name.clone(),
+        internal_address,
+        external_address,
+        server,
+        args.dhcp_relay_option82,
+    )))
+}
+
+/// DHCPv6 relay between the interfaces of a pair, addressed with their MACs,
+/// the internal interface's link-local address and the external
+/// interface's first global address, or its link-local one
+fn dhcpv6_relay(
+    args: &Args,
+    external: &NetworkInterface,
+    internal: &NetworkInterface,
+) -> Result<Option<Dhcpv6Relay>, Error> {
+    let Some(server) = args.dhcpv6_relay else {
+        return Ok(None);
+    };
+    let ipv6 = |iface: &NetworkInterface, link_local: bool| {
+        iface.ips.iter().find_map(|ip| match ip.ip() {
+            IpAddr::V6(ip) if ip.is_unicast_link_local() == link_local => Some(ip),
+            _ => None,
+        })
+    };
+    let address = |iface: &NetworkInterface, ip: Option<Ipv6Addr>| {
+        let mac = iface.mac.ok_or_else(|| Error::MissingAddress {
+            iface: iface.name.clone(),
+            what: "MAC address for DHCPv6 relay",
+        })?;
+        let ip = ip.ok_or_else(|| Error::MissingAddress {
+            iface: iface.name.clone(),
+            what: "IPv6 address for DHCPv6 relay",
+        })?;
+        Ok((mac, ip))
+    };
+    let internal_address = address(internal, ipv6(internal, true))?;
+    let external_address = address(external, ipv6(external, false).or(ipv6(external, true)))?;
+    let link_address = ipv6(internal, false).unwrap_or(Ipv6Addr::UNSPECIFIED);
+    info!(
+        "Relaying DHCPv6 from {} to {} via {} ({})",
+        internal.name, server, external.name, external_address.1
+    );
+    Ok(Some(Dhcpv6Relay::new(
+        internal.name.clone(),
+        internal_address,
+        link_address,
+        external_address,
+        server,
+        HostMacTable::new(args.mac_table_size, args.mac_ttl),
+    )))
+}
+
+/// Router advertisement forwarding into the internal interface of a pair,
+/// rewritten to its MAC if asked to
+fn router_adverts(
+    args: &Args,
+    external: &NetworkInterface,
+    internal: &NetworkInterface,
+) -> Result<Option<RouterAdverts>, Error> {
+    if !args.forward_ra {
+        return Ok(None);
+    }
+    let source_mac = if args.ra_rewrite_source {
+        let mac = internal.mac.ok_or_else(|| Error::MissingAddress {
+            iface: internal.name.clone(),
+            what: "MAC address for router advertisements",
+        })?;
+        Some(mac)
+    } else {
+        None
+    };
+    info!(
+        "Forwarding router advertisements from {} to {}",
+        external.name, internal.name
+    );
+    Ok(Some(RouterAdverts::new(internal.name.clone(), source_mac)))
+}
+
+/// Resolves the source NAT address, falling back to the first IPv4 address
+/// of the external interface when `--snat` is given without a value. A
+/// given address that the interface does not have is warned about.
+fn snat_address(args: &Args, external: &NetworkInterface) -> Result<Option<Ipv4Addr>, Error> {
+    let Some(snat) = args.snat else {
+        return Ok(None);
+    };
+    if let Some(ip) = snat {
+        if !external.ips.iter().any(|net| net.ip() == IpAddr::V4(ip)) {
+            warn!(
+                "Source NAT address {} is not an address of {}, nothing may answer ARP for it",
+                ip, external.name
+            );
+        }
+    }
+    snat.or_else(|| {
+        external.ips.iter().find_map(|ip| match ip.ip() {
+            IpAddr::V4(ip) => Some(ip),
+            IpAddr::V6(_) => None,
+        })
+    })
+    .map(Some)
+    .ok_or_else(|| Error::MissingAddress {
+        iface: external.name.clone(),
+        what: "IPv4 address for source NAT",
+    })
+}
+
+fn channel_config(args: &Args, role: Role, iface: &NetworkInterface) -> ChannelConfig {
+    let promiscuous = args.promiscuous.applies_to(role);
+    info!(
+        "Opening {:?} interface {} in {} mode",
+        role,
+        iface.name,
+        if promiscuous {
+            "promiscuous"
+        } else {
+            "non-promiscuous"
+        }
+    );
+    let size = |sizes: &[PerInterface<u64>]| {
+        PerInterface::lookup(sizes, &iface.name).map(|&size| size as usize)
+    };
+    // Jumbo frames are read whole so they can be fragmented or dropped
+    let mtu = mtu(&iface.name).unwrap_or(ETHERNET_MTU).min(MAX_READ_MTU);
+    let defaults = datalink::Config::default();
+    ChannelConfig {
+        datalink: datalink::Config {
+            promiscuous,
+            read_buffer_size: (mtu as usize + vlan::MAX_HEADER_LEN).max(defaults.read_buffer_size),
+            read_timeout: Some(
+                PerInterface::lookup(&args.read_timeout, &iface.name)
+                    .copied()
+                    .unwrap_or(RX_POLL_INTERVAL),
+            ),
+            ..defaults
+        },
+        backend: args.backend,
+        batch_size: args.batch_size,
+        rx_buffer: size(&args.rx_buffer_size),
+        tx_buffer: size(&args.tx_buffer_size),
+        link_type: link_type(args, role),
+    }
+}
+
+/// Link type of the interfaces in `role`
+fn link_type(args: &Args, role: Role) -> LinkType {
+    match role {
+        Role::Internal => args.internal_link_type,
+        Role::External | Role::Bridge => LinkType::Ethernet,
+    }
+}
+
+/// Network namespaces of the interfaces of each side, `None` for the
+/// forwarder's own
+#[derive(Default)]
+struct Namespaces {
+    external: Option<Arc<NetNs>>,
+    internal: Option<Arc<NetNs>>,
+}
+
+impl Namespaces {
+    fn open(args: &Args) -> Result<Self, Error> {
+        let open = |spec: Option<&str>| -> Result<_, Error> {
+            let Some(spec) = spec else {
+                return Ok(None);
+            };
+            let netns = NetNs::open(spec)?;
+            info!("Opening interfaces in network namespace {}", netns.spec());
+            Ok(Some(Arc::new(netns)))
+        };
+        Ok(Namespaces {
+            external: open(args.external_netns.as_deref())?,
+            internal: open(args.internal_netns.as_deref())?,
+        })
+    }
+
+    fn of(&self, role: Role) -> Option<&Arc<NetNs>> {
+        match role {
+            Role::External => self.external.as_ref(),
+            Role::Internal => self.internal.as_ref(),
+            Role::Bridge => None,
+        }
+    }
+}
+
+/// Interfaces of `netns`, or of the forwarder's own namespace, once all of
+/// `names` exist if the arguments say to wait for them
+async fn interfaces_in(
+    args: &Args,
+    names: &[&str],
+    netns: Option<&NetNs>,
+) -> Result<Vec<NetworkInterface>, Error> {
+    if args.wait_for_iface {
+        wait_for_interfaces(names, netns, args.wait_timeout).await
+    } else {
+        netns::within(netns, datalink::interfaces)
+    }
+}
+
+/// An interface used by one or more pairs or bridged with others. It is
+/// opened once, so frames the forwarder sends on it are never captured again
+/// for another path.
+struct Endpoint {
+    iface: NetworkInterface,
+    /// Network namespace the interface is in, which its threads are spawned
+    /// in, `None` for the forwarder's own
+    netns: Option<Arc<NetNs>>,
+    /// VLAN the interface is on, if any
+    vlan: Option<u16>,
+    config: ChannelConfig,
+    stats: Arc<InterfaceStats>,
+    /// `None` for the interface a trace is replayed on
+    rx: Option<Box<dyn PacketSource>>,
+    queue: SendQueue,
+    paths: Vec<ForwardPath>,
+}
+
+fn endpoint_index(endpoints: &[Endpoint], name: &str) -> usize {
+    endpoints
+        .iter()
+        .position(|endpoint| endpoint.iface.name == name)
+        .expect("every interface in use has an endpoint")
+}
+
+/// State of a pair shared by its filters and rewrites, kept for the state
+/// dump and to rebuild the filters on reload
+struct PairState {
+    /// Learned internal hosts
+    hosts: Option<Arc<HostMacTable>>,
+    /// Devices seen announcing themselves on the external side
+    inventory: Option<Arc<DeviceInventory>>,
+    /// mDNS queries from the internal side that may be answered by unicast
+    mdns_queries: Option<Arc<MdnsQueries>>,
+    mdns_cache: Option<Arc<MdnsCache>>,
+    ssdp_cache: Option<Arc<SsdpCache>>,
+    dhcp_relay: Option<Arc<DhcpRelay>>,
+    dhcpv6_relay: Option<Arc<Dhcpv6Relay>>,
+    router_adverts: Option<Arc<RouterAdverts>>,
+    arp_proxy: Option<Arc<ArpProxy>>,
+    ndp_proxy: Option<Arc<NdpProxy>>,
+    /// Learned external hosts, if the internal interface carries bare IP
+    neighbors: Option<Arc<Neighbors>>,
+    prober: Option<Arc<Prober>>,
+}
+
+impl PairState {
+    /// Caches answering in place of forwarding
+    fn caches(&self) -> Vec<Arc<dyn Cache>> {
+        let mut caches: Vec<Arc<dyn Cache>> = Vec::new();
+        if let Some(cache) = &self.mdns_cache {
+            caches.push(cache.clone());
+        }
+        if let Some(cache) = &self.ssdp_cache {
+            caches.push(cache.clone());
+        }
+        if let Some(proxy) = &self.arp_proxy {
+            caches.push(proxy.clone());
+        }
+        if let Some(proxy) = &self.ndp_proxy {
+            caches.push(proxy.clone());
+        }
+        caches
+    }
+}
+
+/// What a filter chain is built for, kept to rebuild it on reload
+enum ChainKind {
+    Pair {
+        pair: Pair,
+        snat: Option<Translation>,
+        state: PairState,
+    },
+    BridgePort {
+        egress: String,
+        table: Arc<MacTable>,
+    },
+}
+
+impl ChainKind {
+    fn build(
+        &self,
+        args: &Args,
+        udp_ports: &HashSet<u16>,
+        snooping: Option<&Arc<MembershipTable>>,
+    ) -> FilterChain {
+        let snooping = |interfaces: Vec<String>| {
+            snooping.map(|table| SnoopingFilter::new(table.clone(), interfaces))
+        };
+        match self {
+            ChainKind::Pair { pair, snat, state } => {
+                let tracking = udp_ports.contains(&SSDP_PORT) && !args.no_ssdp_tracking;
+                let tracker = tracking.then(|| {
+                    SsdpResponseTracker::new(
+                        pair.internal.clone(),
+                        args.ssdp_response_window,
+                        args.ssdp_max_searches,
+                        snat.clone(),
+                    )
+                });
+                let snooping = snooping(vec![pair.external.clone(), pair.internal.clone()]);
+                build_filter_chain(
+                    args,
+                    udp_ports,
+                    Some(&pair.internal),
+                    snooping,
+                    tracker,
+                    Some(state),
+                )
+            }
+            ChainKind::BridgePort { egress, table } => {
+                let snooping = snooping(vec![egress.clone()]);
+                let bridge = BridgeFilter::new(egress.clone(), table.clone());
+                build_filter_chain(args, udp_ports, None, snooping, Some(bridge), None)
+            }
+        }
+    }
+}
+
+/// Filter chain shared by both directions of a pair, or by all paths
+/// towards one bridge port
+struct ChainSlot {
+    kind: ChainKind,
+    /// Group memberships shared by all chains, kept across reloads
+    snooping: Option<Arc<MembershipTable>>,
+    filters: SharedFilterChain,
+}
+
+impl ChainSlot {
+    fn new(
+        kind: ChainKind,
+        snooping: Option<Arc<MembershipTable>>,
+        args: &Args,
+        udp_ports: &HashSet<u16>,
+    ) -> Self {
+        let filters = kind.build(args, udp_ports, snooping.as_ref());
+        ChainSlot {
+            kind,
+            snooping,
+            filters: Arc::new(ArcSwap::from_pointee(filters)),
+        }
+    }
+
+    fn rebuild(&self, args: &Args, udp_ports: &HashSet<u16>) {
+        let filters = self.kind.build(args, udp_ports, self.snooping.as_ref());
+        self.filters.store(Arc::new(filters));
+    }
+}
+
+/// Group membership table for IGMP and MLD snooping unless disabled
+fn membership_table(args: &Args) -> Option<Arc<MembershipTable>> {
+    if args.no_snooping {
+        return None;
+    }
+    info!(
+        "Snooping IGMP and MLD, multicast to interfaces without membership traffic: {:?}",
+        args.snooping_unknown
+    );
+    Some(Arc::new(MembershipTable::new(args.snooping_unknown)))
+}
+
+/// Opens the send-only channel of the mirror interface and spawns the task
+/// sending copies of frames to it
+fn mirror(
+    args: &Args,
+    interfaces: &[NetworkInterface],
+    name: &str,
+    token: CancellationToken,
+) -> Result<(MirrorQueue, JoinHandle<()>), Error> {
+    let iface = find_interface(interfaces, name)?;
+    // Nothing is read from the mirror interface, its receiving half is
+    // dropped and there is nothing for XDP to take
+    let config = ChannelConfig {
+        backend: match args.backend {
+            Backend::AfXdp => Backend::Pnet,
+            backend => backend,
+        },
+        tx_buffer: PerInterface::lookup(&args.tx_buffer_size, name).map(|&size| size as usize),
+        ..Default::default()
+    };
+    let (tx, _) = open_channel(&iface, config, None, None)?;
+    info!(
+        "Mirroring forwarded{} frames to {}",
+        if args.mirror_dropped {
+            " and dropped"
+        } else {
+            ""
+        },
+        name
+    );
+    Ok(spawn_mirror(
+        tx,
+        Arc::new(MirrorStats::new(name.to_string())),
+        args.send_queue_capacity,
+        args.mirror_dropped,
+        args.dry_run,
+        Affinity::lookup(&args.tx_affinity, name),
+        token,
+    ))
+}
+
+/// Adds both forwarding directions of every pair to the endpoints
+fn connect_pairs(
+    args: &Args,
+    pairs: &[Pair],
+    udp_ports: &HashSet<u16>,
+    endpoints: &mut [Endpoint],
+    pcap: &PcapSinks,
+    loop_guard: Option<&Arc<LoopGuard>>,
+    limiter: Option<&Arc<RateLimiter>>,
+) -> Result<Vec<ChainSlot>, Error> {
+    let snooping = membership_table(args);
+    let mut chains = Vec::new();
+    for pair in pairs {
+        let ext = endpoint_index(endpoints, &pair.external);
+        let int = endpoint_index(endpoints, &pair.internal);
+        let snat = snat_address(args, &endpoints[ext].iface)?.map(Translation::new);
+        let hosts = args
+            .rewrite_unicast_mac
+            .then(|| Arc::new(HostMacTable::new(args.mac_table_size, args.mac_ttl)));
+        let inventory = args.device_inventory.then(|| {
+            info!(
+                "Keeping an inventory of up to {} devices announcing themselves on {}",
+                args.device_inventory_size, pair.external
+            );
+            Arc::new(DeviceInventory::new(
+                args.device_inventory_size,
+                args.device_retention,
+            ))
+        });
+        let tracking = udp_ports.contains(&MDNS_PORT) && !args.no_mdns_tracking;
+        let mdns_queries = tracking.then(|| {
+            Arc::new(MdnsQueries::new(
+                args.mdns_response_window,
+                args.mdns_max_queries,
+                snat.clone(),
+            ))
+        });
+        let mdns_cache = if args.mdns_cache {
+            Some(Arc::new(mdns_cache(args, pair, &endpoints[int])?))
+        } else {
+            None
+        };
+        let ssdp_cache = if args.ssdp_cache {
+            Some(Arc::new(ssdp_cache(args, pair, &endpoints[int])?))
+        } else {
+            None
+        };
+        let dhcp_relay = dhcp_relay(args, &endpoints[ext].iface, &endpoints[int].iface)?;
+        let dhcpv6_relay = dhcpv6_relay(args, &endpoints[ext].iface, &endpoints[int].iface)?;
+        let router_adverts = router_adverts(args, &endpoints[ext].iface, &endpoints[int].iface)?;
+        let arp_proxy = if args.arp_mode == ArpMode::Proxy {
+            Some(Arc::new(arp_proxy(
+                args,
+                pair,
+                &endpoints[ext],
+                &endpoints[int],
+            )?))
+        } else {
+            None
+        };
+        let ndp_proxy = if args.ndp_mode == NdpMode::Proxy {
+            Some(Arc::new(ndp_proxy(
+                args,
+                pair,
+                &endpoints[ext],
+                &endpoints[int],
+            )?))
+        } else {
+            None
+        };
+        let neighbors = (args.internal_link_type == LinkType::Ip)
+            .then(|| Arc::new(Neighbors::new(args.mac_table_size, args.mac_ttl)));
+        let prober = if args.probe {
+            Some(Arc::new(prober(
+                args,
+                udp_ports,
+                pair,
+                &endpoints[ext],
+                limiter,
+            )?))
+        } else {
+            None
+        };
+        let state = PairState {
+            hosts,
+            inventory,
+            mdns_queries,
+            mdns_cache,
+            ssdp_cache,
+            dhcp_relay: dhcp_relay.map(Arc::new),
+            dhcpv6_relay: dhcpv6_relay.map(Arc::new),
+            router_adverts: router_adverts.map(Arc::new),
+            arp_proxy,
+            ndp_proxy,
+            neighbors,
+            prober,
+        };
+        let caches = state.caches();
+        let (to_internal, to_external) = build_rewrite_chains(
+            args,
+            &endpoints[ext].iface,
+            &endpoints[int].iface,
+            snat.as_ref(),
+            &state,
+        )?;
+        let kind = ChainKind::Pair {
+            pair: pair.clone(),
+            snat: snat.clone(),
+            state,
+        };
+        let chain = ChainSlot::new(kind, snooping.clone(), args, udp_ports);
+        info!(
+            "Filter chain for {}: {:?}",
+            pair,
+            chain.filters.load().names()
+        );
+
+        let inbound = ForwardPath {
+            ingress: pair.external.clone(),
+            filters: chain.filters.clone(),
+            rewrites: to_internal,
+            loop_guard: loop_guard.cloned(),
+            limiter: limiter.cloned(),
+            quotas: Arc::default(),
+            caches: Vec::new(),
+            tx: endpoints[int].queue.clone(),
+            stats: Arc::new(PathStats::new(
+                pair.to_string(),
+                Direction::Inbound,
+                pair.external.clone(),
+                pair.internal.clone(),
+            )),
+            pcap: pcap.clone(),
+            vlan: vlan_path(
+                args,
+                endpoints[ext].vlan,
+                endpoints[int].vlan,
+                Direction::Inbound,
+            ),
+            mirror: None,
+            pool: None,
+            tracer: None,
+            paused: Arc::default(),
+            oversize: None,
+            storm: None,
+            passthrough: PassThrough::default(),
+            mdns_aggregator: None,
+            decisions: RepeatedMessages::default(),
+        };
+        let outbound = ForwardPath {
+            ingress: pair.internal.clone(),
+            filters: chain.filters.clone(),
+            rewrites: to_external,
+            loop_guard: loop_guard.cloned(),
+            limiter: limiter.cloned(),
+            quotas: Arc::default(),
+            caches,
+            tx: endpoints[ext].queue.clone(),
+            stats: Arc::new(PathStats::new(
+                pair.to_string(),
+                Direction::Outbound,
+                pair.internal.clone(),
+                pair.external.clone(),
+            )),
+            pcap: pcap.clone(),
+            vlan: vlan_path(
+                args,
+                endpoints[int].vlan,
+                endpoints[ext].vlan,
+                Direction::Outbound,
+            ),
+            mirror: None,
+            pool: None,
+            tracer: None,
+            paused: Arc::default(),
+            oversize: None,
+            storm: None,
+            passthrough: PassThrough::default(),
+            mdns_aggregator: None,
+            decisions: RepeatedMessages::default(),
+        };
+        endpoints[ext].paths.push(inbound);
+        endpoints[int].paths.push(outbound);
+        chains.push(chain);
+    }
+    Ok(chains)
+}
+
+/// Adds a path from every bridge port to every other port, steered by a
+/// shared MAC learning table
+fn connect_bridge(
+    args: &Args,
+    udp_ports: &HashSet<u16>,
+    endpoints: &mut [Endpoint],
+    pcap: &PcapSinks,
+    loop_guard: Option<&Arc<LoopGuard>>,
+    limiter: Option<&Arc<RateLimiter>>,
+) -> Result<Vec<ChainSlot>, Error> {
+    if udp_ports.contains(&SSDP_PORT) && !args.no_ssdp_tracking {
+        info!("SSDP response tracking is not used in bridge mode");
+    }
+    let table = Arc::new(MacTable::new(args.mac_table_size, args.mac_ttl));
+    let snooping = membership_table(args);
+    info!(
+        "Bridging {} with up to {} learned MAC addresses, aging out after {}",
+        args.bridge.join(", "),
+        args.mac_table_size,
+        humantime::format_duration(args.mac_ttl)
+    );
+    let chains: Vec<ChainSlot> = endpoints
+        .iter()
+        .map(|to| {
+            let kind = ChainKind::BridgePort {
+                egress: to.iface.name.clone(),
+                table: table.clone(),
+            };
+            ChainSlot::new(kind, snooping.clone(), args, udp_ports)
+        })
+        .collect();
+    info!(
+        "Filter chain for each bridge port: {:?}",
+        chains[0].filters.load().names()
+    );
+
+    let mut paths = Vec::new();
+    for (ingress, from) in endpoints.iter().enumerate() {
+        for (egress, to) in endpoints.iter().enumerate() {
+            if egress == ingress {
+                continue;
+            }
+            let mut rewrites = RewriteChain::new();
+            rewrites.push(on_link_ttl(args));
+            if args.decrement_ttl {
+                rewrites.push(DecrementTtl::new(&args.ttl_exempt_port));
+            }
+            if let Some(marking) = marking(args, Direction::Bridged) {
+                rewrites.push(MarkDscp::new(marking));
+            }
+            if let Some(rewrite) = mdns_rewrite(args, false) {
+                rewrites.push(rewrite);
+            }
+            if args.masquerade_mac {
+                rewrites.push(masquerade_mac(&to.iface)?);
+            }
+            let path = ForwardPath {
+                ingress: from.iface.name.clone(),
+                filters: chains[egress].filters.clone(),
+                rewrites,
+                loop_guard: loop_guard.cloned(),
+                limiter: limiter.cloned(),
+                quotas: Arc::default(),
+                caches: Vec::new(),
+                tx: to.queue.clone(),
+                stats: Arc::new(PathStats::new(
+                    "bridge".to_string(),
+                    Direction::Bridged,
+                    from.iface.name.clone(),
+                    to.iface.name.clone(),
+                )),
+                pcap: pcap.clone(),
+                vlan: vlan_path(args, from.vlan, to.vlan, Direction::Bridged),
+                mirror: None,
+                pool: None,
+                tracer: None,
+                paused: Arc::default(),
+                oversize: None,
+                storm: None,
+                passthrough: PassThrough::default(),
+                mdns_aggregator: None,
+                decisions: RepeatedMessages::default(),
+            };
+            paths.push((ingress, path));
+        }
+    }
+    for (ingress, path) in paths {
+        endpoints[ingress].paths.push(path);
+    }
+    Ok(chains)
+}
+
+/// Swaps in filter chains built from `new` without touching the channels.
+/// Changes to options that need a restart are reported and ignored, and
+/// their keys returned; options that fail to validate leave the running
+/// configuration as it is and fail with the problem found.
+fn reload(
+    args: &mut Args,
+    new: Args,
+    chains: &[ChainSlot],
+    kernel_filter: Option<&KernelFilter>,
+    quotas: &Quotas,
+) -> Result<Vec<String>, String> {
+    if let Err((_, problem)) = config::check(&new) {
+        error!(
+            "Invalid configuration: {}, keeping the current configuration",
+            problem
+        );
+        return Err(format!("invalid configuration: {}", problem));
+    }
+
+    let changes = config::diff(args, &new);
+    let mut reloadable = 0;
+    let mut ignored = Vec::new();
+    for change in changes {
+        if config::RELOADABLE.contains(&change.key.as_str()) {
+            reloadable += 1;
+            info!("  {}: {} -> {}", change.key, change.old, change.new);
+        } else {
+            warn!(
+                "  {}: {} -> {} needs a restart, change ignored",
+                change.key, change.old, change.new
+            );
+            ignored.push(change.key);
+        }
+    }
+    if reloadable == 0 {
+        info!("No filter changes to apply");
+        return Ok(ignored);
+    }
+
+    config::apply_reloadable(args, new);
+    let udp_ports = udp_ports(args);
+    for chain in chains {
+        chain.rebuild(args, &udp_ports);
+    }
+    if let Some(filter) = kernel_filter {
+        filter.update(kernel_interest(args, &udp_ports));
+    }
+    quotas.update(&args.quota);
+    info!(
+        "Filter configuration reloaded, forwarding UDP ports {:?}",
+        udp_ports
+    );
+    Ok(ignored)
+}
+
+/// What the filters, caches and tables have learned, one titled list of
+/// lines each: SSDP, MAC and multicast listener state
+fn learned_state(chains: &[ChainSlot]) -> Vec<(String, Vec<String>)> {
+    let mut learned = Vec::new();
+    for chain in chains {
+        if let ChainKind::Pair { pair, state, .. } = &chain.kind {
+            let hosts = state.hosts.as_ref();
+            let hosts = hosts.map(|hosts| ("host MACs", hosts.state()));
+            let neighbors = state.neighbors.as_ref();
+            let neighbors = neighbors.map(|neighbors| ("external neighbors", neighbors.hosts()));
+            let inventory = state.inventory.as_ref();
+            let inventory = inventory.map(|inventory| ("device inventory", inventory.state()));
+            let caches = state.caches();
+            let caches = caches.iter().map(|cache| (cache.name(), cache.state()));
+            let extra = hosts
+                .into_iter()
+                .chain(neighbors)
+                .chain(inventory)
+                .chain(caches);
+            for (filter, lines) in chain.filters.load().state().into_iter().chain(extra) {
+                learned.push((format!("{} {}", pair, filter), lines));
+            }
+        }
+    }
+    if let Some(ChainKind::BridgePort { table, .. }) = chains.first().map(|chain| &chain.kind) {
+        learned.push(("MAC table".to_string(), table.state()));
+    }
+    if let Some(table) = chains.first().and_then(|chain| chain.snooping.as_ref()) {
+        learned.push(("Multicast listeners".to_string(), table.state()));
+    }
+    learned
+}
+
+/// Caches of every pair, which the devices are listed from
+fn pair_caches(chains: &[ChainSlot]) -> Vec<Arc<dyn Cache>> {
+    chains
+        .iter()
+        .flat_map(|chain| match &chain.kind {
+            ChainKind::Pair { state, .. } => state.caches(),
+            _ => Vec::new(),
+        })
+        .collect()
+}
+
+/// Inventories of every pair
+fn inventories(chains: &[ChainSlot]) -> Vec<Arc<DeviceInventory>> {
+    chains
+        .iter()
+        .filter_map(|chain| match &chain.kind {
+            ChainKind::Pair { state, .. } => state.inventory.clone(),
+            _ => None,
+        })
+        .collect()
+}
+
+/// What the pairs and the bridge learned, for the state file
+fn saved_state(chains: &[ChainSlot]) -> SavedState {
+    let (now, wall) = (Instant::now(), SystemTime::now());
+    let mut saved = SavedState::default();
+    for chain in chains {
+        match &chain.kind {
+            ChainKind::Pair { pair, state, .. } => {
+                let pair = saved.pairs.entry(pair.to_string()).or_default();
+                if let Some(inventory) = &state.inventory {
+                    pair.inventory = inventory.save(now, wall);
+                }
+                if let Some(cache) = &state.ssdp_cache {
+                    pair.ssdp_cache = cache.save(now, wall);
+                }
+                if let Some(cache) = &state.mdns_cache {
+                    pair.mdns_cache = cache.save(now, wall);
+                }
+                if let Some(hosts) = &state.hosts {
+                    pair.hosts = hosts.save(now, wall);
+                }
+            }
+            // Every port shares the table
+            ChainKind::BridgePort { table, .. } => saved.bridge = table.save(now, wall),
+        }
+    }
+    saved
+}
+
+/// Fills the pairs and the bridge with what they learned before the last
+/// shutdown. State of pairs no longer configured is dropped.
+fn restore_state(chains: &[ChainSlot], mut saved: SavedState) {
+    let (now, wall) = (Instant::now(), SystemTime::now());
+    let mut bridge = Some(std::mem::take(&mut saved.bridge));
+    for chain in chains {
+        match &chain.kind {
+            ChainKind::Pair { pair, state, .. } => {
+                let Some(SavedPair {
+                    inventory,
+                    ssdp_cache,
+                    mdns_cache,
+                    hosts,
+                }) = saved.pairs.remove(&pair.to_string())
+                else {
+                    continue;
+                };
+                if let Some(table) = &state.inventory {
+                    table.restore(inventory, now, wall);
+                }
+                if let Some(cache) = &state.ssdp_cache {
+                    cache.restore(ssdp_cache, now, wall);
+                }
+                if let Some(cache) = &state.mdns_cache {
+                    cache.restore(mdns_cache, now, wall);
+                }
+                if let Some(table) = &state.hosts {
+                    table.restore(hosts, now, wall);
+                }
+            }
+            ChainKind::BridgePort { table, .. } => {
+                if let Some(bridge) = bridge.take() {
+                    table.restore(bridge, now, wall);
+                }
+            }
+        }
+    }
+}
+
+/// Saves the learned state to `--state-file`, if given
+fn save_state(args: &Args, chains: &[ChainSlot]) {
+    let Some(path) = &args.state_file else {
+        return;
+    };
+    match state::save(path, &saved_state(chains)) {
+        Ok(()) => debug!("State saved to {}", path.display()),
+        Err(e) => warn!("Failed to save state to {}: {}", path.display(), e),
+    }
+}
+
+/// Starts the probers of all pairs
+fn spawn_probers(chains: &[ChainSlot], token: &CancellationToken) -> Vec<JoinHandle<()>> {
+    chains
+        .iter()
+        .filter_map(|chain| match &chain.kind {
+            ChainKind::Pair { state, .. } => state.prober.clone(),
+            ChainKind::BridgePort { .. } => None,
+        })
+        .map(|prober| prober::spawn(prober, token.clone()))
+        .collect()
+}
+
+/// Has the SSDP and mDNS caches of every pair announce the devices they
+/// learn from now on to `discoveries`
+#[cfg(feature = "dbus")]
+fn announce_discoveries(chains: &[ChainSlot], discoveries: &Discoveries) {
+    for chain in chains {
+        if let ChainKind::Pair { state, .. } = &chain.kind {
+            if let Some(cache) = &state.mdns_cache {
+                cache.announce_to(discoveries.clone());
+            }
+            if let Some(cache) = &state.ssdp_cache {
+                cache.announce_to(discoveries.clone());
+            }
+        }
+    }
+}
+
+/// Logs the counters, the active port allowlist, the rate limiter and the
+/// learned state
+fn dump_state(args: &Args, stats: &Stats, chains: &[ChainSlot], limiter: Option<&RateLimiter>) {
+    stats.dump();
+    let mut ports: Vec<u16> = udp_ports(args).into_iter().collect();
+    ports.sort_unstable();
+    info!(
+        "Forwarding UDP ports {:?}, TCP ports {:?}",
+        ports,
+        tcp_ports(args)
+    );
+    if let Some(limiter) = limiter {
+        info!("Rate limiter: {}", limiter.state());
+    }
+    for (title, lines) in learned_state(chains) {
+        info!("{}:", title);
+        if lines.is_empty() {
+            info!("  (empty)");
+        }
+        for line in lines {
+            info!("  {}", line);
+        }
+    }
+}
+
+/// Requests to a running forwarder, handled between its periodic tasks
+enum Control {
+    Reload(Box<Args>, oneshot::Sender<Result<Vec<String>, String>>),
+    DumpState,
+    ResetStats,
+    ResetQuotas,
+    Status(oneshot::Sender<String>),
+    Stats(oneshot::Sender<StatsSnapshot>),
+    Learned(oneshot::Sender<Vec<(String, Vec<String>)>>),
+    Devices(oneshot::Sender<Vec<Device>>),
+    Inventory(oneshot::Sender<Vec<InventoryEntry>>),
+}
+
+/// Forwarding engine between the configured interfaces. It is built with
+/// [`Forwarder::builder`] and forwards from [`Forwarder::run`] until the
+/// token given there is cancelled or [`Forwarder::shutdown`] is called.
+pub struct Forwarder {
+    /// Options and the receiving end of the requests, taken by the run
+    startup: Mutex<Option<(Args, mpsc::UnboundedReceiver<Control>)>>,
+    control: mpsc::UnboundedSender<Control>,
+    shutdown: CancellationToken,
+    /// Set once the interfaces are open and frames are being forwarded
+    ready: watch::Sender<bool>,
+    /// Set while received frames are only counted as dropped
+    paused: Arc<Pause>,
+}
+
+impl Forwarder {
+    /// Starts from the defaults of the command line options
+    pub fn builder() -> ForwarderBuilder {
+        ForwarderBuilder {
+            args: Args::default(),
+        }
+    }
+
+    /// Forwarder for options that were already checked
+    pub(crate) fn new(args: Args) -> Self {
+        let (control, requests) = mpsc::unbounded_channel();
+        Forwarder {
+            startup: Mutex::new(Some((args, requests))),
+            control,
+            shutdown: CancellationToken::new(),
+            ready: watch::Sender::new(false),
+            paused: Arc::default(),
+        }
+    }
+
+    /// Opens the interfaces and forwards until `token` is cancelled,
+    /// [`shutdown`](Self::shutdown) is called or a replayed pcap file ends.
+    /// Frames still queued get a short grace period to be sent. A forwarder
+    /// runs only once, later calls fail with [`Error::AlreadyStarted`].
+    pub async fn run(&self, token: CancellationToken) -> Result<(), Error> {
+        let startup = self.startup.lock().unwrap().take();
+        let Some((args, requests)) = startup else {
+            return Err(Error::AlreadyStarted);
+        };
+        let control = Runtime {
+            requests,
+            shutdown: &self.shutdown,
+            ready: &self.ready,
+            paused: &self.paused,
+        };
+        forward(args, control, token.child_token()).await
+    }
+
+    /// Waits until the running forwarder has opened its interfaces and
+    /// started forwarding
+    pub async fn ready(&self) {
+        let _ = self.ready.subscribe().wait_for(|ready| *ready).await;
+    }
+
+    /// One line with the frames forwarded and dropped so far, `None` if the
+    /// forwarder is not running. No answer comes while its control loop is
+    /// stuck, which makes this usable as a liveness check.
+    pub async fn status(&self) -> Option<String> {
+        let (tx, rx) = oneshot::channel();
+        self.control.send(Control::Status(tx)).ok()?;
+        rx.await.ok()
+    }
+
+    /// Stops the forwarder, or makes it return at once if not running yet
+    pub fn shutdown(&self) {
+        self.shutdown.cancel();
+    }
+
+    /// Logs the counters, the active ports and the learned state of the
+    /// running forwarder
+    pub fn dump_state(&self) {
+        let _ = self.control.send(Control::DumpState);
+    }
+
+    /// Sets the statistics of the running forwarder back to zero
+    pub fn reset_stats(&self) {
+        let _ = self.control.send(Control::ResetStats);
+    }
+
+    /// Starts a new window for every quota of the running forwarder, so the
+    /// full budgets are available again
+    pub fn reset_quotas(&self) {
+        let _ = self.control.send(Control::ResetQuotas);
+    }
+
+    /// Applies the options of `args` that can change while running and
+    /// returns the keys of the changed ones that need a restart. Fails if
+    /// the options do not validate or the forwarder is not running.
+    pub(crate) async fn reload(&self, args: Args) -> Result<Vec<String>, String> {
+        let not_running = || "the forwarder is not running".to_string();
+        let (tx, rx) = oneshot::channel();
+        self.control
+            .send(Control::Reload(Box::new(args), tx))
+            .map_err(|_| not_running())?;
+        rx.await.map_err(|_| not_running())?
+    }
+
+    /// All counters of the running forwarder, `None` if it is not running
+    pub(crate) async fn stats(&self) -> Option<StatsSnapshot> {
+        let (tx, rx) = oneshot::channel();
+        self.control.send(Control::Stats(tx)).ok()?;
+        rx.await.ok()
+    }
+
+    /// What the running forwarder has learned about the devices on its
+    /// interfaces, as titled lists of lines
+    pub(crate) async fn learned(&self) -> Option<Vec<(String, Vec<String>)>> {
+        let (tx, rx) = oneshot::channel();
+        self.control.send(Control::Learned(tx)).ok()?;
+        rx.await.ok()
+    }
+
+    /// Devices the SSDP and mDNS caches of the running forwarder hold,
+    /// `None` if it is not running
+    pub(crate) async fn devices(&self) -> Option<Vec<Device>> {
+        let (tx, rx) = oneshot::channel();
+        self.control.send(Control::Devices(tx)).ok()?;
+        rx.await.ok()
+    }
+
+    /// Devices in the inventories of the running forwarder, `None` if it is
+    /// not running
+    pub(crate) async fn inventory(&self) -> Option<Vec<InventoryEntry>> {
+        let (tx, rx) = oneshot::channel();
+        self.control.send(Control::Inventory(tx)).ok()?;
+        rx.await.ok()
+    }
+
+    /// Drops received and still queued frames until
+    /// [`resume`](Self::resume) is called, returning once no frame is being
+    /// sent anymore. Returns `false` if already paused.
+    pub(crate) fn pause(&self) -> bool {
+        self.paused.set(true)
+    }
+
+    /// Forwards again after [`pause`](Self::pause). Returns `false` if not
+    /// paused.
+    pub(crate) fn resume(&self) -> bool {
+        self.paused.set(false)
+    }
+
+    /// Resumes forwarding if paused and pauses it otherwise. Returns whether
+    /// it is paused now.
+    pub(crate) fn toggle_pause(&self) -> bool {
+        self.paused.toggle()
+    }
+
+    /// Waits for the next time forwarding is paused or resumed
+    pub(crate) async fn pause_changed(&self) {
+        self.paused.changed().await;
+    }
+}
+
+/// Builder of a [`Forwarder`]. Anything not set keeps the default of the
+/// corresponding command line option.
+pub struct ForwarderBuilder {
+    args: Args,
+}
+
+impl ForwarderBuilder {
+    /// Interface facing the external network, used with
+    /// [`internal`](Self::internal)
+    pub fn external(mut self, iface: impl Into<String>) -> Self {
+        self.args.external_iface = Some(iface.into());
+        self
+    }
+
+    /// Interface facing the internal network, used with
+    /// [`external`](Self::external)
+    pub fn internal(mut self, iface: impl Into<String>) -> Self {
+        self.args.internal_iface = Some(iface.into());
+        self
+    }
+
+    /// Adds an external/internal interface pair, in place of
+    /// [`external`](Self::external) and [`internal`](Self::internal)
+    pub fn pair(mut self, external: impl Into<String>, internal: impl Into<String>) -> Self {
+        self.args.pair.push(Pair {
+            external: external.into(),
+            internal: internal.into(),
+        });
+        self
+    }
+
+    /// Adds an interface to bridge with the others, in place of pairs
+    pub fn bridge(mut self, iface: impl Into<String>) -> Self {
+        self.args.bridge.push(iface.into());
+        self
+    }
+
+    /// Adds a UDP port to forward
+    pub fn port(mut self, port: u16) -> Self {
+        self.args.ports.push(port);
+        self
+    }
+
+    /// Adds a TCP port to forward
+    pub fn tcp_port(mut self, port: u16) -> Self {
+        self.args.tcp_ports.push(port);
+        self
+    }
+
+    /// Adds a rule, evaluated ahead of the port lists
+    pub fn rule(mut self, rule: Rule) -> Self {
+        self.args.rule.push(rule);
+        self
+    }
+
+    /// Adds a quota, charged with the frames forwarded
+    pub fn quota(mut self, quota: Quota) -> Self {
+        self.args.quota.push(quota);
+        self
+    }
+
+    /// Adds a shape, delaying the frames sent in its direction to its rate
+    pub fn shape(mut self, shape: Shape) -> Self {
+        self.args.shape.push(shape);
+        self
+    }
+
+    /// Adds a filter expression; frames matching none of them are dropped
+    pub fn filter(mut self, expression: Expression) -> Self {
+        self.args.filter.push(expression);
+        self
+    }
+
+    /// Runs the whole pipeline but logs frames instead of sending them
+    pub fn dry_run(mut self, dry_run: bool) -> Self {
+        self.args.dry_run = dry_run;
+        self
+    }
+
+    /// Logs every stage of each accepted frame with an ID
+    pub fn trace_packets(mut self, trace_packets: bool) -> Self {
+        self.args.trace_packets = trace_packets;
+        self
+    }
+
+    /// Checks that the options go together
+    pub fn build(self) -> Result<Forwarder, Error> {
+        config::check(&self.args).map_err(|(_, problem)| Error::InvalidOptions(problem))?;
+        Ok(Forwarder::new(self.args))
+    }
+}
+
+/// How a run is controlled from its [`Forwarder`]
+struct Runtime<'a> {
+    requests: mpsc::UnboundedReceiver<Control>,
+    shutdown: &'a CancellationToken,
+    ready: &'a watch::Sender<bool>,
+    paused: &'a Arc<Pause>,
+}
+
+async fn forward(
+    mut args: Args,
+    mut control: Runtime<'_>,
+    token: CancellationToken,
+) -> Result<(), Error> {
+    let pairs = interface_pairs(&args);
+    let roles = if args.bridge.is_empty() {
+        interface_roles(&pairs)?
+    } else {
+        bridge_roles(&args.bridge)?
+    };
+    if let Some(name) = args.mirror_iface.as_deref() {
+        if roles.iter().any(|(forwarding, _)| *forwarding == name) {
+            return Err(Error::MirrorInUse(name.to_string()));
+        }
+    }
+    // Looked up before anything is opened so that a typo fails fast
+    let credentials = args
+        .user
+        .as_deref()
+        .map(|user| Credentials::resolve(user, args.group.as_deref()))
+        .transpose()?;
+    let namespaces = Namespaces::open(&args)?;
+    let names_in = |netns: Option<Role>| -> Vec<&str> {
+        roles
+            .iter()
+            .filter(|(_, role)| match netns {
+                Some(side) => *role == side,
+                None => namespaces.of(*role).is_none(),
+            })
+            .map(|(name, _)| *name)
+            .collect()
+    };
+    let mut names = names_in(None);
+    names.extend(args.mirror_iface.as_deref());
+    names.extend(args.standby_iface.as_deref());
+    let interfaces = interfaces_in(&args, &names, None).await?;
+    let mut namespaced = Vec::new();
+    for side in [Role::External, Role::Internal] {
+        if let Some(netns) = namespaces.of(side) {
+            let names = names_in(Some(side));
+            namespaced.push((side, interfaces_in(&args, &names, Some(netns)).await?));
+        }
+    }
+
+    if args.dry_run {
+        warn!("Dry run: frames are filtered, rewritten and logged but never sent");
+    }
+    if args.trace_packets {
+        warn!("Tracing packets: every stage of each accepted frame is logged");
+    }
+    match args.backend {
+        Backend::Pnet => {}
+        Backend::Raw => warn!(
+            "Experimental raw packet socket backend, batches of up to {} frames",
+            args.batch_size
+        ),
+        Backend::AfXdp if cfg!(feature = "af-xdp") => warn!(
+            "Experimental AF_XDP backend, frames to the forwarded ports no longer reach the network stack"
+        ),
+        Backend::AfXdp => warn!("Built without the af-xdp feature, using the pnet backend"),
+    }
+    if args.dbus && !cfg!(feature = "dbus") {
+        warn!("Built without the dbus feature, not serving org.ghaf.PacketForwarder");
+    }
+    if args.otel && !cfg!(feature = "otel") {
+        warn!("Built without the otel feature, not exporting over OTLP");
+    }
+    let mut replay = args.pcap_in.as_deref().map(PcapReader::open).transpose()?;
+    let udp_ports = udp_ports(&args);
+    let kernel_filter = kernel_filter(&args, &udp_ports);
+    let roles = roles
+        .into_iter()
+        .map(|(name, role)| {
+            let visible = namespaced
+                .iter()
+                .find(|(side, _)| *side == role)
+                .map_or(&interfaces, |(_, interfaces)| interfaces);
+            let iface = find_interface(visible, name)?;
+            let expected = link_type(&args, role);
+            netns::within(namespaces.of(role).map(Arc::as_ref), || {
+                check_interface(&iface, expected, args.on_link_down)
+            })??;
+            Ok((iface, role))
+        })
+        .collect::<Result<Vec<_>, Error>>()?;
+    let coordination = match (args.standby_group, &args.standby_iface, &args.standby_lock) {
+        (Some(group), Some(name), _) => {
+            let iface = find_interface(&interfaces, name)?;
+            Some(Coordination::heartbeats(
+                group,
+                &iface,
+                args.standby_priority,
+            )?)
+        }
+        (_, _, Some(path)) => Some(Coordination::lock(path)?),
+        _ => None,
+    };
+    // Standing by from the start, until this instance is elected
+    let election = coordination
+        .as_ref()
+        .map(|_| Arc::new(Election::new(control.paused.clone())));
+    // Every channel is opened before any task is spawned
+    let mut channels = Vec::new();
+    let mut memberships = Vec::new();
+    for (iface, role) in roles {
+        let netns = namespaces.of(role).cloned();
+        let config = channel_config(&args, role, &iface);
+        let stats = Arc::new(InterfaceStats::new(iface.name.clone()));
+        let replayed = replay.is_some()
+            && pairs
+                .first()
+                .is_some_and(|pair| pair.internal == iface.name);
+        let (tx, rx, membership) = netns::within(netns.as_deref(), || {
+            if replayed {
+                // Its frames come from the trace, so nothing is received on
+                // it, and a dry run sends nothing there either
+                let tx: Box<dyn PacketSink> = match args.dry_run {
+                    true => Box::new(Unopened),
+                    false => open_sink(&iface, config)?,
+                };
+                return Ok::<_, Error>((tx, None, None));
+            }
+            let (tx, rx) = open_channel(
+                &iface,
+                config,
+                kernel_filter.as_deref(),
+                Some(&stats.kernel),
+            )?;
+            let membership = MulticastMembership::join(&iface, &args.join_group)?;
+            Ok((tx, Some(rx), Some(membership)))
+        })??;
+        memberships.extend(membership);
+        channels.push((iface, netns, role, config, stats, tx, rx));
+    }
+    let mut endpoints = Vec::new();
+    let mut senders = Vec::new();
+    for (iface, netns, role, config, stats, tx, rx) in channels {
+        let paused = control.paused.clone();
+        let (queue, sender) = netns::within(netns.as_deref(), || {
+            spawn_sender(
+                &iface.name,
+                tx,
+                args.send_queue_capacity,
+                args.queue_policy,
+                args.dry_run,
+                paused,
+                Shaper::new(&args.shape, args.shape_max_delay),
+                Affinity::lookup(&args.tx_affinity, &iface.name),
+                token.clone(),
+            )
+        })?;
+        senders.push(sender);
+        let vlan = match role {
+            Role::External => args.external_vlan,
+            Role::Internal => args.internal_vlan,
+            Role::Bridge => None,
+        };
+        endpoints.push(Endpoint {
+            iface,
+            netns,
+            vlan,
+            config,
+            stats,
+            rx,
+            queue,
+            paths: Vec::new(),
+        });
+    }
+
+    let mirror = match &args.mirror_iface {
+        Some(name) => {
+            let (queue, sender) = mirror(&args, &interfaces, name, token.clone())?;
+            senders.push(sender);
+            Some(queue)
+        }
+        None => None,
+    };
+
+    info!("Forwarding UDP ports {:?}", udp_ports);
+    let tcp_ports = tcp_ports(&args);
+    if !tcp_ports.is_empty() {
+        info!("Forwarding TCP ports {:?}", tcp_ports);
+    }
+    let mut pcap = PcapSinks::default();
+    let mut writers = Vec::new();
+    if let Some(path) = &args.pcap_forwarded {
+        let (sink, writer) = spawn_writer(path, args.pcap_max_size)?;
+        info!("Writing forwarded frames to {}", path.display());
+        pcap.forwarded = Some(sink);
+        writers.push(writer);
+    }
+    if let Some(path) = &args.pcap_dropped {
+        let (sink, writer) = spawn_writer(path, args.pcap_max_size)?;
+        info!("Writing dropped frames to {}", path.display());
+        pcap.dropped = Some(sink);
+        writers.push(writer);
+    }
+    let loop_guard = (!args.no_loop_guard).then(|| {
+        // Interfaces without a hardware address such as lo report all zeros
+        let own_macs = endpoints
+            .iter()
+            .filter_map(|e| e.iface.mac)
+            .filter(|mac| *mac != MacAddr::zero())
+            .collect();
+        Arc::new(LoopGuard::new(
+            own_macs,
+            args.loop_window,
+            !args.no_loop_drop_repeats,
+        ))
+    });
+    let limits = rate_limits(&args, &udp_ports);
+    let limiter = RateLimiter::new(limits).map(Arc::new);
+    if limiter.is_some() {
+        let show =
+            |rate: Option<u32>| rate.map_or("unlimited".to_string(), |r| format!("{} pps", r));
+        info!(
+            "Rate limits: {} in total, {} per host, {} per host for mDNS, bursts of {}",
+            show(limits.total),
+            show(limits.per_host),
+            show(limits.mdns_per_host),
+            humantime::format_duration(limits.burst)
+        );
+    }
+    let quotas = Arc::new(Quotas::new(&args.quota));
+    for quota in &args.quota {
+        info!("Quota: {}", quota);
+    }
+    for shape in &args.shape {
+        info!(
+            "Shaping {}, delaying frames by up to {}",
+            shape,
+            humantime::format_duration(args.shape_max_delay)
+        );
+    }
+    let chains = if args.bridge.is_empty() {
+        connect_pairs(
+            &args,
+            &pairs,
+            &udp_ports,
+            &mut endpoints,
+            &pcap,
+            loop_guard.as_ref(),
+            limiter.as_ref(),
+        )?
+    } else {
+        connect_bridge(
+            &args,
+            &udp_ports,
+            &mut endpoints,
+            &pcap,
+            loop_guard.as_ref(),
+            limiter.as_ref(),
+        )?
+    };
+    if let Some(saved) = args.state_file.as_deref().and_then(state::load) {
+        restore_state(&chains, saved);
+    }
+    // The writers finish once the capture loops drop their sinks
+    drop(pcap);
+    let mtus: HashMap<String, usize> = endpoints
+        .iter()
+        .map(|endpoint| {
+            let name = &endpoint.iface.name;
+            let mtu = netns::within(endpoint.netns.as_deref(), || mtu(name))?;
+            let mtu = mtu.unwrap_or(ETHERNET_MTU) as usize;
+            info!("MTU of {}: {}", name, mtu);
+            Ok((name.clone(), mtu))
+        })
+        .collect::<Result<_, Error>>()?;
+    // Frames of the largest MTU, though never more than a read can return,
+    // as with the huge MTU of loopback
+    let buffer_len = endpoints
+        .iter()
+        .map(|endpoint| {
+            (mtus[&endpoint.iface.name] + vlan::MAX_HEADER_LEN)
+                .min(endpoint.config.datalink.read_buffer_size)
+        })
+        .max()
+        .unwrap_or(ETHERNET_MTU as usize + vlan::MAX_HEADER_LEN);
+    let pool = Arc::new(BufferPool::new(args.buffer_pool_size, buffer_len));
+    info!(
+        "Buffer pool of up to {} buffers of {} bytes",
+        args.buffer_pool_size, buffer_len
+    );
+    let tracer = args.trace_packets.then(|| Arc::new(Tracer::default()));
+    for endpoint in &mut endpoints {
+        let too_big = if args.icmp_too_big {
+            too_big_responder(endpoint)
+        } else {
+            None
+        };
+        for path in &mut endpoint.paths {
+            path.mirror = mirror.clone();
+            path.pool = Some(pool.clone());
+            path.tracer = tracer.clone();
+            path.paused = control.paused.clone();
+            path.quotas = quotas.clone();
+            path.oversize = Some(Oversize::new(
+                mtus[&path.stats.egress],
+                args.oversize_policy,
+                too_big.clone(),
+            ));
+            path.storm = (args.storm_threshold > 0)
+                .then(|| StormControl::new(path.ingress.clone(), args.storm_threshold));
+            path.passthrough = PassThrough {
+                eapol: args.pass_eapol,
+                lldp: args.pass_lldp,
+            };
+            if let (Some(window), Direction::Inbound) = (args.mdns_aggregate, path.stats.direction)
+            {
+                let output = mdnsmerge::Output {
+                    tx: path.tx.clone(),
+                    stats: path.stats.clone(),
+                    vlan: path.vlan,
+                    pcap: path.pcap.clone(),
+                    mirror: path.mirror.clone(),
+                    quotas: path.quotas.clone(),
+                    loop_guard: path.loop_guard.clone(),
+                    mtu: mtus[&path.stats.egress],
+                };
+                path.mdns_aggregator = Some(Arc::new(MdnsAggregator::new(window, output)));
+            }
+        }
+    }
+
+    let mut stats = Stats::default();
+    stats.paths = endpoints
+        .iter()
+        .flat_map(|endpoint| &endpoint.paths)
+        .map(|path| path.stats.clone())
+        .collect();
+    stats.paths.sort_by(|a, b| a.pair.cmp(&b.pair));
+    stats.mirror = mirror.map(|queue| queue.stats());
+    stats.pool = Some(pool.stats());
+    stats.pause = control.paused.clone();
+    stats.quotas = quotas.clone();
+    stats.election = election.clone();
+
+    let mut captures = Vec::new();
+    let replay_done = CancellationToken::new();
+    let (failures, mut failed) = mpsc::unbounded_channel();
+    let supervision = Supervision {
+        policy: args.on_task_failure,
+        max_restarts: args.max_task_restarts,
+    };
+    for endpoint in endpoints {
+        let iface_stats = endpoint.stats;
+        stats.interfaces.push(iface_stats.clone());
+        let capture = match endpoint.rx {
+            None => {
+                let reader = replay
+                    .take()
+                    .expect("only the replayed interface has no receive channel");
+                info!(
+                    "Replaying {} on {} instead of capturing",
+                    reader.path().display(),
+                    endpoint.iface.name
+                );
+                let task = spawn_replay(
+                    reader,
+                    iface_stats,
+                    endpoint.paths,
+                    args.replay_timing,
+                    Affinity::lookup(&args.rx_affinity, &endpoint.iface.name),
+                    token.clone(),
+                );
+                let done = replay_done.clone();
+                tokio::spawn(async move {
+                    let _ = task.await;
+                    done.cancel();
+                })
+            }
+            Some(rx) => {
+                // Re-opening the interface once it is lost happens on the
+                // capture thread, inside the interface's namespace
+                let reconnect = Reconnect {
+                    config: endpoint.config,
+                    kernel_filter: kernel_filter.clone(),
+                    own_queue: endpoint.queue,
+                };
+                let capture = netns::within(endpoint.netns.as_deref(), || {
+                    spawn_capture(
+                        rx,
+                        iface_stats,
+                        endpoint.paths,
+                        Some(reconnect),
+                        supervision,
+                        Affinity::lookup(&args.rx_affinity, &endpoint.iface.name),
+                        token.clone(),
+                    )
+                })?;
+                supervise::watch(
+                    format!("Capture on {}", endpoint.iface.name),
+                    capture,
+                    token.clone(),
+                    failures.clone(),
+                )
+            }
+        };
+        captures.push(capture);
+    }
+
+    let stats = Arc::new(stats);
+    #[cfg(feature = "dbus")]
+    let mut announcer = None;
+    // Connected while the system bus policy still sees the original user
+    #[cfg(feature = "dbus")]
+    if args.dbus {
+        let (discoveries, discovered) = Discoveries::channel();
+        announce_discoveries(&chains, &discoveries);
+        let service = PacketForwarder {
+            paused: control.paused.clone(),
+            stats: stats.clone(),
+            caches: pair_caches(&chains),
+            inventories: inventories(&chains),
+        };
+        let connection = dbus::connect(service).await?;
+        announcer = Some(dbus::spawn_announcer(connection, discovered, token.clone()));
+    }
+
+    #[cfg(feature = "otel")]
+    let exporter = args
+        .otel
+        .then(|| otel::spawn(&args, stats.clone(), token.clone()))
+        .transpose()?;
+
+    if let Some(credentials) = &credentials {
+        credentials.apply()?;
+    }
+    seccomp::install(
+        args.seccomp,
+        Features {
+            pcap: args.pcap_forwarded.is_some() || args.pcap_dropped.is_some(),
+            reload: args.config.is_some(),
+            xdp: cfg!(feature = "af-xdp") && args.backend == Backend::AfXdp,
+            control: args.control_socket.is_some(),
+            dbus: cfg!(feature = "dbus") && args.dbus,
+            otel: cfg!(feature = "otel") && args.otel,
+            state: args.state_file.is_some(),
+            lock: args.standby_lock.is_some(),
+        },
+    )?;
+
+    let probers = spawn_probers(&chains, &token);
+    let elector = coordination.zip(election).map(|(coordination, election)| {
+        standby::spawn(
+            coordination,
+            election,
+            args.standby_interval,
+            args.standby_missed,
+            token.clone(),
+        )
+    });
+    control.ready.send_replace(true);
+    let mut report = args
+        .stats_interval
+        .map(|period| tokio::time::interval_at(tokio::time::Instant::now() + period, period));
+    let mut saves = args.state_file.is_some().then(|| {
+        let period = args.state_save_interval;
+        tokio::time::interval_at(tokio::time::Instant::now() + period, period)
+    });
+    let mut failure = None;
+    loop {
+        tokio::select! {
+            _ = token.cancelled() => break,
+            _ = control.shutdown.cancelled() => break,
+            _ = replay_done.cancelled() => break,
+            Some(task) = failed.recv() => {
+                failure = Some(task);
+                break;
+            }
+            Some(request) = control.requests.recv() => match request {
+                Control::Reload(new, reply) => {
+                    let _ = reply.send(reload(&mut args, *new, &chains, kernel_filter.as_deref(), &quotas));
+                }
+                Control::DumpState => dump_state(&args, &stats, &chains, limiter.as_deref()),
+                Control::ResetStats => {
+                    stats.reset();
+                    info!("Statistics reset");
+                }
+                Control::ResetQuotas => {
+                    quotas.reset();
+                    info!("Quotas reset");
+                }
+                Control::Status(reply) => {
+                    let _ = reply.send(stats.status());
+                }
+                Control::Stats(reply) => {
+                    let _ = reply.send(stats.snapshot());
+                }
+                Control::Learned(reply) => {
+                    let _ = reply.send(learned_state(&chains));
+                }
+                Control::Devices(reply) => {
+                    let caches = pair_caches(&chains);
+                    let _ = reply.send(caches.iter().flat_map(|cache| cache.devices()).collect());
+                }
+                Control::Inventory(reply) => {
+                    let inventories = inventories(&chains);
+                    let _ = reply.send(inventories.iter().flat_map(|inventory| inventory.entries()).collect());
+                }
+            },
+            _ = async { report.as_mut().unwrap().tick().await }, if report.is_some() => {
+                stats.log()
+            }
+            _ = async { saves.as_mut().unwrap().tick().await }, if saves.is_some() => {
+                save_state(&args, &chains)
+            }
+        }
+    }
+    info!("Shutting down gracefully...");
+    token.cancel();
+
+    // Capture loops drop their queue handles on exit, which lets the senders finish
+    #[cfg(feature = "dbus")]
+    senders.extend(announcer);
+    let tasks = async {
+        for task in captures
+            .into_iter()
+            .chain(probers)
+            .chain(elector)
+            .chain(senders)
+            .chain(writers)
+        {
+            let _ = task.await;
+        }
+    };
+    if tokio::time::timeout(SHUTDOWN_TIMEOUT, tasks).await.is_err() {
+        warn!(
+            "Forwarding tasks did not stop within {:?}",
+            SHUTDOWN_TIMEOUT
+        );
+    }
+    stats.log();
+    save_state(&args, &chains);
+    #[cfg(feature = "otel")]
+    if let Some(exporter) = exporter {
+        if tokio::time::timeout(OTLP_SHUTDOWN_TIMEOUT, exporter)
+            .await
+            .is_err()
+        {
+            warn!(
+                "OTLP export was not flushed within {:?}",
+                OTLP_SHUTDOWN_TIMEOUT
+            );
+        }
+    }
+    drop(memberships);
+    match failure {
+        Some(task) => Err(Error::TaskFailed(task)),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+impl Forwarder {
+    /// Answers reload requests as a run on no interfaces would, until the
+    /// forwarder is dropped
+    pub(crate) fn serve_reloads(&self) -> JoinHandle<()> {
+        let (mut args, mut requests) = self.startup.lock().unwrap().take().unwrap();
+        tokio::spawn(async move {
+            let quotas = Quotas::default();
+            while let Some(request) = requests.recv().await {
+                if let Control::Reload(new, reply) = request {
+                    let _ = reply.send(reload(&mut args, *new, &[], None, &quotas));
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builder_checks_the_options() {
+        let unpaired = Forwarder::builder().external("eth0").build();
+        assert!(matches!(unpaired, Err(Error::InvalidOptions(_))));
+        let conflicting = Forwarder::builder()
+            .bridge("eth0")
+            .bridge("eth1")
+            .dry_run(true);
+        let conflicting = conflicting.external("eth2").internal("eth3").build();
+        assert!(matches!(conflicting, Err(Error::InvalidOptions(_))));
+
+        let forwarder = Forwarder::builder()
+            .external("eth0")
+            .internal("vmbr0")
+            .port(5353)
+            .rule("any udp port 8009 forward".parse().unwrap())
+            .filter("udp and port 5353".parse().unwrap())
+            .build()
+            .unwrap();
+        let startup = forwarder.startup.lock().unwrap();
+        let (args, _) = startup.as_ref().unwrap();
+        assert_eq!(interface_pairs(args).len(), 1);
+        assert_eq!(args.ports, [5353]);
+        assert!(kernel_interest(args, &udp_ports(args)).is_none());
+    }
+
+    #[test]
+    fn reload_swaps_the_filters_but_keeps_the_interfaces() {
+        let options = |external: &str, ports: Vec<u16>| Args {
+            external_iface: Some(external.to_string()),
+            internal_iface: Some("vm0".to_string()),
+            ports,
+            ..Args::default()
+        };
+        let mut args = options("eth0", vec![5000]);
+        let kind = ChainKind::BridgePort {
+            egress: "vm0".to_string(),
+            table: Arc::new(MacTable::new(16, Duration::from_secs(60))),
+        };
+        let chains = [ChainSlot::new(kind, None, &args, &udp_ports(&args))];
+        let quotas = Quotas::default();
+        let filters = || chains[0].filters.load_full();
+
+        // Only a change that needs a restart: nothing is applied
+        let before = filters();
+        let ignored = reload(
+            &mut args,
+            options("eth1", vec![5000]),
+            &chains,
+            None,
+            &quotas,
+        );
+        assert_eq!(ignored.unwrap(), ["external-iface"]);
+        assert_eq!(args.external_iface.as_deref(), Some("eth0"));
+        assert!(Arc::ptr_eq(&before, &filters()));
+
+        // The filter change is, the interface change still is not
+        let ignored = reload(
+            &mut args,
+            options("eth1", vec![6000]),
+            &chains,
+            None,
+            &quotas,
+        );
+        assert_eq!(ignored.unwrap(), ["external-iface"]);
+        assert_eq!(args.external_iface.as_deref(), Some("eth0"));
+        assert_eq!(args.ports, [6000]);
+        assert!(!Arc::ptr_eq(&before, &filters()));
+
+        // Options that do not validate leave the running chain in place
+        let before = filters();
+        let mut invalid = options("eth0", vec![7000]);
+        invalid.internal_iface = None;
+        let error = reload(&mut args, invalid, &chains, None, &quotas).unwrap_err();
+        assert!(error.contains("must be given together"), "{}", error);
+        assert_eq!(args.ports, [6000]);
+        assert_eq!(args.internal_iface.as_deref(), Some("vm0"));
+        assert!(Arc::ptr_eq(&before, &filters()));
+    }
+}
@@ -0,0 +1,122 @@
+//! Entry points for the cargo-fuzz targets in `fuzz/`, which only see the
+//! public API. Compiled under `--cfg fuzzing`, which cargo-fuzz sets.
+
+use crate::filter::{FilterChain, PacketContext, UdpPortFilter, MDNS_PORT, SSDP_PORT, WSD_PORT};
+use crate::mdns::{write_response, MdnsMessage, MdnsRewrite, MdnsServiceFilter, OwnedMessage};
+use crate::pair::Direction;
+use crate::rewrite::RewriteChain;
+use crate::ssdp::{LocationMapping, SsdpLocationRewrite, SsdpMessage, SsdpMessageFilter};
+use crate::summary::PacketSummary;
+use crate::vlan;
+use crate::wsd::{WsdAction, WsdMessageFilter};
+use pnet::packet::ethernet::{EtherTypes, MutableEthernetPacket};
+use pnet::packet::ip::IpNextHeaderProtocols;
+use pnet::packet::ipv4::MutableIpv4Packet;
+use pnet::packet::udp::MutableUdpPacket;
+use std::collections::HashSet;
+use std::net::Ipv4Addr;
+use std::sync::OnceLock;
+
+const INTERNAL: &str = "int0";
+const EXTERNAL: &str = "ext0";
+/// Largest UDP payload of an Ethernet frame without jumbo frames
+const MAX_PAYLOAD: usize = 1472;
+
+fn filters() -> &'static FilterChain {
+    static FILTERS: OnceLock<FilterChain> = OnceLock::new();
+    FILTERS.get_or_init(|| {
+        let mut chain = FilterChain::new();
+        let internal = Some(INTERNAL.to_string());
+        chain.push(SsdpMessageFilter::new(&[], internal.clone(), false, false));
+        chain.push(MdnsServiceFilter::new(
+            &["_googlecast._tcp.local".to_string()],
+            true,
+        ));
+        chain.push(WsdMessageFilter::new(
+            &[WsdAction::Probe, WsdAction::Hello],
+            internal,
+            false,
+            false,
+        ));
+        chain.push(UdpPortFilter::new(HashSet::from([
+            SSDP_PORT, MDNS_PORT, WSD_PORT,
+        ])));
+        chain
+    })
+}
+
+fn rewrites() -> &'static RewriteChain {
+    static REWRITES: OnceLock<RewriteChain> = OnceLock::new();
+    REWRITES.get_or_init(|| {
+        let mappings: Vec<LocationMapping> = ["192.168.100.5=192.0.2.1", "fd00::5=2001:db8::1"]
+            .iter()
+            .map(|mapping| mapping.parse().unwrap())
+            .collect();
+        let mut chain = RewriteChain::new();
+        chain.push(SsdpLocationRewrite::new(&mappings, false, true));
+        chain.push(MdnsRewrite::new(Some(120), true, &["fn".to_string()]));
+        chain
+    })
+}
+
+/// IPv4 frame carrying `payload` from and to `port`
+fn udp_frame(port: u16, payload: &[u8]) -> Vec<u8> {
+    let payload = &payload[..payload.len().min(MAX_PAYLOAD)];
+    let mut frame = vec![0; 14 + 20 + 8 + payload.len()];
+    MutableEthernetPacket::new(&mut frame)
+        .unwrap()
+        .set_ethertype(EtherTypes::Ipv4);
+    let mut ip = MutableIpv4Packet::new(&mut frame[14..]).unwrap();
+    ip.set_version(4);
+    ip.set_header_length(5);
+    ip.set_total_length((20 + 8 + payload.len()) as u16);
+    ip.set_ttl(1);
+    ip.set_next_level_protocol(IpNextHeaderProtocols::Udp);
+    ip.set_source(Ipv4Addr::new(192, 168, 100, 5));
+    ip.set_destination(Ipv4Addr::new(224, 0, 0, 251));
+    let mut udp = MutableUdpPacket::new(&mut frame[34..]).unwrap();
+    udp.set_source(port);
+    udp.set_destination(port);
+    udp.set_length((8 + payload.len()) as u16);
+    udp.set_payload(payload);
+    frame
+}
+
+/// Takes a received frame through untagging, parsing, the filters, the
+/// summary logged for it and the rewrites, in both directions
+pub fn frame(data: &[u8]) {
+    let (frame, tags) = vlan::untag(data);
+    for (ingress, direction) in [
+        (INTERNAL, Direction::Outbound),
+        (EXTERNAL, Direction::Inbound),
+    ] {
+        let Some(mut ctx) = PacketContext::parse(ingress, direction, &frame) else {
+            return;
+        };
+        ctx.vlan = tags.id();
+        let result = filters().evaluate(&ctx).map(|_| ());
+        PacketSummary::new(&ctx, "out0", "pair", direction, result).to_string();
+    }
+    let mut rewritten = frame.into_owned();
+    let _ = rewrites().apply(&mut rewritten);
+}
+
+/// Parses an mDNS message every way the forwarder does, checks that a
+/// response written from the records parses again and rewrites it
+pub fn mdns(data: &[u8]) {
+    let _ = MdnsMessage::parse(data);
+    if let Some(message) = OwnedMessage::parse(data) {
+        let response = write_response(&message.answers, &message.additional);
+        assert!(
+            OwnedMessage::parse(&response).is_some(),
+            "written response does not parse"
+        );
+    }
+    let _ = rewrites().apply(&mut udp_frame(MDNS_PORT, data));
+}
+
+/// Parses an SSDP message and rewrites its LOCATION
+pub fn ssdp(data: &[u8]) {
+    let _ = SsdpMessage::parse(data);
+    let _ = rewrites().apply(&mut udp_frame(SSDP_PORT, data));
+}
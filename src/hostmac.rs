@@ -0,0 +1,281 @@
+//! Learning the MACs of internal hosts and addressing unicast frames sent
+//! inwards to them.
+
+use crate::dhcp::DHCP_CLIENT_PORT;
+use crate::rewrite::{Rewrite, UdpDatagram};
+use crate::state;
+use pnet::packet::ethernet::{EtherTypes, EthernetPacket, MutableEthernetPacket};
+use pnet::packet::ipv4::Ipv4Packet;
+use pnet::util::MacAddr;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::Hash;
+use std::net::Ipv4Addr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use tracing::debug;
+
+const ETHERNET_HEADER_LEN: usize = 14;
+
+/// Learned host as kept in the state file
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct SavedHost<A = Ipv4Addr> {
+    pub ip: A,
+    pub mac: MacAddr,
+    #[serde(with = "humantime_serde")]
+    pub seen: SystemTime,
+}
+
+/// IPv4 address to MAC mappings of hosts on the internal side, learned
+/// from the frames they send out. Also holds IPv6 addresses for the NDP
+/// proxy.
+pub struct HostMacTable<A = Ipv4Addr> {
+    max_entries: usize,
+    ttl: Duration,
+    entries: Mutex<HashMap<A, (MacAddr, Instant)>>,
+    /// Unicast frames dropped because their destination was not learned
+    unresolved: AtomicU64,
+}
+
+impl<A: Copy + Eq + Hash + fmt::Display> HostMacTable<A> {
+    pub fn new(max_entries: usize, ttl: Duration) -> Self {
+        HostMacTable {
+            max_entries,
+            ttl,
+            entries: Mutex::new(HashMap::new()),
+            unresolved: AtomicU64::new(0),
+        }
+    }
+
+    pub fn learn(&self, ip: A, mac: MacAddr) {
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= self.max_entries && !entries.contains_key(&ip) {
+            entries.retain(|_, (_, seen)| now.duration_since(*seen) < self.ttl);
+            if entries.len() >= self.max_entries {
+                // Evict the least recently seen host to keep the table bounded
+                if let Some(oldest) = entries
+                    .iter()
+                    .min_by_key(|(_, (_, seen))| *seen)
+                    .map(|(ip, _)| *ip)
+                {
+                    entries.remove(&oldest);
+                }
+            }
+        }
+        if let Some((old, _)) = entries.insert(ip, (mac, now)) {
+            if old != mac {
+                debug!("Host {} moved from {} to {}", ip, old, mac);
+            }
+        } else {
+            debug!("Learned host {} at {}", ip, mac);
+        }
+    }
+
+    pub fn lookup(&self, ip: A) -> Option<MacAddr> {
+        let entries = self.entries.lock().unwrap();
+        entries
+            .get(&ip)
+            .filter(|(_, seen)| seen.elapsed() < self.ttl)
+            .map(|(mac, _)| *mac)
+    }
+
+    /// Learned hosts that have not aged out, for the state file
+    pub fn save(&self, now: Instant, wall: SystemTime) -> Vec<SavedHost<A>> {
+        let entries = self.entries.lock().unwrap();
+        entries
+            .iter()
+            .filter(|(_, (_, seen))| now.duration_since(*seen) < self.ttl)
+            .map(|(ip, (mac, seen))| SavedHost {
+                ip: *ip,
+                mac: *mac,
+                seen: state::wall_time(*seen, now, wall),
+            })
+            .collect()
+    }
+
+    /// Adds the saved hosts that have not aged out meanwhile, those seen
+    /// last first while there is room
+    pub fn restore(&self, mut saved: Vec<SavedHost<A>>, now: Instant, wall: SystemTime) {
+        saved.sort_by_key(|host| std::cmp::Reverse(host.seen));
+        let mut entries = self.entries.lock().unwrap();
+        for host in saved {
+            let Some(seen) = state::instant(host.seen, now, wall) else {
+                continue;
+            };
+            if now.duration_since(seen) >= self.ttl || entries.len() >= self.max_entries {
+                continue;
+            }
+            entries.entry(host.ip).or_insert((host.mac, seen));
+        }
+    }
+
+    /// Learned hosts that have not aged out, one line each, followed by the
+    /// number of frames dropped for lack of an entry
+    pub fn state(&self) -> Vec<String> {
+        let mut lines = self.hosts();
+        lines.push(format!(
+            "{} unicast frame(s) dropped without a learned MAC",
+            self.unresolved.load(Ordering::Relaxed)
+        ));
+        lines
+    }
+
+    /// Learned hosts that have not aged out, one line each
+    pub fn hosts(&self) -> Vec<String> {
+        let entries = self.entries.lock().unwrap();
+        let mut hosts: Vec<String> = entries
+            .iter()
+            .filter(|(_, (_, seen))| seen.elapsed() < self.ttl)
+            .map(|(ip, (mac, seen))| {
+                let age = Duration::from_secs(seen.elapsed().as_secs());
+                format!(
+                    "{} at {}, seen {} ago",
+                    ip,
+                    mac,
+                    humantime::format_duration(age)
+                )
+            })
+            .collect();
+        hosts.sort();
+        hosts
+    }
+}
+
+/// Source MAC and IPv4 address of an IPv4 frame
+pub fn ipv4_source(frame: &[u8]) -> Option<(MacAddr, Ipv4Addr)> {
+    let eth = EthernetPacket::new(frame)?;
+    if eth.get_ethertype() != EtherTypes::Ipv4 {
+        return None;
+    }
+    let ip = Ipv4Packet::new(&frame[ETHERNET_HEADER_LEN..])?;
+    Some((eth.get_source(), ip.get_source()))
+}
+
+/// Records the source MAC of IPv4 frames leaving the internal side. Goes
+/// ahead of source NAT so the internal address is learned.
+pub struct LearnHostMac {
+    table: Arc<HostMacTable>,
+}
+
+impl LearnHostMac {
+    pub fn new(table: Arc<HostMacTable>) -> Self {
+        LearnHostMac { table }
+    }
+}
+
+impl Rewrite for LearnHostMac {
+    fn name(&self) -> &str {
+        "learn-host-mac"
+    }
+
+    fn apply(&self, frame: &mut Vec<u8>) -> bool {
+        if let Some((mac, ip)) = ipv4_source(frame) {
+            let unicast_ip = !(ip.is_unspecified() || ip.is_multicast() || ip.is_broadcast());
+            if unicast_ip && !mac.is_multicast() {
+                self.table.learn(ip, mac);
+            }
+        }
+        true
+    }
+}
+
+/// Addresses unicast IPv4 frames sent towards the internal side to the MAC
+/// learned for their destination address, with the internal interface as
+/// source. Without a learned MAC the frame is dropped, as the host would
+/// discard a frame still carrying the external gateway as destination.
+/// Group frames and replies to DHCP clients, addressed by the relay, are
+/// left alone. Goes after reverse NAT, which restores the internal
+/// destination address.
+pub struct UnicastMac {
+    mac: MacAddr,
+    table: Arc<HostMacTable>,
+}
+
+impl UnicastMac {
+    pub fn new(mac: MacAddr, table: Arc<HostMacTable>) -> Self {
+        UnicastMac { mac, table }
+    }
+}
+
+impl Rewrite for UnicastMac {
+    fn name(&self) -> &str {
+        "unicast-mac"
+    }
+
+    fn apply(&self, frame: &mut Vec<u8>) -> bool {
+        let Some(eth) = EthernetPacket::new(frame) else {
+            return false;
+        };
+        if eth.get_ethertype() != EtherTypes::Ipv4 || eth.get_destination().is_multicast() {
+            return true;
+        }
+        let Some(ip) = Ipv4Packet::new(&frame[ETHERNET_HEADER_LEN..]) else {
+            return true;
+        };
+        let destination = ip.get_destination();
+        if destination.is_multicast() || destination.is_broadcast() {
+            return true;
+        }
+        if UdpDatagram::locate(frame).is_some_and(|udp| udp.ports(frame)[1] == DHCP_CLIENT_PORT) {
+            return true;
+        }
+        let Some(mac) = self.table.lookup(destination) else {
+            self.table.unresolved.fetch_add(1, Ordering::Relaxed);
+            debug!("No MAC learned for {}, unicast frame dropped", destination);
+            return false;
+        };
+        let mut eth = MutableEthernetPacket::new(frame).expect("header was parsed above");
+        eth.set_destination(mac);
+        eth.set_source(self.mac);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filter::SSDP_PORT;
+    use crate::testutil::{udp_frame, HOST_MAC};
+
+    fn frame(destination: MacAddr, source: Ipv4Addr, to: Ipv4Addr) -> Vec<u8> {
+        udp_frame(HOST_MAC, destination, source, to, 50000, SSDP_PORT, &[])
+    }
+
+    #[test]
+    fn rewrites_unicast_to_learned_host() {
+        let table = Arc::new(HostMacTable::new(16, Duration::from_secs(60)));
+        let own = MacAddr::new(2, 0, 0, 0, 0, 2);
+        let host = Ipv4Addr::new(192, 168, 100, 7);
+        let gateway = MacAddr::new(2, 0, 0, 0, 0, 9);
+        let remote = Ipv4Addr::new(10, 0, 0, 1);
+        let unicast = UnicastMac::new(own, table.clone());
+
+        let mut reply = frame(gateway, remote, host);
+        assert!(!unicast.apply(&mut reply));
+
+        let mut request = frame(
+            MacAddr::broadcast(),
+            host,
+            Ipv4Addr::new(239, 255, 255, 250),
+        );
+        assert!(LearnHostMac::new(table.clone()).apply(&mut request));
+        assert!(unicast.apply(&mut reply));
+        let eth = EthernetPacket::new(&reply).unwrap();
+        assert_eq!(eth.get_destination(), MacAddr::new(2, 0, 0, 0, 0, 1));
+        assert_eq!(eth.get_source(), own);
+
+        // Multicast is left as it is
+        let mut notify = frame(
+            MacAddr::new(1, 0, 0x5e, 0x7f, 0xff, 0xfa),
+            remote,
+            Ipv4Addr::new(239, 255, 255, 250),
+        );
+        let original = notify.clone();
+        assert!(unicast.apply(&mut notify));
+        assert_eq!(notify, original);
+        assert_eq!(table.state().len(), 2);
+    }
+}
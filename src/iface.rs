@@ -0,0 +1,838 @@
+//! Network interface lookup and per-interface socket options.
+
+use crate::error::Error;
+use crate::iplink::{IpSink, IpSource, LinkType};
+use crate::kernelfilter::KernelFilter;
+use crate::link::{PacketSink, PacketSource};
+use crate::netns::{self, NetNs};
+use crate::packetsocket;
+#[cfg(feature = "af-xdp")]
+use crate::xdp;
+use clap::ValueEnum;
+use pnet::datalink::{self, Channel, NetworkInterface};
+use serde::{Deserialize, Serialize};
+use socket2::{Domain, InterfaceIndexOrAddress, Protocol, SockRef, Socket, Type};
+use std::fmt;
+use std::io;
+use std::mem;
+use std::net::IpAddr;
+use std::os::fd::{AsRawFd, BorrowedFd, IntoRawFd, RawFd};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::time::Instant;
+use tracing::{info, warn};
+
+/// Delay between interface lookups while waiting for them to appear
+const WAIT_POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// Link type of Ethernet interfaces
+const ARPHRD_ETHER: u16 = libc::ARPHRD_ETHER;
+/// MTU assumed for interfaces that do not report one
+pub const ETHERNET_MTU: u32 = 1500;
+/// Largest MTU frames are read in full for, that of jumbo frames, where
+/// loopback reports 65536
+pub const MAX_READ_MTU: u32 = 9216;
+
+/// Whether the kernel accepts `name` for a network interface: one to 15
+/// bytes, without slashes, colons or whitespace, and not `.` or `..`
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() < libc::IFNAMSIZ
+        && name != "."
+        && name != ".."
+        && !name.contains(|c: char| c == '/' || c == ':' || c.is_whitespace())
+}
+
+pub fn find_interface(
+    interfaces: &[NetworkInterface],
+    name: &str,
+) -> Result<NetworkInterface, Error> {
+    interfaces
+        .iter()
+        .find(|iface| iface.name == name)
+        .cloned()
+        .ok_or_else(|| Error::InterfaceNotFound {
+            name: name.to_string(),
+            available: interfaces
+                .iter()
+                .map(|iface| iface.name.as_str())
+                .collect::<Vec<_>>()
+                .join(", "),
+        })
+}
+
+/// Polls the interfaces of `netns`, or of the forwarder's own namespace,
+/// until all of `names` exist, giving up after `timeout` if one is set.
+/// Returns the interface list seen last.
+pub async fn wait_for_interfaces(
+    names: &[&str],
+    netns: Option<&NetNs>,
+    timeout: Option<Duration>,
+) -> Result<Vec<NetworkInterface>, Error> {
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+    let mut attempt = 1;
+    loop {
+        let interfaces = netns::within(netns, datalink::interfaces)?;
+        let missing: Vec<&str> = names
+            .iter()
+            .copied()
+            .filter(|name| !interfaces.iter().any(|iface| iface.name == *name))
+            .collect();
+        if missing.is_empty() {
+            return Ok(interfaces);
+        }
+        if deadline.is_some_and(|deadline| Instant::now() + WAIT_POLL_INTERVAL > deadline) {
+            return Err(Error::InterfaceWaitTimeout {
+                names: missing.join(", "),
+                timeout: timeout.unwrap_or_default(),
+            });
+        }
+        info!(
+            "Waiting for interface(s) {} to appear (attempt {})",
+            missing.join(", "),
+            attempt
+        );
+        attempt += 1;
+        tokio::time::sleep(WAIT_POLL_INTERVAL).await;
+    }
+}
+
+/// Sending and receiving halves of an Ethernet datalink channel
+pub type EthernetChannel = (Box<dyn PacketSink>, Box<dyn PacketSource>);
+
+/// How interfaces are driven
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    /// pnet datalink channels, one system call per frame
+    Pnet,
+    /// Packet sockets receiving and sending batches of frames per system
+    /// call (experimental)
+    Raw,
+    /// AF_XDP sockets for the frames to the forwarded ports, packet sockets
+    /// for the rest (experimental, needs the `af-xdp` feature)
+    #[serde(rename = "af-xdp")]
+    AfXdp,
+}
+
+/// Option value for one interface, or for every interface without one of
+/// its own, written as `[IFACE=]VALUE`
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(
+    try_from = "String",
+    into = "String",
+    bound(deserialize = "T: InterfaceValue", serialize = "T: InterfaceValue")
+)]
+pub struct PerInterface<T> {
+    pub iface: Option<String>,
+    pub value: T,
+}
+
+/// Values given per interface with [`PerInterface`]
+pub trait InterfaceValue: Sized + Clone {
+    fn parse(value: &str) -> Result<Self, String>;
+    fn format(&self) -> String;
+}
+
+/// Sizes in bytes, with an optional `K`, `M` or `G` suffix
+impl InterfaceValue for u64 {
+    fn parse(value: &str) -> Result<Self, String> {
+        crate::pcap::parse_size(value)
+    }
+
+    fn format(&self) -> String {
+        self.to_string()
+    }
+}
+
+impl InterfaceValue for Duration {
+    fn parse(value: &str) -> Result<Self, String> {
+        match humantime::parse_duration(value.trim()) {
+            Ok(duration) if duration.is_zero() => {
+                Err("duration must be greater than zero".to_string())
+            }
+            Ok(duration) => Ok(duration),
+            Err(e) => Err(format!("'{}' is not a duration: {}", value, e)),
+        }
+    }
+
+    fn format(&self) -> String {
+        humantime::format_duration(*self).to_string()
+    }
+}
+
+impl<T: InterfaceValue> PerInterface<T> {
+    /// Value for `iface`, if one applies
+    pub fn lookup<'a>(values: &'a [PerInterface<T>], iface: &str) -> Option<&'a T> {
+        let own = values
+            .iter()
+            .find(|value| value.iface.as_deref() == Some(iface));
+        own.or_else(|| values.iter().find(|value| value.iface.is_none()))
+            .map(|value| &value.value)
+    }
+}
+
+impl<T: InterfaceValue> FromStr for PerInterface<T> {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (iface, value) = match value.split_once('=') {
+            Some((iface, value)) => (Some(iface.trim().to_string()), value),
+            None => (None, value),
+        };
+        Ok(PerInterface {
+            iface,
+            value: T::parse(value)?,
+        })
+    }
+}
+
+impl<T: InterfaceValue> TryFrom<String> for PerInterface<T> {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl<T: InterfaceValue> From<PerInterface<T>> for String {
+    fn from(value: PerInterface<T>) -> Self {
+        value.to_string()
+    }
+}
+
+impl<T: InterfaceValue> fmt::Display for PerInterface<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(iface) = &self.iface {
+            write!(f, "{}=", iface)?;
+        }
+        write!(f, "{}", self.value.format())
+    }
+}
+
+/// Options for opening the channel of an interface
+#[derive(Clone, Copy)]
+pub struct ChannelConfig {
+    pub datalink: datalink::Config,
+    pub backend: Backend,
+    /// Frames received or sent per system call by the raw backend
+    pub batch_size: usize,
+    /// Kernel receive and send buffers of the packet socket, in bytes, or
+    /// the system defaults
+    pub rx_buffer: Option<usize>,
+    pub tx_buffer: Option<usize>,
+    pub link_type: LinkType,
+}
+
+impl Default for ChannelConfig {
+    fn default() -> Self {
+        ChannelConfig {
+            datalink: datalink::Config::default(),
+            backend: Backend::Pnet,
+            batch_size: 1,
+            rx_buffer: None,
+            tx_buffer: None,
+            link_type: LinkType::Ethernet,
+        }
+    }
+}
+
+/// Frames the kernel dropped on the packet socket of an interface because
+/// its receive buffer was full. The kernel resets its count on every read,
+/// so the reads are summed up here.
+#[derive(Debug, Default)]
+pub struct KernelDrops {
+    /// Socket of the channel, with its `/proc/self/fd` link to tell it
+    /// apart from a later socket on the same descriptor
+    socket: Mutex<Option<(RawFd, PathBuf)>>,
+    dropped: AtomicU64,
+}
+
+impl KernelDrops {
+    /// Counts the drops of `socket` from now on, instead of an earlier one
+    pub fn watch(&self, socket: RawFd) {
+        let mut current = self.socket.lock().unwrap();
+        self.read(&mut current);
+        *current = fd_link(socket).ok().map(|link| (socket, link));
+        // Whatever the socket dropped before is not ours to count
+        let _ = packet_statistics(socket);
+    }
+
+    pub fn dropped(&self) -> u64 {
+        self.read(&mut self.socket.lock().unwrap());
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    pub fn reset(&self) {
+        self.read(&mut self.socket.lock().unwrap());
+        self.dropped.store(0, Ordering::Relaxed);
+    }
+
+    fn read(&self, socket: &mut Option<(RawFd, PathBuf)>) {
+        let Some((fd, link)) = socket.as_ref() else {
+            return;
+        };
+        if !fd_link(*fd).is_ok_and(|current| current == *link) {
+            // The channel is closed, its last drops are lost with it
+            *socket = None;
+            return;
+        }
+        if let Ok(stats) = packet_statistics(*fd) {
+            self.dropped
+                .fetch_add(u64::from(stats.tp_drops), Ordering::Relaxed);
+        }
+    }
+}
+
+/// Reads and resets the counters of a packet socket
+fn packet_statistics(socket: RawFd) -> io::Result<libc::tpacket_stats> {
+    // SAFETY: tpacket_stats is plain data, valid when zeroed, and the
+    // length passed along is its size
+    unsafe {
+        let mut stats: libc::tpacket_stats = mem::zeroed();
+        let mut len = mem::size_of_val(&stats) as libc::socklen_t;
+        if libc::getsockopt(
+            socket,
+            libc::SOL_PACKET,
+            libc::PACKET_STATISTICS,
+            (&mut stats as *mut libc::tpacket_stats).cast(),
+            &mut len,
+        ) != 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(stats)
+    }
+}
+
+/// `/proc/self/fd` link of a descriptor, which tells the object it refers to
+pub fn fd_link(fd: RawFd) -> io::Result<PathBuf> {
+    std::fs::read_link(format!("/proc/self/fd/{}", fd))
+}
+
+/// Sets the kernel buffers of `socket` to the configured sizes, beyond
+/// `net.core.rmem_max` and `net.core.wmem_max` where allowed to, and logs
+/// the sizes the kernel settled on
+fn size_buffers(socket: RawFd, config: &ChannelConfig, iface: &str) {
+    let buffers = [
+        (config.rx_buffer, libc::SO_RCVBUFFORCE, libc::SO_RCVBUF),
+        (config.tx_buffer, libc::SO_SNDBUFFORCE, libc::SO_SNDBUF),
+    ];
+    for (size, force, option) in buffers {
+        let Some(size) = size else {
+            continue;
+        };
+        // The kernel doubles the value for its bookkeeping
+        let value = size.min(i32::MAX as usize / 2) as libc::c_int;
+        let set = |option| {
+            // SAFETY: the value is a plain int of the length passed along
+            unsafe {
+                libc::setsockopt(
+                    socket,
+                    libc::SOL_SOCKET,
+                    option,
+                    (&value as *const libc::c_int).cast(),
+                    mem::size_of_val(&value) as libc::socklen_t,
+                ) == 0
+            }
+        };
+        if !set(force) && !set(option) {
+            warn!(
+                "Socket buffer of {} bytes not set on {}: {}",
+                size,
+                iface,
+                io::Error::last_os_error()
+            );
+        }
+    }
+    // SAFETY: the descriptor is open for as long as the channel being
+    // opened, and only borrowed for this call
+    let socket = unsafe { BorrowedFd::borrow_raw(socket) };
+    let socket = SockRef::from(&socket);
+    let size = |size: io::Result<usize>| size.map_or("unknown".to_string(), |s| s.to_string());
+    info!(
+        "Channel of {}: receive buffer {} bytes, send buffer {} bytes, read timeout {}",
+        iface,
+        size(socket.recv_buffer_size()),
+        size(socket.send_buffer_size()),
+        config
+            .datalink
+            .read_timeout
+            .map_or("none".to_string(), |timeout| timeout.format())
+    );
+}
+
+/// Opens an Ethernet channel on `iface` with the configured backend, on a
+/// socket with the kernel filter attached if one is given. The frames the
+/// kernel drops on the socket are counted in `drops` if given. The packets
+/// of an IP interface are framed and unframed on the way, without the
+/// kernel filter, which looks for Ethernet headers.
+pub fn open_channel(
+    iface: &NetworkInterface,
+    config: ChannelConfig,
+    kernel_filter: Option<&KernelFilter>,
+    drops: Option<&KernelDrops>,
+) -> Result<EthernetChannel, Error> {
+    if config.link_type == LinkType::Ip {
+        let (tx, rx) = open_link(iface, config, None, drops)?;
+        return Ok((Box::new(IpSink::new(tx)), Box::new(IpSource::new(rx))));
+    }
+    open_link(iface, config, kernel_filter, drops)
+}
+
+/// Opens the channel of [`open_channel`] as the interface frames it
+fn open_link(
+    iface: &NetworkInterface,
+    config: ChannelConfig,
+    kernel_filter: Option<&KernelFilter>,
+    drops: Option<&KernelDrops>,
+) -> Result<EthernetChannel, Error> {
+    let failed = |e| channel_error(iface, e);
+    #[cfg(feature = "af-xdp")]
+    let fast = match (config.backend, kernel_filter) {
+        (Backend::AfXdp, Some(filter)) => {
+            match xdp::FastPath::open(iface, filter.interest().as_ref()) {
+                Ok(fast) => Some(fast),
+                Err(e) => {
+                    warn!(
+                        "AF_XDP unavailable on {}, falling back to the pnet backend: {}",
+                        iface.name, e
+                    );
+                    None
+                }
+            }
+        }
+        (Backend::AfXdp, None) => {
+            warn!(
+                "AF_XDP needs the kernel filter to tell the forwarded ports, falling back to the pnet backend on {}",
+                iface.name
+            );
+            None
+        }
+        _ => None,
+    };
+    let mut datalink = config.datalink;
+    let socket = match kernel_filter {
+        Some(filter) => filter.open(iface),
+        // Protocol 0 like the kernel filter's, the channel binds it
+        None => Socket::new(Domain::PACKET, Type::RAW, None).map(IntoRawFd::into_raw_fd),
+    };
+    let socket = socket.map_err(failed)?;
+    size_buffers(socket, &config, &iface.name);
+    if let Some(drops) = drops {
+        drops.watch(socket);
+    }
+    datalink.socket_fd = Some(socket);
+    #[cfg(feature = "af-xdp")]
+    if let Some(fast) = fast {
+        // The sockets are polled together, the packet socket only read
+        let (tx, rx) = packetsocket::open(
+            iface,
+            datalink.socket_fd,
+            datalink.promiscuous,
+            Some(Duration::ZERO),
+            datalink.read_buffer_size,
+            config.batch_size,
+        )
+        .map_err(failed)?;
+        if let Some(filter) = kernel_filter {
+            filter.track(fast.program());
+        }
+        let (tx, rx) = fast.channel(tx, rx, datalink.read_timeout, config.batch_size);
+        return Ok((Box::new(tx), Box::new(rx)));
+    }
+    if config.backend == Backend::Raw {
+        let (tx, rx) = packetsocket::open(
+            iface,
+            datalink.socket_fd,
+            datalink.promiscuous,
+            datalink.read_timeout,
+            datalink.read_buffer_size,
+            config.batch_size,
+        )
+        .map_err(failed)?;
+        return Ok((Box::new(tx), Box::new(rx)));
+    }
+    match datalink::channel(iface, datalink) {
+        Ok(Channel::Ethernet(tx, rx)) => Ok((Box::new(tx), Box::new(rx))),
+        Ok(_) => Err(Error::UnsupportedChannel(iface.name.clone())),
+        Err(e) => Err(failed(e)),
+    }
+}
+
+/// Opens only the sending half of a channel on `iface`, for an interface
+/// whose frames come from elsewhere, such as a replayed trace
+pub fn open_sink(
+    iface: &NetworkInterface,
+    config: ChannelConfig,
+) -> Result<Box<dyn PacketSink>, Error> {
+    let sink =
+        packetsocket::open_sink(iface, config.batch_size).map_err(|e| channel_error(iface, e))?;
+    if config.link_type == LinkType::Ip {
+        return Ok(Box::new(IpSink::new(Box::new(sink))));
+    }
+    Ok(Box::new(sink))
+}
+
+fn channel_error(iface: &NetworkInterface, e: io::Error) -> Error {
+    match e.kind() {
+        io::ErrorKind::PermissionDenied => Error::PermissionDenied {
+            iface: iface.name.clone(),
+            source: e,
+        },
+        _ => Error::Channel {
+            iface: iface.name.clone(),
+            source: e,
+        },
+    }
+}
+
+/// Multicast group memberships held on one interface. The kernel keeps
+/// delivering the groups to the NIC for as long as the helper sockets are
+/// open; dropping this leaves all groups again.
+pub struct MulticastMembership {
+    iface: String,
+    sockets: Vec<Socket>,
+}
+
+impl MulticastMembership {
+    /// Joins every group in `groups` on `iface`
+    pub fn join(iface: &NetworkInterface, groups: &[IpAddr]) -> Result<Self, Error> {
+        Self::join_all(iface, groups).map_err(|source| Error::Multicast {
+            iface: iface.name.clone(),
+            source,
+        })
+    }
+
+    fn join_all(iface: &NetworkInterface, groups: &[IpAddr]) -> io::Result<Self> {
+        let mut sockets = Vec::new();
+        for group in groups {
+            let socket = match group {
+                IpAddr::V4(group) => {
+                    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+                    socket
+                        .join_multicast_v4_n(group, &InterfaceIndexOrAddress::Index(iface.index))?;
+                    socket
+                }
+                IpAddr::V6(group) => {
+                    let socket = Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::UDP))?;
+                    socket.join_multicast_v6(group, iface.index)?;
+                    socket
+                }
+            };
+            info!("Joined multicast group {} on {}", group, iface.name);
+            sockets.push(socket);
+        }
+        Ok(MulticastMembership {
+            iface: iface.name.clone(),
+            sockets,
+        })
+    }
+}
+
+impl Drop for MulticastMembership {
+    fn drop(&mut self) {
+        if !self.sockets.is_empty() {
+            info!(
+                "Leaving {} multicast group(s) on {}",
+                self.sockets.len(),
+                self.iface
+            );
+        }
+    }
+}
+
+/// What happens when a forwarding interface is down or has no link at
+/// startup
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LinkDown {
+    /// Warn, and forward once the link comes up
+    Warn,
+    /// Refuse to start
+    Fail,
+}
+
+/// Asks the kernel about the interface called `name` with the ioctl
+/// `request`. The answer comes from the network namespace of the calling
+/// thread, where `/sys/class/net` would show the one sysfs was mounted in.
+fn query_interface(name: &str, request: libc::c_ulong) -> Option<libc::ifreq> {
+    if name.len() >= libc::IFNAMSIZ {
+        return None;
+    }
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, None).ok()?;
+    // SAFETY: ifreq is plain data, valid when zeroed; the name fits with
+    // its terminating zero as checked above
+    let mut ifreq: libc::ifreq = unsafe { mem::zeroed() };
+    for (to, from) in ifreq.ifr_name.iter_mut().zip(name.bytes()) {
+        *to = from as libc::c_char;
+    }
+    // SAFETY: the request only reads the name and fills in the union
+    if unsafe { libc::ioctl(socket.as_raw_fd(), request as _, &mut ifreq) } != 0 {
+        return None;
+    }
+    Some(ifreq)
+}
+
+/// Link type of the interface called `name`, if it reports one
+fn link_type(name: &str) -> Option<u16> {
+    let ifreq = query_interface(name, libc::SIOCGIFHWADDR)?;
+    // SAFETY: SIOCGIFHWADDR fills in the hardware address
+    Some(unsafe { ifreq.ifr_ifru.ifru_hwaddr.sa_family })
+}
+
+/// Refuses an interface frames cannot be forwarded on: a loopback one or
+/// one not of the expected link type, such as a WireGuard tunnel where
+/// Ethernet frames are expected. One that is down or has no link is warned
+/// about, or refused if `link_down` says so.
+pub fn check_interface(
+    iface: &NetworkInterface,
+    expected: LinkType,
+    link_down: LinkDown,
+) -> Result<(), Error> {
+    let unusable = |reason| Error::UnusableInterface {
+        iface: iface.name.clone(),
+        reason,
+    };
+    if iface.is_loopback() {
+        return Err(unusable("it is a loopback interface"));
+    }
+    let ethernet = link_type(&iface.name).map(|link_type| link_type == ARPHRD_ETHER);
+    match (expected, ethernet) {
+        (LinkType::Ethernet, Some(false)) => {
+            return Err(unusable("it does not carry Ethernet frames"))
+        }
+        (LinkType::Ip, Some(true)) => return Err(unusable("it carries Ethernet frames")),
+        _ => {}
+    }
+    let (state, reason) = if !iface.is_up() {
+        ("is down", "it is down")
+    } else if !iface.is_running() {
+        ("has no link", "it has no link")
+    } else {
+        return Ok(());
+    };
+    match link_down {
+        LinkDown::Warn => {
+            warn!(
+                "Interface {} {}, nothing is forwarded on it until it comes up",
+                iface.name, state
+            );
+            Ok(())
+        }
+        LinkDown::Fail => Err(unusable(reason)),
+    }
+}
+
+/// MTU of the interface called `name`, if it reports one
+pub fn mtu(name: &str) -> Option<u32> {
+    let ifreq = query_interface(name, libc::SIOCGIFMTU)?;
+    // SAFETY: SIOCGIFMTU fills in the MTU
+    u32::try_from(unsafe { ifreq.ifr_ifru.ifru_mtu }).ok()
+}
+
+/// Addresses and state of one interface, as shown by `list-interfaces`
+#[derive(Debug, Serialize)]
+pub struct InterfaceInfo {
+    pub name: String,
+    pub index: u32,
+    pub mac: Option<String>,
+    pub ipv4: Vec<String>,
+    pub ipv6: Vec<String>,
+    pub up: bool,
+    pub running: bool,
+    pub loopback: bool,
+    pub mtu: Option<u32>,
+}
+
+impl InterfaceInfo {
+    pub fn new(iface: &NetworkInterface) -> Self {
+        let addresses = |v4: bool| {
+            iface
+                .ips
+                .iter()
+                .filter(|ip| ip.is_ipv4() == v4)
+                .map(|ip| format!("{}/{}", ip.ip(), ip.prefix()))
+                .collect()
+        };
+        let mtu = mtu(&iface.name);
+        InterfaceInfo {
+            name: iface.name.clone(),
+            index: iface.index,
+            mac: iface.mac.map(|mac| mac.to_string()),
+            ipv4: addresses(true),
+            ipv6: addresses(false),
+            up: iface.is_up(),
+            running: iface.is_running(),
+            loopback: iface.is_loopback(),
+            mtu,
+        }
+    }
+}
+
+/// Renders `interfaces` as an aligned text table
+pub fn interface_table(interfaces: &[InterfaceInfo]) -> String {
+    let header = ["NAME", "INDEX", "MAC", "MTU", "FLAGS", "ADDRESSES"].map(String::from);
+    let rows: Vec<[String; 6]> = interfaces
+        .iter()
+        .map(|info| {
+            let flags: Vec<&str> = [
+                (info.up, "up"),
+                (info.running, "running"),
+                (info.loopback, "loopback"),
+            ]
+            .into_iter()
+            .filter_map(|(set, flag)| set.then_some(flag))
+            .collect();
+            let addresses: Vec<&str> = info
+                .ipv4
+                .iter()
+                .chain(&info.ipv6)
+                .map(String::as_str)
+                .collect();
+            [
+                info.name.clone(),
+                info.index.to_string(),
+                info.mac.clone().unwrap_or_else(|| "-".to_string()),
+                info.mtu.map_or("-".to_string(), |mtu| mtu.to_string()),
+                flags.join(","),
+                addresses.join(" "),
+            ]
+        })
+        .collect();
+    let mut widths = header.clone().map(|column| column.len());
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+    let mut table = String::new();
+    for row in std::iter::once(&header).chain(&rows) {
+        let cells: Vec<String> = row
+            .iter()
+            .zip(widths)
+            .map(|(cell, width)| format!("{:width$}", cell))
+            .collect();
+        table.push_str(cells.join("  ").trim_end());
+        table.push('\n');
+    }
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refuses_loopback_and_optionally_down_interfaces() {
+        let loopback = datalink::interfaces()
+            .into_iter()
+            .find(|iface| iface.is_loopback())
+            .expect("a loopback interface");
+        for link_down in [LinkDown::Warn, LinkDown::Fail] {
+            assert!(matches!(
+                check_interface(&loopback, LinkType::Ethernet, link_down),
+                Err(Error::UnusableInterface { .. })
+            ));
+        }
+
+        let down = NetworkInterface {
+            name: "absent0".to_string(),
+            description: String::new(),
+            index: 0,
+            mac: None,
+            ips: Vec::new(),
+            flags: 0,
+        };
+        assert!(check_interface(&down, LinkType::Ethernet, LinkDown::Warn).is_ok());
+        let refused = check_interface(&down, LinkType::Ethernet, LinkDown::Fail).unwrap_err();
+        assert!(
+            refused.to_string().contains("list-interfaces"),
+            "{}",
+            refused
+        );
+    }
+
+    #[test]
+    fn values_apply_to_their_interface_or_all() {
+        let sizes: Vec<PerInterface<u64>> = ["4M", "eth0=512K"]
+            .iter()
+            .map(|size| size.parse().unwrap())
+            .collect();
+        assert_eq!(PerInterface::lookup(&sizes, "eth0"), Some(&(512 << 10)));
+        assert_eq!(PerInterface::lookup(&sizes, "eth1"), Some(&(4 << 20)));
+        assert_eq!(sizes[1].to_string(), "eth0=524288");
+        let timeout: PerInterface<Duration> = "eth1=50ms".parse().unwrap();
+        assert_eq!(PerInterface::lookup(&[timeout], "eth0"), None);
+        for invalid in ["eth0=", "0", "eth0=0s", "4X"] {
+            assert!(invalid.parse::<PerInterface<u64>>().is_err(), "{}", invalid);
+        }
+        assert!("0s".parse::<PerInterface<Duration>>().is_err());
+    }
+
+    #[test]
+    fn validates_interface_names() {
+        for valid in ["eth0", "enp0s31f6", "vlan.10", "br-lan", "a23456789012345"] {
+            assert!(is_valid_name(valid), "{}", valid);
+        }
+        for invalid in ["", ".", "..", "eth0:1", "eth 0", "a/b", "a234567890123456"] {
+            assert!(!is_valid_name(invalid), "{}", invalid);
+        }
+    }
+
+    #[tokio::test]
+    async fn waits_only_for_interfaces_that_are_missing() {
+        let loopback = datalink::interfaces()
+            .into_iter()
+            .find(|iface| iface.is_loopback())
+            .expect("a loopback interface");
+        let found = wait_for_interfaces(&[&loopback.name], None, Some(Duration::ZERO))
+            .await
+            .unwrap();
+        assert!(found.iter().any(|iface| iface.name == loopback.name));
+
+        // Gives up without waiting once the next poll would be too late
+        let timeout = WAIT_POLL_INTERVAL / 2;
+        let started = Instant::now();
+        let names = [loopback.name.as_str(), "absent0", "absent1"];
+        match wait_for_interfaces(&names, None, Some(timeout)).await {
+            Err(Error::InterfaceWaitTimeout { names, .. }) => assert_eq!(names, "absent0, absent1"),
+            other => panic!("expected a timeout, got {:?}", other.map(|_| ())),
+        }
+        assert!(started.elapsed() < WAIT_POLL_INTERVAL);
+    }
+
+    #[test]
+    fn lists_interfaces_in_aligned_columns() {
+        let loopback = InterfaceInfo {
+            name: "lo".to_string(),
+            index: 1,
+            mac: None,
+            ipv4: vec!["127.0.0.1/8".to_string()],
+            ipv6: vec!["::1/128".to_string()],
+            up: true,
+            running: true,
+            loopback: true,
+            mtu: Some(65536),
+        };
+        let ethernet = InterfaceInfo {
+            name: "enp0s31f6".to_string(),
+            index: 2,
+            mac: Some("02:00:00:00:00:01".to_string()),
+            ipv4: Vec::new(),
+            ipv6: Vec::new(),
+            up: true,
+            running: false,
+            loopback: false,
+            mtu: None,
+        };
+        assert_eq!(
+            interface_table(&[loopback, ethernet]),
+            "NAME       INDEX  MAC                MTU    FLAGS                ADDRESSES\n\
+             lo         1      -                  65536  up,running,loopback  127.0.0.1/8 ::1/128\n\
+             enp0s31f6  2      02:00:00:00:00:01  -      up\n"
+        );
+    }
+}
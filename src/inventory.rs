@@ -0,0 +1,515 @@
+//! Inventory of the devices announcing themselves over mDNS and SSDP on the
+//! external side, with where and when they were seen, for finding out why a
+//! device does not show up inside.
+
+use crate::filter::{MDNS_PORT, SSDP_PORT};
+use crate::mdns::{
+    self, is_service_type, OwnedMessage, OwnedRecord, CLASS_IN, CLASS_TOP_BIT, TYPE_PTR, TYPE_TXT,
+};
+use crate::rewrite::{Rewrite, UdpDatagram};
+use crate::ssdp::{header, split_message, SsdpKind, SsdpMessage};
+use crate::ssdpcache::max_age;
+use crate::state;
+use pnet::packet::ethernet::{EtherTypes, EthernetPacket};
+use pnet::packet::ipv4::Ipv4Packet;
+use pnet::packet::ipv6::Ipv6Packet;
+use pnet::util::MacAddr;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use tracing::debug;
+
+const ETHERNET_HEADER_LEN: usize = 14;
+/// Lifetime of SSDP announcements without a usable CACHE-CONTROL, the
+/// minimum UPnP allows
+const SSDP_DEFAULT_MAX_AGE: Duration = Duration::from_secs(1800);
+
+/// Where and when a device was seen
+struct Entry {
+    protocol: &'static str,
+    kind: String,
+    name: Option<String>,
+    ip: IpAddr,
+    mac: MacAddr,
+    first_seen: SystemTime,
+    last_seen: SystemTime,
+    /// When the announcement expires, or expired if it was withdrawn
+    expires: Instant,
+}
+
+/// Point-in-time copy of an inventory entry
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct InventoryEntry {
+    /// `ssdp` or `mdns`
+    pub protocol: &'static str,
+    /// USN, or name of the service instance
+    pub id: String,
+    /// Notification or search target, or service type
+    pub kind: String,
+    /// SERVER header, or `fn` key of the TXT record
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    pub ip: IpAddr,
+    pub mac: String,
+    /// RFC 3339 time of the first announcement
+    pub first_seen: String,
+    /// RFC 3339 time of the last announcement or goodbye
+    pub last_seen: String,
+    /// Seconds until the announcement expires, 0 once expired or withdrawn
+    pub expires_in: u64,
+}
+
+/// Inventory entry as kept in the state file
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct SavedDevice {
+    pub protocol: String,
+    pub id: String,
+    pub kind: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    pub ip: IpAddr,
+    pub mac: MacAddr,
+    #[serde(with = "humantime_serde")]
+    pub first_seen: SystemTime,
+    #[serde(with = "humantime_serde")]
+    pub last_seen: SystemTime,
+    #[serde(with = "humantime_serde")]
+    pub expires: SystemTime,
+}
+
+/// Announcement of a device, or its goodbye if the lifetime is zero
+struct Sighting {
+    protocol: &'static str,
+    id: String,
+    kind: String,
+    name: Option<String>,
+    lifetime: Duration,
+}
+
+/// Devices seen announcing themselves, keyed by USN or service instance
+/// name. Entries stay listed for the retention period after their
+/// announcement expired or was withdrawn; when full, the device seen least
+/// recently makes room.
+pub struct DeviceInventory {
+    max_entries: usize,
+    retention: Duration,
+    entries: Mutex<HashMap<String, Entry>>,
+}
+
+impl DeviceInventory {
+    pub fn new(max_entries: usize, retention: Duration) -> Self {
+        DeviceInventory {
+            max_entries,
+            retention,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Records an announcement or goodbye from `ip` and `mac`. Goodbyes of
+    /// devices not in the inventory are ignored.
+    fn see(&self, sighting: Sighting, ip: IpAddr, mac: MacAddr, now: Instant, wall: SystemTime) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|_, entry| entry.expires + self.retention > now);
+        if sighting.lifetime.is_zero() {
+            if let Some(entry) = entries.get_mut(&sighting.id) {
+                if entry.expires > now {
+                    debug!("Device {} said goodbye", sighting.id);
+                }
+                entry.expires = now;
+                entry.last_seen = wall;
+            }
+            return;
+        }
+        if entries.len() >= self.max_entries && !entries.contains_key(&sighting.id) {
+            if let Some(oldest) = entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_seen)
+                .map(|(id, _)| id.clone())
+            {
+                entries.remove(&oldest);
+            }
+        }
+        let entry = entries.entry(sighting.id).or_insert_with_key(|id| {
+            debug!("Device {} seen at {} ({})", id, ip, mac);
+            Entry {
+                protocol: sighting.protocol,
+                kind: String::new(),
+                name: None,
+                ip,
+                mac,
+                first_seen: wall,
+                last_seen: wall,
+                expires: now,
+            }
+        });
+        entry.kind = sighting.kind;
+        entry.name = sighting.name.or(entry.name.take());
+        entry.ip = ip;
+        entry.mac = mac;
+        entry.last_seen = wall;
+        entry.expires = now + sighting.lifetime;
+    }
+
+    /// Names the device `id` if it is in the inventory
+    fn name(&self, id: &str, name: String) {
+        if let Some(entry) = self.entries.lock().unwrap().get_mut(id) {
+            entry.name = Some(name);
+        }
+    }
+
+    /// Devices in the inventory, ordered by ID
+    pub fn entries(&self) -> Vec<InventoryEntry> {
+        let now = Instant::now();
+        let entries = self.entries.lock().unwrap();
+        let mut listed: Vec<InventoryEntry> = entries
+            .iter()
+            .filter(|(_, entry)| entry.expires + self.retention > now)
+            .map(|(id, entry)| InventoryEntry {
+                protocol: entry.protocol,
+                id: id.clone(),
+                kind: entry.kind.clone(),
+                name: entry.name.clone(),
+                ip: entry.ip,
+                mac: entry.mac.to_string(),
+                first_seen: humantime::format_rfc3339_seconds(entry.first_seen).to_string(),
+                last_seen: humantime::format_rfc3339_seconds(entry.last_seen).to_string(),
+                expires_in: entry.expires.saturating_duration_since(now).as_secs(),
+            })
+            .collect();
+        listed.sort_by(|a, b| a.id.cmp(&b.id));
+        listed
+    }
+
+    /// Entries still listed, for the state file
+    pub fn save(&self, now: Instant, wall: SystemTime) -> Vec<SavedDevice> {
+        let entries = self.entries.lock().unwrap();
+        entries
+            .iter()
+            .filter(|(_, entry)| entry.expires + self.retention > now)
+            .map(|(id, entry)| SavedDevice {
+                protocol: entry.protocol.to_string(),
+                id: id.clone(),
+                kind: entry.kind.clone(),
+                name: entry.name.clone(),
+                ip: entry.ip,
+                mac: entry.mac,
+                first_seen: entry.first_seen,
+                last_seen: entry.last_seen,
+                expires: state::wall_time(entry.expires, now, wall),
+            })
+            .collect()
+    }
+
+    /// Adds the saved entries whose retention period has not passed, those
+    /// seen last first while there is room. Devices seen meanwhile are kept
+    /// as they are.
+    pub fn restore(&self, mut saved: Vec<SavedDevice>, now: Instant, wall: SystemTime) {
+        saved.sort_by_key(|device| std::cmp::Reverse(device.last_seen));
+        let mut entries = self.entries.lock().unwrap();
+        for device in saved {
+            let protocol = match device.protocol.as_str() {
+                "ssdp" => "ssdp",
+                "mdns" => "mdns",
+                _ => continue,
+            };
+            let Some(expires) = state::instant(device.expires, now, wall) else {
+                continue;
+            };
+            if expires + self.retention <= now || entries.len() >= self.max_entries {
+                continue;
+            }
+            entries.entry(device.id).or_insert(Entry {
+                protocol,
+                kind: device.kind,
+                name: device.name,
+                ip: device.ip,
+                mac: device.mac,
+                first_seen: device.first_seen,
+                last_seen: device.last_seen,
+                expires,
+            });
+        }
+    }
+
+    /// One line per device in the inventory
+    pub fn state(&self) -> Vec<String> {
+        self.entries()
+            .into_iter()
+            .map(|entry| {
+                let name = entry.name.map(|name| format!(" \"{}\"", name));
+                let expiry = match entry.expires_in {
+                    0 => "gone".to_string(),
+                    left => format!(
+                        "expiring in {}",
+                        humantime::format_duration(Duration::from_secs(left))
+                    ),
+                };
+                format!(
+                    "{} {}{} ({}) at {} {}, first seen {}, last seen {}, {}",
+                    entry.protocol,
+                    entry.id,
+                    name.unwrap_or_default(),
+                    entry.kind,
+                    entry.ip,
+                    entry.mac,
+                    entry.first_seen,
+                    entry.last_seen,
+                    expiry
+                )
+            })
+            .collect()
+    }
+}
+
+/// Source MAC and IP address of an IPv4 or IPv6 frame
+fn source(frame: &[u8]) -> Option<(MacAddr, IpAddr)> {
+    let eth = EthernetPacket::new(frame)?;
+    let l3 = &frame[ETHERNET_HEADER_LEN..];
+    let ip = match eth.get_ethertype() {
+        EtherTypes::Ipv4 => IpAddr::V4(Ipv4Packet::new(l3)?.get_source()),
+        EtherTypes::Ipv6 => IpAddr::V6(Ipv6Packet::new(l3)?.get_source()),
+        _ => return None,
+    };
+    Some((eth.get_source(), ip))
+}
+
+/// Announcement or goodbye in an SSDP NOTIFY or search response
+fn ssdp_sighting(payload: &[u8]) -> Option<Sighting> {
+    let message = SsdpMessage::parse(payload)?;
+    let (_, headers) = split_message(payload)?;
+    let lifetime = match message.kind {
+        SsdpKind::Alive | SsdpKind::Response => header(&headers, "CACHE-CONTROL")
+            .and_then(max_age)
+            .unwrap_or(SSDP_DEFAULT_MAX_AGE),
+        SsdpKind::ByeBye => Duration::ZERO,
+        SsdpKind::Search | SsdpKind::Update => return None,
+    };
+    Some(Sighting {
+        protocol: "ssdp",
+        id: header(&headers, "USN")?.to_string(),
+        kind: message.target.to_string(),
+        name: header(&headers, "SERVER").map(str::to_string),
+        lifetime,
+    })
+}
+
+/// Value of the `fn` key, the friendly name, in the data of a TXT record
+fn friendly_name(txt: &[u8]) -> Option<String> {
+    let mut rest = txt;
+    while let Some((&len, tail)) = rest.split_first() {
+        let string = tail.get(..usize::from(len))?;
+        if let Some(name) = string.strip_prefix(b"fn=") {
+            return Some(String::from_utf8_lossy(name).into_owned());
+        }
+        rest = &tail[string.len()..];
+    }
+    None
+}
+
+/// Service instances announced or withdrawn by PTR records of an mDNS
+/// response, and the friendly names in its TXT records
+fn mdns_sightings(records: &[OwnedRecord]) -> (Vec<Sighting>, Vec<(String, String)>) {
+    let mut sightings = Vec::new();
+    let mut names = Vec::new();
+    for record in records {
+        if record.class & !CLASS_TOP_BIT != CLASS_IN {
+            continue;
+        }
+        let owner = mdns::dotted_name(&record.name);
+        match record.rtype {
+            TYPE_PTR if is_service_type(&owner) => {
+                let Some(instance) = record.target() else {
+                    continue;
+                };
+                sightings.push(Sighting {
+                    protocol: "mdns",
+                    id: mdns::dotted_name(&instance),
+                    kind: owner,
+                    name: None,
+                    lifetime: Duration::from_secs(u64::from(record.ttl)),
+                });
+            }
+            TYPE_TXT => {
+                if let Some(name) = friendly_name(&record.data) {
+                    names.push((owner, name));
+                }
+            }
+            _ => {}
+        }
+    }
+    (sightings, names)
+}
+
+/// Adds the devices announcing themselves in mDNS responses and SSDP
+/// NOTIFYs or search responses forwarded inwards to the inventory. Goes
+/// ahead of the mDNS and LOCATION rewrites so what the devices sent is
+/// recorded.
+pub struct LearnDevices {
+    inventory: Arc<DeviceInventory>,
+}
+
+impl LearnDevices {
+    pub fn new(inventory: Arc<DeviceInventory>) -> Self {
+        LearnDevices { inventory }
+    }
+}
+
+impl Rewrite for LearnDevices {
+    fn name(&self) -> &str {
+        "device-inventory"
+    }
+
+    fn apply(&self, frame: &mut Vec<u8>) -> bool {
+        let Some(datagram) = UdpDatagram::locate(frame) else {
+            return true;
+        };
+        let Some((mac, ip)) = source(frame) else {
+            return true;
+        };
+        let payload = &frame[datagram.payload()];
+        let (now, wall) = (Instant::now(), SystemTime::now());
+        match datagram.ports(frame) {
+            [SSDP_PORT, _] | [_, SSDP_PORT] => {
+                if let Some(sighting) = ssdp_sighting(payload) {
+                    self.inventory.see(sighting, ip, mac, now, wall);
+                }
+            }
+            [MDNS_PORT, _] => {
+                let Some(message) = OwnedMessage::parse(payload) else {
+                    return true;
+                };
+                if !message.response {
+                    return true;
+                }
+                let records = [message.answers, message.additional].concat();
+                let (sightings, names) = mdns_sightings(&records);
+                for sighting in sightings {
+                    self.inventory.see(sighting, ip, mac, now, wall);
+                }
+                for (id, name) in names {
+                    self.inventory.name(&id, name);
+                }
+            }
+            _ => {}
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::dns_name;
+
+    fn sighting(id: &str, lifetime: u64) -> Sighting {
+        Sighting {
+            protocol: "ssdp",
+            id: id.to_string(),
+            kind: "urn:dial-multiscreen-org:service:dial:1".to_string(),
+            name: Some("Linux/3.8 UPnP/1.0".to_string()),
+            lifetime: Duration::from_secs(lifetime),
+        }
+    }
+
+    #[test]
+    fn keeps_devices_until_retention_after_expiry() {
+        let ip = IpAddr::from([192, 168, 1, 5]);
+        let mac = MacAddr(2, 0, 0, 0, 0, 5);
+        let inventory = DeviceInventory::new(2, Duration::from_secs(60));
+        let start = Instant::now();
+        let wall = SystemTime::now();
+        inventory.see(sighting("uuid:tv", 1800), ip, mac, start, wall);
+        inventory.see(sighting("uuid:speaker", 1800), ip, mac, start, wall);
+        let entries = inventory.entries();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[1].id, "uuid:tv");
+        assert_eq!(entries[1].mac, "02:00:00:00:00:05");
+        assert!(entries[1].expires_in > 1790);
+
+        // A goodbye marks the device gone but keeps it listed
+        inventory.see(sighting("uuid:tv", 0), ip, mac, start, wall);
+        let entries = inventory.entries();
+        assert_eq!(
+            (entries[1].id.as_str(), entries[1].expires_in),
+            ("uuid:tv", 0)
+        );
+        assert!(inventory.state()[1].ends_with("gone"));
+
+        // The device seen least recently makes room
+        let later = wall + Duration::from_secs(10);
+        inventory.see(sighting("uuid:speaker", 1800), ip, mac, start, later);
+        inventory.see(sighting("uuid:radio", 1800), ip, mac, start, later);
+        let ids: Vec<String> = inventory.entries().into_iter().map(|e| e.id).collect();
+        assert_eq!(ids, ["uuid:radio", "uuid:speaker"]);
+
+        // Past the retention, expired devices are gone
+        inventory.see(sighting("uuid:radio", 0), ip, mac, start, later);
+        let past = start + Duration::from_secs(61);
+        inventory.see(sighting("uuid:speaker", 1800), ip, mac, past, later);
+        assert_eq!(inventory.entries().len(), 1);
+    }
+
+    #[test]
+    fn restores_saved_devices_within_retention() {
+        let ip = IpAddr::from([192, 168, 1, 5]);
+        let mac = MacAddr(2, 0, 0, 0, 0, 5);
+        let inventory = DeviceInventory::new(8, Duration::from_secs(60));
+        let (now, wall) = (Instant::now(), SystemTime::now());
+        inventory.see(sighting("uuid:tv", 1800), ip, mac, now, wall);
+        inventory.see(sighting("uuid:speaker", 30), ip, mac, now, wall);
+        let saved = inventory.save(now, wall);
+        assert_eq!(saved.len(), 2);
+
+        // Two minutes later the speaker is past its retention
+        let restored = DeviceInventory::new(8, Duration::from_secs(60));
+        let later = wall + Duration::from_secs(120);
+        restored.restore(saved, now, later);
+        let entries = restored.entries();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].id, "uuid:tv");
+        assert!((1670..=1680).contains(&entries[0].expires_in));
+    }
+
+    #[test]
+    fn parses_ssdp_and_mdns_announcements() {
+        let notify = b"NOTIFY * HTTP/1.1\r\nHOST: 239.255.255.250:1900\r\n\
+            CACHE-CONTROL: max-age=120\r\nNT: upnp:rootdevice\r\nNTS: ssdp:alive\r\n\
+            SERVER: Linux/3.8 UPnP/1.0 Chromecast\r\nUSN: uuid:tv::upnp:rootdevice\r\n\r\n";
+        let sighting = ssdp_sighting(notify).unwrap();
+        assert_eq!(sighting.id, "uuid:tv::upnp:rootdevice");
+        assert_eq!(
+            sighting.name.as_deref(),
+            Some("Linux/3.8 UPnP/1.0 Chromecast")
+        );
+        assert_eq!(sighting.lifetime, Duration::from_secs(120));
+
+        let record = |name: &str, rtype, ttl, data| OwnedRecord {
+            name: name.split('.').map(|l| l.as_bytes().to_vec()).collect(),
+            rtype,
+            class: CLASS_IN,
+            ttl,
+            data,
+        };
+        let instance = "TV._googlecast._tcp.local";
+        let records = [
+            record("_googlecast._tcp.local", TYPE_PTR, 120, dns_name(instance)),
+            record(
+                instance,
+                TYPE_TXT,
+                4500,
+                b"\x06id=abc\x0efn=Living Room".to_vec(),
+            ),
+        ];
+        let (sightings, names) = mdns_sightings(&records);
+        assert_eq!(sightings.len(), 1);
+        assert_eq!(sightings[0].id, "tv._googlecast._tcp.local");
+        assert_eq!(sightings[0].kind, "_googlecast._tcp.local");
+        let named = (
+            "tv._googlecast._tcp.local".to_string(),
+            "Living Room".to_string(),
+        );
+        assert_eq!(names, [named]);
+    }
+}
@@ -0,0 +1,357 @@
+//! Interfaces carrying bare IP packets, such as point-to-point TUN devices.
+//! Their packets get an Ethernet header on receipt and lose it again when
+//! sent, so the filters and rewrites see Ethernet frames whatever the link.
+//! Frames crossing to an Ethernet interface are addressed there from the
+//! MACs learned on it.
+
+use crate::hostmac::HostMacTable;
+use crate::link::{PacketSink, PacketSource};
+use crate::nameservice::subnet_broadcasts;
+use crate::rewrite::Rewrite;
+use clap::ValueEnum;
+use pnet::datalink::NetworkInterface;
+use pnet::packet::ethernet::{EtherType, EtherTypes, EthernetPacket, MutableEthernetPacket};
+use pnet::packet::ipv4::Ipv4Packet;
+use pnet::packet::ipv6::Ipv6Packet;
+use pnet::util::MacAddr;
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::Arc;
+use tracing::{debug, trace};
+
+const ETHERNET_HEADER_LEN: usize = 14;
+
+/// What the frames of an interface start with
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LinkType {
+    /// An Ethernet header
+    Ethernet,
+    /// The IP header, without link layer framing
+    Ip,
+}
+
+/// IP addresses and MACs of the hosts on the Ethernet side of a pair whose
+/// other side is an IP interface
+pub type Neighbors = HostMacTable<IpAddr>;
+
+/// Ethernet multicast MAC of an IPv4 or IPv6 group
+pub fn group_mac(group: IpAddr) -> MacAddr {
+    match group {
+        IpAddr::V4(group) => {
+            let [_, b, c, d] = group.octets();
+            MacAddr(0x01, 0x00, 0x5e, b & 0x7f, c, d)
+        }
+        IpAddr::V6(group) => {
+            let [.., a, b, c, d] = group.octets();
+            MacAddr(0x33, 0x33, a, b, c, d)
+        }
+    }
+}
+
+/// Ethertype and destination address of a bare IP packet
+fn ip_header(packet: &[u8]) -> Option<(EtherType, IpAddr)> {
+    match packet.first()? >> 4 {
+        4 => Some((
+            EtherTypes::Ipv4,
+            Ipv4Packet::new(packet)?.get_destination().into(),
+        )),
+        6 => Some((
+            EtherTypes::Ipv6,
+            Ipv6Packet::new(packet)?.get_destination().into(),
+        )),
+        _ => None,
+    }
+}
+
+/// Ethernet frame carrying `packet`, from no MAC in particular and to the
+/// group or broadcast MAC its destination implies, if any. Returns `None`
+/// if it is not an IPv4 or IPv6 packet.
+fn frame(packet: &[u8], frame: &mut Vec<u8>) -> Option<()> {
+    let (ethertype, destination) = ip_header(packet)?;
+    let destination = match destination {
+        ip if ip.is_multicast() => group_mac(ip),
+        IpAddr::V4(ip) if ip.is_broadcast() => MacAddr::broadcast(),
+        _ => MacAddr::zero(),
+    };
+    frame.clear();
+    frame.resize(ETHERNET_HEADER_LEN, 0);
+    let mut eth = MutableEthernetPacket::new(frame).expect("the header fits");
+    eth.set_destination(destination);
+    eth.set_source(MacAddr::zero());
+    eth.set_ethertype(ethertype);
+    frame.extend_from_slice(packet);
+    Some(())
+}
+
+/// Source of an IP interface, handing out its packets as Ethernet frames.
+/// Anything but IPv4 and IPv6 is skipped.
+pub struct IpSource {
+    inner: Box<dyn PacketSource>,
+    frame: Vec<u8>,
+}
+
+impl IpSource {
+    pub fn new(inner: Box<dyn PacketSource>) -> Self {
+        IpSource {
+            inner,
+            frame: Vec::new(),
+        }
+    }
+}
+
+impl PacketSource for IpSource {
+    fn next(&mut self) -> io::Result<&[u8]> {
+        loop {
+            let packet = self.inner.next()?;
+            if frame(packet, &mut self.frame).is_some() {
+                return Ok(&self.frame);
+            }
+            trace!("Skipped packet that is not IP: {:02x?}", packet);
+        }
+    }
+}
+
+/// Sink of an IP interface, sending frames without their Ethernet header.
+/// Frames that do not carry IP, such as ARP, have no place there and are
+/// discarded.
+pub struct IpSink {
+    inner: Box<dyn PacketSink>,
+}
+
+impl IpSink {
+    pub fn new(inner: Box<dyn PacketSink>) -> Self {
+        IpSink { inner }
+    }
+}
+
+impl PacketSink for IpSink {
+    fn send(&mut self, frame: &[u8]) -> io::Result<()> {
+        let ethertype = EthernetPacket::new(frame).map(|eth| eth.get_ethertype());
+        match ethertype {
+            Some(EtherTypes::Ipv4 | EtherTypes::Ipv6) => {
+                self.inner.send(&frame[ETHERNET_HEADER_LEN..])
+            }
+            _ => {
+                debug!("Frame without IP not sent on an IP interface");
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Source IP address of an IPv4 or IPv6 frame
+fn source_ip(frame: &[u8]) -> Option<IpAddr> {
+    let eth = EthernetPacket::new(frame)?;
+    let l3 = &frame[ETHERNET_HEADER_LEN..];
+    match eth.get_ethertype() {
+        EtherTypes::Ipv4 => Some(Ipv4Packet::new(l3)?.get_source().into()),
+        EtherTypes::Ipv6 => Some(Ipv6Packet::new(l3)?.get_source().into()),
+        _ => None,
+    }
+}
+
+/// Records the MACs of the hosts on the Ethernet side by the source of the
+/// frames they send towards the IP interface
+pub struct LearnNeighbors {
+    neighbors: Arc<Neighbors>,
+}
+
+impl LearnNeighbors {
+    pub fn new(neighbors: Arc<Neighbors>) -> Self {
+        LearnNeighbors { neighbors }
+    }
+}
+
+impl Rewrite for LearnNeighbors {
+    fn name(&self) -> &str {
+        "learn-neighbors"
+    }
+
+    fn apply(&self, frame: &mut Vec<u8>) -> bool {
+        let Some(eth) = EthernetPacket::new(frame) else {
+            return true;
+        };
+        let mac = eth.get_source();
+        if let Some(ip) = source_ip(frame) {
+            if !ip.is_unspecified() && !ip.is_multicast() && !mac.is_multicast() {
+                self.neighbors.learn(ip, mac);
+            }
+        }
+        true
+    }
+}
+
+/// Addresses frames received on an IP interface for the Ethernet interface
+/// they leave through: from its MAC, to the group MAC of a multicast
+/// destination, the broadcast MAC of a broadcast one and the learned MAC of
+/// a unicast one. Unicast frames to hosts that have not sent anything yet
+/// are dropped.
+pub struct EthernetAddressing {
+    mac: MacAddr,
+    broadcasts: Vec<Ipv4Addr>,
+    neighbors: Arc<Neighbors>,
+}
+
+impl EthernetAddressing {
+    pub fn new(mac: MacAddr, iface: &NetworkInterface, neighbors: Arc<Neighbors>) -> Self {
+        EthernetAddressing {
+            mac,
+            broadcasts: subnet_broadcasts(iface),
+            neighbors,
+        }
+    }
+}
+
+impl Rewrite for EthernetAddressing {
+    fn name(&self) -> &str {
+        "ethernet-addressing"
+    }
+
+    fn apply(&self, frame: &mut Vec<u8>) -> bool {
+        let Some((_, destination)) = frame.get(ETHERNET_HEADER_LEN..).and_then(ip_header) else {
+            return false;
+        };
+        let mac = match destination {
+            ip if ip.is_multicast() => group_mac(ip),
+            IpAddr::V4(ip) if ip.is_broadcast() || self.broadcasts.contains(&ip) => {
+                MacAddr::broadcast()
+            }
+            ip => match self.neighbors.lookup(ip) {
+                Some(mac) => mac,
+                None => {
+                    debug!("No MAC learned for {}, unicast frame dropped", ip);
+                    return false;
+                }
+            },
+        };
+        let mut eth = MutableEthernetPacket::new(frame).expect("the IP header was parsed");
+        eth.set_destination(mac);
+        eth.set_source(self.mac);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::link::memory::{self, VecSink};
+    use crate::testutil::{udp_frame, HOST_IP, HOST_MAC, SSDP_IPV4_GROUP};
+    use std::net::Ipv6Addr;
+    use std::time::Duration;
+
+    const ROUTER_MAC: MacAddr = MacAddr(0x02, 0, 0, 0, 0, 0xfe);
+    const DEVICE_MAC: MacAddr = MacAddr(0x02, 0, 0, 0, 0, 0x20);
+    const DEVICE_IP: Ipv4Addr = Ipv4Addr::new(192, 168, 1, 20);
+
+    fn packet(frame: &[u8]) -> &[u8] {
+        &frame[ETHERNET_HEADER_LEN..]
+    }
+
+    #[test]
+    fn ip_interface_to_ethernet() {
+        let (packets, source) = memory::source();
+        let mut source = IpSource::new(Box::new(source));
+        let search = udp_frame(
+            HOST_MAC,
+            group_mac(SSDP_IPV4_GROUP.into()),
+            HOST_IP,
+            SSDP_IPV4_GROUP,
+            50000,
+            1900,
+            b"M-SEARCH * HTTP/1.1\r\n\r\n",
+        );
+        let request = udp_frame(HOST_MAC, HOST_MAC, HOST_IP, DEVICE_IP, 50000, 8008, b"GET");
+        packets.send(b"\x00not ip".to_vec()).unwrap();
+        packets.send(packet(&search).to_vec()).unwrap();
+        packets.send(packet(&request).to_vec()).unwrap();
+
+        // Received with a header implied by the destination, skipping the
+        // packet that is not IP
+        let received = source.next().unwrap().to_vec();
+        let eth = EthernetPacket::new(&received).unwrap();
+        assert_eq!(eth.get_ethertype(), EtherTypes::Ipv4);
+        assert_eq!(eth.get_destination(), group_mac(SSDP_IPV4_GROUP.into()));
+        assert_eq!(packet(&received), packet(&search));
+        let mut unicast = source.next().unwrap().to_vec();
+
+        // Addressed on the Ethernet side once the device was heard from
+        let neighbors = Arc::new(Neighbors::new(16, Duration::from_secs(60)));
+        let iface = NetworkInterface {
+            name: "eth0".to_string(),
+            description: String::new(),
+            index: 2,
+            mac: Some(ROUTER_MAC),
+            ips: vec!["192.168.1.1/24".parse().unwrap()],
+            flags: 0,
+        };
+        let addressing = EthernetAddressing::new(ROUTER_MAC, &iface, neighbors.clone());
+        let mut multicast = received.clone();
+        assert!(addressing.apply(&mut multicast));
+        let eth = EthernetPacket::new(&multicast).unwrap();
+        assert_eq!(eth.get_source(), ROUTER_MAC);
+        assert_eq!(eth.get_destination(), group_mac(SSDP_IPV4_GROUP.into()));
+        assert!(!addressing.apply(&mut unicast.clone()));
+        neighbors.learn(DEVICE_IP.into(), DEVICE_MAC);
+        assert!(addressing.apply(&mut unicast));
+        let eth = EthernetPacket::new(&unicast).unwrap();
+        assert_eq!(
+            (eth.get_source(), eth.get_destination()),
+            (ROUTER_MAC, DEVICE_MAC)
+        );
+        assert_eq!(packet(&unicast), packet(&request));
+
+        let mut subnet_broadcast = udp_frame(
+            HOST_MAC,
+            MacAddr::zero(),
+            HOST_IP,
+            Ipv4Addr::new(192, 168, 1, 255),
+            137,
+            137,
+            b"name query",
+        );
+        assert!(addressing.apply(&mut subnet_broadcast));
+        let eth = EthernetPacket::new(&subnet_broadcast).unwrap();
+        assert_eq!(eth.get_destination(), MacAddr::broadcast());
+    }
+
+    #[test]
+    fn ethernet_to_ip_interface() {
+        let neighbors = Arc::new(Neighbors::new(16, Duration::from_secs(60)));
+        let learn = LearnNeighbors::new(neighbors.clone());
+        let mut response = udp_frame(
+            DEVICE_MAC, ROUTER_MAC, DEVICE_IP, HOST_IP, 1900, 50000, b"OK",
+        );
+        assert!(learn.apply(&mut response));
+        let device = Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 0x20);
+        let group = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 0xfb);
+        let mut announcement = udp_frame(
+            DEVICE_MAC,
+            group_mac(group.into()),
+            device,
+            group,
+            5353,
+            5353,
+            b"mdns",
+        );
+        assert!(learn.apply(&mut announcement));
+        assert_eq!(neighbors.lookup(DEVICE_IP.into()), Some(DEVICE_MAC));
+        assert_eq!(neighbors.lookup(device.into()), Some(DEVICE_MAC));
+
+        // Sent without the Ethernet header, ARP not at all
+        let sent = VecSink::default();
+        let mut sink = IpSink::new(Box::new(sent.clone()));
+        sink.send(&response).unwrap();
+        sink.send(&announcement).unwrap();
+        let mut arp = response.clone();
+        MutableEthernetPacket::new(&mut arp)
+            .unwrap()
+            .set_ethertype(EtherTypes::Arp);
+        sink.send(&arp).unwrap();
+        assert_eq!(
+            sent.frames(),
+            [packet(&response).to_vec(), packet(&announcement).to_vec()]
+        );
+    }
+}
@@ -0,0 +1,173 @@
+//! Native logging to systemd-journald over its socket, one datagram per
+//! event with the event and span fields as journal fields, so that entries
+//! can be matched on them, as in `journalctl IFACE=eth0 DECISION=dropped`.
+//!
+//! Field names are the event field names in upper case, except for the
+//! packet summary attributes [`summary::JOURNAL_FIELDS`] renames. Entries
+//! too large for a datagram are lost; journald takes about 200 KiB.
+
+use crate::summary;
+use std::fmt::{self, Write as _};
+use std::io;
+use std::os::unix::net::UnixDatagram;
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Layer;
+
+/// Socket journald receives native protocol messages on
+const SOCKET: &str = "/run/systemd/journal/socket";
+/// `SYSLOG_IDENTIFIER` of every entry
+const IDENTIFIER: &str = "nw-pckt-fwd";
+
+/// Layer sending every event to the journal
+pub struct Journal {
+    socket: UnixDatagram,
+}
+
+impl Journal {
+    /// Connects to the journal socket; fails where journald does not run,
+    /// e.g. in a container
+    pub fn connect() -> io::Result<Self> {
+        let socket = UnixDatagram::unbound()?;
+        socket.connect(SOCKET)?;
+        Ok(Journal { socket })
+    }
+}
+
+/// syslog priority of `level`
+fn priority(level: Level) -> &'static str {
+    match level {
+        Level::ERROR => "3",
+        Level::WARN => "4",
+        Level::INFO => "6",
+        Level::DEBUG | Level::TRACE => "7",
+    }
+}
+
+/// Journal field name of the event field `name`: upper case letters,
+/// digits and underscores, starting with a letter
+fn field_name(name: &str) -> String {
+    if let Some((_, field)) = summary::JOURNAL_FIELDS
+        .iter()
+        .find(|(attribute, _)| *attribute == name)
+    {
+        return field.to_string();
+    }
+    let name: String = name
+        .chars()
+        .map(|c| match c.to_ascii_uppercase() {
+            c @ ('A'..='Z' | '0'..='9') => c,
+            _ => '_',
+        })
+        .collect();
+    match name.trim_start_matches(|c: char| !c.is_ascii_uppercase()) {
+        "" => "FIELD".to_string(),
+        trimmed => trimmed.to_string(),
+    }
+}
+
+/// Appends the field `name` with `value` in the native protocol: `NAME=value`
+/// on a line, or for values spanning lines the name, the length as 64-bit
+/// little endian and the value
+fn append(entry: &mut Vec<u8>, name: &str, value: &str) {
+    entry.extend_from_slice(name.as_bytes());
+    if value.contains('\n') {
+        entry.push(b'\n');
+        entry.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    } else {
+        entry.push(b'=');
+    }
+    entry.extend_from_slice(value.as_bytes());
+    entry.push(b'\n');
+}
+
+/// Collects the fields of an event or span into an entry
+struct Fields<'a>(&'a mut Vec<u8>);
+
+impl Visit for Fields<'_> {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        let name = match field.name() {
+            "message" => "MESSAGE".to_string(),
+            name => field_name(name),
+        };
+        append(self.0, &name, value);
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        let mut text = String::new();
+        let _ = write!(text, "{:?}", value);
+        self.record_str(field, &text);
+    }
+}
+
+/// Fields of a span, kept in its extensions
+struct SpanFields(Vec<u8>);
+
+impl<S> Layer<S> for Journal
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let mut fields = Vec::new();
+        attrs.record(&mut Fields(&mut fields));
+        span.extensions_mut().insert(SpanFields(fields));
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let mut extensions = span.extensions_mut();
+        if let Some(fields) = extensions.get_mut::<SpanFields>() {
+            values.record(&mut Fields(&mut fields.0));
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let metadata = event.metadata();
+        let mut entry = Vec::with_capacity(512);
+        append(&mut entry, "PRIORITY", priority(*metadata.level()));
+        append(&mut entry, "SYSLOG_IDENTIFIER", IDENTIFIER);
+        append(&mut entry, "TARGET", metadata.target());
+        // Fields of the enclosing spans, outermost first, then the event's;
+        // journald keeps every value of a field given more than once
+        if let Some(scope) = ctx.event_scope(event) {
+            for span in scope.from_root() {
+                if let Some(fields) = span.extensions().get::<SpanFields>() {
+                    entry.extend_from_slice(&fields.0);
+                }
+            }
+        }
+        event.record(&mut Fields(&mut entry));
+        let _ = self.socket.send(&entry);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_fields_in_the_native_protocol() {
+        assert_eq!(field_name("interface"), "IFACE");
+        assert_eq!(field_name("reason"), "DROP_REASON");
+        assert_eq!(field_name("src_ip"), "SRC_IP");
+        assert_eq!(field_name("iface"), "IFACE");
+        assert_eq!(field_name("_0x.name"), "X_NAME");
+        assert_eq!(field_name("_"), "FIELD");
+
+        let mut entry = Vec::new();
+        append(&mut entry, "DECISION", "dropped");
+        append(&mut entry, "MESSAGE", "two\nlines");
+        let mut expected = b"DECISION=dropped\nMESSAGE\n".to_vec();
+        expected.extend_from_slice(&9u64.to_le_bytes());
+        expected.extend_from_slice(b"two\nlines\n");
+        assert_eq!(entry, expected);
+    }
+}
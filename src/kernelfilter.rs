@@ -0,0 +1,380 @@
+//! Classic BPF program attached to the packet sockets, so the kernel
+//! discards the frames no filter could forward before they are copied to
+//! userspace. The filter chain still decides on every frame that passes.
+
+use crate::iface::fd_link;
+use libc::{
+    sock_filter, BPF_ABS, BPF_B, BPF_H, BPF_IND, BPF_JA, BPF_JEQ, BPF_JMP, BPF_K, BPF_LD, BPF_LDX,
+    BPF_MSH, BPF_RET,
+};
+use pnet::datalink::NetworkInterface;
+use socket2::{Domain, SockRef, Socket, Type};
+use std::collections::{BTreeSet, HashMap};
+use std::io;
+use std::os::fd::{AsRawFd, BorrowedFd, IntoRawFd, RawFd};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use tracing::{info, warn};
+#[cfg(feature = "af-xdp")]
+use {
+    crate::xdp::Program,
+    std::sync::{Arc, Weak},
+};
+
+const ETHERTYPE_IPV4: u32 = 0x0800;
+const ETHERTYPE_ARP: u32 = 0x0806;
+const ETHERTYPE_IPV6: u32 = 0x86dd;
+const ETHERTYPE_VLAN: u32 = 0x8100;
+const ETHERTYPE_QINQ: u32 = 0x88a8;
+const ETHERTYPE_WOL: u32 = 0x0842;
+const ETHERTYPE_EAPOL: u32 = 0x888e;
+const ETHERTYPE_LLDP: u32 = 0x88cc;
+const PROTOCOL_IGMP: u32 = 2;
+const PROTOCOL_TCP: u32 = 6;
+const PROTOCOL_UDP: u32 = 17;
+const PROTOCOL_ICMPV6: u32 = 58;
+/// IPv6 extension headers ahead of the transport header: hop-by-hop,
+/// routing, fragment and destination options
+const IPV6_EXTENSION_HEADERS: [u32; 4] = [0, 43, 44, 60];
+
+/// Offsets in an Ethernet frame
+const ETHERTYPE_OFFSET: u32 = 12;
+const IPV4_OFFSET: u32 = 14;
+const IPV4_PROTOCOL_OFFSET: u32 = 23;
+const IPV6_NEXT_HEADER_OFFSET: u32 = 20;
+const IPV6_TRANSPORT_OFFSET: u32 = 54;
+
+/// Traffic the program lets through
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Interest {
+    /// UDP with one of these as source or destination port
+    pub udp_ports: BTreeSet<u16>,
+    /// TCP with one of these as source or destination port
+    pub tcp_ports: BTreeSet<u16>,
+    pub arp: bool,
+    /// IPv6 at all; without it only IPv4 and ARP pass
+    pub ipv6: bool,
+    /// IGMP and ICMPv6, for snooping and neighbor discovery
+    pub control: bool,
+    /// Wake-on-LAN frames of their own EtherType
+    pub wol: bool,
+    /// EAPOL and LLDP frames, passed through
+    pub eapol: bool,
+    pub lldp: bool,
+}
+
+fn statement(code: u32, k: u32) -> sock_filter {
+    jump(code, k, 0, 0)
+}
+
+fn jump(code: u32, k: u32, jt: u8, jf: u8) -> sock_filter {
+    sock_filter {
+        code: code as u16,
+        jt,
+        jf,
+        k,
+    }
+}
+
+fn accept() -> sock_filter {
+    statement(BPF_RET | BPF_K, u32::MAX)
+}
+
+fn reject() -> sock_filter {
+    statement(BPF_RET | BPF_K, 0)
+}
+
+/// Accepts the frame if the value in the accumulator is one of `values`,
+/// falling through otherwise. Every test only skips its own accept, so the
+/// list may be longer than a conditional jump reaches.
+fn accept_any(values: impl IntoIterator<Item = u32>) -> Vec<sock_filter> {
+    values
+        .into_iter()
+        .flat_map(|value| [jump(BPF_JMP | BPF_JEQ | BPF_K, value, 0, 1), accept()])
+        .collect()
+}
+
+/// Loads with `load` and continues with the block of the case matching the
+/// loaded value, rejecting the frame if none does. Blocks must end in a
+/// return.
+fn dispatch(load: sock_filter, cases: Vec<(u32, Vec<sock_filter>)>) -> Vec<sock_filter> {
+    // A test and a long jump per case, then the reject
+    let header = 2 * cases.len() + 2;
+    let mut code = vec![load];
+    let mut start = header;
+    for (i, (value, block)) in cases.iter().enumerate() {
+        let after_jump = 2 * i + 3;
+        code.push(jump(BPF_JMP | BPF_JEQ | BPF_K, *value, 0, 1));
+        code.push(statement(BPF_JMP | BPF_JA, (start - after_jump) as u32));
+        start += block.len();
+    }
+    code.push(reject());
+    code.extend(cases.into_iter().flat_map(|(_, block)| block));
+    code
+}
+
+/// Accepts the frame if the source or destination port is in `ports`. The
+/// ports are loaded from `offset`, indexed by the IPv4 header length for
+/// IPv4.
+fn ports(ports: &BTreeSet<u16>, offset: u32, indexed: bool) -> Vec<sock_filter> {
+    let mode = if indexed { BPF_IND } else { BPF_ABS };
+    let mut code = Vec::new();
+    if indexed {
+        code.push(statement(BPF_LDX | BPF_B | BPF_MSH, IPV4_OFFSET));
+    }
+    for field in [offset, offset + 2] {
+        code.push(statement(BPF_LD | BPF_H | mode, field));
+        code.extend(accept_any(ports.iter().copied().map(u32::from)));
+    }
+    code.push(reject());
+    code
+}
+
+/// Program accepting the traffic of `interest` and rejecting the rest
+pub fn program(interest: &Interest) -> Vec<sock_filter> {
+    let transports = |offset: u32, indexed: bool| {
+        let mut cases = Vec::new();
+        if !interest.udp_ports.is_empty() {
+            let block = ports(&interest.udp_ports, offset, indexed);
+            cases.push((PROTOCOL_UDP, block));
+        }
+        if !interest.tcp_ports.is_empty() {
+            let block = ports(&interest.tcp_ports, offset, indexed);
+            cases.push((PROTOCOL_TCP, block));
+        }
+        cases
+    };
+
+    let mut ipv4 = transports(IPV4_OFFSET, true);
+    if interest.control {
+        ipv4.push((PROTOCOL_IGMP, vec![accept()]));
+    }
+    let mut cases = vec![(
+        ETHERTYPE_IPV4,
+        dispatch(
+            statement(BPF_LD | BPF_B | BPF_ABS, IPV4_PROTOCOL_OFFSET),
+            ipv4,
+        ),
+    )];
+    if interest.ipv6 {
+        let mut ipv6 = transports(IPV6_TRANSPORT_OFFSET, false);
+        if interest.control {
+            ipv6.push((PROTOCOL_ICMPV6, vec![accept()]));
+        }
+        // The transport header is further in, leave these to the filters
+        ipv6.extend(IPV6_EXTENSION_HEADERS.map(|header| (header, vec![accept()])));
+        let load = statement(BPF_LD | BPF_B | BPF_ABS, IPV6_NEXT_HEADER_OFFSET);
+        cases.push((ETHERTYPE_IPV6, dispatch(load, ipv6)));
+    }
+    if interest.arp {
+        cases.push((ETHERTYPE_ARP, vec![accept()]));
+    }
+    if interest.wol {
+        cases.push((ETHERTYPE_WOL, vec![accept()]));
+    }
+    if interest.eapol {
+        cases.push((ETHERTYPE_EAPOL, vec![accept()]));
+    }
+    if interest.lldp {
+        cases.push((ETHERTYPE_LLDP, vec![accept()]));
+    }
+    // Tagged frames are only parsed once their tags are taken off
+    cases.extend([ETHERTYPE_VLAN, ETHERTYPE_QINQ].map(|tpid| (tpid, vec![accept()])));
+    dispatch(statement(BPF_LD | BPF_H | BPF_ABS, ETHERTYPE_OFFSET), cases)
+}
+
+/// Opens the packet sockets of the channels with the program attached and
+/// replaces it on the open sockets when the configuration changes
+pub struct KernelFilter {
+    /// `None` when every frame is needed
+    interest: Mutex<Option<Interest>>,
+    /// Socket of every interface, with its `/proc/self/fd` link to tell it
+    /// apart from a later socket on the same descriptor
+    sockets: Mutex<HashMap<String, (RawFd, PathBuf)>>,
+    /// XDP programs of the `af-xdp` backend, which take the frames to the
+    /// forwarded ports before the packet sockets see them
+    #[cfg(feature = "af-xdp")]
+    programs: Mutex<Vec<Weak<Program>>>,
+}
+
+impl KernelFilter {
+    pub fn new(interest: Option<Interest>) -> Self {
+        KernelFilter {
+            interest: Mutex::new(interest),
+            sockets: Mutex::new(HashMap::new()),
+            #[cfg(feature = "af-xdp")]
+            programs: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Traffic the program currently lets through, `None` for every frame
+    #[cfg(feature = "af-xdp")]
+    pub fn interest(&self) -> Option<Interest> {
+        self.interest.lock().unwrap().clone()
+    }
+
+    /// Keeps `program` in line with the interest until it is dropped
+    #[cfg(feature = "af-xdp")]
+    pub fn track(&self, program: &Arc<Program>) {
+        let mut programs = self.programs.lock().unwrap();
+        programs.retain(|program| program.strong_count() > 0);
+        programs.push(Arc::downgrade(program));
+    }
+
+    /// Packet socket for the channel of `iface`, with the program attached
+    /// if possible. The datalink channel binds it and owns the descriptor.
+    pub fn open(&self, iface: &NetworkInterface) -> io::Result<RawFd> {
+        // Protocol 0 receives nothing until the channel binds the socket to
+        // the interface, so no frame of another interface gets queued
+        let socket = Socket::new(Domain::PACKET, Type::RAW, None)?;
+        if let Some(interest) = &*self.interest.lock().unwrap() {
+            attach(&SockRef::from(&socket), &iface.name, interest);
+        }
+        let link = fd_link(socket.as_raw_fd())?;
+        let fd = socket.into_raw_fd();
+        self.sockets
+            .lock()
+            .unwrap()
+            .insert(iface.name.clone(), (fd, link));
+        Ok(fd)
+    }
+
+    /// Replaces the program on the sockets still open if `interest` differs
+    pub fn update(&self, interest: Option<Interest>) {
+        let mut current = self.interest.lock().unwrap();
+        if *current == interest {
+            return;
+        }
+        let mut sockets = self.sockets.lock().unwrap();
+        sockets.retain(|_, (fd, path)| fd_link(*fd).is_ok_and(|link| link == *path));
+        for (name, (fd, _)) in sockets.iter() {
+            // SAFETY: the descriptor still refers to the socket opened for
+            // the channel, which keeps it open for as long as it runs
+            let fd = unsafe { BorrowedFd::borrow_raw(*fd) };
+            let socket = SockRef::from(&fd);
+            match &interest {
+                Some(interest) => attach(&socket, name, interest),
+                None => match socket.detach_filter() {
+                    Ok(()) => info!("Kernel filter removed from {}", name),
+                    Err(e) => warn!("Removing the kernel filter from {} failed: {}", name, e),
+                },
+            }
+        }
+        #[cfg(feature = "af-xdp")]
+        self.programs
+            .lock()
+            .unwrap()
+            .retain(|program| match program.upgrade() {
+                Some(program) => {
+                    program.update(interest.as_ref());
+                    true
+                }
+                None => false,
+            });
+        *current = interest;
+    }
+}
+
+fn attach(socket: &SockRef, iface: &str, interest: &Interest) {
+    let program = program(interest);
+    match socket.attach_filter(&program) {
+        Ok(()) => info!(
+            "Kernel filter of {} instructions attached on {}",
+            program.len(),
+            iface
+        ),
+        Err(e) => warn!(
+            "Attaching the kernel filter on {} failed, receiving every frame: {}",
+            iface, e
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Runs the subset of classic BPF the programs use
+    fn run(program: &[sock_filter], frame: &[u8]) -> bool {
+        let (mut a, mut x, mut pc) = (0u32, 0u32, 0usize);
+        let byte = |at: u32| u32::from(frame.get(at as usize).copied().unwrap_or(0));
+        loop {
+            let op = program[pc];
+            pc += 1;
+            match u32::from(op.code) {
+                code if code == BPF_RET | BPF_K => return op.k != 0,
+                code if code == BPF_LD | BPF_B | BPF_ABS => a = byte(op.k),
+                code if code == BPF_LD | BPF_H | BPF_ABS => a = byte(op.k) << 8 | byte(op.k + 1),
+                code if code == BPF_LD | BPF_H | BPF_IND => {
+                    a = byte(x + op.k) << 8 | byte(x + op.k + 1)
+                }
+                code if code == BPF_LDX | BPF_B | BPF_MSH => x = 4 * (byte(op.k) & 0xf),
+                code if code == BPF_JMP | BPF_JA => pc += op.k as usize,
+                code if code == BPF_JMP | BPF_JEQ | BPF_K => {
+                    pc += usize::from(if a == op.k { op.jt } else { op.jf })
+                }
+                code => panic!("unexpected instruction {:#x}", code),
+            }
+        }
+    }
+
+    fn frame(ethertype: u16, protocol: u8, ports: (u16, u16)) -> Vec<u8> {
+        let mut frame = vec![0u8; 64];
+        frame[12..14].copy_from_slice(&ethertype.to_be_bytes());
+        let transport = if ethertype == ETHERTYPE_IPV6 as u16 {
+            frame[20] = protocol;
+            54
+        } else {
+            // IPv4 header with options, 24 bytes
+            frame[14] = 0x46;
+            frame[23] = protocol;
+            38
+        };
+        frame[transport..transport + 2].copy_from_slice(&ports.0.to_be_bytes());
+        frame[transport + 2..transport + 4].copy_from_slice(&ports.1.to_be_bytes());
+        frame
+    }
+
+    #[test]
+    fn accepts_only_the_interest() {
+        let mut interest = Interest {
+            udp_ports: (1000..1200).chain([1900, 5353]).collect(),
+            tcp_ports: [8009].into(),
+            arp: false,
+            ipv6: true,
+            control: false,
+            wol: false,
+            eapol: false,
+            lldp: false,
+        };
+        let program = program(&interest);
+        let (ipv4, ipv6) = (ETHERTYPE_IPV4 as u16, ETHERTYPE_IPV6 as u16);
+        let udp = PROTOCOL_UDP as u8;
+        assert!(run(&program, &frame(ipv4, udp, (50000, 1900))));
+        assert!(run(&program, &frame(ipv4, udp, (5353, 40000))));
+        assert!(run(&program, &frame(ipv4, udp, (40000, 1199))));
+        assert!(run(&program, &frame(ipv6, udp, (50000, 5353))));
+        assert!(run(&program, &frame(ipv6, 6, (8009, 50000))));
+        assert!(run(&program, &frame(ipv6, 0, (0, 0))));
+        assert!(!run(&program, &frame(ipv4, udp, (50000, 1901))));
+        assert!(!run(&program, &frame(ipv4, 6, (50000, 1900))));
+        assert!(!run(&program, &frame(ipv4, 2, (0, 0))));
+        assert!(!run(&program, &frame(ETHERTYPE_ARP as u16, 0, (0, 0))));
+        assert!(!run(&program, &frame(ETHERTYPE_WOL as u16, 0, (0, 0))));
+        assert!(!run(&program, &frame(ETHERTYPE_EAPOL as u16, 0, (0, 0))));
+        assert!(run(&program, &frame(ETHERTYPE_VLAN as u16, 0, (0, 0))));
+
+        interest.arp = true;
+        interest.ipv6 = false;
+        interest.control = true;
+        interest.wol = true;
+        interest.eapol = true;
+        let program = super::program(&interest);
+        assert!(run(&program, &frame(ETHERTYPE_ARP as u16, 0, (0, 0))));
+        assert!(run(&program, &frame(ipv4, 2, (0, 0))));
+        assert!(!run(&program, &frame(ipv6, udp, (50000, 5353))));
+        assert!(run(&program, &frame(ETHERTYPE_WOL as u16, 0, (0, 0))));
+        assert!(run(&program, &frame(ETHERTYPE_EAPOL as u16, 0, (0, 0))));
+        assert!(!run(&program, &frame(ETHERTYPE_LLDP as u16, 0, (0, 0))));
+    }
+}
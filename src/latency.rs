@@ -0,0 +1,216 @@
+//! Time frames spend in the forwarder, from being pulled off the receiving
+//! channel to being sent: a histogram per path cheap enough to stay on, and
+//! for `--trace-packets` a log line per stage of every accepted frame.
+
+use serde::Serialize;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use tracing::info;
+
+/// Sub-buckets per power of two, which bounds the error of a percentile to
+/// one sixteenth of its value
+const SUB_BUCKET_BITS: u32 = 4;
+const SUB_BUCKETS: u64 = 1 << SUB_BUCKET_BITS;
+/// Microseconds up to 2^40, about 12 days; longer times count as the last
+/// bucket
+const MAX_EXPONENT: u32 = 40;
+const BUCKETS: usize = ((MAX_EXPONENT - SUB_BUCKET_BITS + 1) as u64 * SUB_BUCKETS) as usize;
+
+/// Log-linear histogram of latencies in microseconds, in the manner of HDR
+/// histograms: exact below 16µs, within 1/16 above
+pub struct LatencyHistogram {
+    buckets: Box<[AtomicU64]>,
+    max: AtomicU64,
+}
+
+fn bucket(micros: u64) -> usize {
+    if micros < SUB_BUCKETS {
+        return micros as usize;
+    }
+    let exponent = 63 - micros.leading_zeros();
+    if exponent >= MAX_EXPONENT {
+        return BUCKETS - 1;
+    }
+    let sub = (micros >> (exponent - SUB_BUCKET_BITS)) & (SUB_BUCKETS - 1);
+    ((exponent - SUB_BUCKET_BITS + 1) as u64 * SUB_BUCKETS + sub) as usize
+}
+
+/// Largest value counted in `bucket`
+fn highest(bucket: usize) -> u64 {
+    let bucket = bucket as u64;
+    if bucket < SUB_BUCKETS {
+        return bucket;
+    }
+    let shift = bucket / SUB_BUCKETS - 1;
+    ((SUB_BUCKETS + bucket % SUB_BUCKETS + 1) << shift) - 1
+}
+
+impl LatencyHistogram {
+    pub fn new() -> Self {
+        LatencyHistogram {
+            buckets: (0..BUCKETS).map(|_| AtomicU64::new(0)).collect(),
+            max: AtomicU64::new(0),
+        }
+    }
+
+    pub fn record(&self, latency: Duration) {
+        let micros = latency.as_micros().min(u128::from(u64::MAX)) as u64;
+        self.buckets[bucket(micros)].fetch_add(1, Ordering::Relaxed);
+        self.max.fetch_max(micros, Ordering::Relaxed);
+    }
+
+    pub fn reset(&self) {
+        for counter in self.buckets.iter().chain([&self.max]) {
+            counter.store(0, Ordering::Relaxed);
+        }
+    }
+
+    /// Percentiles of what was recorded so far. Frames recorded meanwhile
+    /// may or may not be in it.
+    pub fn snapshot(&self) -> LatencySnapshot {
+        let counts: Vec<u64> = self
+            .buckets
+            .iter()
+            .map(|bucket| bucket.load(Ordering::Relaxed))
+            .collect();
+        let count: u64 = counts.iter().sum();
+        let max = self.max.load(Ordering::Relaxed);
+        let percentile = |percent: u64| {
+            let rank = (count * percent).div_ceil(100).max(1);
+            let mut seen = 0;
+            for (bucket, n) in counts.iter().enumerate() {
+                seen += n;
+                if seen >= rank {
+                    return highest(bucket).min(max);
+                }
+            }
+            max
+        };
+        if count == 0 {
+            return LatencySnapshot::default();
+        }
+        LatencySnapshot {
+            count,
+            p50_us: percentile(50),
+            p95_us: percentile(95),
+            p99_us: percentile(99),
+            max_us: max,
+        }
+    }
+}
+
+impl Default for LatencyHistogram {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Debug for LatencyHistogram {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.snapshot().fmt(f)
+    }
+}
+
+/// Percentiles of a [`LatencyHistogram`], in microseconds
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct LatencySnapshot {
+    pub count: u64,
+    pub p50_us: u64,
+    pub p95_us: u64,
+    pub p99_us: u64,
+    pub max_us: u64,
+}
+
+impl fmt::Display for LatencySnapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "latency p50={}µs p95={}µs p99={}µs max={}µs",
+            self.p50_us, self.p95_us, self.p99_us, self.max_us
+        )
+    }
+}
+
+/// Stage of an accepted frame on its way through the forwarder
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    Received,
+    /// Accepted by the filters, the loop guard, the rate limit and caches
+    Filtered,
+    Rewritten,
+    Queued,
+    Sent,
+}
+
+impl Stage {
+    pub fn name(self) -> &'static str {
+        match self {
+            Stage::Received => "received",
+            Stage::Filtered => "filtered",
+            Stage::Rewritten => "rewritten",
+            Stage::Queued => "queued",
+            Stage::Sent => "sent",
+        }
+    }
+}
+
+/// Hands out the IDs of `--trace-packets`
+#[derive(Debug, Default)]
+pub struct Tracer {
+    last: AtomicU64,
+}
+
+impl Tracer {
+    /// ID of the next accepted frame, counting from 1
+    pub fn start(&self) -> u64 {
+        self.last.fetch_add(1, Ordering::Relaxed) + 1
+    }
+}
+
+/// Logs that frame `id`, received at `received`, reached `stage` on `iface`
+pub fn trace(id: u64, stage: Stage, iface: &str, received: Instant) {
+    let micros = received.elapsed().as_micros() as u64;
+    info!(
+        trace = id,
+        stage = stage.name(),
+        elapsed_us = micros,
+        "Packet {} {} on {} +{}µs",
+        id,
+        stage.name(),
+        iface,
+        micros
+    );
+    #[cfg(feature = "otel")]
+    crate::otel::trace(id, stage, iface, received);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percentiles_within_a_sixteenth() {
+        for micros in [0, 15, 16, 17, 31, 32, 1000, 123_456, 1 << 39] {
+            let bucket = bucket(micros);
+            assert!(highest(bucket) >= micros, "{}", micros);
+            assert!(bucket == 0 || highest(bucket - 1) < micros, "{}", micros);
+            assert!(highest(bucket) - micros <= micros / 16, "{}", micros);
+        }
+        assert_eq!(bucket(u64::MAX), BUCKETS - 1);
+
+        let histogram = LatencyHistogram::new();
+        assert_eq!(histogram.snapshot(), LatencySnapshot::default());
+        for micros in 1..=100 {
+            histogram.record(Duration::from_micros(micros));
+        }
+        histogram.record(Duration::from_millis(5));
+        let snapshot = histogram.snapshot();
+        assert_eq!((snapshot.count, snapshot.max_us), (101, 5000));
+        assert!((51..=54).contains(&snapshot.p50_us), "{:?}", snapshot);
+        assert!((96..=102).contains(&snapshot.p95_us), "{:?}", snapshot);
+        assert!((100..=106).contains(&snapshot.p99_us), "{:?}", snapshot);
+        histogram.reset();
+        assert_eq!(histogram.snapshot().count, 0);
+    }
+}
@@ -0,0 +1,1041 @@
+//! Packet forwarding engine between external and internal network
+//! interfaces, filtering and rewriting the discovery and control protocols
+//! it lets through.
+//!
+//! The `nw-pckt-fwd` binary is a thin wrapper around [`cli::main`]; other
+//! services embed the engine through [`Forwarder`]:
+//!
+//! ```no_run
+//! use nw_pckt_fwd::Forwarder;
+//! use tokio_util::sync::CancellationToken;
+//!
+//! # async fn forward() -> Result<(), nw_pckt_fwd::Error> {
+//! let forwarder = Forwarder::builder()
+//!     .external("eth0")
+//!     .internal("vmbr0")
+//!     .filter("udp and port 5353".parse().expect("valid expression"))
+//!     .build()?;
+//! forwarder.run(CancellationToken::new()).await
+//! # }
+//! ```
+
+mod allowlist;
+mod arp;
+#[cfg(feature = "bench")]
+#[doc(hidden)]
+pub mod bench;
+mod bridge;
+mod capture;
+mod checksum;
+pub mod cli;
+mod coap;
+mod config;
+mod control;
+#[cfg(feature = "dbus")]
+mod dbus;
+mod dhcp;
+mod dhcpv6;
+mod discovery;
+mod dscp;
+mod error;
+mod expression;
+mod filter;
+mod fmt;
+mod forward;
+#[cfg(fuzzing)]
+#[doc(hidden)]
+pub mod fuzz;
+mod hostmac;
+mod iface;
+mod inventory;
+mod iplink;
+mod journal;
+mod kernelfilter;
+mod latency;
+mod link;
+mod logging;
+mod loopguard;
+mod mdns;
+mod mdnscache;
+mod mdnsmerge;
+mod mdnsunicast;
+mod nameservice;
+mod nat;
+mod ndp;
+mod netns;
+mod otel;
+mod oversize;
+mod packetsocket;
+mod pair;
+mod passthrough;
+mod pause;
+mod pcap;
+mod pool;
+mod privileges;
+mod prober;
+mod profile;
+mod quota;
+mod random;
+mod ratelimit;
+mod responder;
+mod rewrite;
+mod routeradvert;
+mod rules;
+mod schema;
+mod seccomp;
+mod sender;
+mod shaper;
+mod snooping;
+mod ssdp;
+mod ssdpcache;
+mod ssdpresponse;
+mod standby;
+mod state;
+mod stats;
+mod storm;
+mod summary;
+mod supervise;
+mod systemd;
+mod talkers;
+#[cfg(test)]
+mod testutil;
+mod threads;
+mod ttl;
+mod vlan;
+mod wol;
+mod wsd;
+#[cfg(feature = "af-xdp")]
+mod xdp;
+
+use clap::builder::RangedU64ValueParser;
+use clap::{FromArgMatches, ValueEnum, ValueHint};
+use pnet::util::MacAddr;
+use serde::{Deserialize, Serialize};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddrV4};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use allowlist::IpNetwork;
+use arp::ArpMode;
+use dscp::{DscpMark, MarkDirection};
+use filter::{LLMNR_PORT, MDNS_PORT};
+use iface::{Backend, LinkDown, PerInterface};
+use iplink::LinkType;
+use logging::{LogFormat, LogLevel, LogTarget};
+use nameservice::NameServiceMode;
+use ndp::NdpMode;
+use otel::{OtlpHeader, OtlpProtocol};
+use oversize::OversizePolicy;
+use pair::{parse_pair, Pair, Role};
+use pcap::parse_size;
+use profile::Profile;
+use seccomp::SeccompMode;
+use sender::QueuePolicy;
+use snooping::UnknownGroups;
+use ssdp::LocationMapping;
+use supervise::OnTaskFailure;
+use threads::Affinity;
+use vlan::VlanEgress;
+use wsd::WsdAction;
+
+pub use error::Error;
+pub use expression::Expression;
+pub use forward::{Forwarder, ForwarderBuilder};
+pub use quota::Quota;
+pub use rules::Rule;
+pub use shaper::Shape;
+
+/// Options of a forwarder, given to `run` on the command line
+#[derive(clap::Args, Debug)]
+struct Args {
+    /// Read options from a TOML file; options given on the command line
+    /// take precedence
+    #[arg(long, value_name = "FILE", value_hint = ValueHint::FilePath)]
+    config: Option<PathBuf>,
+
+    /// Print the effective configuration in the file format and exit
+    #[arg(long)]
+    dump_config: bool,
+
+    /// Log verbosity; without it RUST_LOG is honoured when set
+    #[arg(long, value_enum, default_value_t = LogLevel::Info)]
+    log_level: LogLevel,
+
+    /// Log output format
+    #[arg(long, value_enum, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+
+    /// Where log output goes; the journal gets the event fields, such as
+    /// IFACE, DIRECTION, PROTO, DECISION and DROP_REASON, as journal fields
+    #[arg(long, value_enum, default_value_t = LogTarget::Stderr)]
+    log_target: LogTarget,
+
+    /// Period over which repetitive per-packet messages of one kind, such
+    /// as drops for the same reason, are counted at debug level
+    #[arg(long, value_parser = humantime::parse_duration, default_value = "10s")]
+    log_suppress_window: Duration,
+
+    /// Messages of one kind logged per window before the rest are only
+    /// summed up; at trace level all are logged
+    #[arg(long, default_value_t = 5)]
+    log_suppress_threshold: u64,
+
+    /// Bytes of each received frame printed as a hexdump at debug level,
+    /// after a one-line summary of the frame; 0 prints none. At trace level
+    /// the summary is printed for every frame.
+    #[arg(long, value_name = "BYTES", default_value_t = 0)]
+    dump_payload: usize,
+
+    /// Print backtraces on panics
+    #[arg(long)]
+    debug: bool,
+
+    /// External network interface name
+    #[arg(
+        long,
+        conflicts_with_all = ["pair", "bridge"],
+        requires = "internal_iface"
+    )]
+    external_iface: Option<String>,
+
+    /// Internal network interface name
+    #[arg(
+        long,
+        conflicts_with_all = ["pair", "bridge"],
+        requires = "external_iface"
+    )]
+    internal_iface: Option<String>,
+
+    /// Interface pair to forward between, as external:IFACE,internal:IFACE.
+    /// Repeatable; an external interface may be shared by several pairs.
+    #[arg(long, value_name = "PAIR", value_parser = parse_pair, conflicts_with = "bridge")]
+    pair: Vec<Pair>,
+
+    /// Forward between all of these interfaces as a MAC learning bridge
+    /// instead of fixed pairs, repeatable or comma-separated
+    #[arg(
+        long,
+        value_name = "IFACE",
+        value_delimiter = ',',
+        conflicts_with = "snat"
+    )]
+    bridge: Vec<String>,
+
+    /// VLAN of the external interfaces: only frames tagged with it are
+    /// accepted there, and frames sent there are tagged with it
+    #[arg(
+        long,
+        value_name = "ID",
+        value_parser = clap::value_parser!(u16).range(1..4095),
+        conflicts_with = "bridge"
+    )]
+    external_vlan: Option<u16>,
+
+    /// VLAN of the internal interfaces, like --external-vlan
+    #[arg(
+        long,
+        value_name = "ID",
+        value_parser = clap::value_parser!(u16).range(1..4095),
+        conflicts_with = "bridge"
+    )]
+    internal_vlan: Option<u16>,
+
+    /// Link type of the internal interfaces, `ip` for point-to-point
+    /// devices such as TUN that carry bare IP packets. Frames sent from
+    /// them to the external side are addressed to the MACs learned there.
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = LinkType::Ethernet,
+        conflicts_with_all = ["bridge", "internal_vlan"]
+    )]
+    internal_link_type: LinkType,
+
+    /// Network namespace the external interfaces are in, a name under
+    /// /run/netns or a path such as /proc/PID/ns/net
+    #[arg(long, value_name = "NAME|PATH", conflicts_with = "bridge")]
+    external_netns: Option<String>,
+
+    /// Network namespace the internal interfaces are in, like
+    /// --external-netns
+    #[arg(long, value_name = "NAME|PATH", conflicts_with = "bridge")]
+    internal_netns: Option<String>,
+
+    /// Whether frames received tagged keep their tags when sent to an
+    /// interface without a VLAN
+    #[arg(long, value_enum, default_value_t = VlanEgress::Strip)]
+    vlan_egress: VlanEgress,
+
+    /// Maximum number of MAC addresses learned in bridge mode or with
+    /// --rewrite-unicast-mac
+    #[arg(long, default_value_t = 1024, value_parser = RangedU64ValueParser::<usize>::new().range(1..))]
+    mac_table_size: usize,
+
+    /// How long a learned MAC address stays valid without frames from it
+    #[arg(long, value_parser = humantime::parse_duration, default_value = "5m")]
+    mac_ttl: Duration,
+
+    /// Wait for the interfaces to appear instead of failing at startup
+    #[arg(long)]
+    wait_for_iface: bool,
+
+    /// Give up waiting for the interfaces after this long (default: wait forever)
+    #[arg(long, value_parser = humantime::parse_duration)]
+    wait_timeout: Option<Duration>,
+
+    /// What happens when a forwarding interface is down or has no link at
+    /// startup: a warning, or refusing to start
+    #[arg(long, value_enum, default_value_t = LinkDown::Warn)]
+    on_link_down: LinkDown,
+
+    /// Presets of ports, mDNS services and SSDP targets to forward:
+    /// chromecast, airplay, printer, dlna or onvif; repeatable or
+    /// comma-separated, and extended by the explicit options
+    #[arg(long, value_delimiter = ',')]
+    profile: Vec<Profile>,
+
+    /// UDP ports to forward, repeatable or comma-separated (default: 1900
+    /// without a profile)
+    #[arg(long, value_delimiter = ',', value_parser = clap::value_parser!(u16).range(1..))]
+    ports: Vec<u16>,
+
+    /// TCP ports to forward, repeatable or comma-separated (default: none)
+    #[arg(long, value_delimiter = ',', value_parser = clap::value_parser!(u16).range(1..))]
+    tcp_ports: Vec<u16>,
+
+    /// Forward or drop matching traffic in one direction before the port
+    /// filters, e.g. "in->out udp dport 1900 forward"; repeatable, the first
+    /// matching rule wins
+    #[arg(long, value_name = "RULE")]
+    rule: Vec<Rule>,
+
+    /// Forward only the frames matching an expression such as "udp and (port
+    /// 1900 or port 5353) and src net 192.168.100.0/24", checked after the
+    /// rules; an "in:" or "out:" prefix limits it to one direction;
+    /// repeatable, any match forwards
+    #[arg(long, value_name = "EXPR")]
+    filter: Vec<Expression>,
+
+    /// Leave out the kernel socket filter that discards the frames no filter
+    /// could forward before they reach userspace
+    #[arg(long)]
+    no_kernel_filter: bool,
+
+    /// Only forward frames from the internal side with one of these source
+    /// MAC addresses, repeatable or comma-separated
+    #[arg(
+        long,
+        value_name = "MAC",
+        value_delimiter = ',',
+        conflicts_with = "bridge"
+    )]
+    allow_src_mac: Vec<MacAddr>,
+
+    /// Only forward frames from the internal side with a source IP in one of
+    /// these networks (CIDR), repeatable or comma-separated
+    #[arg(
+        long,
+        value_name = "CIDR",
+        value_delimiter = ',',
+        conflicts_with = "bridge"
+    )]
+    allow_src_ip: Vec<IpNetwork>,
+
+    /// Frames forwarded per second in total (default: unlimited)
+    #[arg(long, value_name = "PPS", value_parser = clap::value_parser!(u32).range(1..))]
+    max_pps: Option<u32>,
+
+    /// Frames forwarded per second from one source address (default:
+    /// unlimited)
+    #[arg(long, value_name = "PPS", value_parser = clap::value_parser!(u32).range(1..))]
+    max_pps_per_host: Option<u32>,
+
+    /// mDNS frames forwarded per second from one source address, in place of
+    /// --max-pps-per-host; 0 disables the limit
+    #[arg(long, value_name = "PPS", default_value_t = 10)]
+    mdns_max_pps_per_host: u32,
+
+    /// Longest burst at the full rate let through after a quiet period
+    #[arg(long, value_parser = humantime::parse_duration, default_value = "1s")]
+    rate_limit_burst: Duration,
+
+    /// Source addresses tracked for --max-pps-per-host before the least
+    /// recently seen one is forgotten
+    #[arg(long, default_value_t = 1024, value_parser = RangedU64ValueParser::<usize>::new().range(1..))]
+    rate_limit_hosts: usize,
+
+    /// Drop matching frames once a byte or packet budget is used up, until
+    /// its window rolls over, e.g. "in->out discovery bytes 1M per 1h";
+    /// repeatable, a frame has to fit into every quota it matches
+    #[arg(long, value_name = "QUOTA")]
+    quota: Vec<Quota>,
+
+    /// Delay frames sent in a direction to this rate instead of sending
+    /// them at once, per egress interface, e.g. "out->in 2M/s burst 32K";
+    /// repeatable, one per direction
+    #[arg(long, value_name = "SHAPE")]
+    shape: Vec<Shape>,
+
+    /// Longest a frame is delayed by --shape before it is dropped instead
+    #[arg(long, value_parser = humantime::parse_duration, default_value = "20ms")]
+    shape_max_delay: Duration,
+
+    /// Broadcast and multicast frames received per second in one direction
+    /// above which they are not forwarded until the storm passes; 0
+    /// disables storm control
+    #[arg(long, value_name = "PPS", default_value_t = 5000)]
+    storm_threshold: u32,
+
+    /// Drop frames received on an interface an identical frame was sent on
+    /// this recently, and frames carrying the MAC of one of the forwarder's
+    /// interfaces
+    #[arg(long, value_parser = humantime::parse_duration, default_value = "1s")]
+    loop_window: Duration,
+
+    /// Forward frames identical to one forwarded to the same interface
+    /// within --loop-window. Such repeats are dropped by default, as a
+    /// second path between the segments reflects them; mDNS probes and
+    /// announcements, which hosts repeat on purpose, are always forwarded,
+    /// but other retransmissions such as SSDP retries within the window are
+    /// dropped unless this is given
+    #[arg(long)]
+    no_loop_drop_repeats: bool,
+
+    /// Forward frames without checking for loops and echoes
+    #[arg(long)]
+    no_loop_guard: bool,
+
+    /// Forward mDNS (UDP 5353) traffic
+    #[arg(long)]
+    enable_mdns: bool,
+
+    /// mDNS service names whose queries and records are forwarded,
+    /// repeatable or comma-separated (default: _googlecast._tcp.local
+    /// without a profile)
+    #[arg(long, value_delimiter = ',')]
+    mdns_services: Vec<String>,
+
+    /// Forward mDNS traffic regardless of the service names it refers to
+    #[arg(long)]
+    no_mdns_filtering: bool,
+
+    /// Forward only the services listed, leaving out the speaker group
+    /// services of the chromecast profile and the records of devices that
+    /// announced an allowed service for another one
+    #[arg(long)]
+    chromecast_strict: bool,
+
+    /// Clamp the TTLs of forwarded mDNS records to this; goodbye records
+    /// with TTL 0 are left alone
+    #[arg(long, value_parser = humantime::parse_duration)]
+    mdns_max_ttl: Option<Duration>,
+
+    /// Drop mDNS packets from port 5353 that arrive with an IP TTL or hop
+    /// limit below 255, a sign they were routed from another link.
+    /// Forwarded mDNS is always sent with 255.
+    #[arg(long)]
+    mdns_enforce_ttl: bool,
+
+    /// Remove TXT records from mDNS messages forwarded to the external side
+    #[arg(long, conflicts_with = "bridge")]
+    mdns_strip_txt: bool,
+
+    /// TXT keys removed from mDNS messages forwarded to the external side,
+    /// e.g. fn for the Chromecast friendly name, repeatable or comma-separated
+    #[arg(long, value_delimiter = ',', conflicts_with = "bridge")]
+    mdns_strip_txt_key: Vec<String>,
+
+    /// Answer mDNS queries from the internal side with the records of
+    /// responses forwarded inwards instead of forwarding them again
+    #[arg(long, conflicts_with = "bridge")]
+    mdns_cache: bool,
+
+    /// Maximum number of records in the mDNS cache
+    #[arg(long, default_value_t = 256, value_parser = RangedU64ValueParser::<usize>::new().range(1..))]
+    mdns_cache_size: usize,
+
+    /// Forward unicast mDNS responses regardless of outstanding queries
+    /// asking for them
+    #[arg(long)]
+    no_mdns_tracking: bool,
+
+    /// How long unicast responses to a forwarded mDNS query with the QU bit
+    /// or from a port other than 5353 are accepted
+    #[arg(long, value_parser = humantime::parse_duration, default_value = "5s")]
+    mdns_response_window: Duration,
+
+    /// Hold back mDNS responses forwarded to the internal side for this
+    /// long (20ms to 120ms) and send their records merged, and delay cache
+    /// answers to multicast queries as RFC 6762 asks
+    #[arg(long, value_name = "WINDOW", value_parser = humantime::parse_duration, conflicts_with = "bridge")]
+    mdns_aggregate: Option<Duration>,
+
+    /// Maximum number of outstanding mDNS queries tracked
+    #[arg(long, default_value_t = 256)]
+    mdns_max_queries: usize,
+
+    /// Do not forward SSDP (UDP 1900) traffic
+    #[arg(long)]
+    disable_ssdp: bool,
+
+    /// Only forward IPv4 traffic
+    #[arg(long)]
+    disable_ipv6: bool,
+
+    /// Rewrite the source MAC of forwarded frames to the egress interface MAC
+    #[arg(long)]
+    masquerade_mac: bool,
+
+    /// Decrement the IPv4 TTL and IPv6 hop limit of forwarded packets like a
+    /// router, dropping those that expire
+    #[arg(long)]
+    decrement_ttl: bool,
+
+    /// UDP port whose packets keep their TTL with --decrement-ttl; by default
+    /// mDNS and LLMNR, which check it to accept only on-link senders
+    #[arg(long, value_name = "PORT", default_values_t = [MDNS_PORT, LLMNR_PORT])]
+    ttl_exempt_port: Vec<u16>,
+
+    /// DSCP forwarded packets of a class are marked with, `discovery=cs4`
+    /// for UDP or `control=af41` for TCP; a name such as cs4, af41 and ef,
+    /// or a number up to 63
+    #[arg(long, value_name = "CLASS=DSCP")]
+    dscp: Vec<DscpMark>,
+
+    /// Directions --dscp marks packets in
+    #[arg(long, value_enum, default_value_t = MarkDirection::Both)]
+    dscp_direction: MarkDirection,
+
+    /// Also set the 802.1p priority of marked frames sent tagged to the
+    /// class selector of their DSCP
+    #[arg(long, requires = "dscp")]
+    dscp_pcp: bool,
+
+    /// Learn the MACs of internal hosts and address unicast IPv4 frames sent
+    /// inwards to them, dropping those for hosts not learned yet
+    #[arg(long, conflicts_with = "bridge")]
+    rewrite_unicast_mac: bool,
+
+    /// Rewrite the IPv4 source of frames leaving via the external interface,
+    /// using the given address or the first IPv4 address of the interface
+    #[arg(long, value_name = "EXTERNAL_IP", num_args = 0..=1)]
+    snat: Option<Option<Ipv4Addr>>,
+
+    /// Interfaces to put into promiscuous mode while the forwarder runs
+    #[arg(long, value_enum, default_value_t = Promiscuous::All)]
+    promiscuous: Promiscuous,
+
+    /// Multicast groups to join on both interfaces so the NIC delivers them
+    /// without promiscuous mode, repeatable or comma-separated
+    #[arg(long, value_delimiter = ',', value_parser = parse_multicast_group)]
+    join_group: Vec<IpAddr>,
+
+    /// Frames queued per egress interface before new ones are dropped
+    #[arg(long, default_value_t = 1024, value_parser = RangedU64ValueParser::<usize>::new().range(1..))]
+    send_queue_capacity: usize,
+
+    /// Frame dropped when a send queue is full
+    #[arg(long, value_enum, default_value_t = QueuePolicy::DropNewest)]
+    queue_policy: QueuePolicy,
+
+    /// Buffers kept for reuse by accepted frames on their way to the send
+    /// queues; frames beyond that are allocated and counted
+    #[arg(long, default_value_t = 4096, value_parser = RangedU64ValueParser::<usize>::new().range(1..))]
+    buffer_pool_size: usize,
+
+    /// Pin the receive threads to these CPUs, e.g. `2-3`, or only the one of
+    /// an interface with `eth0=2`; repeatable
+    #[arg(long, value_name = "[IFACE=]CPUS")]
+    rx_affinity: Vec<Affinity>,
+
+    /// Pin the send threads to these CPUs, like --rx-affinity
+    #[arg(long, value_name = "[IFACE=]CPUS")]
+    tx_affinity: Vec<Affinity>,
+
+    /// How interfaces are driven: pnet channels, packet sockets moving
+    /// batches of frames per system call, or AF_XDP sockets for the
+    /// forwarded ports (both experimental, af-xdp needs the feature of the
+    /// same name)
+    #[arg(long, value_enum, default_value_t = Backend::Pnet)]
+    backend: Backend,
+
+    /// Most frames received or sent per system call with --backend raw or
+    /// af-xdp
+    #[arg(long, default_value_t = 32, value_parser = RangedU64ValueParser::<usize>::new().range(1..=1024))]
+    batch_size: usize,
+
+    /// Kernel receive buffer of the packet sockets, e.g. `4M`, or only the
+    /// one of an interface with `eth0=4M`; repeatable. Raise it if the
+    /// statistics show kernel drops during bursts of responses.
+    #[arg(long, value_name = "[IFACE=]SIZE")]
+    rx_buffer_size: Vec<PerInterface<u64>>,
+
+    /// Kernel send buffer of the packet sockets, like --rx-buffer-size
+    #[arg(long, value_name = "[IFACE=]SIZE")]
+    tx_buffer_size: Vec<PerInterface<u64>>,
+
+    /// How long a capture thread waits for a frame before checking for
+    /// shutdown, 100ms by default, or only on an interface with
+    /// `eth0=50ms`; repeatable
+    #[arg(long, value_name = "[IFACE=]DURATION")]
+    read_timeout: Vec<PerInterface<Duration>>,
+
+    /// What happens when a capture thread panics: the forwarder exits with
+    /// an error, or the thread's loop is restarted with backoff
+    #[arg(long, value_enum, default_value_t = OnTaskFailure::Exit)]
+    on_task_failure: OnTaskFailure,
+
+    /// Restarts of one capture loop with --on-task-failure restart before
+    /// the forwarder exits anyway
+    #[arg(long, default_value_t = 5)]
+    max_task_restarts: u32,
+
+    /// What happens to frames larger than the MTU of the egress interface:
+    /// dropped, or IPv4 packets without the don't fragment flag split into
+    /// fragments
+    #[arg(long, value_enum, default_value_t = OversizePolicy::Drop)]
+    oversize_policy: OversizePolicy,
+
+    /// Drop frames whose IPv4 header or UDP/TCP checksum is wrong; those
+    /// whose sender left the checksum to offload are still forwarded
+    #[arg(long)]
+    verify_checksums: bool,
+
+    /// Answer IPv6 packets too large for the egress interface with an
+    /// ICMPv6 Packet Too Big to their sender
+    #[arg(long)]
+    icmp_too_big: bool,
+
+    /// Forward unicast SSDP responses regardless of outstanding M-SEARCH requests
+    #[arg(long)]
+    no_ssdp_tracking: bool,
+
+    /// How long unicast responses to a forwarded M-SEARCH are accepted
+    #[arg(long, value_parser = humantime::parse_duration, default_value = "5s")]
+    ssdp_response_window: Duration,
+
+    /// Maximum number of outstanding M-SEARCH requests tracked
+    #[arg(long, default_value_t = 256)]
+    ssdp_max_searches: usize,
+
+    /// SSDP search and notification targets (ST/NT) forwarded, repeatable
+    /// or comma-separated (default: ssdp:all and the DIAL service and
+    /// device types without a profile)
+    #[arg(long, value_delimiter = ',')]
+    ssdp_targets: Vec<String>,
+
+    /// Forward SSDP traffic without inspecting its messages
+    #[arg(long)]
+    no_ssdp_filtering: bool,
+
+    /// Accept M-SEARCH requests from the external side as well
+    #[arg(long)]
+    ssdp_external_search: bool,
+
+    /// Accept NOTIFY messages and search responses from the internal side as well
+    #[arg(long)]
+    ssdp_internal_announce: bool,
+
+    /// Rewrite the host of SSDP LOCATION URLs from an internal address to
+    /// the one advertised outside, and back for frames sent inwards,
+    /// repeatable or comma-separated
+    #[arg(
+        long,
+        value_name = "INTERNAL=ADVERTISED",
+        value_delimiter = ',',
+        conflicts_with = "bridge"
+    )]
+    ssdp_location_map: Vec<LocationMapping>,
+
+    /// Drop SSDP messages whose LOCATION cannot be parsed instead of
+    /// forwarding them unchanged
+    #[arg(long)]
+    ssdp_location_fail_closed: bool,
+
+    /// Answer SSDP searches from the internal side with the devices
+    /// announced by NOTIFYs forwarded inwards instead of forwarding them
+    #[arg(long, conflicts_with = "bridge")]
+    ssdp_cache: bool,
+
+    /// Maximum number of devices in the SSDP cache
+    #[arg(long, default_value_t = 256, value_parser = RangedU64ValueParser::<usize>::new().range(1..))]
+    ssdp_cache_size: usize,
+
+    /// SERVER header of the responses sent from the SSDP cache, in place of
+    /// the one the device announced, e.g. to tell them from direct answers
+    #[arg(long, value_name = "SERVER", requires = "ssdp_cache")]
+    ssdp_server_string: Option<String>,
+
+    /// Keep an inventory of the devices announcing themselves over mDNS
+    /// and SSDP on the external side, with their names, addresses and when
+    /// they were seen, listed on SIGUSR1
+    #[arg(long, conflicts_with = "bridge")]
+    device_inventory: bool,
+
+    /// Maximum number of devices in the inventory, evicting the one seen
+    /// least recently
+    #[arg(long, default_value_t = 256, value_parser = RangedU64ValueParser::<usize>::new().range(1..))]
+    device_inventory_size: usize,
+
+    /// How long devices stay in the inventory after their announcement
+    /// expired or they said goodbye
+    #[arg(long, value_parser = humantime::parse_duration, default_value = "1h")]
+    device_retention: Duration,
+
+    /// Periodically search for devices on the external side on behalf of
+    /// the internal side, with SSDP M-SEARCHes and mDNS PTR queries sent
+    /// from the external interface's address
+    #[arg(long, conflicts_with = "bridge")]
+    probe: bool,
+
+    /// Time between two probes
+    #[arg(long, value_parser = humantime::parse_duration, default_value = "5m")]
+    probe_interval: Duration,
+
+    /// Longest random delay added to every probe interval, so several
+    /// forwarders do not probe in step
+    #[arg(long, value_parser = humantime::parse_duration, default_value = "30s")]
+    probe_jitter: Duration,
+
+    /// SSDP search targets probed for, repeatable or comma-separated
+    /// (default: the forwarded SSDP targets)
+    #[arg(long, value_delimiter = ',', requires = "probe")]
+    probe_ssdp_targets: Vec<String>,
+
+    /// mDNS service types queried by the prober, repeatable or
+    /// comma-separated (default: the forwarded mDNS services)
+    #[arg(long, value_delimiter = ',', requires = "probe")]
+    probe_mdns_services: Vec<String>,
+
+    /// Forward WS-Discovery (UDP 3702) traffic
+    #[arg(long)]
+    enable_wsd: bool,
+
+    /// SOAP actions of the WS-Discovery messages forwarded, repeatable or
+    /// comma-separated
+    #[arg(
+        long,
+        value_enum,
+        value_delimiter = ',',
+        default_value = "probe,probe-matches,hello,bye"
+    )]
+    wsd_actions: Vec<WsdAction>,
+
+    /// Forward WS-Discovery traffic without inspecting its messages
+    #[arg(long)]
+    no_wsd_filtering: bool,
+
+    /// Accept WS-Discovery probes from the external side as well
+    #[arg(long)]
+    wsd_external_probe: bool,
+
+    /// Accept WS-Discovery matches, hellos and byes from the internal side
+    /// as well
+    #[arg(long)]
+    wsd_internal_announce: bool,
+
+    /// Forward CoAP (UDP 5683) traffic, multicast to 224.0.1.187 and
+    /// ff0x::fd for discovery
+    #[arg(long)]
+    enable_coap: bool,
+
+    /// Forward CoAP traffic without inspecting its messages
+    #[arg(long)]
+    no_coap_filtering: bool,
+
+    /// Accept CoAP requests from the external side as well
+    #[arg(long)]
+    coap_external_request: bool,
+
+    /// Accept CoAP responses from the internal side as well
+    #[arg(long)]
+    coap_internal_response: bool,
+
+    /// Directions LLMNR (UDP 5355) is forwarded in
+    #[arg(long, value_enum, default_value_t = NameServiceMode::Block)]
+    llmnr: NameServiceMode,
+
+    /// Names forwarded LLMNR messages must ask for, repeatable or
+    /// comma-separated; any name if none are given
+    #[arg(long, value_name = "NAME", value_delimiter = ',')]
+    llmnr_names: Vec<String>,
+
+    /// Drop LLMNR packets to the multicast group that arrive with an IP TTL
+    /// or hop limit above 1, which link-scoped senders do not use.
+    /// Forwarded multicast LLMNR is always sent with 1.
+    #[arg(long)]
+    llmnr_enforce_ttl: bool,
+
+    /// Directions the NetBIOS name service (UDP 137) is forwarded in. Its
+    /// broadcasts to the subnet of one side are readdressed to the subnet
+    /// of the other.
+    #[arg(long, value_enum, default_value_t = NameServiceMode::Block)]
+    netbios_ns: NameServiceMode,
+
+    /// Forward Wake-on-LAN magic packets, raw or to UDP port 9 or 7, from
+    /// the internal to the external side
+    #[arg(long, conflicts_with = "bridge")]
+    enable_wol: bool,
+
+    /// MAC addresses magic packets may wake, repeatable or comma-separated;
+    /// any if none are given
+    #[arg(
+        long,
+        value_name = "MAC",
+        value_delimiter = ',',
+        requires = "enable_wol"
+    )]
+    wol_targets: Vec<MacAddr>,
+
+    /// Pass EAPOL (802.1X) frames between the two interfaces of the pair as
+    /// they are, ahead of every filter, so the internal side can
+    /// authenticate itself on the external port
+    #[arg(long, conflicts_with = "bridge")]
+    pass_eapol: bool,
+
+    /// Pass LLDP frames between the two interfaces of a pair as they are,
+    /// ahead of every filter, so the switch sees the endpoint behind
+    #[arg(long, conflicts_with = "bridge")]
+    pass_lldp: bool,
+
+    /// Relay DHCP requests from the internal side to this server, or
+    /// broadcast them on the external side with 255.255.255.255
+    #[arg(long, value_name = "SERVER_IP", conflicts_with = "bridge")]
+    dhcp_relay: Option<Ipv4Addr>,
+
+    /// Add a relay agent information option carrying the internal
+    /// interface name to relayed DHCP requests
+    #[arg(long)]
+    dhcp_relay_option82: bool,
+
+    /// Forward IPv6 router advertisements from the external to the internal
+    /// side and router solicitations the other way. Advertisements from the
+    /// internal side are always dropped.
+    #[arg(long, conflicts_with = "bridge")]
+    forward_ra: bool,
+
+    /// Send forwarded router advertisements from the internal interface's
+    /// MAC and announce it as the router's link-layer address
+    #[arg(long, requires = "forward_ra")]
+    ra_rewrite_source: bool,
+
+    /// Relay DHCPv6 messages from the internal side to this server in
+    /// Relay-Forward messages, or to all relay agents and servers on the
+    /// external link with ff02::1:2
+    #[arg(long, value_name = "SERVER_IP", conflicts_with = "bridge")]
+    dhcpv6_relay: Option<Ipv6Addr>,
+
+    /// Drop ARP, forward it between the interfaces, or answer requests from
+    /// the internal side for hosts learned on the external side
+    #[arg(long, value_enum, default_value_t = ArpMode::Off)]
+    arp_mode: ArpMode,
+
+    /// Drop IPv6 neighbor discovery, forward it between the interfaces, or
+    /// answer solicitations from the internal side for hosts learned on the
+    /// external side
+    #[arg(long, value_enum, default_value_t = NdpMode::Off)]
+    ndp_mode: NdpMode,
+
+    /// Forward multicast UDP to every interface instead of only to those
+    /// where a listener joined the group by IGMP or MLD
+    #[arg(long)]
+    no_snooping: bool,
+
+    /// Multicast forwarded to an interface where no IGMP or MLD traffic was
+    /// seen yet
+    #[arg(long, value_enum, default_value_t = UnknownGroups::Flood)]
+    snooping_unknown: UnknownGroups,
+
+    /// Log forwarding statistics this often (default: only on shutdown)
+    #[arg(long, value_parser = humantime::parse_duration)]
+    stats_interval: Option<Duration>,
+
+    /// Write forwarded frames, as sent after rewriting, to this pcap file
+    #[arg(long, value_name = "PATH", value_hint = ValueHint::FilePath)]
+    pcap_forwarded: Option<PathBuf>,
+
+    /// Write frames dropped by a filter, rewrite or full send queue to this
+    /// pcap file
+    #[arg(long, value_name = "PATH", value_hint = ValueHint::FilePath)]
+    pcap_dropped: Option<PathBuf>,
+
+    /// Move pcap files to <PATH>.1 and start over once they reach this size,
+    /// e.g. 100M
+    #[arg(long, value_parser = parse_size)]
+    pcap_max_size: Option<u64>,
+
+    /// Replay frames from this pcap file as if received on the internal
+    /// interface, instead of capturing there, and exit at its end
+    #[arg(long, value_name = "FILE", value_hint = ValueHint::FilePath)]
+    pcap_in: Option<PathBuf>,
+
+    /// Keep the recorded gaps between replayed frames instead of replaying
+    /// as fast as possible
+    #[arg(long)]
+    replay_timing: bool,
+
+    /// Keep the device inventory, the SSDP and mDNS caches and the MAC
+    /// tables in this file across restarts: loaded at startup, saved
+    /// periodically and on shutdown. The --user must be able to write it.
+    #[arg(long, value_name = "PATH", value_hint = ValueHint::FilePath)]
+    state_file: Option<PathBuf>,
+
+    /// How often --state-file is saved while running
+    #[arg(long, value_name = "INTERVAL", value_parser = humantime::parse_duration, default_value = "5m")]
+    state_save_interval: Duration,
+
+    /// Send a copy of every forwarded frame, as sent, to this interface
+    #[arg(long, value_name = "NAME")]
+    mirror_iface: Option<String>,
+
+    /// Mirror frames that were dropped as well, as received
+    #[arg(long)]
+    mirror_dropped: bool,
+
+    /// Run as one of redundant instances that elect a leader over heartbeats
+    /// to this multicast group and port, e.g. 239.255.77.77:7777; only the
+    /// leader sends, a standby keeps receiving and learning
+    #[arg(long, value_name = "GROUP:PORT", value_parser = parse_standby_group, requires = "standby_iface", conflicts_with = "standby_lock")]
+    standby_group: Option<SocketAddrV4>,
+
+    /// Interface the --standby-group heartbeats are exchanged on, by its
+    /// IPv4 address
+    #[arg(long, value_name = "NAME", requires = "standby_group")]
+    standby_iface: Option<String>,
+
+    /// Run as one of redundant instances of which the one holding a lock on
+    /// this file on shared storage leads; only the leader sends
+    #[arg(long, value_name = "PATH", value_hint = ValueHint::FilePath)]
+    standby_lock: Option<PathBuf>,
+
+    /// How much this instance is preferred when two leaders with the same
+    /// epoch meet; higher wins
+    #[arg(long, default_value_t = 100)]
+    standby_priority: u8,
+
+    /// How often heartbeats are sent and the lock is tried
+    #[arg(long, value_parser = humantime::parse_duration, default_value = "1s")]
+    standby_interval: Duration,
+
+    /// Heartbeats of the leader missed before a standby takes over
+    #[arg(long, default_value_t = 3, value_parser = clap::value_parser!(u32).range(1..))]
+    standby_missed: u32,
+
+    /// Switch to this user, by name or UID, once the interfaces and pcap
+    /// files are open, dropping all supplementary groups. Reopening an
+    /// interface that went away and rotating pcap files in a directory the
+    /// user cannot write then fail; to keep those working, start as an
+    /// unprivileged user with ambient CAP_NET_RAW and CAP_NET_ADMIN instead.
+    #[arg(long, value_name = "USER", value_hint = ValueHint::Username)]
+    user: Option<String>,
+
+    /// Switch to this group, by name or GID, instead of the primary group of
+    /// the user
+    #[arg(long, value_name = "GROUP")]
+    group: Option<String>,
+
+    /// Restrict the system calls of the process to those forwarding needs
+    /// once it is set up. `log` lets everything through but has the kernel
+    /// log what is outside the allowlist; `enforce` kills the process on it.
+    #[arg(long, value_enum, default_value_t = SeccompMode::Off)]
+    seccomp: SeccompMode,
+
+    /// Answer requests such as `stats` or `pause` on this Unix socket, as
+    /// sent by the `ctl` command; /run/nw-pckt-fwd.sock if no path is given
+    #[arg(
+        long,
+        value_name = "PATH",
+        value_hint = ValueHint::FilePath,
+        num_args = 0..=1,
+        default_missing_value = control::DEFAULT_SOCKET
+    )]
+    control_socket: Option<PathBuf>,
+
+    /// Serve org.ghaf.PacketForwarder on the system bus, to enable and
+    /// disable forwarding, read statistics and follow discovered devices
+    #[arg(long)]
+    dbus: bool,
+
+    /// Export the counters as OpenTelemetry metrics over OTLP, and with
+    /// --trace-packets the stages of each frame as a span. The OTEL_*
+    /// environment variables configure what the options below leave out.
+    #[arg(long)]
+    otel: bool,
+
+    /// Collector the exports go to, by default http://localhost:4318 or 4317
+    /// for gRPC
+    #[arg(long, value_name = "URL", requires = "otel")]
+    otel_endpoint: Option<String>,
+
+    /// Transport of the exports, by default http/protobuf
+    #[arg(long, value_enum, requires = "otel")]
+    otel_protocol: Option<OtlpProtocol>,
+
+    /// Header sent with every export, such as an API key
+    #[arg(long, value_name = "NAME=VALUE", requires = "otel")]
+    otel_header: Vec<OtlpHeader>,
+
+    /// Time between metric exports, by default 60s
+    #[arg(long, value_name = "INTERVAL", value_parser = humantime::parse_duration, requires = "otel")]
+    otel_export_interval: Option<Duration>,
+
+    /// Run the full pipeline including rewrites but log frames instead of
+    /// sending them
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Log every stage of each accepted frame, from being received to being
+    /// sent, with an ID and the time since it was received
+    #[arg(long)]
+    trace_packets: bool,
+}
+
+impl Default for Args {
+    /// Options as if none were given on the command line
+    fn default() -> Self {
+        let name = env!("CARGO_PKG_NAME");
+        let matches =
+            <Args as clap::Args>::augment_args(clap::Command::new(name)).get_matches_from([name]);
+        Args::from_arg_matches(&matches).expect("defaults are valid options")
+    }
+}
+
+fn parse_standby_group(value: &str) -> Result<SocketAddrV4, String> {
+    let group: SocketAddrV4 = value.parse().map_err(|e| format!("{}", e))?;
+    if group.ip().is_multicast() {
+        Ok(group)
+    } else {
+        Err(format!("{} is not a multicast address", group.ip()))
+    }
+}
+
+fn parse_multicast_group(value: &str) -> Result<IpAddr, String> {
+    let ip: IpAddr = value.parse().map_err(|e| format!("{}", e))?;
+    if ip.is_multicast() {
+        Ok(ip)
+    } else {
+        Err(format!("{} is not a multicast address", ip))
+    }
+}
+
+/// Interfaces to open in promiscuous mode
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Promiscuous {
+    All,
+    External,
+    Internal,
+    None,
+}
+
+impl Promiscuous {
+    fn applies_to(self, role: Role) -> bool {
+        matches!(
+            (self, role),
+            (Promiscuous::All, _)
+                | (Promiscuous::External, Role::External)
+                | (Promiscuous::Internal, Role::Internal)
+                | (Promiscuous::External | Promiscuous::Internal, Role::Bridge)
+        )
+    }
+}
@@ -0,0 +1,193 @@
+//! Frame sources and sinks the capture and send tasks work on: the halves
+//! of a pnet datalink channel or of a raw packet socket or, in tests,
+//! in-memory queues.
+
+use crate::pool::PacketBuffer;
+use pnet::datalink::{DataLinkReceiver, DataLinkSender};
+use std::io;
+
+/// Where a capture loop receives frames from
+pub trait PacketSource: Send {
+    /// Returns the next frame. Fails with [`io::ErrorKind::TimedOut`] if none
+    /// arrived within the read timeout, so the caller can check whether it
+    /// should stop.
+    fn next(&mut self) -> io::Result<&[u8]>;
+}
+
+/// Where a send task transmits frames to
+pub trait PacketSink: Send {
+    /// Sends `frame` as it is
+    fn send(&mut self, frame: &[u8]) -> io::Result<()>;
+
+    /// Most frames [`send_batch`](Self::send_batch) takes at once
+    fn batch_size(&self) -> usize {
+        1
+    }
+
+    /// Sends the first frames of `frames`, returning how many were sent.
+    /// Fails only if not even the first one could be. The default sends
+    /// just the first frame.
+    fn send_batch(&mut self, frames: &[PacketBuffer]) -> io::Result<usize> {
+        self.send(&frames[0])?;
+        Ok(1)
+    }
+}
+
+impl PacketSource for Box<dyn DataLinkReceiver> {
+    fn next(&mut self) -> io::Result<&[u8]> {
+        DataLinkReceiver::next(self.as_mut())
+    }
+}
+
+impl PacketSink for Box<dyn DataLinkSender> {
+    fn send(&mut self, frame: &[u8]) -> io::Result<()> {
+        self.send_to(frame, None)
+            .unwrap_or_else(|| Err(io::Error::other("no send result")))
+    }
+}
+
+/// Sink of an interface nothing is sent to, for dry runs that open no
+/// socket on it
+pub struct Unopened;
+
+impl PacketSink for Unopened {
+    fn send(&mut self, _frame: &[u8]) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::NotConnected,
+            "interface not opened for sending",
+        ))
+    }
+}
+
+/// In-memory source and sink, to run frames through the pipeline without
+/// opening interfaces
+#[cfg(test)]
+pub mod memory {
+    use super::{PacketSink, PacketSource};
+    use crate::capture::RX_POLL_INTERVAL;
+    use std::io;
+    use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+    use std::sync::{Arc, Condvar, Mutex};
+
+    /// Source handing out the frames sent to its channel. Reads time out
+    /// like on an idle network while the channel is empty or closed.
+    pub struct ChannelSource {
+        frames: Receiver<Vec<u8>>,
+        current: Vec<u8>,
+    }
+
+    /// Returns the sending end feeding a new [`ChannelSource`]
+    pub fn source() -> (Sender<Vec<u8>>, ChannelSource) {
+        let (tx, frames) = mpsc::channel();
+        let source = ChannelSource {
+            frames,
+            current: Vec::new(),
+        };
+        (tx, source)
+    }
+
+    impl PacketSource for ChannelSource {
+        fn next(&mut self) -> io::Result<&[u8]> {
+            match self.frames.recv_timeout(RX_POLL_INTERVAL) {
+                Ok(frame) => {
+                    self.current = frame;
+                    Ok(&self.current)
+                }
+                Err(RecvTimeoutError::Timeout) => {
+                    Err(io::Error::new(io::ErrorKind::TimedOut, "Timed out"))
+                }
+                Err(RecvTimeoutError::Disconnected) => {
+                    std::thread::sleep(RX_POLL_INTERVAL);
+                    Err(io::Error::new(io::ErrorKind::TimedOut, "Timed out"))
+                }
+            }
+        }
+    }
+
+    /// Sink keeping every frame sent to it; clones share the frames
+    #[derive(Clone, Default)]
+    pub struct VecSink(Arc<Mutex<Vec<Vec<u8>>>>);
+
+    impl VecSink {
+        pub fn frames(&self) -> Vec<Vec<u8>> {
+            self.0.lock().unwrap().clone()
+        }
+    }
+
+    impl PacketSink for VecSink {
+        fn send(&mut self, frame: &[u8]) -> io::Result<()> {
+            self.0.lock().unwrap().push(frame.to_vec());
+            Ok(())
+        }
+    }
+
+    /// Sink holding every send until [`release`](Self::release) is called,
+    /// like an interface that stopped draining, then keeping the frames
+    #[derive(Clone, Default)]
+    pub struct StalledSink {
+        sent: VecSink,
+        /// Whether sends go through, and the sends waiting for that
+        gate: Arc<(Mutex<(bool, usize)>, Condvar)>,
+    }
+
+    impl StalledSink {
+        pub fn release(&self) {
+            self.gate.0.lock().unwrap().0 = true;
+            self.gate.1.notify_all();
+        }
+
+        /// Sends waiting for the release
+        pub fn pending(&self) -> usize {
+            self.gate.0.lock().unwrap().1
+        }
+
+        pub fn sent(&self) -> VecSink {
+            self.sent.clone()
+        }
+    }
+
+    impl PacketSink for StalledSink {
+        fn send(&mut self, frame: &[u8]) -> io::Result<()> {
+            let (state, released) = &*self.gate;
+            let mut state = state.lock().unwrap();
+            state.1 += 1;
+            while !state.0 {
+                state = released.wait(state).unwrap();
+            }
+            state.1 -= 1;
+            drop(state);
+            self.sent.send(frame)
+        }
+    }
+
+    /// Sink failing with ENOBUFS, like a full device queue, a set number of
+    /// times before frames go through
+    #[derive(Clone, Default)]
+    pub struct BusySink {
+        sent: VecSink,
+        failures: Arc<Mutex<usize>>,
+    }
+
+    impl BusySink {
+        /// Fails the next `count` sends
+        pub fn fail(&self, count: usize) {
+            *self.failures.lock().unwrap() = count;
+        }
+
+        pub fn sent(&self) -> VecSink {
+            self.sent.clone()
+        }
+    }
+
+    impl PacketSink for BusySink {
+        fn send(&mut self, frame: &[u8]) -> io::Result<()> {
+            let mut failures = self.failures.lock().unwrap();
+            if *failures > 0 {
+                *failures -= 1;
+                return Err(io::Error::from_raw_os_error(libc::ENOBUFS));
+            }
+            drop(failures);
+            self.sent.send(frame)
+        }
+    }
+}
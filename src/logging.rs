@@ -0,0 +1,523 @@
+//! Subscriber setup for human-readable and structured output on stderr or
+//! the journal, throttling of warnings about recurring events and
+//! suppression of repetitive per-packet messages.
+
+use crate::journal::Journal;
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt::{self, Write as _};
+use std::io::{self, IsTerminal};
+use std::mem;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Mutex, OnceLock, RwLock};
+use std::time::{Duration, Instant, SystemTime};
+use tracing::field::{Field, Visit};
+use tracing::{warn, Event, Level, Subscriber};
+use tracing_subscriber::filter::{EnvFilter, LevelFilter};
+use tracing_subscriber::fmt::format::Writer;
+use tracing_subscriber::fmt::{self as format, FmtContext, FormatEvent, FormatFields};
+use tracing_subscriber::prelude::*;
+use tracing_subscriber::registry::{LookupSpan, Registry};
+use tracing_subscriber::reload;
+
+/// Filter of the installed subscriber, replaced by [`set_level`]
+static FILTER: OnceLock<reload::Handle<EnvFilter, Registry>> = OnceLock::new();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl From<LogLevel> for LevelFilter {
+    fn from(level: LogLevel) -> Self {
+        match level {
+            LogLevel::Error => LevelFilter::ERROR,
+            LogLevel::Warn => LevelFilter::WARN,
+            LogLevel::Info => LevelFilter::INFO,
+            LogLevel::Debug => LevelFilter::DEBUG,
+            LogLevel::Trace => LevelFilter::TRACE,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// One human-readable line per event, as env_logger writes them
+    Text,
+    /// One line per event with its fields and those of the enclosing spans
+    Compact,
+    /// Several lines per event with its fields and spans, for reading
+    Pretty,
+    /// One JSON object per line with the event fields merged in and the
+    /// enclosing spans listed
+    Json,
+}
+
+/// Where log output goes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogTarget {
+    Stderr,
+    /// The journal, with the event fields as journal fields; stderr where
+    /// journald does not run
+    Journald,
+    /// The journal and stderr
+    Both,
+}
+
+/// Installs the global subscriber, writing to `target` in `format`.
+/// `filters` uses the RUST_LOG syntax and overrides `level` where given.
+pub fn init(level: LogLevel, filters: Option<&str>, format: LogFormat, target: LogTarget) {
+    let (filter, handle) = reload::Layer::new(env_filter(level, filters));
+    let _ = FILTER.set(handle);
+    let (journal, unavailable) = match target {
+        LogTarget::Stderr => (None, None),
+        LogTarget::Journald | LogTarget::Both => match Journal::connect() {
+            Ok(journal) => (Some(journal), None),
+            Err(e) => (None, Some(e)),
+        },
+    };
+    let stderr = target != LogTarget::Journald || journal.is_none();
+    let registry = tracing_subscriber::registry().with(filter).with(journal);
+    let layer = || {
+        stderr.then(|| {
+            format::layer()
+                .with_writer(io::stderr)
+                .with_ansi(io::stderr().is_terminal())
+        })
+    };
+    match format {
+        LogFormat::Text => registry
+            .with(layer().map(|layer| layer.event_format(Text)))
+            .init(),
+        LogFormat::Compact => registry.with(layer().map(|layer| layer.compact())).init(),
+        LogFormat::Pretty => registry.with(layer().map(|layer| layer.pretty())).init(),
+        LogFormat::Json => registry
+            .with(layer().map(|layer| {
+                layer
+                    .json()
+                    .flatten_event(true)
+                    .with_current_span(false)
+                    .with_span_list(true)
+            }))
+            .init(),
+    }
+    if let Some(e) = unavailable {
+        warn!("Journal unavailable, logging to stderr: {}", e);
+    }
+}
+
+fn env_filter(level: LogLevel, filters: Option<&str>) -> EnvFilter {
+    EnvFilter::builder()
+        .with_default_directive(LevelFilter::from(level).into())
+        .parse_lossy(filters.unwrap_or_default())
+}
+
+/// Logs at `level` from now on, dropping the RUST_LOG filters. Fails if
+/// [`init`] was not called, e.g. when the forwarder is used as a library.
+pub fn set_level(level: LogLevel) -> Result<(), String> {
+    let handle = FILTER
+        .get()
+        .ok_or("logging was not set up by the forwarder")?;
+    handle
+        .reload(env_filter(level, None))
+        .map_err(|e| e.to_string())
+}
+
+/// `[timestamp LEVEL target] message`, the env_logger default. Fields and
+/// spans are left out; the other formats show them.
+struct Text;
+
+impl<S, N> FormatEvent<S, N> for Text
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(
+        &self,
+        _: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        let metadata = event.metadata();
+        let timestamp = humantime::format_rfc3339_seconds(SystemTime::now());
+        if writer.has_ansi_escapes() {
+            let style = match *metadata.level() {
+                Level::TRACE => "36",
+                Level::DEBUG => "34",
+                Level::INFO => "32",
+                Level::WARN => "33",
+                Level::ERROR => "1;31",
+            };
+            write!(
+                writer,
+                "\x1b[90m[\x1b[0m{} \x1b[{}m{:<5}\x1b[0m {}\x1b[90m]\x1b[0m ",
+                timestamp,
+                style,
+                metadata.level(),
+                metadata.target()
+            )?;
+        } else {
+            write!(
+                writer,
+                "[{} {:<5} {}] ",
+                timestamp,
+                metadata.level(),
+                metadata.target()
+            )?;
+        }
+        let mut message = Message(&mut writer, Ok(()));
+        event.record(&mut message);
+        message.1?;
+        writeln!(writer)
+    }
+}
+
+/// Writes the message of an event, keeping the first error
+struct Message<'a, 'w>(&'a mut Writer<'w>, fmt::Result);
+
+impl Visit for Message<'_, '_> {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "message" {
+            self.1 = self.1.and_then(|()| write!(self.0, "{:?}", value));
+        }
+    }
+}
+
+/// Limits a recurring warning to one per `interval`, counting the
+/// occurrences that were not logged
+pub struct ThrottledWarning {
+    interval: Duration,
+    last: Mutex<Option<Instant>>,
+    suppressed: AtomicU64,
+}
+
+impl ThrottledWarning {
+    pub fn new(interval: Duration) -> Self {
+        ThrottledWarning {
+            interval,
+            last: Mutex::new(None),
+            suppressed: AtomicU64::new(0),
+        }
+    }
+
+    /// Records an occurrence. Returns the number of occurrences suppressed
+    /// since the last warning if this one should be logged, `None` if it is
+    /// only counted.
+    pub fn occurred(&self) -> Option<Suppressed> {
+        let now = Instant::now();
+        let mut last = self.last.lock().unwrap();
+        if last.is_some_and(|last| now.duration_since(last) < self.interval) {
+            self.suppressed.fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+        *last = Some(now);
+        Some(Suppressed(self.suppressed.swap(0, Ordering::Relaxed)))
+    }
+}
+
+/// Occurrences left out since the last warning, shown as a note appended
+/// to the next one
+pub struct Suppressed(pub u64);
+
+impl fmt::Display for Suppressed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            0 => Ok(()),
+            count => write!(f, " ({} more since the last warning)", count),
+        }
+    }
+}
+
+/// How often per-packet messages of one kind are logged individually
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Suppression {
+    /// Period over which the messages of a kind are counted
+    pub window: Duration,
+    /// Messages of a kind logged per window before the rest are summed up
+    pub threshold: u64,
+}
+
+/// Suppression applied by every [`RepeatedMessages`], replaced by
+/// [`set_suppression`]
+static SUPPRESSION: RwLock<Suppression> = RwLock::new(Suppression {
+    window: Duration::from_secs(10),
+    threshold: 5,
+});
+
+/// Replaces the window and threshold of the per-packet messages
+pub fn set_suppression(suppression: Suppression) {
+    *SUPPRESSION.write().unwrap() = suppression;
+}
+
+/// Bytes of each received frame hexdumped at debug level, replaced by
+/// [`set_payload_dump`]
+static PAYLOAD_DUMP: AtomicUsize = AtomicUsize::new(0);
+
+/// Replaces the number of bytes of each received frame hexdumped at debug
+/// level; 0 dumps none
+pub fn set_payload_dump(bytes: usize) {
+    PAYLOAD_DUMP.store(bytes, Ordering::Relaxed);
+}
+
+/// Bytes of each received frame to hexdump at debug level
+pub fn payload_dump() -> usize {
+    PAYLOAD_DUMP.load(Ordering::Relaxed)
+}
+
+/// Per-packet messages of a filter or stage, counted per kind, e.g. per
+/// drop reason. Only the first messages of a kind in each window are
+/// logged; the others are summed up once the window is over, with the
+/// next message of any kind, so a flood that stops is summed up as well.
+/// Use it through [`debug_repeated`].
+#[derive(Debug, Default)]
+pub struct RepeatedMessages {
+    kinds: Mutex<HashMap<String, Window>>,
+}
+
+#[derive(Debug)]
+struct Window {
+    start: Instant,
+    count: u64,
+    /// The last message not logged
+    last: String,
+}
+
+impl RepeatedMessages {
+    /// Counts a message of `kind` and tells whether to log it, along with
+    /// the summaries of the windows of any kind that are over, if messages
+    /// of them were left out
+    pub fn occurred(&self, kind: &str, message: impl fmt::Display) -> Occurrence {
+        let suppression = *SUPPRESSION.read().unwrap();
+        self.occurred_at(kind, message, suppression, Instant::now())
+    }
+
+    fn occurred_at(
+        &self,
+        kind: &str,
+        message: impl fmt::Display,
+        suppression: Suppression,
+        now: Instant,
+    ) -> Occurrence {
+        let Suppression { window, threshold } = suppression;
+        let mut kinds = self.kinds.lock().unwrap();
+        let mut summaries = Vec::new();
+        kinds.retain(|kind, current| {
+            if now.duration_since(current.start) < window {
+                return true;
+            }
+            if current.count > threshold {
+                summaries.push(Summary {
+                    kind: kind.clone(),
+                    count: current.count,
+                    suppressed: current.count - threshold,
+                    window,
+                    last: mem::take(&mut current.last),
+                });
+            }
+            false
+        });
+        if !kinds.contains_key(kind) {
+            let window = Window {
+                start: now,
+                count: 0,
+                last: String::new(),
+            };
+            kinds.insert(kind.to_string(), window);
+        }
+        let current = kinds.get_mut(kind).expect("inserted above");
+        current.count += 1;
+        let log = current.count <= threshold;
+        if !log {
+            current.last.clear();
+            let _ = write!(current.last, "{}", message);
+        }
+        Occurrence { log, summaries }
+    }
+}
+
+/// What to log for one message counted by [`RepeatedMessages`]
+pub struct Occurrence {
+    /// Whether the message itself is logged
+    pub log: bool,
+    /// Messages of the windows that are over
+    pub summaries: Vec<Summary>,
+}
+
+/// Messages of one kind within a window, some of which were not logged
+pub struct Summary {
+    pub kind: String,
+    count: u64,
+    suppressed: u64,
+    window: Duration,
+    last: String,
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} within {}, {} not logged (last: {})",
+            self.count,
+            humantime::format_duration(self.window),
+            self.suppressed,
+            self.last
+        )
+    }
+}
+
+/// Logs a per-packet message of `kind` at debug level through a
+/// [`RepeatedMessages`], summing up the messages over the threshold of a
+/// window as "`kind`: N within 10s, ...". At trace level every message is
+/// logged.
+macro_rules! debug_repeated {
+    ($repeated:expr, $kind:expr, $($message:tt)+) => {{
+        if tracing::enabled!(tracing::Level::TRACE) {
+            tracing::debug!($($message)+);
+        } else if tracing::enabled!(tracing::Level::DEBUG) {
+            let message = format!($($message)+);
+            let occurrence = $repeated.occurred($kind, &message);
+            for summary in occurrence.summaries {
+                tracing::debug!("{}: {}", summary.kind, summary);
+            }
+            if occurrence.log {
+                tracing::debug!("{}", message);
+            }
+        }
+    }};
+}
+pub(crate) use debug_repeated;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TWO_PER_10S: Suppression = Suppression {
+        window: Duration::from_secs(10),
+        threshold: 2,
+    };
+
+    #[test]
+    fn rust_log_filters_override_the_level_where_given() {
+        let hint = |level, filters| env_filter(level, filters).max_level_hint();
+        assert_eq!(hint(LogLevel::Info, None), Some(LevelFilter::INFO));
+        assert_eq!(
+            hint(LogLevel::Info, Some("debug")),
+            Some(LevelFilter::DEBUG)
+        );
+        let capture = Some("nw_pckt_fwd::capture=trace");
+        assert_eq!(hint(LogLevel::Warn, capture), Some(LevelFilter::TRACE));
+        // Directives that do not parse are skipped
+        assert_eq!(
+            hint(LogLevel::Warn, Some("=bogus")),
+            Some(LevelFilter::WARN)
+        );
+    }
+
+    #[test]
+    fn warns_once_per_interval_counting_the_rest() {
+        let warning = ThrottledWarning::new(Duration::from_secs(3600));
+        assert_eq!(warning.occurred().unwrap().to_string(), "");
+        assert!(warning.occurred().is_none());
+        assert!(warning.occurred().is_none());
+        assert_eq!(warning.suppressed.load(Ordering::Relaxed), 2);
+        assert_eq!(
+            Suppressed(2).to_string(),
+            " (2 more since the last warning)"
+        );
+
+        let warning = ThrottledWarning::new(Duration::ZERO);
+        assert!(warning.occurred().is_some());
+        assert!(warning.occurred().is_some());
+    }
+
+    #[test]
+    fn logs_messages_up_to_the_threshold_per_window() {
+        let repeated = RepeatedMessages::default();
+        let start = Instant::now();
+        let occurred = |kind, message: &str, after| {
+            let now = start + Duration::from_secs(after);
+            repeated.occurred_at(kind, message, TWO_PER_10S, now)
+        };
+        assert!(occurred("port", "a", 0).log);
+        assert!(occurred("port", "b", 1).log);
+        assert!(!occurred("port", "c", 2).log);
+        assert!(!occurred("port", "d", 3).log);
+        // Counted per kind
+        assert!(occurred("malformed", "e", 4).log);
+
+        // The window is over: summed up, and logged again
+        let occurrence = occurred("port", "f", 10);
+        assert!(occurrence.log);
+        assert_eq!(occurrence.summaries.len(), 1);
+        let summary = &occurrence.summaries[0];
+        assert_eq!(summary.kind, "port");
+        assert_eq!(summary.to_string(), "4 within 10s, 2 not logged (last: d)");
+        // Nothing was left out of the other kind
+        assert!(occurred("malformed", "g", 14).summaries.is_empty());
+    }
+
+    #[test]
+    fn sums_up_a_flood_that_stopped_with_a_message_of_another_kind() {
+        let repeated = RepeatedMessages::default();
+        let start = Instant::now();
+        for i in 0..5 {
+            let occurrence = repeated.occurred_at("port", i, TWO_PER_10S, start);
+            assert!(occurrence.summaries.is_empty());
+        }
+        let later = start + Duration::from_secs(60);
+        let occurrence = repeated.occurred_at("malformed", "x", TWO_PER_10S, later);
+        assert!(occurrence.log);
+        let summaries: Vec<String> = occurrence
+            .summaries
+            .iter()
+            .map(|summary| format!("{}: {}", summary.kind, summary))
+            .collect();
+        assert_eq!(summaries, ["port: 5 within 10s, 3 not logged (last: 4)"]);
+        // Summed up once only
+        let occurrence = repeated.occurred_at("port", 5, TWO_PER_10S, later);
+        assert!(occurrence.log && occurrence.summaries.is_empty());
+    }
+
+    /// Log output collected in memory
+    #[derive(Clone, Default)]
+    struct Output(std::sync::Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for Output {
+        fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(bytes);
+            Ok(bytes.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn writes_text_lines_without_fields_or_spans() {
+        let output = Output::default();
+        let writer = output.clone();
+        let layer = format::layer()
+            .with_writer(move || writer.clone())
+            .with_ansi(false)
+            .event_format(Text);
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            let span = tracing::info_span!("capture", iface = "eth0");
+            let _entered = span.enter();
+            tracing::warn!(target: "nw_pckt_fwd::capture", port = 1900, "Frame dropped");
+        });
+        let text = String::from_utf8(output.0.lock().unwrap().clone()).unwrap();
+        let (prefix, message) = text.split_once("] ").unwrap();
+        assert!(prefix.starts_with('[') && prefix.ends_with("Z WARN  nw_pckt_fwd::capture"));
+        assert_eq!(message, "Frame dropped\n");
+    }
+}
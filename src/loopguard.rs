@@ -0,0 +1,307 @@
+//! Loop guard dropping frames the forwarder has already forwarded or sent
+//! itself, so a second path between the segments cannot cause a storm.
+
+use crate::filter::{IpHeader, PacketContext, MDNS_PORT};
+use crate::logging::ThrottledWarning;
+use crate::mdns::{FLAG_RESPONSE, HEADER_LEN};
+use pnet::packet::Packet;
+use pnet::util::MacAddr;
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::{debug, warn};
+
+/// Forwarded frames remembered at most, the oldest are forgotten first
+const MAX_RECENT: usize = 8192;
+/// Minimum time between two warnings about looping frames
+const WARNING_INTERVAL: Duration = Duration::from_secs(10);
+
+const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0100_0000_01b3;
+
+/// Hashes of recently forwarded frames with the interfaces they were sent
+/// on and when
+#[derive(Default)]
+struct RecentFrames {
+    frames: HashMap<u64, Vec<(String, Instant)>>,
+    order: VecDeque<(Instant, u64)>,
+}
+
+impl RecentFrames {
+    fn expire(&mut self, now: Instant, window: Duration) {
+        while let Some(&(at, hash)) = self.order.front() {
+            if now.duration_since(at) < window && self.order.len() < MAX_RECENT {
+                break;
+            }
+            self.order.pop_front();
+            if let Some(sent) = self.frames.get_mut(&hash) {
+                sent.retain(|(_, sent_at)| *sent_at != at);
+                if sent.is_empty() {
+                    self.frames.remove(&hash);
+                }
+            }
+        }
+    }
+}
+
+/// Drops frames whose source MAC belongs to one of the forwarder's
+/// interfaces, and frames received on an interface an identical frame was
+/// sent on within `window`: the forwarder's own transmission echoed back by
+/// the capture. Frames are compared by a hash of their IP addresses and IP
+/// payload, so changes to the MACs, TTL or IP header on the way back do not
+/// hide a loop. With `drop_repeats` a frame already forwarded to the same
+/// egress interface is dropped as well, as reflected back by another path
+/// between the segments. mDNS probes and announcements are exempt from
+/// that, as hosts repeat them identically on purpose; other
+/// retransmissions within the window, such as SSDP retries, are dropped.
+pub struct LoopGuard {
+    own_macs: Vec<MacAddr>,
+    window: Duration,
+    drop_repeats: bool,
+    recent: Mutex<RecentFrames>,
+    own_warning: ThrottledWarning,
+    loop_warning: ThrottledWarning,
+}
+
+impl LoopGuard {
+    pub fn new(own_macs: Vec<MacAddr>, window: Duration, drop_repeats: bool) -> Self {
+        LoopGuard {
+            own_macs,
+            window,
+            drop_repeats,
+            recent: Mutex::new(RecentFrames::default()),
+            own_warning: ThrottledWarning::new(WARNING_INTERVAL),
+            loop_warning: ThrottledWarning::new(WARNING_INTERVAL),
+        }
+    }
+
+    /// Whether the frame must be dropped instead of being forwarded to
+    /// `egress`
+    pub fn looped(&self, ctx: &PacketContext, egress: &str) -> bool {
+        let source = ctx.ethernet.get_source();
+        if self.own_macs.contains(&source) {
+            if let Some(suppressed) = self.own_warning.occurred() {
+                warn!(
+                    "Frame sent by the forwarder itself ({}) received on {}, dropped{}",
+                    source, ctx.ingress, suppressed
+                );
+            }
+            return true;
+        }
+        let Some(hash) = frame_hash(ctx) else {
+            return false;
+        };
+        let now = Instant::now();
+        let mut recent = self.recent.lock().unwrap();
+        recent.expire(now, self.window);
+        let Some(sent) = recent.frames.get(&hash) else {
+            return false;
+        };
+        if sent.iter().any(|(iface, _)| iface == ctx.ingress) {
+            debug!("Echo of a frame sent on {} dropped", ctx.ingress);
+            return true;
+        }
+        if !self.drop_repeats || deliberate_repeat(ctx) {
+            return false;
+        }
+        let Some((_, at)) = sent.iter().find(|(iface, _)| iface == egress) else {
+            return false;
+        };
+        if let Some(suppressed) = self.loop_warning.occurred() {
+            warn!(
+                "Frame on {} was already forwarded to {} {:?} ago, possible \
+                 forwarding loop, dropped{}",
+                ctx.ingress,
+                egress,
+                now.duration_since(*at),
+                suppressed
+            );
+        }
+        true
+    }
+
+    /// Remembers a frame [`looped`](Self::looped) let through as sent on
+    /// `egress`, once it was forwarded there
+    pub fn sent(&self, ctx: &PacketContext, egress: &str) {
+        let Some(hash) = frame_hash(ctx) else {
+            return;
+        };
+        let now = Instant::now();
+        let mut recent = self.recent.lock().unwrap();
+        recent.expire(now, self.window);
+        recent
+            .frames
+            .entry(hash)
+            .or_default()
+            .push((egress.to_string(), now));
+        recent.order.push_back((now, hash));
+    }
+}
+
+/// Whether the frame is an mDNS probe or announcement, which hosts send
+/// several times in a row (RFC 6762 sections 8.1 and 8.3): a query with
+/// the proposed records in its authority section, or an unsolicited
+/// response. Responses to queries cannot be told apart from announcements
+/// and are let through as well.
+fn deliberate_repeat(ctx: &PacketContext) -> bool {
+    let Some(udp) = ctx.udp().filter(|udp| udp.get_destination() == MDNS_PORT) else {
+        return false;
+    };
+    let Some(header) = udp.payload().get(..HEADER_LEN) else {
+        return false;
+    };
+    let field = |i: usize| u16::from_be_bytes([header[i], header[i + 1]]);
+    field(2) & FLAG_RESPONSE != 0 || field(8) != 0
+}
+
+/// FNV-1a hash of the source and destination address and the IP payload,
+/// `None` for frames that are not IP
+fn frame_hash(ctx: &PacketContext) -> Option<u64> {
+    let payload = match ctx.ip.as_ref()? {
+        IpHeader::V4(ip) => ip.payload(),
+        IpHeader::V6(ip) => ip.payload(),
+    };
+    let mut hash = FNV_OFFSET;
+    let mut add = |bytes: &[u8]| {
+        for byte in bytes {
+            hash = (hash ^ u64::from(*byte)).wrapping_mul(FNV_PRIME);
+        }
+    };
+    for ip in [ctx.source_ip()?, ctx.destination_ip()?] {
+        match ip {
+            IpAddr::V4(ip) => add(&ip.octets()),
+            IpAddr::V6(ip) => add(&ip.octets()),
+        }
+    }
+    add(payload);
+    Some(hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filter::MDNS_IPV4_GROUP;
+    use crate::mdns::TYPE_A;
+    use crate::pair::Direction;
+    use crate::testutil::{
+        mdns_query, mdns_response, multicast_mac, ssdp_search_frame, udp_frame, HOST_IP, HOST_MAC,
+    };
+    use std::net::Ipv4Addr;
+
+    const OWN_MAC: MacAddr = MacAddr(0x02, 0, 0, 0, 0, 0xfe);
+    const WINDOW: Duration = Duration::from_secs(1);
+
+    fn context<'a>(ingress: &'a str, frame: &'a [u8]) -> PacketContext<'a> {
+        PacketContext::parse(ingress, Direction::Outbound, frame).unwrap()
+    }
+
+    #[test]
+    fn drops_echoes_of_frames_sent_on_the_ingress() {
+        let guard = LoopGuard::new(vec![OWN_MAC], WINDOW, false);
+        let frame = ssdp_search_frame("ssdp:all");
+        let inside = context("vm1", &frame);
+        let outside = context("eth0", &frame);
+
+        // Only frames actually forwarded are remembered
+        assert!(!guard.looped(&inside, "eth0"));
+        assert!(!guard.looped(&outside, "vm1"));
+        guard.sent(&inside, "eth0");
+        assert!(guard.looped(&outside, "vm1"));
+        // A retry of the same search is forwarded again
+        assert!(!guard.looped(&inside, "eth0"));
+    }
+
+    #[test]
+    fn drops_repeats_to_the_same_egress_only_when_asked() {
+        let guard = LoopGuard::new(vec![OWN_MAC], WINDOW, true);
+        let frame = ssdp_search_frame("ssdp:all");
+        let inside = context("vm1", &frame);
+        guard.sent(&inside, "eth0");
+        assert!(guard.looped(&inside, "eth0"));
+        assert!(!guard.looped(&inside, "eth1"));
+    }
+
+    #[test]
+    fn forwards_repeated_mdns_probes_and_announcements() {
+        let guard = LoopGuard::new(vec![OWN_MAC], WINDOW, true);
+        let mdns = |payload: &[u8]| {
+            let group = MDNS_IPV4_GROUP.into();
+            let (sport, dport) = (MDNS_PORT, MDNS_PORT);
+            udp_frame(
+                HOST_MAC,
+                multicast_mac(group),
+                HOST_IP,
+                group,
+                sport,
+                dport,
+                payload,
+            )
+        };
+        let query = mdns_query("host.local", TYPE_A);
+        let mut probe = query.clone();
+        probe[9] = 1;
+        let announcement = mdns_response("host.local", TYPE_A, 120, &[192, 168, 100, 5]);
+
+        for (frame, looped) in [
+            (mdns(&query), true),
+            (mdns(&probe), false),
+            (mdns(&announcement), false),
+        ] {
+            let inside = context("vm1", &frame);
+            guard.sent(&inside, "eth0");
+            assert_eq!(guard.looped(&inside, "eth0"), looped);
+        }
+    }
+
+    #[test]
+    fn drops_frames_from_own_macs() {
+        let guard = LoopGuard::new(vec![OWN_MAC], WINDOW, false);
+        let payload = b"hello";
+        let dst = Ipv4Addr::new(192, 168, 100, 6);
+        let own = udp_frame(OWN_MAC, HOST_MAC, HOST_IP, dst, 4000, 4001, payload);
+        assert!(guard.looped(&context("eth0", &own), "vm1"));
+        let other = udp_frame(HOST_MAC, OWN_MAC, HOST_IP, dst, 4000, 4001, payload);
+        assert!(!guard.looped(&context("eth0", &other), "vm1"));
+    }
+
+    #[test]
+    fn forgets_frames_after_the_window() {
+        let guard = LoopGuard::new(Vec::new(), Duration::from_millis(20), false);
+        let frame = ssdp_search_frame("ssdp:all");
+        guard.sent(&context("vm1", &frame), "eth0");
+        assert!(guard.looped(&context("eth0", &frame), "vm1"));
+        std::thread::sleep(Duration::from_millis(30));
+        assert!(!guard.looped(&context("eth0", &frame), "vm1"));
+        assert!(guard.recent.lock().unwrap().frames.is_empty());
+    }
+
+    #[test]
+    fn remembers_at_most_max_recent_frames() {
+        let guard = LoopGuard::new(Vec::new(), WINDOW, false);
+        let dst = Ipv4Addr::new(192, 168, 100, 6);
+        let frames: Vec<_> = (0..=MAX_RECENT as u32)
+            .map(|n| {
+                udp_frame(
+                    HOST_MAC,
+                    OWN_MAC,
+                    HOST_IP,
+                    dst,
+                    4000,
+                    4001,
+                    &n.to_be_bytes(),
+                )
+            })
+            .collect();
+        for frame in &frames {
+            guard.sent(&context("vm1", frame), "eth0");
+        }
+        let recent = guard.recent.lock().unwrap();
+        assert_eq!(recent.order.len(), MAX_RECENT);
+        assert_eq!(recent.frames.len(), MAX_RECENT);
+        drop(recent);
+        // The oldest frame made room for the newest
+        assert!(!guard.looped(&context("eth0", &frames[0]), "vm1"));
+        assert!(guard.looped(&context("eth0", &frames[MAX_RECENT]), "vm1"));
+    }
+}
@@ -1,3 +1,6 @@
-fn main() {
-    println!("Hello, world!");
+use std::process::ExitCode;
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    nw_pckt_fwd::cli::main().await
 }
@@ -0,0 +1,1129 @@
+//! mDNS message parsing, filtering on the service names it refers to, and
+//! rewriting of forwarded messages.
+
+use crate::filter::{Decision, Filter, PacketContext, MDNS_PORT};
+use crate::logging::{debug_repeated, RepeatedMessages};
+use crate::rewrite::{Rewrite, UdpDatagram};
+use pnet::packet::Packet;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::debug;
+
+pub const HEADER_LEN: usize = 12;
+pub const FLAG_RESPONSE: u16 = 0x8000;
+const FLAG_AUTHORITATIVE: u16 = 0x0400;
+/// Top bit of the class: unicast response requested in questions, cache
+/// flush in records (RFC 6762 sections 5.4 and 10.2)
+pub const CLASS_TOP_BIT: u16 = 0x8000;
+pub const CLASS_IN: u16 = 1;
+/// Longest name in wire format allowed by RFC 1035
+const MAX_NAME_LEN: usize = 255;
+/// Compression pointers followed per name before the message is rejected
+const MAX_POINTERS: usize = 16;
+/// How long a device that announced an allowed service is remembered, the
+/// TTL RFC 6762 recommends for service records
+const DEVICE_LIFETIME: Duration = Duration::from_secs(4500);
+/// Devices remembered at most; the one seen longest ago is forgotten first
+const MAX_DEVICES: usize = 256;
+
+pub const TYPE_A: u16 = 1;
+const TYPE_NS: u16 = 2;
+const TYPE_CNAME: u16 = 5;
+const TYPE_SOA: u16 = 6;
+pub const TYPE_PTR: u16 = 12;
+const TYPE_MX: u16 = 15;
+pub const TYPE_TXT: u16 = 16;
+const TYPE_RP: u16 = 17;
+const TYPE_AFSDB: u16 = 18;
+const TYPE_RT: u16 = 21;
+const TYPE_PX: u16 = 26;
+pub const TYPE_AAAA: u16 = 28;
+pub const TYPE_SRV: u16 = 33;
+const TYPE_KX: u16 = 36;
+const TYPE_DNAME: u16 = 39;
+pub const TYPE_OPT: u16 = 41;
+const TYPE_NSEC: u16 = 47;
+pub const TYPE_ANY: u16 = 255;
+
+/// Names an mDNS message refers to, lowercased and without trailing dot
+#[derive(Debug, PartialEq, Eq)]
+pub struct MdnsMessage {
+    pub response: bool,
+    pub questions: Vec<String>,
+    /// Type and owner name of every answer, authority and additional record
+    pub records: Vec<(u16, String)>,
+    /// Owner name and device of every SRV record and TXT record with an
+    /// `id=` key
+    pub devices: Vec<(String, Device)>,
+}
+
+/// What tells the records of one device apart, whichever service they are
+/// for
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Device {
+    /// Target host of its SRV records, lowercased
+    Host(String),
+    /// Value of the `id=` key of its TXT records, lowercased, as Chromecasts
+    /// give in all their services
+    Id(String),
+}
+
+impl fmt::Display for Device {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Device::Host(host) => write!(f, "host {}", host),
+            Device::Id(id) => write!(f, "id {}", id),
+        }
+    }
+}
+
+/// Value of the `id=` key among the character strings of TXT record data
+fn txt_id(data: &[u8]) -> Option<String> {
+    let mut pos = 0;
+    while let Some(&len) = data.get(pos) {
+        let string = data.get(pos + 1..pos + 1 + usize::from(len))?;
+        if string.len() > 3 && string[..3].eq_ignore_ascii_case(b"id=") {
+            return Some(dotted_name(&[&string[3..]]));
+        }
+        pos += 1 + usize::from(len);
+    }
+    None
+}
+
+impl MdnsMessage {
+    /// Parses the header, questions and resource records of `payload`.
+    /// Returns `None` if the message is truncated or a name is invalid.
+    pub fn parse(payload: &[u8]) -> Option<Self> {
+        let header = payload.get(..HEADER_LEN)?;
+        let field = |i: usize| u16::from_be_bytes([header[i], header[i + 1]]);
+        let response = field(2) & FLAG_RESPONSE != 0;
+        let questions = field(4);
+        let records = u32::from(field(6)) + u32::from(field(8)) + u32::from(field(10));
+
+        let mut pos = HEADER_LEN;
+        let mut message = MdnsMessage {
+            response,
+            questions: Vec::new(),
+            records: Vec::new(),
+            devices: Vec::new(),
+        };
+        for _ in 0..questions {
+            let (name, next) = read_name(payload, pos)?;
+            // Type and class
+            payload.get(next..next + 4)?;
+            message.questions.push(name);
+            pos = next + 4;
+        }
+        for _ in 0..records {
+            let (name, next) = read_name(payload, pos)?;
+            // Type, class, TTL and data length
+            let fixed = payload.get(next..next + 10)?;
+            let rtype = u16::from_be_bytes([fixed[0], fixed[1]]);
+            let data_len = usize::from(u16::from_be_bytes([fixed[8], fixed[9]]));
+            let data = next + 10;
+            pos = data + data_len;
+            if pos > payload.len() {
+                return None;
+            }
+            let device = match rtype {
+                TYPE_SRV if data_len > 6 => {
+                    read_name(payload, data + 6).map(|(host, _)| Device::Host(host))
+                }
+                TYPE_TXT => txt_id(&payload[data..pos]).map(Device::Id),
+                _ => None,
+            };
+            if let Some(device) = device {
+                message.devices.push((name.clone(), device));
+            }
+            message.records.push((rtype, name));
+        }
+        Some(message)
+    }
+}
+
+/// Reads the possibly compressed name at `start` and returns its labels
+/// together with the offset following it. Compression pointers must point
+/// backwards, which rules out loops, and their number is capped.
+fn read_labels(message: &[u8], start: usize) -> Option<(Vec<&[u8]>, usize)> {
+    let mut labels = Vec::new();
+    let mut pos = start;
+    let mut end = None;
+    let mut pointers = 0;
+    let mut wire_len = 1;
+    loop {
+        let len = usize::from(*message.get(pos)?);
+        match len & 0xc0 {
+            0x00 if len == 0 => break,
+            0x00 => {
+                let label = message.get(pos + 1..pos + 1 + len)?;
+                wire_len += len + 1;
+                if wire_len > MAX_NAME_LEN {
+                    return None;
+                }
+                labels.push(label);
+                pos += 1 + len;
+            }
+            0xc0 => {
+                let low = *message.get(pos + 1)?;
+                let target = usize::from(u16::from_be_bytes([len as u8 & 0x3f, low]));
+                pointers += 1;
+                if pointers > MAX_POINTERS || target >= pos {
+                    return None;
+                }
+                end.get_or_insert(pos + 2);
+                pos = target;
+            }
+            _ => return None,
+        }
+    }
+    Some((labels, end.unwrap_or(pos + 1)))
+}
+
+/// Like [`read_labels`], returning the name lowercased and dot-separated
+fn read_name(message: &[u8], start: usize) -> Option<(String, usize)> {
+    let (labels, next) = read_labels(message, start)?;
+    Some((dotted_name(&labels), next))
+}
+
+/// Name made of `labels`, lowercased and dot-separated
+pub fn dotted_name<L: AsRef<[u8]>>(labels: &[L]) -> String {
+    let labels: Vec<String> = labels
+        .iter()
+        .map(|label| {
+            label
+                .as_ref()
+                .iter()
+                .map(|b| char::from(b.to_ascii_lowercase()))
+                .collect()
+        })
+        .collect();
+    labels.join(".")
+}
+
+/// Owner of the PTR records enumerating the service types (RFC 6763
+/// section 9), which are not instances
+const SERVICE_TYPES: &str = "_services._dns-sd._udp.local";
+
+/// Whether PTR records owned by the dotted `name` point at instances of a
+/// service
+pub fn is_service_type(name: &str) -> bool {
+    name.starts_with('_') && name != SERVICE_TYPES
+}
+
+/// Lets through mDNS messages that refer to one of the allowed services:
+/// queries by their question names, responses by the owner names of their
+/// PTR, SRV, TXT, A and AAAA records. A response with any allowed record
+/// passes unchanged. Malformed messages are dropped.
+///
+/// Linking devices, the filter remembers the SRV target hosts and TXT ids
+/// of the responses it lets through, and also lets through the responses
+/// of those devices about other services, such as the `_googlezone._tcp`
+/// records of a Chromecast in a speaker group, and the addresses of the
+/// hosts.
+pub struct MdnsServiceFilter {
+    services: Vec<String>,
+    /// When each device that announced an allowed service was last seen,
+    /// if devices are linked
+    devices: Option<Mutex<HashMap<Device, Instant>>>,
+    log: RepeatedMessages,
+}
+
+impl MdnsServiceFilter {
+    pub fn new(services: &[String], link_devices: bool) -> Self {
+        let services = services
+            .iter()
+            .map(|service| service.trim_end_matches('.').to_ascii_lowercase())
+            .collect();
+        MdnsServiceFilter {
+            services,
+            devices: link_devices.then(Mutex::default),
+            log: RepeatedMessages::default(),
+        }
+    }
+
+    /// Whether the response `message` refers to an allowed service, or
+    /// with linking to a device that announced one. Remembers the devices
+    /// of allowed responses.
+    fn allowed_response(&self, message: &MdnsMessage) -> bool {
+        let allowed = message.records.iter().any(|(rtype, name)| {
+            matches!(*rtype, TYPE_PTR | TYPE_SRV | TYPE_TXT | TYPE_A | TYPE_AAAA)
+                && self.allowed(name)
+        });
+        let Some(devices) = &self.devices else {
+            return allowed;
+        };
+        let now = Instant::now();
+        let mut devices = devices.lock().unwrap();
+        devices.retain(|_, seen| now.duration_since(*seen) < DEVICE_LIFETIME);
+        if !allowed {
+            let host = |name: &String| devices.contains_key(&Device::Host(name.clone()));
+            return message
+                .devices
+                .iter()
+                .any(|(_, device)| devices.contains_key(device))
+                || message.records.iter().any(|(rtype, name)| {
+                    matches!(*rtype, TYPE_SRV | TYPE_TXT | TYPE_A | TYPE_AAAA) && host(name)
+                });
+        }
+        for (_, device) in message
+            .devices
+            .iter()
+            .filter(|(name, _)| self.allowed(name))
+        {
+            if devices.len() >= MAX_DEVICES && !devices.contains_key(device) {
+                let oldest = devices
+                    .iter()
+                    .min_by_key(|(_, seen)| **seen)
+                    .map(|(device, _)| device.clone());
+                if let Some(oldest) = oldest {
+                    devices.remove(&oldest);
+                }
+            }
+            devices.insert(device.clone(), now);
+        }
+        true
+    }
+
+    /// Whether `name` is an allowed service or an instance of one
+    fn allowed(&self, name: &str) -> bool {
+        self.services.iter().any(|service| {
+            name.strip_suffix(service.as_str())
+                .is_some_and(|rest| rest.is_empty() || rest.ends_with('.'))
+        })
+    }
+}
+
+impl Filter for MdnsServiceFilter {
+    fn name(&self) -> &str {
+        "mdns-services"
+    }
+
+    fn evaluate(&self, ctx: &PacketContext) -> Decision {
+        let Some(udp) = ctx.udp() else {
+            return Decision::Continue;
+        };
+        if udp.get_source() != MDNS_PORT && udp.get_destination() != MDNS_PORT {
+            return Decision::Continue;
+        }
+        let Some(message) = MdnsMessage::parse(udp.payload()) else {
+            debug_repeated!(
+                self.log,
+                "Malformed mDNS messages dropped",
+                "Malformed mDNS message dropped"
+            );
+            return Decision::Drop;
+        };
+        let allowed = if message.response {
+            self.allowed_response(&message)
+        } else {
+            message.questions.iter().any(|name| self.allowed(name))
+        };
+        if allowed {
+            Decision::Continue
+        } else {
+            debug_repeated!(
+                self.log,
+                "mDNS messages for other services dropped",
+                "mDNS message for other services dropped"
+            );
+            Decision::Drop
+        }
+    }
+
+    fn state(&self) -> Option<Vec<String>> {
+        let devices = self.devices.as_ref()?.lock().unwrap();
+        let now = Instant::now();
+        Some(
+            devices
+                .iter()
+                .map(|(device, seen)| {
+                    let ago = Duration::from_secs(now.duration_since(*seen).as_secs());
+                    format!("{}, seen {} ago", device, humantime::format_duration(ago))
+                })
+                .collect(),
+        )
+    }
+}
+
+/// Resource record of a message being rewritten, with its names
+/// decompressed
+struct Record<'a> {
+    name: Vec<&'a [u8]>,
+    rtype: u16,
+    class: u16,
+    ttl: u32,
+    /// Offset of the TTL in the original message
+    ttl_at: usize,
+    data: RecordData<'a>,
+}
+
+enum RecordData<'a> {
+    /// Data without names, copied as it is
+    Raw(&'a [u8]),
+    /// Data of types that may hold compressed names: fixed fields, the
+    /// names and whatever follows them
+    Names {
+        fixed: &'a [u8],
+        names: Vec<Vec<&'a [u8]>>,
+        rest: &'a [u8],
+    },
+    /// Character strings of a TXT record
+    Txt(Vec<&'a [u8]>),
+}
+
+/// Length of the fixed fields and number of names at the start of the data
+/// of types whose names may be compressed (RFC 6762 section 18.14)
+fn names_in_data(rtype: u16) -> Option<(usize, usize)> {
+    match rtype {
+        TYPE_NS | TYPE_CNAME | TYPE_PTR | TYPE_DNAME | TYPE_NSEC => Some((0, 1)),
+        TYPE_MX | TYPE_AFSDB | TYPE_RT | TYPE_KX => Some((2, 1)),
+        TYPE_SRV => Some((6, 1)),
+        TYPE_SOA | TYPE_RP => Some((0, 2)),
+        TYPE_PX => Some((2, 2)),
+        _ => None,
+    }
+}
+
+/// mDNS message taken apart for rewriting
+struct EditableMessage<'a> {
+    header: &'a [u8],
+    /// Name, type and class of every question
+    questions: Vec<(Vec<&'a [u8]>, &'a [u8])>,
+    /// Answer, authority and additional records
+    sections: [Vec<Record<'a>>; 3],
+}
+
+impl<'a> EditableMessage<'a> {
+    fn parse(payload: &'a [u8]) -> Option<Self> {
+        let header = payload.get(..HEADER_LEN)?;
+        let field = |i: usize| u16::from_be_bytes([header[i], header[i + 1]]);
+        let mut pos = HEADER_LEN;
+        let mut questions = Vec::new();
+        for _ in 0..field(4) {
+            let (name, next) = read_labels(payload, pos)?;
+            questions.push((name, payload.get(next..next + 4)?));
+            pos = next + 4;
+        }
+        let mut sections: [Vec<Record>; 3] = Default::default();
+        for (section, count) in sections.iter_mut().zip([field(6), field(8), field(10)]) {
+            for _ in 0..count {
+                let (record, next) = parse_record(payload, pos)?;
+                section.push(record);
+                pos = next;
+            }
+        }
+        Some(EditableMessage {
+            header,
+            questions,
+            sections,
+        })
+    }
+
+    fn records_mut(&mut self) -> impl Iterator<Item = &mut Record<'a>> {
+        self.sections.iter_mut().flatten()
+    }
+
+    fn response(&self) -> bool {
+        u16::from_be_bytes([self.header[2], self.header[3]]) & FLAG_RESPONSE != 0
+    }
+
+    /// Serializes the message, compressing names against earlier ones
+    fn write(&self) -> Vec<u8> {
+        let mut writer = NameWriter::default();
+        let out = &mut writer.out;
+        out.extend_from_slice(&self.header[..4]);
+        out.extend_from_slice(&(self.questions.len() as u16).to_be_bytes());
+        for section in &self.sections {
+            out.extend_from_slice(&(section.len() as u16).to_be_bytes());
+        }
+        for (name, fields) in &self.questions {
+            writer.name(name);
+            writer.out.extend_from_slice(fields);
+        }
+        for record in self.sections.iter().flatten() {
+            writer.name(&record.name);
+            let out = &mut writer.out;
+            out.extend_from_slice(&record.rtype.to_be_bytes());
+            out.extend_from_slice(&record.class.to_be_bytes());
+            out.extend_from_slice(&record.ttl.to_be_bytes());
+            let len_at = out.len();
+            out.extend_from_slice(&[0, 0]);
+            match &record.data {
+                RecordData::Raw(data) => out.extend_from_slice(data),
+                RecordData::Names { fixed, names, rest } => {
+                    out.extend_from_slice(fixed);
+                    for name in names {
+                        writer.name(name);
+                    }
+                    writer.out.extend_from_slice(rest);
+                }
+                RecordData::Txt(strings) if strings.is_empty() => out.push(0),
+                RecordData::Txt(strings) => {
+                    for string in strings {
+                        out.push(string.len() as u8);
+                        out.extend_from_slice(string);
+                    }
+                }
+            }
+            let len = (writer.out.len() - len_at - 2) as u16;
+            writer.out[len_at..len_at + 2].copy_from_slice(&len.to_be_bytes());
+        }
+        writer.out
+    }
+}
+
+fn parse_record(payload: &[u8], pos: usize) -> Option<(Record<'_>, usize)> {
+    let (name, next) = read_labels(payload, pos)?;
+    let fixed = payload.get(next..next + 10)?;
+    let rtype = u16::from_be_bytes([fixed[0], fixed[1]]);
+    let class = u16::from_be_bytes([fixed[2], fixed[3]]);
+    let ttl = u32::from_be_bytes([fixed[4], fixed[5], fixed[6], fixed[7]]);
+    let start = next + 10;
+    let end = start + usize::from(u16::from_be_bytes([fixed[8], fixed[9]]));
+    let data = payload.get(start..end)?;
+    let data = match (rtype, names_in_data(rtype)) {
+        (TYPE_TXT, _) => {
+            let mut strings = Vec::new();
+            let mut rest = data;
+            while let Some((&len, tail)) = rest.split_first() {
+                let string = tail.get(..usize::from(len))?;
+                if !string.is_empty() {
+                    strings.push(string);
+                }
+                rest = &tail[string.len()..];
+            }
+            RecordData::Txt(strings)
+        }
+        (_, Some((fixed_len, count))) => {
+            let mut pos = start + fixed_len;
+            let mut names = Vec::new();
+            for _ in 0..count {
+                let (name, next) = read_labels(payload, pos)?;
+                if next > end {
+                    return None;
+                }
+                names.push(name);
+                pos = next;
+            }
+            RecordData::Names {
+                fixed: data.get(..fixed_len)?,
+                names,
+                rest: &payload[pos..end],
+            }
+        }
+        _ => RecordData::Raw(data),
+    };
+    let record = Record {
+        name,
+        rtype,
+        class,
+        ttl,
+        ttl_at: next + 4,
+        data,
+    };
+    Some((record, end))
+}
+
+/// Output buffer remembering where each name suffix was written so later
+/// names can point to it
+#[derive(Default)]
+struct NameWriter {
+    out: Vec<u8>,
+    suffixes: HashMap<Vec<u8>, u16>,
+}
+
+impl NameWriter {
+    fn name(&mut self, labels: &[&[u8]]) {
+        for (i, label) in labels.iter().enumerate() {
+            let suffix: Vec<u8> = labels[i..]
+                .iter()
+                .flat_map(|label| std::iter::once(label.len() as u8).chain(label.iter().copied()))
+                .collect();
+            if let Some(offset) = self.suffixes.get(&suffix) {
+                self.out.extend_from_slice(&(0xc000 | offset).to_be_bytes());
+                return;
+            }
+            // Pointers have 14 bits for the offset
+            if self.out.len() < 0x4000 {
+                self.suffixes.insert(suffix, self.out.len() as u16);
+            }
+            self.out.push(label.len() as u8);
+            self.out.extend_from_slice(label);
+        }
+        self.out.push(0);
+    }
+}
+
+/// Rewrites forwarded mDNS messages: record TTLs of responses are clamped
+/// to `max_ttl`, leaving goodbye records (TTL 0) alone, and TXT records or
+/// the listed TXT keys are removed for privacy. Removing data shifts the
+/// rest of the message, so it is then serialized anew with its own name
+/// compression. Messages that cannot be parsed are dropped when anything is
+/// to be removed and passed unchanged otherwise.
+pub struct MdnsRewrite {
+    max_ttl: Option<u32>,
+    strip_txt: bool,
+    /// Lowercased TXT keys to remove
+    strip_keys: Vec<Vec<u8>>,
+}
+
+impl MdnsRewrite {
+    pub fn new(max_ttl: Option<u32>, strip_txt: bool, strip_keys: &[String]) -> Self {
+        let strip_keys = strip_keys
+            .iter()
+            .map(|key| key.to_ascii_lowercase().into_bytes())
+            .collect();
+        MdnsRewrite {
+            max_ttl,
+            strip_txt,
+            strip_keys,
+        }
+    }
+
+    fn strips(&self) -> bool {
+        self.strip_txt || !self.strip_keys.is_empty()
+    }
+
+    /// Removes TXT records or keys, returning whether anything was removed
+    fn strip(&self, message: &mut EditableMessage) -> bool {
+        let mut removed = false;
+        if self.strip_txt {
+            for section in &mut message.sections {
+                let before = section.len();
+                section.retain(|record| record.rtype != TYPE_TXT);
+                removed |= section.len() != before;
+            }
+        }
+        for record in message.records_mut() {
+            let RecordData::Txt(strings) = &mut record.data else {
+                continue;
+            };
+            let before = strings.len();
+            strings.retain(|string| {
+                let key = string.split(|b| *b == b'=').next().unwrap_or_default();
+                !self
+                    .strip_keys
+                    .iter()
+                    .any(|strip| key.eq_ignore_ascii_case(strip))
+            });
+            removed |= strings.len() != before;
+        }
+        removed
+    }
+}
+
+impl Rewrite for MdnsRewrite {
+    fn name(&self) -> &str {
+        "mdns"
+    }
+
+    fn apply(&self, frame: &mut Vec<u8>) -> bool {
+        let Some(datagram) = UdpDatagram::locate(frame) else {
+            return true;
+        };
+        if !datagram.ports(frame).contains(&MDNS_PORT) {
+            return true;
+        }
+        let payload = datagram.payload();
+        let Some(mut message) = EditableMessage::parse(&frame[payload.clone()]) else {
+            debug!("Malformed mDNS message not rewritten");
+            return !self.strips();
+        };
+
+        let mut clamped = Vec::new();
+        if let Some(max_ttl) = self.max_ttl.filter(|_| message.response()) {
+            for record in message.records_mut() {
+                if record.rtype != TYPE_OPT && record.ttl > max_ttl {
+                    record.ttl = max_ttl;
+                    clamped.push(record.ttl_at);
+                }
+            }
+        }
+        if self.strips() && self.strip(&mut message) {
+            let rewritten = message.write();
+            debug!("TXT data removed from mDNS message");
+            return datagram.replace_payload(frame, &rewritten);
+        }
+        if clamped.is_empty() {
+            return true;
+        }
+        let ttl = self.max_ttl.unwrap_or_default().to_be_bytes();
+        for at in clamped {
+            let at = payload.start + at;
+            frame[at..at + 4].copy_from_slice(&ttl);
+        }
+        debug!("mDNS record TTLs clamped");
+        datagram.update_checksums(frame)
+    }
+}
+
+/// Resource record detached from its message, with the names in its data
+/// uncompressed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OwnedRecord {
+    pub name: Vec<Vec<u8>>,
+    pub rtype: u16,
+    /// Class including the cache flush bit
+    pub class: u16,
+    pub ttl: u32,
+    pub data: Vec<u8>,
+}
+
+impl OwnedRecord {
+    fn new(record: &Record) -> Self {
+        let mut data = Vec::new();
+        match &record.data {
+            RecordData::Raw(raw) => data.extend_from_slice(raw),
+            RecordData::Names { fixed, names, rest } => {
+                data.extend_from_slice(fixed);
+                for name in names {
+                    for label in name {
+                        data.push(label.len() as u8);
+                        data.extend_from_slice(label);
+                    }
+                    data.push(0);
+                }
+                data.extend_from_slice(rest);
+            }
+            RecordData::Txt(strings) if strings.is_empty() => data.push(0),
+            RecordData::Txt(strings) => {
+                for string in strings {
+                    data.push(string.len() as u8);
+                    data.extend_from_slice(string);
+                }
+            }
+        }
+        OwnedRecord {
+            name: record.name.iter().map(|label| label.to_vec()).collect(),
+            rtype: record.rtype,
+            class: record.class,
+            ttl: record.ttl,
+            data,
+        }
+    }
+
+    /// The record for writing, with the names in its data taken apart so
+    /// they are compressed against the rest of the message
+    fn borrowed(&self) -> Record<'_> {
+        Record {
+            name: self.name.iter().map(Vec::as_slice).collect(),
+            rtype: self.rtype,
+            class: self.class,
+            ttl: self.ttl,
+            ttl_at: 0,
+            data: self.names().unwrap_or(RecordData::Raw(&self.data)),
+        }
+    }
+
+    fn names(&self) -> Option<RecordData<'_>> {
+        let (fixed_len, count) = names_in_data(self.rtype)?;
+        let mut pos = fixed_len;
+        let mut names = Vec::new();
+        for _ in 0..count {
+            let (name, next) = read_labels(&self.data, pos)?;
+            names.push(name);
+            pos = next;
+        }
+        Some(RecordData::Names {
+            fixed: self.data.get(..fixed_len)?,
+            names,
+            rest: &self.data[pos..],
+        })
+    }
+
+    /// Target name of a PTR or SRV record
+    pub fn target(&self) -> Option<Vec<Vec<u8>>> {
+        let start = match self.rtype {
+            TYPE_PTR => 0,
+            TYPE_SRV => 6,
+            _ => return None,
+        };
+        let (labels, _) = read_labels(&self.data, start)?;
+        Some(labels.iter().map(|label| label.to_vec()).collect())
+    }
+}
+
+/// Question of an mDNS query
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Question {
+    pub name: Vec<Vec<u8>>,
+    pub qtype: u16,
+    /// Class including the unicast response bit
+    pub class: u16,
+}
+
+/// mDNS message detached from the payload it was parsed from
+#[derive(Debug)]
+pub struct OwnedMessage {
+    pub response: bool,
+    pub questions: Vec<Question>,
+    /// Answers of a response, known answers of a query
+    pub answers: Vec<OwnedRecord>,
+    pub additional: Vec<OwnedRecord>,
+}
+
+impl OwnedMessage {
+    pub fn parse(payload: &[u8]) -> Option<Self> {
+        let message = EditableMessage::parse(payload)?;
+        let questions = message
+            .questions
+            .iter()
+            .map(|(name, fields)| Question {
+                name: name.iter().map(|label| label.to_vec()).collect(),
+                qtype: u16::from_be_bytes([fields[0], fields[1]]),
+                class: u16::from_be_bytes([fields[2], fields[3]]),
+            })
+            .collect();
+        let owned = |section: &Vec<Record>| section.iter().map(OwnedRecord::new).collect();
+        Some(OwnedMessage {
+            response: message.response(),
+            questions,
+            answers: owned(&message.sections[0]),
+            additional: owned(&message.sections[2]),
+        })
+    }
+}
+
+/// Serializes an authoritative response with the given records
+pub fn write_response(answers: &[OwnedRecord], additional: &[OwnedRecord]) -> Vec<u8> {
+    let mut header = [0; HEADER_LEN];
+    header[2..4].copy_from_slice(&(FLAG_RESPONSE | FLAG_AUTHORITATIVE).to_be_bytes());
+    let message = EditableMessage {
+        header: &header,
+        questions: Vec::new(),
+        sections: [
+            answers.iter().map(OwnedRecord::borrowed).collect(),
+            Vec::new(),
+            additional.iter().map(OwnedRecord::borrowed).collect(),
+        ],
+    };
+    message.write()
+}
+
+/// Serializes a query with the given questions
+pub fn write_query(questions: &[Question]) -> Vec<u8> {
+    let header = [0; HEADER_LEN];
+    let fields: Vec<[u8; 4]> = questions
+        .iter()
+        .map(|question| {
+            let [a, b] = question.qtype.to_be_bytes();
+            let [c, d] = question.class.to_be_bytes();
+            [a, b, c, d]
+        })
+        .collect();
+    let message = EditableMessage {
+        header: &header,
+        questions: questions
+            .iter()
+            .zip(&fields)
+            .map(|(question, fields)| {
+                let name = question.name.iter().map(Vec::as_slice).collect();
+                (name, &fields[..])
+            })
+            .collect(),
+        sections: Default::default(),
+    };
+    message.write()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filter::{MDNS_IPV4_GROUP, MDNS_IPV6_GROUP};
+    use crate::pair::Direction;
+    use crate::profile::{combine, Profile};
+    use crate::testutil::*;
+    use pnet::packet::ipv4::Ipv4Packet;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    #[test]
+    fn parses_compressed_response() {
+        let mut wire = dns_header(FLAG_RESPONSE, 0, 2);
+        let service = wire.len();
+        wire.extend(dns_name("_googlecast._tcp.local"));
+        wire.extend_from_slice(&[0, 12, 0, 1, 0, 0, 0, 120, 0, 2, 0xc0, service as u8]);
+        // Instance name compressed against the service name of the PTR record
+        wire.extend_from_slice(&[4, b'T', b'e', b'S', b't', 0xc0, service as u8]);
+        wire.extend_from_slice(&[0, 16, 0, 1, 0, 0, 0, 120, 0, 0]);
+
+        let message = MdnsMessage::parse(&wire).unwrap();
+        assert!(message.response);
+        assert_eq!(
+            message.records,
+            vec![
+                (TYPE_PTR, "_googlecast._tcp.local".to_string()),
+                (TYPE_TXT, "test._googlecast._tcp.local".to_string()),
+            ]
+        );
+        let filter = MdnsServiceFilter::new(&["_googlecast._tcp.local.".to_string()], false);
+        assert!(message.records.iter().all(|(_, name)| filter.allowed(name)));
+        assert!(!filter.allowed("_x_googlecast._tcp.local"));
+    }
+
+    #[test]
+    fn forwards_matter_discovery() {
+        let profile: Profile = "matter".parse().unwrap();
+        let services = combine(&[profile], |preset| preset.mdns_services, &[] as &[String]);
+        let filter = MdnsServiceFilter::new(&services, true);
+        let frame = |payload: &[u8]| {
+            let group = multicast_mac(MDNS_IPV6_GROUP.into());
+            let host = "fe80::1c2b:3aff:fe4d:5e6f".parse::<Ipv6Addr>().unwrap();
+            udp_frame(
+                HOST_MAC,
+                group,
+                host,
+                MDNS_IPV6_GROUP,
+                MDNS_PORT,
+                MDNS_PORT,
+                payload,
+            )
+        };
+        let evaluate = |payload: &[u8]| {
+            let frame = frame(payload);
+            filter.evaluate(&PacketContext::parse("eth0", Direction::Outbound, &frame).unwrap())
+        };
+
+        // A commissioner looking for devices by their long discriminator,
+        // and the device answering with its commissionable instance
+        let browse = mdns_query("_L3840._sub._matterc._udp.local", DNS_TYPE_PTR);
+        let instance = dns_name("3A1B2C3D4E5F6071._matterc._udp.local");
+        let answer = mdns_response("_matterc._udp.local", DNS_TYPE_PTR, 120, &instance);
+        // A controller resolving an operational node
+        let operational = mdns_query("2906C908D115D362-8FC7772401CD0696._matter._tcp.local", 33);
+        for payload in [&browse, &answer, &operational] {
+            assert_eq!(evaluate(payload), Decision::Continue);
+        }
+        let other = mdns_query("_googlecast._tcp.local", DNS_TYPE_PTR);
+        assert_eq!(evaluate(&other), Decision::Drop);
+    }
+
+    /// Announcement of `instance` of `service` in the shape Chromecasts send
+    /// them: PTR, SRV on `host`, TXT with `txt` and the address of the host
+    fn cast_announcement(
+        service: &str,
+        instance: &str,
+        host: &str,
+        port: u16,
+        txt: &[&str],
+    ) -> Vec<u8> {
+        let instance = format!("{}.{}", instance, service);
+        let mut wire = dns_header(0x8400, 0, 4);
+        let mut record = |name: &str, rtype: u16, class: u16, ttl: u32, data: &[u8]| {
+            wire.extend(dns_name(name));
+            wire.extend_from_slice(&rtype.to_be_bytes());
+            wire.extend_from_slice(&class.to_be_bytes());
+            wire.extend_from_slice(&ttl.to_be_bytes());
+            wire.extend_from_slice(&(data.len() as u16).to_be_bytes());
+            wire.extend_from_slice(data);
+        };
+        record(service, TYPE_PTR, CLASS_IN, 120, &dns_name(&instance));
+        let mut srv = vec![0, 0, 0, 0];
+        srv.extend_from_slice(&port.to_be_bytes());
+        srv.extend(dns_name(host));
+        record(&instance, TYPE_SRV, 0x8001, 120, &srv);
+        let strings: Vec<u8> = txt
+            .iter()
+            .flat_map(|string| [&[string.len() as u8][..], string.as_bytes()].concat())
+            .collect();
+        record(&instance, TYPE_TXT, 0x8001, 4500, &strings);
+        record(host, TYPE_A, 0x8001, 120, &[192, 168, 100, 7]);
+        wire
+    }
+
+    #[test]
+    fn forwards_the_services_of_chromecast_speaker_groups() {
+        // A Chromecast Audio, the speaker group it hosts and its zone
+        // service, as they announce themselves
+        let kitchen = "5a3c8d3f-1e0b-4b2d-9c7e-6f8a1b2c3d4e.local";
+        let device = cast_announcement(
+            "_googlecast._tcp.local",
+            "Chromecast-Audio-5a3c8d3f1e0b4b2d9c7e6f8a1b2c3d4e",
+            kitchen,
+            8009,
+            &[
+                "id=5a3c8d3f1e0b4b2d9c7e6f8a1b2c3d4e",
+                "cd=0A1B2C3D4E5F60718293A4B5C6D7E8F9",
+                "rm=",
+                "ve=05",
+                "md=Chromecast Audio",
+                "ic=/setup/icon.png",
+                "fn=Kitchen speaker",
+                "ca=2052",
+                "st=0",
+                "bs=FA8FCA9A1B2C",
+                "nf=1",
+                "rs=",
+            ],
+        );
+        let group = cast_announcement(
+            "_googlecast._tcp.local",
+            "Google-Cast-Group-0f1e2d3c4b5a69788796a5b4c3d2e1f0",
+            kitchen,
+            32187,
+            &[
+                "id=0f1e2d3c-4b5a-6978-8796-a5b4c3d2e1f0",
+                "md=Google Cast Group",
+                "fn=Downstairs",
+                "ca=199172",
+                "st=0",
+            ],
+        );
+        // The zone service names the host in upper case and compresses
+        // nothing; the id matches the cast service's in any case
+        let zone = cast_announcement(
+            "_googlezone._tcp.local",
+            "5A3C8D3F1E0B4B2D9C7E6F8A1B2C3D4E",
+            &kitchen.to_ascii_uppercase(),
+            10001,
+            &["id=5A3C8D3F1E0B4B2D9C7E6F8A1B2C3D4E"],
+        );
+        let follower = cast_announcement(
+            "_googlerpc._tcp.local",
+            "7b9e0c1d2e3f405162738495a6b7c8d9",
+            "7b9e0c1d-2e3f-4051-6273-8495a6b7c8d9.local",
+            10001,
+            &["id=7b9e0c1d2e3f405162738495a6b7c8d9"],
+        );
+
+        let message = MdnsMessage::parse(&zone).unwrap();
+        assert_eq!(
+            message.devices,
+            [
+                (
+                    "5a3c8d3f1e0b4b2d9c7e6f8a1b2c3d4e._googlezone._tcp.local".to_string(),
+                    Device::Host(kitchen.to_string())
+                ),
+                (
+                    "5a3c8d3f1e0b4b2d9c7e6f8a1b2c3d4e._googlezone._tcp.local".to_string(),
+                    Device::Id("5a3c8d3f1e0b4b2d9c7e6f8a1b2c3d4e".to_string())
+                ),
+            ]
+        );
+
+        let cast = ["_googlecast._tcp.local".to_string()];
+        let evaluate = |filter: &MdnsServiceFilter, payload: &[u8]| {
+            let frame = ipv4_frame(payload);
+            filter.evaluate(&PacketContext::parse("eth0", Direction::Inbound, &frame).unwrap())
+        };
+
+        // With only the cast service allowed, the zone records pass once
+        // the device announced its cast service, other devices' do not
+        let linked = MdnsServiceFilter::new(&cast, true);
+        assert_eq!(evaluate(&linked, &zone), Decision::Drop);
+        assert_eq!(evaluate(&linked, &device), Decision::Continue);
+        assert_eq!(evaluate(&linked, &group), Decision::Continue);
+        assert_eq!(evaluate(&linked, &zone), Decision::Continue);
+        assert_eq!(evaluate(&linked, &follower), Decision::Drop);
+        let query = mdns_query("_googlezone._tcp.local", DNS_TYPE_PTR);
+        assert_eq!(evaluate(&linked, &query), Decision::Drop);
+        let state = linked.state().unwrap();
+        assert_eq!(state.len(), 3, "{:?}", state);
+        assert!(state.contains(&format!("host {}, seen 0s ago", kitchen)));
+
+        // Strictly only the cast service
+        let strict = MdnsServiceFilter::new(&cast, false);
+        assert_eq!(evaluate(&strict, &device), Decision::Continue);
+        assert_eq!(evaluate(&strict, &zone), Decision::Drop);
+        assert!(strict.state().is_none());
+
+        // The profile allows the group services outright
+        let profile: Profile = "chromecast".parse().unwrap();
+        let services = combine(&[profile], |preset| preset.mdns_services, &[] as &[String]);
+        let profiled = MdnsServiceFilter::new(&services, false);
+        assert_eq!(evaluate(&profiled, &zone), Decision::Continue);
+        assert_eq!(evaluate(&profiled, &follower), Decision::Continue);
+    }
+
+    #[test]
+    fn rejects_malformed_names() {
+        // Pointer to itself
+        let mut wire = dns_header(0, 1, 0);
+        wire.extend_from_slice(&[0xc0, HEADER_LEN as u8, 0, 12, 0, 1]);
+        assert_eq!(MdnsMessage::parse(&wire), None);
+
+        // Truncated label and missing question
+        let mut wire = dns_header(0, 2, 0);
+        wire.extend(dns_name("_googlecast._tcp.local"));
+        wire.extend_from_slice(&[0, 12, 0, 1, 5, b'a']);
+        assert_eq!(MdnsMessage::parse(&wire), None);
+
+        // Name longer than 255 bytes
+        let mut wire = dns_header(0, 1, 0);
+        wire.extend(dns_name(&vec!["a".repeat(63); 5].join(".")));
+        wire.extend_from_slice(&[0, 12, 0, 1]);
+        assert_eq!(MdnsMessage::parse(&wire), None);
+    }
+
+    fn ipv4_frame(payload: &[u8]) -> Vec<u8> {
+        let group = multicast_mac(MDNS_IPV4_GROUP.into());
+        let host = Ipv4Addr::new(192, 168, 100, 7);
+        udp_frame(
+            HOST_MAC,
+            group,
+            host,
+            MDNS_IPV4_GROUP,
+            MDNS_PORT,
+            MDNS_PORT,
+            payload,
+        )
+    }
+
+    #[test]
+    fn clamps_ttls_and_strips_txt_keys() {
+        let mut wire = dns_header(FLAG_RESPONSE, 0, 3);
+        let service = wire.len();
+        wire.extend(dns_name("_googlecast._tcp.local"));
+        wire.extend_from_slice(&[0, 12, 0, 1, 0, 0, 0x11, 0x94, 0, 7]);
+        wire.extend_from_slice(&[4, b't', b'e', b's', b't', 0xc0, service as u8]);
+        let instance = wire.len() - 7;
+        wire.extend_from_slice(&[0xc0, instance as u8, 0, 16, 0x80, 1, 0, 0, 0x11, 0x94]);
+        wire.extend_from_slice(&[0, 17, 6, b'i', b'd', b'=', b'a', b'b', b'c']);
+        wire.extend_from_slice(&[9, b'f', b'n', b'=', b'K', b'i', b't', b'c', b'h', b'n']);
+        // Goodbye for an address record
+        wire.extend_from_slice(&[0xc0, instance as u8, 0, 1, 0, 1, 0, 0, 0, 0, 0, 4]);
+        wire.extend_from_slice(&[192, 168, 100, 7]);
+
+        let mut frame = ipv4_frame(&wire);
+        assert!(MdnsRewrite::new(Some(120), false, &["FN".to_string()]).apply(&mut frame));
+        let ip = Ipv4Packet::new(&frame[ETHERNET_HEADER_LEN..]).unwrap();
+        assert_eq!(
+            usize::from(ip.get_total_length()),
+            frame.len() - ETHERNET_HEADER_LEN
+        );
+        assert_eq!(ip.get_checksum(), pnet::packet::ipv4::checksum(&ip));
+        let udp = pnet::packet::udp::UdpPacket::new(ip.payload()).unwrap();
+        let expected =
+            pnet::packet::udp::ipv4_checksum(&udp, &ip.get_source(), &ip.get_destination());
+        assert_eq!(udp.get_checksum(), expected);
+
+        let message = EditableMessage::parse(udp.payload()).unwrap();
+        let records: Vec<_> = message.sections[0].iter().collect();
+        assert_eq!(
+            records.iter().map(|r| r.ttl).collect::<Vec<_>>(),
+            [120, 120, 0]
+        );
+        assert!(matches!(&records[1].data, RecordData::Txt(strings) if strings == &[b"id=abc"]));
+        assert_eq!(records[2].name, records[1].name);
+        // Names stay compressed
+        assert_eq!(udp.payload().len(), wire.len() - 10);
+
+        // Queries keep their TTLs, TXT records are removed entirely
+        let mut query = wire.clone();
+        query[2..4].copy_from_slice(&[0, 0]);
+        let mut frame = ipv4_frame(&query);
+        assert!(MdnsRewrite::new(Some(120), true, &[]).apply(&mut frame));
+        let message = EditableMessage::parse(&frame[ETHERNET_HEADER_LEN + 28..]).unwrap();
+        let types: Vec<_> = message.sections[0]
+            .iter()
+            .map(|r| (r.rtype, r.ttl))
+            .collect();
+        assert_eq!(types, [(TYPE_PTR, 4500), (TYPE_A, 0)]);
+
+        // Fails closed on messages it cannot parse
+        let mut frame = ipv4_frame(&wire[..wire.len() - 3]);
+        assert!(!MdnsRewrite::new(None, true, &[]).apply(&mut frame));
+        assert!(MdnsRewrite::new(Some(120), false, &[]).apply(&mut frame));
+    }
+}
@@ -0,0 +1,665 @@
+//! Cache of the mDNS records in responses forwarded inwards, answering
+//! repeated queries from the internal side without forwarding them.
+
+use crate::discovery::Device;
+#[cfg(feature = "dbus")]
+use crate::discovery::Discoveries;
+use crate::filter::{PacketContext, MDNS_HOP_LIMIT, MDNS_IPV4_GROUP, MDNS_IPV6_GROUP, MDNS_PORT};
+use crate::mdns::{
+    self, is_service_type, OwnedMessage, OwnedRecord, CLASS_IN, CLASS_TOP_BIT, TYPE_A, TYPE_AAAA,
+    TYPE_ANY, TYPE_PTR, TYPE_SRV, TYPE_TXT,
+};
+use crate::mdnsmerge::response_delay;
+use crate::responder::{Cache, Destination, Responder};
+use crate::rewrite::{Rewrite, UdpDatagram};
+use crate::state;
+use pnet::packet::Packet;
+use pnet::util::MacAddr;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+#[cfg(feature = "dbus")]
+use std::sync::OnceLock;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use tracing::debug;
+
+const IPV4_GROUP_MAC: MacAddr = MacAddr(0x01, 0x00, 0x5e, 0x00, 0x00, 0xfb);
+const IPV6_GROUP_MAC: MacAddr = MacAddr(0x33, 0x33, 0x00, 0x00, 0x00, 0xfb);
+/// Records of one set received this close together belong to the same
+/// announcement, so their cache flush bits do not evict each other
+/// (RFC 6762 section 10.2)
+const FLUSH_GRACE: Duration = Duration::from_secs(1);
+
+struct CachedRecord {
+    record: OwnedRecord,
+    received: Instant,
+    expires: Instant,
+}
+
+/// Cached record as kept in the state file, with its name as labels and
+/// the bytes in hex
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct SavedRecord {
+    #[serde(with = "crate::state::hex::list")]
+    pub name: Vec<Vec<u8>>,
+    pub rtype: u16,
+    pub class: u16,
+    pub ttl: u32,
+    #[serde(with = "crate::state::hex")]
+    pub data: Vec<u8>,
+    #[serde(with = "humantime_serde")]
+    pub received: SystemTime,
+    #[serde(with = "humantime_serde")]
+    pub expires: SystemTime,
+}
+
+/// Lowercased owner name and type of a record set
+type SetKey = (String, u16);
+
+/// Service type, instance name and expiry of a PTR record not cached before
+type NewInstance = (String, Vec<Vec<u8>>, Instant);
+
+/// What the cache has for a query
+#[derive(Debug)]
+struct Answer {
+    answers: Vec<OwnedRecord>,
+    additional: Vec<OwnedRecord>,
+    /// Whether a question is about shared records, such as the PTR records
+    /// of a browse, which responders the cache has not heard from may hold
+    shared: bool,
+}
+
+impl Answer {
+    /// Whether the answer replaces forwarding the query: only for questions
+    /// all about unique records, and not when the querier knows every answer
+    /// and is asking whether anyone else has one
+    fn replaces_query(&self) -> bool {
+        !self.shared && !self.answers.is_empty()
+    }
+}
+
+/// Record sets learned from responses, bounded by evicting the records
+/// expiring first
+struct Records {
+    max_records: usize,
+    sets: HashMap<SetKey, Vec<CachedRecord>>,
+}
+
+impl Records {
+    /// Stores the records of a response. Goodbye records (TTL 0) evict
+    /// their record and the cache flush bit evicts the rest of the set.
+    /// Returns the service instances that were not cached.
+    fn store(&mut self, records: Vec<OwnedRecord>, now: Instant) -> Vec<NewInstance> {
+        let mut learned = Vec::new();
+        let sets = &mut self.sets;
+        for record in records {
+            if record.class & !CLASS_TOP_BIT != CLASS_IN {
+                continue;
+            }
+            let key = (mdns::dotted_name(&record.name), record.rtype);
+            let instance = (record.rtype == TYPE_PTR && is_service_type(&key.0))
+                .then(|| record.target())
+                .flatten();
+            let service = key.0.clone();
+            let set = sets.entry(key).or_default();
+            let known = set.iter().any(|cached| cached.record.data == record.data);
+            set.retain(|cached| cached.record.data != record.data);
+            if record.ttl == 0 {
+                debug!("mDNS goodbye for {}", mdns::dotted_name(&record.name));
+                continue;
+            }
+            if record.class & CLASS_TOP_BIT != 0 {
+                set.retain(|cached| now.duration_since(cached.received) < FLUSH_GRACE);
+            }
+            let expires = now + Duration::from_secs(u64::from(record.ttl));
+            if let (false, Some(instance)) = (known, instance) {
+                learned.push((service, instance, expires));
+            }
+            set.push(CachedRecord {
+                record,
+                received: now,
+                expires,
+            });
+        }
+        for set in sets.values_mut() {
+            set.retain(|cached| cached.expires > now);
+        }
+        sets.retain(|_, set| !set.is_empty());
+        let mut count: usize = sets.values().map(Vec::len).sum();
+        while count > self.max_records {
+            // Evict the record expiring first
+            let Some((key, index)) = sets
+                .iter()
+                .flat_map(|(key, set)| set.iter().enumerate().map(move |(i, c)| (key, i, c)))
+                .min_by_key(|(_, _, cached)| cached.expires)
+                .map(|(key, index, _)| (key.clone(), index))
+            else {
+                break;
+            };
+            let set = sets.get_mut(&key).expect("key was just found");
+            set.remove(index);
+            if set.is_empty() {
+                sets.remove(&key);
+            }
+            count -= 1;
+        }
+        learned
+    }
+
+    /// What is listed and announced about an instance of `service`, with
+    /// the host and port of its SRV record if one is cached
+    fn device(
+        &self,
+        service: &str,
+        instance: &[Vec<u8>],
+        expires: Instant,
+        now: Instant,
+    ) -> Device {
+        let id = mdns::dotted_name(instance);
+        let location = self
+            .sets
+            .get(&(id.clone(), TYPE_SRV))
+            .and_then(|set| set.first())
+            .and_then(|cached| {
+                let data = &cached.record.data;
+                let port = u16::from_be_bytes([*data.get(4)?, *data.get(5)?]);
+                let host = mdns::dotted_name(&cached.record.target()?);
+                Some(format!("{}:{}", host, port))
+            });
+        Device {
+            protocol: "mdns",
+            id,
+            kind: service.to_string(),
+            location,
+            expires_in: expires.saturating_duration_since(now).as_secs(),
+        }
+    }
+
+    /// Records of a set that have not expired, with their remaining TTL
+    fn lookup(&self, name: &[Vec<u8>], rtype: u16, now: Instant) -> Vec<OwnedRecord> {
+        let Some(set) = self.sets.get(&(mdns::dotted_name(name), rtype)) else {
+            return Vec::new();
+        };
+        set.iter()
+            .filter_map(|cached| {
+                let ttl = cached.expires.saturating_duration_since(now).as_secs();
+                let ttl = u32::try_from(ttl).unwrap_or(u32::MAX);
+                (ttl > 0).then(|| OwnedRecord {
+                    ttl,
+                    ..cached.record.clone()
+                })
+            })
+            .collect()
+    }
+
+    /// Answers and additional records for `query`, or `None` if a question
+    /// cannot be answered from the cache
+    fn answers(&self, query: &OwnedMessage, now: Instant) -> Option<Answer> {
+        let mut answers = Vec::new();
+        let mut shared = false;
+        for question in &query.questions {
+            if question.qtype == TYPE_ANY || question.class & !CLASS_TOP_BIT != CLASS_IN {
+                return None;
+            }
+            let records = self.lookup(&question.name, question.qtype, now);
+            if records.is_empty() {
+                return None;
+            }
+            shared |= records
+                .iter()
+                .any(|record| record.class & CLASS_TOP_BIT == 0);
+            answers.extend(records);
+        }
+        // Known-answer suppression (RFC 6762 section 7.1)
+        answers.retain(|answer| {
+            !query.answers.iter().any(|known| {
+                known.rtype == answer.rtype
+                    && known.data == answer.data
+                    && mdns::dotted_name(&known.name) == mdns::dotted_name(&answer.name)
+                    && known.ttl >= answer.ttl / 2
+            })
+        });
+
+        // What a querier would ask for next (RFC 6763 section 12): the SRV
+        // and TXT records of PTR targets, then the addresses of SRV targets
+        let mut additional: Vec<OwnedRecord> = Vec::new();
+        for (from, wanted) in [
+            (TYPE_PTR, [TYPE_SRV, TYPE_TXT]),
+            (TYPE_SRV, [TYPE_A, TYPE_AAAA]),
+        ] {
+            let targets: Vec<Vec<Vec<u8>>> = answers
+                .iter()
+                .chain(&additional)
+                .filter(|record| record.rtype == from)
+                .filter_map(OwnedRecord::target)
+                .collect();
+            for target in targets {
+                for rtype in wanted {
+                    for record in self.lookup(&target, rtype, now) {
+                        if !answers.contains(&record) && !additional.contains(&record) {
+                            additional.push(record);
+                        }
+                    }
+                }
+            }
+        }
+        Some(Answer {
+            answers,
+            additional,
+            shared,
+        })
+    }
+}
+
+/// Records learned from mDNS responses forwarded to the internal side.
+/// Queries from the internal side are answered from it when every question
+/// is, and forwarded otherwise. Queries about shared records are forwarded
+/// as well, since the cache only knows the responders it has heard from, and
+/// so are queries whose answers the querier all listed as known. Answers
+/// carry the remaining TTL, leave out records the querier listed as known,
+/// and are sent to the multicast group unless every question asked for a
+/// unicast response.
+pub struct MdnsCache {
+    records: Mutex<Records>,
+    responder: Responder,
+    /// Whether answers to multicast queries for shared records wait the
+    /// random delay of RFC 6762 section 6
+    delay: bool,
+    answered: AtomicU64,
+    missed: AtomicU64,
+    /// Where service instances seen for the first time are announced
+    #[cfg(feature = "dbus")]
+    discoveries: OnceLock<Discoveries>,
+}
+
+impl MdnsCache {
+    pub fn new(max_records: usize, responder: Responder, delay: bool) -> Self {
+        MdnsCache {
+            records: Mutex::new(Records {
+                max_records,
+                sets: HashMap::new(),
+            }),
+            responder,
+            delay,
+            answered: AtomicU64::new(0),
+            missed: AtomicU64::new(0),
+            #[cfg(feature = "dbus")]
+            discoveries: OnceLock::new(),
+        }
+    }
+
+    /// Records that have not expired, for the state file
+    pub fn save(&self, now: Instant, wall: SystemTime) -> Vec<SavedRecord> {
+        let records = self.records.lock().unwrap();
+        records
+            .sets
+            .values()
+            .flatten()
+            .filter(|cached| cached.expires > now)
+            .map(|cached| SavedRecord {
+                name: cached.record.name.clone(),
+                rtype: cached.record.rtype,
+                class: cached.record.class,
+                ttl: cached.record.ttl,
+                data: cached.record.data.clone(),
+                received: state::wall_time(cached.received, now, wall),
+                expires: state::wall_time(cached.expires, now, wall),
+            })
+            .collect()
+    }
+
+    /// Adds the saved records whose TTL has not run out, those expiring last
+    /// first while there is room
+    pub fn restore(&self, mut saved: Vec<SavedRecord>, now: Instant, wall: SystemTime) {
+        saved.sort_by_key(|record| std::cmp::Reverse(record.expires));
+        let mut records = self.records.lock().unwrap();
+        let mut count: usize = records.sets.values().map(Vec::len).sum();
+        for saved in saved {
+            let Some(expires) = state::instant(saved.expires, now, wall) else {
+                continue;
+            };
+            if expires <= now || count >= records.max_records {
+                continue;
+            }
+            let record = OwnedRecord {
+                name: saved.name,
+                rtype: saved.rtype,
+                class: saved.class,
+                ttl: saved.ttl,
+                data: saved.data,
+            };
+            let set = records
+                .sets
+                .entry((mdns::dotted_name(&record.name), record.rtype))
+                .or_default();
+            if set.iter().any(|cached| cached.record.data == record.data) {
+                continue;
+            }
+            set.push(CachedRecord {
+                record,
+                received: state::instant(saved.received, now, wall).unwrap_or(now),
+                expires,
+            });
+            count += 1;
+        }
+    }
+
+    /// Announces the service instances learned from now on to
+    /// `discoveries`
+    #[cfg(feature = "dbus")]
+    pub fn announce_to(&self, discoveries: Discoveries) {
+        let _ = self.discoveries.set(discoveries);
+    }
+}
+
+impl Cache for MdnsCache {
+    fn name(&self) -> &str {
+        "mdns-cache"
+    }
+
+    fn answer(&self, ctx: &PacketContext) -> bool {
+        let Some(udp) = ctx.udp() else {
+            return false;
+        };
+        // Legacy unicast queries from other ports expect a different reply
+        if udp.get_source() != MDNS_PORT || udp.get_destination() != MDNS_PORT {
+            return false;
+        }
+        let Some(query) = OwnedMessage::parse(udp.payload()) else {
+            return false;
+        };
+        if query.response || query.questions.is_empty() {
+            return false;
+        }
+        let source = ctx.source_ip();
+        let unicast = query
+            .questions
+            .iter()
+            .all(|question| question.class & CLASS_TOP_BIT != 0);
+        let destination = match (source, unicast) {
+            (Some(ip), true) => (ctx.ethernet.get_source(), ip),
+            (Some(IpAddr::V4(_)), false) => (IPV4_GROUP_MAC, IpAddr::V4(MDNS_IPV4_GROUP)),
+            (Some(IpAddr::V6(_)), false) => (IPV6_GROUP_MAC, IpAddr::V6(MDNS_IPV6_GROUP)),
+            (None, _) => return false,
+        };
+        let destination = Destination {
+            mac: destination.0,
+            ip: destination.1,
+            port: MDNS_PORT,
+        };
+        let answer = self.records.lock().unwrap().answers(&query, Instant::now());
+        let Some(answer) = answer else {
+            self.missed.fetch_add(1, Ordering::Relaxed);
+            return false;
+        };
+        if answer.answers.is_empty() {
+            self.missed.fetch_add(1, Ordering::Relaxed);
+            debug!("mDNS query lists every cached answer as known, forwarded");
+            return false;
+        }
+        let payload = mdns::write_response(&answer.answers, &answer.additional);
+        // Unique records are answered right away
+        let delay = if self.delay && !unicast && answer.shared {
+            response_delay()
+        } else {
+            Duration::ZERO
+        };
+        if !self
+            .responder
+            .send_after(delay, MDNS_PORT, destination, MDNS_HOP_LIMIT, &payload)
+        {
+            self.missed.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+        self.answered.fetch_add(1, Ordering::Relaxed);
+        debug!(
+            "mDNS query answered from the cache with {} record(s) to {}",
+            answer.answers.len() + answer.additional.len(),
+            destination.ip
+        );
+        if !answer.replaces_query() {
+            // Other responders may hold shared records the cache lacks
+            self.missed.fetch_add(1, Ordering::Relaxed);
+        }
+        answer.replaces_query()
+    }
+
+    /// Instances of the services whose PTR records are cached
+    fn devices(&self) -> Vec<Device> {
+        let now = Instant::now();
+        let records = self.records.lock().unwrap();
+        let mut devices: Vec<Device> = records
+            .sets
+            .iter()
+            .filter(|((name, rtype), _)| *rtype == TYPE_PTR && is_service_type(name))
+            .flat_map(|((name, _), set)| {
+                set.iter().filter_map(|cached| {
+                    let instance = cached.record.target()?;
+                    Some(records.device(name, &instance, cached.expires, now))
+                })
+            })
+            .collect();
+        devices.sort_by(|a, b| a.id.cmp(&b.id));
+        devices
+    }
+
+    /// One line per cached record set, followed by the query counters
+    fn state(&self) -> Vec<String> {
+        let now = Instant::now();
+        let records = self.records.lock().unwrap();
+        let mut lines: Vec<String> = records
+            .sets
+            .iter()
+            .map(|((name, rtype), set)| {
+                let expires = set.iter().map(|cached| cached.expires).max();
+                let left = expires.map_or(0, |at| at.saturating_duration_since(now).as_secs());
+                format!(
+                    "{} {}: {} record(s), expiring in {}",
+                    name,
+                    type_name(*rtype),
+                    set.len(),
+                    humantime::format_duration(Duration::from_secs(left))
+                )
+            })
+            .collect();
+        lines.sort();
+        lines.push(format!(
+            "{} quer(ies) answered from the cache, {} forwarded",
+            self.answered.load(Ordering::Relaxed),
+            self.missed.load(Ordering::Relaxed)
+        ));
+        lines
+    }
+}
+
+fn type_name(rtype: u16) -> String {
+    match rtype {
+        TYPE_A => "A".to_string(),
+        TYPE_PTR => "PTR".to_string(),
+        TYPE_TXT => "TXT".to_string(),
+        TYPE_AAAA => "AAAA".to_string(),
+        TYPE_SRV => "SRV".to_string(),
+        _ => format!("TYPE{}", rtype),
+    }
+}
+
+/// Stores the records of mDNS responses forwarded to the internal side in
+/// the cache. Goes after the mDNS rewrite so clamped TTLs are cached.
+pub struct LearnMdnsRecords {
+    cache: Arc<MdnsCache>,
+}
+
+impl LearnMdnsRecords {
+    pub fn new(cache: Arc<MdnsCache>) -> Self {
+        LearnMdnsRecords { cache }
+    }
+}
+
+impl Rewrite for LearnMdnsRecords {
+    fn name(&self) -> &str {
+        "mdns-cache"
+    }
+
+    fn apply(&self, frame: &mut Vec<u8>) -> bool {
+        let Some(datagram) = UdpDatagram::locate(frame) else {
+            return true;
+        };
+        if datagram.ports(frame)[0] != MDNS_PORT {
+            return true;
+        }
+        if let Some(message) = OwnedMessage::parse(&frame[datagram.payload()]) {
+            if message.response {
+                let records = message.answers.into_iter().chain(message.additional);
+                let now = Instant::now();
+                let mut cached = self.cache.records.lock().unwrap();
+                for (service, instance, expires) in cached.store(records.collect(), now) {
+                    let device = cached.device(&service, &instance, expires, now);
+                    debug!("mDNS service instance {} learned", device.id);
+                    #[cfg(feature = "dbus")]
+                    if let Some(discoveries) = self.cache.discoveries.get() {
+                        discoveries.announce(device);
+                    }
+                }
+            }
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mdns::Question;
+    use crate::testutil::dns_name;
+
+    fn labels(name: &str) -> Vec<Vec<u8>> {
+        name.split('.')
+            .map(|label| label.as_bytes().to_vec())
+            .collect()
+    }
+
+    fn record(name: &str, rtype: u16, class: u16, ttl: u32, data: Vec<u8>) -> OwnedRecord {
+        OwnedRecord {
+            name: labels(name),
+            rtype,
+            class,
+            ttl,
+            data,
+        }
+    }
+
+    fn query(name: &str, qtype: u16, known: Vec<OwnedRecord>) -> OwnedMessage {
+        OwnedMessage {
+            response: false,
+            questions: vec![Question {
+                name: labels(name),
+                qtype,
+                class: CLASS_IN,
+            }],
+            answers: known,
+            additional: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn answers_from_stored_records() {
+        let flush = CLASS_IN | CLASS_TOP_BIT;
+        let service = "_googlecast._tcp.local";
+        let instance = "TV._googlecast._tcp.local";
+        let ptr = record(service, TYPE_PTR, CLASS_IN, 120, dns_name(instance));
+        let mut srv_data = vec![0, 0, 0, 0, 0x1f, 0x49];
+        srv_data.extend(dns_name("tv.local"));
+        let srv = record(instance, TYPE_SRV, flush, 120, srv_data);
+        let txt = record(instance, TYPE_TXT, flush, 4500, b"\x06id=abc".to_vec());
+        let address = record("tv.local", TYPE_A, flush, 120, vec![192, 168, 1, 5]);
+
+        let start = Instant::now();
+        let mut records = Records {
+            max_records: 16,
+            sets: HashMap::new(),
+        };
+        let response = vec![ptr.clone(), srv.clone(), txt.clone(), address.clone()];
+        let learned = records.store(response.clone(), start);
+        assert_eq!(learned.len(), 1);
+        let (kind, name, expires) = &learned[0];
+        let device = records.device(kind, name, *expires, start);
+        assert_eq!(device.id, "tv._googlecast._tcp.local");
+        assert_eq!(device.kind, service);
+        assert_eq!(device.location.as_deref(), Some("tv.local:8009"));
+        assert!(records.store(response, start).is_empty());
+
+        let later = start + Duration::from_secs(20);
+        let answer = records
+            .answers(&query(service, TYPE_PTR, Vec::new()), later)
+            .unwrap();
+        // A browse is answered, and still forwarded to find other devices
+        assert!(answer.shared && !answer.replaces_query());
+        let (answers, additional) = (answer.answers, answer.additional);
+        assert_eq!(
+            answers,
+            [OwnedRecord {
+                ttl: 100,
+                ..ptr.clone()
+            }]
+        );
+        let types: Vec<_> = additional.iter().map(|r| (r.rtype, r.ttl)).collect();
+        assert_eq!(types, [(TYPE_SRV, 100), (TYPE_TXT, 4480), (TYPE_A, 100)]);
+
+        // Known answers with at least half the TTL are not repeated
+        let known = vec![OwnedRecord {
+            ttl: 60,
+            ..ptr.clone()
+        }];
+        let answer = records
+            .answers(&query(service, TYPE_PTR, known), later)
+            .unwrap();
+        assert!(answer.answers.is_empty());
+        assert!(!answer.replaces_query());
+        // Unique records replace forwarding, unless all are known
+        let answer = records
+            .answers(&query(instance, TYPE_SRV, Vec::new()), later)
+            .unwrap();
+        assert!(!answer.shared && answer.replaces_query());
+        let known = vec![OwnedRecord {
+            ttl: 100,
+            ..srv.clone()
+        }];
+        let answer = records
+            .answers(&query(instance, TYPE_SRV, known), later)
+            .unwrap();
+        assert!(!answer.replaces_query());
+        assert!(records
+            .answers(&query(instance, TYPE_A, Vec::new()), later)
+            .is_none());
+        assert!(records
+            .answers(&query(service, TYPE_ANY, Vec::new()), later)
+            .is_none());
+
+        // A record with the cache flush bit replaces the older ones of its set
+        let moved = record("tv.local", TYPE_A, flush, 120, vec![192, 168, 1, 6]);
+        records.store(vec![moved.clone()], later);
+        let answer = records
+            .answers(&query("TV.local", TYPE_A, Vec::new()), later)
+            .unwrap();
+        assert_eq!(answer.answers, [moved]);
+
+        // Goodbyes evict, and so does expiry
+        records.store(vec![OwnedRecord { ttl: 0, ..ptr }], later);
+        assert!(records
+            .answers(&query(service, TYPE_PTR, Vec::new()), later)
+            .is_none());
+        let expired = start + Duration::from_secs(120);
+        assert!(records
+            .answers(&query(instance, TYPE_SRV, Vec::new()), expired)
+            .is_none());
+
+        // The record expiring first makes room
+        records.max_records = 2;
+        records.store(vec![srv, address], later);
+        let sets: Vec<_> = records.sets.keys().map(|(_, rtype)| *rtype).collect();
+        assert_eq!(records.sets.values().map(Vec::len).sum::<usize>(), 2);
+        assert!(sets.contains(&TYPE_TXT));
+    }
+}
@@ -0,0 +1,378 @@
+//! Aggregation of the mDNS responses forwarded to the internal side. When
+//! many devices answer one query, their responses arrive back-to-back and
+//! a guest's virtio ring may drop some; merged into a few messages they
+//! arrive whole.
+
+use crate::filter::{PacketContext, MDNS_PORT};
+use crate::loopguard::LoopGuard;
+use crate::mdns::{self, OwnedMessage, OwnedRecord, FLAG_RESPONSE, HEADER_LEN, TYPE_OPT};
+use crate::pcap::PcapSinks;
+use crate::quota::Quotas;
+use crate::random::random_up_to;
+use crate::rewrite::UdpDatagram;
+use crate::sender::{MirrorQueue, Origin, SendQueue};
+use crate::stats::{DropReason, PathStats};
+use crate::vlan::{Tags, VlanPath};
+use pnet::packet::ethernet::EthernetPacket;
+use pnet::packet::ipv4::Ipv4Packet;
+use pnet::packet::ipv6::Ipv6Packet;
+use pnet::util::MacAddr;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::ops::RangeInclusive;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::runtime::Handle;
+use tracing::debug;
+
+/// Delay of a response to a multicast query for shared records (RFC 6762
+/// section 6), which is also the range an aggregation window may have
+pub const RESPONSE_DELAY: RangeInclusive<Duration> =
+    Duration::from_millis(20)..=Duration::from_millis(120);
+/// Longest mDNS message including the IP and UDP headers (RFC 6762
+/// section 17)
+const MAX_PACKET_LEN: usize = 9000;
+const ETHERNET_HEADER_LEN: usize = 14;
+/// Header flags other than QR and AA: opcode, TC, RD, RA, Z, AD, CD and
+/// rcode. Responses with any of them set are passed as they are.
+const OTHER_FLAGS: u16 = 0x7bff;
+
+/// Random delay within [`RESPONSE_DELAY`]
+pub fn response_delay() -> Duration {
+    let (min, max) = (*RESPONSE_DELAY.start(), *RESPONSE_DELAY.end());
+    min + random_up_to(max - min)
+}
+
+/// Whether `payload` is a plain multicast DNS response made of answer and
+/// additional records only, whose records can go into another message
+fn mergeable(payload: &[u8]) -> bool {
+    let Some(header) = payload.get(..HEADER_LEN) else {
+        return false;
+    };
+    let field = |i: usize| u16::from_be_bytes([header[i], header[i + 1]]);
+    field(2) & FLAG_RESPONSE != 0 && field(2) & OTHER_FLAGS == 0 && field(4) == 0 && field(8) == 0
+}
+
+/// Serializes `answers` and `additional` as responses of at most `max_len`
+/// bytes each, split between records where they would not fit into one.
+/// Every record keeps its TTL and cache flush bit, and a record too large
+/// on its own gets a message of its own.
+pub fn write_merged(
+    answers: &[OwnedRecord],
+    additional: &[OwnedRecord],
+    max_len: usize,
+) -> Vec<Vec<u8>> {
+    let records = answers
+        .iter()
+        .map(|record| (0, record))
+        .chain(additional.iter().map(|record| (1, record)));
+    let mut messages = Vec::new();
+    let mut current: [Vec<OwnedRecord>; 2] = Default::default();
+    let mut written = Vec::new();
+    for (section, record) in records {
+        current[section].push(record.clone());
+        let mut message = mdns::write_response(&current[0], &current[1]);
+        if message.len() > max_len && current[0].len() + current[1].len() > 1 {
+            messages.push(written);
+            current = Default::default();
+            current[section].push(record.clone());
+            message = mdns::write_response(&current[0], &current[1]);
+        }
+        written = message;
+    }
+    if current.iter().any(|records| !records.is_empty()) {
+        messages.push(written);
+    }
+    messages
+}
+
+/// Where merged responses go: the rest of the path they were held back on
+pub struct Output {
+    pub tx: SendQueue,
+    pub stats: Arc<PathStats>,
+    pub vlan: VlanPath,
+    pub pcap: PcapSinks,
+    pub mirror: Option<MirrorQueue>,
+    /// Charged with the responses sent, not with the ones held back
+    pub quotas: Arc<Quotas>,
+    pub loop_guard: Option<Arc<LoopGuard>>,
+    /// MTU of the egress interface, which merged responses fit into
+    pub mtu: usize,
+}
+
+impl Output {
+    fn send(&self, mut frame: Vec<u8>, tags: &Tags, received: Instant) {
+        let untagged = frame.clone();
+        self.vlan.retag(&mut frame, tags);
+        let copy = self
+            .pcap
+            .forwarded
+            .as_ref()
+            .map(|sink| (sink, frame.clone()));
+        let mirrored = self.mirror.as_ref().map(|mirror| (mirror, frame.clone()));
+        let origin = Origin {
+            stats: self.stats.clone(),
+            received: Some(received),
+            trace: None,
+        };
+        if !self.tx.enqueue(frame.into(), origin) {
+            self.stats.dropped(DropReason::QueueFull);
+            return;
+        }
+        self.sent(&untagged);
+        if let Some((sink, frame)) = copy {
+            sink.write(SystemTime::now(), frame);
+        }
+        if let Some((mirror, frame)) = mirrored {
+            mirror.forwarded(frame);
+        }
+    }
+
+    /// Charges the quotas with a response queued for sending and lets the
+    /// loop guard know it went out
+    fn sent(&self, frame: &[u8]) {
+        let stats = &self.stats;
+        let Some(ctx) = PacketContext::parse(&stats.ingress, stats.direction, frame) else {
+            return;
+        };
+        self.quotas.charge(&ctx, frame.len());
+        if let Some(guard) = &self.loop_guard {
+            guard.sent(&ctx, &stats.egress);
+        }
+    }
+}
+
+/// Responses held back for one destination
+struct Pending {
+    /// First response, whose headers the merged ones are sent with
+    template: Vec<u8>,
+    tags: Tags,
+    received: Instant,
+    responses: u32,
+    answers: Vec<OwnedRecord>,
+    additional: Vec<OwnedRecord>,
+}
+
+/// Holds back mDNS responses for `window` after the first one to the same
+/// destination, which answer the same query, and then sends their records
+/// merged into as few responses as fit the egress MTU and the mDNS limit of
+/// 9000 bytes. Merged responses carry the addresses of the first one.
+/// Duplicate records are sent once, and records specific to one message,
+/// such as EDNS options, are left out.
+pub struct MdnsAggregator {
+    window: Duration,
+    output: Output,
+    pending: Mutex<HashMap<(MacAddr, IpAddr), Pending>>,
+    runtime: Handle,
+}
+
+impl MdnsAggregator {
+    /// Must be called within the runtime the flushes are to run on
+    pub fn new(window: Duration, output: Output) -> Self {
+        MdnsAggregator {
+            window,
+            output,
+            pending: Mutex::new(HashMap::new()),
+            runtime: Handle::current(),
+        }
+    }
+
+    /// Takes `frame`, rewritten but not yet tagged, if it is a response to
+    /// hold back. Returns `false` for frames to forward as they are.
+    pub fn hold(self: &Arc<Self>, frame: &[u8], tags: &Tags, at: Instant) -> bool {
+        let Some(datagram) = UdpDatagram::locate(frame) else {
+            return false;
+        };
+        // Legacy unicast responses echo the ID and question of their query
+        let payload = &frame[datagram.payload()];
+        if datagram.ports(frame) != [MDNS_PORT, MDNS_PORT] || !mergeable(payload) {
+            return false;
+        }
+        let (Some(key), Some(message)) = (destination(frame), OwnedMessage::parse(payload)) else {
+            return false;
+        };
+        let mut pending = self.pending.lock().unwrap();
+        let held = pending.entry(key).or_insert_with(|| {
+            let aggregator = self.clone();
+            self.runtime.spawn(async move {
+                tokio::time::sleep(aggregator.window).await;
+                aggregator.flush(key);
+            });
+            Pending {
+                template: frame.to_vec(),
+                tags: *tags,
+                received: at,
+                responses: 0,
+                answers: Vec::new(),
+                additional: Vec::new(),
+            }
+        });
+        held.responses += 1;
+        for record in message.answers {
+            if !held.answers.contains(&record) {
+                held.additional.retain(|additional| *additional != record);
+                held.answers.push(record);
+            }
+        }
+        for record in message.additional {
+            if record.rtype != TYPE_OPT
+                && !held.answers.contains(&record)
+                && !held.additional.contains(&record)
+            {
+                held.additional.push(record);
+            }
+        }
+        true
+    }
+
+    fn flush(&self, key: (MacAddr, IpAddr)) {
+        let Some(held) = self.pending.lock().unwrap().remove(&key) else {
+            return;
+        };
+        let output = &self.output;
+        if held.responses == 1 {
+            output.send(held.template, &held.tags, held.received);
+            return;
+        }
+        let Some(datagram) = UdpDatagram::locate(&held.template) else {
+            return;
+        };
+        let headers_len = datagram.payload().start - ETHERNET_HEADER_LEN;
+        let max_len = MAX_PACKET_LEN.min(output.mtu) - headers_len;
+        let messages = write_merged(&held.answers, &held.additional, max_len);
+        debug!(
+            "{} mDNS responses to {} merged into {}",
+            held.responses,
+            key.1,
+            messages.len()
+        );
+        output.stats.merged(held.responses);
+        for message in messages {
+            let mut frame = held.template.clone();
+            if datagram.replace_payload(&mut frame, &message) {
+                output.send(frame, &held.tags, held.received);
+            }
+        }
+    }
+}
+
+/// Destination MAC and IP address of a UDP frame
+fn destination(frame: &[u8]) -> Option<(MacAddr, IpAddr)> {
+    let mac = EthernetPacket::new(frame)?.get_destination();
+    let l3 = &frame[ETHERNET_HEADER_LEN..];
+    let ip = match l3.first()? >> 4 {
+        4 => IpAddr::V4(Ipv4Packet::new(l3)?.get_destination()),
+        6 => IpAddr::V6(Ipv6Packet::new(l3)?.get_destination()),
+        _ => return None,
+    };
+    Some((mac, ip))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mdns::{CLASS_IN, CLASS_TOP_BIT, TYPE_A, TYPE_PTR, TYPE_SRV, TYPE_TXT};
+
+    /// Responses in the shape an Avahi printer and a Chromecast announce
+    /// themselves with: a shared PTR answer and unique SRV, TXT and A
+    /// records with the cache flush bit, names compressed
+    const PRINTER: &str = concat!(
+        "000084000000000100000003045f697070045f746370056c6f63616c00000c00",
+        "010000119400110e4f6666696365205072696e746572c00cc027002180010000",
+        "00780010000000000277077072696e746572c016c0270010800100001194001f",
+        "09747874766572733d310871746f74616c3d310b72703d7072696e74657273c0",
+        "4a00018001000000780004c0a80114",
+    );
+    const CHROMECAST: &str = concat!(
+        "0000840000000001000000030b5f676f6f676c6563617374045f746370056c6f",
+        "63616c00000c00010000007800120f4368726f6d65636173742d34663261c00c",
+        "c02e001080010000119400332369643d34663261396330653162376434653366",
+        "386136623563346433653266316130620e666e3d4c6976696e6720526f6f6dc0",
+        "2e00218001000000780011000000001f49083466326139633065c01dc0910001",
+        "8001000000780004c0a8011f",
+    );
+
+    fn unhex(hex: &str) -> Vec<u8> {
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    /// Type, TTL and class of every record of a message, answers first
+    fn records(message: &OwnedMessage) -> Vec<(String, u16, u32, u16)> {
+        message
+            .answers
+            .iter()
+            .chain(&message.additional)
+            .map(|r| (mdns::dotted_name(&r.name), r.rtype, r.ttl, r.class))
+            .collect()
+    }
+
+    #[test]
+    fn merges_responses_keeping_every_record() {
+        let printer = OwnedMessage::parse(&unhex(PRINTER)).unwrap();
+        let chromecast = OwnedMessage::parse(&unhex(CHROMECAST)).unwrap();
+        assert!(mergeable(&unhex(PRINTER)) && mergeable(&unhex(CHROMECAST)));
+        let mut query = unhex(PRINTER);
+        query[2] = 0;
+        assert!(!mergeable(&query));
+
+        let answers: Vec<OwnedRecord> = [&printer, &chromecast]
+            .iter()
+            .flat_map(|message| message.answers.clone())
+            .collect();
+        let additional: Vec<OwnedRecord> = [&printer, &chromecast]
+            .iter()
+            .flat_map(|message| message.additional.clone())
+            .collect();
+        let merged = write_merged(&answers, &additional, 9000);
+        assert_eq!(merged.len(), 1);
+        // Names shared by both, such as _tcp.local, are compressed once
+        assert!(merged[0].len() < unhex(PRINTER).len() + unhex(CHROMECAST).len());
+        assert!(mergeable(&merged[0]));
+        let message = OwnedMessage::parse(&merged[0]).unwrap();
+        assert!(message.response);
+        assert_eq!(message.answers, answers);
+        assert_eq!(message.additional, additional);
+
+        let flush = CLASS_IN | CLASS_TOP_BIT;
+        let instance = "office printer._ipp._tcp.local".to_string();
+        assert_eq!(
+            records(&message)[..5],
+            [
+                ("_ipp._tcp.local".to_string(), TYPE_PTR, 4500, CLASS_IN),
+                (
+                    "_googlecast._tcp.local".to_string(),
+                    TYPE_PTR,
+                    120,
+                    CLASS_IN
+                ),
+                (instance.clone(), TYPE_SRV, 120, flush),
+                (instance, TYPE_TXT, 4500, flush),
+                ("printer.local".to_string(), TYPE_A, 120, flush),
+            ]
+        );
+
+        // Split so every message fits, in order and without losing records
+        let limit = 200;
+        let split = write_merged(&answers, &additional, limit);
+        assert!(split.len() > 1);
+        let mut parts = Vec::new();
+        for message in &split {
+            assert!(message.len() <= limit, "{} bytes", message.len());
+            parts.extend(records(&OwnedMessage::parse(message).unwrap()));
+        }
+        let mut all = records(&message);
+        all.sort();
+        parts.sort();
+        assert_eq!(parts, all);
+    }
+
+    #[test]
+    fn delays_within_the_range() {
+        for _ in 0..100 {
+            assert!(RESPONSE_DELAY.contains(&response_delay()));
+        }
+    }
+}
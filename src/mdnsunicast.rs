@@ -0,0 +1,328 @@
+//! Unicast mDNS responses (RFC 6762 sections 5.4 and 6.7): queries from the
+//! internal side asking for them are tracked with the address of their
+//! sender, so that only the responses to such a query are let back in and
+//! reach the querier.
+
+use crate::checksum;
+use crate::filter::{Decision, Filter, PacketContext, MDNS_PORT};
+use crate::mdns::{OwnedMessage, CLASS_TOP_BIT};
+use crate::nat::Translation;
+use crate::rewrite::{Rewrite, UdpDatagram};
+use crate::ssdp::is_broadcast;
+use pnet::packet::ethernet::MutableEthernetPacket;
+use pnet::packet::ip::IpNextHeaderProtocols;
+use pnet::packet::ipv4::MutableIpv4Packet;
+use pnet::packet::Packet;
+use pnet::util::MacAddr;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::debug;
+
+const ETHERNET_HEADER_LEN: usize = 14;
+
+/// Sender of a query, as seen before source NAT
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Querier {
+    mac: MacAddr,
+    ip: IpAddr,
+    port: u16,
+    sent: Instant,
+}
+
+/// Queries forwarded from the internal side that may be answered by
+/// unicast, by the address and port the responses are sent to
+pub struct MdnsQueries {
+    window: Duration,
+    max_entries: usize,
+    /// Source NAT changing the address and port queries come from
+    snat: Option<Translation>,
+    queries: Mutex<HashMap<(IpAddr, u16), Querier>>,
+    /// Unicast responses let in and readdressed to their querier
+    routed: AtomicU64,
+}
+
+impl MdnsQueries {
+    pub fn new(window: Duration, max_entries: usize, snat: Option<Translation>) -> Self {
+        MdnsQueries {
+            window,
+            max_entries,
+            snat,
+            queries: Mutex::new(HashMap::new()),
+            routed: AtomicU64::new(0),
+        }
+    }
+
+    fn record(&self, port: u16, mac: MacAddr, ip: IpAddr, now: Instant) {
+        let key = match &self.snat {
+            Some(snat) => snat.source(IpNextHeaderProtocols::Udp, (ip, port)),
+            None => (ip, port),
+        };
+        let mut queries = self.queries.lock().unwrap();
+        queries.retain(|_, querier| now.duration_since(querier.sent) < self.window);
+        if queries.len() >= self.max_entries && !queries.contains_key(&key) {
+            // Evict the least recently sent query to keep the table bounded
+            if let Some(oldest) = queries
+                .iter()
+                .min_by_key(|(_, querier)| querier.sent)
+                .map(|(key, _)| *key)
+            {
+                queries.remove(&oldest);
+            }
+        }
+        queries.insert(
+            key,
+            Querier {
+                mac,
+                ip,
+                port,
+                sent: now,
+            },
+        );
+    }
+
+    /// Sender of the query a unicast response to `destination` answers
+    fn lookup(&self, destination: (IpAddr, u16), now: Instant) -> Option<Querier> {
+        let queries = self.queries.lock().unwrap();
+        queries
+            .get(&destination)
+            .filter(|querier| now.duration_since(querier.sent) < self.window)
+            .copied()
+    }
+
+    /// Outstanding queries, one line each, followed by the responses routed
+    pub fn state(&self) -> Vec<String> {
+        let now = Instant::now();
+        let queries = self.queries.lock().unwrap();
+        let mut lines: Vec<String> = queries
+            .iter()
+            .filter(|(_, querier)| now.duration_since(querier.sent) < self.window)
+            .map(|((ip, port), querier)| {
+                let age = now.duration_since(querier.sent);
+                format!(
+                    "{}:{} for {} at {}, queried {} ago",
+                    ip,
+                    port,
+                    querier.ip,
+                    querier.mac,
+                    humantime::format_duration(Duration::from_millis(age.as_millis() as u64))
+                )
+            })
+            .collect();
+        lines.sort();
+        lines.push(format!(
+            "{} unicast response(s) routed to their querier",
+            self.routed.load(Ordering::Relaxed)
+        ));
+        lines
+    }
+}
+
+/// Whether responses to a query from `source_port` may come by unicast:
+/// legacy queries from other ports always get them, others when a question
+/// has the unicast-response (QU) bit
+fn wants_unicast(query: &OwnedMessage, source_port: u16) -> bool {
+    !query.response
+        && (source_port != MDNS_PORT
+            || query
+                .questions
+                .iter()
+                .any(|question| question.class & CLASS_TOP_BIT != 0))
+}
+
+/// Records the queries from the internal side asking for unicast responses
+/// and drops unicast mDNS responses from the other side that answer none.
+/// Multicast traffic is not affected and is left to the other filters.
+pub struct MdnsResponseTracker {
+    internal_iface: String,
+    queries: Arc<MdnsQueries>,
+}
+
+impl MdnsResponseTracker {
+    pub fn new(internal_iface: String, queries: Arc<MdnsQueries>) -> Self {
+        MdnsResponseTracker {
+            internal_iface,
+            queries,
+        }
+    }
+}
+
+impl Filter for MdnsResponseTracker {
+    fn name(&self) -> &str {
+        "mdns-tracking"
+    }
+
+    fn evaluate(&self, ctx: &PacketContext) -> Decision {
+        let Some(udp) = ctx.udp() else {
+            return Decision::Continue;
+        };
+        let (Some(src_ip), Some(dst_ip)) = (ctx.source_ip(), ctx.destination_ip()) else {
+            return Decision::Continue;
+        };
+
+        if ctx.ingress == self.internal_iface {
+            if udp.get_destination() == MDNS_PORT {
+                let query = OwnedMessage::parse(udp.payload());
+                if query.is_some_and(|query| wants_unicast(&query, udp.get_source())) {
+                    let mac = ctx.ethernet.get_source();
+                    self.queries
+                        .record(udp.get_source(), mac, src_ip, Instant::now());
+                }
+            }
+            return Decision::Continue;
+        }
+
+        if udp.get_source() != MDNS_PORT || dst_ip.is_multicast() || is_broadcast(dst_ip) {
+            return Decision::Continue;
+        }
+        let destination = (dst_ip, udp.get_destination());
+        if self.queries.lookup(destination, Instant::now()).is_some() {
+            Decision::Continue
+        } else {
+            debug!(
+                "Unsolicited mDNS unicast to {}:{} dropped",
+                dst_ip,
+                udp.get_destination()
+            );
+            Decision::Drop
+        }
+    }
+
+    fn state(&self) -> Option<Vec<String>> {
+        Some(self.queries.state())
+    }
+}
+
+/// Addresses unicast mDNS responses sent inwards to the querier they
+/// answer: its MAC and, if source NAT changed the address and port the
+/// query came from, its IP and port. Goes ahead of the reverse NAT, which
+/// then leaves the response alone.
+pub struct RouteUnicastResponses {
+    queries: Arc<MdnsQueries>,
+}
+
+impl RouteUnicastResponses {
+    pub fn new(queries: Arc<MdnsQueries>) -> Self {
+        RouteUnicastResponses { queries }
+    }
+}
+
+impl Rewrite for RouteUnicastResponses {
+    fn name(&self) -> &str {
+        "mdns-unicast"
+    }
+
+    fn apply(&self, frame: &mut Vec<u8>) -> bool {
+        let Some(datagram) = UdpDatagram::locate(frame) else {
+            return true;
+        };
+        let [source_port, port] = datagram.ports(frame);
+        if source_port != MDNS_PORT || datagram.is_ipv6() {
+            // IPv6 is not translated, so the querier's address is already
+            // the destination; its MAC comes from neighbor discovery
+            return true;
+        }
+        let Some(ip) = MutableIpv4Packet::new(&mut frame[ETHERNET_HEADER_LEN..]) else {
+            return true;
+        };
+        let destination = IpAddr::V4(ip.get_destination());
+        let Some(querier) = self.queries.lookup((destination, port), Instant::now()) else {
+            return true;
+        };
+        let IpAddr::V4(querier_ip) = querier.ip else {
+            return true;
+        };
+        if let Some(mut eth) = MutableEthernetPacket::new(frame) {
+            eth.set_destination(querier.mac);
+        }
+        self.queries.routed.fetch_add(1, Ordering::Relaxed);
+        debug!(
+            "mDNS unicast response to {}:{} routed to {} at {}",
+            destination, port, querier_ip, querier.mac
+        );
+        if (destination, port) == (querier.ip, querier.port) {
+            return true;
+        }
+        if let Some(mut ip) = MutableIpv4Packet::new(&mut frame[ETHERNET_HEADER_LEN..]) {
+            ip.set_destination(querier_ip);
+        }
+        datagram.set_ports(frame, [source_port, querier.port]);
+        checksum::update_ipv4(frame)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mdns::{Question, CLASS_IN, TYPE_PTR};
+    use std::net::Ipv4Addr;
+
+    fn query(class: u16) -> OwnedMessage {
+        OwnedMessage {
+            response: false,
+            questions: vec![Question {
+                name: vec![b"_googlecast".to_vec(), b"_tcp".to_vec(), b"local".to_vec()],
+                qtype: TYPE_PTR,
+                class,
+            }],
+            answers: Vec::new(),
+            additional: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn routes_responses_to_the_querier_behind_nat() {
+        assert!(!wants_unicast(&query(CLASS_IN), MDNS_PORT));
+        assert!(wants_unicast(&query(CLASS_IN | CLASS_TOP_BIT), MDNS_PORT));
+        assert!(wants_unicast(&query(CLASS_IN), 40000));
+
+        let external = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 2));
+        let querier = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 5));
+        let mac = MacAddr(2, 0, 0, 0, 0, 5);
+        let snat = Translation::new(Ipv4Addr::new(192, 168, 1, 2));
+        let queries = Arc::new(MdnsQueries::new(Duration::from_secs(5), 2, Some(snat)));
+        let start = Instant::now();
+        queries.record(MDNS_PORT, mac, querier, start);
+        let found = queries.lookup((external, MDNS_PORT), start).unwrap();
+        assert_eq!((found.mac, found.ip), (mac, querier));
+        assert!(queries.lookup((querier, MDNS_PORT), start).is_none());
+        let late = start + Duration::from_secs(5);
+        assert!(queries.lookup((external, MDNS_PORT), late).is_none());
+
+        // A response to the translated address goes to the querier
+        let mut frame = vec![0; ETHERNET_HEADER_LEN + 20 + 8];
+        frame[12..14].copy_from_slice(&[0x08, 0x00]);
+        let ip = &mut frame[ETHERNET_HEADER_LEN..];
+        ip[..4].copy_from_slice(&[0x45, 0, 0, 28]);
+        ip[8..10].copy_from_slice(&[255, 17]);
+        ip[12..16].copy_from_slice(&[192, 168, 1, 9]);
+        ip[16..20].copy_from_slice(&[192, 168, 1, 2]);
+        ip[20..24].copy_from_slice(&[0x14, 0xe9, 0x14, 0xe9]);
+        ip[24..26].copy_from_slice(&[0, 8]);
+        assert!(RouteUnicastResponses::new(queries.clone()).apply(&mut frame));
+        assert_eq!(&frame[..6], &[2, 0, 0, 0, 0, 5]);
+        assert_eq!(&frame[30..34], &[10, 0, 0, 5]);
+        assert_eq!(queries.routed.load(Ordering::Relaxed), 1);
+
+        // Another querier on port 5353 gets a port of its own, which its
+        // responses are sent to and which is translated back
+        let other = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 6));
+        queries.record(MDNS_PORT, MacAddr(2, 0, 0, 0, 0, 6), other, start);
+        let (&(_, port), _) = queries
+            .queries
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|(_, querier)| querier.ip == other)
+            .unwrap();
+        assert_ne!(port, MDNS_PORT);
+        frame[30..34].copy_from_slice(&[192, 168, 1, 2]);
+        frame[36..38].copy_from_slice(&port.to_be_bytes());
+        assert!(RouteUnicastResponses::new(queries.clone()).apply(&mut frame));
+        assert_eq!(&frame[..6], &[2, 0, 0, 0, 0, 6]);
+        assert_eq!(&frame[30..34], &[10, 0, 0, 6]);
+        assert_eq!(&frame[36..38], &MDNS_PORT.to_be_bytes());
+    }
+}
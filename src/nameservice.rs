@@ -0,0 +1,361 @@
+//! Windows name resolution: LLMNR, multicast to 224.0.0.252 and ff02::1:3,
+//! and the NetBIOS name service, broadcast on the local subnet. Both are
+//! blocked unless allowed for a direction, with LLMNR optionally limited to
+//! queries for some names.
+
+use crate::checksum;
+use crate::filter::{Decision, Filter, PacketContext, LLMNR_PORT};
+use crate::logging::{debug_repeated, RepeatedMessages};
+use crate::mdns::MdnsMessage;
+use crate::pair::Direction;
+use crate::rewrite::{Rewrite, UdpDatagram};
+use clap::ValueEnum;
+use pnet::datalink::NetworkInterface;
+use pnet::ipnetwork::IpNetwork;
+use pnet::packet::ipv4::MutableIpv4Packet;
+use pnet::packet::Packet;
+use serde::{Deserialize, Serialize};
+use std::net::Ipv4Addr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tracing::debug;
+
+pub const NETBIOS_NS_PORT: u16 = 137;
+
+const ETHERNET_HEADER_LEN: usize = 14;
+
+/// Directions a name service crosses in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NameServiceMode {
+    /// Drop it in both directions
+    Block,
+    /// Forward it from the internal to the external side only
+    Outbound,
+    /// Forward it from the external to the internal side only
+    Inbound,
+    /// Forward it both ways
+    Both,
+}
+
+impl NameServiceMode {
+    /// Whether frames of the service cross in `direction`. Without sides,
+    /// as in bridge mode, only `Both` lets them through.
+    pub fn allows(self, direction: Direction) -> bool {
+        match direction {
+            Direction::Inbound => matches!(self, NameServiceMode::Inbound | NameServiceMode::Both),
+            Direction::Outbound => {
+                matches!(self, NameServiceMode::Outbound | NameServiceMode::Both)
+            }
+            Direction::Bridged => self == NameServiceMode::Both,
+        }
+    }
+}
+
+/// Name service a [`NameServiceFilter`] handles
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NameService {
+    Llmnr,
+    NetbiosNs,
+}
+
+impl NameService {
+    pub fn port(self) -> u16 {
+        match self {
+            NameService::Llmnr => LLMNR_PORT,
+            NameService::NetbiosNs => NETBIOS_NS_PORT,
+        }
+    }
+
+    /// Name of its filter, which its drops are counted under
+    pub fn name(self) -> &'static str {
+        match self {
+            NameService::Llmnr => "llmnr",
+            NameService::NetbiosNs => "netbios-ns",
+        }
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            NameService::Llmnr => "LLMNR",
+            NameService::NetbiosNs => "NetBIOS name service",
+        }
+    }
+
+    /// Kind of the repeated messages about its drops
+    fn drops(self) -> &'static str {
+        match self {
+            NameService::Llmnr => "LLMNR messages dropped",
+            NameService::NetbiosNs => "NetBIOS name service messages dropped",
+        }
+    }
+}
+
+/// Drops the traffic of a name service in the directions it is not allowed
+/// in. LLMNR messages can further be limited to the ones asking for one of
+/// `names`; responses repeat the question, so they are checked the same way.
+pub struct NameServiceFilter {
+    service: NameService,
+    mode: NameServiceMode,
+    names: Vec<String>,
+    blocked: AtomicU64,
+    malformed: AtomicU64,
+    other_name: AtomicU64,
+    log: RepeatedMessages,
+}
+
+impl NameServiceFilter {
+    pub fn new(service: NameService, mode: NameServiceMode, names: &[String]) -> Self {
+        let names = match service {
+            NameService::Llmnr => names
+                .iter()
+                .map(|name| name.trim_end_matches('.').to_ascii_lowercase())
+                .collect(),
+            NameService::NetbiosNs => Vec::new(),
+        };
+        NameServiceFilter {
+            service,
+            mode,
+            names,
+            blocked: AtomicU64::new(0),
+            malformed: AtomicU64::new(0),
+            other_name: AtomicU64::new(0),
+            log: RepeatedMessages::default(),
+        }
+    }
+
+    fn dropped(&self, counter: &AtomicU64, why: &str) -> Decision {
+        counter.fetch_add(1, Ordering::Relaxed);
+        debug_repeated!(
+            self.log,
+            self.service.drops(),
+            "{} message dropped: {}",
+            self.service.label(),
+            why
+        );
+        Decision::Drop
+    }
+}
+
+impl Filter for NameServiceFilter {
+    fn name(&self) -> &str {
+        self.service.name()
+    }
+
+    fn evaluate(&self, ctx: &PacketContext) -> Decision {
+        let Some(udp) = ctx.udp() else {
+            return Decision::Continue;
+        };
+        let port = self.service.port();
+        if udp.get_source() != port && udp.get_destination() != port {
+            return Decision::Continue;
+        }
+        if !self.mode.allows(ctx.direction) {
+            return self.dropped(&self.blocked, ctx.direction.name());
+        }
+        if self.names.is_empty() {
+            return Decision::Continue;
+        }
+        let Some(message) = MdnsMessage::parse(udp.payload()) else {
+            return self.dropped(&self.malformed, "malformed");
+        };
+        if message
+            .questions
+            .iter()
+            .any(|name| self.names.contains(name))
+        {
+            Decision::Continue
+        } else {
+            self.dropped(&self.other_name, "other name")
+        }
+    }
+
+    fn state(&self) -> Option<Vec<String>> {
+        Some(vec![format!(
+            "dropped blocked={} malformed={} other-name={}",
+            self.blocked.load(Ordering::Relaxed),
+            self.malformed.load(Ordering::Relaxed),
+            self.other_name.load(Ordering::Relaxed)
+        )])
+    }
+}
+
+/// Subnet broadcast addresses of the IPv4 networks of `iface`
+pub fn subnet_broadcasts(iface: &NetworkInterface) -> Vec<Ipv4Addr> {
+    iface
+        .ips
+        .iter()
+        .filter_map(|network| match network {
+            IpNetwork::V4(network) if network.prefix() < 31 => Some(network.broadcast()),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Readdresses NetBIOS name service broadcasts to the subnet of the egress
+/// interface. Hosts send them to the broadcast address of their own subnet,
+/// which means nothing on the other side; limited broadcasts to
+/// 255.255.255.255 are left alone.
+pub struct NetbiosBroadcast {
+    ingress: Vec<Ipv4Addr>,
+    egress: Ipv4Addr,
+}
+
+impl NetbiosBroadcast {
+    /// Sends the broadcasts of the subnets of `ingress` to the first subnet
+    /// of `egress`, or to 255.255.255.255 if it has none
+    pub fn new(ingress: &NetworkInterface, egress: &NetworkInterface) -> Self {
+        NetbiosBroadcast {
+            ingress: subnet_broadcasts(ingress),
+            egress: subnet_broadcasts(egress)
+                .first()
+                .copied()
+                .unwrap_or(Ipv4Addr::BROADCAST),
+        }
+    }
+}
+
+impl Rewrite for NetbiosBroadcast {
+    fn name(&self) -> &str {
+        "netbios-broadcast"
+    }
+
+    fn apply(&self, frame: &mut Vec<u8>) -> bool {
+        let Some(datagram) = UdpDatagram::locate(frame) else {
+            return true;
+        };
+        if datagram.is_ipv6() || datagram.ports(frame)[1] != NETBIOS_NS_PORT {
+            return true;
+        }
+        let Some(mut ip) = MutableIpv4Packet::new(&mut frame[ETHERNET_HEADER_LEN..]) else {
+            return true;
+        };
+        let destination = ip.get_destination();
+        if !self.ingress.contains(&destination) || destination == self.egress {
+            return true;
+        }
+        ip.set_destination(self.egress);
+        debug!(
+            "NetBIOS broadcast to {} sent to {}",
+            destination, self.egress
+        );
+        checksum::update_ipv4(frame)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::checksum::Verdict;
+    use crate::testutil::{dns_header, dns_name, multicast_mac, udp_frame, HOST_IP, HOST_MAC};
+    use pnet::packet::ipv4::Ipv4Packet;
+    use pnet::util::MacAddr;
+    use std::net::IpAddr;
+
+    const LLMNR_GROUP: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 252);
+
+    fn llmnr_query(name: &str) -> Vec<u8> {
+        let mut query = dns_header(0, 1, 0);
+        query.extend(dns_name(name));
+        query.extend_from_slice(&[0, 1, 0, 1]);
+        udp_frame(
+            HOST_MAC,
+            multicast_mac(IpAddr::V4(LLMNR_GROUP)),
+            HOST_IP,
+            LLMNR_GROUP,
+            49152,
+            LLMNR_PORT,
+            &query,
+        )
+    }
+
+    fn evaluate(filter: &NameServiceFilter, direction: Direction, frame: &[u8]) -> Decision {
+        filter.evaluate(&PacketContext::parse("eth0", direction, frame).unwrap())
+    }
+
+    #[test]
+    fn blocks_by_direction_and_name() {
+        let names = ["Printer".to_string()];
+        let llmnr = NameServiceFilter::new(NameService::Llmnr, NameServiceMode::Outbound, &names);
+        let printer = llmnr_query("printer");
+        assert_eq!(
+            evaluate(&llmnr, Direction::Outbound, &printer),
+            Decision::Continue
+        );
+        assert_eq!(
+            evaluate(&llmnr, Direction::Inbound, &printer),
+            Decision::Drop
+        );
+        assert_eq!(
+            evaluate(&llmnr, Direction::Outbound, &llmnr_query("fileserver")),
+            Decision::Drop
+        );
+        let mut truncated = printer.clone();
+        truncated.truncate(printer.len() - 2);
+        assert_eq!(
+            evaluate(&llmnr, Direction::Outbound, &truncated),
+            Decision::Drop
+        );
+        assert_eq!(
+            llmnr.state().unwrap(),
+            ["dropped blocked=1 malformed=1 other-name=1"]
+        );
+
+        let netbios = NameServiceFilter::new(NameService::NetbiosNs, NameServiceMode::Block, &[]);
+        assert_eq!(
+            evaluate(&netbios, Direction::Outbound, &printer),
+            Decision::Continue
+        );
+        let broadcast = udp_frame(
+            HOST_MAC,
+            MacAddr::broadcast(),
+            HOST_IP,
+            Ipv4Addr::new(192, 168, 100, 255),
+            NETBIOS_NS_PORT,
+            NETBIOS_NS_PORT,
+            b"name query",
+        );
+        for direction in [Direction::Inbound, Direction::Outbound, Direction::Bridged] {
+            assert_eq!(evaluate(&netbios, direction, &broadcast), Decision::Drop);
+        }
+        assert!(NameServiceMode::Both.allows(Direction::Bridged));
+        assert!(!NameServiceMode::Inbound.allows(Direction::Bridged));
+    }
+
+    #[test]
+    fn readdresses_subnet_broadcasts() {
+        let rewrite = NetbiosBroadcast {
+            ingress: vec![Ipv4Addr::new(192, 168, 100, 255)],
+            egress: Ipv4Addr::new(10, 0, 0, 255),
+        };
+        let frame = |destination: Ipv4Addr, port: u16| {
+            udp_frame(
+                HOST_MAC,
+                MacAddr::broadcast(),
+                HOST_IP,
+                destination,
+                NETBIOS_NS_PORT,
+                port,
+                b"name query",
+            )
+        };
+        let destination = |frame: &[u8]| {
+            Ipv4Packet::new(&frame[ETHERNET_HEADER_LEN..])
+                .unwrap()
+                .get_destination()
+        };
+
+        let mut subnet = frame(Ipv4Addr::new(192, 168, 100, 255), NETBIOS_NS_PORT);
+        assert!(rewrite.apply(&mut subnet));
+        assert_eq!(destination(&subnet), Ipv4Addr::new(10, 0, 0, 255));
+        assert_eq!(checksum::verify(&subnet), Verdict::Valid);
+
+        for (to, port) in [
+            (Ipv4Addr::BROADCAST, NETBIOS_NS_PORT),
+            (Ipv4Addr::new(192, 168, 100, 255), 138),
+        ] {
+            let mut other = frame(to, port);
+            assert!(rewrite.apply(&mut other));
+            assert_eq!(destination(&other), to);
+        }
+    }
+}
@@ -0,0 +1,471 @@
+//! IPv4 source NAT for frames leaving through the external interface.
+//!
+//! Every flow, by (protocol, internal ip, internal port), gets an external
+//! port of its own: the internal port while no other host uses it, so the
+//! well-known mDNS and SSDP source ports are kept where they can be, and
+//! otherwise one from the dynamic range. Replies are mapped back by the
+//! external port to the host and port that sent the flow.
+
+use crate::checksum;
+use crate::rewrite::Rewrite;
+use pnet::packet::ethernet::{EtherTypes, EthernetPacket};
+use pnet::packet::ip::{IpNextHeaderProtocol, IpNextHeaderProtocols};
+use pnet::packet::ipv4::MutableIpv4Packet;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr};
+use std::ops::RangeInclusive;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::debug;
+
+const ETHERNET_HEADER_LEN: usize = 14;
+const ENTRY_TIMEOUT: Duration = Duration::from_secs(120);
+const MAX_ENTRIES: usize = 4096;
+/// External ports given to flows whose internal port is taken
+const DYNAMIC_PORTS: RangeInclusive<u16> = 49152..=65535;
+
+/// Internal endpoint of a translated flow
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct NatKey {
+    pub protocol: u8,
+    pub internal_ip: Ipv4Addr,
+    pub internal_port: u16,
+}
+
+/// External port of a flow and when it was last used
+#[derive(Debug, Clone, Copy)]
+struct Mapping {
+    external_port: u16,
+    seen: Instant,
+}
+
+/// Translation table shared by the outbound and inbound NAT stages
+#[derive(Debug)]
+pub struct NatTable {
+    entries: HashMap<NatKey, Mapping>,
+    by_port: HashMap<(u8, u16), NatKey>,
+    /// Where the search for a free dynamic port starts
+    next_port: u16,
+}
+
+impl Default for NatTable {
+    fn default() -> Self {
+        NatTable {
+            entries: HashMap::new(),
+            by_port: HashMap::new(),
+            next_port: *DYNAMIC_PORTS.start(),
+        }
+    }
+}
+
+impl NatTable {
+    /// External port of the flow `key`, allocating one for a new flow.
+    /// A full table makes room by expiring idle flows, or else by evicting
+    /// the least recently used one.
+    fn record(&mut self, key: NatKey, now: Instant) -> u16 {
+        if let Some(mapping) = self.entries.get_mut(&key) {
+            mapping.seen = now;
+            return mapping.external_port;
+        }
+        if self.entries.len() >= MAX_ENTRIES {
+            self.expire(now);
+        }
+        if self.entries.len() >= MAX_ENTRIES {
+            if let Some(oldest) = self
+                .entries
+                .iter()
+                .min_by_key(|(_, mapping)| mapping.seen)
+                .map(|(key, _)| *key)
+            {
+                self.remove(&oldest);
+            }
+        }
+        let external_port = if self.is_free(key.protocol, key.internal_port, now) {
+            key.internal_port
+        } else {
+            self.free_port(key.protocol, now)
+        };
+        self.entries.insert(
+            key,
+            Mapping {
+                external_port,
+                seen: now,
+            },
+        );
+        self.by_port.insert((key.protocol, external_port), key);
+        external_port
+    }
+
+    /// Internal endpoint of the flow given `external_port`
+    fn lookup(&self, protocol: u8, external_port: u16, now: Instant) -> Option<(Ipv4Addr, u16)> {
+        let key = self.by_port.get(&(protocol, external_port))?;
+        let mapping = self.entries.get(key)?;
+        (now.duration_since(mapping.seen) < ENTRY_TIMEOUT)
+            .then_some((key.internal_ip, key.internal_port))
+    }
+
+    /// Whether `port` can be given to a new flow, removing the idle flow
+    /// holding it if there is one
+    fn is_free(&mut self, protocol: u8, port: u16, now: Instant) -> bool {
+        let Some(key) = self.by_port.get(&(protocol, port)).copied() else {
+            return true;
+        };
+        let idle = self
+            .entries
+            .get(&key)
+            .is_none_or(|mapping| now.duration_since(mapping.seen) >= ENTRY_TIMEOUT);
+        if idle {
+            self.remove(&key);
+        }
+        idle
+    }
+
+    /// Next free port of the dynamic range, which has room for more flows
+    /// than the table holds
+    fn free_port(&mut self, protocol: u8, now: Instant) -> u16 {
+        loop {
+            let port = self.next_port;
+            self.next_port = match port {
+                port if port == *DYNAMIC_PORTS.end() => *DYNAMIC_PORTS.start(),
+                port => port + 1,
+            };
+            if self.is_free(protocol, port, now) {
+                return port;
+            }
+        }
+    }
+
+    fn remove(&mut self, key: &NatKey) {
+        if let Some(mapping) = self.entries.remove(key) {
+            self.by_port.remove(&(key.protocol, mapping.external_port));
+        }
+    }
+
+    fn expire(&mut self, now: Instant) {
+        self.entries
+            .retain(|_, mapping| now.duration_since(mapping.seen) < ENTRY_TIMEOUT);
+        let entries = &self.entries;
+        self.by_port.retain(|_, key| entries.contains_key(key));
+    }
+}
+
+/// Source NAT to the address of one external interface, shared by the
+/// rewrite stages and by the filters matching replies before they are
+/// translated back
+#[derive(Debug, Clone)]
+pub struct Translation {
+    external_ip: Ipv4Addr,
+    table: Arc<Mutex<NatTable>>,
+}
+
+impl Translation {
+    pub fn new(external_ip: Ipv4Addr) -> Self {
+        Translation {
+            external_ip,
+            table: Arc::default(),
+        }
+    }
+
+    pub fn external_ip(&self) -> Ipv4Addr {
+        self.external_ip
+    }
+
+    /// Address and port datagrams of `protocol` from `source` leave with,
+    /// allocating the port of a new flow. IPv6 is not translated.
+    pub fn source(&self, protocol: IpNextHeaderProtocol, source: (IpAddr, u16)) -> (IpAddr, u16) {
+        let (IpAddr::V4(internal_ip), internal_port) = source else {
+            return source;
+        };
+        if internal_ip == self.external_ip {
+            return source;
+        }
+        let key = NatKey {
+            protocol: protocol.0,
+            internal_ip,
+            internal_port,
+        };
+        let port = self.table.lock().unwrap().record(key, Instant::now());
+        (IpAddr::V4(self.external_ip), port)
+    }
+}
+
+/// Transport protocol, offset of the transport header and source and
+/// destination ports of an IPv4 frame carrying UDP or TCP. Fragments have
+/// no ports that can be translated, so only their addresses are.
+struct Transport {
+    protocol: IpNextHeaderProtocol,
+    /// `None` for fragments
+    ports: Option<(usize, u16, u16)>,
+}
+
+fn transport(frame: &[u8]) -> Option<Transport> {
+    let eth = EthernetPacket::new(frame)?;
+    if eth.get_ethertype() != EtherTypes::Ipv4 {
+        return None;
+    }
+    let l3 = &frame[ETHERNET_HEADER_LEN..];
+    let header_len = usize::from(*l3.first()? & 0x0f) * 4;
+    let protocol = IpNextHeaderProtocol::new(*l3.get(9)?);
+    if protocol != IpNextHeaderProtocols::Udp && protocol != IpNextHeaderProtocols::Tcp {
+        return None;
+    }
+    // More fragments flag or a fragment offset
+    let fragmented = u16::from_be_bytes([*l3.get(6)?, *l3.get(7)?]) & 0x3fff != 0;
+    if fragmented {
+        return Some(Transport {
+            protocol,
+            ports: None,
+        });
+    }
+    let l4 = l3.get(header_len..header_len + 4)?;
+    Some(Transport {
+        protocol,
+        ports: Some((
+            ETHERNET_HEADER_LEN + header_len,
+            u16::from_be_bytes([l4[0], l4[1]]),
+            u16::from_be_bytes([l4[2], l4[3]]),
+        )),
+    })
+}
+
+/// Rewrites the source address and port of frames leaving through the
+/// external interface
+pub struct SourceNat {
+    translation: Translation,
+}
+
+impl SourceNat {
+    pub fn new(translation: Translation) -> Self {
+        SourceNat { translation }
+    }
+}
+
+impl Rewrite for SourceNat {
+    fn name(&self) -> &str {
+        "snat"
+    }
+
+    fn apply(&self, frame: &mut Vec<u8>) -> bool {
+        let Some(transport) = transport(frame) else {
+            return true;
+        };
+        let Some(mut ip) = MutableIpv4Packet::new(&mut frame[ETHERNET_HEADER_LEN..]) else {
+            return true;
+        };
+        let internal_ip = ip.get_source();
+        let external_ip = self.translation.external_ip;
+        if internal_ip == external_ip {
+            return true;
+        }
+        ip.set_source(external_ip);
+        if let Some((l4, src_port, _)) = transport.ports {
+            let source = (IpAddr::V4(internal_ip), src_port);
+            let (_, port) = self.translation.source(transport.protocol, source);
+            frame[l4..l4 + 2].copy_from_slice(&port.to_be_bytes());
+            debug!(
+                "SNAT {}:{} -> {}:{}",
+                internal_ip, src_port, external_ip, port
+            );
+        }
+        checksum::update_ipv4(frame)
+    }
+}
+
+/// Translates replies addressed to the external address back to the
+/// internal host and port recorded by [`SourceNat`]
+pub struct ReverseNat {
+    translation: Translation,
+}
+
+impl ReverseNat {
+    pub fn new(translation: Translation) -> Self {
+        ReverseNat { translation }
+    }
+}
+
+impl Rewrite for ReverseNat {
+    fn name(&self) -> &str {
+        "reverse-nat"
+    }
+
+    fn apply(&self, frame: &mut Vec<u8>) -> bool {
+        let Some(Transport {
+            protocol,
+            ports: Some((l4, _, dst_port)),
+        }) = transport(frame)
+        else {
+            return true;
+        };
+        let Some(mut ip) = MutableIpv4Packet::new(&mut frame[ETHERNET_HEADER_LEN..]) else {
+            return true;
+        };
+        let external_ip = self.translation.external_ip;
+        if ip.get_destination() != external_ip {
+            return true;
+        }
+        let table = self.translation.table.lock().unwrap();
+        let Some((internal_ip, internal_port)) = table.lookup(protocol.0, dst_port, Instant::now())
+        else {
+            return true;
+        };
+        drop(table);
+        ip.set_destination(internal_ip);
+        frame[l4 + 2..l4 + 4].copy_from_slice(&internal_port.to_be_bytes());
+        debug!(
+            "Reverse NAT {}:{} -> {}:{}",
+            external_ip, dst_port, internal_ip, internal_port
+        );
+        checksum::update_ipv4(frame)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pnet::packet::ethernet::MutableEthernetPacket;
+    use pnet::packet::ipv4;
+    use pnet::packet::udp::{self, MutableUdpPacket};
+
+    const EXTERNAL: Ipv4Addr = Ipv4Addr::new(192, 0, 2, 1);
+    const PEER: Ipv4Addr = Ipv4Addr::new(192, 0, 2, 9);
+
+    /// IPv4 UDP frame with valid checksums
+    fn udp_frame(source: (Ipv4Addr, u16), destination: (Ipv4Addr, u16), payload: &[u8]) -> Vec<u8> {
+        let udp_len = 8 + payload.len();
+        let mut frame = vec![0; ETHERNET_HEADER_LEN + 20 + udp_len];
+        MutableEthernetPacket::new(&mut frame)
+            .unwrap()
+            .set_ethertype(EtherTypes::Ipv4);
+        let (l3, l4) = frame[ETHERNET_HEADER_LEN..].split_at_mut(20);
+        let mut datagram = MutableUdpPacket::new(l4).unwrap();
+        datagram.set_source(source.1);
+        datagram.set_destination(destination.1);
+        datagram.set_length(udp_len as u16);
+        datagram.set_payload(payload);
+        let checksum = udp::ipv4_checksum(&datagram.to_immutable(), &source.0, &destination.0);
+        datagram.set_checksum(checksum);
+        let mut ip = MutableIpv4Packet::new(l3).unwrap();
+        ip.set_version(4);
+        ip.set_header_length(5);
+        ip.set_total_length((20 + udp_len) as u16);
+        ip.set_ttl(1);
+        ip.set_next_level_protocol(IpNextHeaderProtocols::Udp);
+        ip.set_source(source.0);
+        ip.set_destination(destination.0);
+        ip.set_checksum(ipv4::checksum(&ip.to_immutable()));
+        frame
+    }
+
+    /// Whether the IPv4 and UDP checksums of `frame` match its contents
+    fn checksums_valid(frame: &[u8]) -> bool {
+        let mut recomputed = frame.to_vec();
+        checksum::update_ipv4(&mut recomputed) && recomputed == frame
+    }
+
+    fn from_host(ip: Ipv4Addr, port: u16) -> Vec<u8> {
+        udp_frame((ip, port), (PEER, 1900), b"M-SEARCH * HTTP/1.1\r\n\r\n")
+    }
+
+    fn reply_to(port: u16) -> Vec<u8> {
+        udp_frame((PEER, 1900), (EXTERNAL, port), b"HTTP/1.1 200 OK\r\n\r\n")
+    }
+
+    /// Source or destination address and port of a translated frame
+    fn endpoint(frame: &[u8], destination: bool) -> (Ipv4Addr, u16) {
+        let (ip, port) = if destination { (30, 36) } else { (26, 34) };
+        let ip: [u8; 4] = frame[ip..ip + 4].try_into().unwrap();
+        (
+            ip.into(),
+            u16::from_be_bytes([frame[port], frame[port + 1]]),
+        )
+    }
+
+    #[test]
+    fn gives_hosts_on_the_same_port_their_own_external_port() {
+        let translation = Translation::new(EXTERNAL);
+        let snat = SourceNat::new(translation.clone());
+        let reverse = ReverseNat::new(translation);
+        let first = Ipv4Addr::new(10, 0, 0, 5);
+        let second = Ipv4Addr::new(10, 0, 0, 6);
+
+        let mut frame = from_host(first, 1900);
+        assert!(snat.apply(&mut frame));
+        assert_eq!(endpoint(&frame, false), (EXTERNAL, 1900));
+        assert!(checksums_valid(&frame));
+        let mut frame = from_host(second, 1900);
+        assert!(snat.apply(&mut frame));
+        let (_, port) = endpoint(&frame, false);
+        assert!(DYNAMIC_PORTS.contains(&port), "{}", port);
+        assert!(checksums_valid(&frame));
+
+        // Each flow keeps its port, and replies find their way back
+        let mut again = from_host(first, 1900);
+        assert!(snat.apply(&mut again));
+        assert_eq!(endpoint(&again, false), (EXTERNAL, 1900));
+        let mut reply = reply_to(port);
+        assert!(reverse.apply(&mut reply));
+        assert_eq!(endpoint(&reply, true), (second, 1900));
+        assert!(checksums_valid(&reply));
+        let mut reply = reply_to(1900);
+        assert!(reverse.apply(&mut reply));
+        assert_eq!(endpoint(&reply, true), (first, 1900));
+
+        // Replies to ports no flow has are left alone
+        let mut reply = reply_to(40000);
+        assert!(reverse.apply(&mut reply));
+        assert_eq!(reply, reply_to(40000));
+    }
+
+    #[test]
+    fn keeps_udp_without_a_checksum_without_one() {
+        let translation = Translation::new(EXTERNAL);
+        let mut frame = from_host(Ipv4Addr::new(10, 0, 0, 5), 5353);
+        frame[40..42].copy_from_slice(&[0, 0]);
+        assert!(SourceNat::new(translation.clone()).apply(&mut frame));
+        assert_eq!(&frame[40..42], &[0, 0]);
+        assert!(checksums_valid(&frame));
+
+        let mut reply = reply_to(5353);
+        reply[40..42].copy_from_slice(&[0, 0]);
+        assert!(ReverseNat::new(translation).apply(&mut reply));
+        assert_eq!(&reply[40..42], &[0, 0]);
+        assert!(checksums_valid(&reply));
+    }
+
+    #[test]
+    fn evicts_the_least_recently_used_flow_when_full() {
+        let mut table = NatTable::default();
+        let start = Instant::now();
+        let key = |n: usize| NatKey {
+            protocol: IpNextHeaderProtocols::Udp.0,
+            internal_ip: Ipv4Addr::from(0x0a00_0000 + n as u32),
+            internal_port: 1900,
+        };
+        let ports: Vec<u16> = (0..MAX_ENTRIES)
+            .map(|n| table.record(key(n), start + Duration::from_millis(n as u64)))
+            .collect();
+        let now = start + Duration::from_secs(60);
+        // Refreshing a flow keeps it over older ones
+        assert_eq!(table.record(key(0), now), ports[0]);
+
+        let port = table.record(key(MAX_ENTRIES), now);
+        assert_eq!(table.entries.len(), MAX_ENTRIES);
+        assert_eq!(table.by_port.len(), MAX_ENTRIES);
+        assert!(!table.entries.contains_key(&key(1)));
+        assert_eq!(table.lookup(17, ports[1], now), None);
+        assert_eq!(
+            table.lookup(17, ports[0], now),
+            Some((key(0).internal_ip, 1900))
+        );
+        assert_eq!(
+            table.lookup(17, port, now),
+            Some((key(MAX_ENTRIES).internal_ip, 1900))
+        );
+
+        // Idle flows give way before fresh ones
+        let later = start + ENTRY_TIMEOUT + Duration::from_secs(10);
+        table.record(key(MAX_ENTRIES + 1), later);
+        assert_eq!(table.entries.len(), 3);
+        assert_eq!(table.lookup(17, ports[2], later), None);
+    }
+}
@@ -0,0 +1,381 @@
+//! IPv6 neighbor discovery between the interfaces, either forwarded as it
+//! is or answered for the hosts on the external side (NDP proxy).
+
+use crate::filter::{Decision, Filter, IpHeader, PacketContext};
+use crate::hostmac::HostMacTable;
+use crate::responder::{Cache, Responder};
+use crate::rewrite::Rewrite;
+use clap::ValueEnum;
+use pnet::ipnetwork::IpNetwork;
+use pnet::packet::ethernet::{EtherTypes, EthernetPacket, MutableEthernetPacket};
+use pnet::packet::icmpv6::ndp::{MutableNeighborAdvertPacket, NeighborAdvertFlags};
+use pnet::packet::icmpv6::{self, Icmpv6Code, Icmpv6Packet, Icmpv6Types};
+use pnet::packet::ip::IpNextHeaderProtocols;
+use pnet::packet::ipv6::{Ipv6Packet, MutableIpv6Packet};
+use pnet::packet::Packet;
+use pnet::util::MacAddr;
+use serde::{Deserialize, Serialize};
+use std::net::{IpAddr, Ipv6Addr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tracing::debug;
+
+const ETHERNET_HEADER_LEN: usize = 14;
+const IPV6_HEADER_LEN: usize = 40;
+/// Neighbor advertisement with a target link-layer address option
+const ADVERT_LEN: usize = 24 + 8;
+const OPTION_TARGET_LINK_LAYER_ADDRESS: u8 = 2;
+/// Hop limit of every neighbor discovery message; receivers discard those
+/// with any other, as they may have crossed a router (RFC 4861 section 7.1)
+const NDP_HOP_LIMIT: u8 = 255;
+
+/// How IPv6 neighbor discovery is handled
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NdpMode {
+    /// Drop neighbor discovery like any frame no filter claims
+    Off,
+    /// Forward router and neighbor solicitations and advertisements
+    /// between the interfaces
+    Forward,
+    /// Answer neighbor solicitations from the internal side for hosts
+    /// learned on the external side with the internal interface's MAC
+    Proxy,
+}
+
+/// Whether `icmpv6` is a router or neighbor solicitation or advertisement
+/// that arrived with the hop limit neighbor discovery requires
+fn is_ndp(hop_limit: u8, icmpv6: &Icmpv6Packet) -> bool {
+    let ndp = matches!(
+        icmpv6.get_icmpv6_type(),
+        Icmpv6Types::RouterSolicit
+            | Icmpv6Types::RouterAdvert
+            | Icmpv6Types::NeighborSolicit
+            | Icmpv6Types::NeighborAdvert
+    );
+    ndp && hop_limit == NDP_HOP_LIMIT
+}
+
+/// Sender MAC, sender address and target address of a neighbor
+/// solicitation. Solicitations for duplicate address detection, sent from
+/// the unspecified address, are left alone.
+fn solicitation(ctx: &PacketContext) -> Option<(MacAddr, Ipv6Addr, Ipv6Addr)> {
+    let (Some(IpHeader::V6(ip)), Some(icmpv6)) = (&ctx.ip, ctx.icmpv6()) else {
+        return None;
+    };
+    let solicit = icmpv6.get_icmpv6_type() == Icmpv6Types::NeighborSolicit;
+    if !solicit || !is_ndp(ip.get_hop_limit(), icmpv6) || ip.get_source().is_unspecified() {
+        return None;
+    }
+    let target = icmpv6.payload().get(4..20)?;
+    let target: [u8; 16] = target.try_into().ok()?;
+    Some((ctx.ethernet.get_source(), ip.get_source(), target.into()))
+}
+
+/// Solicited neighbor advertisement telling `to` that `target` is at `mac`,
+/// sent from the address `from`
+fn advertisement(
+    (mac, from): (MacAddr, Ipv6Addr),
+    target: Ipv6Addr,
+    to: (MacAddr, Ipv6Addr),
+) -> Vec<u8> {
+    let mut frame = vec![0; ETHERNET_HEADER_LEN + IPV6_HEADER_LEN + ADVERT_LEN];
+    let mut eth = MutableEthernetPacket::new(&mut frame).expect("frame is large enough");
+    eth.set_destination(to.0);
+    eth.set_source(mac);
+    eth.set_ethertype(EtherTypes::Ipv6);
+
+    let l3 = &mut frame[ETHERNET_HEADER_LEN..];
+    let (l3_header, l4) = l3.split_at_mut(IPV6_HEADER_LEN);
+    let mut advert = MutableNeighborAdvertPacket::new(l4).expect("frame is large enough");
+    advert.set_icmpv6_type(Icmpv6Types::NeighborAdvert);
+    advert.set_icmpv6_code(Icmpv6Code(0));
+    advert.set_flags(NeighborAdvertFlags::Solicited | NeighborAdvertFlags::Override);
+    advert.set_target_addr(target);
+    l4[24..].copy_from_slice(&[
+        OPTION_TARGET_LINK_LAYER_ADDRESS,
+        1,
+        mac.0,
+        mac.1,
+        mac.2,
+        mac.3,
+        mac.4,
+        mac.5,
+    ]);
+    let checksum = icmpv6::checksum(
+        &Icmpv6Packet::new(l4).expect("frame is large enough"),
+        &from,
+        &to.1,
+    );
+    MutableNeighborAdvertPacket::new(l4)
+        .expect("frame is large enough")
+        .set_checksum(checksum);
+
+    let mut ip = MutableIpv6Packet::new(l3_header).expect("frame is large enough");
+    ip.set_version(6);
+    ip.set_payload_length(ADVERT_LEN as u16);
+    ip.set_next_header(IpNextHeaderProtocols::Icmpv6);
+    ip.set_hop_limit(NDP_HOP_LIMIT);
+    ip.set_source(from);
+    ip.set_destination(to.1);
+    frame
+}
+
+/// Source MAC and IPv6 address of an IPv6 frame
+fn ipv6_source(frame: &[u8]) -> Option<(MacAddr, Ipv6Addr)> {
+    let eth = EthernetPacket::new(frame)?;
+    if eth.get_ethertype() != EtherTypes::Ipv6 {
+        return None;
+    }
+    let ip = Ipv6Packet::new(&frame[ETHERNET_HEADER_LEN..])?;
+    Some((eth.get_source(), ip.get_source()))
+}
+
+/// IPv6 hosts learned from the frames forwarded inwards, answered for on
+/// the internal side with the internal interface's MAC. Addresses in the
+/// internal interface's own prefixes are never answered for, as the hosts
+/// there answer themselves; link-local addresses are, since every link
+/// shares their prefix.
+pub struct NdpProxy {
+    internal_iface: String,
+    internal_networks: Vec<IpNetwork>,
+    /// MAC the frames sent to a proxied host leave the external interface
+    /// with
+    external_mac: MacAddr,
+    hosts: HostMacTable<Ipv6Addr>,
+    responder: Responder,
+    answered: AtomicU64,
+    refused: AtomicU64,
+}
+
+impl NdpProxy {
+    pub fn new(
+        internal_iface: String,
+        internal_networks: Vec<IpNetwork>,
+        external_mac: MacAddr,
+        hosts: HostMacTable<Ipv6Addr>,
+        responder: Responder,
+    ) -> Self {
+        NdpProxy {
+            internal_iface,
+            internal_networks,
+            external_mac,
+            hosts,
+            responder,
+            answered: AtomicU64::new(0),
+            refused: AtomicU64::new(0),
+        }
+    }
+
+    fn is_internal(&self, ip: Ipv6Addr) -> bool {
+        !ip.is_unicast_link_local()
+            && self
+                .internal_networks
+                .iter()
+                .any(|network| network.contains(IpAddr::V6(ip)))
+    }
+
+    /// Whether a solicitation from `sender` for `target` is answered
+    fn answers_for(&self, sender: Ipv6Addr, target: Ipv6Addr) -> bool {
+        sender != target && !self.is_internal(target) && self.hosts.lookup(target).is_some()
+    }
+}
+
+impl Cache for NdpProxy {
+    fn name(&self) -> &str {
+        "ndp-proxy"
+    }
+
+    fn answer(&self, ctx: &PacketContext) -> bool {
+        let Some((sender_mac, sender, target)) = solicitation(ctx) else {
+            return false;
+        };
+        let Some(from) = self.responder.ipv6() else {
+            return false;
+        };
+        if !self.answers_for(sender, target) {
+            return false;
+        }
+        let frame = advertisement((self.responder.mac(), from), target, (sender_mac, sender));
+        if !self.responder.send_frame(frame) {
+            self.refused.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+        self.answered.fetch_add(1, Ordering::Relaxed);
+        debug!(
+            "Neighbor solicitation from {} for {} answered",
+            sender, target
+        );
+        true
+    }
+
+    /// One line per learned external host, followed by the solicitation
+    /// counters
+    fn state(&self) -> Vec<String> {
+        let mut lines = self.hosts.hosts();
+        lines.push(format!(
+            "{} solicitation(s) answered, {} refused",
+            self.answered.load(Ordering::Relaxed),
+            self.refused.load(Ordering::Relaxed)
+        ));
+        lines
+    }
+}
+
+/// Lets neighbor discovery through. Without a proxy every router and
+/// neighbor solicitation and advertisement is forwarded; with one, only the
+/// neighbor solicitations from the internal side it answers for are, and it
+/// answers them in place of forwarding.
+pub struct NdpFilter {
+    proxy: Option<Arc<NdpProxy>>,
+}
+
+impl NdpFilter {
+    pub fn new(proxy: Option<Arc<NdpProxy>>) -> Self {
+        NdpFilter { proxy }
+    }
+}
+
+impl Filter for NdpFilter {
+    fn name(&self) -> &str {
+        "ndp"
+    }
+
+    fn evaluate(&self, ctx: &PacketContext) -> Decision {
+        let (Some(IpHeader::V6(ip)), Some(icmpv6)) = (&ctx.ip, ctx.icmpv6()) else {
+            return Decision::Continue;
+        };
+        if !is_ndp(ip.get_hop_limit(), icmpv6) {
+            return Decision::Continue;
+        }
+        let Some(proxy) = &self.proxy else {
+            return Decision::Forward;
+        };
+        if ctx.ingress != proxy.internal_iface {
+            return Decision::Drop;
+        }
+        match solicitation(ctx) {
+            Some((_, sender, target)) if proxy.answers_for(sender, target) => Decision::Forward,
+            Some((_, _, target)) => {
+                proxy.refused.fetch_add(1, Ordering::Relaxed);
+                debug!("Neighbor solicitation for {} not answered", target);
+                Decision::Drop
+            }
+            None => Decision::Drop,
+        }
+    }
+}
+
+/// Learns the MAC the external IPv6 hosts are reached at from the frames
+/// forwarded inwards. Goes first, ahead of any rewrite of their source.
+pub struct LearnExternalNeighbors {
+    proxy: Arc<NdpProxy>,
+}
+
+impl LearnExternalNeighbors {
+    pub fn new(proxy: Arc<NdpProxy>) -> Self {
+        LearnExternalNeighbors { proxy }
+    }
+}
+
+impl Rewrite for LearnExternalNeighbors {
+    fn name(&self) -> &str {
+        "ndp-proxy"
+    }
+
+    fn apply(&self, frame: &mut Vec<u8>) -> bool {
+        if let Some((mac, ip)) = ipv6_source(frame) {
+            let unicast_ip = !(ip.is_unspecified() || ip.is_multicast());
+            if unicast_ip && !mac.is_multicast() && !self.proxy.is_internal(ip) {
+                self.proxy.hosts.learn(ip, mac);
+            }
+        }
+        true
+    }
+}
+
+/// Addresses IPv6 frames the internal hosts sent to the internal
+/// interface's MAC, as answered by the proxy, to the learned MAC of their
+/// destination, with the external interface as source.
+pub struct ProxiedNeighborMac {
+    proxy: Arc<NdpProxy>,
+}
+
+impl ProxiedNeighborMac {
+    pub fn new(proxy: Arc<NdpProxy>) -> Self {
+        ProxiedNeighborMac { proxy }
+    }
+}
+
+impl Rewrite for ProxiedNeighborMac {
+    fn name(&self) -> &str {
+        "ndp-proxy"
+    }
+
+    fn apply(&self, frame: &mut Vec<u8>) -> bool {
+        let Some(eth) = EthernetPacket::new(frame) else {
+            return false;
+        };
+        let proxied = eth.get_ethertype() == EtherTypes::Ipv6
+            && eth.get_destination() == self.proxy.responder.mac();
+        if !proxied {
+            return true;
+        }
+        let Some(ip) = Ipv6Packet::new(&frame[ETHERNET_HEADER_LEN..]) else {
+            return true;
+        };
+        let Some(mac) = self.proxy.hosts.lookup(ip.get_destination()) else {
+            return true;
+        };
+        let mut eth = MutableEthernetPacket::new(frame).expect("header was parsed above");
+        eth.set_destination(mac);
+        eth.set_source(self.proxy.external_mac);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pair::Direction;
+
+    #[test]
+    fn advertises_with_valid_checksum() {
+        let own = (
+            MacAddr::new(2, 0, 0, 0, 0, 1),
+            "fe80::1".parse::<Ipv6Addr>().unwrap(),
+        );
+        let client = (
+            MacAddr::new(0x52, 0x54, 0, 0, 0, 7),
+            "fe80::7".parse::<Ipv6Addr>().unwrap(),
+        );
+        let renderer: Ipv6Addr = "2001:db8::5".parse().unwrap();
+
+        let frame = advertisement(own, renderer, client);
+        let ctx = PacketContext::parse("vm1", Direction::Inbound, &frame).unwrap();
+        assert_eq!(ctx.ethernet.get_destination(), client.0);
+        let Some(IpHeader::V6(ip)) = &ctx.ip else {
+            panic!("not IPv6");
+        };
+        assert_eq!(ip.get_hop_limit(), NDP_HOP_LIMIT);
+        let icmpv6 = ctx.icmpv6().unwrap();
+        assert!(is_ndp(ip.get_hop_limit(), icmpv6));
+        assert_eq!(icmpv6.get_icmpv6_type(), Icmpv6Types::NeighborAdvert);
+        assert_eq!(
+            icmpv6::checksum(icmpv6, &own.1, &client.1),
+            icmpv6.get_checksum()
+        );
+        assert_eq!(&icmpv6.payload()[..4], [0x60, 0, 0, 0]);
+        assert_eq!(&icmpv6.payload()[20..], [2, 1, 2, 0, 0, 0, 0, 1]);
+        assert_eq!(solicitation(&ctx), None);
+
+        // A solicitation is the advertisement with the type changed
+        let mut solicit = advertisement(client, renderer, own);
+        solicit[ETHERNET_HEADER_LEN + IPV6_HEADER_LEN] = Icmpv6Types::NeighborSolicit.0;
+        let ctx = PacketContext::parse("vm1", Direction::Outbound, &solicit).unwrap();
+        assert_eq!(solicitation(&ctx), Some((client.0, client.1, renderer)));
+        solicit[ETHERNET_HEADER_LEN + 7] = 64;
+        let ctx = PacketContext::parse("vm1", Direction::Outbound, &solicit).unwrap();
+        assert_eq!(solicitation(&ctx), None);
+    }
+}
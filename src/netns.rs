@@ -0,0 +1,133 @@
+//! Network namespaces interfaces can be opened in, for an interface that
+//! lives in another namespace than the forwarder, such as that of a VM's
+//! network stack.
+//!
+//! Entering a namespace only switches the calling thread, and threads start
+//! in the namespace of the thread creating them. Work for an interface in a
+//! namespace, including spawning its capture and send threads, is therefore
+//! done on a short-lived thread that entered it, leaving the threads of the
+//! runtime where they are.
+
+use crate::error::Error;
+use std::fs::File;
+use std::io;
+use std::os::fd::AsRawFd;
+use std::path::PathBuf;
+use tokio::runtime::Handle;
+
+/// Directory `ip netns add` keeps named namespaces in
+const NAMED_NETNS_DIR: &str = "/run/netns";
+
+/// Path of the namespace `spec` refers to: a name under `/run/netns`, or a
+/// path such as `/proc/1234/ns/net` if it contains a slash
+pub fn path(spec: &str) -> PathBuf {
+    if spec.contains('/') {
+        PathBuf::from(spec)
+    } else {
+        PathBuf::from(NAMED_NETNS_DIR).join(spec)
+    }
+}
+
+/// An opened network namespace, kept open so it cannot go away while
+/// interfaces in it are used
+#[derive(Debug)]
+pub struct NetNs {
+    /// As given, for messages
+    spec: String,
+    file: File,
+}
+
+impl NetNs {
+    /// Opens the namespace `spec` refers to, see [`path`]
+    pub fn open(spec: &str) -> Result<Self, Error> {
+        let file = File::open(path(spec)).map_err(|source| Error::Netns {
+            netns: spec.to_string(),
+            source,
+        })?;
+        Ok(NetNs {
+            spec: spec.to_string(),
+            file,
+        })
+    }
+
+    pub fn spec(&self) -> &str {
+        &self.spec
+    }
+
+    /// Moves the calling thread into the namespace
+    fn enter(&self) -> io::Result<()> {
+        // SAFETY: setns only reads the descriptor, which the file keeps open
+        if unsafe { libc::setns(self.file.as_raw_fd(), libc::CLONE_NEWNET) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Runs `work` on a thread inside the namespace and returns its result.
+    /// Threads and sockets `work` creates belong to the namespace for their
+    /// lifetime, and it can spawn tasks on the runtime it was called from.
+    pub fn run<T: Send>(&self, work: impl FnOnce() -> T + Send) -> Result<T, Error> {
+        let runtime = Handle::try_current().ok();
+        std::thread::scope(|scope| {
+            std::thread::Builder::new()
+                .name("netns".to_string())
+                .spawn_scoped(scope, || {
+                    let _runtime = runtime.as_ref().map(Handle::enter);
+                    self.enter().map_err(|source| Error::Netns {
+                        netns: self.spec.clone(),
+                        source,
+                    })?;
+                    Ok(work())
+                })
+                .expect("thread spawned")
+                .join()
+                .unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+        })
+    }
+}
+
+/// Runs `work` inside `netns` if given, or right away on the calling thread
+pub fn within<T: Send>(netns: Option<&NetNs>, work: impl FnOnce() -> T + Send) -> Result<T, Error> {
+    match netns {
+        Some(netns) => netns.run(work),
+        None => Ok(work()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    /// Namespace inode of the calling thread
+    fn current() -> u64 {
+        use std::os::unix::fs::MetadataExt;
+        fs::metadata("/proc/thread-self/ns/net").unwrap().ino()
+    }
+
+    #[test]
+    fn resolves_names_and_enters_namespaces() {
+        assert_eq!(path("netvm"), PathBuf::from("/run/netns/netvm"));
+        assert_eq!(path("/proc/42/ns/net"), PathBuf::from("/proc/42/ns/net"));
+        let missing = NetNs::open("does-not-exist").unwrap_err();
+        assert!(
+            missing.to_string().contains("does-not-exist"),
+            "{}",
+            missing
+        );
+
+        // Entering the namespace already in use needs CAP_SYS_ADMIN too
+        let own = NetNs::open("/proc/self/ns/net").unwrap();
+        match own.run(|| (current(), std::thread::spawn(current).join().unwrap())) {
+            Ok((inside, spawned)) => {
+                assert_eq!(inside, current());
+                assert_eq!(spawned, inside);
+            }
+            Err(Error::Netns { source, .. }) => {
+                assert_eq!(source.kind(), io::ErrorKind::PermissionDenied)
+            }
+            Err(e) => panic!("{}", e),
+        }
+        assert_eq!(within(None, current).unwrap(), current());
+    }
+}
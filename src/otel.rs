@@ -0,0 +1,615 @@
+//! OpenTelemetry export over OTLP with `--otel`, built with the `otel`
+//! feature: the counters and latency percentiles of every path as metrics
+//! and, with `--trace-packets`, a span per forwarded frame with its stages
+//! as events.
+//!
+//! Exports run on threads of the OpenTelemetry SDK. Metrics are read from
+//! the counters when an export is due and spans wait in a bounded queue that
+//! drops new ones while the collector is unreachable, so the data path never
+//! waits for it.
+
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+#[cfg(feature = "otel")]
+use {
+    crate::error::Error,
+    crate::latency::Stage,
+    crate::stats::{PathSnapshot, Stats, StatsSnapshot},
+    crate::Args,
+    arc_swap::ArcSwapOption,
+    opentelemetry::metrics::{Meter, MeterProvider},
+    opentelemetry::trace::{Event, Span, SpanKind, Tracer, TracerProvider},
+    opentelemetry::KeyValue,
+    opentelemetry_otlp::{
+        MetricExporter, SpanExporter, WithExportConfig, WithHttpConfig, WithTonicConfig,
+    },
+    opentelemetry_sdk::metrics::{PeriodicReader, SdkMeterProvider},
+    opentelemetry_sdk::trace::{SdkTracer, SdkTracerProvider},
+    opentelemetry_sdk::Resource,
+    std::collections::{BTreeMap, HashMap},
+    std::sync::{Arc, Mutex},
+    std::time::{Duration, Instant, SystemTime},
+    tokio::task::JoinHandle,
+    tokio_util::sync::CancellationToken,
+    tracing::{info, warn},
+};
+
+/// Transport of the exports, named as in `OTEL_EXPORTER_OTLP_PROTOCOL`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Deserialize, Serialize)]
+pub enum OtlpProtocol {
+    #[value(name = "grpc")]
+    #[serde(rename = "grpc")]
+    Grpc,
+    /// Protobuf over HTTP, the default of OpenTelemetry
+    #[value(name = "http/protobuf")]
+    #[serde(rename = "http/protobuf")]
+    HttpProtobuf,
+}
+
+/// Header sent with every export, such as an API key, written as
+/// `NAME=VALUE`
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct OtlpHeader {
+    pub name: String,
+    pub value: String,
+}
+
+impl FromStr for OtlpHeader {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (name, header) = value
+            .split_once('=')
+            .ok_or_else(|| format!("'{}' is not of the form NAME=VALUE", value))?;
+        let name = name.trim();
+        if name.is_empty()
+            || !name
+                .bytes()
+                .all(|byte| byte.is_ascii_alphanumeric() || b"-_.".contains(&byte))
+        {
+            return Err(format!("'{}' is not a header name", name));
+        }
+        if header.bytes().any(|byte| byte.is_ascii_control()) {
+            return Err(format!(
+                "the value of header {} has control characters",
+                name
+            ));
+        }
+        Ok(OtlpHeader {
+            name: name.to_ascii_lowercase(),
+            value: header.trim().to_string(),
+        })
+    }
+}
+
+impl TryFrom<String> for OtlpHeader {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<OtlpHeader> for String {
+    fn from(header: OtlpHeader) -> Self {
+        header.to_string()
+    }
+}
+
+impl fmt::Display for OtlpHeader {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", self.name, self.value)
+    }
+}
+
+/// Name of the meter, the tracer and, unless `OTEL_SERVICE_NAME` says
+/// otherwise, the service
+#[cfg(feature = "otel")]
+const SERVICE: &str = "nw-pckt-fwd";
+/// Frames traced at once before the oldest are forgotten, such as those
+/// dropped after being queued
+#[cfg(feature = "otel")]
+const MAX_PENDING: usize = 1024;
+/// Age up to which one snapshot of the counters serves every instrument of
+/// an export
+#[cfg(feature = "otel")]
+const SNAPSHOT_AGE: Duration = Duration::from_secs(1);
+
+/// Spans of the frames traced by `--trace-packets`, while exporting
+#[cfg(feature = "otel")]
+static SPANS: ArcSwapOption<PacketSpans> = ArcSwapOption::const_empty();
+
+/// Stages a traced frame reached so far
+#[cfg(feature = "otel")]
+#[derive(Debug)]
+struct Pending {
+    ingress: String,
+    start: SystemTime,
+    events: Vec<Event>,
+}
+
+#[cfg(feature = "otel")]
+#[derive(Debug, Default)]
+struct PendingSpans {
+    frames: Mutex<BTreeMap<u64, Pending>>,
+}
+
+#[cfg(feature = "otel")]
+impl PendingSpans {
+    /// Records that frame `id` reached `stage` on `iface` at `now`, `elapsed`
+    /// after it was received. Returns the frame once it was sent.
+    fn reached(
+        &self,
+        id: u64,
+        stage: Stage,
+        iface: &str,
+        now: SystemTime,
+        elapsed: Duration,
+    ) -> Option<Pending> {
+        let event = Event::new(stage.name(), now, Vec::new(), 0);
+        let mut frames = self.frames.lock().unwrap();
+        if stage == Stage::Sent {
+            let mut pending = frames.remove(&id)?;
+            pending.events.push(event);
+            return Some(pending);
+        }
+        frames
+            .entry(id)
+            .or_insert_with(|| Pending {
+                ingress: iface.to_string(),
+                start: now.checked_sub(elapsed).unwrap_or(now),
+                events: Vec::new(),
+            })
+            .events
+            .push(event);
+        // IDs count up, so the first are the oldest
+        while frames.len() > MAX_PENDING {
+            frames.pop_first();
+        }
+        None
+    }
+}
+
+#[cfg(feature = "otel")]
+struct PacketSpans {
+    tracer: SdkTracer,
+    pending: PendingSpans,
+}
+
+/// Adds that frame `id`, received at `received`, reached `stage` on `iface`
+/// to its span, which is exported once the frame is sent
+#[cfg(feature = "otel")]
+pub fn trace(id: u64, stage: Stage, iface: &str, received: Instant) {
+    let Some(spans) = &*SPANS.load() else {
+        return;
+    };
+    let now = SystemTime::now();
+    let Some(pending) = spans
+        .pending
+        .reached(id, stage, iface, now, received.elapsed())
+    else {
+        return;
+    };
+    let mut span = spans
+        .tracer
+        .span_builder("forward")
+        .with_kind(SpanKind::Internal)
+        .with_start_time(pending.start)
+        .with_attributes([
+            KeyValue::new("nw_pckt_fwd.trace", id as i64),
+            KeyValue::new("nw_pckt_fwd.ingress", pending.ingress),
+            KeyValue::new("nw_pckt_fwd.egress", iface.to_string()),
+        ])
+        .with_events(pending.events)
+        .start(&spans.tracer);
+    span.end_with_timestamp(now);
+}
+
+/// Snapshot of the counters shared by the instruments of one export
+#[cfg(feature = "otel")]
+struct Snapshots {
+    stats: Arc<Stats>,
+    last: Mutex<Option<(Instant, Arc<StatsSnapshot>)>>,
+}
+
+#[cfg(feature = "otel")]
+impl Snapshots {
+    fn get(&self) -> Arc<StatsSnapshot> {
+        let mut last = self.last.lock().unwrap();
+        match &*last {
+            Some((taken, snapshot)) if taken.elapsed() < SNAPSHOT_AGE => snapshot.clone(),
+            _ => {
+                let snapshot = Arc::new(self.stats.snapshot());
+                *last = Some((Instant::now(), snapshot.clone()));
+                snapshot
+            }
+        }
+    }
+}
+
+#[cfg(feature = "otel")]
+fn path_attributes(path: &PathSnapshot) -> Vec<KeyValue> {
+    vec![
+        KeyValue::new("nw_pckt_fwd.pair", path.pair.clone()),
+        KeyValue::new("nw_pckt_fwd.direction", path.direction.name()),
+        KeyValue::new("nw_pckt_fwd.ingress", path.ingress.clone()),
+        KeyValue::new("nw_pckt_fwd.egress", path.egress.clone()),
+    ]
+}
+
+/// Counters of every path, with their unit and description
+#[cfg(feature = "otel")]
+type PathCounter = (
+    &'static str,
+    &'static str,
+    &'static str,
+    fn(&PathSnapshot) -> u64,
+);
+
+#[cfg(feature = "otel")]
+const PATH_COUNTERS: [PathCounter; 10] = [
+    (
+        "nw_pckt_fwd.received",
+        "{frame}",
+        "Frames received",
+        |path| path.received,
+    ),
+    (
+        "nw_pckt_fwd.received.bytes",
+        "By",
+        "Bytes received",
+        |path| path.received_bytes,
+    ),
+    (
+        "nw_pckt_fwd.queued",
+        "{frame}",
+        "Frames queued for sending",
+        |path| path.queued,
+    ),
+    ("nw_pckt_fwd.forwarded", "{frame}", "Frames sent", |path| {
+        path.forwarded
+    }),
+    ("nw_pckt_fwd.forwarded.bytes", "By", "Bytes sent", |path| {
+        path.forwarded_bytes
+    }),
+    ("nw_pckt_fwd.retried", "{send}", "Sends retried", |path| {
+        path.retried
+    }),
+    (
+        "nw_pckt_fwd.fragmented",
+        "{frame}",
+        "Frames fragmented",
+        |path| path.fragmented,
+    ),
+    (
+        "nw_pckt_fwd.mdns_merged",
+        "{frame}",
+        "mDNS responses merged",
+        |path| path.mdns_merged,
+    ),
+    (
+        "nw_pckt_fwd.eapol_passed",
+        "{frame}",
+        "EAPOL frames passed through",
+        |path| path.eapol_passed,
+    ),
+    (
+        "nw_pckt_fwd.lldp_passed",
+        "{frame}",
+        "LLDP frames passed through",
+        |path| path.lldp_passed,
+    ),
+];
+
+/// Registers the instruments read from `snapshots` on every export
+#[cfg(feature = "otel")]
+fn register(meter: &Meter, snapshots: Arc<Snapshots>) {
+    for (name, unit, description, value) in PATH_COUNTERS {
+        let snapshots = snapshots.clone();
+        meter
+            .u64_observable_counter(name)
+            .with_unit(unit)
+            .with_description(description)
+            .with_callback(move |observer| {
+                for path in &snapshots.get().paths {
+                    observer.observe(value(path), &path_attributes(path));
+                }
+            })
+            .build();
+    }
+    let drops = snapshots.clone();
+    meter
+        .u64_observable_counter("nw_pckt_fwd.dropped")
+        .with_unit("{frame}")
+        .with_description("Frames dropped, by reason")
+        .with_callback(move |observer| {
+            for path in &drops.get().paths {
+                let mut attributes = path_attributes(path);
+                attributes.push(KeyValue::new("nw_pckt_fwd.reason", ""));
+                for (reason, frames) in path.drops() {
+                    attributes[4] = KeyValue::new("nw_pckt_fwd.reason", reason);
+                    observer.observe(frames, &attributes);
+                }
+            }
+        })
+        .build();
+    let latency = snapshots.clone();
+    meter
+        .u64_observable_gauge("nw_pckt_fwd.latency")
+        .with_unit("us")
+        .with_description("Time from receiving to sending frames, by percentile")
+        .with_callback(move |observer| {
+            for path in &latency.get().paths {
+                let latency = path.latency;
+                let mut attributes = path_attributes(path);
+                attributes.push(KeyValue::new("nw_pckt_fwd.percentile", ""));
+                for (percentile, micros) in [
+                    ("p50", latency.p50_us),
+                    ("p95", latency.p95_us),
+                    ("p99", latency.p99_us),
+                    ("max", latency.max_us),
+                ] {
+                    attributes[4] = KeyValue::new("nw_pckt_fwd.percentile", percentile);
+                    observer.observe(micros, &attributes);
+                }
+            }
+        })
+        .build();
+    let shaped = snapshots.clone();
+    meter
+        .u64_observable_gauge("nw_pckt_fwd.shaped_delay")
+        .with_unit("us")
+        .with_description("Delay the shaper added to frames, by percentile")
+        .with_callback(move |observer| {
+            for path in shaped
+                .get()
+                .paths
+                .iter()
+                .filter(|path| path.shaped.count > 0)
+            {
+                let delay = path.shaped;
+                let mut attributes = path_attributes(path);
+                attributes.push(KeyValue::new("nw_pckt_fwd.percentile", ""));
+                for (percentile, micros) in [
+                    ("p50", delay.p50_us),
+                    ("p95", delay.p95_us),
+                    ("p99", delay.p99_us),
+                    ("max", delay.max_us),
+                ] {
+                    attributes[4] = KeyValue::new("nw_pckt_fwd.percentile", percentile);
+                    observer.observe(micros, &attributes);
+                }
+            }
+        })
+        .build();
+    let interfaces = snapshots.clone();
+    meter
+        .u64_observable_counter("nw_pckt_fwd.interface.kernel_dropped")
+        .with_unit("{frame}")
+        .with_description("Frames the kernel dropped before they were read")
+        .with_callback(move |observer| {
+            for iface in &interfaces.get().interfaces {
+                let attributes = [KeyValue::new("nw_pckt_fwd.interface", iface.name.clone())];
+                observer.observe(iface.kernel_dropped, &attributes);
+            }
+        })
+        .build();
+    let reconnects = snapshots.clone();
+    meter
+        .u64_observable_counter("nw_pckt_fwd.interface.reconnects")
+        .with_unit("{reconnect}")
+        .with_description("Times the interface was reopened")
+        .with_callback(move |observer| {
+            for iface in &reconnects.get().interfaces {
+                let attributes = [KeyValue::new("nw_pckt_fwd.interface", iface.name.clone())];
+                observer.observe(iface.reconnects, &attributes);
+            }
+        })
+        .build();
+    meter
+        .u64_observable_gauge("nw_pckt_fwd.paused")
+        .with_description("1 while forwarding is paused")
+        .with_callback(move |observer| observer.observe(snapshots.get().paused.into(), &[]))
+        .build();
+}
+
+/// Protocol of `--otel-protocol`, else of `OTEL_EXPORTER_OTLP_PROTOCOL`
+#[cfg(feature = "otel")]
+fn protocol(args: &Args) -> OtlpProtocol {
+    if let Some(protocol) = args.otel_protocol {
+        return protocol;
+    }
+    match std::env::var("OTEL_EXPORTER_OTLP_PROTOCOL").as_deref() {
+        Ok("grpc") => OtlpProtocol::Grpc,
+        Ok("http/protobuf") | Err(_) => OtlpProtocol::HttpProtobuf,
+        Ok(other) => {
+            warn!(
+                "OTLP protocol {} is not supported, using http/protobuf",
+                other
+            );
+            OtlpProtocol::HttpProtobuf
+        }
+    }
+}
+
+/// Builds an exporter of `kind` for `args`. Endpoints given as options are
+/// the base of both signals, as `OTEL_EXPORTER_OTLP_ENDPOINT` is, while the
+/// other variables are left to the exporters, which read them themselves.
+#[cfg(feature = "otel")]
+macro_rules! exporter {
+    ($kind:ident, $args:expr, $path:literal) => {{
+        let args: &Args = $args;
+        match protocol(args) {
+            OtlpProtocol::Grpc => {
+                let mut builder = $kind::builder().with_tonic();
+                if let Some(endpoint) = &args.otel_endpoint {
+                    builder = builder.with_endpoint(endpoint.clone());
+                }
+                let headers = args
+                    .otel_header
+                    .iter()
+                    .filter_map(|header| {
+                        Some((
+                            http::HeaderName::from_str(&header.name).ok()?,
+                            http::HeaderValue::from_str(&header.value).ok()?,
+                        ))
+                    })
+                    .collect();
+                builder
+                    .with_metadata(
+                        opentelemetry_otlp::tonic_types::metadata::MetadataMap::from_headers(
+                            headers,
+                        ),
+                    )
+                    .build()
+            }
+            OtlpProtocol::HttpProtobuf => {
+                let mut builder = $kind::builder().with_http();
+                if let Some(endpoint) = &args.otel_endpoint {
+                    builder = builder.with_endpoint(format!(
+                        "{}{}",
+                        endpoint.trim_end_matches('/'),
+                        $path
+                    ));
+                }
+                let headers: HashMap<String, String> = args
+                    .otel_header
+                    .iter()
+                    .map(|header| (header.name.clone(), header.value.clone()))
+                    .collect();
+                builder.with_headers(headers).build()
+            }
+        }
+        .map_err(Error::Otel)
+    }};
+}
+
+/// Providers of the running export
+#[cfg(feature = "otel")]
+struct Providers {
+    meters: SdkMeterProvider,
+    tracers: Option<SdkTracerProvider>,
+}
+
+#[cfg(feature = "otel")]
+impl Providers {
+    /// Exports what is left and stops
+    fn shutdown(self) {
+        SPANS.store(None);
+        if let Some(tracers) = self.tracers {
+            if let Err(err) = tracers.shutdown() {
+                warn!("Failed to flush the OTLP span export: {}", err);
+            }
+        }
+        if let Err(err) = self.meters.shutdown() {
+            warn!("Failed to flush the OTLP metric export: {}", err);
+        }
+    }
+}
+
+/// Starts exporting `stats` and, with `--trace-packets`, the spans of traced
+/// frames. The returned task flushes both and stops once `token` is
+/// cancelled.
+#[cfg(feature = "otel")]
+pub fn spawn(
+    args: &Args,
+    stats: Arc<Stats>,
+    token: CancellationToken,
+) -> Result<JoinHandle<()>, Error> {
+    let mut resource = Resource::builder();
+    if std::env::var_os("OTEL_SERVICE_NAME").is_none() {
+        resource = resource.with_service_name(SERVICE);
+    }
+    let resource = resource.build();
+    let mut reader = PeriodicReader::builder(exporter!(MetricExporter, args, "/v1/metrics")?);
+    if let Some(interval) = args.otel_export_interval {
+        reader = reader.with_interval(interval);
+    }
+    let meters = SdkMeterProvider::builder()
+        .with_reader(reader.build())
+        .with_resource(resource.clone())
+        .build();
+    register(
+        &meters.meter(SERVICE),
+        Arc::new(Snapshots {
+            stats,
+            last: Mutex::new(None),
+        }),
+    );
+    let tracers = if args.trace_packets {
+        let tracers = SdkTracerProvider::builder()
+            .with_batch_exporter(exporter!(SpanExporter, args, "/v1/traces")?)
+            .with_resource(resource)
+            .build();
+        SPANS.store(Some(Arc::new(PacketSpans {
+            tracer: tracers.tracer(SERVICE),
+            pending: PendingSpans::default(),
+        })));
+        Some(tracers)
+    } else {
+        None
+    };
+    info!(
+        "Exporting metrics{} over OTLP/{}",
+        if tracers.is_some() {
+            " and packet traces"
+        } else {
+            ""
+        },
+        match protocol(args) {
+            OtlpProtocol::Grpc => "gRPC",
+            OtlpProtocol::HttpProtobuf => "HTTP",
+        }
+    );
+    let providers = Providers { meters, tracers };
+    Ok(tokio::spawn(async move {
+        token.cancelled().await;
+        // Flushing waits for the collector, up to the export timeout
+        let _ = tokio::task::spawn_blocking(move || providers.shutdown()).await;
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_headers() {
+        let header: OtlpHeader = "Authorization=Bearer abc=".parse().unwrap();
+        assert_eq!(header.name, "authorization");
+        assert_eq!(header.value, "Bearer abc=");
+        assert_eq!(header.to_string(), "authorization=Bearer abc=");
+        assert!("authorization".parse::<OtlpHeader>().is_err());
+        assert!("=value".parse::<OtlpHeader>().is_err());
+        assert!("bad name=value".parse::<OtlpHeader>().is_err());
+        assert!("name=a\nb".parse::<OtlpHeader>().is_err());
+    }
+
+    #[cfg(feature = "otel")]
+    #[test]
+    fn assembles_packet_spans() {
+        let spans = PendingSpans::default();
+        let now = SystemTime::now();
+        let elapsed = Duration::from_micros(50);
+        for stage in [Stage::Received, Stage::Filtered, Stage::Queued] {
+            assert!(spans.reached(1, stage, "eth0", now, elapsed).is_none());
+        }
+        let sent = spans.reached(1, Stage::Sent, "vm0", now, elapsed).unwrap();
+        assert_eq!(sent.ingress, "eth0");
+        assert_eq!(sent.start, now - elapsed);
+        let stages: Vec<_> = sent.events.iter().map(|event| &event.name).collect();
+        assert_eq!(stages, ["received", "filtered", "queued", "sent"]);
+        // Sent only once, and frames dropped on the way are forgotten
+        assert!(spans.reached(1, Stage::Sent, "vm0", now, elapsed).is_none());
+        for id in 2..MAX_PENDING as u64 + 10 {
+            spans.reached(id, Stage::Received, "eth0", now, elapsed);
+        }
+        assert!(spans.reached(2, Stage::Sent, "vm0", now, elapsed).is_none());
+        assert!(spans
+            .reached(MAX_PENDING as u64 + 9, Stage::Sent, "vm0", now, elapsed)
+            .is_some());
+    }
+}
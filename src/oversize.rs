@@ -0,0 +1,339 @@
+//! Frames too large for the MTU of their egress interface: dropped, or for
+//! IPv4 packets that may be fragmented, split into fragments that fit. The
+//! sender of a dropped IPv6 packet can be told with an ICMPv6 Packet Too Big.
+
+use crate::responder::Responder;
+use clap::ValueEnum;
+use pnet::packet::ethernet::{EtherTypes, EthernetPacket, MutableEthernetPacket};
+use pnet::packet::icmpv6::{self, Icmpv6Packet};
+use pnet::packet::ip::IpNextHeaderProtocols;
+use pnet::packet::ipv4::{self, Ipv4Flags, Ipv4Packet, MutableIpv4Packet};
+use pnet::packet::ipv6::{Ipv6Packet, MutableIpv6Packet};
+use pnet::packet::Packet;
+use pnet::util::MacAddr;
+use serde::{Deserialize, Serialize};
+use std::net::Ipv6Addr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::debug;
+
+const ETHERNET_HEADER_LEN: usize = 14;
+const IPV6_HEADER_LEN: usize = 40;
+const ICMPV6_HEADER_LEN: usize = 8;
+/// IPv6 minimum MTU, which a Packet Too Big message must fit in
+const IPV6_MIN_MTU: usize = 1280;
+const ICMPV6_PACKET_TOO_BIG: u8 = 2;
+const PACKET_TOO_BIG_HOP_LIMIT: u8 = 64;
+/// Least time between two Packet Too Big messages of a path (RFC 4443
+/// section 2.4 (f))
+const PACKET_TOO_BIG_INTERVAL: Duration = Duration::from_millis(100);
+const IPV4_OPTION_END: u8 = 0;
+const IPV4_OPTION_NOP: u8 = 1;
+/// Option type bit of the options repeated in every fragment
+const IPV4_OPTION_COPIED: u8 = 0x80;
+
+/// What happens to a frame too large for the egress interface
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OversizePolicy {
+    /// Drop it
+    Drop,
+    /// Fragment IPv4 packets without the don't fragment flag, drop the rest
+    Fragment,
+}
+
+/// Frames to send in place of an oversized one
+pub enum Fit {
+    /// The frame fits as it is
+    Fits,
+    /// Fragments of the packet, each fitting the MTU
+    Fragments(Vec<Vec<u8>>),
+    /// The frame is dropped
+    TooBig,
+}
+
+/// MTU of the egress interface of a path and what happens to the frames
+/// exceeding it
+#[derive(Clone)]
+pub struct Oversize {
+    mtu: usize,
+    policy: OversizePolicy,
+    /// Sends Packet Too Big on the ingress interface, if enabled and the
+    /// interface has an IPv6 address
+    too_big: Option<TooBig>,
+}
+
+/// Packet Too Big messages sent on the ingress interface, at most one per
+/// [`PACKET_TOO_BIG_INTERVAL`]
+#[derive(Clone)]
+struct TooBig {
+    responder: Responder,
+    last: Arc<Mutex<Option<Instant>>>,
+}
+
+impl Oversize {
+    pub fn new(mtu: usize, policy: OversizePolicy, responder: Option<Responder>) -> Self {
+        Oversize {
+            mtu,
+            policy,
+            too_big: responder.map(|responder| TooBig {
+                responder,
+                last: Default::default(),
+            }),
+        }
+    }
+
+    /// Whether `frame`, without VLAN tags, fits the MTU and if not, what is
+    /// sent instead
+    pub fn fit(&self, frame: &[u8]) -> Fit {
+        if frame.len() <= ETHERNET_HEADER_LEN + self.mtu {
+            return Fit::Fits;
+        }
+        let Some(eth) = EthernetPacket::new(frame) else {
+            return Fit::TooBig;
+        };
+        match eth.get_ethertype() {
+            EtherTypes::Ipv4 if self.policy == OversizePolicy::Fragment => {
+                match fragment(frame, self.mtu) {
+                    Some(fragments) => Fit::Fragments(fragments),
+                    None => Fit::TooBig,
+                }
+            }
+            EtherTypes::Ipv6 => {
+                if let Some(too_big) = &self.too_big {
+                    too_big.send(frame, self.mtu);
+                }
+                Fit::TooBig
+            }
+            _ => Fit::TooBig,
+        }
+    }
+}
+
+impl TooBig {
+    fn send(&self, frame: &[u8], mtu: usize) {
+        let Some(source) = self.responder.ipv6() else {
+            return;
+        };
+        let mut last = self.last.lock().unwrap();
+        let now = Instant::now();
+        if last.is_some_and(|last| now.duration_since(last) < PACKET_TOO_BIG_INTERVAL) {
+            return;
+        }
+        let Some(reply) = packet_too_big(frame, mtu, (self.responder.mac(), source)) else {
+            return;
+        };
+        if self.responder.send_frame(reply) {
+            *last = Some(now);
+            debug!("Packet Too Big sent for a frame exceeding MTU {}", mtu);
+        }
+    }
+}
+
+/// Splits the IPv4 packet in `frame` into fragments of at most `mtu`
+/// bytes, each in a copy of the Ethernet header. `None` if the packet may
+/// not be fragmented or is malformed.
+pub fn fragment(frame: &[u8], mtu: usize) -> Option<Vec<Vec<u8>>> {
+    let (eth, l3) = frame.split_at_checked(ETHERNET_HEADER_LEN)?;
+    let ip = Ipv4Packet::new(l3)?;
+    let header_len = usize::from(ip.get_header_length()) * 4;
+    let total_len = usize::from(ip.get_total_length());
+    if ip.get_flags() & Ipv4Flags::DontFragment != 0
+        || header_len < Ipv4Packet::minimum_packet_size()
+        || total_len < header_len
+        || total_len > l3.len()
+    {
+        return None;
+    }
+    let (header, payload) = l3[..total_len].split_at(header_len);
+    let more = ip.get_flags() & Ipv4Flags::MoreFragments != 0;
+    let offset = usize::from(ip.get_fragment_offset()) * 8;
+    let later_header = copied_options(header);
+
+    let mut fragments = Vec::new();
+    let mut start = 0;
+    while start < payload.len() {
+        let header = if start == 0 { header } else { &later_header };
+        // Every fragment but the last carries a multiple of 8 bytes
+        let room = mtu.checked_sub(header.len())? & !7;
+        if room == 0 {
+            return None;
+        }
+        let end = payload.len().min(start + room);
+        let last = end == payload.len();
+        let mut fragment = Vec::with_capacity(eth.len() + header.len() + end - start);
+        fragment.extend_from_slice(eth);
+        fragment.extend_from_slice(header);
+        fragment.extend_from_slice(&payload[start..end]);
+        let mut ip = MutableIpv4Packet::new(&mut fragment[ETHERNET_HEADER_LEN..])?;
+        ip.set_header_length((header.len() / 4) as u8);
+        ip.set_total_length((header.len() + end - start) as u16);
+        ip.set_flags(if !last || more {
+            Ipv4Flags::MoreFragments
+        } else {
+            0
+        });
+        ip.set_fragment_offset(((offset + start) / 8) as u16);
+        ip.set_checksum(ipv4::checksum(&ip.to_immutable()));
+        fragments.push(fragment);
+        start = end;
+    }
+    Some(fragments)
+}
+
+/// Header of the fragments after the first, which only keep the options
+/// with the copied flag (RFC 791)
+fn copied_options(header: &[u8]) -> Vec<u8> {
+    let (fixed, options) = header.split_at(Ipv4Packet::minimum_packet_size());
+    let mut copied = fixed.to_vec();
+    let mut at = 0;
+    while let Some(&kind) = options.get(at) {
+        let len = match kind {
+            IPV4_OPTION_END => break,
+            IPV4_OPTION_NOP => 1,
+            _ => match options.get(at + 1) {
+                Some(&len) if len >= 2 => usize::from(len),
+                _ => break,
+            },
+        };
+        let Some(option) = options.get(at..at + len) else {
+            break;
+        };
+        if kind & IPV4_OPTION_COPIED != 0 {
+            copied.extend_from_slice(option);
+        }
+        at += len;
+    }
+    copied.resize(copied.len().next_multiple_of(4), IPV4_OPTION_END);
+    copied
+}
+
+/// ICMPv6 Packet Too Big telling the sender of the IPv6 packet in `frame`
+/// to fit `mtu`, sent from `from`, quoting as much of the packet as fits
+/// the IPv6 minimum MTU
+fn packet_too_big(frame: &[u8], mtu: usize, from: (MacAddr, Ipv6Addr)) -> Option<Vec<u8>> {
+    let eth = EthernetPacket::new(frame)?;
+    let ip = Ipv6Packet::new(eth.payload())?;
+    let sender = ip.get_source();
+    if sender.is_unspecified() || sender.is_multicast() {
+        return None;
+    }
+    let quoted = &eth.payload()[..eth
+        .payload()
+        .len()
+        .min(IPV6_MIN_MTU - IPV6_HEADER_LEN - ICMPV6_HEADER_LEN)];
+    let icmp_len = ICMPV6_HEADER_LEN + quoted.len();
+    let mut reply = vec![0; ETHERNET_HEADER_LEN + IPV6_HEADER_LEN + icmp_len];
+    let mut reply_eth = MutableEthernetPacket::new(&mut reply)?;
+    reply_eth.set_destination(eth.get_source());
+    reply_eth.set_source(from.0);
+    reply_eth.set_ethertype(EtherTypes::Ipv6);
+
+    let (header, icmp) = reply[ETHERNET_HEADER_LEN..].split_at_mut(IPV6_HEADER_LEN);
+    icmp[0] = ICMPV6_PACKET_TOO_BIG;
+    icmp[4..8].copy_from_slice(&(mtu as u32).to_be_bytes());
+    icmp[ICMPV6_HEADER_LEN..].copy_from_slice(quoted);
+    let checksum = icmpv6::checksum(&Icmpv6Packet::new(icmp)?, &from.1, &sender);
+    icmp[2..4].copy_from_slice(&checksum.to_be_bytes());
+
+    let mut reply_ip = MutableIpv6Packet::new(header)?;
+    reply_ip.set_version(6);
+    reply_ip.set_payload_length(icmp_len as u16);
+    reply_ip.set_next_header(IpNextHeaderProtocols::Icmpv6);
+    reply_ip.set_hop_limit(PACKET_TOO_BIG_HOP_LIMIT);
+    reply_ip.set_source(from.1);
+    reply_ip.set_destination(sender);
+    Some(reply)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{self, HOST_IP, HOST_MAC, IPV4_HEADER_LEN, UDP_HEADER_LEN};
+    use pnet::packet::udp::{self, UdpPacket};
+    use std::net::Ipv4Addr;
+
+    const OWN_MAC: MacAddr = MacAddr(0x02, 0, 0, 0, 0, 0xfe);
+    const MTU: usize = 1500;
+
+    fn jumbo_frame(payload: &[u8]) -> Vec<u8> {
+        testutil::udp_frame(
+            HOST_MAC,
+            OWN_MAC,
+            HOST_IP,
+            Ipv4Addr::new(192, 168, 100, 9),
+            50000,
+            8009,
+            payload,
+        )
+    }
+
+    #[test]
+    fn fragments_jumbo_ipv4_frames() {
+        let payload: Vec<u8> = (0..4000).map(|i| i as u8).collect();
+        let frame = jumbo_frame(&payload);
+        let fragments = fragment(&frame, MTU).unwrap();
+        assert_eq!(fragments.len(), 3);
+
+        let mut datagram = Vec::new();
+        for (i, fragment) in fragments.iter().enumerate() {
+            assert!(fragment.len() <= ETHERNET_HEADER_LEN + MTU);
+            assert_eq!(
+                fragment[..ETHERNET_HEADER_LEN],
+                frame[..ETHERNET_HEADER_LEN]
+            );
+            let ip = Ipv4Packet::new(&fragment[ETHERNET_HEADER_LEN..]).unwrap();
+            assert_eq!(ip.get_checksum(), ipv4::checksum(&ip));
+            assert_eq!(usize::from(ip.get_fragment_offset()) * 8, datagram.len());
+            let more = ip.get_flags() & Ipv4Flags::MoreFragments != 0;
+            assert_eq!(more, i < 2);
+            assert_eq!(usize::from(ip.get_total_length()), ip.packet().len());
+            datagram.extend_from_slice(ip.payload());
+        }
+        assert_eq!(datagram.len(), UDP_HEADER_LEN + payload.len());
+        let udp = UdpPacket::new(&datagram).unwrap();
+        assert_eq!(udp.payload(), payload);
+        let destination = Ipv4Addr::new(192, 168, 100, 9);
+        assert_eq!(
+            udp.get_checksum(),
+            udp::ipv4_checksum(&udp, &HOST_IP, &destination)
+        );
+
+        // Only options with the copied flag are repeated
+        let header = [&[0x47][..], &[0; 19], &[0x07, 3, 0], &[0x94, 4, 0, 0], &[1]].concat();
+        assert_eq!(copied_options(&header)[IPV4_HEADER_LEN..], [0x94, 4, 0, 0]);
+
+        let mut df = frame.clone();
+        df[ETHERNET_HEADER_LEN + 6] |= 0x40;
+        assert!(fragment(&df, MTU).is_none());
+        let policy = Oversize::new(MTU, OversizePolicy::Drop, None);
+        assert!(matches!(policy.fit(&frame), Fit::TooBig));
+        assert!(matches!(policy.fit(&jumbo_frame(&[0; 100])), Fit::Fits));
+    }
+
+    #[test]
+    fn answers_too_big_ipv6_packets() {
+        let sender: Ipv6Addr = "fe80::1".parse().unwrap();
+        let own: Ipv6Addr = "fe80::fe".parse().unwrap();
+        let frame = testutil::udp_frame(
+            HOST_MAC,
+            OWN_MAC,
+            sender,
+            "fe80::9".parse::<Ipv6Addr>().unwrap(),
+            50000,
+            8009,
+            &[0; 3000],
+        );
+        let reply = packet_too_big(&frame, MTU, (OWN_MAC, own)).unwrap();
+        assert_eq!(reply.len(), ETHERNET_HEADER_LEN + IPV6_MIN_MTU);
+        let eth = EthernetPacket::new(&reply).unwrap();
+        assert_eq!(eth.get_destination(), HOST_MAC);
+        let ip = Ipv6Packet::new(eth.payload()).unwrap();
+        assert_eq!((ip.get_source(), ip.get_destination()), (own, sender));
+        let icmp = Icmpv6Packet::new(ip.payload()).unwrap();
+        assert_eq!(icmp.get_icmpv6_type().0, ICMPV6_PACKET_TOO_BIG);
+        assert_eq!(icmp.get_checksum(), icmpv6::checksum(&icmp, &own, &sender));
+        assert_eq!(icmp.payload()[..4], (MTU as u32).to_be_bytes());
+        assert_eq!(icmp.payload()[4..44], frame[ETHERNET_HEADER_LEN..][..40]);
+    }
+}
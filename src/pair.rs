@@ -0,0 +1,222 @@
+//! External/internal interface pairs managed by one forwarder process.
+
+use crate::error::Error;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// How an interface is used: one side of a pair or a bridge port
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    External,
+    Internal,
+    Bridge,
+}
+
+/// Direction a forwarding path carries frames in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum Direction {
+    #[serde(rename = "external->internal")]
+    Inbound,
+    #[serde(rename = "internal->external")]
+    Outbound,
+    #[serde(rename = "bridge")]
+    Bridged,
+}
+
+impl Direction {
+    /// Name in logs and statistics, as serialized
+    pub fn name(self) -> &'static str {
+        match self {
+            Direction::Inbound => "external->internal",
+            Direction::Outbound => "internal->external",
+            Direction::Bridged => "bridge",
+        }
+    }
+}
+
+/// One forwarding pair between an external and an internal interface
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Pair {
+    pub external: String,
+    pub internal: String,
+}
+
+impl fmt::Display for Pair {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}<->{}", self.external, self.internal)
+    }
+}
+
+/// Parses a pair given as `external:IFACE,internal:IFACE`
+pub fn parse_pair(value: &str) -> Result<Pair, String> {
+    let (mut external, mut internal) = (None, None);
+    for part in value.split(',') {
+        let (key, name) = part
+            .split_once(':')
+            .ok_or_else(|| format!("expected KEY:IFACE, got '{}'", part))?;
+        let slot = match key.trim() {
+            "external" => &mut external,
+            "internal" => &mut internal,
+            other => {
+                return Err(format!(
+                    "unknown key '{}', expected external or internal",
+                    other
+                ))
+            }
+        };
+        let name = name.trim();
+        if name.is_empty() {
+            return Err(format!("missing interface name for {}", key));
+        }
+        if slot.replace(name.to_string()).is_some() {
+            return Err(format!("{} given more than once", key));
+        }
+    }
+    let (Some(external), Some(internal)) = (external, internal) else {
+        return Err("both external:IFACE and internal:IFACE are required".to_string());
+    };
+    if external == internal {
+        return Err(format!("{} cannot be both external and internal", external));
+    }
+    Ok(Pair { external, internal })
+}
+
+/// Returns every interface used by `pairs` once, in order of first use,
+/// with the side it is on. An interface may be shared by several pairs but
+/// only ever on the same side.
+pub fn interface_roles(pairs: &[Pair]) -> Result<Vec<(&str, Role)>, Error> {
+    let mut roles: Vec<(&str, Role)> = Vec::new();
+    for (i, pair) in pairs.iter().enumerate() {
+        if pair.external == pair.internal {
+            return Err(Error::InvalidPairs(format!(
+                "{} cannot be both external and internal",
+                pair.external
+            )));
+        }
+        if pairs[..i].contains(pair) {
+            return Err(Error::InvalidPairs(format!(
+                "{} given more than once",
+                pair
+            )));
+        }
+        for (name, role) in [
+            (pair.external.as_str(), Role::External),
+            (pair.internal.as_str(), Role::Internal),
+        ] {
+            match roles.iter().find(|(seen, _)| *seen == name) {
+                Some((_, seen)) if *seen != role => {
+                    return Err(Error::InvalidPairs(format!(
+                        "{} is used as both an external and an internal interface",
+                        name
+                    )));
+                }
+                Some(_) => {}
+                None => roles.push((name, role)),
+            }
+        }
+    }
+    Ok(roles)
+}
+
+/// Returns the bridge ports in `names`, which must be at least two distinct
+/// interfaces
+pub fn bridge_roles(names: &[String]) -> Result<Vec<(&str, Role)>, Error> {
+    for (i, name) in names.iter().enumerate() {
+        if names[..i].contains(name) {
+            return Err(Error::InvalidBridge(format!(
+                "{} given more than once",
+                name
+            )));
+        }
+    }
+    if names.len() < 2 {
+        return Err(Error::InvalidBridge(
+            "at least two interfaces are required".to_string(),
+        ));
+    }
+    Ok(names
+        .iter()
+        .map(|name| (name.as_str(), Role::Bridge))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pair(external: &str, internal: &str) -> Pair {
+        Pair {
+            external: external.to_string(),
+            internal: internal.to_string(),
+        }
+    }
+
+    #[test]
+    fn parses_pairs_in_either_order() {
+        assert_eq!(
+            parse_pair("external:eth0,internal:vm1"),
+            Ok(pair("eth0", "vm1"))
+        );
+        assert_eq!(
+            parse_pair(" internal: vm1 , external:eth0"),
+            Ok(pair("eth0", "vm1"))
+        );
+        for (value, error) in [
+            ("eth0,vm1", "expected KEY:IFACE"),
+            ("external:eth0,inside:vm1", "unknown key 'inside'"),
+            ("external:eth0,internal:", "missing interface name"),
+            ("external:eth0,external:eth1", "given more than once"),
+            ("external:eth0", "are required"),
+            ("external:eth0,internal:eth0", "both external and internal"),
+        ] {
+            let message = parse_pair(value).unwrap_err();
+            assert!(message.contains(error), "{}: {}", value, message);
+        }
+    }
+
+    #[test]
+    fn shares_interfaces_between_pairs_only_on_the_same_side() {
+        let pairs = [
+            pair("eth0", "vm1"),
+            pair("eth0", "vm2"),
+            pair("eth1", "vm2"),
+        ];
+        let roles = interface_roles(&pairs).unwrap();
+        assert_eq!(
+            roles,
+            [
+                ("eth0", Role::External),
+                ("vm1", Role::Internal),
+                ("vm2", Role::Internal),
+                ("eth1", Role::External),
+            ]
+        );
+
+        let crossed = [pair("eth0", "vm1"), pair("vm1", "vm2")];
+        let repeated = [pair("eth0", "vm1"), pair("eth0", "vm1")];
+        for pairs in [&crossed[..], &repeated[..]] {
+            assert!(matches!(
+                interface_roles(pairs),
+                Err(Error::InvalidPairs(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn needs_two_distinct_bridge_ports() {
+        let names = |names: &[&str]| {
+            names
+                .iter()
+                .map(|name| name.to_string())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(bridge_roles(&names(&["eth0", "eth1"])).unwrap().len(), 2);
+        for ports in [&["eth0"][..], &["eth0", "eth0"]] {
+            assert!(matches!(
+                bridge_roles(&names(ports)),
+                Err(Error::InvalidBridge(_))
+            ));
+        }
+    }
+}
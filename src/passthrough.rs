@@ -0,0 +1,82 @@
+//! Link-layer control protocols passed between the two interfaces of a pair
+//! as they are, ahead of every filter: EAPOL, for the internal side to
+//! authenticate itself on an 802.1X port, and LLDP, for the switch to see
+//! the endpoint behind the forwarder.
+
+use std::fmt;
+
+const ETHERTYPE_EAPOL: u16 = 0x888e;
+const ETHERTYPE_LLDP: u16 = 0x88cc;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ControlProtocol {
+    Eapol,
+    Lldp,
+}
+
+impl fmt::Display for ControlProtocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ControlProtocol::Eapol => "EAPOL",
+            ControlProtocol::Lldp => "LLDP",
+        })
+    }
+}
+
+/// Control protocols a path passes through, none by default
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PassThrough {
+    pub eapol: bool,
+    pub lldp: bool,
+}
+
+impl PassThrough {
+    /// Protocol of an untagged `frame`, if it is one passed through
+    pub fn protocol(&self, frame: &[u8]) -> Option<ControlProtocol> {
+        let ethertype = frame.get(12..14)?;
+        match u16::from_be_bytes([ethertype[0], ethertype[1]]) {
+            ETHERTYPE_EAPOL if self.eapol => Some(ControlProtocol::Eapol),
+            ETHERTYPE_LLDP if self.lldp => Some(ControlProtocol::Lldp),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// EAPOL-Start to the port access entity group address
+    const EAPOL_START: [u8; 18] = [
+        0x01, 0x80, 0xc2, 0x00, 0x00, 0x03, 0x02, 0x00, 0x00, 0x00, 0x00, 0x01, 0x88, 0x8e, 0x02,
+        0x01, 0x00, 0x00,
+    ];
+
+    #[test]
+    fn passes_only_the_enabled_protocols() {
+        let mut lldp = EAPOL_START;
+        lldp[5] = 0x0e;
+        lldp[12..14].copy_from_slice(&ETHERTYPE_LLDP.to_be_bytes());
+
+        let none = PassThrough::default();
+        assert_eq!(none.protocol(&EAPOL_START), None);
+        assert_eq!(none.protocol(&lldp), None);
+
+        let eapol = PassThrough {
+            eapol: true,
+            lldp: false,
+        };
+        assert_eq!(eapol.protocol(&EAPOL_START), Some(ControlProtocol::Eapol));
+        assert_eq!(eapol.protocol(&lldp), None);
+
+        let both = PassThrough {
+            eapol: true,
+            lldp: true,
+        };
+        assert_eq!(both.protocol(&lldp), Some(ControlProtocol::Lldp));
+        assert_eq!(both.protocol(&EAPOL_START[..13]), None);
+        let mut ipv4 = EAPOL_START;
+        ipv4[12..14].copy_from_slice(&[0x08, 0x00]);
+        assert_eq!(both.protocol(&ipv4), None);
+    }
+}
@@ -0,0 +1,134 @@
+//! Runtime switch turning forwarding off and on without closing the
+//! interfaces or losing learned state. Capture loops drop what they receive
+//! while it is off, and send threads drop what is still queued, so nothing
+//! crosses once pausing has returned. A standby instance only has sending
+//! switched off, so it keeps learning from what it receives.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{RwLock, RwLockReadGuard};
+use tokio::sync::Notify;
+use tracing::info;
+
+/// Whether forwarding is paused, shared by the capture loops, the send
+/// threads and everything that pauses or resumes it
+#[derive(Debug, Default)]
+pub struct Pause {
+    paused: AtomicBool,
+    /// Set while another instance leads, see [`crate::standby`]
+    standby: AtomicBool,
+    /// Held for reading by send threads while they send, so that pausing
+    /// waits for the frames already being sent
+    sending: RwLock<()>,
+    /// Woken on every change
+    changed: Notify,
+}
+
+impl Pause {
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Acquire)
+    }
+
+    /// Pauses or resumes forwarding. Pausing returns once no frame is being
+    /// sent anymore. Returns `false` if it was set that way already.
+    pub fn set(&self, pause: bool) -> bool {
+        let changed = self.paused.swap(pause, Ordering::AcqRel) != pause;
+        if changed {
+            self.changed_to(pause);
+        }
+        changed
+    }
+
+    /// Resumes forwarding if paused and pauses it otherwise. Returns whether
+    /// it is paused now.
+    pub fn toggle(&self) -> bool {
+        let pause = !self.paused.fetch_xor(true, Ordering::AcqRel);
+        self.changed_to(pause);
+        pause
+    }
+
+    /// Waits for the sends that started before pausing, then tells about
+    /// the change
+    fn changed_to(&self, pause: bool) {
+        if pause {
+            self.wait_for_senders();
+        }
+        info!("Forwarding {}", if pause { "paused" } else { "resumed" });
+        self.changed.notify_waiters();
+    }
+
+    fn wait_for_senders(&self) {
+        // Senders check the flags only once they hold the lock, so the ones
+        // taking it from now on see them set
+        drop(self.sending.write().unwrap());
+    }
+
+    pub fn is_standby(&self) -> bool {
+        self.standby.load(Ordering::Acquire)
+    }
+
+    /// Stops or resumes sending while receiving goes on. Stopping returns
+    /// once no frame is being sent anymore. Returns `false` if it was set
+    /// that way already.
+    pub fn set_standby(&self, standby: bool) -> bool {
+        let changed = self.standby.swap(standby, Ordering::AcqRel) != standby;
+        if changed {
+            if standby {
+                self.wait_for_senders();
+            }
+            self.changed.notify_waiters();
+        }
+        changed
+    }
+
+    /// Guard to hold while sending, `None` if paused or standing by.
+    /// Pausing waits until the guard is dropped.
+    pub fn sending(&self) -> Option<RwLockReadGuard<'_, ()>> {
+        let guard = self.sending.read().unwrap();
+        (!self.is_paused() && !self.is_standby()).then_some(guard)
+    }
+
+    /// Waits for the next time forwarding is paused or resumed, or sending
+    /// stopped or resumed for standby
+    pub async fn changed(&self) {
+        self.changed.notified().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn pausing_waits_for_frames_being_sent() {
+        let pause = Arc::new(Pause::default());
+        let sending = pause.sending().expect("not paused");
+
+        let (done_tx, done_rx) = mpsc::channel();
+        let pausing = {
+            let pause = pause.clone();
+            thread::spawn(move || {
+                let changed = pause.set(true);
+                done_tx.send(changed).unwrap();
+            })
+        };
+        assert!(done_rx.recv_timeout(Duration::from_millis(100)).is_err());
+        drop(sending);
+        assert!(done_rx.recv_timeout(Duration::from_secs(5)).unwrap());
+        pausing.join().unwrap();
+
+        assert!(pause.is_paused() && pause.sending().is_none());
+        assert!(!pause.set(true));
+        assert!(!pause.toggle());
+        assert!(pause.sending().is_some());
+
+        assert!(pause.set_standby(true));
+        assert!(!pause.is_paused() && pause.sending().is_none());
+        assert!(!pause.set_standby(true));
+        assert!(pause.set_standby(false));
+        assert!(pause.sending().is_some());
+    }
+}
@@ -0,0 +1,379 @@
+//! Writing forwarded and dropped frames to pcap files off the capture path,
+//! and reading recorded frames back for replay.
+
+use crate::error::Error;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tokio::task::JoinHandle;
+use tracing::{debug, error, warn};
+
+/// Frames waiting to be written before new ones are dropped
+const QUEUE_CAPACITY: usize = 4096;
+/// Magic number of pcap files with nanosecond timestamps
+const MAGIC_NANOS: u32 = 0xa1b2_3c4d;
+/// Magic number of pcap files with microsecond timestamps
+const MAGIC_MICROS: u32 = 0xa1b2_c3d4;
+const LINKTYPE_ETHERNET: u32 = 1;
+const SNAPLEN: u32 = 262_144;
+const FILE_HEADER_LEN: u64 = 24;
+const RECORD_HEADER_LEN: u64 = 16;
+
+/// Handle used by capture loops to queue frames for one pcap file
+#[derive(Clone)]
+pub struct PcapSink {
+    tx: mpsc::Sender<(SystemTime, Vec<u8>)>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl PcapSink {
+    /// Queues `frame` without blocking. If the writer falls behind the frame
+    /// is left out of the file and counted.
+    pub fn write(&self, at: SystemTime, frame: Vec<u8>) {
+        if let Err(TrySendError::Full(_)) = self.tx.try_send((at, frame)) {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Pcap files forwarded and dropped frames are written to, if enabled
+#[derive(Clone, Default)]
+pub struct PcapSinks {
+    pub forwarded: Option<PcapSink>,
+    pub dropped: Option<PcapSink>,
+}
+
+/// Pcap file that is rotated once it would exceed `max_size`
+struct PcapFile {
+    path: PathBuf,
+    max_size: Option<u64>,
+    out: BufWriter<File>,
+    size: u64,
+}
+
+impl PcapFile {
+    fn create(path: &Path, max_size: Option<u64>) -> io::Result<Self> {
+        let mut out = BufWriter::new(File::create(path)?);
+        out.write_all(&MAGIC_NANOS.to_le_bytes())?;
+        out.write_all(&2u16.to_le_bytes())?;
+        out.write_all(&4u16.to_le_bytes())?;
+        // Time zone offset and timestamp accuracy, both unused
+        out.write_all(&[0; 8])?;
+        out.write_all(&SNAPLEN.to_le_bytes())?;
+        out.write_all(&LINKTYPE_ETHERNET.to_le_bytes())?;
+        Ok(PcapFile {
+            path: path.to_path_buf(),
+            max_size,
+            out,
+            size: FILE_HEADER_LEN,
+        })
+    }
+
+    fn write(&mut self, at: SystemTime, frame: &[u8]) -> io::Result<()> {
+        let captured = frame.len().min(SNAPLEN as usize);
+        let record_len = RECORD_HEADER_LEN + captured as u64;
+        let full = self
+            .max_size
+            .is_some_and(|max| self.size + record_len > max);
+        if full && self.size > FILE_HEADER_LEN {
+            self.rotate()?;
+        }
+        let since = at.duration_since(UNIX_EPOCH).unwrap_or_default();
+        self.out
+            .write_all(&(since.as_secs() as u32).to_le_bytes())?;
+        self.out.write_all(&since.subsec_nanos().to_le_bytes())?;
+        self.out.write_all(&(captured as u32).to_le_bytes())?;
+        self.out.write_all(&(frame.len() as u32).to_le_bytes())?;
+        self.out.write_all(&frame[..captured])?;
+        self.size += record_len;
+        Ok(())
+    }
+
+    /// Moves the file to `<path>.1`, replacing an earlier one, and starts a
+    /// new file at `path`
+    fn rotate(&mut self) -> io::Result<()> {
+        self.out.flush()?;
+        let mut rotated = self.path.clone().into_os_string();
+        rotated.push(".1");
+        fs::rename(&self.path, &rotated)?;
+        debug!("Rotated {}", self.path.display());
+        let path = self.path.clone();
+        *self = PcapFile::create(&path, self.max_size)?;
+        Ok(())
+    }
+}
+
+/// Creates the pcap file at `path` and spawns the task writing frames
+/// queued on the returned sink to it. The file is flushed and closed once
+/// every [`PcapSink`] clone is dropped.
+pub fn spawn_writer(
+    path: &Path,
+    max_size: Option<u64>,
+) -> Result<(PcapSink, JoinHandle<()>), Error> {
+    let mut file = PcapFile::create(path, max_size).map_err(|source| Error::Pcap {
+        path: path.to_path_buf(),
+        source,
+    })?;
+    let (tx, mut rx) = mpsc::channel(QUEUE_CAPACITY);
+    let sink = PcapSink {
+        tx,
+        dropped: Arc::default(),
+    };
+    let dropped = sink.dropped.clone();
+    let handle = tokio::task::spawn_blocking(move || {
+        while let Some((at, frame)) = rx.blocking_recv() {
+            if let Err(e) = file.write(at, &frame) {
+                error!("Failed to write {}: {}", file.path.display(), e);
+                break;
+            }
+        }
+        if let Err(e) = file.out.flush() {
+            error!("Failed to write {}: {}", file.path.display(), e);
+        }
+        let dropped = dropped.load(Ordering::Relaxed);
+        if dropped > 0 {
+            warn!(
+                "{} frame(s) left out of {}, writer fell behind",
+                dropped,
+                file.path.display()
+            );
+        }
+        debug!("Pcap writer for {} stopped", file.path.display());
+    });
+    Ok((sink, handle))
+}
+
+/// Pcap file of Ethernet frames read for replay, in either byte order and
+/// with microsecond or nanosecond timestamps
+pub struct PcapReader {
+    path: PathBuf,
+    input: BufReader<File>,
+    swapped: bool,
+    nanos: bool,
+}
+
+impl PcapReader {
+    pub fn open(path: &Path) -> Result<Self, Error> {
+        let read_error = |source| Error::PcapRead {
+            path: path.to_path_buf(),
+            source,
+        };
+        let mut input = BufReader::new(File::open(path).map_err(read_error)?);
+        let mut header = [0; FILE_HEADER_LEN as usize];
+        input.read_exact(&mut header).map_err(read_error)?;
+        let magic = u32::from_le_bytes(header[..4].try_into().unwrap());
+        let (swapped, nanos) = match magic {
+            MAGIC_MICROS => (false, false),
+            MAGIC_NANOS => (false, true),
+            _ if magic.swap_bytes() == MAGIC_MICROS => (true, false),
+            _ if magic.swap_bytes() == MAGIC_NANOS => (true, true),
+            _ => return Err(read_error(invalid_data("not a pcap file"))),
+        };
+        let reader = PcapReader {
+            path: path.to_path_buf(),
+            input,
+            swapped,
+            nanos,
+        };
+        let link_type = reader.field(&header[20..24]);
+        if link_type != LINKTYPE_ETHERNET {
+            let message = format!("link type {} is not Ethernet", link_type);
+            return Err(read_error(invalid_data(&message)));
+        }
+        Ok(reader)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns the next frame with its capture time since the Unix epoch,
+    /// or `None` at the end of the file
+    pub fn next_frame(&mut self) -> io::Result<Option<(Duration, Vec<u8>)>> {
+        let mut header = [0; RECORD_HEADER_LEN as usize];
+        match self.input.read_exact(&mut header) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+        }
+        let secs = self.field(&header[..4]);
+        let fraction = self.field(&header[4..8]);
+        let captured = self.field(&header[8..12]);
+        if captured > SNAPLEN {
+            return Err(invalid_data("record longer than the snapshot length"));
+        }
+        let mut frame = vec![0; captured as usize];
+        self.input.read_exact(&mut frame)?;
+        let nanos = if self.nanos {
+            fraction
+        } else {
+            fraction.saturating_mul(1000)
+        };
+        Ok(Some((Duration::new(secs.into(), nanos), frame)))
+    }
+
+    fn field(&self, bytes: &[u8]) -> u32 {
+        let value = u32::from_le_bytes(bytes.try_into().unwrap());
+        if self.swapped {
+            value.swap_bytes()
+        } else {
+            value
+        }
+    }
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Parses a size in bytes with an optional `K`, `M` or `G` suffix (powers
+/// of 1024)
+pub fn parse_size(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let (digits, unit) = match s.char_indices().last() {
+        Some((i, c)) if c.is_ascii_alphabetic() => (&s[..i], c.to_ascii_uppercase()),
+        _ => (s, 'B'),
+    };
+    let shift = match unit {
+        'B' => 0,
+        'K' => 10,
+        'M' => 20,
+        'G' => 30,
+        _ => return Err(format!("unknown size unit '{}', use K, M or G", unit)),
+    };
+    let value: u64 = digits
+        .trim()
+        .parse()
+        .map_err(|_| format!("'{}' is not a size", s))?;
+    match value.checked_mul(1 << shift) {
+        Some(0) => Err("size must be greater than zero".to_string()),
+        Some(size) => Ok(size),
+        None => Err(format!("size '{}' is too large", s)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Trace of `frames` as other tools write them, in either byte order
+    /// and with microsecond or nanosecond timestamps
+    fn trace(big_endian: bool, nanos: bool, frames: &[(Duration, Vec<u8>)]) -> Vec<u8> {
+        let word = |value: u32| match big_endian {
+            true => value.to_be_bytes(),
+            false => value.to_le_bytes(),
+        };
+        let mut trace = word(if nanos { MAGIC_NANOS } else { MAGIC_MICROS }).to_vec();
+        let version = [2u16, 4u16].map(|half| match big_endian {
+            true => half.to_be_bytes(),
+            false => half.to_le_bytes(),
+        });
+        trace.extend(version.concat());
+        trace.extend([0; 8]);
+        trace.extend(word(SNAPLEN));
+        trace.extend(word(LINKTYPE_ETHERNET));
+        for (at, frame) in frames {
+            let fraction = match nanos {
+                true => at.subsec_nanos(),
+                false => at.subsec_micros(),
+            };
+            trace.extend(word(at.as_secs() as u32));
+            trace.extend(word(fraction));
+            trace.extend(word(frame.len() as u32));
+            trace.extend(word(frame.len() as u32));
+            trace.extend(frame);
+        }
+        trace
+    }
+
+    fn read_all(path: &Path) -> Vec<(Duration, Vec<u8>)> {
+        let mut reader = PcapReader::open(path).unwrap();
+        let mut frames = Vec::new();
+        while let Some(frame) = reader.next_frame().unwrap() {
+            frames.push(frame);
+        }
+        frames
+    }
+
+    #[test]
+    fn reads_traces_in_either_byte_order_and_resolution() {
+        let dir = std::env::temp_dir().join(format!("nw-pckt-fwd-pcap-in-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("trace.pcap");
+        let frames = vec![
+            (Duration::new(1_700_000_000, 123_456_000), vec![0xab; 60]),
+            (Duration::new(1_700_000_001, 0), vec![0xcd; 1514]),
+        ];
+
+        // As the forwarder writes them
+        let mut file = PcapFile::create(&path, None).unwrap();
+        for (at, frame) in &frames {
+            file.write(UNIX_EPOCH + *at, frame).unwrap();
+        }
+        file.out.flush().unwrap();
+        assert_eq!(read_all(&path), frames);
+
+        for (big_endian, nanos) in [(false, false), (true, false), (true, true)] {
+            fs::write(&path, trace(big_endian, nanos, &frames)).unwrap();
+            assert_eq!(read_all(&path), frames, "{} {}", big_endian, nanos);
+        }
+
+        // A truncated last record ends the trace with an error
+        let mut truncated = trace(false, true, &frames);
+        truncated.truncate(truncated.len() - 1);
+        fs::write(&path, truncated).unwrap();
+        let mut reader = PcapReader::open(&path).unwrap();
+        assert!(reader.next_frame().unwrap().is_some());
+        assert!(reader.next_frame().is_err());
+
+        // Neither other files nor other link types are read
+        fs::write(&path, [0; 24]).unwrap();
+        assert!(PcapReader::open(&path).is_err());
+        let mut raw_ip = trace(false, true, &frames);
+        raw_ip[20..24].copy_from_slice(&101u32.to_le_bytes());
+        fs::write(&path, raw_ip).unwrap();
+        assert!(PcapReader::open(&path).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rotates_files_that_would_grow_too_large() {
+        let dir = std::env::temp_dir().join(format!("nw-pckt-fwd-pcap-out-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("forwarded.pcap");
+        let frames: Vec<_> = (0..3u8)
+            .map(|n| (Duration::new(1_700_000_000 + u64::from(n), 0), vec![n; 60]))
+            .collect();
+        // Room for two records after the file header
+        let max_size = FILE_HEADER_LEN + 2 * (RECORD_HEADER_LEN + 60);
+        let mut file = PcapFile::create(&path, Some(max_size)).unwrap();
+        for (at, frame) in &frames {
+            file.write(UNIX_EPOCH + *at, frame).unwrap();
+        }
+        file.out.flush().unwrap();
+        assert_eq!(read_all(&dir.join("forwarded.pcap.1")), frames[..2]);
+        assert_eq!(read_all(&path), frames[2..]);
+
+        // A frame larger than the limit still goes into a file of its own
+        let mut file = PcapFile::create(&path, Some(FILE_HEADER_LEN)).unwrap();
+        file.write(UNIX_EPOCH, &[0; 60]).unwrap();
+        file.out.flush().unwrap();
+        assert_eq!(read_all(&path), [(Duration::ZERO, vec![0; 60])]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn parses_sizes_in_powers_of_1024() {
+        assert_eq!(parse_size("1500"), Ok(1500));
+        assert_eq!(parse_size("64k"), Ok(64 << 10));
+        assert_eq!(parse_size(" 10 M "), Ok(10 << 20));
+        assert_eq!(parse_size("2G"), Ok(2 << 30));
+        for invalid in ["", "0", "0M", "1T", "M", "-1", "17179869184G"] {
+            assert!(parse_size(invalid).is_err(), "{}", invalid);
+        }
+    }
+}
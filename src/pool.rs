@@ -0,0 +1,120 @@
+//! Reusable buffers for the copies of accepted frames, so forwarding a frame
+//! does not cost a heap allocation once the pool has warmed up.
+
+use crate::stats::PoolStats;
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Mutex};
+
+/// Free list of frame buffers. Up to `size` buffers are handed out at once;
+/// beyond that frames get buffers of their own, which are counted and freed
+/// after sending instead of kept.
+#[derive(Debug)]
+pub struct BufferPool {
+    free: Mutex<Vec<Vec<u8>>>,
+    /// Capacity of new buffers, enough for a frame of the largest MTU
+    buffer_len: usize,
+    stats: Arc<PoolStats>,
+}
+
+impl BufferPool {
+    pub fn new(size: usize, buffer_len: usize) -> Self {
+        BufferPool {
+            free: Mutex::new(Vec::with_capacity(size)),
+            buffer_len,
+            stats: Arc::new(PoolStats::new(size, buffer_len)),
+        }
+    }
+
+    pub fn stats(&self) -> Arc<PoolStats> {
+        self.stats.clone()
+    }
+
+    /// Buffer holding a copy of `frame`, from `pool` if there is one
+    pub fn copy(pool: Option<&Arc<BufferPool>>, frame: &[u8]) -> PacketBuffer {
+        let Some(pool) = pool else {
+            return frame.to_vec().into();
+        };
+        let pooled = pool.stats.take();
+        let free = if pooled {
+            pool.free.lock().unwrap().pop()
+        } else {
+            None
+        };
+        // The pool grows as needed, up to its size
+        let mut data = free.unwrap_or_else(|| Vec::with_capacity(pool.buffer_len.max(frame.len())));
+        data.extend_from_slice(frame);
+        PacketBuffer {
+            data,
+            pool: Some((pool.clone(), pooled)),
+        }
+    }
+
+    fn give_back(&self, mut data: Vec<u8>, pooled: bool) {
+        self.stats.give_back();
+        if pooled {
+            data.clear();
+            self.free.lock().unwrap().push(data);
+        }
+    }
+}
+
+/// Frame on its way to a send queue, returned to its pool once dropped
+#[derive(Debug)]
+pub struct PacketBuffer {
+    data: Vec<u8>,
+    /// Pool the buffer is accounted to, and whether it goes back on the
+    /// free list
+    pool: Option<(Arc<BufferPool>, bool)>,
+}
+
+impl From<Vec<u8>> for PacketBuffer {
+    fn from(data: Vec<u8>) -> Self {
+        PacketBuffer { data, pool: None }
+    }
+}
+
+impl Deref for PacketBuffer {
+    type Target = Vec<u8>;
+
+    fn deref(&self) -> &Vec<u8> {
+        &self.data
+    }
+}
+
+impl DerefMut for PacketBuffer {
+    fn deref_mut(&mut self) -> &mut Vec<u8> {
+        &mut self.data
+    }
+}
+
+impl Drop for PacketBuffer {
+    fn drop(&mut self) {
+        if let Some((pool, pooled)) = self.pool.take() {
+            pool.give_back(std::mem::take(&mut self.data), pooled);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reuses_buffers_and_allocates_beyond_the_pool() {
+        let pool = Arc::new(BufferPool::new(2, 1514));
+        let first = BufferPool::copy(Some(&pool), b"first");
+        let address = first.as_ptr();
+        assert_eq!(first.capacity(), 1514);
+        drop(first);
+        let second = BufferPool::copy(Some(&pool), b"second");
+        assert_eq!((second.as_ptr(), &second[..]), (address, &b"second"[..]));
+
+        let third = BufferPool::copy(Some(&pool), b"third");
+        let extra = BufferPool::copy(Some(&pool), b"extra");
+        let stats = pool.stats();
+        assert_eq!((stats.high_water(), stats.allocated()), (3, 1));
+        drop((second, third, extra));
+        assert_eq!(pool.free.lock().unwrap().len(), 2, "extra buffer freed");
+        assert_eq!(stats.in_use(), 0);
+    }
+}
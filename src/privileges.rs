@@ -0,0 +1,169 @@
+//! Switching to an unprivileged user once the interfaces and files that
+//! need root are open.
+
+use crate::error::Error;
+use libc::{gid_t, uid_t};
+use std::ffi::CString;
+use std::io;
+use std::mem::MaybeUninit;
+use std::ptr;
+use tracing::info;
+
+/// Initial buffer for the strings of a passwd or group entry, doubled while
+/// too small
+const LOOKUP_BUFFER_LEN: usize = 1024;
+
+/// User and group to switch to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Credentials {
+    /// As given, for log messages
+    user: String,
+    uid: uid_t,
+    gid: gid_t,
+}
+
+impl Credentials {
+    /// Looks up `user` and `group`, each a name or a numeric ID. Without
+    /// `group` the primary group of the user is taken.
+    pub fn resolve(user: &str, group: Option<&str>) -> Result<Self, Error> {
+        let unknown = |what, name: &str| Error::UnknownId {
+            what,
+            name: name.to_string(),
+        };
+        let entry = lookup("user", user, user_entry(user));
+        let (uid, primary) = match (user.parse::<uid_t>(), entry) {
+            (_, Ok((uid, gid))) => (uid, Some(gid)),
+            // Numeric IDs need no entry in /etc/passwd
+            (Ok(uid), Err(Error::UnknownId { .. })) => (uid, None),
+            (_, Err(e)) => return Err(e),
+        };
+        let gid = match group {
+            Some(group) => match group.parse::<gid_t>() {
+                Ok(gid) => gid,
+                Err(_) => lookup("group", group, group_id(group))?,
+            },
+            None => primary.ok_or_else(|| unknown("primary group of user", user))?,
+        };
+        Ok(Credentials {
+            user: user.to_string(),
+            uid,
+            gid,
+        })
+    }
+
+    /// Clears the supplementary groups, then switches group and user for all
+    /// threads. Checks that root cannot be regained afterwards.
+    pub fn apply(&self) -> Result<(), Error> {
+        let failed = |source| Error::DropPrivileges {
+            user: self.user.clone(),
+            source,
+        };
+        // SAFETY: plain system calls without pointers but the empty group list
+        unsafe {
+            if libc::setgroups(0, ptr::null()) != 0
+                || libc::setgid(self.gid) != 0
+                || libc::setuid(self.uid) != 0
+            {
+                return Err(failed(io::Error::last_os_error()));
+            }
+            if self.uid != 0 && libc::setuid(0) == 0 {
+                return Err(failed(io::Error::other("root could be regained")));
+            }
+        }
+        info!(
+            "Running as user {} (uid {}, gid {})",
+            self.user, self.uid, self.gid
+        );
+        Ok(())
+    }
+}
+
+/// Entry found for `name`, with a missing entry an [`Error::UnknownId`]
+fn lookup<T>(what: &'static str, name: &str, result: io::Result<Option<T>>) -> Result<T, Error> {
+    match result {
+        Ok(found) => found.ok_or_else(|| Error::UnknownId {
+            what,
+            name: name.to_string(),
+        }),
+        Err(source) => Err(Error::Lookup {
+            what,
+            name: name.to_string(),
+            source,
+        }),
+    }
+}
+
+/// Calls a reentrant lookup with a growing buffer until it fits
+fn with_buffer<T>(
+    mut call: impl FnMut(*mut MaybeUninit<T>, &mut [libc::c_char], *mut *mut T) -> libc::c_int,
+) -> io::Result<Option<T>> {
+    let mut buffer = vec![0; LOOKUP_BUFFER_LEN];
+    loop {
+        let mut entry = MaybeUninit::<T>::uninit();
+        let mut result = ptr::null_mut();
+        match call(&mut entry, &mut buffer, &mut result) {
+            0 if result.is_null() => return Ok(None),
+            // SAFETY: a non-null result points at the entry, now filled in
+            0 => return Ok(Some(unsafe { entry.assume_init() })),
+            libc::ERANGE => buffer.resize(buffer.len() * 2, 0),
+            code => return Err(io::Error::from_raw_os_error(code)),
+        }
+    }
+}
+
+/// UID and primary GID of the user called `name`
+fn user_entry(name: &str) -> io::Result<Option<(uid_t, gid_t)>> {
+    let name = CString::new(name).map_err(io::Error::other)?;
+    let entry = with_buffer(|entry, buffer, result| {
+        // SAFETY: every pointer is valid for the length passed along
+        unsafe {
+            libc::getpwnam_r(
+                name.as_ptr(),
+                entry.cast(),
+                buffer.as_mut_ptr(),
+                buffer.len(),
+                result,
+            )
+        }
+    })?;
+    Ok(entry.map(|entry: libc::passwd| (entry.pw_uid, entry.pw_gid)))
+}
+
+/// GID of the group called `name`
+fn group_id(name: &str) -> io::Result<Option<gid_t>> {
+    let name = CString::new(name).map_err(io::Error::other)?;
+    let entry = with_buffer(|entry, buffer, result| {
+        // SAFETY: every pointer is valid for the length passed along
+        unsafe {
+            libc::getgrnam_r(
+                name.as_ptr(),
+                entry.cast(),
+                buffer.as_mut_ptr(),
+                buffer.len(),
+                result,
+            )
+        }
+    })?;
+    Ok(entry.map(|entry: libc::group| entry.gr_gid))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolves_names_and_numeric_ids() {
+        let root = Credentials::resolve("root", None).unwrap();
+        assert_eq!((root.uid, root.gid), (0, 0));
+        let numeric = Credentials::resolve("65534", Some("0")).unwrap();
+        assert_eq!((numeric.uid, numeric.gid), (65534, 0));
+        assert!(matches!(
+            Credentials::resolve("no-such-user-here", None),
+            Err(Error::UnknownId { what: "user", .. })
+        ));
+        assert!(matches!(
+            Credentials::resolve("root", Some("no-such-group-here")),
+            Err(Error::UnknownId { what: "group", .. })
+        ));
+    }
+}
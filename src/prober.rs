@@ -0,0 +1,428 @@
+//! Active discovery: SSDP searches and mDNS queries sent on the external
+//! side on behalf of the internal side, for devices that only announce
+//! themselves when asked.
+//!
+//! mDNS queries are sent from the mDNS port as plain multicast queries, so
+//! the responses come back to the group and take the same path as the
+//! responses to a query from the internal side. SSDP responses come back by
+//! unicast to the forwarder instead; they are let in and forwarded inwards
+//! as the `ssdp:alive` NOTIFY the device would have sent, which the LOCATION
+//! rewrite, the SSDP cache and the device inventory then handle as usual.
+
+use crate::filter::{
+    Decision, Filter, PacketContext, MDNS_HOP_LIMIT, MDNS_IPV4_GROUP, MDNS_PORT, SSDP_PORT,
+};
+use crate::mdns::{self, Question, CLASS_IN, TYPE_PTR};
+use crate::pair::Direction;
+use crate::random::{random, random_up_to};
+use crate::ratelimit::RateLimiter;
+use crate::responder::{Destination, Responder};
+use crate::rewrite::{Rewrite, UdpDatagram};
+use crate::ssdp::{split_message, SsdpKind, SsdpMessage};
+use pnet::packet::ethernet::MutableEthernetPacket;
+use pnet::packet::ipv4::MutableIpv4Packet;
+use pnet::packet::Packet;
+use pnet::util::MacAddr;
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+use tracing::debug;
+
+const ETHERNET_HEADER_LEN: usize = 14;
+const SSDP_GROUP: Ipv4Addr = Ipv4Addr::new(239, 255, 255, 250);
+const SSDP_GROUP_MAC: MacAddr = MacAddr(0x01, 0x00, 0x5e, 0x7f, 0xff, 0xfa);
+const MDNS_GROUP_MAC: MacAddr = MacAddr(0x01, 0x00, 0x5e, 0x00, 0x00, 0xfb);
+/// Hop limit of SSDP multicast (UPnP Device Architecture 2.0 section 1.1.2)
+const SSDP_HOP_LIMIT: u8 = 2;
+/// Seconds devices may wait before responding to a search
+const SSDP_MX: u8 = 2;
+/// Headers of a response not carried over into the announcement made of it
+const RESPONSE_ONLY_HEADERS: [&str; 6] = ["HOST", "ST", "EXT", "DATE", "NT", "NTS"];
+
+/// Port in the dynamic range (RFC 6335) searches are sent from
+fn dynamic_port() -> u16 {
+    49152 + (random() % 16384) as u16
+}
+
+/// M-SEARCH for `target`
+fn search(target: &str) -> String {
+    format!(
+        "M-SEARCH * HTTP/1.1\r\nHOST: {}:{}\r\nMAN: \"ssdp:discover\"\r\nMX: {}\r\nST: {}\r\n\r\n",
+        SSDP_GROUP, SSDP_PORT, SSDP_MX, target
+    )
+}
+
+/// Multicast query for the PTR records of the service types `services`
+fn query(services: &[String]) -> Vec<u8> {
+    let questions: Vec<Question> = services
+        .iter()
+        .map(|service| Question {
+            name: service
+                .trim_end_matches('.')
+                .split('.')
+                .map(|label| label.as_bytes().to_vec())
+                .collect(),
+            qtype: TYPE_PTR,
+            class: CLASS_IN,
+        })
+        .collect();
+    mdns::write_query(&questions)
+}
+
+/// The `ssdp:alive` NOTIFY announcing what the search response `payload`
+/// describes, `None` if it is not a response
+fn announcement(payload: &[u8]) -> Option<String> {
+    let message = SsdpMessage::parse(payload)?;
+    if message.kind != SsdpKind::Response {
+        return None;
+    }
+    let (_, headers) = split_message(payload)?;
+    let mut notify = format!(
+        "NOTIFY * HTTP/1.1\r\nHOST: {}:{}\r\nNT: {}\r\nNTS: ssdp:alive\r\n",
+        SSDP_GROUP, SSDP_PORT, message.target
+    );
+    for (name, value) in headers {
+        if !RESPONSE_ONLY_HEADERS
+            .iter()
+            .any(|skipped| name.eq_ignore_ascii_case(skipped))
+        {
+            notify.push_str(&format!("{}: {}\r\n", name, value));
+        }
+    }
+    notify.push_str("\r\n");
+    Some(notify)
+}
+
+/// Readdresses the search response in `frame` to the SSDP group as the
+/// announcement made of it. Returns `false` if it is no search response.
+fn announce(frame: &mut Vec<u8>, datagram: &UdpDatagram) -> bool {
+    let Some(notify) = announcement(&frame[datagram.payload()]) else {
+        return false;
+    };
+    let [source_port, _] = datagram.ports(frame);
+    datagram.set_ports(frame, [source_port, SSDP_PORT]);
+    MutableEthernetPacket::new(frame)
+        .expect("frame was located as UDP")
+        .set_destination(SSDP_GROUP_MAC);
+    MutableIpv4Packet::new(&mut frame[ETHERNET_HEADER_LEN..])
+        .expect("frame was located as UDP")
+        .set_destination(SSDP_GROUP);
+    datagram.replace_payload(frame, notify.as_bytes())
+}
+
+/// What is probed for, and how often
+#[derive(Debug, Clone)]
+pub struct ProbeTargets {
+    /// Search targets of the SSDP searches
+    pub ssdp: Vec<String>,
+    /// Service types whose PTR records are queried
+    pub mdns: Vec<String>,
+    pub interval: Duration,
+    /// Longest random delay added to every interval
+    pub jitter: Duration,
+    /// How long responses to a search are accepted
+    pub window: Duration,
+}
+
+/// Searches for devices on the external interface of a pair from its
+/// address, subject to the rate limits like forwarded frames
+pub struct Prober {
+    external: String,
+    targets: ProbeTargets,
+    responder: Responder,
+    limiter: Option<Arc<RateLimiter>>,
+    /// Address and port searches are sent from and responses come back to
+    source: (Ipv4Addr, u16),
+    last_search: Mutex<Option<Instant>>,
+    probes: AtomicU64,
+    sent: AtomicU64,
+    limited: AtomicU64,
+    responses: AtomicU64,
+}
+
+impl Prober {
+    /// Returns `None` if `responder` has no IPv4 address to send from
+    pub fn new(
+        external: String,
+        targets: ProbeTargets,
+        responder: Responder,
+        limiter: Option<Arc<RateLimiter>>,
+    ) -> Option<Self> {
+        let source = (responder.ipv4()?, dynamic_port());
+        Some(Prober {
+            external,
+            targets,
+            responder,
+            limiter,
+            source,
+            last_search: Mutex::new(None),
+            probes: AtomicU64::new(0),
+            sent: AtomicU64::new(0),
+            limited: AtomicU64::new(0),
+            responses: AtomicU64::new(0),
+        })
+    }
+
+    /// Address and port the searches are sent from
+    pub fn source(&self) -> (Ipv4Addr, u16) {
+        self.source
+    }
+
+    /// Sends one search per SSDP target and one query for all mDNS
+    /// service types
+    pub fn probe(&self) {
+        self.probes.fetch_add(1, Ordering::Relaxed);
+        if !self.targets.ssdp.is_empty() {
+            *self.last_search.lock().unwrap() = Some(Instant::now());
+        }
+        let (_, port) = self.source;
+        for target in &self.targets.ssdp {
+            let group = Destination {
+                mac: SSDP_GROUP_MAC,
+                ip: SSDP_GROUP.into(),
+                port: SSDP_PORT,
+            };
+            self.send(port, group, SSDP_HOP_LIMIT, search(target).as_bytes());
+        }
+        if !self.targets.mdns.is_empty() {
+            let group = Destination {
+                mac: MDNS_GROUP_MAC,
+                ip: MDNS_IPV4_GROUP.into(),
+                port: MDNS_PORT,
+            };
+            self.send(MDNS_PORT, group, MDNS_HOP_LIMIT, &query(&self.targets.mdns));
+        }
+        debug!(
+            "Probed for {} SSDP target(s) and {} mDNS service(s) on {}",
+            self.targets.ssdp.len(),
+            self.targets.mdns.len(),
+            self.external
+        );
+    }
+
+    fn send(&self, source_port: u16, destination: Destination, hop_limit: u8, payload: &[u8]) {
+        let Some(frame) = self
+            .responder
+            .frame(source_port, destination, hop_limit, payload)
+        else {
+            return;
+        };
+        let allowed = match (
+            &self.limiter,
+            PacketContext::parse(&self.external, Direction::Outbound, &frame),
+        ) {
+            (Some(limiter), Some(ctx)) => limiter.allow(&ctx),
+            _ => true,
+        };
+        if !allowed {
+            self.limited.fetch_add(1, Ordering::Relaxed);
+            debug!("Probe to {} held back by the rate limits", destination.ip);
+        } else if self.responder.send_frame(frame) {
+            self.sent.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Whether `ctx` is a response to the last search for a wanted target
+    fn expects(&self, ctx: &PacketContext) -> bool {
+        let Some(udp) = ctx.udp() else {
+            return false;
+        };
+        let (ip, port) = self.source;
+        if ctx.ingress != self.external
+            || udp.get_source() != SSDP_PORT
+            || ctx.destination_ip() != Some(IpAddr::V4(ip))
+            || udp.get_destination() != port
+        {
+            return false;
+        }
+        let searched = *self.last_search.lock().unwrap();
+        if !searched.is_some_and(|at| at.elapsed() < self.targets.window) {
+            return false;
+        }
+        let Some(response) = SsdpMessage::parse(udp.payload()) else {
+            return false;
+        };
+        response.kind == SsdpKind::Response
+            && self.targets.ssdp.iter().any(|target| {
+                target.eq_ignore_ascii_case("ssdp:all")
+                    || target.eq_ignore_ascii_case(response.target)
+            })
+    }
+
+    /// Counters, one line
+    fn state(&self) -> Vec<String> {
+        let (ip, port) = self.source;
+        vec![format!(
+            "from {}:{} every {} plus up to {}, {} probe(s), {} message(s) sent, {} held back \
+             by the rate limits, {} SSDP response(s) announced inwards",
+            ip,
+            port,
+            humantime::format_duration(self.targets.interval),
+            humantime::format_duration(self.targets.jitter),
+            self.probes.load(Ordering::Relaxed),
+            self.sent.load(Ordering::Relaxed),
+            self.limited.load(Ordering::Relaxed),
+            self.responses.load(Ordering::Relaxed)
+        )]
+    }
+}
+
+/// Probes every interval plus a random delay of up to the jitter, the
+/// first time after only the random delay, until `token` is cancelled
+pub fn spawn(prober: Arc<Prober>, token: CancellationToken) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut wait = random_up_to(prober.targets.jitter);
+        loop {
+            tokio::select! {
+                _ = token.cancelled() => break,
+                _ = tokio::time::sleep(wait) => prober.probe(),
+            }
+            wait = prober.targets.interval + random_up_to(prober.targets.jitter);
+        }
+    })
+}
+
+/// Lets in the unicast responses to the prober's searches, ahead of the
+/// SSDP response tracking that would drop them
+pub struct ProbeResponses {
+    prober: Arc<Prober>,
+}
+
+impl ProbeResponses {
+    pub fn new(prober: Arc<Prober>) -> Self {
+        ProbeResponses { prober }
+    }
+}
+
+impl Filter for ProbeResponses {
+    fn name(&self) -> &str {
+        "probe"
+    }
+
+    fn evaluate(&self, ctx: &PacketContext) -> Decision {
+        if self.prober.expects(ctx) {
+            Decision::Forward
+        } else {
+            Decision::Continue
+        }
+    }
+
+    fn state(&self) -> Option<Vec<String>> {
+        Some(self.prober.state())
+    }
+}
+
+/// Turns the responses to the prober's searches into announcements to the
+/// SSDP group. Goes ahead of the stages learning from SSDP messages.
+pub struct AnnounceProbeResponses {
+    prober: Arc<Prober>,
+}
+
+impl AnnounceProbeResponses {
+    pub fn new(prober: Arc<Prober>) -> Self {
+        AnnounceProbeResponses { prober }
+    }
+}
+
+impl Rewrite for AnnounceProbeResponses {
+    fn name(&self) -> &str {
+        "probe"
+    }
+
+    fn apply(&self, frame: &mut Vec<u8>) -> bool {
+        let Some(datagram) = UdpDatagram::locate(frame) else {
+            return true;
+        };
+        let (ip, port) = self.prober.source;
+        let destination = frame.get(ETHERNET_HEADER_LEN + 16..ETHERNET_HEADER_LEN + 20);
+        if datagram.is_ipv6()
+            || datagram.ports(frame) != [SSDP_PORT, port]
+            || destination != Some(&ip.octets()[..])
+        {
+            return true;
+        }
+        if !announce(frame, &datagram) {
+            debug!("Unicast to the prober that is no SSDP response dropped");
+            return false;
+        }
+        self.prober.responses.fetch_add(1, Ordering::Relaxed);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::checksum::{self, Verdict};
+    use crate::mdns::OwnedMessage;
+    use crate::testutil::{udp_frame, HOST_IP, HOST_MAC};
+
+    #[test]
+    fn announces_search_responses() {
+        let search = search("urn:dial-multiscreen-org:service:dial:1");
+        let parsed = SsdpMessage::parse(search.as_bytes()).unwrap();
+        assert_eq!(parsed.kind, SsdpKind::Search);
+
+        let query = OwnedMessage::parse(&query(&["_googlecast._tcp.local.".to_string()])).unwrap();
+        assert!(!query.response);
+        assert_eq!(
+            query.questions,
+            [Question {
+                name: vec![b"_googlecast".to_vec(), b"_tcp".to_vec(), b"local".to_vec()],
+                qtype: TYPE_PTR,
+                class: CLASS_IN,
+            }]
+        );
+
+        let response = b"HTTP/1.1 200 OK\r\nCACHE-CONTROL: max-age=1800\r\nEXT:\r\n\
+            LOCATION: http://192.168.100.5:8008/ssdp/device-desc.xml\r\n\
+            SERVER: Linux/3.8 UPnP/1.0\r\nST: upnp:rootdevice\r\n\
+            USN: uuid:tv::upnp:rootdevice\r\n\r\n";
+        let prober = (Ipv4Addr::new(192, 168, 100, 1), 50000);
+        let mut frame = udp_frame(
+            HOST_MAC,
+            MacAddr(0x02, 0, 0, 0, 0, 2),
+            HOST_IP,
+            prober.0,
+            SSDP_PORT,
+            prober.1,
+            response,
+        );
+        let datagram = UdpDatagram::locate(&frame).unwrap();
+        assert!(announce(&mut frame, &datagram));
+        let datagram = UdpDatagram::locate(&frame).unwrap();
+        assert_eq!(datagram.ports(&frame), [SSDP_PORT, SSDP_PORT]);
+        assert_eq!(&frame[..6], &[0x01, 0x00, 0x5e, 0x7f, 0xff, 0xfa]);
+        assert_eq!(
+            &frame[ETHERNET_HEADER_LEN + 16..ETHERNET_HEADER_LEN + 20],
+            &SSDP_GROUP.octets()
+        );
+        assert_eq!(checksum::verify(&frame), Verdict::Valid);
+        assert_eq!(
+            std::str::from_utf8(&frame[datagram.payload()]).unwrap(),
+            "NOTIFY * HTTP/1.1\r\nHOST: 239.255.255.250:1900\r\nNT: upnp:rootdevice\r\n\
+             NTS: ssdp:alive\r\nCACHE-CONTROL: max-age=1800\r\n\
+             LOCATION: http://192.168.100.5:8008/ssdp/device-desc.xml\r\n\
+             SERVER: Linux/3.8 UPnP/1.0\r\nUSN: uuid:tv::upnp:rootdevice\r\n\r\n"
+        );
+        let notify = SsdpMessage::parse(&frame[datagram.payload()]).unwrap();
+        assert_eq!(notify.kind, SsdpKind::Alive);
+
+        // Anything else sent to the prober is not announced
+        let mut frame = udp_frame(
+            HOST_MAC,
+            MacAddr(0x02, 0, 0, 0, 0, 2),
+            HOST_IP,
+            prober.0,
+            SSDP_PORT,
+            prober.1,
+            search.as_bytes(),
+        );
+        let datagram = UdpDatagram::locate(&frame).unwrap();
+        assert!(!announce(&mut frame, &datagram));
+        assert!(announcement(b"\xff\xfe").is_none());
+    }
+}
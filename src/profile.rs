@@ -0,0 +1,193 @@
+//! Named presets of the ports, mDNS services and SSDP targets a use case
+//! needs, selected with `--profile`.
+
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+/// Filter settings of one use case
+#[derive(Debug, PartialEq, Eq)]
+pub struct Preset {
+    pub name: &'static str,
+    pub udp_ports: &'static [u16],
+    pub tcp_ports: &'static [u16],
+    pub mdns_services: &'static [&'static str],
+    pub ssdp_targets: &'static [&'static str],
+}
+
+/// Services of the chromecast profile besides `_googlecast._tcp`, for
+/// speaker groups and guest mode, left out with `--chromecast-strict`
+pub const CHROMECAST_GROUP_SERVICES: &[&str] = &["_googlezone._tcp.local", "_googlerpc._tcp.local"];
+
+/// The known profiles; a new one only needs an entry here
+pub const PRESETS: &[Preset] = &[
+    Preset {
+        name: "chromecast",
+        udp_ports: &[1900, 5353],
+        tcp_ports: &[8008, 8009],
+        mdns_services: &[
+            "_googlecast._tcp.local",
+            "_googlezone._tcp.local",
+            "_googlerpc._tcp.local",
+        ],
+        ssdp_targets: &[
+            "ssdp:all",
+            "urn:dial-multiscreen-org:service:dial:1",
+            "urn:dial-multiscreen-org:device:dial:1",
+        ],
+    },
+    Preset {
+        name: "airplay",
+        udp_ports: &[5353],
+        tcp_ports: &[7000, 7100],
+        mdns_services: &["_airplay._tcp.local", "_raop._tcp.local"],
+        ssdp_targets: &[],
+    },
+    Preset {
+        name: "printer",
+        udp_ports: &[3702, 5353],
+        tcp_ports: &[631, 9100],
+        mdns_services: &["_ipp._tcp.local", "_pdl-datastream._tcp.local"],
+        ssdp_targets: &[],
+    },
+    Preset {
+        name: "dlna",
+        udp_ports: &[1900],
+        tcp_ports: &[],
+        mdns_services: &[],
+        ssdp_targets: &[
+            "ssdp:all",
+            "urn:schemas-upnp-org:device:MediaRenderer:1",
+            "urn:schemas-upnp-org:device:MediaServer:1",
+        ],
+    },
+    Preset {
+        name: "onvif",
+        udp_ports: &[3702],
+        tcp_ports: &[],
+        mdns_services: &[],
+        ssdp_targets: &[],
+    },
+    Preset {
+        name: "coap",
+        udp_ports: &[5683],
+        tcp_ports: &[],
+        mdns_services: &[],
+        ssdp_targets: &[],
+    },
+    Preset {
+        name: "matter",
+        udp_ports: &[5353, 5540],
+        tcp_ports: &[],
+        mdns_services: &["_matter._tcp.local", "_matterc._udp.local"],
+        ssdp_targets: &[],
+    },
+];
+
+/// A preset selected by its name
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Profile(&'static Preset);
+
+impl Profile {
+    pub fn preset(&self) -> &'static Preset {
+        self.0
+    }
+}
+
+impl FromStr for Profile {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        PRESETS
+            .iter()
+            .find(|preset| preset.name.eq_ignore_ascii_case(value))
+            .map(Profile)
+            .ok_or_else(|| {
+                let names: Vec<&str> = PRESETS.iter().map(|preset| preset.name).collect();
+                format!(
+                    "'{}' is not a profile, expected one of {}",
+                    value,
+                    names.join(", ")
+                )
+            })
+    }
+}
+
+impl TryFrom<String> for Profile {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<Profile> for String {
+    fn from(profile: Profile) -> Self {
+        profile.to_string()
+    }
+}
+
+impl fmt::Display for Profile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.0.name)
+    }
+}
+
+/// Values of `field` over all `profiles` followed by `explicit`, in order
+/// and without duplicates
+pub fn combine<P, T>(
+    profiles: &[Profile],
+    field: impl Fn(&'static Preset) -> &'static [P],
+    explicit: &[T],
+) -> Vec<T>
+where
+    P: Copy + Into<T> + 'static,
+    T: Clone + PartialEq,
+{
+    let mut values: Vec<T> = Vec::new();
+    let presets = profiles.iter().flat_map(|profile| field(profile.preset()));
+    let all = presets
+        .map(|value| (*value).into())
+        .chain(explicit.iter().cloned());
+    for value in all {
+        if !values.contains(&value) {
+            values.push(value);
+        }
+    }
+    values
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn combines_profiles_with_explicit_values() {
+        let profiles: Vec<Profile> = ["printer", "AirPlay"]
+            .iter()
+            .map(|name| name.parse().unwrap())
+            .collect();
+        assert_eq!(profiles[1].to_string(), "airplay");
+        assert!("tv".parse::<Profile>().is_err());
+
+        let ports = combine(&profiles, |preset| preset.tcp_ports, &[22, 631]);
+        assert_eq!(ports, [631, 9100, 7000, 7100, 22]);
+        let services = combine(
+            &profiles,
+            |preset| preset.mdns_services,
+            &["_scanner._tcp.local".to_string()],
+        );
+        assert_eq!(
+            services,
+            [
+                "_ipp._tcp.local",
+                "_pdl-datastream._tcp.local",
+                "_airplay._tcp.local",
+                "_raop._tcp.local",
+                "_scanner._tcp.local"
+            ]
+        );
+        assert!(combine(&profiles, |preset| preset.ssdp_targets, &[] as &[String]).is_empty());
+    }
+}
@@ -0,0 +1,510 @@
+//! Byte and packet budgets per direction and protocol class over long
+//! windows, such as 1 MB of discovery traffic per hour leaving the internal
+//! side. The rate limiter bounds short bursts; a quota bounds what crosses
+//! in total, and both apply to the same frame.
+
+use crate::filter::{PacketContext, COAP_PORT, MDNS_PORT, SSDP_PORT, WSD_PORT};
+use crate::pair::Direction;
+use crate::pcap::parse_size;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::{debug, info, warn};
+
+/// Traffic a quota is charged with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Class {
+    /// SSDP, mDNS, WS-Discovery and CoAP
+    Discovery,
+    Ssdp,
+    Mdns,
+    Wsd,
+    Coap,
+    Udp,
+    Tcp,
+}
+
+const CLASSES: [(&str, Class); 7] = [
+    ("discovery", Class::Discovery),
+    ("ssdp", Class::Ssdp),
+    ("mdns", Class::Mdns),
+    ("wsd", Class::Wsd),
+    ("coap", Class::Coap),
+    ("udp", Class::Udp),
+    ("tcp", Class::Tcp),
+];
+
+impl Class {
+    fn matches(self, ctx: &PacketContext) -> bool {
+        let Some(udp) = ctx.udp() else {
+            return self == Class::Tcp && ctx.tcp().is_some();
+        };
+        let port = |port| udp.get_source() == port || udp.get_destination() == port;
+        match self {
+            Class::Discovery => [SSDP_PORT, MDNS_PORT, WSD_PORT, COAP_PORT]
+                .into_iter()
+                .any(port),
+            Class::Ssdp => port(SSDP_PORT),
+            Class::Mdns => port(MDNS_PORT),
+            Class::Wsd => port(WSD_PORT),
+            Class::Coap => port(COAP_PORT),
+            Class::Udp => true,
+            Class::Tcp => false,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        CLASSES
+            .iter()
+            .find(|(_, class)| *class == self)
+            .map(|(name, _)| *name)
+            .expect("every class is listed")
+    }
+}
+
+/// One quota, written as `DIRECTION [CLASS] [bytes SIZE] [packets N] per
+/// DURATION`, e.g. `in->out discovery bytes 1M per 1h`. DIRECTION is as in
+/// the rules; CLASS is `discovery`, `ssdp`, `mdns`, `wsd`, `coap`, `udp`,
+/// `tcp` or `any`. At least one of the budgets is needed.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Quota {
+    /// `None` charges both directions, including bridged traffic
+    pub direction: Option<Direction>,
+    pub class: Option<Class>,
+    pub bytes: Option<u64>,
+    pub packets: Option<u64>,
+    pub window: Duration,
+}
+
+impl Quota {
+    fn matches(&self, ctx: &PacketContext) -> bool {
+        self.direction
+            .is_none_or(|direction| direction == ctx.direction)
+            && self.class.is_none_or(|class| class.matches(ctx))
+    }
+}
+
+impl FromStr for Quota {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let mut words = value.split_whitespace();
+        let direction = match words.next() {
+            Some("in->out" | "internal->external") => Some(Direction::Outbound),
+            Some("out->in" | "external->internal") => Some(Direction::Inbound),
+            Some("any") => None,
+            Some(other) => {
+                return Err(format!(
+                    "unknown direction '{}', expected in->out, out->in or any",
+                    other
+                ))
+            }
+            None => return Err("empty quota".to_string()),
+        };
+        let mut quota = Quota {
+            direction,
+            class: None,
+            bytes: None,
+            packets: None,
+            window: Duration::ZERO,
+        };
+        let mut window = None;
+        while let Some(word) = words.next() {
+            if window.is_some() {
+                return Err(format!("unexpected '{}' after the window", word));
+            }
+            if word == "any" {
+                continue;
+            }
+            if let Some((_, class)) = CLASSES.iter().find(|(name, _)| *name == word) {
+                quota.class = Some(*class);
+                continue;
+            }
+            if !["bytes", "packets", "per"].contains(&word) {
+                return Err(format!("unknown word '{}'", word));
+            }
+            let value = words
+                .next()
+                .ok_or_else(|| format!("'{}' needs a value", word))?;
+            match word {
+                "bytes" => quota.bytes = Some(parse_size(value)?),
+                "packets" => {
+                    let packets = value
+                        .parse()
+                        .ok()
+                        .filter(|packets| *packets != 0)
+                        .ok_or("'packets' needs a number greater than zero")?;
+                    quota.packets = Some(packets);
+                }
+                _ => {
+                    let duration = humantime::parse_duration(value).map_err(|e| e.to_string())?;
+                    if duration.is_zero() {
+                        return Err("the window must be longer than zero".to_string());
+                    }
+                    window = Some(duration);
+                }
+            }
+        }
+        if quota.bytes.is_none() && quota.packets.is_none() {
+            return Err("missing budget, expected bytes or packets".to_string());
+        }
+        quota.window = window.ok_or("missing window, expected per DURATION")?;
+        Ok(quota)
+    }
+}
+
+impl TryFrom<String> for Quota {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<Quota> for String {
+    fn from(quota: Quota) -> Self {
+        quota.to_string()
+    }
+}
+
+/// `bytes` with the largest of the suffixes [`parse_size`] takes that
+/// divides it
+pub fn size(bytes: u64) -> String {
+    let units = [(30, "G"), (20, "M"), (10, "K")];
+    match units
+        .iter()
+        .find(|(shift, _)| bytes.trailing_zeros() >= *shift)
+    {
+        Some((shift, unit)) => format!("{}{}", bytes >> shift, unit),
+        None => bytes.to_string(),
+    }
+}
+
+impl fmt::Display for Quota {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let direction = match self.direction {
+            Some(Direction::Outbound) => "in->out",
+            Some(Direction::Inbound) => "out->in",
+            _ => "any",
+        };
+        f.write_str(direction)?;
+        if let Some(class) = self.class {
+            write!(f, " {}", class.name())?;
+        }
+        if let Some(bytes) = self.bytes {
+            write!(f, " bytes {}", size(bytes))?;
+        }
+        if let Some(packets) = self.packets {
+            write!(f, " packets {}", packets)?;
+        }
+        write!(f, " per {}", humantime::format_duration(self.window))
+    }
+}
+
+/// Use of one quota in its current window
+#[derive(Debug)]
+struct Usage {
+    quota: Quota,
+    started: Instant,
+    bytes: u64,
+    packets: u64,
+    /// Frames dropped since the quota was set
+    dropped: u64,
+    /// Whether running out was logged in this window
+    warned: bool,
+}
+
+impl Usage {
+    fn new(quota: Quota, now: Instant) -> Self {
+        Usage {
+            quota,
+            started: now,
+            bytes: 0,
+            packets: 0,
+            dropped: 0,
+            warned: false,
+        }
+    }
+
+    /// Starts the window `now` falls into if the current one is over.
+    /// Windows follow each other from when the quota was set or reset.
+    fn roll(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.started);
+        if elapsed < self.quota.window {
+            return;
+        }
+        let into = elapsed.as_nanos() % self.quota.window.as_nanos();
+        self.started = now - Duration::from_nanos(into as u64);
+        if self.warned {
+            info!("Quota '{}' renewed", self.quota);
+        }
+        self.bytes = 0;
+        self.packets = 0;
+        self.warned = false;
+    }
+
+    fn fits(&self, len: u64) -> bool {
+        self.quota
+            .bytes
+            .is_none_or(|bytes| self.bytes + len <= bytes)
+            && self
+                .quota
+                .packets
+                .is_none_or(|packets| self.packets < packets)
+    }
+
+    fn snapshot(&self, now: Instant) -> QuotaSnapshot {
+        let remaining = self
+            .quota
+            .window
+            .saturating_sub(now.saturating_duration_since(self.started));
+        QuotaSnapshot {
+            quota: self.quota.to_string(),
+            bytes: self.bytes,
+            packets: self.packets,
+            dropped: self.dropped,
+            exhausted: self.warned,
+            window_remaining_secs: remaining.as_secs(),
+        }
+    }
+}
+
+/// Quotas shared by all forwarding paths. They are kept across reloads:
+/// a quota that is still configured keeps its use, so a reload does not
+/// hand out a fresh budget.
+#[derive(Debug, Default)]
+pub struct Quotas {
+    usage: Mutex<Vec<Usage>>,
+    /// Set while any quota is configured, so frames skip the lock otherwise
+    active: AtomicBool,
+}
+
+impl Quotas {
+    pub fn new(quotas: &[Quota]) -> Self {
+        let limits = Quotas::default();
+        limits.update(quotas);
+        limits
+    }
+
+    /// Replaces the quotas by `quotas`, keeping the use of the ones that
+    /// stay
+    pub fn update(&self, quotas: &[Quota]) {
+        let now = Instant::now();
+        let mut usage = self.usage.lock().unwrap();
+        let mut kept = Vec::with_capacity(quotas.len());
+        for quota in quotas {
+            match usage.iter().position(|usage| usage.quota == *quota) {
+                Some(at) => kept.push(usage.swap_remove(at)),
+                None => kept.push(Usage::new(quota.clone(), now)),
+            }
+        }
+        *usage = kept;
+        self.active.store(!usage.is_empty(), Ordering::Relaxed);
+    }
+
+    /// Whether a frame of `len` bytes fits into every quota it is charged
+    /// to. It is charged only once forwarded, by [`charge`](Self::charge).
+    pub fn allow(&self, ctx: &PacketContext, len: usize) -> bool {
+        if !self.active.load(Ordering::Relaxed) {
+            return true;
+        }
+        self.allow_at(ctx, len as u64, Instant::now())
+    }
+
+    /// Charges a forwarded frame of `len` bytes to every quota it matches
+    pub fn charge(&self, ctx: &PacketContext, len: usize) {
+        if self.active.load(Ordering::Relaxed) {
+            self.charge_at(ctx, len as u64, Instant::now());
+        }
+    }
+
+    fn allow_at(&self, ctx: &PacketContext, len: u64, now: Instant) -> bool {
+        let mut usage = self.usage.lock().unwrap();
+        let mut matching: Vec<&mut Usage> = usage
+            .iter_mut()
+            .filter(|usage| usage.quota.matches(ctx))
+            .collect();
+        for usage in matching.iter_mut() {
+            usage.roll(now);
+        }
+        let Some(full) = matching.into_iter().find(|usage| !usage.fits(len)) else {
+            return true;
+        };
+        full.dropped += 1;
+        if !full.warned {
+            full.warned = true;
+            let left = full
+                .quota
+                .window
+                .saturating_sub(now.saturating_duration_since(full.started));
+            let left = Duration::from_secs(left.as_secs());
+            warn!(
+                "Quota '{}' used up, dropping matching frames for {}",
+                full.quota,
+                humantime::format_duration(left)
+            );
+        } else {
+            debug!("Quota '{}' used up", full.quota);
+        }
+        false
+    }
+
+    fn charge_at(&self, ctx: &PacketContext, len: u64, now: Instant) {
+        let mut usage = self.usage.lock().unwrap();
+        for usage in usage.iter_mut().filter(|usage| usage.quota.matches(ctx)) {
+            usage.roll(now);
+            usage.bytes += len;
+            usage.packets += 1;
+        }
+    }
+
+    /// Starts a new window for every quota
+    pub fn reset(&self) {
+        let now = Instant::now();
+        for usage in self.usage.lock().unwrap().iter_mut() {
+            *usage = Usage::new(usage.quota.clone(), now);
+        }
+    }
+
+    pub fn snapshot(&self) -> Vec<QuotaSnapshot> {
+        let now = Instant::now();
+        let usage = self.usage.lock().unwrap();
+        usage.iter().map(|usage| usage.snapshot(now)).collect()
+    }
+}
+
+/// Point-in-time use of one quota
+#[derive(Debug, Clone, Serialize)]
+pub struct QuotaSnapshot {
+    pub quota: String,
+    pub bytes: u64,
+    pub packets: u64,
+    pub dropped: u64,
+    /// Whether frames are being dropped until the window rolls over
+    pub exhausted: bool,
+    pub window_remaining_secs: u64,
+}
+
+impl fmt::Display for QuotaSnapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Quota '{}': used {} bytes in {} frames, {}s of the window left{}, dropped {}",
+            self.quota,
+            self.bytes,
+            self.packets,
+            self.window_remaining_secs,
+            if self.exhausted { ", used up" } else { "" },
+            self.dropped
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{mdns_query_frame, ssdp_search_frame};
+
+    #[test]
+    fn parses_and_prints_quotas() {
+        let quota: Quota = "in->out discovery bytes 1M per 1h".parse().unwrap();
+        assert_eq!(quota.direction, Some(Direction::Outbound));
+        assert_eq!(quota.class, Some(Class::Discovery));
+        assert_eq!(quota.bytes, Some(1 << 20));
+        assert_eq!(quota.window, Duration::from_secs(3600));
+        assert_eq!(quota.to_string(), "in->out discovery bytes 1M per 1h");
+
+        let quota: Quota = "any any packets 1000 bytes 1536 per 1day".parse().unwrap();
+        assert_eq!(quota.to_string(), "any bytes 1536 packets 1000 per 1day");
+        assert_eq!(quota.to_string().parse::<Quota>().unwrap(), quota);
+
+        for invalid in [
+            "",
+            "up mdns bytes 1M per 1h",
+            "in->out mdns per 1h",
+            "in->out mdns bytes 1M",
+            "in->out bytes 0 per 1h",
+            "in->out packets 0 per 1h",
+            "in->out bytes 1M per 0s",
+            "in->out bytes 1M per 1h drop",
+            "in->out ftp bytes 1M per 1h",
+            "in->out packets",
+        ] {
+            assert!(invalid.parse::<Quota>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn drops_over_budget_until_the_window_rolls_over() {
+        let ssdp = ssdp_search_frame("ssdp:all");
+        let mdns = mdns_query_frame("_http._tcp.local");
+        let outbound = |frame| PacketContext::parse("vm1", Direction::Outbound, frame).unwrap();
+        let inbound = |frame| PacketContext::parse("eth0", Direction::Inbound, frame).unwrap();
+        let hour: Quota = "in->out ssdp packets 2 per 1h".parse().unwrap();
+        let bytes = format!("any bytes {} per 1m", ssdp.len() * 3);
+        let quotas = Quotas::new(&[hour.clone(), bytes.parse().unwrap()]);
+        let start = Instant::now();
+        let forward = |ctx: &PacketContext, len: usize, now| {
+            let allowed = quotas.allow_at(ctx, len as u64, now);
+            if allowed {
+                quotas.charge_at(ctx, len as u64, now);
+            }
+            allowed
+        };
+
+        assert!(forward(&outbound(&ssdp), ssdp.len(), start));
+        assert!(forward(&outbound(&ssdp), ssdp.len(), start));
+        // The packet budget is used up, frames of other classes still pass
+        assert!(!forward(&outbound(&ssdp), ssdp.len(), start));
+        assert!(forward(&outbound(&mdns), 1, start));
+        // The byte budget of both directions is used up as well
+        assert!(!forward(&inbound(&ssdp), ssdp.len(), start));
+
+        let later = start + Duration::from_secs(90);
+        assert!(forward(&inbound(&ssdp), ssdp.len(), later));
+        assert!(!forward(&outbound(&ssdp), ssdp.len(), later));
+        let snapshot = quotas.snapshot();
+        assert_eq!(snapshot[0].packets, 2);
+        assert_eq!(snapshot[0].dropped, 2);
+        assert!(snapshot[0].exhausted);
+        assert_eq!(snapshot[1].packets, 1);
+
+        // Kept across a reload as long as the quota stays the same
+        quotas.update(&[hour]);
+        assert!(!forward(&outbound(&ssdp), ssdp.len(), later));
+        assert!(forward(
+            &outbound(&ssdp),
+            1,
+            start + Duration::from_secs(3600)
+        ));
+        quotas.reset();
+        assert_eq!(quotas.snapshot()[0].packets, 0);
+        assert_eq!(quotas.snapshot()[0].dropped, 0);
+        quotas.update(&[]);
+        assert!(quotas.allow(&outbound(&ssdp), usize::MAX));
+    }
+
+    #[test]
+    fn charges_only_forwarded_frames() {
+        let ssdp = ssdp_search_frame("ssdp:all");
+        let ctx = PacketContext::parse("vm1", Direction::Outbound, &ssdp).unwrap();
+        let quotas = Quotas::new(&["in->out ssdp packets 1 per 1h".parse().unwrap()]);
+        let start = Instant::now();
+
+        // Frames lost after the check, such as to a full send queue, leave
+        // the budget to the next one
+        assert!(quotas.allow_at(&ctx, ssdp.len() as u64, start));
+        assert!(quotas.allow_at(&ctx, ssdp.len() as u64, start));
+        assert_eq!(quotas.snapshot()[0].packets, 0);
+        quotas.charge_at(&ctx, ssdp.len() as u64, start);
+        assert!(!quotas.allow_at(&ctx, ssdp.len() as u64, start));
+        let snapshot = quotas.snapshot();
+        assert_eq!(snapshot[0].packets, 1);
+        assert_eq!(snapshot[0].bytes, ssdp.len() as u64);
+        assert_eq!(snapshot[0].dropped, 1);
+    }
+}
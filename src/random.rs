@@ -0,0 +1,29 @@
+//! Randomness for spreading timers and telling instances apart. Hashers are
+//! keyed with random numbers from the operating system, which is plenty for
+//! jitter and saves a dependency on a random number generator.
+
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::time::{Duration, Instant};
+
+/// Random number, not fit for cryptographic use
+pub fn random() -> u64 {
+    RandomState::new().hash_one(Instant::now())
+}
+
+/// Random duration of up to `max`, in whole milliseconds
+pub fn random_up_to(max: Duration) -> Duration {
+    Duration::from_millis(random() % (max.as_millis() as u64 + 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stays_within_the_bound() {
+        let max = Duration::from_millis(20);
+        assert!((0..1000).all(|_| random_up_to(max) <= max));
+        assert_eq!(random_up_to(Duration::ZERO), Duration::ZERO);
+    }
+}
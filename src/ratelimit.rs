@@ -0,0 +1,214 @@
+//! Token bucket rate limiting of forwarded frames, in total and per source
+//! host.
+
+use crate::filter::{PacketContext, MDNS_PORT};
+use std::collections::{BTreeMap, HashMap};
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::debug;
+
+/// Limits taken from the command line
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimits {
+    /// Frames per second forwarded in total
+    pub total: Option<u32>,
+    /// Frames per second forwarded per source address
+    pub per_host: Option<u32>,
+    /// Frames per second forwarded per source address for mDNS, which
+    /// replaces `per_host` for mDNS traffic
+    pub mdns_per_host: Option<u32>,
+    /// How much of its rate a bucket holds, i.e. the longest burst at full
+    /// rate that passes after a quiet period
+    pub burst: Duration,
+    /// Source addresses tracked before the least recently seen is evicted
+    pub max_hosts: usize,
+}
+
+/// Bucket refilled at `rate` tokens per second up to `depth`; forwarding a
+/// frame takes one token
+#[derive(Debug)]
+struct TokenBucket {
+    rate: f64,
+    depth: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    /// Starts full, so the first burst after startup passes
+    fn new(rate: u32, burst: Duration, now: Instant) -> Self {
+        let rate = f64::from(rate);
+        let depth = (rate * burst.as_secs_f64()).max(1.0);
+        TokenBucket {
+            rate,
+            depth,
+            tokens: depth,
+            updated: now,
+        }
+    }
+
+    fn take(&mut self, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.depth);
+        self.updated = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// One bucket per source address, bounded by evicting the least recently
+/// seen address
+#[derive(Debug)]
+struct HostBuckets {
+    rate: u32,
+    burst: Duration,
+    max_hosts: usize,
+    /// Bucket and last use of every address
+    buckets: HashMap<IpAddr, (TokenBucket, u64)>,
+    /// Addresses by last use, oldest first
+    order: BTreeMap<u64, IpAddr>,
+    uses: u64,
+}
+
+impl HostBuckets {
+    fn new(rate: u32, limits: &RateLimits) -> Self {
+        HostBuckets {
+            rate,
+            burst: limits.burst,
+            max_hosts: limits.max_hosts,
+            buckets: HashMap::new(),
+            order: BTreeMap::new(),
+            uses: 0,
+        }
+    }
+
+    fn take(&mut self, host: IpAddr, now: Instant) -> bool {
+        self.uses += 1;
+        let use_id = self.uses;
+        if let Some((bucket, last_use)) = self.buckets.get_mut(&host) {
+            self.order.remove(last_use);
+            self.order.insert(use_id, host);
+            *last_use = use_id;
+            return bucket.take(now);
+        }
+        if self.buckets.len() >= self.max_hosts {
+            if let Some((_, oldest)) = self.order.pop_first() {
+                self.buckets.remove(&oldest);
+            }
+        }
+        let mut bucket = TokenBucket::new(self.rate, self.burst, now);
+        let taken = bucket.take(now);
+        self.buckets.insert(host, (bucket, use_id));
+        self.order.insert(use_id, host);
+        taken
+    }
+}
+
+/// Rate limiter shared by all forwarding paths. A frame has to get a token
+/// from its host bucket first and then from the total bucket, so a single
+/// flooding host does not use up the total rate of everyone else.
+pub struct RateLimiter {
+    total: Option<Mutex<TokenBucket>>,
+    per_host: Option<Mutex<HostBuckets>>,
+    mdns_per_host: Option<Mutex<HostBuckets>>,
+}
+
+impl RateLimiter {
+    /// Returns `None` if no limit is set
+    pub fn new(limits: RateLimits) -> Option<Self> {
+        let now = Instant::now();
+        let limiter = RateLimiter {
+            total: limits
+                .total
+                .map(|rate| Mutex::new(TokenBucket::new(rate, limits.burst, now))),
+            per_host: limits
+                .per_host
+                .map(|rate| Mutex::new(HostBuckets::new(rate, &limits))),
+            mdns_per_host: limits
+                .mdns_per_host
+                .map(|rate| Mutex::new(HostBuckets::new(rate, &limits))),
+        };
+        let limited = limiter.total.is_some()
+            || limiter.per_host.is_some()
+            || limiter.mdns_per_host.is_some();
+        limited.then_some(limiter)
+    }
+
+    /// Whether the frame fits into the limits. Frames without an IP source
+    /// are only subject to the total limit.
+    pub fn allow(&self, ctx: &PacketContext) -> bool {
+        let now = Instant::now();
+        let mdns = ctx
+            .udp()
+            .is_some_and(|udp| udp.get_source() == MDNS_PORT || udp.get_destination() == MDNS_PORT);
+        let hosts = match (&self.mdns_per_host, &self.per_host) {
+            (Some(hosts), _) if mdns => Some(hosts),
+            (_, hosts) => hosts.as_ref(),
+        };
+        if let (Some(hosts), Some(source)) = (hosts, ctx.source_ip()) {
+            if !hosts.lock().unwrap().take(source, now) {
+                debug!("Rate limit of {} exceeded", source);
+                return false;
+            }
+        }
+        if let Some(total) = &self.total {
+            if !total.lock().unwrap().take(now) {
+                debug!("Total rate limit exceeded");
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Number of source addresses tracked, one line
+    pub fn state(&self) -> String {
+        let tracked = |hosts: &Option<Mutex<HostBuckets>>| {
+            hosts
+                .as_ref()
+                .map_or(0, |hosts| hosts.lock().unwrap().buckets.len())
+        };
+        format!(
+            "{} host(s) tracked, {} for mDNS",
+            tracked(&self.per_host),
+            tracked(&self.mdns_per_host)
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buckets_allow_bursts_and_evict_oldest_host() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(10, Duration::from_secs(2), start);
+        assert_eq!((0..30).filter(|_| bucket.take(start)).count(), 20);
+        assert!(bucket.take(start + Duration::from_millis(100)));
+        assert!(!bucket.take(start + Duration::from_millis(100)));
+
+        let limits = RateLimits {
+            total: None,
+            per_host: Some(1),
+            mdns_per_host: None,
+            burst: Duration::from_secs(1),
+            max_hosts: 2,
+        };
+        let mut hosts = HostBuckets::new(1, &limits);
+        let [a, b, c]: [IpAddr; 3] =
+            [[10, 0, 0, 1], [10, 0, 0, 2], [10, 0, 0, 3]].map(IpAddr::from);
+        assert!(hosts.take(a, start));
+        assert!(hosts.take(b, start));
+        assert!(!hosts.take(a, start));
+        // b is now the least recently seen address and makes room for c
+        assert!(hosts.take(c, start));
+        assert_eq!(hosts.buckets.len(), 2);
+        assert!(!hosts.take(a, start));
+        assert!(hosts.take(b, start));
+    }
+}
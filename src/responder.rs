@@ -0,0 +1,223 @@
+//! Answering queries from the internal side locally, from state learned
+//! from forwarded traffic, instead of forwarding them.
+
+use crate::discovery::Device;
+use crate::error::Error;
+use crate::filter::PacketContext;
+use crate::sender::{Origin, SendQueue};
+use crate::stats::PathStats;
+use crate::vlan;
+use pnet::datalink::NetworkInterface;
+use pnet::packet::ethernet::{EtherTypes, MutableEthernetPacket};
+use pnet::packet::ip::IpNextHeaderProtocols;
+use pnet::packet::ipv4::{self, MutableIpv4Packet};
+use pnet::packet::ipv6::MutableIpv6Packet;
+use pnet::packet::udp::{self, MutableUdpPacket};
+use pnet::util::MacAddr;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::runtime::Handle;
+
+const ETHERNET_HEADER_LEN: usize = 14;
+const IPV4_HEADER_LEN: usize = 20;
+const IPV6_HEADER_LEN: usize = 40;
+const UDP_HEADER_LEN: usize = 8;
+
+/// Cache consulted for frames leaving the internal side once the filters
+/// accepted them
+pub trait Cache: Send + Sync {
+    /// Short name used in log messages and the state dump
+    fn name(&self) -> &str;
+
+    /// Answers `ctx` locally if the cache can. Returns whether it did, in
+    /// which case the frame must not be forwarded.
+    fn answer(&self, ctx: &PacketContext) -> bool;
+
+    /// Lines describing the cached state
+    fn state(&self) -> Vec<String>;
+
+    /// Devices learned from announcements, if the cache keeps any
+    fn devices(&self) -> Vec<Device> {
+        Vec::new()
+    }
+}
+
+/// Where a local answer is sent
+#[derive(Debug, Clone, Copy)]
+pub struct Destination {
+    pub mac: MacAddr,
+    pub ip: IpAddr,
+    pub port: u16,
+}
+
+/// Sends answers on the internal interface, from its MAC and addresses
+#[derive(Clone)]
+pub struct Responder {
+    queue: SendQueue,
+    /// Counts the answers sent; not part of the forwarding statistics
+    stats: Arc<PathStats>,
+    mac: MacAddr,
+    ipv4: Option<Ipv4Addr>,
+    ipv6: Option<Ipv6Addr>,
+    /// VLAN of the interface, which the answers are tagged with
+    vlan: Option<u16>,
+    /// Runtime delayed answers wait on, if created within one
+    runtime: Option<Handle>,
+}
+
+impl Responder {
+    /// Answers from the first IPv4 address of `iface` and from its IPv6
+    /// link-local address, or its first IPv6 address without one, tagged
+    /// with `vlan` if given
+    pub fn new(
+        iface: &NetworkInterface,
+        vlan: Option<u16>,
+        queue: SendQueue,
+        stats: PathStats,
+    ) -> Result<Self, Error> {
+        let mac = iface.mac.ok_or_else(|| Error::MissingAddress {
+            iface: iface.name.clone(),
+            what: "MAC address for local answers",
+        })?;
+        let ipv4 = iface.ips.iter().find_map(|ip| match ip.ip() {
+            IpAddr::V4(ip) => Some(ip),
+            IpAddr::V6(_) => None,
+        });
+        let ipv6: Vec<Ipv6Addr> = iface
+            .ips
+            .iter()
+            .filter_map(|ip| match ip.ip() {
+                IpAddr::V6(ip) => Some(ip),
+                IpAddr::V4(_) => None,
+            })
+            .collect();
+        let ipv6 = ipv6
+            .iter()
+            .find(|ip| ip.is_unicast_link_local())
+            .or(ipv6.first())
+            .copied();
+        Ok(Responder {
+            queue,
+            stats: Arc::new(stats),
+            mac,
+            ipv4,
+            ipv6,
+            vlan,
+            runtime: Handle::try_current().ok(),
+        })
+    }
+
+    /// MAC of the interface answers are sent from
+    pub fn mac(&self) -> MacAddr {
+        self.mac
+    }
+
+    /// IPv4 address answers are sent from, if the interface has one
+    pub fn ipv4(&self) -> Option<Ipv4Addr> {
+        self.ipv4
+    }
+
+    /// IPv6 address answers are sent from, if the interface has one
+    pub fn ipv6(&self) -> Option<Ipv6Addr> {
+        self.ipv6
+    }
+
+    /// Queues a UDP datagram carrying `payload` once `delay` has passed.
+    /// Returns `false` if the interface has no address of the destination's
+    /// family or the queue is full; a full queue is only seen in the counters
+    /// when the datagram is queued later.
+    pub fn send_after(
+        &self,
+        delay: Duration,
+        source_port: u16,
+        destination: Destination,
+        hop_limit: u8,
+        payload: &[u8],
+    ) -> bool {
+        let Some(frame) = self.frame(source_port, destination, hop_limit, payload) else {
+            return false;
+        };
+        match &self.runtime {
+            Some(runtime) if !delay.is_zero() => {
+                let responder = self.clone();
+                runtime.spawn(async move {
+                    tokio::time::sleep(delay).await;
+                    responder.send_frame(frame);
+                });
+                true
+            }
+            _ => self.send_frame(frame),
+        }
+    }
+
+    /// Queues a complete untagged frame. Returns `false` if the queue is
+    /// full.
+    pub fn send_frame(&self, mut frame: Vec<u8>) -> bool {
+        if let Some(id) = self.vlan {
+            vlan::tag(&mut frame, id, 0);
+        }
+        self.queue.enqueue(frame.into(), Origin::new(&self.stats))
+    }
+
+    /// Untagged frame carrying a UDP datagram with `payload`, `None` if the
+    /// interface has no address of the destination's family
+    pub fn frame(
+        &self,
+        source_port: u16,
+        destination: Destination,
+        hop_limit: u8,
+        payload: &[u8],
+    ) -> Option<Vec<u8>> {
+        let udp_len = UDP_HEADER_LEN + payload.len();
+        let ip_header_len = match destination.ip {
+            IpAddr::V4(_) => IPV4_HEADER_LEN,
+            IpAddr::V6(_) => IPV6_HEADER_LEN,
+        };
+        if ip_header_len + udp_len > usize::from(u16::MAX) {
+            return None;
+        }
+        let mut frame = vec![0; ETHERNET_HEADER_LEN + ip_header_len + udp_len];
+        let mut eth = MutableEthernetPacket::new(&mut frame)?;
+        eth.set_destination(destination.mac);
+        eth.set_source(self.mac);
+
+        let l3 = &mut frame[ETHERNET_HEADER_LEN..];
+        let (l3_header, l4) = l3.split_at_mut(ip_header_len);
+        let mut udp = MutableUdpPacket::new(l4)?;
+        udp.set_source(source_port);
+        udp.set_destination(destination.port);
+        udp.set_length(udp_len as u16);
+        udp.set_payload(payload);
+        let ethertype = match destination.ip {
+            IpAddr::V4(to) => {
+                let from = self.ipv4?;
+                udp.set_checksum(udp::ipv4_checksum(&udp.to_immutable(), &from, &to));
+                let mut ip = MutableIpv4Packet::new(l3_header)?;
+                ip.set_version(4);
+                ip.set_header_length((IPV4_HEADER_LEN / 4) as u8);
+                ip.set_total_length((IPV4_HEADER_LEN + udp_len) as u16);
+                ip.set_ttl(hop_limit);
+                ip.set_next_level_protocol(IpNextHeaderProtocols::Udp);
+                ip.set_source(from);
+                ip.set_destination(to);
+                ip.set_checksum(ipv4::checksum(&ip.to_immutable()));
+                EtherTypes::Ipv4
+            }
+            IpAddr::V6(to) => {
+                let from = self.ipv6?;
+                udp.set_checksum(udp::ipv6_checksum(&udp.to_immutable(), &from, &to));
+                let mut ip = MutableIpv6Packet::new(l3_header)?;
+                ip.set_version(6);
+                ip.set_payload_length(udp_len as u16);
+                ip.set_next_header(IpNextHeaderProtocols::Udp);
+                ip.set_hop_limit(hop_limit);
+                ip.set_source(from);
+                ip.set_destination(to);
+                EtherTypes::Ipv6
+            }
+        };
+        MutableEthernetPacket::new(&mut frame)?.set_ethertype(ethertype);
+        Some(frame)
+    }
+}
@@ -0,0 +1,253 @@
+//! Rewrite stages applied to accepted frames before they are transmitted.
+
+use crate::checksum;
+use pnet::packet::ethernet::{EtherTypes, EthernetPacket, MutableEthernetPacket};
+use pnet::packet::ip::IpNextHeaderProtocols;
+use pnet::packet::ipv4::{Ipv4Flags, Ipv4Packet};
+use pnet::packet::ipv6::Ipv6Packet;
+use pnet::util::MacAddr;
+use std::ops::Range;
+
+const ETHERNET_HEADER_LEN: usize = 14;
+const IPV6_HEADER_LEN: usize = 40;
+const UDP_HEADER_LEN: usize = 8;
+
+/// A single modification of an outgoing frame
+pub trait Rewrite: Send + Sync {
+    /// Short name used in log messages
+    fn name(&self) -> &str;
+
+    /// Modifies `frame` in place. Returns `false` if the frame must not be sent.
+    fn apply(&self, frame: &mut Vec<u8>) -> bool;
+}
+
+/// Ordered list of rewrite stages for one egress interface
+#[derive(Default)]
+pub struct RewriteChain {
+    stages: Vec<Box<dyn Rewrite>>,
+}
+
+impl RewriteChain {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, stage: impl Rewrite + 'static) {
+        self.stages.push(Box::new(stage));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.stages.is_empty()
+    }
+
+    /// Runs every stage in order, stopping at the first one that rejects the frame
+    pub fn apply(&self, frame: &mut Vec<u8>) -> Result<(), &str> {
+        for stage in &self.stages {
+            if !stage.apply(frame) {
+                return Err(stage.name());
+            }
+        }
+        Ok(())
+    }
+
+    pub fn names(&self) -> Vec<&str> {
+        self.stages.iter().map(|s| s.name()).collect()
+    }
+}
+
+/// Replaces the source MAC with the egress interface address so the segment
+/// on the other side only ever sees the forwarder's own MAC. The destination
+/// MAC is left untouched, which is what multicast and broadcast frames need.
+pub struct MasqueradeMac {
+    mac: MacAddr,
+}
+
+impl MasqueradeMac {
+    pub fn new(mac: MacAddr) -> Self {
+        MasqueradeMac { mac }
+    }
+}
+
+impl Rewrite for MasqueradeMac {
+    fn name(&self) -> &str {
+        "masquerade-mac"
+    }
+
+    fn apply(&self, frame: &mut Vec<u8>) -> bool {
+        match MutableEthernetPacket::new(frame) {
+            Some(mut eth) => {
+                eth.set_source(self.mac);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Location of the UDP datagram in an Ethernet frame carrying IPv4 or IPv6
+/// without extension headers, for stages that rewrite UDP payloads
+pub struct UdpDatagram {
+    ipv6: bool,
+    /// Offset of the UDP header
+    udp: usize,
+    /// End of the IP packet, before any Ethernet padding
+    end: usize,
+}
+
+impl UdpDatagram {
+    /// Returns `None` for anything but unfragmented UDP
+    pub fn locate(frame: &[u8]) -> Option<Self> {
+        let eth = EthernetPacket::new(frame)?;
+        let l3 = &frame[ETHERNET_HEADER_LEN..];
+        let (ipv6, header_len, total_len) = match eth.get_ethertype() {
+            EtherTypes::Ipv4 => {
+                let ip = Ipv4Packet::new(l3)?;
+                let fragmented =
+                    ip.get_fragment_offset() != 0 || ip.get_flags() & Ipv4Flags::MoreFragments != 0;
+                if ip.get_next_level_protocol() != IpNextHeaderProtocols::Udp || fragmented {
+                    return None;
+                }
+                let header_len = usize::from(ip.get_header_length()) * 4;
+                (false, header_len, usize::from(ip.get_total_length()))
+            }
+            EtherTypes::Ipv6 => {
+                let ip = Ipv6Packet::new(l3)?;
+                if ip.get_next_header() != IpNextHeaderProtocols::Udp {
+                    return None;
+                }
+                let total_len = IPV6_HEADER_LEN + usize::from(ip.get_payload_length());
+                (true, IPV6_HEADER_LEN, total_len)
+            }
+            _ => return None,
+        };
+        let datagram = UdpDatagram {
+            ipv6,
+            udp: ETHERNET_HEADER_LEN + header_len,
+            end: ETHERNET_HEADER_LEN + total_len,
+        };
+        if datagram.udp + UDP_HEADER_LEN > datagram.end || datagram.end > frame.len() {
+            return None;
+        }
+        Some(datagram)
+    }
+
+    /// Source and destination port
+    pub fn ports(&self, frame: &[u8]) -> [u16; 2] {
+        let udp = &frame[self.udp..];
+        [
+            u16::from_be_bytes([udp[0], udp[1]]),
+            u16::from_be_bytes([udp[2], udp[3]]),
+        ]
+    }
+
+    pub fn is_ipv6(&self) -> bool {
+        self.ipv6
+    }
+
+    /// Sets the source and destination port; the checksums are left to
+    /// the caller
+    pub fn set_ports(&self, frame: &mut [u8], ports: [u16; 2]) {
+        frame[self.udp..self.udp + 2].copy_from_slice(&ports[0].to_be_bytes());
+        frame[self.udp + 2..self.udp + 4].copy_from_slice(&ports[1].to_be_bytes());
+    }
+
+    /// Range of the payload in the frame
+    pub fn payload(&self) -> Range<usize> {
+        self.udp + UDP_HEADER_LEN..self.end
+    }
+
+    /// Recomputes the IP and UDP checksums after the payload was modified
+    pub fn update_checksums(&self, frame: &mut [u8]) -> bool {
+        if self.ipv6 {
+            checksum::update_ipv6(frame)
+        } else {
+            checksum::update_ipv4(frame)
+        }
+    }
+
+    /// Replaces the payload, which may change its size, dropping any
+    /// Ethernet padding and fixing up the lengths and checksums
+    pub fn replace_payload(&self, frame: &mut Vec<u8>, payload: &[u8]) -> bool {
+        let udp_len = UDP_HEADER_LEN + payload.len();
+        let ip_len = self.udp - ETHERNET_HEADER_LEN + udp_len;
+        if ip_len > usize::from(u16::MAX) {
+            return false;
+        }
+        frame.truncate(self.end);
+        frame.splice(self.payload(), payload.iter().copied());
+        frame[self.udp + 4..self.udp + 6].copy_from_slice(&(udp_len as u16).to_be_bytes());
+        if self.ipv6 {
+            // The payload length excludes the fixed header
+            let at = ETHERNET_HEADER_LEN + 4;
+            frame[at..at + 2].copy_from_slice(&(udp_len as u16).to_be_bytes());
+        } else {
+            let at = ETHERNET_HEADER_LEN + 2;
+            frame[at..at + 2].copy_from_slice(&(ip_len as u16).to_be_bytes());
+        }
+        self.update_checksums(frame)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::ssdp_search_frame;
+
+    /// Appends its byte to the frame, so the frame records the order the
+    /// stages ran in
+    struct Append(&'static str, u8);
+
+    impl Rewrite for Append {
+        fn name(&self) -> &str {
+            self.0
+        }
+
+        fn apply(&self, frame: &mut Vec<u8>) -> bool {
+            frame.push(self.1);
+            true
+        }
+    }
+
+    struct Reject;
+
+    impl Rewrite for Reject {
+        fn name(&self) -> &str {
+            "reject"
+        }
+
+        fn apply(&self, _frame: &mut Vec<u8>) -> bool {
+            false
+        }
+    }
+
+    #[test]
+    fn runs_stages_in_order_until_one_rejects() {
+        let mut chain = RewriteChain::new();
+        assert!(chain.is_empty());
+        chain.push(Append("first", 1));
+        chain.push(Append("second", 2));
+        let mut frame = Vec::new();
+        assert_eq!(chain.apply(&mut frame), Ok(()));
+        assert_eq!(frame, [1, 2]);
+
+        chain.push(Reject);
+        chain.push(Append("third", 3));
+        assert_eq!(chain.names(), ["first", "second", "reject", "third"]);
+        let mut frame = Vec::new();
+        assert_eq!(chain.apply(&mut frame), Err("reject"));
+        assert_eq!(frame, [1, 2]);
+    }
+
+    #[test]
+    fn masquerades_only_the_source_mac() {
+        let own = MacAddr(0x02, 0, 0, 0, 0, 0xfe);
+        let original = ssdp_search_frame("ssdp:all");
+        let mut frame = original.clone();
+        assert!(MasqueradeMac::new(own).apply(&mut frame));
+        let eth = EthernetPacket::new(&frame).unwrap();
+        assert_eq!(eth.get_source(), own);
+        assert_eq!(frame[..6], original[..6]);
+        assert_eq!(frame[12..], original[12..]);
+        assert!(!MasqueradeMac::new(own).apply(&mut vec![0; 10]));
+    }
+}
@@ -0,0 +1,280 @@
+//! IPv6 router advertisements forwarded from the external to the internal
+//! side only, so the internal hosts configure themselves from the routers
+//! outside while a guest cannot inject advertisements of its own.
+
+use crate::filter::{Decision, Filter, IpHeader, PacketContext};
+use crate::rewrite::Rewrite;
+use pnet::packet::ethernet::{EtherTypes, EthernetPacket, MutableEthernetPacket};
+use pnet::packet::icmpv6::{self, Icmpv6Packet, Icmpv6Types};
+use pnet::packet::ip::IpNextHeaderProtocols;
+use pnet::packet::ipv6::Ipv6Packet;
+use pnet::util::MacAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tracing::debug;
+
+const ETHERNET_HEADER_LEN: usize = 14;
+const IPV6_HEADER_LEN: usize = 40;
+/// Offset of the ICMPv6 message in a frame without extension headers
+const ICMPV6_OFFSET: usize = ETHERNET_HEADER_LEN + IPV6_HEADER_LEN;
+/// ICMPv6 header, hop limit, flags, router lifetime, reachable time and
+/// retransmission timer ahead of the options (RFC 4861 section 4.2)
+const ADVERT_HEADER_LEN: usize = 16;
+const OPTION_SOURCE_LINK_LAYER_ADDRESS: u8 = 1;
+/// Hop limit of every neighbor discovery message (RFC 4861 section 6.1.2)
+const NDP_HOP_LIMIT: u8 = 255;
+
+/// Offsets of the source link-layer address options in the options of an
+/// advertisement, `None` if an option is empty or runs past the end
+fn source_link_layer_options(options: &[u8]) -> Option<Vec<usize>> {
+    let mut found = Vec::new();
+    let mut at = 0;
+    while at < options.len() {
+        let len = usize::from(*options.get(at + 1)?) * 8;
+        if len == 0 || at + len > options.len() {
+            return None;
+        }
+        if options[at] == OPTION_SOURCE_LINK_LAYER_ADDRESS && len >= 8 {
+            found.push(at + 2);
+        }
+        at += len;
+    }
+    Some(found)
+}
+
+/// Forwarding of router advertisements to the internal interface, with the
+/// counters shown in the state dump
+pub struct RouterAdverts {
+    internal_iface: String,
+    /// MAC the advertisements are sent inwards from and announce the router
+    /// at, if rewritten
+    source_mac: Option<MacAddr>,
+    forwarded: AtomicU64,
+    solicitations: AtomicU64,
+    rogue: AtomicU64,
+    malformed: AtomicU64,
+}
+
+impl RouterAdverts {
+    /// Advertisements forwarded to `internal_iface`; with `source_mac` they
+    /// announce it as the router's link-layer address
+    pub fn new(internal_iface: String, source_mac: Option<MacAddr>) -> Self {
+        RouterAdverts {
+            internal_iface,
+            source_mac,
+            forwarded: AtomicU64::new(0),
+            solicitations: AtomicU64::new(0),
+            rogue: AtomicU64::new(0),
+            malformed: AtomicU64::new(0),
+        }
+    }
+
+    fn state(&self) -> Vec<String> {
+        vec![format!(
+            "forwarded {} advertisement(s) and {} solicitation(s), dropped rogue={} malformed={}",
+            self.forwarded.load(Ordering::Relaxed),
+            self.solicitations.load(Ordering::Relaxed),
+            self.rogue.load(Ordering::Relaxed),
+            self.malformed.load(Ordering::Relaxed)
+        )]
+    }
+}
+
+/// Forwards router advertisements from the external side and router
+/// solicitations from the internal one, and drops every advertisement from
+/// the internal side, ahead of the neighbor discovery filter. Other
+/// neighbor discovery is left to the rest of the chain.
+pub struct RouterAdvertFilter {
+    adverts: Arc<RouterAdverts>,
+}
+
+impl RouterAdvertFilter {
+    pub fn new(adverts: Arc<RouterAdverts>) -> Self {
+        RouterAdvertFilter { adverts }
+    }
+}
+
+impl Filter for RouterAdvertFilter {
+    fn name(&self) -> &str {
+        "router-advert"
+    }
+
+    fn evaluate(&self, ctx: &PacketContext) -> Decision {
+        let (Some(IpHeader::V6(ip)), Some(icmpv6)) = (&ctx.ip, ctx.icmpv6()) else {
+            return Decision::Continue;
+        };
+        let internal = ctx.ingress == self.adverts.internal_iface;
+        let valid = ip.get_hop_limit() == NDP_HOP_LIMIT;
+        match icmpv6.get_icmpv6_type() {
+            Icmpv6Types::RouterAdvert if internal => {
+                self.adverts.rogue.fetch_add(1, Ordering::Relaxed);
+                debug!(
+                    "Router advertisement from {} on {} dropped",
+                    ip.get_source(),
+                    ctx.ingress
+                );
+                Decision::Drop
+            }
+            Icmpv6Types::RouterAdvert if valid => {
+                self.adverts.forwarded.fetch_add(1, Ordering::Relaxed);
+                Decision::Forward
+            }
+            Icmpv6Types::RouterSolicit if internal && valid => {
+                self.adverts.solicitations.fetch_add(1, Ordering::Relaxed);
+                Decision::Forward
+            }
+            _ => Decision::Continue,
+        }
+    }
+
+    fn state(&self) -> Option<Vec<String>> {
+        Some(self.adverts.state())
+    }
+}
+
+/// Sends router advertisements inwards from the internal interface's MAC
+/// and announces it in their source link-layer address options, fixing up
+/// the ICMPv6 checksum. Goes after the stages learning the external hosts,
+/// which need the router's own MAC.
+pub struct RewriteAdvertSource {
+    adverts: Arc<RouterAdverts>,
+}
+
+impl RewriteAdvertSource {
+    pub fn new(adverts: Arc<RouterAdverts>) -> Self {
+        RewriteAdvertSource { adverts }
+    }
+}
+
+impl Rewrite for RewriteAdvertSource {
+    fn name(&self) -> &str {
+        "router-advert"
+    }
+
+    fn apply(&self, frame: &mut Vec<u8>) -> bool {
+        let Some(mac) = self.adverts.source_mac else {
+            return true;
+        };
+        let Some(eth) = EthernetPacket::new(frame) else {
+            return true;
+        };
+        if eth.get_ethertype() != EtherTypes::Ipv6 {
+            return true;
+        }
+        let Some(ip) = Ipv6Packet::new(&frame[ETHERNET_HEADER_LEN..]) else {
+            return true;
+        };
+        let advert = ip.get_next_header() == IpNextHeaderProtocols::Icmpv6
+            && frame.get(ICMPV6_OFFSET) == Some(&Icmpv6Types::RouterAdvert.0);
+        if !advert {
+            return true;
+        }
+        let (source, destination) = (ip.get_source(), ip.get_destination());
+        let end = ICMPV6_OFFSET + usize::from(ip.get_payload_length());
+        let options = frame
+            .get(ICMPV6_OFFSET + ADVERT_HEADER_LEN..end)
+            .and_then(source_link_layer_options);
+        let Some(options) = options else {
+            self.adverts.malformed.fetch_add(1, Ordering::Relaxed);
+            debug!("Malformed router advertisement from {} dropped", source);
+            return false;
+        };
+        for at in options {
+            let at = ICMPV6_OFFSET + ADVERT_HEADER_LEN + at;
+            frame[at..at + 6].copy_from_slice(&mac.octets());
+        }
+        MutableEthernetPacket::new(frame)
+            .expect("header was parsed above")
+            .set_source(mac);
+        let checksum = icmpv6::checksum(
+            &Icmpv6Packet::new(&frame[ICMPV6_OFFSET..end]).expect("advertisement was located"),
+            &source,
+            &destination,
+        );
+        frame[ICMPV6_OFFSET + 2..ICMPV6_OFFSET + 4].copy_from_slice(&checksum.to_be_bytes());
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pair::Direction;
+    use pnet::packet::ipv6::MutableIpv6Packet;
+    use std::net::Ipv6Addr;
+
+    /// Advertisement from `mac` at fe80::1 to all nodes, announcing a
+    /// prefix after its source link-layer address option
+    fn advert(mac: MacAddr) -> Vec<u8> {
+        let mut icmpv6 = vec![Icmpv6Types::RouterAdvert.0, 0, 0, 0, 64, 0, 0x07, 0x08];
+        icmpv6.extend([0; 8]);
+        icmpv6.extend([OPTION_SOURCE_LINK_LAYER_ADDRESS, 1]);
+        icmpv6.extend(mac.octets());
+        // Prefix information for 2001:db8::/64
+        icmpv6.extend([3, 4, 64, 0xc0]);
+        icmpv6.extend([0; 12]);
+        icmpv6.extend(0x2001_0db8_u32.to_be_bytes());
+        icmpv6.extend([0; 12]);
+
+        let (from, to) = ("fe80::1".parse().unwrap(), "ff02::1".parse().unwrap());
+        let checksum = icmpv6::checksum(&Icmpv6Packet::new(&icmpv6).unwrap(), &from, &to);
+        icmpv6[2..4].copy_from_slice(&checksum.to_be_bytes());
+        let mut frame = vec![0; ICMPV6_OFFSET];
+        let mut eth = MutableEthernetPacket::new(&mut frame).unwrap();
+        eth.set_source(mac);
+        eth.set_destination(MacAddr::new(0x33, 0x33, 0, 0, 0, 1));
+        eth.set_ethertype(EtherTypes::Ipv6);
+        let mut ip = MutableIpv6Packet::new(&mut frame[ETHERNET_HEADER_LEN..]).unwrap();
+        ip.set_version(6);
+        ip.set_payload_length(icmpv6.len() as u16);
+        ip.set_next_header(IpNextHeaderProtocols::Icmpv6);
+        ip.set_hop_limit(NDP_HOP_LIMIT);
+        ip.set_source(from);
+        ip.set_destination(to);
+        frame.extend(icmpv6);
+        frame
+    }
+
+    #[test]
+    fn forwards_advertisements_inwards_only() {
+        let router = MacAddr::new(0x52, 0x54, 0, 0, 0, 1);
+        let internal = MacAddr::new(2, 0, 0, 0, 0, 2);
+        let adverts = Arc::new(RouterAdverts::new("vm1".to_string(), Some(internal)));
+        let filter = RouterAdvertFilter::new(adverts.clone());
+
+        let frame = advert(router);
+        let outside = PacketContext::parse("eth0", Direction::Inbound, &frame).unwrap();
+        assert_eq!(filter.evaluate(&outside), Decision::Forward);
+        let inside = PacketContext::parse("vm1", Direction::Outbound, &frame).unwrap();
+        assert_eq!(filter.evaluate(&inside), Decision::Drop);
+        let mut solicit = frame.clone();
+        solicit[ICMPV6_OFFSET] = Icmpv6Types::RouterSolicit.0;
+        let inside = PacketContext::parse("vm1", Direction::Outbound, &solicit).unwrap();
+        assert_eq!(filter.evaluate(&inside), Decision::Forward);
+        let mut routed = frame.clone();
+        routed[ETHERNET_HEADER_LEN + 7] = 64;
+        let outside = PacketContext::parse("eth0", Direction::Inbound, &routed).unwrap();
+        assert_eq!(filter.evaluate(&outside), Decision::Continue);
+
+        let mut rewritten = frame.clone();
+        assert!(RewriteAdvertSource::new(adverts.clone()).apply(&mut rewritten));
+        let ctx = PacketContext::parse("eth0", Direction::Inbound, &rewritten).unwrap();
+        assert_eq!(ctx.ethernet.get_source(), internal);
+        let icmpv6 = ctx.icmpv6().unwrap();
+        let from: Ipv6Addr = "fe80::1".parse().unwrap();
+        let to: Ipv6Addr = "ff02::1".parse().unwrap();
+        assert_eq!(icmpv6::checksum(icmpv6, &from, &to), icmpv6.get_checksum());
+        let option = ICMPV6_OFFSET + ADVERT_HEADER_LEN;
+        assert_eq!(rewritten[option + 2..option + 8], internal.octets());
+        // The prefix information is left alone
+        assert_eq!(rewritten[option + 8..], frame[option + 8..]);
+
+        let mut broken = frame.clone();
+        broken[option + 1] = 0;
+        assert!(!RewriteAdvertSource::new(adverts.clone()).apply(&mut broken));
+        assert_eq!(
+            adverts.state(),
+            ["forwarded 1 advertisement(s) and 1 solicitation(s), dropped rogue=1 malformed=1"]
+        );
+    }
+}
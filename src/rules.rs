@@ -0,0 +1,267 @@
+//! User rules that forward or drop traffic per direction.
+
+use crate::filter::{Decision, Filter, PacketContext};
+use crate::pair::Direction;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+use tracing::debug;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    Udp,
+    Tcp,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Forward,
+    Drop,
+}
+
+/// One rule, written as `DIRECTION [PROTOCOL] [sport N] [dport N] [port N]
+/// ACTION`, e.g. `in->out udp dport 1900 forward`. DIRECTION is `in->out`
+/// (internal to external), `out->in` or `any`; PROTOCOL is `udp`, `tcp` or
+/// `any`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Rule {
+    /// `None` matches both directions, including bridged traffic
+    pub direction: Option<Direction>,
+    pub protocol: Option<Protocol>,
+    pub source_port: Option<u16>,
+    pub destination_port: Option<u16>,
+    /// Matches either the source or the destination port
+    pub port: Option<u16>,
+    pub action: Action,
+}
+
+impl Rule {
+    fn matches(&self, ctx: &PacketContext) -> bool {
+        if self
+            .direction
+            .is_some_and(|direction| direction != ctx.direction)
+        {
+            return false;
+        }
+        let ports = match (ctx.udp(), ctx.tcp()) {
+            (Some(udp), _) if self.protocol != Some(Protocol::Tcp) => {
+                Some((udp.get_source(), udp.get_destination()))
+            }
+            (_, Some(tcp)) if self.protocol != Some(Protocol::Udp) => {
+                Some((tcp.get_source(), tcp.get_destination()))
+            }
+            _ => None,
+        };
+        let Some((source, destination)) = ports else {
+            // Without a protocol or port condition the rule covers any frame
+            return self.protocol.is_none()
+                && self.source_port.is_none()
+                && self.destination_port.is_none()
+                && self.port.is_none();
+        };
+        self.source_port.is_none_or(|port| port == source)
+            && self.destination_port.is_none_or(|port| port == destination)
+            && self
+                .port
+                .is_none_or(|port| port == source || port == destination)
+    }
+
+    /// Whether this rule matches every frame `other` matches, so that
+    /// `other` never matches after it
+    pub fn covers(&self, other: &Rule) -> bool {
+        let port = |own: Option<u16>, theirs: &[Option<u16>]| {
+            own.is_none_or(|port| theirs.contains(&Some(port)))
+        };
+        self.direction
+            .is_none_or(|_| self.direction == other.direction)
+            && self
+                .protocol
+                .is_none_or(|_| self.protocol == other.protocol)
+            && port(self.source_port, &[other.source_port])
+            && port(self.destination_port, &[other.destination_port])
+            && port(
+                self.port,
+                &[other.port, other.source_port, other.destination_port],
+            )
+    }
+}
+
+impl FromStr for Rule {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let mut words = value.split_whitespace();
+        let direction = match words.next() {
+            Some("in->out" | "internal->external") => Some(Direction::Outbound),
+            Some("out->in" | "external->internal") => Some(Direction::Inbound),
+            Some("any") => None,
+            Some(other) => {
+                return Err(format!(
+                    "unknown direction '{}', expected in->out, out->in or any",
+                    other
+                ))
+            }
+            None => return Err("empty rule".to_string()),
+        };
+        let mut rule = Rule {
+            direction,
+            protocol: None,
+            source_port: None,
+            destination_port: None,
+            port: None,
+            action: Action::Drop,
+        };
+        let mut action = None;
+        while let Some(word) = words.next() {
+            if action.is_some() {
+                return Err(format!("unexpected '{}' after the action", word));
+            }
+            let slot = match word {
+                "udp" => {
+                    rule.protocol = Some(Protocol::Udp);
+                    continue;
+                }
+                "tcp" => {
+                    rule.protocol = Some(Protocol::Tcp);
+                    continue;
+                }
+                "any" => continue,
+                "forward" => {
+                    action = Some(Action::Forward);
+                    continue;
+                }
+                "drop" => {
+                    action = Some(Action::Drop);
+                    continue;
+                }
+                "sport" => &mut rule.source_port,
+                "dport" => &mut rule.destination_port,
+                "port" => &mut rule.port,
+                other => return Err(format!("unknown word '{}'", other)),
+            };
+            let port = words
+                .next()
+                .and_then(|port| port.parse().ok())
+                .filter(|port| *port != 0)
+                .ok_or_else(|| format!("'{}' needs a port number", word))?;
+            *slot = Some(port);
+        }
+        rule.action = action.ok_or("missing action, expected forward or drop")?;
+        Ok(rule)
+    }
+}
+
+impl TryFrom<String> for Rule {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<Rule> for String {
+    fn from(rule: Rule) -> Self {
+        rule.to_string()
+    }
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let direction = match self.direction {
+            Some(Direction::Outbound) => "in->out",
+            Some(Direction::Inbound) => "out->in",
+            _ => "any",
+        };
+        f.write_str(direction)?;
+        match self.protocol {
+            Some(Protocol::Udp) => f.write_str(" udp")?,
+            Some(Protocol::Tcp) => f.write_str(" tcp")?,
+            None => {}
+        }
+        let ports = [
+            ("sport", self.source_port),
+            ("dport", self.destination_port),
+            ("port", self.port),
+        ];
+        for (word, port) in ports {
+            if let Some(port) = port {
+                write!(f, " {} {}", word, port)?;
+            }
+        }
+        match self.action {
+            Action::Forward => f.write_str(" forward"),
+            Action::Drop => f.write_str(" drop"),
+        }
+    }
+}
+
+/// Applies the first matching rule. Frames no rule matches are left to the
+/// following filters.
+pub struct RuleFilter {
+    rules: Vec<Rule>,
+}
+
+impl RuleFilter {
+    pub fn new(rules: Vec<Rule>) -> Self {
+        RuleFilter { rules }
+    }
+}
+
+impl Filter for RuleFilter {
+    fn name(&self) -> &str {
+        "rules"
+    }
+
+    fn evaluate(&self, ctx: &PacketContext) -> Decision {
+        let Some(rule) = self.rules.iter().find(|rule| rule.matches(ctx)) else {
+            return Decision::Continue;
+        };
+        debug!("Rule '{}' matched", rule);
+        match rule.action {
+            Action::Forward => Decision::Forward,
+            Action::Drop => Decision::Drop,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_and_displays_rules() {
+        let rule: Rule = "in->out udp dport 1900 forward".parse().unwrap();
+        assert_eq!(rule.direction, Some(Direction::Outbound));
+        assert_eq!(rule.protocol, Some(Protocol::Udp));
+        assert_eq!(rule.destination_port, Some(1900));
+        assert_eq!(rule.action, Action::Forward);
+        assert_eq!(rule.to_string(), "in->out udp dport 1900 forward");
+        assert_eq!(
+            "any any drop".parse::<Rule>().unwrap().to_string(),
+            "any drop"
+        );
+
+        assert!("in->out udp dport forward".parse::<Rule>().is_err());
+        assert!("in->out udp".parse::<Rule>().is_err());
+        assert!("up udp drop".parse::<Rule>().is_err());
+        assert!("any drop forward".parse::<Rule>().is_err());
+    }
+
+    #[test]
+    fn finds_rules_covering_others() {
+        let rule = |text: &str| text.parse::<Rule>().unwrap();
+        let covers = |earlier: &str, later: &str| rule(earlier).covers(&rule(later));
+        assert!(covers("any drop", "in->out udp dport 1900 forward"));
+        assert!(covers(
+            "in->out port 5353 forward",
+            "in->out udp sport 5353 drop"
+        ));
+        assert!(covers("any udp forward", "out->in udp dport 53 forward"));
+        assert!(!covers("in->out udp dport 1900 forward", "any drop"));
+        assert!(!covers("in->out drop", "out->in drop"));
+        assert!(!covers("any udp drop", "any port 53 drop"));
+        assert!(!covers("any sport 53 drop", "any port 53 drop"));
+        assert!(!covers("any dport 53 drop", "any sport 53 drop"));
+    }
+}
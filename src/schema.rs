@@ -0,0 +1,191 @@
+//! JSON Schema of the configuration file, derived from the command line
+//! options its keys mirror, for tools that generate the file to check it
+//! before it reaches a device.
+
+use crate::config::{self, Config};
+use crate::profile::PRESETS;
+use crate::Args;
+use clap::{Arg, ArgAction};
+use serde_json::{json, Map, Value as Json};
+use std::any::TypeId;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::path::PathBuf;
+
+const DRAFT: &str = "https://json-schema.org/draft/2020-12/schema";
+
+/// Smallest value the file takes for the integer `key`, one for the keys
+/// that refuse zero
+fn minimum(key: &str, array: bool) -> u64 {
+    let zero = if array {
+        format!("{} = [0]", key)
+    } else {
+        format!("{} = 0", key)
+    };
+    if toml::from_str::<Config>(&zero).is_ok() {
+        0
+    } else {
+        1
+    }
+}
+
+/// Schema of one value of `arg`, as parsed on the command line
+fn value(key: &str, arg: &Arg, array: bool) -> Json {
+    let type_id = arg.get_value_parser().type_id();
+    if type_id == TypeId::of::<bool>() {
+        return json!({ "type": "boolean" });
+    }
+    let names: Vec<String> = arg
+        .get_possible_values()
+        .iter()
+        .filter(|value| !value.is_hide_set())
+        .map(|value| value.get_name().to_string())
+        .collect();
+    if !names.is_empty() {
+        return json!({ "enum": names });
+    }
+    let integers = [
+        (TypeId::of::<u8>(), u64::from(u8::MAX)),
+        (TypeId::of::<u16>(), u64::from(u16::MAX)),
+        (TypeId::of::<u32>(), u64::from(u32::MAX)),
+        (TypeId::of::<u64>(), u64::MAX),
+        (TypeId::of::<usize>(), usize::MAX as u64),
+    ];
+    if let Some((_, maximum)) = integers.iter().find(|(id, _)| type_id == *id) {
+        return json!({
+            "type": "integer",
+            "minimum": minimum(key, array),
+            "maximum": maximum,
+        });
+    }
+    if type_id == TypeId::of::<Ipv4Addr>() {
+        json!({ "type": "string", "format": "ipv4" })
+    } else if type_id == TypeId::of::<Ipv6Addr>() {
+        json!({ "type": "string", "format": "ipv6" })
+    } else if type_id == TypeId::of::<PathBuf>() {
+        json!({ "type": "string", "minLength": 1 })
+    } else {
+        // Durations, addresses, rules and the other values parsed from text
+        json!({ "type": "string" })
+    }
+}
+
+/// Schema of the key `key` for the option `arg`. Keys the file writes
+/// differently from the command line are spelled out.
+fn property(key: &str, arg: &Arg) -> Json {
+    let array = matches!(arg.get_action(), ArgAction::Append);
+    match key {
+        "pair" => json!({
+            "type": "array",
+            "items": {
+                "type": "object",
+                "properties": {
+                    "external": { "type": "string" },
+                    "internal": { "type": "string" },
+                },
+                "required": ["external", "internal"],
+                "additionalProperties": false,
+            },
+        }),
+        "snat" => json!({
+            "oneOf": [{ "type": "boolean" }, { "type": "string", "format": "ipv4" }],
+        }),
+        "pcap-max-size" => json!({
+            "oneOf": [{ "type": "integer", "minimum": 1 }, { "type": "string" }],
+        }),
+        "profile" => {
+            let names: Vec<&str> = PRESETS.iter().map(|preset| preset.name).collect();
+            json!({ "type": "array", "items": { "enum": names } })
+        }
+        _ if array => json!({ "type": "array", "items": value(key, arg, true) }),
+        _ => value(key, arg, false),
+    }
+}
+
+/// Schema of the configuration file: an object with a property per key,
+/// carrying the option's help as description and its default
+pub fn config() -> Json {
+    let defaults = serde_json::to_value(config::effective(&Args::default()))
+        .expect("configuration is representable as JSON");
+    let Json::Object(defaults) = defaults else {
+        unreachable!("configuration serializes to an object");
+    };
+    let command = <Args as clap::Args>::augment_args(clap::Command::new(env!("CARGO_PKG_NAME")));
+    let mut properties = Map::new();
+    for (key, default) in defaults {
+        let Some(arg) = command
+            .get_arguments()
+            .find(|arg| arg.get_long() == Some(key.as_str()))
+        else {
+            properties.insert(key, json!({}));
+            continue;
+        };
+        let mut schema = property(&key, arg);
+        let help = arg.get_long_help().or_else(|| arg.get_help());
+        if let Some(help) = help {
+            schema["description"] = help.to_string().into();
+        }
+        if !default.is_null() {
+            schema["default"] = default;
+        }
+        properties.insert(key, schema);
+    }
+    json!({
+        "$schema": DRAFT,
+        "title": "nw-pckt-fwd configuration",
+        "description": "Keys are named after the long command line options",
+        "type": "object",
+        "properties": properties,
+        "additionalProperties": false,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn describes_every_key_as_the_file_takes_it() {
+        let schema = config();
+        let properties = schema["properties"].as_object().unwrap();
+        assert!(properties.contains_key("pair"));
+        for (key, property) in properties {
+            let described = ["type", "enum", "oneOf"]
+                .iter()
+                .any(|field| property.get(field).is_some());
+            assert!(described, "{} has no type", key);
+        }
+
+        // Every value listed is one the file accepts
+        for (key, property) in properties {
+            let (items, array) = match property.get("items") {
+                Some(items) => (items, true),
+                None => (property, false),
+            };
+            let Some(names) = items.get("enum").and_then(Json::as_array) else {
+                continue;
+            };
+            for name in names {
+                let value = if array {
+                    format!("[{}]", name)
+                } else {
+                    name.to_string()
+                };
+                let parsed = toml::from_str::<Config>(&format!("{} = {}", key, value));
+                assert!(parsed.is_ok(), "{} = {}: {:?}", key, value, parsed.err());
+            }
+        }
+
+        assert_eq!(properties["ports"]["items"]["minimum"], 1);
+        assert_eq!(properties["ports"]["items"]["maximum"], 65535);
+        assert_eq!(properties["dump-payload"]["minimum"], 0);
+        assert_eq!(properties["mac-table-size"]["minimum"], 1);
+        assert_eq!(properties["mac-table-size"]["default"], 1024);
+        assert_eq!(properties["enable-mdns"]["type"], "boolean");
+        assert_eq!(properties["dhcp-relay"]["format"], "ipv4");
+        assert_eq!(properties["backend"]["enum"][0], "pnet");
+        assert!(properties["enable-mdns"]["description"]
+            .as_str()
+            .unwrap()
+            .contains("mDNS"));
+    }
+}
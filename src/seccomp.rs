@@ -0,0 +1,400 @@
+//! Seccomp allowlist installed once the forwarder is set up, so that a bug in
+//! the code parsing untrusted frames cannot make the process do much more
+//! than move packets.
+//!
+//! The list covers the tokio runtime, the packet sockets, logging and
+//! reopening interfaces that went away. Optional parts add what they need:
+//! pcap files and `SIGHUP` reloads open files, which is refused otherwise,
+//! the AF_XDP backend loads XDP programs, the control socket accepts
+//! connections and is removed on exit, the D-Bus connection is shut down,
+//! the OTLP export connects to its collector, resolving its name, the
+//! state file is rewritten and the standby lock file is locked and written.
+
+use crate::error::Error;
+use clap::ValueEnum;
+use seccompiler::{
+    BackendError, BpfProgram, SeccompAction, SeccompFilter, SeccompRule, TargetArch,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use tracing::info;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SeccompMode {
+    /// No filter is installed
+    Off,
+    /// System calls outside the allowlist succeed but are logged by the
+    /// kernel, which shows what a profile is missing
+    Log,
+    /// System calls outside the allowlist kill the process
+    Enforce,
+}
+
+/// Optional parts of the forwarder that need more than the base allowlist
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Features {
+    /// pcap files are written and rotated
+    pub pcap: bool,
+    /// The configuration file is re-read on SIGHUP
+    pub reload: bool,
+    /// XDP programs are loaded when interfaces are reopened and replaced on
+    /// reloads
+    pub xdp: bool,
+    /// Connections to the control socket are accepted
+    pub control: bool,
+    /// The service on the system bus is connected
+    pub dbus: bool,
+    /// Metrics and traces are exported over OTLP
+    pub otel: bool,
+    /// The learned state is saved to a file
+    pub state: bool,
+    /// Leadership is taken by locking a file
+    pub lock: bool,
+}
+
+/// Runtime, memory, threads, signals and time
+const RUNTIME: &[libc::c_long] = &[
+    libc::SYS_read,
+    libc::SYS_write,
+    libc::SYS_readv,
+    libc::SYS_writev,
+    libc::SYS_close,
+    libc::SYS_fcntl,
+    libc::SYS_ioctl,
+    libc::SYS_epoll_ctl,
+    libc::SYS_epoll_pwait,
+    libc::SYS_epoll_pwait2,
+    libc::SYS_ppoll,
+    libc::SYS_eventfd2,
+    libc::SYS_futex,
+    libc::SYS_clock_gettime,
+    libc::SYS_clock_nanosleep,
+    libc::SYS_nanosleep,
+    libc::SYS_sched_yield,
+    libc::SYS_sched_getaffinity,
+    libc::SYS_sched_setaffinity,
+    libc::SYS_getrandom,
+    libc::SYS_mmap,
+    libc::SYS_munmap,
+    libc::SYS_mremap,
+    libc::SYS_mprotect,
+    libc::SYS_madvise,
+    libc::SYS_brk,
+    libc::SYS_rt_sigaction,
+    libc::SYS_rt_sigprocmask,
+    libc::SYS_rt_sigreturn,
+    libc::SYS_sigaltstack,
+    libc::SYS_clone,
+    libc::SYS_clone3,
+    libc::SYS_set_robust_list,
+    libc::SYS_rseq,
+    libc::SYS_prctl,
+    libc::SYS_gettid,
+    libc::SYS_getpid,
+    libc::SYS_tgkill,
+    libc::SYS_exit,
+    libc::SYS_exit_group,
+];
+
+/// Packet sockets, multicast memberships, interface lookups over netlink and
+/// the systemd notification socket
+const NETWORK: &[libc::c_long] = &[
+    libc::SYS_socket,
+    libc::SYS_bind,
+    libc::SYS_getsockname,
+    libc::SYS_getsockopt,
+    libc::SYS_setsockopt,
+    libc::SYS_recvfrom,
+    libc::SYS_recvmsg,
+    libc::SYS_recvmmsg,
+    libc::SYS_sendto,
+    libc::SYS_sendmsg,
+    libc::SYS_sendmmsg,
+];
+
+/// Reading the `/proc/self/fd` links the kernel filter tells its sockets
+/// apart by, also when interfaces are reopened
+#[cfg(target_arch = "x86_64")]
+const LINKS: &[libc::c_long] = &[libc::SYS_readlinkat, libc::SYS_readlink];
+#[cfg(not(target_arch = "x86_64"))]
+const LINKS: &[libc::c_long] = &[libc::SYS_readlinkat];
+
+/// Accepting and closing control connections and removing the socket file
+#[cfg(target_arch = "x86_64")]
+const CONTROL: &[libc::c_long] = &[
+    libc::SYS_accept4,
+    libc::SYS_shutdown,
+    libc::SYS_unlinkat,
+    libc::SYS_unlink,
+];
+#[cfg(not(target_arch = "x86_64"))]
+const CONTROL: &[libc::c_long] = &[libc::SYS_accept4, libc::SYS_shutdown, libc::SYS_unlinkat];
+
+/// Connecting to the OTLP collector and shutting the connections down
+const OTLP: &[libc::c_long] = &[
+    libc::SYS_connect,
+    libc::SYS_getpeername,
+    libc::SYS_shutdown,
+    libc::SYS_uname,
+];
+
+/// Locking the standby lock file and writing the epoch into it
+const LOCK: &[libc::c_long] = &[
+    libc::SYS_flock,
+    libc::SYS_pread64,
+    libc::SYS_pwrite64,
+    libc::SYS_ftruncate,
+    libc::SYS_fsync,
+];
+
+/// Opening, inspecting and renaming files
+const FILES: &[libc::c_long] = &[
+    libc::SYS_openat,
+    libc::SYS_newfstatat,
+    libc::SYS_fstat,
+    libc::SYS_statx,
+    libc::SYS_lseek,
+    libc::SYS_readlinkat,
+    libc::SYS_renameat,
+    libc::SYS_renameat2,
+];
+
+/// Opening files. Without the file features it fails with `EACCES` instead
+/// of being fatal: glibc's allocator reads `/proc/sys/vm/overcommit_memory`
+/// the first time it shrinks the heap of a thread and copes if it cannot.
+#[cfg(target_arch = "x86_64")]
+const OPEN: &[libc::c_long] = &[libc::SYS_openat, libc::SYS_open];
+#[cfg(not(target_arch = "x86_64"))]
+const OPEN: &[libc::c_long] = &[libc::SYS_openat];
+
+/// Older variants glibc still uses on x86_64
+#[cfg(target_arch = "x86_64")]
+const LEGACY: &[libc::c_long] = &[libc::SYS_poll, libc::SYS_epoll_wait];
+#[cfg(not(target_arch = "x86_64"))]
+const LEGACY: &[libc::c_long] = &[];
+
+/// Older variants of the file system calls
+#[cfg(target_arch = "x86_64")]
+const LEGACY_FILES: &[libc::c_long] = &[
+    libc::SYS_open,
+    libc::SYS_stat,
+    libc::SYS_readlink,
+    libc::SYS_rename,
+];
+#[cfg(not(target_arch = "x86_64"))]
+const LEGACY_FILES: &[libc::c_long] = &[];
+
+impl Features {
+    /// Whether files are opened, including `/etc/hosts` and
+    /// `/etc/resolv.conf` to resolve the OTLP collector
+    fn files(self) -> bool {
+        self.pcap || self.reload || self.otel || self.state
+    }
+}
+
+/// System calls allowed with `features` enabled
+pub fn allowlist(features: Features) -> Vec<libc::c_long> {
+    let mut syscalls = [RUNTIME, NETWORK, LINKS, LEGACY].concat();
+    if features.files() {
+        syscalls.extend(FILES);
+        syscalls.extend(LEGACY_FILES);
+    }
+    if features.xdp {
+        syscalls.push(libc::SYS_bpf);
+    }
+    if features.control {
+        syscalls.extend(CONTROL);
+    }
+    if features.dbus {
+        syscalls.push(libc::SYS_shutdown);
+    }
+    if features.otel {
+        syscalls.extend(OTLP);
+    }
+    if features.lock {
+        syscalls.extend(LOCK);
+    }
+    syscalls
+}
+
+/// Compiles a filter taking `matched` on `syscalls` and `mismatch` on
+/// everything else
+fn compile(
+    syscalls: impl IntoIterator<Item = libc::c_long>,
+    mismatch: SeccompAction,
+    matched: SeccompAction,
+) -> Result<BpfProgram, Error> {
+    let rules: BTreeMap<i64, Vec<SeccompRule>> = syscalls
+        .into_iter()
+        .map(|syscall| (syscall, Vec::new()))
+        .collect();
+    let failed = |e: BackendError| Error::Seccomp(e.into());
+    let arch = TargetArch::try_from(std::env::consts::ARCH).map_err(failed)?;
+    let filter = SeccompFilter::new(rules, mismatch, matched, arch).map_err(failed)?;
+    filter.try_into().map_err(failed)
+}
+
+/// Compiles the allowlist for `features` into a filter taking the action of
+/// `mode` on everything else; `None` when the mode is off. Opening files
+/// passes this filter and is left to the one of [`refusal`].
+pub fn filter(mode: SeccompMode, features: Features) -> Result<Option<BpfProgram>, Error> {
+    let mismatch = match mode {
+        SeccompMode::Off => return Ok(None),
+        SeccompMode::Log => SeccompAction::Log,
+        SeccompMode::Enforce => SeccompAction::KillProcess,
+    };
+    let mut syscalls = allowlist(features);
+    syscalls.extend(OPEN);
+    syscalls.sort_unstable();
+    syscalls.dedup();
+    compile(syscalls, mismatch, SeccompAction::Allow).map(Some)
+}
+
+/// Filter refusing to open files when no feature needs to, with `EACCES` or
+/// in log mode a log entry; `None` if files may be opened
+pub fn refusal(mode: SeccompMode, features: Features) -> Result<Option<BpfProgram>, Error> {
+    let refused = match mode {
+        _ if features.files() => return Ok(None),
+        SeccompMode::Off => return Ok(None),
+        SeccompMode::Log => SeccompAction::Log,
+        SeccompMode::Enforce => SeccompAction::Errno(libc::EACCES as u32),
+    };
+    compile(OPEN.iter().copied(), SeccompAction::Allow, refused).map(Some)
+}
+
+/// Installs the filters for `mode` on every thread of the process; threads
+/// started later inherit them
+pub fn install(mode: SeccompMode, features: Features) -> Result<(), Error> {
+    let Some(program) = filter(mode, features)? else {
+        return Ok(());
+    };
+    // The kernel takes the most restrictive verdict of all filters, so the
+    // refusal wins over the allowlist letting files be opened
+    if let Some(refusal) = refusal(mode, features)? {
+        seccompiler::apply_filter_all_threads(&refusal).map_err(Error::Seccomp)?;
+    }
+    seccompiler::apply_filter_all_threads(&program).map_err(Error::Seccomp)?;
+    info!(
+        "Seccomp filter of {} instructions installed in {} mode",
+        program.len(),
+        match mode {
+            SeccompMode::Log => "log",
+            _ => "enforce",
+        }
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::iface::find_interface;
+    use crate::kernelfilter::KernelFilter;
+    use pnet::datalink;
+    use std::io;
+
+    #[test]
+    fn files_are_allowed_only_for_features_that_need_them() {
+        let base = allowlist(Features::default());
+        assert!(base.contains(&libc::SYS_recvfrom));
+        assert!(!base.contains(&libc::SYS_openat));
+        assert!(!base.contains(&libc::SYS_execve));
+        assert!(!base.contains(&libc::SYS_bpf));
+        assert!(base.contains(&libc::SYS_readlinkat));
+        let xdp = Features {
+            xdp: true,
+            ..Features::default()
+        };
+        assert!(allowlist(xdp).contains(&libc::SYS_bpf));
+        assert!(!base.contains(&libc::SYS_accept4));
+        let control = Features {
+            control: true,
+            ..Features::default()
+        };
+        assert!(allowlist(control).contains(&libc::SYS_accept4));
+        assert!(!base.contains(&libc::SYS_shutdown));
+        let dbus = Features {
+            dbus: true,
+            ..Features::default()
+        };
+        assert!(allowlist(dbus).contains(&libc::SYS_shutdown));
+        assert!(!base.contains(&libc::SYS_connect));
+        let otel = Features {
+            otel: true,
+            ..Features::default()
+        };
+        assert!(allowlist(otel).contains(&libc::SYS_connect));
+        assert!(allowlist(otel).contains(&libc::SYS_openat));
+        let lock = Features {
+            lock: true,
+            ..Features::default()
+        };
+        assert!(allowlist(lock).contains(&libc::SYS_flock));
+        assert!(!base.contains(&libc::SYS_flock));
+        for features in [
+            Features {
+                pcap: true,
+                ..Features::default()
+            },
+            Features {
+                reload: true,
+                ..Features::default()
+            },
+            Features {
+                state: true,
+                ..Features::default()
+            },
+        ] {
+            assert!(allowlist(features).contains(&libc::SYS_openat));
+        }
+
+        assert!(filter(SeccompMode::Off, Features::default())
+            .unwrap()
+            .is_none());
+        let program = filter(SeccompMode::Enforce, Features::default()).unwrap();
+        assert!(program.is_some_and(|program| !program.is_empty()));
+        assert!(refusal(SeccompMode::Enforce, Features::default())
+            .unwrap()
+            .is_some());
+        let pcap = Features {
+            pcap: true,
+            ..Features::default()
+        };
+        assert!(refusal(SeccompMode::Enforce, pcap).unwrap().is_none());
+    }
+
+    #[test]
+    fn reopening_an_interface_needs_only_the_base_allowlist() {
+        // What the capture loop does once an interface comes back, in a
+        // thread confined to the base allowlist where anything else fails
+        // with ENOSYS instead of killing the test
+        let reopened = std::thread::spawn(|| {
+            let rules: BTreeMap<i64, Vec<SeccompRule>> = allowlist(Features::default())
+                .into_iter()
+                .map(|syscall| (syscall, Vec::new()))
+                .collect();
+            let arch = TargetArch::try_from(std::env::consts::ARCH).unwrap();
+            let mismatch = SeccompAction::Errno(libc::ENOSYS as u32);
+            let filter = SeccompFilter::new(rules, mismatch, SeccompAction::Allow, arch).unwrap();
+            let program: BpfProgram = filter.try_into().unwrap();
+            seccompiler::apply_filter(&program).unwrap();
+
+            let lo = find_interface(&datalink::interfaces(), "lo").unwrap();
+            let fd = KernelFilter::new(None).open(&lo)?;
+            let config = datalink::Config {
+                socket_fd: Some(fd),
+                ..datalink::Config::default()
+            };
+            datalink::channel(&lo, config).map(drop)
+        })
+        .join()
+        .unwrap();
+        match reopened {
+            Err(e) if e.kind() == io::ErrorKind::PermissionDenied => {
+                eprintln!("Skipped, opening a packet socket needs CAP_NET_RAW")
+            }
+            result => result.unwrap(),
+        }
+    }
+}
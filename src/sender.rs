@@ -0,0 +1,649 @@
+//! Per-interface send tasks fed through bounded queues.
+
+use crate::filter::PacketContext;
+use crate::latency::{self, Stage};
+use crate::link::PacketSink;
+use crate::logging::ThrottledWarning;
+use crate::pause::Pause;
+use crate::pool::PacketBuffer;
+use crate::shaper::Shaper;
+use crate::stats::{DropReason, MirrorStats, PathStats};
+use crate::summary::{packet_event, PacketSummary};
+use crate::threads;
+use crate::vlan;
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::io;
+use std::iter;
+use std::sync::{mpsc as std_mpsc, Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, info, info_span, warn, Level};
+
+/// Frame waiting to be sent, with where it came from
+type Outgoing = (PacketBuffer, Origin);
+
+/// Where a queued frame came from
+#[derive(Clone)]
+pub struct Origin {
+    /// Counters of the path the frame is forwarded on
+    pub stats: Arc<PathStats>,
+    /// When the frame was received, if it was forwarded rather than made up
+    pub received: Option<Instant>,
+    /// ID given by `--trace-packets`
+    pub trace: Option<u64>,
+}
+
+impl Origin {
+    /// Origin of a frame made up by the forwarder itself
+    pub fn new(stats: &Arc<PathStats>) -> Self {
+        Origin {
+            stats: stats.clone(),
+            received: None,
+            trace: None,
+        }
+    }
+
+    /// Counts `frame` as forwarded, with its latency if it was received
+    fn sent(&self, frame: &[u8], at: Instant, iface: &str) {
+        self.stats.forwarded(frame.len());
+        if let Some(received) = self.received {
+            self.stats.latency(at.saturating_duration_since(received));
+            if let Some(id) = self.trace {
+                latency::trace(id, Stage::Sent, iface, received);
+            }
+        }
+    }
+}
+
+/// Minimum time between two warnings about failed mirror sends
+const MIRROR_WARNING_INTERVAL: Duration = Duration::from_secs(10);
+/// Minimum time between two errors about failed sends on one interface
+const SEND_ERROR_INTERVAL: Duration = Duration::from_secs(10);
+/// Retries of a send that failed because the interface was busy
+const SEND_RETRIES: u32 = 5;
+/// Wait before the first retry, doubled for every further one
+const RETRY_BACKOFF: Duration = Duration::from_millis(1);
+
+/// Which frame gives way when a send queue is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum QueuePolicy {
+    /// The frame being queued is dropped
+    DropNewest,
+    /// The frame queued longest ago is dropped to make room
+    DropOldest,
+}
+
+/// Bounded queue between the capture loops and the send task of one
+/// interface. Adding never waits: when it is full the policy decides which
+/// frame is shed.
+struct Outbox {
+    state: Mutex<OutboxState>,
+    /// Signalled when a frame is added or the last handle goes away
+    changed: Condvar,
+    capacity: usize,
+    policy: QueuePolicy,
+}
+
+struct OutboxState {
+    frames: VecDeque<Outgoing>,
+    /// Live [`SendQueue`] handles; the send task stops at zero
+    handles: usize,
+}
+
+impl Outbox {
+    /// Waits for frames and moves up to `max` of them to `frames`, with
+    /// where they came from to `origins`. Returns `false` once the
+    /// queue is empty and every handle is gone.
+    fn next_batch(
+        &self,
+        max: usize,
+        frames: &mut Vec<PacketBuffer>,
+        origins: &mut Vec<Origin>,
+    ) -> bool {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if !state.frames.is_empty() {
+                let count = state.frames.len().min(max);
+                for (frame, origin) in state.frames.drain(..count) {
+                    frames.push(frame);
+                    origins.push(origin);
+                }
+                return true;
+            }
+            if state.handles == 0 {
+                return false;
+            }
+            state = self.changed.wait(state).unwrap();
+        }
+    }
+
+    /// Empties the queue, returning how many frames were in it
+    fn clear(&self) -> usize {
+        let mut state = self.state.lock().unwrap();
+        let count = state.frames.len();
+        state.frames.clear();
+        count
+    }
+}
+
+/// Handle used by capture loops to queue frames for one egress interface
+pub struct SendQueue {
+    iface: Arc<str>,
+    outbox: Arc<Outbox>,
+    replace: std_mpsc::Sender<Box<dyn PacketSink>>,
+}
+
+impl SendQueue {
+    /// Queues `frame` without blocking, so capture is never stalled by a
+    /// slow interface. If the queue is full a frame is shed as the policy
+    /// says: this one, reported by returning `false`, or the oldest queued
+    /// one, which is counted on the path it came from. The send result is
+    /// counted on the path of `origin`.
+    pub fn enqueue(&self, frame: PacketBuffer, origin: Origin) -> bool {
+        self.enqueue_all(iter::once(frame), origin)
+    }
+
+    /// Queues `frames`, such as the fragments of one datagram, as
+    /// [`enqueue`](Self::enqueue) does one, but all of them or none: a
+    /// receiver cannot reassemble a datagram missing a fragment. Returns
+    /// `false` if there is no room for them.
+    pub fn enqueue_all(
+        &self,
+        frames: impl ExactSizeIterator<Item = PacketBuffer>,
+        origin: Origin,
+    ) -> bool {
+        let count = frames.len();
+        let mut state = self.outbox.state.lock().unwrap();
+        let room = self.outbox.capacity.saturating_sub(state.frames.len());
+        if count > room {
+            if self.outbox.policy == QueuePolicy::DropNewest || count > self.outbox.capacity {
+                debug!("Send queue for {} full, frame dropped", self.iface);
+                return false;
+            }
+            for _ in room..count {
+                if let Some((_, shed)) = state.frames.pop_front() {
+                    debug!("Send queue for {} full, oldest frame dropped", self.iface);
+                    shed.stats.dropped(DropReason::QueueFull);
+                }
+            }
+        }
+        for frame in frames {
+            origin.stats.queued();
+            state.frames.push_back((frame, origin.clone()));
+        }
+        drop(state);
+        self.outbox.changed.notify_one();
+        true
+    }
+
+    /// Hands a freshly opened sender to the send task, e.g. after the
+    /// interface was re-created. It is picked up before the next send.
+    pub fn replace_sender(&self, tx: Box<dyn PacketSink>) {
+        let _ = self.replace.send(tx);
+    }
+}
+
+impl Clone for SendQueue {
+    fn clone(&self) -> Self {
+        self.outbox.state.lock().unwrap().handles += 1;
+        SendQueue {
+            iface: self.iface.clone(),
+            outbox: self.outbox.clone(),
+            replace: self.replace.clone(),
+        }
+    }
+}
+
+impl Drop for SendQueue {
+    fn drop(&mut self) {
+        self.outbox.state.lock().unwrap().handles -= 1;
+        self.outbox.changed.notify_all();
+    }
+}
+
+/// Whether a failed send is worth retrying shortly: the socket buffer or
+/// the device queue was full
+fn transient(e: &io::Error) -> bool {
+    e.kind() == io::ErrorKind::WouldBlock || e.raw_os_error() == Some(libc::ENOBUFS)
+}
+
+/// Sends the first frames of `frames`, as many as `tx` takes at once,
+/// retrying with exponential backoff while the interface is busy. Returns
+/// how many were sent. Retries are counted in `stats` and given up on once
+/// `token` is cancelled.
+fn send(
+    tx: &mut dyn PacketSink,
+    frames: &[PacketBuffer],
+    stats: &PathStats,
+    token: &CancellationToken,
+) -> io::Result<usize> {
+    let mut backoff = RETRY_BACKOFF;
+    let mut retries = 0;
+    loop {
+        match tx.send_batch(frames) {
+            Err(e) if transient(&e) && retries < SEND_RETRIES && !token.is_cancelled() => {
+                retries += 1;
+                stats.retried();
+                std::thread::sleep(backoff);
+                backoff *= 2;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Sends `frames` in as few batches as `tx` allows, counting each one as
+/// forwarded or failed on the path it came from in `origins`
+fn send_all(
+    tx: &mut dyn PacketSink,
+    frames: &[PacketBuffer],
+    origins: &[Origin],
+    iface: &str,
+    errors: &ThrottledWarning,
+    token: &CancellationToken,
+) {
+    let mut done = 0;
+    while done < frames.len() {
+        match send(tx, &frames[done..], &origins[done].stats, token) {
+            Ok(sent) => {
+                let at = Instant::now();
+                for (frame, origin) in frames[done..done + sent].iter().zip(&origins[done..]) {
+                    origin.sent(frame, at, iface);
+                }
+                debug!("{} packet(s) forwarded to {}", sent, iface);
+                done += sent;
+            }
+            Err(e) => {
+                origins[done].stats.dropped(DropReason::SendError);
+                if let Some(suppressed) = errors.occurred() {
+                    error!("Failed to forward packet to {}: {}{}", iface, e, suppressed);
+                }
+                done += 1;
+            }
+        }
+    }
+}
+
+/// Sends `frames` once `shaper` lets them go, in batches of the frames due
+/// at the same time. Frames it would delay past its maximum are dropped
+/// first, and the delays of the others recorded on their paths.
+fn send_shaped(
+    tx: &mut dyn PacketSink,
+    shaper: &mut Shaper,
+    frames: &mut Vec<PacketBuffer>,
+    origins: &mut Vec<Origin>,
+    iface: &str,
+    errors: &ThrottledWarning,
+    token: &CancellationToken,
+) {
+    let now = Instant::now();
+    let mut waits = Vec::with_capacity(frames.len());
+    for i in 0..frames.len() {
+        let stats = &origins[i].stats;
+        match shaper.reserve(stats.direction, frames[i].len(), now) {
+            Some(wait) => {
+                stats.shaped(wait);
+                frames.swap(waits.len(), i);
+                origins.swap(waits.len(), i);
+                waits.push(wait);
+            }
+            None => stats.dropped(DropReason::Shaper),
+        }
+    }
+    frames.truncate(waits.len());
+    origins.truncate(waits.len());
+    let mut start = 0;
+    for (i, wait) in waits.into_iter().enumerate() {
+        let due = now + wait;
+        let at = Instant::now();
+        if due > at && !token.is_cancelled() {
+            send_all(
+                tx,
+                &frames[start..i],
+                &origins[start..i],
+                iface,
+                errors,
+                token,
+            );
+            start = i;
+            std::thread::sleep(due - at);
+        }
+    }
+    send_all(
+        tx,
+        &frames[start..],
+        &origins[start..],
+        iface,
+        errors,
+        token,
+    );
+}
+
+/// Spawns the thread owning `tx`, pinned to `cpus` if given. It runs until
+/// every [`SendQueue`] clone is dropped; frames still queued once `token` is
+/// cancelled are abandoned. With `dry_run` frames are counted and logged
+/// instead of sent. Frames taken from the queue while `pause` is set or
+/// the instance stands by are dropped. With a `shaper` frames over its
+/// rates wait before they are sent, while the queue keeps filling up to its
+/// capacity.
+#[allow(clippy::too_many_arguments)]
+pub fn spawn_sender(
+    iface: &str,
+    mut tx: Box<dyn PacketSink>,
+    capacity: usize,
+    policy: QueuePolicy,
+    dry_run: bool,
+    pause: Arc<Pause>,
+    mut shaper: Option<Shaper>,
+    cpus: Option<&[usize]>,
+    token: CancellationToken,
+) -> (SendQueue, JoinHandle<()>) {
+    let outbox = Arc::new(Outbox {
+        state: Mutex::new(OutboxState {
+            frames: VecDeque::with_capacity(capacity),
+            handles: 1,
+        }),
+        changed: Condvar::new(),
+        capacity,
+        policy,
+    });
+    let (replace_tx, replace_rx) = std_mpsc::channel();
+    let queue = SendQueue {
+        iface: iface.into(),
+        outbox: outbox.clone(),
+        replace: replace_tx,
+    };
+    let iface = queue.iface.clone();
+    let handle = threads::spawn(format!("tx-{}", iface), cpus, move || {
+        let _span = info_span!("send", iface = &*iface).entered();
+        let errors = ThrottledWarning::new(SEND_ERROR_INTERVAL);
+        let mut frames = Vec::new();
+        let mut origins = Vec::new();
+        while outbox.next_batch(tx.batch_size(), &mut frames, &mut origins) {
+            if token.is_cancelled() {
+                let abandoned = frames.len() + outbox.clear();
+                info!("Abandoned {} queued frame(s) for {}", abandoned, iface);
+                break;
+            }
+            while let Ok(replacement) = replace_rx.try_recv() {
+                debug!("Sender for {} replaced", iface);
+                tx = replacement;
+            }
+            // Held until the batch is sent, so pausing waits for it
+            let sending = pause.sending();
+            if sending.is_none() {
+                let reason = if pause.is_paused() {
+                    DropReason::Paused
+                } else {
+                    DropReason::Standby
+                };
+                debug!(
+                    "Forwarding paused or standing by, {} queued frame(s) dropped",
+                    frames.len()
+                );
+                for origin in &origins {
+                    origin.stats.dropped(reason);
+                }
+            } else if dry_run {
+                for (frame, origin) in frames.iter().zip(&origins) {
+                    let stats = &origin.stats;
+                    stats.forwarded(frame.len());
+                    let (untagged, tags) = vlan::untag(frame);
+                    if let Some(mut ctx) =
+                        PacketContext::parse(&stats.ingress, stats.direction, &untagged)
+                    {
+                        ctx.vlan = tags.id();
+                        let summary =
+                            PacketSummary::new(&ctx, &iface, &stats.pair, stats.direction, Ok(()));
+                        packet_event!(Level::INFO, summary, "Dry run, not sent: {}", summary);
+                    }
+                }
+            } else if let Some(shaper) = shaper.as_mut() {
+                send_shaped(
+                    tx.as_mut(),
+                    shaper,
+                    &mut frames,
+                    &mut origins,
+                    &iface,
+                    &errors,
+                    &token,
+                );
+            } else {
+                send_all(tx.as_mut(), &frames, &origins, &iface, &errors, &token);
+            }
+            frames.clear();
+            origins.clear();
+        }
+        debug!("Sender for {} stopped", iface);
+    });
+    (queue, handle)
+}
+
+/// Handle used by capture loops to queue copies of frames for the mirror
+/// interface
+#[derive(Clone)]
+pub struct MirrorQueue {
+    tx: mpsc::Sender<Vec<u8>>,
+    stats: Arc<MirrorStats>,
+    /// Whether dropped frames are mirrored too
+    dropped: bool,
+}
+
+impl MirrorQueue {
+    /// Queues a copy of a forwarded frame without blocking. If the mirror
+    /// falls behind the copy is counted and left out.
+    pub fn forwarded(&self, frame: Vec<u8>) {
+        if let Err(TrySendError::Full(_)) = self.tx.try_send(frame) {
+            self.stats.queue_full();
+        }
+    }
+
+    pub fn stats(&self) -> Arc<MirrorStats> {
+        self.stats.clone()
+    }
+
+    /// Queues a copy of a dropped frame if dropped frames are mirrored
+    pub fn dropped(&self, frame: &[u8]) {
+        if self.dropped {
+            self.forwarded(frame.to_vec());
+        }
+    }
+}
+
+/// Spawns the thread sending copies of frames to the mirror interface, pinned
+/// to `cpus` if given. Like [`spawn_sender`] it runs until every
+/// [`MirrorQueue`] clone is dropped.
+/// Send failures are counted in `stats` and warned about at most once per
+/// [`MIRROR_WARNING_INTERVAL`].
+pub fn spawn_mirror(
+    mut tx: Box<dyn PacketSink>,
+    stats: Arc<MirrorStats>,
+    capacity: usize,
+    dropped: bool,
+    dry_run: bool,
+    cpus: Option<&[usize]>,
+    token: CancellationToken,
+) -> (MirrorQueue, JoinHandle<()>) {
+    let (queue_tx, mut queue_rx) = mpsc::channel::<Vec<u8>>(capacity);
+    let queue = MirrorQueue {
+        tx: queue_tx,
+        stats: stats.clone(),
+        dropped,
+    };
+    let name = format!("tx-{}", stats.iface);
+    let handle = threads::spawn(name, cpus, move || {
+        let _span = info_span!("mirror", iface = stats.iface.as_str()).entered();
+        let warning = ThrottledWarning::new(MIRROR_WARNING_INTERVAL);
+        while let Some(frame) = queue_rx.blocking_recv() {
+            if token.is_cancelled() {
+                break;
+            }
+            if dry_run {
+                stats.sent();
+                continue;
+            }
+            match tx.send(&frame) {
+                Ok(()) => stats.sent(),
+                Err(e) => {
+                    stats.send_error();
+                    if let Some(suppressed) = warning.occurred() {
+                        warn!(
+                            "Failed to mirror frame to {}: {}{}",
+                            stats.iface, e, suppressed
+                        );
+                    }
+                }
+            }
+        }
+        debug!("Mirror to {} stopped", stats.iface);
+    });
+    (queue, handle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::capture::{process_packet, ForwardPath};
+    use crate::filter::{FilterChain, UdpPortFilter, SSDP_PORT};
+    use crate::link::memory::{BusySink, VecSink};
+    use crate::stats::{MirrorSnapshot, PathSnapshot};
+    use crate::testutil::{mdns_query_frame, ssdp_search_frame};
+    use std::collections::HashSet;
+
+    const TIMEOUT: Duration = Duration::from_secs(1);
+
+    /// Frames that reached the egress and the mirror, with the counters of
+    /// the path and the mirror, after a search and a query went through a
+    /// path forwarding SSDP only. The mirror fails its first `failures`
+    /// sends.
+    async fn run_mirrored(
+        dropped: bool,
+        failures: usize,
+    ) -> (Vec<Vec<u8>>, Vec<Vec<u8>>, PathSnapshot, MirrorSnapshot) {
+        let token = CancellationToken::new();
+        let egress = VecSink::default();
+        let (queue, sender) = spawn_sender(
+            "test1",
+            Box::new(egress.clone()),
+            16,
+            QueuePolicy::DropNewest,
+            false,
+            Arc::default(),
+            None,
+            None,
+            token.clone(),
+        );
+        let busy = BusySink::default();
+        busy.fail(failures);
+        let stats = Arc::new(MirrorStats::new("mirror0".to_string()));
+        let (mirror, mirroring) = spawn_mirror(
+            Box::new(busy.clone()),
+            stats.clone(),
+            16,
+            dropped,
+            false,
+            None,
+            token.clone(),
+        );
+        let mut filters = FilterChain::new();
+        filters.push(UdpPortFilter::new(HashSet::from([SSDP_PORT])));
+        let mut path = ForwardPath::plain("test0", "test1", filters, queue);
+        path.mirror = Some(mirror);
+
+        let frames = [
+            ssdp_search_frame("ssdp:all"),
+            mdns_query_frame("_ipp._tcp.local"),
+        ];
+        for frame in &frames {
+            process_packet(frame, &path, Instant::now());
+        }
+        let mirrored = if dropped { 2 } else { 1 };
+        let start = Instant::now();
+        loop {
+            let done = stats.snapshot();
+            if egress.frames().len() == 1 && done.sent + done.send_error == mirrored {
+                break;
+            }
+            assert!(start.elapsed() < TIMEOUT, "frames did not arrive");
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        let counters = path.stats.snapshot();
+        drop(path);
+        sender.await.unwrap();
+        mirroring.await.unwrap();
+        (
+            egress.frames(),
+            busy.sent().frames(),
+            counters,
+            stats.snapshot(),
+        )
+    }
+
+    #[tokio::test]
+    async fn mirror_failures_do_not_reach_the_forwarded_path() {
+        let (sent, mirrored, path, mirror) = run_mirrored(false, 1).await;
+        assert_eq!(sent, [ssdp_search_frame("ssdp:all")]);
+        assert_eq!((path.forwarded, path.send_error), (1, 0));
+        assert_eq!(mirrored, Vec::<Vec<u8>>::new());
+        assert_eq!((mirror.sent, mirror.send_error), (0, 1));
+    }
+
+    #[tokio::test]
+    async fn mirrors_dropped_frames_only_when_asked() {
+        let (_, mirrored, _, mirror) = run_mirrored(false, 0).await;
+        assert_eq!(mirrored, [ssdp_search_frame("ssdp:all")]);
+        assert_eq!((mirror.sent, mirror.send_error), (1, 0));
+
+        let (sent, mirrored, path, _) = run_mirrored(true, 0).await;
+        assert_eq!(sent, [ssdp_search_frame("ssdp:all")]);
+        assert_eq!(path.port_mismatch, 1);
+        assert_eq!(
+            mirrored,
+            [
+                ssdp_search_frame("ssdp:all"),
+                mdns_query_frame("_ipp._tcp.local")
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn counts_frames_without_sending_them_on_a_dry_run() {
+        let token = CancellationToken::new();
+        let egress = VecSink::default();
+        let (queue, sender) = spawn_sender(
+            "test1",
+            Box::new(egress.clone()),
+            16,
+            QueuePolicy::DropNewest,
+            true,
+            Arc::default(),
+            None,
+            None,
+            token.clone(),
+        );
+        let mut filters = FilterChain::new();
+        filters.push(UdpPortFilter::new(HashSet::from([SSDP_PORT])));
+        let path = ForwardPath::plain("test0", "test1", filters, queue);
+        for frame in [
+            ssdp_search_frame("ssdp:all"),
+            mdns_query_frame("_ipp._tcp.local"),
+        ] {
+            process_packet(&frame, &path, Instant::now());
+        }
+        let start = Instant::now();
+        while path.stats.snapshot().forwarded == 0 {
+            assert!(start.elapsed() < TIMEOUT, "frames did not arrive");
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        let counters = path.stats.snapshot();
+        drop(path);
+        sender.await.unwrap();
+        assert_eq!((counters.forwarded, counters.port_mismatch), (1, 1));
+        assert_eq!(egress.frames(), Vec::<Vec<u8>>::new());
+    }
+}
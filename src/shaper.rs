@@ -0,0 +1,245 @@
+//! Smoothing of what is sent: a token bucket per direction in each send
+//! task delays frames over the rate instead of dropping them, so that a
+//! burst of answers is spread out rather than overrunning the receiver's
+//! ring. Frames that would have to wait longer than the maximum delay are
+//! dropped, which bounds the latency the shaper adds.
+
+use crate::pair::Direction;
+use crate::pcap::parse_size;
+use crate::quota::size;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+/// Burst of a shape that gives none
+const DEFAULT_BURST: u64 = 16 * 1024;
+
+/// One shaped direction, written as `DIRECTION RATE/s [burst SIZE]`, e.g.
+/// `out->in 2M/s burst 32K`. DIRECTION is as in the rules; RATE and SIZE
+/// are bytes with the suffixes of `--pcap-max-size`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Shape {
+    /// `None` shapes all directions together, including bridged traffic
+    pub direction: Option<Direction>,
+    /// Bytes per second
+    pub rate: u64,
+    /// Bytes sent at once after an idle period
+    pub burst: u64,
+}
+
+impl FromStr for Shape {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let mut words = value.split_whitespace();
+        let direction = match words.next() {
+            Some("in->out" | "internal->external") => Some(Direction::Outbound),
+            Some("out->in" | "external->internal") => Some(Direction::Inbound),
+            Some("any") => None,
+            Some(other) => {
+                return Err(format!(
+                    "unknown direction '{}', expected in->out, out->in or any",
+                    other
+                ))
+            }
+            None => return Err("empty shape".to_string()),
+        };
+        let rate = words
+            .next()
+            .and_then(|rate| rate.strip_suffix("/s"))
+            .ok_or("missing rate, expected RATE/s")?;
+        let rate = parse_size(rate)?;
+        if rate == 0 {
+            return Err("the rate must be greater than zero".to_string());
+        }
+        let burst = match words.next() {
+            Some("burst") => {
+                let burst = parse_size(words.next().ok_or("'burst' needs a value")?)?;
+                if burst == 0 {
+                    return Err("the burst must be greater than zero".to_string());
+                }
+                burst
+            }
+            Some(other) => return Err(format!("unknown word '{}'", other)),
+            None => DEFAULT_BURST,
+        };
+        if let Some(word) = words.next() {
+            return Err(format!("unexpected '{}' after the burst", word));
+        }
+        Ok(Shape {
+            direction,
+            rate,
+            burst,
+        })
+    }
+}
+
+impl TryFrom<String> for Shape {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<Shape> for String {
+    fn from(shape: Shape) -> Self {
+        shape.to_string()
+    }
+}
+
+impl fmt::Display for Shape {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let direction = match self.direction {
+            Some(Direction::Outbound) => "in->out",
+            Some(Direction::Inbound) => "out->in",
+            _ => "any",
+        };
+        write!(
+            f,
+            "{} {}/s burst {}",
+            direction,
+            size(self.rate),
+            size(self.burst)
+        )
+    }
+}
+
+/// Token bucket of one shape. Tokens go below zero for frames reserved
+/// ahead of time, which then wait until the bucket is back at zero.
+#[derive(Debug)]
+struct Bucket {
+    shape: Shape,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.shape.rate as f64).min(self.shape.burst as f64);
+        self.updated = now;
+    }
+
+    /// Time until the bucket is back at zero
+    fn wait(&self) -> Duration {
+        if self.tokens >= 0.0 {
+            return Duration::ZERO;
+        }
+        Duration::from_secs_f64(-self.tokens / self.shape.rate as f64)
+    }
+}
+
+/// Token buckets of one send task
+#[derive(Debug)]
+pub struct Shaper {
+    buckets: Vec<Bucket>,
+    max_delay: Duration,
+}
+
+impl Shaper {
+    /// Buckets for `shapes`, starting full; `None` without any
+    pub fn new(shapes: &[Shape], max_delay: Duration) -> Option<Self> {
+        if shapes.is_empty() {
+            return None;
+        }
+        let now = Instant::now();
+        let buckets = shapes
+            .iter()
+            .map(|shape| Bucket {
+                shape: shape.clone(),
+                tokens: shape.burst as f64,
+                updated: now,
+            })
+            .collect();
+        Some(Shaper { buckets, max_delay })
+    }
+
+    /// Reserves `len` bytes in the bucket of `direction`, the first shape
+    /// naming it. Returns how long the frame has to wait from `now`, zero
+    /// for directions that are not shaped, or `None` if that is longer than
+    /// the maximum delay and it is to be dropped.
+    pub fn reserve(&mut self, direction: Direction, len: usize, now: Instant) -> Option<Duration> {
+        let Some(bucket) = self
+            .buckets
+            .iter_mut()
+            .find(|bucket| bucket.shape.direction.is_none_or(|d| d == direction))
+        else {
+            return Some(Duration::ZERO);
+        };
+        bucket.refill(now);
+        let wait = bucket.wait();
+        if wait > self.max_delay {
+            return None;
+        }
+        bucket.tokens -= len as f64;
+        Some(wait)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_shapes() {
+        let shape: Shape = "out->in 2M/s burst 32K".parse().unwrap();
+        assert_eq!(shape.direction, Some(Direction::Inbound));
+        assert_eq!((shape.rate, shape.burst), (2 << 20, 32 << 10));
+        assert_eq!(shape.to_string(), "out->in 2M/s burst 32K");
+        let shape: Shape = "any 100000/s".parse().unwrap();
+        assert_eq!(shape.direction, None);
+        assert_eq!(shape.burst, DEFAULT_BURST);
+        for invalid in [
+            "",
+            "out->in",
+            "out->in 2M",
+            "out->in 0/s",
+            "in->out 1M/s burst",
+        ] {
+            assert!(invalid.parse::<Shape>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn delays_over_the_rate_and_drops_over_the_maximum_delay() {
+        let shape: Shape = "out->in 1M/s burst 2000".parse().unwrap();
+        let mut shaper = Shaper::new(&[shape], Duration::from_millis(5)).unwrap();
+        let now = Instant::now();
+        let reserve = |shaper: &mut Shaper, direction, at| shaper.reserve(direction, 1000, at);
+
+        // The burst goes at once, then frames are spread at the rate
+        assert_eq!(
+            reserve(&mut shaper, Direction::Inbound, now),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(
+            reserve(&mut shaper, Direction::Inbound, now),
+            Some(Duration::ZERO)
+        );
+        let wait = reserve(&mut shaper, Direction::Inbound, now).unwrap();
+        assert_eq!(wait, Duration::ZERO);
+        let wait = reserve(&mut shaper, Direction::Inbound, now).unwrap();
+        assert!(
+            wait > Duration::ZERO && wait < Duration::from_millis(2),
+            "{:?}",
+            wait
+        );
+
+        // Other directions are not held up
+        assert_eq!(
+            reserve(&mut shaper, Direction::Outbound, now),
+            Some(Duration::ZERO)
+        );
+
+        // Past the maximum delay frames are dropped without using tokens
+        while reserve(&mut shaper, Direction::Inbound, now).is_some() {}
+        assert_eq!(reserve(&mut shaper, Direction::Inbound, now), None);
+        let later = now + Duration::from_millis(10);
+        assert!(reserve(&mut shaper, Direction::Inbound, later).is_some());
+
+        assert!(Shaper::new(&[], Duration::from_millis(5)).is_none());
+    }
+}
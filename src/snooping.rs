@@ -0,0 +1,445 @@
+//! IGMP and MLD snooping: multicast UDP is only forwarded to interfaces
+//! where a listener joined its group.
+
+use crate::filter::{Decision, Filter, IpHeader, PacketContext};
+use crate::logging::{debug_repeated, RepeatedMessages};
+use clap::ValueEnum;
+use pnet::packet::icmpv6::Icmpv6Type;
+use pnet::packet::ip::IpNextHeaderProtocols;
+use pnet::packet::Packet;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::debug;
+
+/// Default query interval and maximum response time (RFC 3376 section 8)
+const QUERY_INTERVAL: Duration = Duration::from_secs(125);
+const QUERY_RESPONSE_INTERVAL: Duration = Duration::from_secs(10);
+const ROBUSTNESS: u32 = 2;
+/// Time a group is kept after a leave, for the listeners left to answer
+/// the querier's group-specific queries
+const LAST_MEMBER_QUERY_TIME: Duration = Duration::from_secs(2);
+
+const IGMP_QUERY: u8 = 0x11;
+const IGMP_V1_REPORT: u8 = 0x12;
+const IGMP_V2_REPORT: u8 = 0x16;
+const IGMP_LEAVE: u8 = 0x17;
+const IGMP_V3_REPORT: u8 = 0x22;
+const MLD_QUERY: Icmpv6Type = Icmpv6Type(130);
+const MLD_V1_REPORT: Icmpv6Type = Icmpv6Type(131);
+const MLD_DONE: Icmpv6Type = Icmpv6Type(132);
+const MLD_V2_REPORT: Icmpv6Type = Icmpv6Type(143);
+
+/// What happens to multicast for an interface without any membership
+/// traffic seen on it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UnknownGroups {
+    /// Forward every group, as without snooping
+    Flood,
+    /// Forward no group until a listener reports it
+    Drop,
+}
+
+/// Membership message seen on an interface
+#[derive(Debug, PartialEq, Eq)]
+enum Message {
+    Query {
+        interval: Option<Duration>,
+        max_response: Duration,
+    },
+    Report {
+        joined: Vec<IpAddr>,
+        left: Vec<IpAddr>,
+    },
+}
+
+/// Value of an 8-bit or 16-bit code with a floating point encoding above
+/// 127 or 32767 (RFC 3376 section 4.1.1, RFC 3810 section 5.1.3)
+fn decode(code: u16, mantissa_bits: u32) -> u64 {
+    let threshold = 1 << (mantissa_bits + 3);
+    if code < threshold {
+        return u64::from(code);
+    }
+    let mantissa = u64::from(code) & ((1 << mantissa_bits) - 1);
+    let exponent = (u32::from(code) >> mantissa_bits) & 0x7;
+    (mantissa | (1 << mantissa_bits)) << (exponent + 3)
+}
+
+/// Groups of the `count` records following the 8 byte header of an IGMPv3
+/// or MLDv2 report, with addresses of `len` bytes. Records in exclude mode
+/// or including sources join the group, a change to including no source
+/// leaves it.
+fn report_records(
+    message: &[u8],
+    count: usize,
+    len: usize,
+    address: impl Fn(&[u8]) -> IpAddr,
+) -> Option<Message> {
+    let (mut joined, mut left) = (Vec::new(), Vec::new());
+    let mut at = 8;
+    for _ in 0..count {
+        let header = message.get(at..at + 4 + len)?;
+        let (kind, aux_len) = (header[0], usize::from(header[1]));
+        let sources = usize::from(u16::from_be_bytes([header[2], header[3]]));
+        let group = address(&header[4..]);
+        match (kind, sources) {
+            // MODE_IS_EXCLUDE and CHANGE_TO_EXCLUDE
+            (2 | 4, _) => joined.push(group),
+            // CHANGE_TO_INCLUDE of nothing
+            (3, 0) => left.push(group),
+            // MODE_IS_INCLUDE, CHANGE_TO_INCLUDE and ALLOW_NEW with sources
+            (1 | 3 | 5, _) => joined.push(group),
+            _ => {}
+        }
+        at += 4 + len + sources * len + aux_len * 4;
+    }
+    Some(Message::Report { joined, left })
+}
+
+fn igmp(message: &[u8]) -> Option<Message> {
+    let group = |at: usize| -> Option<IpAddr> {
+        let octets: [u8; 4] = message.get(at..at + 4)?.try_into().ok()?;
+        Some(Ipv4Addr::from(octets).into())
+    };
+    match *message.first()? {
+        IGMP_QUERY => {
+            let code = *message.get(1)?;
+            // IGMPv1 queries leave the response time out
+            let tenths = if code == 0 {
+                100
+            } else {
+                decode(code.into(), 4)
+            };
+            let interval = message.get(9).map(|qqic| decode((*qqic).into(), 4));
+            Some(Message::Query {
+                interval: interval.filter(|s| *s > 0).map(Duration::from_secs),
+                max_response: Duration::from_millis(tenths * 100),
+            })
+        }
+        IGMP_V1_REPORT | IGMP_V2_REPORT => Some(Message::Report {
+            joined: vec![group(4)?],
+            left: Vec::new(),
+        }),
+        IGMP_LEAVE => Some(Message::Report {
+            joined: Vec::new(),
+            left: vec![group(4)?],
+        }),
+        IGMP_V3_REPORT => {
+            let count = u16::from_be_bytes([*message.get(6)?, *message.get(7)?]);
+            report_records(message, count.into(), 4, |b| {
+                Ipv4Addr::new(b[0], b[1], b[2], b[3]).into()
+            })
+        }
+        _ => None,
+    }
+}
+
+fn mld(message: &[u8]) -> Option<Message> {
+    let group = |at: usize| -> Option<IpAddr> {
+        let octets: [u8; 16] = message.get(at..at + 16)?.try_into().ok()?;
+        Some(Ipv6Addr::from(octets).into())
+    };
+    match Icmpv6Type(*message.first()?) {
+        MLD_QUERY => {
+            let code = u16::from_be_bytes([*message.get(4)?, *message.get(5)?]);
+            let interval = message.get(25).map(|qqic| decode((*qqic).into(), 4));
+            Some(Message::Query {
+                interval: interval.filter(|s| *s > 0).map(Duration::from_secs),
+                max_response: Duration::from_millis(decode(code, 12)),
+            })
+        }
+        MLD_V1_REPORT => Some(Message::Report {
+            joined: vec![group(8)?],
+            left: Vec::new(),
+        }),
+        MLD_DONE => Some(Message::Report {
+            joined: Vec::new(),
+            left: vec![group(8)?],
+        }),
+        MLD_V2_REPORT => {
+            let count = u16::from_be_bytes([*message.get(6)?, *message.get(7)?]);
+            report_records(message, count.into(), 16, |b| {
+                let octets: [u8; 16] = b[..16].try_into().expect("record has the address");
+                Ipv6Addr::from(octets).into()
+            })
+        }
+        _ => None,
+    }
+}
+
+/// Membership message carried by a frame, if any
+fn message(ctx: &PacketContext) -> Option<Message> {
+    match &ctx.ip {
+        Some(IpHeader::V4(ip)) if ip.get_next_level_protocol() == IpNextHeaderProtocols::Igmp => {
+            igmp(ip.payload())
+        }
+        Some(IpHeader::V6(_)) => mld(ctx.icmpv6()?.packet()),
+        _ => None,
+    }
+}
+
+/// Timers of the querier on one interface, learned from its queries
+#[derive(Debug, Clone, Copy)]
+struct Querier {
+    interval: Duration,
+    max_response: Duration,
+}
+
+impl Default for Querier {
+    fn default() -> Self {
+        Querier {
+            interval: QUERY_INTERVAL,
+            max_response: QUERY_RESPONSE_INTERVAL,
+        }
+    }
+}
+
+impl Querier {
+    /// How long a report keeps a group, and membership traffic an
+    /// interface, known (RFC 3376 section 8.4)
+    fn membership_interval(&self) -> Duration {
+        self.interval * ROBUSTNESS + self.max_response
+    }
+}
+
+#[derive(Default)]
+struct Memberships {
+    /// Expiry of every group with a listener, by interface
+    groups: HashMap<(String, IpAddr), Instant>,
+    queriers: HashMap<String, Querier>,
+    /// Interfaces with membership traffic seen, until when they count as
+    /// snooped
+    snooped: HashMap<String, Instant>,
+}
+
+impl Memberships {
+    fn learn(&mut self, iface: &str, message: Message, now: Instant) {
+        let querier = self.queriers.entry(iface.to_string()).or_default();
+        match message {
+            Message::Query {
+                interval,
+                max_response,
+            } => {
+                querier.interval = interval.unwrap_or(querier.interval);
+                querier.max_response = max_response;
+            }
+            Message::Report { joined, left } => {
+                let expires = now + querier.membership_interval();
+                for group in joined {
+                    let key = (iface.to_string(), group);
+                    if self.groups.insert(key, expires).is_none() {
+                        debug!("Listener for {} joined on {}", group, iface);
+                    }
+                }
+                for group in left {
+                    let leaving = now + LAST_MEMBER_QUERY_TIME;
+                    if let Some(expires) = self.groups.get_mut(&(iface.to_string(), group)) {
+                        *expires = (*expires).min(leaving);
+                        debug!("Listener for {} left on {}", group, iface);
+                    }
+                }
+            }
+        }
+        let until = now + querier.membership_interval();
+        self.snooped.insert(iface.to_string(), until);
+    }
+
+    /// Whether `group` has a listener on `iface`, `None` if no membership
+    /// traffic was seen there
+    fn listening(&mut self, iface: &str, group: IpAddr, now: Instant) -> Option<bool> {
+        self.groups.retain(|_, expires| *expires > now);
+        self.snooped.retain(|_, until| *until > now);
+        self.snooped
+            .contains_key(iface)
+            .then(|| self.groups.contains_key(&(iface.to_string(), group)))
+    }
+}
+
+/// Group memberships of all interfaces, learned from the IGMP and MLD
+/// messages received on them
+pub struct MembershipTable {
+    unknown: UnknownGroups,
+    memberships: Mutex<Memberships>,
+    /// Multicast frames dropped for lack of a listener
+    dropped: AtomicU64,
+}
+
+impl MembershipTable {
+    pub fn new(unknown: UnknownGroups) -> Self {
+        MembershipTable {
+            unknown,
+            memberships: Mutex::new(Memberships::default()),
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    /// Groups with a listener by interface, one line each, followed by the
+    /// number of frames dropped without one
+    pub fn state(&self) -> Vec<String> {
+        let now = Instant::now();
+        let memberships = self.memberships.lock().unwrap();
+        let mut lines: Vec<String> = memberships
+            .groups
+            .iter()
+            .filter(|(_, expires)| **expires > now)
+            .map(|((iface, group), expires)| {
+                let left = expires.saturating_duration_since(now).as_secs();
+                format!(
+                    "{} on {}, expiring in {}",
+                    group,
+                    iface,
+                    humantime::format_duration(Duration::from_secs(left))
+                )
+            })
+            .collect();
+        lines.sort();
+        lines.push(format!(
+            "{} multicast frame(s) dropped without a listener",
+            self.dropped.load(Ordering::Relaxed)
+        ));
+        lines
+    }
+}
+
+/// Learns memberships from the IGMP and MLD messages received on
+/// `interfaces` and drops multicast UDP towards an interface without a
+/// listener for its group. The egress is the first of `interfaces` the
+/// frame was not received on. The all-nodes groups are never reported and
+/// always forwarded.
+pub struct SnoopingFilter {
+    table: Arc<MembershipTable>,
+    interfaces: Vec<String>,
+    log: RepeatedMessages,
+}
+
+impl SnoopingFilter {
+    pub fn new(table: Arc<MembershipTable>, interfaces: Vec<String>) -> Self {
+        SnoopingFilter {
+            table,
+            interfaces,
+            log: RepeatedMessages::default(),
+        }
+    }
+}
+
+impl Filter for SnoopingFilter {
+    fn name(&self) -> &str {
+        "snooping"
+    }
+
+    fn evaluate(&self, ctx: &PacketContext) -> Decision {
+        if let Some(message) = message(ctx) {
+            let mut memberships = self.table.memberships.lock().unwrap();
+            memberships.learn(ctx.ingress, message, Instant::now());
+            return Decision::Continue;
+        }
+        let (Some(_), Some(group)) = (ctx.udp(), ctx.destination_ip()) else {
+            return Decision::Continue;
+        };
+        let all_nodes = group == IpAddr::V4(Ipv4Addr::new(224, 0, 0, 1))
+            || group == IpAddr::V6(Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 1));
+        if !group.is_multicast() || all_nodes {
+            return Decision::Continue;
+        }
+        let Some(egress) = self.interfaces.iter().find(|iface| *iface != ctx.ingress) else {
+            return Decision::Continue;
+        };
+        let listening = {
+            let mut memberships = self.table.memberships.lock().unwrap();
+            memberships.listening(egress, group, Instant::now())
+        };
+        match (listening, self.table.unknown) {
+            (Some(true), _) | (None, UnknownGroups::Flood) => Decision::Continue,
+            _ => {
+                self.table.dropped.fetch_add(1, Ordering::Relaxed);
+                debug_repeated!(
+                    self.log,
+                    "Frames without listeners dropped",
+                    "No listener for {} on {}, frame dropped",
+                    group,
+                    egress
+                );
+                Decision::Drop
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tracks_reports_and_leaves() {
+        let mdns: IpAddr = Ipv4Addr::new(224, 0, 0, 251).into();
+        let ssdp: IpAddr = Ipv4Addr::new(239, 255, 255, 250).into();
+        assert_eq!(
+            igmp(&[IGMP_V2_REPORT, 0, 0, 0, 224, 0, 0, 251]),
+            Some(Message::Report {
+                joined: vec![mdns],
+                left: Vec::new()
+            })
+        );
+        // IGMPv3 report joining 239.255.255.250 and leaving 224.0.0.251,
+        // with one source and one word of auxiliary data in between
+        let v3 = [
+            &[IGMP_V3_REPORT, 0, 0, 0, 0, 0, 0, 2][..],
+            // ALLOW_NEW
+            &[5, 1, 0, 1, 239, 255, 255, 250, 192, 0, 2, 5, 0, 0, 0, 0],
+            // CHANGE_TO_INCLUDE of nothing
+            &[3, 0, 0, 0, 224, 0, 0, 251],
+        ]
+        .concat();
+        assert_eq!(
+            igmp(&v3),
+            Some(Message::Report {
+                joined: vec![ssdp],
+                left: vec![mdns]
+            })
+        );
+        assert_eq!(igmp(&v3[..20]), None);
+        // IGMPv3 query with a 20 second maximum response and 200 second
+        // query interval, both in the floating point encoding
+        let query = [IGMP_QUERY, 0x89, 0, 0, 0, 0, 0, 0, 2, 0x89, 0, 0];
+        assert_eq!(
+            igmp(&query),
+            Some(Message::Query {
+                interval: Some(Duration::from_secs(200)),
+                max_response: Duration::from_secs(20)
+            })
+        );
+        assert_eq!(decode(0x8000, 12), 32768);
+
+        let mut memberships = Memberships::default();
+        let start = Instant::now();
+        assert_eq!(memberships.listening("vm1", mdns, start), None);
+        memberships.learn("vm1", igmp(&query).unwrap(), start);
+        memberships.learn(
+            "vm1",
+            igmp(&[IGMP_V2_REPORT, 0, 0, 0, 224, 0, 0, 251]).unwrap(),
+            start,
+        );
+        assert_eq!(memberships.listening("vm1", mdns, start), Some(true));
+        assert_eq!(memberships.listening("vm1", ssdp, start), Some(false));
+        assert_eq!(memberships.listening("eth0", mdns, start), None);
+
+        // Membership interval of two query intervals and the response time
+        let later = start + Duration::from_secs(419);
+        assert_eq!(memberships.listening("vm1", mdns, later), Some(true));
+        memberships.learn(
+            "vm1",
+            igmp(&[IGMP_LEAVE, 0, 0, 0, 224, 0, 0, 251]).unwrap(),
+            later,
+        );
+        let after_leave = later + LAST_MEMBER_QUERY_TIME;
+        assert_eq!(memberships.listening("vm1", mdns, after_leave), Some(false));
+        assert_eq!(
+            memberships.listening("vm1", mdns, start + Duration::from_secs(1000)),
+            None
+        );
+    }
+}
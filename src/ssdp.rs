@@ -0,0 +1,640 @@
+//! SSDP specific handling.
+
+use crate::filter::{Decision, Filter, PacketContext, SSDP_PORT};
+use crate::logging::{debug_repeated, RepeatedMessages};
+use crate::nat::Translation;
+use crate::rewrite::{Rewrite, UdpDatagram};
+use pnet::packet::ip::IpNextHeaderProtocols;
+use pnet::packet::Packet;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::debug;
+
+/// Tracks M-SEARCH requests sent from the internal side so that only
+/// unicast responses to an outstanding search are let back in.
+/// Multicast traffic is not affected and is left to the other filters.
+/// Searches are recorded once forwarded, so one a later filter drops lets
+/// no responses in.
+pub struct SsdpResponseTracker {
+    internal_iface: String,
+    window: Duration,
+    max_entries: usize,
+    /// Source NAT changing the address and port searches come from
+    snat: Option<Translation>,
+    searches: Mutex<HashMap<(IpAddr, u16), Instant>>,
+}
+
+impl SsdpResponseTracker {
+    pub fn new(
+        internal_iface: String,
+        window: Duration,
+        max_entries: usize,
+        snat: Option<Translation>,
+    ) -> Self {
+        SsdpResponseTracker {
+            internal_iface,
+            window,
+            max_entries,
+            snat,
+            searches: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn record(&self, source: (IpAddr, u16), now: Instant) {
+        let mut searches = self.searches.lock().unwrap();
+        searches.retain(|_, sent| now.duration_since(*sent) < self.window);
+        if searches.len() >= self.max_entries && !searches.contains_key(&source) {
+            // Evict the least recently sent search to keep the table bounded
+            if let Some(oldest) = searches
+                .iter()
+                .min_by_key(|(_, sent)| **sent)
+                .map(|(k, _)| *k)
+            {
+                searches.remove(&oldest);
+            }
+        }
+        searches.insert(source, now);
+    }
+
+    fn outstanding(&self) -> Vec<String> {
+        let searches = self.searches.lock().unwrap();
+        searches
+            .iter()
+            .filter(|(_, sent)| sent.elapsed() < self.window)
+            .map(|((ip, port), sent)| {
+                let age = Duration::from_millis(sent.elapsed().as_millis() as u64);
+                format!(
+                    "{}:{} searched {} ago",
+                    ip,
+                    port,
+                    humantime::format_duration(age)
+                )
+            })
+            .collect()
+    }
+
+    fn is_expected(&self, destination: (IpAddr, u16), now: Instant) -> bool {
+        let searches = self.searches.lock().unwrap();
+        searches
+            .get(&destination)
+            .is_some_and(|sent| now.duration_since(*sent) < self.window)
+    }
+
+    /// Address and port responses to `ctx` are sent to, if it is a search
+    /// from the internal side
+    fn search_source(&self, ctx: &PacketContext) -> Option<(IpAddr, u16)> {
+        let udp = ctx.udp()?;
+        if ctx.ingress != self.internal_iface
+            || udp.get_destination() != SSDP_PORT
+            || !udp.payload().starts_with(b"M-SEARCH ")
+        {
+            return None;
+        }
+        let source = (ctx.source_ip()?, udp.get_source());
+        Some(match &self.snat {
+            Some(snat) => snat.source(IpNextHeaderProtocols::Udp, source),
+            None => source,
+        })
+    }
+}
+
+impl Filter for SsdpResponseTracker {
+    fn name(&self) -> &str {
+        "ssdp-tracking"
+    }
+
+    fn evaluate(&self, ctx: &PacketContext) -> Decision {
+        let Some(udp) = ctx.udp() else {
+            return Decision::Continue;
+        };
+        let Some(dst_ip) = ctx.destination_ip() else {
+            return Decision::Continue;
+        };
+        if ctx.ingress == self.internal_iface
+            || udp.get_source() != SSDP_PORT
+            || dst_ip.is_multicast()
+            || is_broadcast(dst_ip)
+        {
+            return Decision::Continue;
+        }
+        if self.is_expected((dst_ip, udp.get_destination()), Instant::now()) {
+            Decision::Continue
+        } else {
+            debug!(
+                "Unsolicited SSDP unicast to {}:{} dropped",
+                dst_ip,
+                udp.get_destination()
+            );
+            Decision::Drop
+        }
+    }
+
+    fn state(&self) -> Option<Vec<String>> {
+        Some(self.outstanding())
+    }
+
+    fn forwarded(&self, ctx: &PacketContext) {
+        if let Some(source) = self.search_source(ctx) {
+            self.record(source, Instant::now());
+        }
+    }
+}
+
+pub fn is_broadcast(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.is_broadcast(),
+        IpAddr::V6(_) => false,
+    }
+}
+
+/// Kind of an SSDP message, from its start line and NTS header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SsdpKind {
+    Search,
+    Alive,
+    ByeBye,
+    Update,
+    Response,
+}
+
+/// The parts of an SSDP message the filters look at
+#[derive(Debug, PartialEq, Eq)]
+pub struct SsdpMessage<'a> {
+    pub kind: SsdpKind,
+    /// ST of searches and responses, NT of notifications
+    pub target: &'a str,
+}
+
+impl<'a> SsdpMessage<'a> {
+    /// Parses the start line and headers of `payload`. Returns `None` for
+    /// anything but M-SEARCH, NOTIFY and 200 OK responses, for malformed
+    /// header lines and if a mandatory header is missing.
+    pub fn parse(payload: &'a [u8]) -> Option<Self> {
+        let (start, headers) = split_message(payload)?;
+        let header = |wanted: &str| header(&headers, wanted);
+
+        let (kind, target) = if start.eq_ignore_ascii_case("M-SEARCH * HTTP/1.1") {
+            header("HOST")?;
+            let man = header("MAN")?.trim_matches('"');
+            if !man.eq_ignore_ascii_case("ssdp:discover") {
+                return None;
+            }
+            (SsdpKind::Search, header("ST")?)
+        } else if start.eq_ignore_ascii_case("NOTIFY * HTTP/1.1") {
+            header("HOST")?;
+            header("USN")?;
+            let kind = match header("NTS")?.to_ascii_lowercase().as_str() {
+                "ssdp:alive" => SsdpKind::Alive,
+                "ssdp:byebye" => SsdpKind::ByeBye,
+                "ssdp:update" => SsdpKind::Update,
+                _ => return None,
+            };
+            (kind, header("NT")?)
+        } else if start.starts_with("HTTP/1.1 200") {
+            header("USN")?;
+            (SsdpKind::Response, header("ST")?)
+        } else {
+            return None;
+        };
+        Some(SsdpMessage { kind, target })
+    }
+}
+
+/// Start line and header fields of an SSDP message, `None` if it is not
+/// UTF-8 or a header line has no colon
+pub fn split_message(payload: &[u8]) -> Option<(&str, Vec<(&str, &str)>)> {
+    let text = std::str::from_utf8(payload).ok()?;
+    let mut lines = text.lines();
+    let start = lines.next()?.trim_end();
+    let mut headers = Vec::new();
+    for line in lines.take_while(|line| !line.is_empty()) {
+        let (name, value) = line.split_once(':')?;
+        headers.push((name.trim(), value.trim()));
+    }
+    Some((start, headers))
+}
+
+/// Value of the header `wanted`, whose name is matched case-insensitively
+pub fn header<'a>(headers: &[(&str, &'a str)], wanted: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(wanted))
+        .map(|(_, value)| *value)
+}
+
+/// Lets through SSDP messages whose search or notification target is
+/// allowed. Searches are only accepted from the internal interface and
+/// notifications and responses only from the others, unless relaxed.
+/// Without an internal interface, as in bridge mode, direction is not
+/// checked.
+pub struct SsdpMessageFilter {
+    targets: Vec<String>,
+    internal_iface: Option<String>,
+    external_search: bool,
+    internal_announce: bool,
+    malformed: AtomicU64,
+    wrong_direction: AtomicU64,
+    other_target: AtomicU64,
+    log: RepeatedMessages,
+}
+
+impl SsdpMessageFilter {
+    pub fn new(
+        targets: &[String],
+        internal_iface: Option<String>,
+        external_search: bool,
+        internal_announce: bool,
+    ) -> Self {
+        SsdpMessageFilter {
+            targets: targets.to_vec(),
+            internal_iface,
+            external_search,
+            internal_announce,
+            malformed: AtomicU64::new(0),
+            wrong_direction: AtomicU64::new(0),
+            other_target: AtomicU64::new(0),
+            log: RepeatedMessages::default(),
+        }
+    }
+
+    fn dropped(&self, counter: &AtomicU64, what: &str) -> Decision {
+        counter.fetch_add(1, Ordering::Relaxed);
+        debug_repeated!(
+            self.log,
+            "SSDP messages dropped",
+            "SSDP message dropped: {}",
+            what
+        );
+        Decision::Drop
+    }
+}
+
+impl Filter for SsdpMessageFilter {
+    fn name(&self) -> &str {
+        "ssdp-messages"
+    }
+
+    fn evaluate(&self, ctx: &PacketContext) -> Decision {
+        let Some(udp) = ctx.udp() else {
+            return Decision::Continue;
+        };
+        if udp.get_source() != SSDP_PORT && udp.get_destination() != SSDP_PORT {
+            return Decision::Continue;
+        }
+        let Some(message) = SsdpMessage::parse(udp.payload()) else {
+            return self.dropped(&self.malformed, "malformed");
+        };
+        if let Some(internal) = &self.internal_iface {
+            let from_internal = ctx.ingress == internal;
+            let expected = match message.kind {
+                SsdpKind::Search => from_internal || self.external_search,
+                _ => !from_internal || self.internal_announce,
+            };
+            if !expected {
+                return self.dropped(&self.wrong_direction, "wrong direction");
+            }
+        }
+        let allowed = self
+            .targets
+            .iter()
+            .any(|target| target.eq_ignore_ascii_case(message.target));
+        if !allowed {
+            return self.dropped(&self.other_target, message.target);
+        }
+        Decision::Continue
+    }
+
+    fn state(&self) -> Option<Vec<String>> {
+        Some(vec![format!(
+            "dropped malformed={} wrong-direction={} other-target={}",
+            self.malformed.load(Ordering::Relaxed),
+            self.wrong_direction.load(Ordering::Relaxed),
+            self.other_target.load(Ordering::Relaxed)
+        )])
+    }
+}
+
+/// Internal address in SSDP LOCATION URLs and the address advertised in its
+/// place on the external side, written as `INTERNAL=ADVERTISED`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct LocationMapping {
+    pub internal: IpAddr,
+    pub advertised: IpAddr,
+}
+
+impl FromStr for LocationMapping {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (internal, advertised) = value
+            .split_once('=')
+            .ok_or_else(|| format!("'{}' is not of the form INTERNAL=ADVERTISED", value))?;
+        let address = |address: &str| {
+            address
+                .trim()
+                .parse()
+                .map_err(|_| format!("'{}' is not an IP address", address))
+        };
+        Ok(LocationMapping {
+            internal: address(internal)?,
+            advertised: address(advertised)?,
+        })
+    }
+}
+
+impl TryFrom<String> for LocationMapping {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<LocationMapping> for String {
+    fn from(mapping: LocationMapping) -> Self {
+        mapping.to_string()
+    }
+}
+
+impl fmt::Display for LocationMapping {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", self.internal, self.advertised)
+    }
+}
+
+/// Replaces the host of the LOCATION URL in `payload` if it is an address
+/// found in `mappings`. Returns `Ok(None)` if the message has no LOCATION
+/// header or its host is not mapped, and `Err` if the message or the URL
+/// cannot be parsed.
+fn rewrite_location(payload: &[u8], mappings: &[(IpAddr, IpAddr)]) -> Result<Option<Vec<u8>>, ()> {
+    let text = std::str::from_utf8(payload).map_err(|_| ())?;
+    let mut offset = 0;
+    let mut location = None;
+    for (index, line) in text.split_inclusive('\n').enumerate() {
+        let start = offset;
+        offset += line.len();
+        let line = line.trim_end_matches(['\r', '\n']);
+        if index == 0 {
+            continue;
+        }
+        if line.is_empty() {
+            break;
+        }
+        let (name, value) = line.split_once(':').ok_or(())?;
+        if name.trim().eq_ignore_ascii_case("LOCATION") {
+            let value_start = start + name.len() + 1;
+            location = Some((value_start, value));
+            break;
+        }
+    }
+    let Some((value_start, value)) = location else {
+        return Ok(None);
+    };
+
+    let authority_start = value.find("://").ok_or(())? + 3;
+    let authority = &value[authority_start..];
+    let authority = &authority[..authority.find(['/', '?', '#']).unwrap_or(authority.len())];
+    let userinfo_len = authority.find('@').map_or(0, |at| at + 1);
+    let host_start = authority_start + userinfo_len;
+    let host = &authority[userinfo_len..];
+    let host = if host.starts_with('[') {
+        &host[..host.find(']').ok_or(())? + 1]
+    } else {
+        &host[..host.find(':').unwrap_or(host.len())]
+    };
+    let Ok(address) = host
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse::<IpAddr>()
+    else {
+        // A host name is left to the name resolution of the other side
+        return Ok(None);
+    };
+    let Some((_, to)) = mappings.iter().find(|(from, _)| *from == address) else {
+        return Ok(None);
+    };
+    let to = match to {
+        IpAddr::V4(to) => to.to_string(),
+        IpAddr::V6(to) => format!("[{}]", to),
+    };
+    let host_start = value_start + host_start;
+    let mut rewritten = payload[..host_start].to_vec();
+    rewritten.extend_from_slice(to.as_bytes());
+    rewritten.extend_from_slice(&payload[host_start + host.len()..]);
+    Ok(Some(rewritten))
+}
+
+/// Rewrites the host of the LOCATION URL in SSDP messages, so devices behind
+/// source NAT advertise an address reachable from the external side. The
+/// stage for frames sent inwards maps the advertised addresses back.
+/// Messages whose LOCATION cannot be parsed are dropped if `fail_closed` and
+/// passed unchanged otherwise.
+pub struct SsdpLocationRewrite {
+    /// Addresses to replace and their replacements
+    mappings: Vec<(IpAddr, IpAddr)>,
+    fail_closed: bool,
+}
+
+impl SsdpLocationRewrite {
+    pub fn new(mappings: &[LocationMapping], inwards: bool, fail_closed: bool) -> Self {
+        let mappings = mappings
+            .iter()
+            .map(|mapping| match inwards {
+                false => (mapping.internal, mapping.advertised),
+                true => (mapping.advertised, mapping.internal),
+            })
+            .collect();
+        SsdpLocationRewrite {
+            mappings,
+            fail_closed,
+        }
+    }
+}
+
+impl Rewrite for SsdpLocationRewrite {
+    fn name(&self) -> &str {
+        "ssdp-location"
+    }
+
+    fn apply(&self, frame: &mut Vec<u8>) -> bool {
+        let Some(datagram) = UdpDatagram::locate(frame) else {
+            return true;
+        };
+        if !datagram.ports(frame).contains(&SSDP_PORT) {
+            return true;
+        }
+        match rewrite_location(&frame[datagram.payload()], &self.mappings) {
+            Ok(Some(rewritten)) => {
+                debug!("SSDP LOCATION rewritten");
+                datagram.replace_payload(frame, &rewritten)
+            }
+            Ok(None) => true,
+            Err(()) => {
+                debug!("SSDP message with a malformed LOCATION");
+                !self.fail_closed
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pair::Direction;
+    use crate::testutil::{
+        ssdp_notify, ssdp_search, ssdp_search_frame, udp_frame, HOST_IP, HOST_MAC,
+    };
+    use pnet::util::MacAddr;
+    use std::net::Ipv4Addr;
+
+    const INTERNAL: &str = "vm0";
+    const WINDOW: Duration = Duration::from_secs(5);
+
+    /// Unicast response of a device on the external side to `port` of the
+    /// default host
+    fn response_frame(port: u16) -> Vec<u8> {
+        udp_frame(
+            MacAddr(0x02, 0, 0, 0, 0, 0x20),
+            HOST_MAC,
+            Ipv4Addr::new(192, 168, 1, 20),
+            HOST_IP,
+            SSDP_PORT,
+            port,
+            b"HTTP/1.1 200 OK\r\nST: ssdp:all\r\nUSN: uuid:1\r\n\r\n",
+        )
+    }
+
+    fn inbound(frame: &[u8]) -> PacketContext<'_> {
+        PacketContext::parse("eth0", Direction::Inbound, frame).unwrap()
+    }
+
+    #[test]
+    fn lets_in_responses_only_to_forwarded_searches() {
+        let tracker = SsdpResponseTracker::new(INTERNAL.to_string(), WINDOW, 16, None);
+        // From port 50000 of the default host
+        let search = ssdp_search_frame("ssdp:all");
+        let search = PacketContext::parse(INTERNAL, Direction::Outbound, &search).unwrap();
+        let response = response_frame(50000);
+        assert_eq!(tracker.evaluate(&search), Decision::Continue);
+        // Evaluated, but dropped further down the chain
+        assert_eq!(tracker.evaluate(&inbound(&response)), Decision::Drop);
+
+        tracker.forwarded(&search);
+        assert_eq!(tracker.evaluate(&inbound(&response)), Decision::Continue);
+        let other_port = response_frame(50001);
+        assert_eq!(tracker.evaluate(&inbound(&other_port)), Decision::Drop);
+        // Responses seen on the internal side are not checked
+        let outbound = PacketContext::parse(INTERNAL, Direction::Outbound, &other_port).unwrap();
+        assert_eq!(tracker.evaluate(&outbound), Decision::Continue);
+    }
+
+    #[test]
+    fn expects_responses_within_the_window() {
+        let tracker = SsdpResponseTracker::new(INTERNAL.to_string(), WINDOW, 16, None);
+        let start = Instant::now();
+        let host = IpAddr::from(HOST_IP);
+        tracker.record((host, 50000), start);
+        let almost = start + WINDOW - Duration::from_millis(1);
+        assert!(tracker.is_expected((host, 50000), almost));
+        assert!(!tracker.is_expected((host, 50001), almost));
+        assert!(!tracker.is_expected((host, 50000), start + WINDOW));
+        // Searching again opens a new window
+        tracker.record((host, 50000), start + WINDOW);
+        assert!(tracker.is_expected((host, 50000), almost + WINDOW));
+    }
+
+    #[test]
+    fn forgets_the_oldest_search_when_full() {
+        let tracker = SsdpResponseTracker::new(INTERNAL.to_string(), WINDOW, 2, None);
+        let start = Instant::now();
+        let host = IpAddr::from(HOST_IP);
+        for (port, after) in [(50000, 0), (50001, 1), (50002, 2)] {
+            tracker.record((host, port), start + Duration::from_secs(after));
+        }
+        let now = start + Duration::from_secs(3);
+        assert!(!tracker.is_expected((host, 50000), now));
+        assert!(tracker.is_expected((host, 50001), now));
+        assert!(tracker.is_expected((host, 50002), now));
+        // Searching again from a tracked port evicts nothing
+        tracker.record((host, 50001), now);
+        assert!(tracker.is_expected((host, 50002), now));
+        assert_eq!(tracker.searches.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn parses_messages() {
+        let search = ssdp_search("urn:dial-multiscreen-org:service:dial:1");
+        assert_eq!(
+            SsdpMessage::parse(&search),
+            Some(SsdpMessage {
+                kind: SsdpKind::Search,
+                target: "urn:dial-multiscreen-org:service:dial:1",
+            })
+        );
+        let notify = ssdp_notify("upnp:rootdevice", "ssdp:byebye");
+        assert_eq!(
+            SsdpMessage::parse(&notify).map(|message| message.kind),
+            Some(SsdpKind::ByeBye)
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_messages() {
+        // Missing ST
+        let search =
+            b"M-SEARCH * HTTP/1.1\r\nHOST: 239.255.255.250:1900\r\nMAN: \"ssdp:discover\"\r\n\r\n";
+        assert_eq!(SsdpMessage::parse(search), None);
+        // Header line without a colon
+        let response = b"HTTP/1.1 200 OK\r\nST ssdp:all\r\nUSN: uuid:1\r\n\r\n";
+        assert_eq!(SsdpMessage::parse(response), None);
+        assert_eq!(SsdpMessage::parse(b"\xff\xfe"), None);
+    }
+
+    #[test]
+    fn rewrites_location_host() {
+        let mappings: Vec<LocationMapping> = ["192.168.100.5=192.0.2.1", "fd00::5=2001:db8::1"]
+            .iter()
+            .map(|mapping| mapping.parse().unwrap())
+            .collect();
+        let outwards = SsdpLocationRewrite::new(&mappings, false, false).mappings;
+        let response = b"HTTP/1.1 200 OK\r\nST: upnp:rootdevice\r\n\
+            Location: http://192.168.100.5:8008/ssdp/device-desc.xml\r\nUSN: uuid:1\r\n\r\n";
+        let rewritten = rewrite_location(response, &outwards).unwrap().unwrap();
+        assert_eq!(
+            std::str::from_utf8(&rewritten).unwrap(),
+            "HTTP/1.1 200 OK\r\nST: upnp:rootdevice\r\n\
+            Location: http://192.0.2.1:8008/ssdp/device-desc.xml\r\nUSN: uuid:1\r\n\r\n"
+        );
+        let inwards = SsdpLocationRewrite::new(&mappings, true, false).mappings;
+        assert_eq!(
+            rewrite_location(&rewritten, &inwards).unwrap().unwrap(),
+            response
+        );
+
+        let notify = b"NOTIFY * HTTP/1.1\r\nLOCATION: http://[fd00::5]/desc.xml\r\n\r\n";
+        let rewritten = rewrite_location(notify, &outwards).unwrap().unwrap();
+        assert_eq!(
+            rewritten,
+            b"NOTIFY * HTTP/1.1\r\nLOCATION: http://[2001:db8::1]/desc.xml\r\n\r\n"
+        );
+
+        // Unmapped hosts, host names and messages without LOCATION pass
+        let other = b"NOTIFY * HTTP/1.1\r\nLOCATION: http://192.168.100.6/\r\n\r\n";
+        assert_eq!(rewrite_location(other, &outwards), Ok(None));
+        let named = b"NOTIFY * HTTP/1.1\r\nLOCATION: http://tv.local:8008/\r\n\r\n";
+        assert_eq!(rewrite_location(named, &outwards), Ok(None));
+        let search = b"M-SEARCH * HTTP/1.1\r\nST: ssdp:all\r\n\r\n";
+        assert_eq!(rewrite_location(search, &outwards), Ok(None));
+
+        let relative = b"NOTIFY * HTTP/1.1\r\nLOCATION: /desc.xml\r\n\r\n";
+        assert_eq!(rewrite_location(relative, &outwards), Err(()));
+        assert!("192.168.100.5".parse::<LocationMapping>().is_err());
+    }
+}
@@ -0,0 +1,467 @@
+//! Cache of the devices announced by SSDP NOTIFYs forwarded inwards,
+//! answering searches from the internal side without forwarding them.
+
+use crate::discovery;
+#[cfg(feature = "dbus")]
+use crate::discovery::Discoveries;
+use crate::filter::{PacketContext, SSDP_PORT};
+use crate::random::random_up_to;
+use crate::responder::{Cache, Destination, Responder};
+use crate::rewrite::{Rewrite, UdpDatagram};
+use crate::ssdp::{header, split_message, SsdpKind, SsdpMessage};
+use crate::ssdpresponse::{self, Advertisement};
+use crate::state;
+use pnet::packet::Packet;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+#[cfg(feature = "dbus")]
+use std::sync::OnceLock;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use tracing::debug;
+
+/// Hop limit of the unicast responses
+const RESPONSE_HOP_LIMIT: u8 = 64;
+
+/// What an ssdp:alive NOTIFY announced for one USN
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Device {
+    nt: String,
+    location: String,
+    server: Option<String>,
+    boot_id: Option<u32>,
+    config_id: Option<u32>,
+    expires: Instant,
+}
+
+impl Device {
+    fn advertisement<'a>(&'a self, usn: &'a str, now: Instant) -> Advertisement<'a> {
+        Advertisement {
+            usn,
+            nt: &self.nt,
+            location: &self.location,
+            server: self.server.as_deref(),
+            boot_id: self.boot_id,
+            config_id: self.config_id,
+            max_age: self.expires.saturating_duration_since(now).as_secs(),
+        }
+    }
+}
+
+/// Cached device as kept in the state file
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct SavedSsdpDevice {
+    pub usn: String,
+    pub nt: String,
+    pub location: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub server: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub boot_id: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub config_id: Option<u32>,
+    #[serde(with = "humantime_serde")]
+    pub expires: SystemTime,
+}
+
+/// Announced devices by USN, bounded by evicting the devices expiring first
+struct Devices {
+    max_devices: usize,
+    devices: HashMap<String, Device>,
+}
+
+impl Devices {
+    /// Stores the device announced for `usn`. Returns whether none was.
+    fn store(&mut self, usn: &str, device: Device, now: Instant) -> bool {
+        self.devices.retain(|_, device| device.expires > now);
+        if self.devices.len() >= self.max_devices && !self.devices.contains_key(usn) {
+            if let Some(first) = self
+                .devices
+                .iter()
+                .min_by_key(|(_, device)| device.expires)
+                .map(|(usn, _)| usn.clone())
+            {
+                self.devices.remove(&first);
+            }
+        }
+        self.devices.insert(usn.to_string(), device).is_none()
+    }
+
+    /// Devices that have not expired and match the search target `st`
+    fn matching(&self, st: &str, now: Instant) -> Vec<(&str, &Device)> {
+        let mut matching: Vec<(&str, &Device)> = self
+            .devices
+            .iter()
+            .filter(|(_, device)| device.expires > now)
+            .filter(|(_, device)| {
+                st.eq_ignore_ascii_case("ssdp:all") || device.nt.eq_ignore_ascii_case(st)
+            })
+            .map(|(usn, device)| (usn.as_str(), device))
+            .collect();
+        matching.sort_by_key(|(usn, _)| *usn);
+        matching
+    }
+}
+
+/// What is listed and announced about the device announced for `usn`
+fn discovered(usn: &str, device: &Device, now: Instant) -> discovery::Device {
+    discovery::Device {
+        protocol: "ssdp",
+        id: usn.to_string(),
+        kind: device.nt.clone(),
+        location: Some(device.location.clone()),
+        expires_in: device.expires.saturating_duration_since(now).as_secs(),
+    }
+}
+
+/// `max-age` directive of a CACHE-CONTROL header
+pub fn max_age(cache_control: &str) -> Option<Duration> {
+    cache_control.split(',').find_map(|directive| {
+        let (name, value) = directive.split_once('=')?;
+        if !name.trim().eq_ignore_ascii_case("max-age") {
+            return None;
+        }
+        value.trim().parse().ok().map(Duration::from_secs)
+    })
+}
+
+/// Devices learned from ssdp:alive NOTIFYs forwarded to the internal side,
+/// kept for their max-age or until an ssdp:byebye. Multicast searches from
+/// the internal side are answered from it with one unicast 200 OK per
+/// matching device if there is one, and forwarded otherwise.
+pub struct SsdpCache {
+    devices: Mutex<Devices>,
+    responder: Responder,
+    /// SERVER of the responses in place of the one the devices announced
+    server: Option<String>,
+    answered: AtomicU64,
+    missed: AtomicU64,
+    /// Where devices seen for the first time are announced
+    #[cfg(feature = "dbus")]
+    discoveries: OnceLock<Discoveries>,
+}
+
+impl SsdpCache {
+    pub fn new(max_devices: usize, responder: Responder, server: Option<String>) -> Self {
+        SsdpCache {
+            devices: Mutex::new(Devices {
+                max_devices,
+                devices: HashMap::new(),
+            }),
+            responder,
+            server,
+            answered: AtomicU64::new(0),
+            missed: AtomicU64::new(0),
+            #[cfg(feature = "dbus")]
+            discoveries: OnceLock::new(),
+        }
+    }
+
+    /// Announces the devices learned from now on to `discoveries`
+    #[cfg(feature = "dbus")]
+    pub fn announce_to(&self, discoveries: Discoveries) {
+        let _ = self.discoveries.set(discoveries);
+    }
+
+    /// Devices that have not expired, for the state file
+    pub fn save(&self, now: Instant, wall: SystemTime) -> Vec<SavedSsdpDevice> {
+        let devices = self.devices.lock().unwrap();
+        devices
+            .matching("ssdp:all", now)
+            .into_iter()
+            .map(|(usn, device)| SavedSsdpDevice {
+                usn: usn.to_string(),
+                nt: device.nt.clone(),
+                location: device.location.clone(),
+                server: device.server.clone(),
+                boot_id: device.boot_id,
+                config_id: device.config_id,
+                expires: state::wall_time(device.expires, now, wall),
+            })
+            .collect()
+    }
+
+    /// Adds the saved devices whose max-age has not passed, those expiring
+    /// last first while there is room
+    pub fn restore(&self, mut saved: Vec<SavedSsdpDevice>, now: Instant, wall: SystemTime) {
+        saved.sort_by_key(|device| std::cmp::Reverse(device.expires));
+        let mut devices = self.devices.lock().unwrap();
+        for device in saved {
+            let Some(expires) = state::instant(device.expires, now, wall) else {
+                continue;
+            };
+            if expires <= now || devices.devices.len() >= devices.max_devices {
+                continue;
+            }
+            devices.devices.entry(device.usn).or_insert(Device {
+                nt: device.nt,
+                location: device.location,
+                server: device.server,
+                boot_id: device.boot_id,
+                config_id: device.config_id,
+                expires,
+            });
+        }
+    }
+
+    fn learn(&self, payload: &[u8]) {
+        let Some(message) = SsdpMessage::parse(payload) else {
+            return;
+        };
+        let Some((_, headers)) = split_message(payload) else {
+            return;
+        };
+        let Some(usn) = header(&headers, "USN") else {
+            return;
+        };
+        let mut devices = self.devices.lock().unwrap();
+        match message.kind {
+            SsdpKind::Alive => {
+                let location = header(&headers, "LOCATION");
+                let max_age = header(&headers, "CACHE-CONTROL").and_then(max_age);
+                let (Some(location), Some(max_age)) = (location, max_age) else {
+                    return;
+                };
+                let now = Instant::now();
+                let device = Device {
+                    nt: message.target.to_string(),
+                    location: location.to_string(),
+                    server: header(&headers, "SERVER").map(str::to_string),
+                    boot_id: header(&headers, "BOOTID.UPNP.ORG").and_then(|id| id.parse().ok()),
+                    config_id: header(&headers, "CONFIGID.UPNP.ORG").and_then(|id| id.parse().ok()),
+                    expires: now + max_age,
+                };
+                if devices.store(usn, device, now) {
+                    debug!("SSDP device {} learned", usn);
+                    #[cfg(feature = "dbus")]
+                    if let Some(discoveries) = self.discoveries.get() {
+                        discoveries.announce(discovered(usn, &devices.devices[usn], now));
+                    }
+                }
+            }
+            SsdpKind::ByeBye if devices.devices.remove(usn).is_some() => {
+                debug!("SSDP byebye for {}", usn);
+            }
+            _ => {}
+        }
+    }
+}
+
+impl Cache for SsdpCache {
+    fn name(&self) -> &str {
+        "ssdp-cache"
+    }
+
+    fn answer(&self, ctx: &PacketContext) -> bool {
+        let Some(udp) = ctx.udp() else {
+            return false;
+        };
+        // Searches sent to one device are left to it
+        let (Some(source), Some(destination)) = (ctx.source_ip(), ctx.destination_ip()) else {
+            return false;
+        };
+        if udp.get_destination() != SSDP_PORT || !destination.is_multicast() {
+            return false;
+        }
+        let Some(search) = SsdpMessage::parse(udp.payload()) else {
+            return false;
+        };
+        if search.kind != SsdpKind::Search {
+            return false;
+        }
+        let window = split_message(udp.payload())
+            .and_then(|(_, headers)| ssdpresponse::mx(&headers))
+            .unwrap_or_default();
+        let (now, date) = (Instant::now(), SystemTime::now());
+        let responses: Vec<String> = {
+            let devices = self.devices.lock().unwrap();
+            devices
+                .matching(search.target, now)
+                .into_iter()
+                .filter_map(|(usn, device)| {
+                    let advertisement = device.advertisement(usn, now);
+                    let response = ssdpresponse::build(
+                        search.target,
+                        &advertisement,
+                        self.server.as_deref(),
+                        date,
+                    );
+                    if response.is_none() {
+                        debug!("Malformed SSDP cache entry {:?} not answered with", usn);
+                    }
+                    response
+                })
+                .collect()
+        };
+        if responses.is_empty() {
+            self.missed.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+        let searcher = Destination {
+            mac: ctx.ethernet.get_source(),
+            ip: source,
+            port: udp.get_source(),
+        };
+        for response in &responses {
+            // Spread over the MX the searcher gave
+            let sent = self.responder.send_after(
+                random_up_to(window),
+                SSDP_PORT,
+                searcher,
+                RESPONSE_HOP_LIMIT,
+                response.as_bytes(),
+            );
+            if !sent {
+                self.missed.fetch_add(1, Ordering::Relaxed);
+                return false;
+            }
+        }
+        self.answered.fetch_add(1, Ordering::Relaxed);
+        debug!(
+            "SSDP search for {} answered from the cache with {} device(s) to {}",
+            search.target,
+            responses.len(),
+            source
+        );
+        true
+    }
+
+    fn devices(&self) -> Vec<discovery::Device> {
+        let now = Instant::now();
+        let devices = self.devices.lock().unwrap();
+        devices
+            .matching("ssdp:all", now)
+            .into_iter()
+            .map(|(usn, device)| discovered(usn, device, now))
+            .collect()
+    }
+
+    /// One line per cached device, followed by the search counters
+    fn state(&self) -> Vec<String> {
+        let now = Instant::now();
+        let devices = self.devices.lock().unwrap();
+        let mut lines: Vec<String> = devices
+            .matching("ssdp:all", now)
+            .into_iter()
+            .map(|(usn, device)| {
+                let left = device.expires.saturating_duration_since(now).as_secs();
+                format!(
+                    "{} at {} ({}), expiring in {}",
+                    usn,
+                    device.location,
+                    device.nt,
+                    humantime::format_duration(Duration::from_secs(left))
+                )
+            })
+            .collect();
+        lines.push(format!(
+            "{} search(es) answered from the cache, {} forwarded",
+            self.answered.load(Ordering::Relaxed),
+            self.missed.load(Ordering::Relaxed)
+        ));
+        lines
+    }
+}
+
+/// Stores the devices announced by NOTIFYs forwarded to the internal side
+/// in the cache and removes them on byebye. Goes after the LOCATION
+/// rewrite so the address valid on the internal side is cached.
+pub struct LearnSsdpDevices {
+    cache: Arc<SsdpCache>,
+}
+
+impl LearnSsdpDevices {
+    pub fn new(cache: Arc<SsdpCache>) -> Self {
+        LearnSsdpDevices { cache }
+    }
+}
+
+impl Rewrite for LearnSsdpDevices {
+    fn name(&self) -> &str {
+        "ssdp-cache"
+    }
+
+    fn apply(&self, frame: &mut Vec<u8>) -> bool {
+        if let Some(datagram) = UdpDatagram::locate(frame) {
+            if datagram.ports(frame)[1] == SSDP_PORT {
+                self.cache.learn(&frame[datagram.payload()]);
+            }
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn device(nt: &str, expires: Instant) -> Device {
+        Device {
+            nt: nt.to_string(),
+            location: "http://192.168.1.5:8008/ssdp/device-desc.xml".to_string(),
+            server: Some("Linux/3.8 UPnP/1.0".to_string()),
+            boot_id: Some(7),
+            config_id: None,
+            expires,
+        }
+    }
+
+    #[test]
+    fn answers_searches_from_announced_devices() {
+        assert_eq!(max_age("max-age = 1800"), Some(Duration::from_secs(1800)));
+        assert_eq!(
+            max_age("no-cache=\"Ext\", max-age=60"),
+            Some(Duration::from_secs(60))
+        );
+        assert_eq!(max_age("no-cache"), None);
+
+        let start = Instant::now();
+        let mut devices = Devices {
+            max_devices: 2,
+            devices: HashMap::new(),
+        };
+        let dial = "urn:dial-multiscreen-org:service:dial:1";
+        let root = "upnp:rootdevice";
+        let expires = start + Duration::from_secs(1800);
+        devices.store("uuid:tv::upnp:rootdevice", device(root, expires), start);
+        devices.store(&format!("uuid:tv::{}", dial), device(dial, expires), start);
+
+        let later = start + Duration::from_secs(100);
+        let found = devices.matching("URN:DIAL-MULTISCREEN-ORG:SERVICE:DIAL:1", later);
+        assert_eq!(found.len(), 1);
+        assert_eq!(devices.matching("ssdp:all", later).len(), 2);
+        assert!(devices
+            .matching("urn:schemas-upnp-org:device:Basic:1", later)
+            .is_empty());
+
+        let (usn, found) = devices.matching(root, later)[0];
+        let date = SystemTime::UNIX_EPOCH + Duration::from_secs(784_111_777);
+        let response = |st| ssdpresponse::build(st, &found.advertisement(usn, later), None, date);
+        assert_eq!(
+            response("ssdp:all").unwrap(),
+            "HTTP/1.1 200 OK\r\nCACHE-CONTROL: max-age=1700\r\n\
+             DATE: Sun, 06 Nov 1994 08:49:37 GMT\r\nEXT:\r\n\
+             LOCATION: http://192.168.1.5:8008/ssdp/device-desc.xml\r\n\
+             SERVER: Linux/3.8 UPnP/1.0\r\nST: upnp:rootdevice\r\n\
+             USN: uuid:tv::upnp:rootdevice\r\nBOOTID.UPNP.ORG: 7\r\n\r\n"
+        );
+        assert!(SsdpMessage::parse(response(root).unwrap().as_bytes()).is_some());
+
+        // Expired devices are not answered, and the one expiring first
+        // makes room
+        assert!(devices.matching("ssdp:all", expires).is_empty());
+        let soon = start + Duration::from_secs(200);
+        devices.store("uuid:tv::upnp:rootdevice", device(root, soon), start);
+        devices.store("uuid:speaker", device(root, expires), start);
+        let usns: Vec<&str> = devices
+            .matching("ssdp:all", start)
+            .into_iter()
+            .map(|(usn, _)| usn)
+            .collect();
+        assert_eq!(
+            usns,
+            ["uuid:speaker", format!("uuid:tv::{}", dial).as_str()]
+        );
+    }
+}
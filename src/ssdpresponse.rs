@@ -0,0 +1,211 @@
+//! Search responses sent for cached SSDP devices, laid out as in UPnP Device
+//! Architecture 2.0 section 1.3.3.
+
+use crate::ssdp::header;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Longest MX honoured; larger values are treated as this (UPnP Device
+/// Architecture 2.0 section 1.3.2)
+const MAX_MX: u64 = 5;
+const WEEKDAYS: [&str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// SERVER header of responses for devices that announced none
+pub fn default_server() -> String {
+    format!(
+        "{} UPnP/2.0 nw-pckt-fwd/{}",
+        std::env::consts::OS,
+        env!("CARGO_PKG_VERSION")
+    )
+}
+
+/// What a response says about one device, as it announced itself
+#[derive(Debug, Clone, Copy)]
+pub struct Advertisement<'a> {
+    pub usn: &'a str,
+    pub nt: &'a str,
+    pub location: &'a str,
+    pub server: Option<&'a str>,
+    pub boot_id: Option<u32>,
+    pub config_id: Option<u32>,
+    /// Seconds until the announcement expires
+    pub max_age: u64,
+}
+
+/// `value` if it can go into a header field: not empty and without line
+/// breaks or other control characters
+fn field(value: &str) -> Option<&str> {
+    let valid = !value.is_empty() && !value.chars().any(char::is_control);
+    valid.then_some(value)
+}
+
+/// Date in the format of HTTP (RFC 9110 section 5.6.7)
+pub fn http_date(at: SystemTime) -> String {
+    let secs = at
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs());
+    let (days, time) = (secs / 86_400, secs % 86_400);
+    // Civil date of a day count (Howard Hinnant's days_from_civil inverse)
+    let z = days as i64 + 719_468;
+    let (era, day_of_era) = (z.div_euclid(146_097), z.rem_euclid(146_097));
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        WEEKDAYS[((days + 4) % 7) as usize],
+        day,
+        MONTHS[month as usize - 1],
+        year,
+        time / 3600,
+        time % 3600 / 60,
+        time % 60
+    )
+}
+
+/// Window a search asks the responses to be spread over, from its MX
+/// header. `None` if it has none or it is not a number of seconds.
+pub fn mx(headers: &[(&str, &str)]) -> Option<Duration> {
+    let seconds: u64 = header(headers, "MX")?.parse().ok()?;
+    Some(Duration::from_secs(seconds.min(MAX_MX)))
+}
+
+/// 200 OK answering a search for `st` with `device`, sent at `date`. The ST
+/// echoes the search target, except for ssdp:all, which gets the device's
+/// NT. `server` replaces the SERVER the device announced. Returns `None` if
+/// a field of the device or the search target cannot go into a header.
+pub fn build(
+    st: &str,
+    device: &Advertisement,
+    server: Option<&str>,
+    date: SystemTime,
+) -> Option<String> {
+    let st = if st.eq_ignore_ascii_case("ssdp:all") {
+        device.nt
+    } else {
+        st
+    };
+    let location = field(device.location)?;
+    let scheme = location.get(..7)?;
+    if !scheme.eq_ignore_ascii_case("http://") {
+        return None;
+    }
+    let server = match server.or(device.server) {
+        Some(server) => field(server)?.to_string(),
+        None => default_server(),
+    };
+    let mut response = format!(
+        "HTTP/1.1 200 OK\r\nCACHE-CONTROL: max-age={}\r\nDATE: {}\r\nEXT:\r\nLOCATION: {}\r\n\
+         SERVER: {}\r\nST: {}\r\nUSN: {}\r\n",
+        device.max_age,
+        http_date(date),
+        location,
+        server,
+        field(st)?,
+        field(device.usn)?
+    );
+    if let Some(boot_id) = device.boot_id {
+        response.push_str(&format!("BOOTID.UPNP.ORG: {}\r\n", boot_id));
+    }
+    if let Some(config_id) = device.config_id {
+        response.push_str(&format!("CONFIGID.UPNP.ORG: {}\r\n", config_id));
+    }
+    response.push_str("\r\n");
+    Some(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ssdp::{split_message, SsdpKind, SsdpMessage};
+
+    #[test]
+    fn builds_responses_as_specified() {
+        assert_eq!(
+            http_date(UNIX_EPOCH + Duration::from_secs(784_111_777)),
+            "Sun, 06 Nov 1994 08:49:37 GMT"
+        );
+        assert_eq!(
+            http_date(UNIX_EPOCH + Duration::from_secs(951_782_400)),
+            "Tue, 29 Feb 2000 00:00:00 GMT"
+        );
+
+        // The example of UPnP Device Architecture 2.0 section 1.3.3
+        let device = Advertisement {
+            usn: "uuid:2fac1234-31f8-11b4-a222-08002b34c003::upnp:rootdevice",
+            nt: "upnp:rootdevice",
+            location: "http://192.168.1.5:49152/description.xml",
+            server: Some("Linux/5.10 UPnP/2.0 Renderer/1.0"),
+            boot_id: Some(1_700_000_000),
+            config_id: Some(123),
+            max_age: 1800,
+        };
+        let date = UNIX_EPOCH + Duration::from_secs(784_111_777);
+        let response = build("ssdp:all", &device, None, date).unwrap();
+        assert_eq!(
+            response,
+            "HTTP/1.1 200 OK\r\nCACHE-CONTROL: max-age=1800\r\n\
+             DATE: Sun, 06 Nov 1994 08:49:37 GMT\r\nEXT:\r\n\
+             LOCATION: http://192.168.1.5:49152/description.xml\r\n\
+             SERVER: Linux/5.10 UPnP/2.0 Renderer/1.0\r\nST: upnp:rootdevice\r\n\
+             USN: uuid:2fac1234-31f8-11b4-a222-08002b34c003::upnp:rootdevice\r\n\
+             BOOTID.UPNP.ORG: 1700000000\r\nCONFIGID.UPNP.ORG: 123\r\n\r\n"
+        );
+        let parsed = SsdpMessage::parse(response.as_bytes()).unwrap();
+        assert_eq!(parsed.kind, SsdpKind::Response);
+
+        // The search target is echoed, and the configured identity wins
+        let response = build("UPNP:ROOTDEVICE", &device, Some("proxy/1.0"), date).unwrap();
+        let (_, headers) = split_message(response.as_bytes()).unwrap();
+        assert_eq!(header(&headers, "ST"), Some("UPNP:ROOTDEVICE"));
+        assert_eq!(header(&headers, "SERVER"), Some("proxy/1.0"));
+        let anonymous = Advertisement {
+            server: None,
+            boot_id: None,
+            config_id: None,
+            ..device
+        };
+        let response = build("upnp:rootdevice", &anonymous, None, date).unwrap();
+        let (_, headers) = split_message(response.as_bytes()).unwrap();
+        assert_eq!(header(&headers, "SERVER"), Some(default_server().as_str()));
+        assert_eq!(header(&headers, "BOOTID.UPNP.ORG"), None);
+
+        // Malformed entries are not answered with
+        for malformed in [
+            Advertisement {
+                location: "ftp://192.168.1.5/description.xml",
+                ..device
+            },
+            Advertisement {
+                location: "",
+                ..device
+            },
+            Advertisement {
+                usn: "uuid:1\r\nLOCATION: http://attacker/",
+                ..device
+            },
+            Advertisement {
+                server: Some("Linux\n"),
+                ..device
+            },
+        ] {
+            assert!(build("upnp:rootdevice", &malformed, None, date).is_none());
+        }
+
+        let search = [("MX", "3")];
+        assert_eq!(mx(&search), Some(Duration::from_secs(3)));
+        assert_eq!(mx(&[("mx", "120")]), Some(Duration::from_secs(MAX_MX)));
+        assert_eq!(mx(&[("MX", "soon")]), None);
+        assert_eq!(mx(&[]), None);
+    }
+}
@@ -0,0 +1,446 @@
+//! Warm standby between redundant instances: only the elected leader sends,
+//! the others keep their channels open and learn from what they receive so
+//! that they can take over with warm caches.
+//!
+//! With a heartbeat group every instance sends a heartbeat each interval to
+//! the group on one interface. A standby takes over, in a new epoch, once
+//! it has heard no leader for the given number of intervals. Two leaders
+//! meeting again after a partition keep the one of the later epoch, then
+//! the one of higher priority, then the one with the higher random id, so
+//! one of them steps down as soon as it hears the other. With a lock file
+//! the instance holding an exclusive lock on it leads, and counts the
+//! epoch up in the file when it takes over.
+
+use crate::error::Error;
+use crate::pause::Pause;
+use crate::random::random;
+use pnet::datalink::NetworkInterface;
+use serde::Serialize;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info, warn};
+
+const MAGIC: &[u8; 4] = b"NWPF";
+const VERSION: u8 = 1;
+const HEARTBEAT_LEN: usize = 24;
+
+/// What an instance tells the others every interval
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Heartbeat {
+    pub id: u64,
+    pub priority: u8,
+    pub epoch: u64,
+    pub leader: bool,
+}
+
+impl Heartbeat {
+    /// Magic, version, leader flag, priority, a reserved byte, then the
+    /// epoch and the id in network byte order
+    pub fn encode(&self) -> [u8; HEARTBEAT_LEN] {
+        let mut buf = [0; HEARTBEAT_LEN];
+        buf[..4].copy_from_slice(MAGIC);
+        buf[4] = VERSION;
+        buf[5] = self.leader as u8;
+        buf[6] = self.priority;
+        buf[8..16].copy_from_slice(&self.epoch.to_be_bytes());
+        buf[16..].copy_from_slice(&self.id.to_be_bytes());
+        buf
+    }
+
+    pub fn decode(buf: &[u8]) -> Option<Self> {
+        if buf.len() != HEARTBEAT_LEN || &buf[..4] != MAGIC || buf[4] != VERSION {
+            return None;
+        }
+        Some(Heartbeat {
+            leader: buf[5] != 0,
+            priority: buf[6],
+            epoch: u64::from_be_bytes(buf[8..16].try_into().unwrap()),
+            id: u64::from_be_bytes(buf[16..].try_into().unwrap()),
+        })
+    }
+
+    /// Which of two leaders stays
+    fn rank(&self) -> (u64, u8, u64) {
+        (self.epoch, self.priority, self.id)
+    }
+}
+
+/// Election over heartbeats, fed with what is heard and the passing time
+#[derive(Debug)]
+pub struct Elector {
+    own: Heartbeat,
+    timeout: Duration,
+    leader_seen: Instant,
+}
+
+impl Elector {
+    /// Starts as standby and waits one `timeout` for a leader before taking
+    /// over
+    pub fn new(id: u64, priority: u8, timeout: Duration, now: Instant) -> Self {
+        Elector {
+            own: Heartbeat {
+                id,
+                priority,
+                epoch: 0,
+                leader: false,
+            },
+            timeout,
+            leader_seen: now,
+        }
+    }
+
+    /// Heartbeat to send now
+    pub fn heartbeat(&self) -> Heartbeat {
+        self.own
+    }
+
+    /// Takes in a heartbeat from another instance; `Some` with whether this
+    /// instance leads if that changed
+    pub fn heard(&mut self, heartbeat: Heartbeat, now: Instant) -> Option<bool> {
+        if heartbeat.id == self.own.id {
+            return None;
+        }
+        let outranked = heartbeat.leader && heartbeat.rank() > self.own.rank();
+        self.own.epoch = self.own.epoch.max(heartbeat.epoch);
+        if !heartbeat.leader {
+            return None;
+        }
+        if self.own.leader && !outranked {
+            return None;
+        }
+        self.leader_seen = now;
+        if self.own.leader {
+            self.own.leader = false;
+            return Some(false);
+        }
+        None
+    }
+
+    /// Takes over in a new epoch once no leader was heard for the timeout;
+    /// `Some(true)` if it did
+    pub fn tick(&mut self, now: Instant) -> Option<bool> {
+        if self.own.leader || now.duration_since(self.leader_seen) < self.timeout {
+            return None;
+        }
+        self.own.leader = true;
+        self.own.epoch += 1;
+        Some(true)
+    }
+}
+
+/// Role of this instance, shared with the stats
+#[derive(Debug)]
+pub struct Election {
+    leader: AtomicBool,
+    epoch: AtomicU64,
+    pause: Arc<Pause>,
+}
+
+impl Election {
+    /// Starts as standby, which stops sending through `pause`
+    pub fn new(pause: Arc<Pause>) -> Self {
+        pause.set_standby(true);
+        Election {
+            leader: AtomicBool::new(false),
+            epoch: AtomicU64::new(0),
+            pause,
+        }
+    }
+
+    pub fn is_leader(&self) -> bool {
+        self.leader.load(Ordering::Acquire)
+    }
+
+    pub fn epoch(&self) -> u64 {
+        self.epoch.load(Ordering::Relaxed)
+    }
+
+    fn apply(&self, leader: bool, epoch: u64) {
+        self.epoch.store(epoch, Ordering::Relaxed);
+        if self.leader.swap(leader, Ordering::AcqRel) == leader {
+            return;
+        }
+        self.pause.set_standby(!leader);
+        if leader {
+            info!("Leading at epoch {}, forwarding", epoch);
+        } else {
+            warn!("Another instance leads at epoch {}, standing by", epoch);
+        }
+    }
+
+    pub fn snapshot(&self) -> RoleSnapshot {
+        RoleSnapshot {
+            role: if self.is_leader() {
+                "leader"
+            } else {
+                "standby"
+            },
+            epoch: self.epoch(),
+        }
+    }
+}
+
+/// Point-in-time copy of [`Election`]
+#[derive(Debug, Clone, Serialize)]
+pub struct RoleSnapshot {
+    pub role: &'static str,
+    pub epoch: u64,
+}
+
+/// How the instances coordinate, set up before the sandbox is entered
+pub enum Coordination {
+    Heartbeats {
+        socket: UdpSocket,
+        group: SocketAddrV4,
+        priority: u8,
+    },
+    Lock {
+        file: File,
+        path: PathBuf,
+    },
+}
+
+impl Coordination {
+    /// Joins `group` on `iface` to exchange heartbeats with the other
+    /// instances
+    pub fn heartbeats(
+        group: SocketAddrV4,
+        iface: &NetworkInterface,
+        priority: u8,
+    ) -> Result<Self, Error> {
+        let failed = |source| Error::Standby {
+            what: format!("heartbeats on {}", iface.name),
+            source,
+        };
+        let addr = iface
+            .ips
+            .iter()
+            .find_map(|ip| match ip.ip() {
+                IpAddr::V4(ip) => Some(ip),
+                IpAddr::V6(_) => None,
+            })
+            .ok_or_else(|| Error::MissingAddress {
+                iface: iface.name.clone(),
+                what: "IPv4 address for standby heartbeats",
+            })?;
+        let socket = join(group, addr).map_err(failed)?;
+        let socket = UdpSocket::from_std(socket.into()).map_err(failed)?;
+        info!(
+            "Electing a leader over heartbeats to {} on {}",
+            group, iface.name
+        );
+        Ok(Coordination::Heartbeats {
+            socket,
+            group,
+            priority,
+        })
+    }
+
+    /// Opens the lock file at `path`, creating it if needed
+    pub fn lock(path: &Path) -> Result<Self, Error> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .map_err(|source| Error::Standby {
+                what: format!("lock file {}", path.display()),
+                source,
+            })?;
+        info!("Leading while holding a lock on {}", path.display());
+        Ok(Coordination::Lock {
+            file,
+            path: path.to_path_buf(),
+        })
+    }
+}
+
+fn join(group: SocketAddrV4, addr: Ipv4Addr) -> io::Result<Socket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, group.port())).into())?;
+    socket.join_multicast_v4(group.ip(), &addr)?;
+    socket.set_multicast_if_v4(&addr)?;
+    socket.set_multicast_ttl_v4(1)?;
+    // Instances on the same host hear each other
+    socket.set_multicast_loop_v4(true)?;
+    socket.set_nonblocking(true)?;
+    Ok(socket)
+}
+
+/// Random id telling this instance apart from the others
+fn instance_id() -> u64 {
+    random()
+}
+
+/// Elects the leader each `interval` until `token` is cancelled. With
+/// heartbeats a standby takes over after `missed` intervals without
+/// hearing a leader.
+pub fn spawn(
+    coordination: Coordination,
+    election: Arc<Election>,
+    interval: Duration,
+    missed: u32,
+    token: CancellationToken,
+) -> JoinHandle<()> {
+    match coordination {
+        Coordination::Heartbeats {
+            socket,
+            group,
+            priority,
+        } => tokio::spawn(async move {
+            let mut elector =
+                Elector::new(instance_id(), priority, interval * missed, Instant::now());
+            let mut ticks = tokio::time::interval(interval);
+            let mut buf = [0; 64];
+            loop {
+                tokio::select! {
+                    _ = token.cancelled() => break,
+                    _ = ticks.tick() => {
+                        elector.tick(Instant::now());
+                        let own = elector.heartbeat();
+                        election.apply(own.leader, own.epoch);
+                        if let Err(e) = socket.send_to(&own.encode(), group).await {
+                            warn!("Failed to send heartbeat to {}: {}", group, e);
+                        }
+                    }
+                    received = socket.recv_from(&mut buf) => match received {
+                        Ok((len, from)) => match Heartbeat::decode(&buf[..len]) {
+                            Some(heartbeat) => {
+                                elector.heard(heartbeat, Instant::now());
+                                let own = elector.heartbeat();
+                                election.apply(own.leader, own.epoch);
+                            }
+                            None => debug!("Ignoring invalid heartbeat from {}", from),
+                        },
+                        Err(e) => warn!("Failed to receive heartbeats: {}", e),
+                    },
+                }
+            }
+        }),
+        Coordination::Lock { file, path } => tokio::spawn(async move {
+            let mut ticks = tokio::time::interval(interval);
+            loop {
+                tokio::select! {
+                    _ = token.cancelled() => break,
+                    _ = ticks.tick() => match take_lock(&file) {
+                        Ok(Some(epoch)) => {
+                            election.apply(true, epoch);
+                            // The lock is held until the file is closed
+                            break;
+                        }
+                        Ok(None) => {}
+                        Err(e) => warn!("Failed to lock {}: {}", path.display(), e),
+                    },
+                }
+            }
+            token.cancelled().await;
+            drop(file);
+        }),
+    }
+}
+
+/// Takes the lock on `file` if no other instance holds it, and counts up
+/// the epoch stored in it; `None` if another instance holds it
+fn take_lock(file: &File) -> io::Result<Option<u64>> {
+    // SAFETY: flock on a file descriptor owned by `file`
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+        let e = io::Error::last_os_error();
+        return match e.raw_os_error() {
+            Some(libc::EWOULDBLOCK) => Ok(None),
+            _ => Err(e),
+        };
+    }
+    let mut buf = [0; 20];
+    let len = file.read_at(&mut buf, 0)?;
+    let epoch = std::str::from_utf8(&buf[..len])
+        .ok()
+        .and_then(|text| text.trim().parse::<u64>().ok())
+        .unwrap_or(0)
+        + 1;
+    let text = format!("{}\n", epoch);
+    file.set_len(0)?;
+    file.write_all_at(text.as_bytes(), 0)?;
+    file.sync_all()?;
+    Ok(Some(epoch))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn heartbeats_round_trip() {
+        let heartbeat = Heartbeat {
+            id: 0x0102_0304_0506_0708,
+            priority: 200,
+            epoch: 7,
+            leader: true,
+        };
+        let buf = heartbeat.encode();
+        assert_eq!(&buf[..4], b"NWPF");
+        assert_eq!(Heartbeat::decode(&buf), Some(heartbeat));
+        assert_eq!(Heartbeat::decode(&buf[..20]), None);
+        let mut other = buf;
+        other[4] = 2;
+        assert_eq!(Heartbeat::decode(&other), None);
+    }
+
+    #[test]
+    fn takes_over_after_missed_heartbeats_and_heals_partitions() {
+        let timeout = Duration::from_secs(3);
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let mut a = Elector::new(1, 100, timeout, start);
+        let mut b = Elector::new(2, 100, timeout, start);
+
+        // Nobody leads before the first timeout, then both would take over
+        // on their own; the first to do so makes the other stand by
+        assert_eq!(a.tick(at(2)), None);
+        assert_eq!(a.tick(at(3)), Some(true));
+        assert_eq!(b.heard(a.heartbeat(), at(3)), None);
+        assert_eq!(b.tick(at(5)), None);
+        assert_eq!(b.heartbeat().epoch, 1);
+        assert_eq!(a.heard(b.heartbeat(), at(5)), None);
+
+        // A goes silent, B takes over once the heartbeats are missed
+        assert_eq!(b.tick(at(5)), None);
+        assert_eq!(b.tick(at(6)), Some(true));
+        assert_eq!(b.heartbeat().epoch, 2);
+
+        // After the partition heals the leader of the older epoch steps
+        // down, even if it has the higher id
+        assert_eq!(b.heard(a.heartbeat(), at(7)), None);
+        assert_eq!(a.heard(b.heartbeat(), at(7)), Some(false));
+        assert!(!a.heartbeat().leader && b.heartbeat().leader);
+        assert_eq!(a.heartbeat().epoch, 2);
+        assert_eq!(a.tick(at(9)), None);
+
+        // Within one epoch the priority decides, then the id
+        let mut c = Elector::new(3, 50, timeout, start);
+        let mut d = Elector::new(4, 50, timeout, start);
+        c.tick(at(3));
+        d.tick(at(3));
+        assert_eq!(d.heard(c.heartbeat(), at(4)), None);
+        assert_eq!(c.heard(d.heartbeat(), at(4)), Some(false));
+        let mut e = Elector::new(5, 10, timeout, start);
+        e.tick(at(3));
+        assert_eq!(e.heard(d.heartbeat(), at(4)), Some(false));
+
+        // Own heartbeats come back over the loopback and are ignored
+        assert_eq!(d.heard(d.heartbeat(), at(4)), None);
+        assert!(d.heartbeat().leader);
+    }
+}
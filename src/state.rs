@@ -0,0 +1,251 @@
+//! State learned at run time that is kept across restarts with
+//! `--state-file`: the device inventory, the SSDP and mDNS caches and the
+//! MAC tables, which otherwise stay empty until devices announce themselves
+//! again.
+//!
+//! The file is JSON with a `version`. Fields added later must default when
+//! missing, so older files still load; a file of another version, or one
+//! that cannot be read, is ignored with a warning. Times are stored as wall
+//! clock times, and entries that expired while the forwarder was not
+//! running are left out when the file is loaded.
+
+use crate::bridge::SavedMac;
+use crate::hostmac::SavedHost;
+use crate::inventory::SavedDevice;
+use crate::mdnscache::SavedRecord;
+use crate::ssdpcache::SavedSsdpDevice;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io;
+use std::path::Path;
+use std::time::{Instant, SystemTime};
+use tracing::{debug, info, warn};
+
+/// Version of the file format, raised only for changes older versions
+/// cannot read by ignoring what they do not know
+pub const STATE_VERSION: u32 = 1;
+
+/// What a pair learned, stored under its name
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct SavedPair {
+    #[serde(default)]
+    pub inventory: Vec<SavedDevice>,
+    #[serde(default)]
+    pub ssdp_cache: Vec<SavedSsdpDevice>,
+    #[serde(default)]
+    pub mdns_cache: Vec<SavedRecord>,
+    /// Learned internal hosts
+    #[serde(default)]
+    pub hosts: Vec<SavedHost>,
+}
+
+/// Contents of the state file
+#[derive(Debug, Deserialize, Serialize)]
+pub struct SavedState {
+    pub version: u32,
+    #[serde(with = "humantime_serde")]
+    pub saved: SystemTime,
+    #[serde(default)]
+    pub pairs: BTreeMap<String, SavedPair>,
+    /// MAC table of bridge mode
+    #[serde(default)]
+    pub bridge: Vec<SavedMac>,
+}
+
+impl Default for SavedState {
+    fn default() -> Self {
+        SavedState {
+            version: STATE_VERSION,
+            saved: SystemTime::now(),
+            pairs: BTreeMap::new(),
+            bridge: Vec::new(),
+        }
+    }
+}
+
+/// Just the version, read before the rest
+#[derive(Deserialize)]
+struct Version {
+    version: u32,
+}
+
+/// Reads the state saved at `path`. A missing file is a first start; an
+/// unreadable, corrupt or foreign one is logged and treated the same.
+pub fn load(path: &Path) -> Option<SavedState> {
+    let text = match std::fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            debug!("No state saved in {} yet", path.display());
+            return None;
+        }
+        Err(e) => {
+            warn!("Ignoring state file {}: {}", path.display(), e);
+            return None;
+        }
+    };
+    let state = serde_json::from_str::<Version>(&text).and_then(|Version { version }| {
+        if version != STATE_VERSION {
+            return Ok(Err(version));
+        }
+        serde_json::from_str::<SavedState>(&text).map(Ok)
+    });
+    match state {
+        Ok(Ok(state)) => {
+            info!(
+                "Loaded state saved in {} at {}",
+                path.display(),
+                humantime::format_rfc3339_seconds(state.saved)
+            );
+            Some(state)
+        }
+        Ok(Err(version)) => {
+            warn!(
+                "Ignoring state file {} of version {}, expected {}",
+                path.display(),
+                version,
+                STATE_VERSION
+            );
+            None
+        }
+        Err(e) => {
+            warn!("Ignoring corrupt state file {}: {}", path.display(), e);
+            None
+        }
+    }
+}
+
+/// Writes `state` to `path` through a temporary file renamed over it, so a
+/// crash while saving leaves the previous state
+pub fn save(path: &Path, state: &SavedState) -> io::Result<()> {
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(".tmp");
+    let json = serde_json::to_vec_pretty(state).map_err(io::Error::other)?;
+    std::fs::write(&temporary, json)?;
+    std::fs::rename(&temporary, path)
+}
+
+/// Wall clock time of `at`, given that `now` is `wall`
+pub fn wall_time(at: Instant, now: Instant, wall: SystemTime) -> SystemTime {
+    if at >= now {
+        wall + (at - now)
+    } else {
+        wall - (now - at)
+    }
+}
+
+/// Instant of the wall clock time `at`, given that `now` is `wall`, or
+/// `None` for times older than the monotonic clock, such as before a reboot
+pub fn instant(at: SystemTime, now: Instant, wall: SystemTime) -> Option<Instant> {
+    match at.duration_since(wall) {
+        Ok(ahead) => Some(now + ahead),
+        Err(behind) => now.checked_sub(behind.duration()),
+    }
+}
+
+/// Bytes in the file as a hex string, for `#[serde(with = ...)]`
+pub mod hex {
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn encode(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{b:02x}")).collect()
+    }
+
+    pub fn decode(text: &str) -> Option<Vec<u8>> {
+        if !text.is_ascii() || !text.len().is_multiple_of(2) {
+            return None;
+        }
+        (0..text.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&text[i..i + 2], 16).ok())
+            .collect()
+    }
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&encode(bytes))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let text = String::deserialize(deserializer)?;
+        decode(&text).ok_or_else(|| D::Error::custom("invalid hex string"))
+    }
+
+    /// A list of byte strings, such as the labels of a DNS name
+    pub mod list {
+        use serde::de::Error;
+        use serde::{Deserialize, Deserializer, Serializer};
+
+        pub fn serialize<S: Serializer>(
+            list: &[Vec<u8>],
+            serializer: S,
+        ) -> Result<S::Ok, S::Error> {
+            serializer.collect_seq(list.iter().map(|bytes| super::encode(bytes)))
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(
+            deserializer: D,
+        ) -> Result<Vec<Vec<u8>>, D::Error> {
+            Vec::<String>::deserialize(deserializer)?
+                .iter()
+                .map(|text| {
+                    super::decode(text).ok_or_else(|| D::Error::custom("invalid hex string"))
+                })
+                .collect()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn ignores_foreign_and_corrupt_files() {
+        let dir = std::env::temp_dir().join(format!("nw-pckt-fwd-state-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("state.json");
+        assert!(load(&path).is_none());
+
+        let mut state = SavedState::default();
+        state
+            .pairs
+            .insert("eth0<->vm0".to_string(), SavedPair::default());
+        save(&path, &state).unwrap();
+        let loaded = load(&path).unwrap();
+        assert_eq!(loaded.version, STATE_VERSION);
+        assert!(loaded.pairs.contains_key("eth0<->vm0"));
+
+        // Fields of later versions are skipped and missing ones default
+        std::fs::write(
+            &path,
+            r#"{"version": 1, "saved": "2026-01-01T00:00:00Z", "future": [1, 2]}"#,
+        )
+        .unwrap();
+        assert!(load(&path).unwrap().pairs.is_empty());
+        std::fs::write(&path, r#"{"version": 2, "saved": "2026-01-01T00:00:00Z"}"#).unwrap();
+        assert!(load(&path).is_none());
+        std::fs::write(&path, r#"{"version": 1, "saved": "#).unwrap();
+        assert!(load(&path).is_none());
+        std::fs::write(&path, [0xff, 0xfe, 0]).unwrap();
+        assert!(load(&path).is_none());
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(hex::encode(b"\x00tv"), "007476");
+        assert_eq!(hex::decode("007476").unwrap(), b"\x00tv");
+        assert!(hex::decode("0g").is_none() && hex::decode("007").is_none());
+
+        let (now, wall) = (Instant::now(), SystemTime::now());
+        let later = now + Duration::from_secs(90);
+        assert_eq!(wall_time(later, now, wall), wall + Duration::from_secs(90));
+        assert_eq!(
+            instant(wall + Duration::from_secs(90), now, wall),
+            Some(later)
+        );
+        let earlier = wall - Duration::from_secs(5);
+        assert_eq!(
+            instant(earlier, now, wall),
+            now.checked_sub(Duration::from_secs(5))
+        );
+    }
+}
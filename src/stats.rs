@@ -0,0 +1,1007 @@
+//! Forwarding counters shared between the capture and send tasks.
+
+use crate::iface::KernelDrops;
+use crate::latency::{LatencyHistogram, LatencySnapshot};
+use crate::pair::Direction;
+use crate::passthrough::ControlProtocol;
+use crate::pause::Pause;
+use crate::quota::{QuotaSnapshot, Quotas};
+use crate::standby::{Election, RoleSnapshot};
+use crate::talkers::{Talker, TopTalkers};
+use crate::ttl::{DECREMENT_TTL, ON_LINK_TTL};
+use crate::wol::WAKE_ON_LAN;
+use serde::Serialize;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::info;
+
+/// Counters of one forwarding path, i.e. one direction of a pair or the
+/// path between two bridge ports
+#[derive(Debug)]
+pub struct PathStats {
+    pub pair: String,
+    pub direction: Direction,
+    pub ingress: String,
+    pub egress: String,
+    received: AtomicU64,
+    received_bytes: AtomicU64,
+    queued: AtomicU64,
+    retried: AtomicU64,
+    /// Oversized packets sent as fragments
+    fragmented: AtomicU64,
+    /// Frames whose checksums were left to offload by the sender, filled in
+    offloaded: AtomicU64,
+    /// Wake-on-LAN magic packets forwarded ahead of the protocol filters
+    wol_forwarded: AtomicU64,
+    /// mDNS responses held back and sent merged with others
+    mdns_merged: AtomicU64,
+    /// EAPOL and LLDP frames passed through, counted apart so that it shows
+    /// whether they cross at all
+    eapol_passed: AtomicU64,
+    lldp_passed: AtomicU64,
+    forwarded: AtomicU64,
+    forwarded_bytes: AtomicU64,
+    source_not_allowed: AtomicU64,
+    other_vlan: AtomicU64,
+    /// Frames to a group address reserved by IEEE 802.1, such as spanning
+    /// tree BPDUs
+    reserved: AtomicU64,
+    non_ipv4: AtomicU64,
+    unmatched_protocol: AtomicU64,
+    /// IP fragments no filter accepted, which carry no ports to filter on
+    fragments: AtomicU64,
+    port_mismatch: AtomicU64,
+    filtered: AtomicU64,
+    /// LLMNR and NetBIOS name service frames, counted apart so that it
+    /// shows they never crossed
+    llmnr: AtomicU64,
+    netbios_ns: AtomicU64,
+    /// Wake-on-LAN magic packets dropped, in the wrong direction or for a
+    /// machine not allowed to be woken
+    wol: AtomicU64,
+    bad_checksum: AtomicU64,
+    rewrite_failed: AtomicU64,
+    expired: AtomicU64,
+    /// mDNS and LLMNR packets whose TTL shows they were routed from another
+    /// link
+    off_link: AtomicU64,
+    looped: AtomicU64,
+    rate_limited: AtomicU64,
+    /// Frames over a quota whose budget is used up
+    quota_exceeded: AtomicU64,
+    /// Broadcast and multicast frames received during a storm
+    storm: AtomicU64,
+    cached: AtomicU64,
+    oversize: AtomicU64,
+    queue_full: AtomicU64,
+    /// Frames the shaper would have delayed past the maximum delay
+    shaper: AtomicU64,
+    send_error: AtomicU64,
+    /// Frames received or still queued while forwarding was paused
+    paused: AtomicU64,
+    /// Frames not sent because another instance leads
+    standby: AtomicU64,
+    /// From receiving to sending forwarded frames
+    latency: LatencyHistogram,
+    /// Delays added by the shaper
+    shaped: LatencyHistogram,
+    /// Sources of the received frames sending the most
+    pub talkers: TopTalkers,
+}
+
+/// Why a frame was not forwarded
+#[derive(Debug, Clone, Copy)]
+pub enum DropReason<'a> {
+    /// Dropped by the named filter, or `no-match` if no filter claimed it
+    Filter(&'a str),
+    /// Received on a VLAN other than the one of the ingress interface
+    Vlan,
+    /// Sent to a group address reserved by IEEE 802.1
+    Reserved,
+    /// IP fragment no filter accepted
+    Fragment,
+    /// Rejected by the named rewrite stage
+    Rewrite(&'a str),
+    Loop,
+    RateLimit,
+    /// Over a quota whose budget is used up for the current window
+    Quota,
+    /// Broadcast or multicast received while storm control suspended it
+    Storm,
+    /// Query answered from a cache on the internal side
+    Cached,
+    /// Larger than the MTU of the egress interface
+    Oversize,
+    QueueFull,
+    /// Would have been delayed by the shaper past the maximum delay
+    Shaper,
+    SendError,
+    /// Received or still queued while forwarding was paused
+    Paused,
+    /// Queued while another instance leads
+    Standby,
+}
+
+impl PathStats {
+    pub fn new(pair: String, direction: Direction, ingress: String, egress: String) -> Self {
+        PathStats {
+            pair,
+            direction,
+            ingress,
+            egress,
+            received: AtomicU64::new(0),
+            received_bytes: AtomicU64::new(0),
+            queued: AtomicU64::new(0),
+            retried: AtomicU64::new(0),
+            fragmented: AtomicU64::new(0),
+            offloaded: AtomicU64::new(0),
+            wol_forwarded: AtomicU64::new(0),
+            mdns_merged: AtomicU64::new(0),
+            eapol_passed: AtomicU64::new(0),
+            lldp_passed: AtomicU64::new(0),
+            forwarded: AtomicU64::new(0),
+            forwarded_bytes: AtomicU64::new(0),
+            source_not_allowed: AtomicU64::new(0),
+            other_vlan: AtomicU64::new(0),
+            reserved: AtomicU64::new(0),
+            non_ipv4: AtomicU64::new(0),
+            unmatched_protocol: AtomicU64::new(0),
+            fragments: AtomicU64::new(0),
+            port_mismatch: AtomicU64::new(0),
+            filtered: AtomicU64::new(0),
+            llmnr: AtomicU64::new(0),
+            netbios_ns: AtomicU64::new(0),
+            wol: AtomicU64::new(0),
+            bad_checksum: AtomicU64::new(0),
+            rewrite_failed: AtomicU64::new(0),
+            expired: AtomicU64::new(0),
+            off_link: AtomicU64::new(0),
+            looped: AtomicU64::new(0),
+            rate_limited: AtomicU64::new(0),
+            quota_exceeded: AtomicU64::new(0),
+            storm: AtomicU64::new(0),
+            cached: AtomicU64::new(0),
+            oversize: AtomicU64::new(0),
+            queue_full: AtomicU64::new(0),
+            shaper: AtomicU64::new(0),
+            send_error: AtomicU64::new(0),
+            paused: AtomicU64::new(0),
+            standby: AtomicU64::new(0),
+            latency: LatencyHistogram::new(),
+            shaped: LatencyHistogram::new(),
+            talkers: TopTalkers::default(),
+        }
+    }
+
+    pub fn received(&self, len: usize) {
+        self.received.fetch_add(1, Ordering::Relaxed);
+        self.received_bytes.fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    /// Counts a frame accepted into the send queue
+    pub fn queued(&self) {
+        self.queued.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a send retried because the interface was busy
+    pub fn retried(&self) {
+        self.retried.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a packet sent as fragments
+    pub fn fragmented(&self) {
+        self.fragmented.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a frame whose checksums were filled in for its sender
+    pub fn offloaded(&self) {
+        self.offloaded.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a forwarded frame by the filter that let it through, for the
+    /// filters whose frames are counted apart
+    pub fn claimed(&self, filter: &str) {
+        if filter == WAKE_ON_LAN {
+            self.wol_forwarded.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Counts mDNS responses sent merged into fewer
+    pub fn merged(&self, responses: u32) {
+        self.mdns_merged
+            .fetch_add(u64::from(responses), Ordering::Relaxed);
+    }
+
+    /// Counts a control protocol frame queued ahead of the filters
+    pub fn passed(&self, protocol: ControlProtocol) {
+        let counter = match protocol {
+            ControlProtocol::Eapol => &self.eapol_passed,
+            ControlProtocol::Lldp => &self.lldp_passed,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn forwarded(&self, len: usize) {
+        self.forwarded.fetch_add(1, Ordering::Relaxed);
+        self.forwarded_bytes
+            .fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    /// Records how long after being received a frame was sent
+    pub fn latency(&self, latency: Duration) {
+        self.latency.record(latency);
+    }
+
+    /// Records how long the shaper delayed a frame
+    pub fn shaped(&self, delay: Duration) {
+        self.shaped.record(delay);
+    }
+
+    pub fn dropped(&self, reason: DropReason) {
+        let counter = match reason {
+            DropReason::Filter("source-allowlist") => &self.source_not_allowed,
+            DropReason::Filter("ipv4-only") => &self.non_ipv4,
+            DropReason::Filter("no-match") => &self.unmatched_protocol,
+            DropReason::Filter("udp-ports" | "tcp-ports") => &self.port_mismatch,
+            DropReason::Filter("checksum") => &self.bad_checksum,
+            DropReason::Filter("llmnr") => &self.llmnr,
+            DropReason::Filter("netbios-ns") => &self.netbios_ns,
+            DropReason::Filter(WAKE_ON_LAN) => &self.wol,
+            DropReason::Filter(_) => &self.filtered,
+            DropReason::Fragment => &self.fragments,
+            DropReason::Vlan => &self.other_vlan,
+            DropReason::Reserved => &self.reserved,
+            DropReason::Rewrite(DECREMENT_TTL) => &self.expired,
+            DropReason::Rewrite(ON_LINK_TTL) => &self.off_link,
+            DropReason::Rewrite(_) => &self.rewrite_failed,
+            DropReason::Loop => &self.looped,
+            DropReason::RateLimit => &self.rate_limited,
+            DropReason::Quota => &self.quota_exceeded,
+            DropReason::Storm => &self.storm,
+            DropReason::Cached => &self.cached,
+            DropReason::Oversize => &self.oversize,
+            DropReason::QueueFull => &self.queue_full,
+            DropReason::Shaper => &self.shaper,
+            DropReason::SendError => &self.send_error,
+            DropReason::Paused => &self.paused,
+            DropReason::Standby => &self.standby,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Sets all counters back to zero
+    pub fn reset(&self) {
+        for counter in [
+            &self.received,
+            &self.received_bytes,
+            &self.queued,
+            &self.retried,
+            &self.fragmented,
+            &self.offloaded,
+            &self.wol_forwarded,
+            &self.mdns_merged,
+            &self.eapol_passed,
+            &self.lldp_passed,
+            &self.forwarded,
+            &self.forwarded_bytes,
+            &self.source_not_allowed,
+            &self.other_vlan,
+            &self.reserved,
+            &self.non_ipv4,
+            &self.unmatched_protocol,
+            &self.fragments,
+            &self.port_mismatch,
+            &self.filtered,
+            &self.llmnr,
+            &self.netbios_ns,
+            &self.wol,
+            &self.bad_checksum,
+            &self.rewrite_failed,
+            &self.expired,
+            &self.off_link,
+            &self.looped,
+            &self.rate_limited,
+            &self.quota_exceeded,
+            &self.storm,
+            &self.cached,
+            &self.oversize,
+            &self.queue_full,
+            &self.shaper,
+            &self.send_error,
+            &self.paused,
+            &self.standby,
+        ] {
+            counter.store(0, Ordering::Relaxed);
+        }
+        self.latency.reset();
+        self.shaped.reset();
+        self.talkers.reset();
+    }
+
+    pub fn snapshot(&self) -> PathSnapshot {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        PathSnapshot {
+            pair: self.pair.clone(),
+            direction: self.direction,
+            ingress: self.ingress.clone(),
+            egress: self.egress.clone(),
+            received: load(&self.received),
+            received_bytes: load(&self.received_bytes),
+            queued: load(&self.queued),
+            retried: load(&self.retried),
+            fragmented: load(&self.fragmented),
+            offloaded: load(&self.offloaded),
+            wol_forwarded: load(&self.wol_forwarded),
+            mdns_merged: load(&self.mdns_merged),
+            eapol_passed: load(&self.eapol_passed),
+            lldp_passed: load(&self.lldp_passed),
+            forwarded: load(&self.forwarded),
+            forwarded_bytes: load(&self.forwarded_bytes),
+            source_not_allowed: load(&self.source_not_allowed),
+            other_vlan: load(&self.other_vlan),
+            reserved: load(&self.reserved),
+            non_ipv4: load(&self.non_ipv4),
+            unmatched_protocol: load(&self.unmatched_protocol),
+            fragments: load(&self.fragments),
+            port_mismatch: load(&self.port_mismatch),
+            filtered: load(&self.filtered),
+            llmnr: load(&self.llmnr),
+            netbios_ns: load(&self.netbios_ns),
+            wol: load(&self.wol),
+            bad_checksum: load(&self.bad_checksum),
+            rewrite_failed: load(&self.rewrite_failed),
+            expired: load(&self.expired),
+            off_link: load(&self.off_link),
+            looped: load(&self.looped),
+            rate_limited: load(&self.rate_limited),
+            quota_exceeded: load(&self.quota_exceeded),
+            storm: load(&self.storm),
+            cached: load(&self.cached),
+            oversize: load(&self.oversize),
+            queue_full: load(&self.queue_full),
+            shaper: load(&self.shaper),
+            send_error: load(&self.send_error),
+            paused: load(&self.paused),
+            standby: load(&self.standby),
+            latency: self.latency.snapshot(),
+            shaped: self.shaped.snapshot(),
+            talkers: self.talkers.top(),
+        }
+    }
+}
+
+/// Counters of one opened interface
+#[derive(Debug)]
+pub struct InterfaceStats {
+    pub name: String,
+    pub reconnects: AtomicU64,
+    /// Capture loops restarted after a panic
+    pub restarts: AtomicU64,
+    /// Frames the kernel dropped before they were read
+    pub kernel: KernelDrops,
+    /// Milliseconds since the Unix epoch at the last received frame, 0 if none
+    last_frame: AtomicU64,
+}
+
+impl InterfaceStats {
+    pub fn new(name: String) -> Self {
+        InterfaceStats {
+            name,
+            reconnects: AtomicU64::new(0),
+            restarts: AtomicU64::new(0),
+            kernel: KernelDrops::default(),
+            last_frame: AtomicU64::new(0),
+        }
+    }
+
+    pub fn frame_received(&self) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_millis() as u64);
+        self.last_frame.store(now, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> InterfaceSnapshot {
+        InterfaceSnapshot {
+            name: self.name.clone(),
+            reconnects: self.reconnects.load(Ordering::Relaxed),
+            restarts: self.restarts.load(Ordering::Relaxed),
+            kernel_dropped: self.kernel.dropped(),
+            last_frame: self
+                .last_frame()
+                .map(|at| humantime::format_rfc3339_millis(at).to_string()),
+        }
+    }
+
+    pub fn last_frame(&self) -> Option<SystemTime> {
+        match self.last_frame.load(Ordering::Relaxed) {
+            0 => None,
+            millis => Some(UNIX_EPOCH + Duration::from_millis(millis)),
+        }
+    }
+}
+
+/// Counters of the copies sent to the mirror interface
+#[derive(Debug)]
+pub struct MirrorStats {
+    pub iface: String,
+    sent: AtomicU64,
+    queue_full: AtomicU64,
+    send_error: AtomicU64,
+}
+
+impl MirrorStats {
+    pub fn new(iface: String) -> Self {
+        MirrorStats {
+            iface,
+            sent: AtomicU64::new(0),
+            queue_full: AtomicU64::new(0),
+            send_error: AtomicU64::new(0),
+        }
+    }
+
+    pub fn sent(&self) {
+        self.sent.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn queue_full(&self) {
+        self.queue_full.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn send_error(&self) {
+        self.send_error.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn reset(&self) {
+        for counter in [&self.sent, &self.queue_full, &self.send_error] {
+            counter.store(0, Ordering::Relaxed);
+        }
+    }
+
+    pub fn snapshot(&self) -> MirrorSnapshot {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        MirrorSnapshot {
+            iface: self.iface.clone(),
+            sent: load(&self.sent),
+            queue_full: load(&self.queue_full),
+            send_error: load(&self.send_error),
+        }
+    }
+}
+
+impl fmt::Display for MirrorStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        write!(
+            f,
+            "Mirror to {}: sent {}, dropped queue-full={} send-error={}",
+            self.iface,
+            load(&self.sent),
+            load(&self.queue_full),
+            load(&self.send_error)
+        )
+    }
+}
+
+/// Counters of the buffer pool
+#[derive(Debug)]
+pub struct PoolStats {
+    size: usize,
+    buffer_len: usize,
+    in_use: AtomicUsize,
+    high_water: AtomicUsize,
+    /// Buffers allocated because the pool was exhausted
+    allocated: AtomicU64,
+}
+
+impl PoolStats {
+    pub fn new(size: usize, buffer_len: usize) -> Self {
+        PoolStats {
+            size,
+            buffer_len,
+            in_use: AtomicUsize::new(0),
+            high_water: AtomicUsize::new(0),
+            allocated: AtomicU64::new(0),
+        }
+    }
+
+    /// Counts a buffer handed out. Returns `false` if the pool is exhausted
+    /// and the buffer has to be allocated.
+    pub fn take(&self) -> bool {
+        let in_use = self.in_use.fetch_add(1, Ordering::Relaxed) + 1;
+        self.high_water.fetch_max(in_use, Ordering::Relaxed);
+        if in_use > self.size {
+            self.allocated.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+        true
+    }
+
+    pub fn give_back(&self) {
+        self.in_use.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn in_use(&self) -> usize {
+        self.in_use.load(Ordering::Relaxed)
+    }
+
+    pub fn high_water(&self) -> usize {
+        self.high_water.load(Ordering::Relaxed)
+    }
+
+    pub fn allocated(&self) -> u64 {
+        self.allocated.load(Ordering::Relaxed)
+    }
+
+    /// Clears the allocation count and restarts the high-water mark from the
+    /// buffers in use now
+    pub fn reset(&self) {
+        self.allocated.store(0, Ordering::Relaxed);
+        self.high_water.store(self.in_use(), Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> PoolSnapshot {
+        PoolSnapshot {
+            size: self.size,
+            buffer_len: self.buffer_len,
+            in_use: self.in_use(),
+            high_water: self.high_water(),
+            allocated: self.allocated(),
+        }
+    }
+}
+
+impl fmt::Display for PoolStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Buffer pool: {} of {} buffers of {} bytes in use, high-water {}, allocated beyond the pool {}",
+            self.in_use(),
+            self.size,
+            self.buffer_len,
+            self.high_water(),
+            self.allocated()
+        )
+    }
+}
+
+/// All counters of the forwarder
+#[derive(Debug)]
+pub struct Stats {
+    pub started: Instant,
+    pub paths: Vec<Arc<PathStats>>,
+    pub interfaces: Vec<Arc<InterfaceStats>>,
+    pub mirror: Option<Arc<MirrorStats>>,
+    pub pool: Option<Arc<PoolStats>>,
+    pub pause: Arc<Pause>,
+    pub quotas: Arc<Quotas>,
+    /// Role among redundant instances, if any
+    pub election: Option<Arc<Election>>,
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Stats {
+            started: Instant::now(),
+            paths: Vec::new(),
+            interfaces: Vec::new(),
+            mirror: None,
+            pool: None,
+            pause: Arc::default(),
+            quotas: Arc::default(),
+            election: None,
+        }
+    }
+}
+
+impl Stats {
+    /// Logs whether forwarding is paused and the role among redundant
+    /// instances, one line per path followed by its top talkers, the
+    /// interface reconnect, capture restart and kernel drop counts, the
+    /// mirror and buffer pool counters and the use of the quotas
+    pub fn log(&self) {
+        if self.pause.is_paused() {
+            info!("Forwarding paused");
+        }
+        if let Some(election) = &self.election {
+            let role = election.snapshot();
+            info!("Role: {} at epoch {}", role.role, role.epoch);
+        }
+        for path in &self.paths {
+            let path = path.snapshot();
+            info!("{}", path);
+            if !path.talkers.is_empty() {
+                info!("Top talkers {} -> {}:", path.ingress, path.egress);
+            }
+            for talker in &path.talkers {
+                info!("  {}", talker);
+            }
+        }
+        let reconnects: Vec<String> = self
+            .interfaces
+            .iter()
+            .map(|iface| {
+                format!(
+                    "{}={}",
+                    iface.name,
+                    iface.reconnects.load(Ordering::Relaxed)
+                )
+            })
+            .collect();
+        info!("Interface reconnects: {}", reconnects.join(" "));
+        let restarts: Vec<String> = self
+            .interfaces
+            .iter()
+            .map(|iface| format!("{}={}", iface.name, iface.restarts.load(Ordering::Relaxed)))
+            .collect();
+        info!("Capture restarts: {}", restarts.join(" "));
+        let drops: Vec<String> = self
+            .interfaces
+            .iter()
+            .map(|iface| format!("{}={}", iface.name, iface.kernel.dropped()))
+            .collect();
+        info!("Kernel drops: {}", drops.join(" "));
+        if let Some(mirror) = &self.mirror {
+            info!("{}", mirror);
+        }
+        if let Some(pool) = &self.pool {
+            info!("{}", pool);
+        }
+        for quota in self.quotas.snapshot() {
+            info!("{}", quota);
+        }
+    }
+
+    /// Frames forwarded and dropped on all paths, in one line that starts
+    /// with whether forwarding is paused and the role among redundant
+    /// instances
+    pub fn status(&self) -> String {
+        let (forwarded, dropped) = self
+            .paths
+            .iter()
+            .fold((0, 0), |(forwarded, dropped), path| {
+                let path = path.snapshot();
+                (forwarded + path.forwarded, dropped + path.dropped())
+            });
+        let mut state = if self.pause.is_paused() {
+            "Paused"
+        } else if self.pause.is_standby() {
+            "Standing by"
+        } else {
+            "Forwarding"
+        }
+        .to_string();
+        if let Some(election) = &self.election {
+            if election.is_leader() {
+                state.push_str(" as leader");
+            }
+            state = format!("{} at epoch {}", state, election.epoch());
+        }
+        format!(
+            "{}, forwarded {} frames, dropped {}",
+            state, forwarded, dropped
+        )
+    }
+
+    /// Logs the uptime, the counters and when each interface last received
+    /// a frame
+    pub fn dump(&self) {
+        let uptime = Duration::from_secs(self.started.elapsed().as_secs());
+        info!("Uptime {}", humantime::format_duration(uptime));
+        self.log();
+        for iface in &self.interfaces {
+            match iface.last_frame() {
+                Some(at) => info!(
+                    "Last frame on {}: {}",
+                    iface.name,
+                    humantime::format_rfc3339_millis(at)
+                ),
+                None => info!("Last frame on {}: never", iface.name),
+            }
+        }
+    }
+
+    /// Copy of all counters
+    pub fn snapshot(&self) -> StatsSnapshot {
+        StatsSnapshot {
+            uptime_secs: self.started.elapsed().as_secs(),
+            paused: self.pause.is_paused(),
+            paths: self.paths.iter().map(|path| path.snapshot()).collect(),
+            interfaces: self
+                .interfaces
+                .iter()
+                .map(|iface| iface.snapshot())
+                .collect(),
+            mirror: self.mirror.as_ref().map(|mirror| mirror.snapshot()),
+            pool: self.pool.as_ref().map(|pool| pool.snapshot()),
+            quotas: self.quotas.snapshot(),
+            role: self.election.as_ref().map(|election| election.snapshot()),
+        }
+    }
+
+    /// Sets the counters of all paths and interfaces back to zero
+    pub fn reset(&self) {
+        for path in &self.paths {
+            path.reset();
+        }
+        for iface in &self.interfaces {
+            iface.reconnects.store(0, Ordering::Relaxed);
+            iface.restarts.store(0, Ordering::Relaxed);
+            iface.kernel.reset();
+        }
+        if let Some(mirror) = &self.mirror {
+            mirror.reset();
+        }
+        if let Some(pool) = &self.pool {
+            pool.reset();
+        }
+    }
+}
+
+/// Point-in-time copy of [`Stats`]
+#[derive(Debug, Clone, Serialize)]
+pub struct StatsSnapshot {
+    pub uptime_secs: u64,
+    pub paused: bool,
+    pub paths: Vec<PathSnapshot>,
+    pub interfaces: Vec<InterfaceSnapshot>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mirror: Option<MirrorSnapshot>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pool: Option<PoolSnapshot>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub quotas: Vec<QuotaSnapshot>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub role: Option<RoleSnapshot>,
+}
+
+/// Point-in-time copy of [`InterfaceStats`]
+#[derive(Debug, Clone, Serialize)]
+pub struct InterfaceSnapshot {
+    pub name: String,
+    pub reconnects: u64,
+    pub restarts: u64,
+    pub kernel_dropped: u64,
+    /// RFC 3339 time of the last received frame
+    pub last_frame: Option<String>,
+}
+
+/// Point-in-time copy of [`MirrorStats`]
+#[derive(Debug, Clone, Serialize)]
+pub struct MirrorSnapshot {
+    pub iface: String,
+    pub sent: u64,
+    pub queue_full: u64,
+    pub send_error: u64,
+}
+
+/// Point-in-time copy of [`PoolStats`]
+#[derive(Debug, Clone, Serialize)]
+pub struct PoolSnapshot {
+    pub size: usize,
+    pub buffer_len: usize,
+    pub in_use: usize,
+    pub high_water: usize,
+    pub allocated: u64,
+}
+
+/// Point-in-time copy of [`PathStats`]
+#[derive(Debug, Clone, Serialize)]
+pub struct PathSnapshot {
+    pub pair: String,
+    pub direction: Direction,
+    pub ingress: String,
+    pub egress: String,
+    pub received: u64,
+    pub received_bytes: u64,
+    pub queued: u64,
+    pub retried: u64,
+    pub fragmented: u64,
+    pub offloaded: u64,
+    pub wol_forwarded: u64,
+    pub mdns_merged: u64,
+    pub eapol_passed: u64,
+    pub lldp_passed: u64,
+    pub forwarded: u64,
+    pub forwarded_bytes: u64,
+    pub source_not_allowed: u64,
+    pub other_vlan: u64,
+    pub reserved: u64,
+    pub non_ipv4: u64,
+    pub unmatched_protocol: u64,
+    pub fragments: u64,
+    pub port_mismatch: u64,
+    pub filtered: u64,
+    pub llmnr: u64,
+    pub netbios_ns: u64,
+    pub wol: u64,
+    pub bad_checksum: u64,
+    pub rewrite_failed: u64,
+    pub expired: u64,
+    pub off_link: u64,
+    pub looped: u64,
+    pub rate_limited: u64,
+    pub quota_exceeded: u64,
+    pub storm: u64,
+    pub cached: u64,
+    pub oversize: u64,
+    pub queue_full: u64,
+    pub shaper: u64,
+    pub send_error: u64,
+    pub paused: u64,
+    pub standby: u64,
+    pub latency: LatencySnapshot,
+    /// Delays added by the shaper
+    pub shaped: LatencySnapshot,
+    pub talkers: Vec<Talker>,
+}
+
+impl PathSnapshot {
+    /// Frames dropped for any reason
+    pub fn dropped(&self) -> u64 {
+        self.drops().iter().map(|(_, frames)| frames).sum()
+    }
+
+    /// Dropped frames by reason, named as in the log line
+    pub fn drops(&self) -> [(&'static str, u64); 26] {
+        [
+            ("source", self.source_not_allowed),
+            ("vlan", self.other_vlan),
+            ("reserved", self.reserved),
+            ("non-ipv4", self.non_ipv4),
+            ("non-udp/tcp", self.unmatched_protocol),
+            ("fragment", self.fragments),
+            ("port", self.port_mismatch),
+            ("filter", self.filtered),
+            ("llmnr", self.llmnr),
+            ("netbios-ns", self.netbios_ns),
+            ("wol", self.wol),
+            ("checksum", self.bad_checksum),
+            ("rewrite", self.rewrite_failed),
+            ("expired", self.expired),
+            ("off-link", self.off_link),
+            ("loop", self.looped),
+            ("ratelimit", self.rate_limited),
+            ("quota", self.quota_exceeded),
+            ("storm", self.storm),
+            ("cached", self.cached),
+            ("oversize", self.oversize),
+            ("queue-full", self.queue_full),
+            ("shaper", self.shaper),
+            ("send-error", self.send_error),
+            ("paused", self.paused),
+            ("standby", self.standby),
+        ]
+    }
+}
+
+impl fmt::Display for PathSnapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} -> {}: received {} ({} bytes), queued {}, forwarded {} ({} bytes), retries {}, fragmented {}, offloaded {}, wake-on-lan {}, mdns-merged {}, eapol {}, lldp {}, \
+             dropped source={} vlan={} reserved={} non-ipv4={} non-udp/tcp={} fragment={} port={} filter={} llmnr={} netbios-ns={} wol={} checksum={} rewrite={} expired={} off-link={} loop={} ratelimit={} quota={} storm={} cached={} oversize={} queue-full={} shaper={} send-error={} paused={} standby={}, {}",
+            self.pair,
+            self.ingress,
+            self.egress,
+            self.received,
+            self.received_bytes,
+            self.queued,
+            self.forwarded,
+            self.forwarded_bytes,
+            self.retried,
+            self.fragmented,
+            self.offloaded,
+            self.wol_forwarded,
+            self.mdns_merged,
+            self.eapol_passed,
+            self.lldp_passed,
+            self.source_not_allowed,
+            self.other_vlan,
+            self.reserved,
+            self.non_ipv4,
+            self.unmatched_protocol,
+            self.fragments,
+            self.port_mismatch,
+            self.filtered,
+            self.llmnr,
+            self.netbios_ns,
+            self.wol,
+            self.bad_checksum,
+            self.rewrite_failed,
+            self.expired,
+            self.off_link,
+            self.looped,
+            self.rate_limited,
+            self.quota_exceeded,
+            self.storm,
+            self.cached,
+            self.oversize,
+            self.queue_full,
+            self.shaper,
+            self.send_error,
+            self.paused,
+            self.standby,
+            self.latency
+        )?;
+        if self.shaped.count > 0 {
+            write!(
+                f,
+                ", shaped {} delay p50={}µs p99={}µs max={}µs",
+                self.shaped.count, self.shaped.p50_us, self.shaped.p99_us, self.shaped.max_us
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_each_direction_by_drop_reason() {
+        let outbound = PathStats::new(
+            "eth0<->vm1".to_string(),
+            Direction::Outbound,
+            "vm1".to_string(),
+            "eth0".to_string(),
+        );
+        let inbound = PathStats::new(
+            "eth0<->vm1".to_string(),
+            Direction::Inbound,
+            "eth0".to_string(),
+            "vm1".to_string(),
+        );
+        for _ in 0..3 {
+            outbound.received(100);
+        }
+        outbound.forwarded(100);
+        outbound.dropped(DropReason::Filter("udp-ports"));
+        outbound.dropped(DropReason::Filter("expression"));
+        inbound.received(60);
+        inbound.dropped(DropReason::QueueFull);
+
+        let path = outbound.snapshot();
+        assert_eq!((path.received, path.received_bytes), (3, 300));
+        assert_eq!(
+            (path.port_mismatch, path.filtered, path.queue_full),
+            (1, 1, 0)
+        );
+        assert_eq!(path.dropped(), 2);
+        let line = path.to_string();
+        assert!(line.starts_with("eth0<->vm1 vm1 -> eth0: received 3 (300 bytes)"));
+        assert!(line.contains(" port=1 filter=1 "));
+        assert_eq!(inbound.snapshot().queue_full, 1);
+    }
+
+    #[test]
+    fn resets_counters_but_remembers_the_last_frame() {
+        let path = Arc::new(PathStats::new(
+            "eth0<->vm1".to_string(),
+            Direction::Inbound,
+            "eth0".to_string(),
+            "vm1".to_string(),
+        ));
+        let iface = Arc::new(InterfaceStats::new("eth0".to_string()));
+        path.received(100);
+        path.forwarded(100);
+        path.dropped(DropReason::Loop);
+        iface.reconnects.fetch_add(1, Ordering::Relaxed);
+        iface.frame_received();
+        let stats = Stats {
+            paths: vec![path.clone()],
+            interfaces: vec![iface.clone()],
+            ..Stats::default()
+        };
+
+        stats.reset();
+        let snapshot = path.snapshot();
+        assert_eq!(snapshot.dropped(), 0);
+        assert_eq!((snapshot.received, snapshot.forwarded_bytes), (0, 0));
+        assert_eq!(iface.snapshot().reconnects, 0);
+        assert!(iface.last_frame().is_some());
+    }
+}
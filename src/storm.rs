@@ -0,0 +1,124 @@
+//! Guards against frames a repeater between two segments must not pass on:
+//! those to the group addresses reserved for bridges, such as spanning tree
+//! BPDUs, and broadcast and multicast storms.
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+/// First three bytes of the group addresses IEEE 802.1 reserves, among
+/// them 01:80:C2:00:00:00 of spanning tree BPDUs, pause frames and LLDP
+const RESERVED_PREFIX: [u8; 3] = [0x01, 0x80, 0xc2];
+/// Period broadcast and multicast frames are counted over
+const WINDOW: Duration = Duration::from_secs(1);
+
+/// Whether `frame` is addressed to a group address reserved by IEEE 802.1,
+/// which a switch on the other side would take for traffic of its own
+pub fn reserved(frame: &[u8]) -> bool {
+    frame.starts_with(&RESERVED_PREFIX)
+}
+
+#[derive(Debug)]
+struct Window {
+    start: Instant,
+    frames: u32,
+    /// When forwarding was suspended, if it is
+    suspended: Option<Instant>,
+}
+
+/// Storm control of a path. Broadcast and multicast frames are counted
+/// per second; once more than the threshold arrive within one, all of them
+/// are dropped until a second passes with no more than the threshold.
+#[derive(Debug)]
+pub struct StormControl {
+    ingress: String,
+    threshold: u32,
+    window: Mutex<Window>,
+}
+
+impl StormControl {
+    pub fn new(ingress: String, threshold: u32) -> Self {
+        StormControl {
+            ingress,
+            threshold,
+            window: Mutex::new(Window {
+                start: Instant::now(),
+                frames: 0,
+                suspended: None,
+            }),
+        }
+    }
+
+    /// Whether `frame`, received at `at`, is dropped as part of a storm.
+    /// Unicast frames never are.
+    pub fn suppressed(&self, frame: &[u8], at: Instant) -> bool {
+        if frame.first().is_none_or(|byte| byte & 1 == 0) {
+            return false;
+        }
+        let mut window = self.window.lock().unwrap();
+        let elapsed = at.saturating_duration_since(window.start);
+        if elapsed >= WINDOW {
+            // A gap of a whole window means the last second was quiet
+            let calm = window.frames <= self.threshold || elapsed >= 2 * WINDOW;
+            if let (true, Some(since)) = (calm, window.suspended) {
+                window.suspended = None;
+                info!(
+                    "Broadcast and multicast from {} forwarded again after a storm of {}",
+                    self.ingress,
+                    humantime::format_duration(Duration::from_secs(
+                        at.saturating_duration_since(since).as_secs()
+                    ))
+                );
+            }
+            window.start = at;
+            window.frames = 0;
+        }
+        window.frames += 1;
+        if window.suspended.is_none() && window.frames > self.threshold {
+            window.suspended = Some(at);
+            warn!(
+                "Broadcast and multicast storm on {}, more than {} frames per second; \
+                 not forwarding them until it calms down",
+                self.ingress, self.threshold
+            );
+        }
+        window.suspended.is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn suspends_and_resumes_on_storms() {
+        assert!(reserved(&[0x01, 0x80, 0xc2, 0, 0, 0, 0xaa]));
+        assert!(reserved(&[0x01, 0x80, 0xc2, 0, 0, 0x0e]));
+        assert!(!reserved(&[0x01, 0x00, 0x5e, 0, 0, 0xfb]));
+
+        let storm = StormControl::new("eth0".to_string(), 10);
+        let start = Instant::now();
+        let broadcast = [0xff; 14];
+        let unicast = [0x02, 0, 0, 0, 0, 1];
+        let second = |n: u32| start + WINDOW * n;
+        for _ in 0..10 {
+            assert!(!storm.suppressed(&broadcast, start));
+        }
+        assert!(storm.suppressed(&broadcast, start));
+        assert!(!storm.suppressed(&unicast, start));
+        // Still storming in the next second
+        for _ in 0..20 {
+            assert!(storm.suppressed(&broadcast, second(1)));
+        }
+        // The storm of the last second keeps it suspended
+        assert!(storm.suppressed(&broadcast, second(2)));
+        assert!(!storm.suppressed(&broadcast, second(3)));
+
+        for _ in 0..11 {
+            storm.suppressed(&broadcast, second(4));
+        }
+        assert!(storm.suppressed(&broadcast, second(4)));
+        // After a quiet second
+        assert!(!storm.suppressed(&broadcast, second(6)));
+    }
+}
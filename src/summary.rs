@@ -0,0 +1,188 @@
+//! Per-packet summary logged for every forwarding decision.
+
+use crate::filter::{IpHeader, PacketContext, Transport};
+use crate::pair::Direction;
+use pnet::packet::Packet;
+use serde::Serialize;
+use std::fmt;
+use std::net::IpAddr;
+
+/// What happened to one frame on one forwarding path
+#[derive(Debug, Serialize)]
+pub struct PacketSummary<'a> {
+    pub interface: &'a str,
+    pub egress: &'a str,
+    pub pair: &'a str,
+    pub direction: Direction,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub vlan: Option<u16>,
+    pub protocol: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub src_ip: Option<IpAddr>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dst_ip: Option<IpAddr>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub src_port: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dst_port: Option<u16>,
+    pub length: usize,
+    pub decision: &'static str,
+    /// Filter, rewrite stage or queue condition that dropped the frame
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<&'a str>,
+}
+
+impl<'a> PacketSummary<'a> {
+    pub fn new(
+        ctx: &PacketContext<'a>,
+        egress: &'a str,
+        pair: &'a str,
+        direction: Direction,
+        result: Result<(), &'a str>,
+    ) -> Self {
+        let (protocol, src_port, dst_port) = match &ctx.transport {
+            Some(Transport::Udp(udp)) => {
+                ("udp", Some(udp.get_source()), Some(udp.get_destination()))
+            }
+            Some(Transport::Tcp(tcp)) => {
+                ("tcp", Some(tcp.get_source()), Some(tcp.get_destination()))
+            }
+            Some(Transport::Icmpv6(_)) => ("icmpv6", None, None),
+            None => match ctx.ip {
+                Some(IpHeader::V4(_)) => ("ipv4", None, None),
+                Some(IpHeader::V6(_)) => ("ipv6", None, None),
+                None => ("other", None, None),
+            },
+        };
+        PacketSummary {
+            interface: ctx.ingress,
+            egress,
+            pair,
+            direction,
+            vlan: ctx.vlan,
+            protocol,
+            src_ip: ctx.source_ip(),
+            dst_ip: ctx.destination_ip(),
+            src_port,
+            dst_port,
+            length: ctx.ethernet.packet().len(),
+            decision: if result.is_ok() {
+                "forwarded"
+            } else {
+                "dropped"
+            },
+            reason: result.err(),
+        }
+    }
+}
+
+/// Journal fields of the attributes whose name in upper case is not the
+/// field name, e.g. `IFACE=eth0` for the interface
+pub const JOURNAL_FIELDS: [(&str, &str); 3] = [
+    ("interface", "IFACE"),
+    ("protocol", "PROTO"),
+    ("reason", "DROP_REASON"),
+];
+
+/// Emits a [`PacketSummary`] as an event at `level` with one field per
+/// attribute, followed by the message
+macro_rules! packet_event {
+    ($level:expr, $summary:expr, $($message:tt)+) => {{
+        let summary = &$summary;
+        tracing::event!(
+            $level,
+            interface = summary.interface,
+            egress = summary.egress,
+            pair = summary.pair,
+            direction = summary.direction.name(),
+            vlan = summary.vlan,
+            protocol = summary.protocol,
+            src_ip = summary.src_ip.map(tracing::field::display),
+            dst_ip = summary.dst_ip.map(tracing::field::display),
+            src_port = summary.src_port,
+            dst_port = summary.dst_port,
+            length = summary.length,
+            decision = summary.decision,
+            reason = summary.reason,
+            $($message)+
+        )
+    }};
+}
+pub(crate) use packet_event;
+
+impl fmt::Display for PacketSummary<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} -> {}", self.interface, self.egress)?;
+        if let Some(vlan) = self.vlan {
+            write!(f, " vlan {}", vlan)?;
+        }
+        write!(f, " {}", self.protocol)?;
+        if let (Some(src), Some(dst)) = (self.src_ip, self.dst_ip) {
+            match (self.src_port, self.dst_port) {
+                (Some(sport), Some(dport)) => write!(f, " {}:{} > {}:{}", src, sport, dst, dport)?,
+                _ => write!(f, " {} > {}", src, dst)?,
+            }
+        }
+        write!(f, " len {}: {}", self.length, self.decision)?;
+        if let Some(reason) = self.reason {
+            write!(f, " by {}", reason)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{ssdp_search_frame, ETHERNET_HEADER_LEN};
+    use serde_json::json;
+
+    #[test]
+    fn summarizes_the_decision_on_a_frame() {
+        let frame = ssdp_search_frame("ssdp:all");
+        let ctx = PacketContext::parse("vm1", Direction::Outbound, &frame).unwrap();
+        let summary = PacketSummary::new(&ctx, "eth0", "eth0<->vm1", Direction::Outbound, Ok(()));
+        assert_eq!(
+            serde_json::to_value(&summary).unwrap(),
+            json!({
+                "interface": "vm1",
+                "egress": "eth0",
+                "pair": "eth0<->vm1",
+                "direction": "internal->external",
+                "protocol": "udp",
+                "src_ip": "192.168.100.5",
+                "dst_ip": "239.255.255.250",
+                "src_port": 50000,
+                "dst_port": 1900,
+                "length": frame.len(),
+                "decision": "forwarded",
+            })
+        );
+
+        // The capture loop untags frames and notes the VLAN they came on
+        let mut ctx = PacketContext::parse("vm1", Direction::Outbound, &frame).unwrap();
+        ctx.vlan = Some(10);
+        let dropped = Err("loop");
+        let summary = PacketSummary::new(&ctx, "eth0", "eth0<->vm1", Direction::Outbound, dropped);
+        assert_eq!(
+            summary.to_string(),
+            format!(
+                "vm1 -> eth0 vlan 10 udp 192.168.100.5:50000 > 239.255.255.250:1900 len {}: \
+                 dropped by loop",
+                frame.len()
+            )
+        );
+        let fields = serde_json::to_value(&summary).unwrap();
+        assert_eq!(
+            (&fields["vlan"], &fields["reason"]),
+            (&json!(10), &json!("loop"))
+        );
+
+        let mut arp = frame.clone();
+        arp[12..14].copy_from_slice(&[0x08, 0x06]);
+        arp.truncate(ETHERNET_HEADER_LEN + 28);
+        let ctx = PacketContext::parse("vm1", Direction::Outbound, &arp).unwrap();
+        let summary = PacketSummary::new(&ctx, "eth0", "eth0<->vm1", Direction::Outbound, Ok(()));
+        assert_eq!(summary.to_string(), "vm1 -> eth0 other len 42: forwarded");
+    }
+}
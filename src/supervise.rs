@@ -0,0 +1,99 @@
+//! Supervision of the capture loops. A loop that panics would otherwise
+//! leave its direction silently without forwarding, so it either stops the
+//! forwarder, which exits with an error for the service manager to restart
+//! it, or is restarted in place a limited number of times.
+
+use crate::capture::sleep_unless_cancelled;
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+use tracing::{error, warn};
+
+const RESTART_BACKOFF_MIN: Duration = Duration::from_millis(100);
+const RESTART_BACKOFF_MAX: Duration = Duration::from_secs(5);
+
+/// What happens when a capture loop panics
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OnTaskFailure {
+    /// Stop forwarding and exit with an error
+    Exit,
+    /// Restart the loop after a backoff, exiting once the restarts run out
+    Restart,
+}
+
+/// How a capture loop is supervised
+#[derive(Debug, Clone, Copy)]
+pub struct Supervision {
+    pub policy: OnTaskFailure,
+    /// Restarts of one loop before a panic is passed on anyway
+    pub max_restarts: u32,
+}
+
+impl Default for Supervision {
+    fn default() -> Self {
+        Supervision {
+            policy: OnTaskFailure::Exit,
+            max_restarts: 5,
+        }
+    }
+}
+
+impl Supervision {
+    /// Runs `work` until it returns. A panic is passed on or, under the
+    /// restart policy, `work` is run again after a growing backoff, at most
+    /// `max_restarts` times, each counted in `restarts`. Returns early if
+    /// `token` is cancelled during the backoff.
+    pub fn run(
+        &self,
+        name: &str,
+        restarts: &AtomicU64,
+        token: &CancellationToken,
+        mut work: impl FnMut(),
+    ) {
+        let mut delay = RESTART_BACKOFF_MIN;
+        for restart in 1.. {
+            let Err(panic) = panic::catch_unwind(AssertUnwindSafe(&mut work)) else {
+                return;
+            };
+            if self.policy == OnTaskFailure::Exit || restart > self.max_restarts {
+                panic::resume_unwind(panic);
+            }
+            restarts.fetch_add(1, Ordering::Relaxed);
+            warn!(
+                "{} panicked, restarting in {:?} (restart {} of {})",
+                name, delay, restart, self.max_restarts
+            );
+            if !sleep_unless_cancelled(delay, token) {
+                return;
+            }
+            delay = (delay * 2).min(RESTART_BACKOFF_MAX);
+        }
+    }
+}
+
+/// Waits for `task`, which is meant to run until `token` is cancelled, and
+/// reports its name on `failures` if it ends earlier, panicked or not
+pub fn watch(
+    name: String,
+    task: JoinHandle<()>,
+    token: CancellationToken,
+    failures: UnboundedSender<String>,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let result = task.await;
+        if token.is_cancelled() {
+            return;
+        }
+        match result {
+            Err(e) if e.is_panic() => error!("{} panicked", name),
+            _ => error!("{} stopped unexpectedly", name),
+        }
+        let _ = failures.send(name);
+    })
+}
@@ -0,0 +1,96 @@
+//! Notifications to systemd for services with `Type=notify` and
+//! `WatchdogSec=`: readiness, stopping, the watchdog and a status line.
+//! Without `NOTIFY_SOCKET` in the environment nothing is sent.
+
+use std::io;
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::time::Duration;
+use tracing::{debug, warn};
+
+/// Connection to the notification socket of the service manager
+pub struct Notifier {
+    socket: UnixDatagram,
+    address: SocketAddr,
+    watchdog: Option<Duration>,
+}
+
+impl Notifier {
+    /// Notifier for the socket systemd passed in `NOTIFY_SOCKET`, or `None`
+    /// if the process was not started by systemd
+    pub fn from_env() -> Option<Self> {
+        let path = std::env::var("NOTIFY_SOCKET").ok()?;
+        let watchdog = watchdog_interval(
+            std::env::var("WATCHDOG_USEC").ok().as_deref(),
+            std::env::var("WATCHDOG_PID").ok().as_deref(),
+        );
+        match Notifier::connect(&path, watchdog) {
+            Ok(notifier) => Some(notifier),
+            Err(e) => {
+                warn!("Not notifying systemd, socket {}: {}", path, e);
+                None
+            }
+        }
+    }
+
+    fn connect(path: &str, watchdog: Option<Duration>) -> io::Result<Self> {
+        let address = match path.strip_prefix('@') {
+            Some(name) => SocketAddr::from_abstract_name(name)?,
+            None => SocketAddr::from_pathname(path)?,
+        };
+        Ok(Notifier {
+            socket: UnixDatagram::unbound()?,
+            address,
+            watchdog,
+        })
+    }
+
+    /// How often the watchdog has to be pinged, if it is enabled for this
+    /// process
+    pub fn watchdog(&self) -> Option<Duration> {
+        self.watchdog
+    }
+
+    /// Sends newline-separated `VARIABLE=value` assignments
+    pub fn notify(&self, state: &str) {
+        if let Err(e) = self.socket.send_to_addr(state.as_bytes(), &self.address) {
+            debug!("Failed to notify systemd: {}", e);
+        }
+    }
+}
+
+/// Half the watchdog timeout, as systemd recommends, when the watchdog is
+/// enabled and meant for this process
+fn watchdog_interval(usec: Option<&str>, pid: Option<&str>) -> Option<Duration> {
+    if pid.is_some_and(|pid| pid.parse() != Ok(std::process::id())) {
+        return None;
+    }
+    let usec: u64 = usec?.parse().ok().filter(|usec| *usec > 0)?;
+    Some(Duration::from_micros(usec) / 2)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn notifies_the_socket_and_halves_the_watchdog() {
+        let path = std::env::temp_dir().join(format!("nw-pckt-fwd-notify-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let manager = UnixDatagram::bind(&path).unwrap();
+        let notifier = Notifier::connect(path.to_str().unwrap(), None).unwrap();
+        notifier.notify("READY=1\nSTATUS=Forwarding");
+        let mut buf = [0; 64];
+        let len = manager.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"READY=1\nSTATUS=Forwarding");
+        std::fs::remove_file(&path).unwrap();
+
+        let own = std::process::id().to_string();
+        let interval = watchdog_interval(Some("30000000"), Some(&own));
+        assert_eq!(interval, Some(Duration::from_secs(15)));
+        assert_eq!(watchdog_interval(Some("30000000"), None), interval);
+        assert_eq!(watchdog_interval(Some("30000000"), Some("1")), None);
+        assert_eq!(watchdog_interval(Some("0"), None), None);
+        assert_eq!(watchdog_interval(None, None), None);
+    }
+}
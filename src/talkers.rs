@@ -0,0 +1,244 @@
+//! Top talkers of a path: the sources sending it the most frames, with a
+//! breakdown by protocol. The sources are kept in a space-saving sketch, a
+//! fixed set of counters where an unknown source takes over the counter of
+//! the quietest one, so that flooding senders stand out without a counter
+//! per source or an allocation per frame.
+
+use crate::filter::{PacketContext, MDNS_PORT, SSDP_PORT};
+use serde::Serialize;
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::Mutex;
+
+/// Sources reported
+pub const TOP_TALKERS: usize = 10;
+/// Sources counted per path, more than are reported so that the reported
+/// ones rarely lose their counters to short bursts of others
+const TRACKED: usize = 64;
+
+/// What a frame carries, as far as the talkers are broken down
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Protocol {
+    Ssdp,
+    Mdns,
+    OtherUdp,
+    Tcp,
+    Other,
+}
+
+const PROTOCOLS: [Protocol; 5] = [
+    Protocol::Ssdp,
+    Protocol::Mdns,
+    Protocol::OtherUdp,
+    Protocol::Tcp,
+    Protocol::Other,
+];
+
+impl Protocol {
+    pub fn of(ctx: &PacketContext) -> Self {
+        if let Some(udp) = ctx.udp() {
+            let ports = [udp.get_source(), udp.get_destination()];
+            if ports.contains(&SSDP_PORT) {
+                Protocol::Ssdp
+            } else if ports.contains(&MDNS_PORT) {
+                Protocol::Mdns
+            } else {
+                Protocol::OtherUdp
+            }
+        } else if ctx.tcp().is_some() {
+            Protocol::Tcp
+        } else {
+            Protocol::Other
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Protocol::Ssdp => "ssdp",
+            Protocol::Mdns => "mdns",
+            Protocol::OtherUdp => "other-udp",
+            Protocol::Tcp => "tcp",
+            Protocol::Other => "other",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Traffic {
+    frames: u64,
+    bytes: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Counter {
+    source: IpAddr,
+    /// Frames counted, including the `inherited` ones
+    frames: u64,
+    /// Frames of the source whose counter this one took over
+    inherited: u64,
+    /// Frames and bytes of the source itself, by protocol
+    protocols: [Traffic; PROTOCOLS.len()],
+}
+
+/// Space-saving sketch of the sources of one path. Only the capture thread
+/// of the path records into it, so its lock is only contended by readers.
+#[derive(Debug)]
+pub struct TopTalkers {
+    counters: Mutex<Vec<Counter>>,
+}
+
+impl Default for TopTalkers {
+    fn default() -> Self {
+        TopTalkers {
+            counters: Mutex::new(Vec::with_capacity(TRACKED)),
+        }
+    }
+}
+
+impl TopTalkers {
+    /// Counts a frame of `len` bytes from `source`
+    pub fn record(&self, source: IpAddr, protocol: Protocol, len: usize) {
+        let mut counters = self.counters.lock().unwrap();
+        let index = match counters.iter().position(|counter| counter.source == source) {
+            Some(index) => index,
+            None if counters.len() < TRACKED => {
+                counters.push(Counter {
+                    source,
+                    frames: 0,
+                    inherited: 0,
+                    protocols: Default::default(),
+                });
+                counters.len() - 1
+            }
+            None => {
+                let (index, quietest) = counters
+                    .iter_mut()
+                    .enumerate()
+                    .min_by_key(|(_, counter)| counter.frames)
+                    .expect("the sketch is full");
+                *quietest = Counter {
+                    source,
+                    frames: quietest.frames,
+                    inherited: quietest.frames,
+                    protocols: Default::default(),
+                };
+                index
+            }
+        };
+        let counter = &mut counters[index];
+        counter.frames += 1;
+        let traffic = &mut counter.protocols[protocol as usize];
+        traffic.frames += 1;
+        traffic.bytes += len as u64;
+    }
+
+    /// The sources with the most frames, most first
+    pub fn top(&self) -> Vec<Talker> {
+        let mut counters = self.counters.lock().unwrap().clone();
+        counters.sort_by_key(|counter| std::cmp::Reverse(counter.frames));
+        counters
+            .iter()
+            .take(TOP_TALKERS)
+            .map(|counter| Talker {
+                source: counter.source,
+                frames: counter.frames,
+                bytes: counter.protocols.iter().map(|traffic| traffic.bytes).sum(),
+                inherited: counter.inherited,
+                protocols: PROTOCOLS
+                    .iter()
+                    .zip(&counter.protocols)
+                    .filter(|(_, traffic)| traffic.frames > 0)
+                    .map(|(&protocol, traffic)| ProtocolTraffic {
+                        protocol,
+                        frames: traffic.frames,
+                        bytes: traffic.bytes,
+                    })
+                    .collect(),
+            })
+            .collect()
+    }
+
+    /// Forgets all sources
+    pub fn reset(&self) {
+        self.counters.lock().unwrap().clear();
+    }
+}
+
+/// One of the top talkers of a path
+#[derive(Debug, Clone, Serialize)]
+pub struct Talker {
+    pub source: IpAddr,
+    /// Frames counted for the source, at most `inherited` more than it sent
+    pub frames: u64,
+    /// Bytes of the frames the source itself sent, leaving out inherited ones
+    pub bytes: u64,
+    /// Frames of other sources the counter of this one took over
+    pub inherited: u64,
+    pub protocols: Vec<ProtocolTraffic>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ProtocolTraffic {
+    pub protocol: Protocol,
+    pub frames: u64,
+    pub bytes: u64,
+}
+
+impl fmt::Display for Talker {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:<39} {:>10} frames {:>12} bytes",
+            self.source, self.frames, self.bytes
+        )?;
+        for traffic in &self.protocols {
+            write!(f, " {}={}", traffic.protocol.name(), traffic.frames)?;
+        }
+        if self.inherited > 0 {
+            write!(f, " (up to {} of others)", self.inherited)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn floods_stand_out_among_more_sources_than_tracked() {
+        let talkers = TopTalkers::default();
+        let flooder = IpAddr::from(Ipv4Addr::new(192, 168, 1, 66));
+        for round in 0..1000u32 {
+            talkers.record(flooder, Protocol::Ssdp, 100);
+            // A new quiet source every frame, far more than are tracked
+            let quiet = IpAddr::from(Ipv4Addr::from(0x0a00_0000 + round));
+            talkers.record(quiet, Protocol::OtherUdp, 60);
+            if round % 2 == 0 {
+                talkers.record(flooder, Protocol::Mdns, 200);
+            }
+        }
+
+        let top = talkers.top();
+        assert_eq!(top.len(), TOP_TALKERS);
+        let first = &top[0];
+        assert_eq!(
+            (first.source, first.frames, first.inherited),
+            (flooder, 1500, 0)
+        );
+        assert_eq!(first.bytes, 1000 * 100 + 500 * 200);
+        let protocols: Vec<_> = first
+            .protocols
+            .iter()
+            .map(|traffic| (traffic.protocol, traffic.frames))
+            .collect();
+        assert_eq!(protocols, [(Protocol::Ssdp, 1000), (Protocol::Mdns, 500)]);
+        assert!(top[1].frames < 100 && top[1].inherited == top[1].frames - 1);
+        assert!(first.to_string().contains("ssdp=1000 mdns=500"));
+
+        talkers.reset();
+        assert!(talkers.top().is_empty());
+    }
+}
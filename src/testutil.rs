@@ -0,0 +1,327 @@
+//! Frame builders and golden frames shared by the unit tests, so each test
+//! states the fields it cares about instead of poking header bytes.
+
+use crate::filter::{MDNS_IPV4_GROUP, MDNS_PORT, SSDP_PORT};
+use crate::vlan;
+use pnet::packet::ethernet::{EtherTypes, MutableEthernetPacket};
+use pnet::packet::ip::IpNextHeaderProtocols;
+use pnet::packet::ipv4::{self, Ipv4Flags, MutableIpv4Packet};
+use pnet::packet::ipv6::MutableIpv6Packet;
+use pnet::packet::udp::{self, MutableUdpPacket};
+use pnet::util::MacAddr;
+use std::net::{IpAddr, Ipv4Addr};
+
+pub const ETHERNET_HEADER_LEN: usize = 14;
+pub const IPV4_HEADER_LEN: usize = 20;
+pub const IPV6_HEADER_LEN: usize = 40;
+pub const UDP_HEADER_LEN: usize = 8;
+
+/// Source MAC of the frames built here unless a test picks another
+pub const HOST_MAC: MacAddr = MacAddr(0x02, 0, 0, 0, 0, 1);
+/// Source address of the frames built here unless a test picks another
+pub const HOST_IP: Ipv4Addr = Ipv4Addr::new(192, 168, 100, 5);
+pub const SSDP_IPV4_GROUP: Ipv4Addr = Ipv4Addr::new(239, 255, 255, 250);
+pub const DNS_TYPE_PTR: u16 = 12;
+const DNS_CLASS_IN: u16 = 1;
+
+/// Ethernet multicast MAC of an IPv4 or IPv6 group
+pub fn multicast_mac(group: IpAddr) -> MacAddr {
+    match group {
+        IpAddr::V4(group) => {
+            let [_, b, c, d] = group.octets();
+            MacAddr(0x01, 0x00, 0x5e, b & 0x7f, c, d)
+        }
+        IpAddr::V6(group) => {
+            let [.., a, b, c, d] = group.octets();
+            MacAddr(0x33, 0x33, a, b, c, d)
+        }
+    }
+}
+
+/// UDP datagram in an IPv4 or IPv6 packet in an Ethernet frame, with valid
+/// lengths and checksums. The addresses must be of the same family.
+pub fn udp_frame(
+    src_mac: MacAddr,
+    dst_mac: MacAddr,
+    src_ip: impl Into<IpAddr>,
+    dst_ip: impl Into<IpAddr>,
+    sport: u16,
+    dport: u16,
+    payload: &[u8],
+) -> Vec<u8> {
+    let udp_len = UDP_HEADER_LEN + payload.len();
+    let (src_ip, dst_ip) = (src_ip.into(), dst_ip.into());
+    let ip_len = match src_ip {
+        IpAddr::V4(_) => IPV4_HEADER_LEN,
+        IpAddr::V6(_) => IPV6_HEADER_LEN,
+    };
+    let mut frame = vec![0; ETHERNET_HEADER_LEN + ip_len + udp_len];
+    let mut eth = MutableEthernetPacket::new(&mut frame).unwrap();
+    eth.set_source(src_mac);
+    eth.set_destination(dst_mac);
+
+    let (l3, l4) = frame[ETHERNET_HEADER_LEN..].split_at_mut(ip_len);
+    let mut datagram = MutableUdpPacket::new(l4).unwrap();
+    datagram.set_source(sport);
+    datagram.set_destination(dport);
+    datagram.set_length(udp_len as u16);
+    datagram.set_payload(payload);
+    let ethertype = match (src_ip, dst_ip) {
+        (IpAddr::V4(source), IpAddr::V4(destination)) => {
+            let checksum = udp::ipv4_checksum(&datagram.to_immutable(), &source, &destination);
+            datagram.set_checksum(checksum);
+            let mut ip = MutableIpv4Packet::new(l3).unwrap();
+            ip.set_version(4);
+            ip.set_header_length(5);
+            ip.set_total_length((IPV4_HEADER_LEN + udp_len) as u16);
+            ip.set_ttl(1);
+            ip.set_next_level_protocol(IpNextHeaderProtocols::Udp);
+            ip.set_source(source);
+            ip.set_destination(destination);
+            ip.set_checksum(ipv4::checksum(&ip.to_immutable()));
+            EtherTypes::Ipv4
+        }
+        (IpAddr::V6(source), IpAddr::V6(destination)) => {
+            let checksum = udp::ipv6_checksum(&datagram.to_immutable(), &source, &destination);
+            datagram.set_checksum(checksum);
+            let mut ip = MutableIpv6Packet::new(l3).unwrap();
+            ip.set_version(6);
+            ip.set_payload_length(udp_len as u16);
+            ip.set_next_header(IpNextHeaderProtocols::Udp);
+            ip.set_hop_limit(1);
+            ip.set_source(source);
+            ip.set_destination(destination);
+            EtherTypes::Ipv6
+        }
+        _ => panic!("{} and {} are of different families", src_ip, dst_ip),
+    };
+    MutableEthernetPacket::new(&mut frame)
+        .unwrap()
+        .set_ethertype(ethertype);
+    frame
+}
+
+/// DNS name in wire format, uncompressed
+pub fn dns_name(name: &str) -> Vec<u8> {
+    let mut wire = Vec::new();
+    for label in name.split('.').filter(|label| !label.is_empty()) {
+        wire.push(label.len() as u8);
+        wire.extend_from_slice(label.as_bytes());
+    }
+    wire.push(0);
+    wire
+}
+
+/// DNS message header with the given flags and section counts
+pub fn dns_header(flags: u16, questions: u16, answers: u16) -> Vec<u8> {
+    let mut wire = vec![0, 0];
+    for field in [flags, questions, answers, 0, 0] {
+        wire.extend_from_slice(&field.to_be_bytes());
+    }
+    wire
+}
+
+/// mDNS query with a single question for `name`
+pub fn mdns_query(name: &str, qtype: u16) -> Vec<u8> {
+    let mut wire = dns_header(0, 1, 0);
+    wire.extend(dns_name(name));
+    wire.extend_from_slice(&qtype.to_be_bytes());
+    wire.extend_from_slice(&DNS_CLASS_IN.to_be_bytes());
+    wire
+}
+
+/// Authoritative mDNS response with a single answer for `name`
+pub fn mdns_response(name: &str, rtype: u16, ttl: u32, data: &[u8]) -> Vec<u8> {
+    let mut wire = dns_header(0x8400, 0, 1);
+    wire.extend(dns_name(name));
+    wire.extend_from_slice(&rtype.to_be_bytes());
+    wire.extend_from_slice(&DNS_CLASS_IN.to_be_bytes());
+    wire.extend_from_slice(&ttl.to_be_bytes());
+    wire.extend_from_slice(&(data.len() as u16).to_be_bytes());
+    wire.extend_from_slice(data);
+    wire
+}
+
+/// SSDP M-SEARCH for `target` with the headers UPnP requires
+pub fn ssdp_search(target: &str) -> Vec<u8> {
+    format!(
+        "M-SEARCH * HTTP/1.1\r\nHOST: 239.255.255.250:1900\r\n\
+        MAN: \"ssdp:discover\"\r\nMX: 1\r\nST: {}\r\n\r\n",
+        target
+    )
+    .into_bytes()
+}
+
+/// SSDP NOTIFY of kind `nts` for `target`. Announcements carry a LOCATION
+/// on the default host.
+pub fn ssdp_notify(target: &str, nts: &str) -> Vec<u8> {
+    let location = match nts {
+        "ssdp:alive" => format!("LOCATION: http://{}:8008/desc.xml\r\n", HOST_IP),
+        _ => String::new(),
+    };
+    format!(
+        "NOTIFY * HTTP/1.1\r\nHOST: 239.255.255.250:1900\r\nNT: {}\r\nNTS: {}\r\n\
+        {}USN: uuid:1::{}\r\n\r\n",
+        target, nts, location, target
+    )
+    .into_bytes()
+}
+
+/// M-SEARCH from the default host to the SSDP group
+pub fn ssdp_search_frame(target: &str) -> Vec<u8> {
+    udp_frame(
+        HOST_MAC,
+        multicast_mac(SSDP_IPV4_GROUP.into()),
+        HOST_IP,
+        SSDP_IPV4_GROUP,
+        50000,
+        SSDP_PORT,
+        &ssdp_search(target),
+    )
+}
+
+/// mDNS query from the default host to the IPv4 mDNS group
+pub fn mdns_query_frame(name: &str) -> Vec<u8> {
+    udp_frame(
+        HOST_MAC,
+        multicast_mac(MDNS_IPV4_GROUP.into()),
+        HOST_IP,
+        MDNS_IPV4_GROUP,
+        MDNS_PORT,
+        MDNS_PORT,
+        &mdns_query(name, DNS_TYPE_PTR),
+    )
+}
+
+/// Copies of an untagged UDP `frame` cut one byte short of and right at the
+/// end of its Ethernet, IP and UDP headers
+pub fn truncations(frame: &[u8]) -> Vec<Vec<u8>> {
+    let ip_len = match frame[ETHERNET_HEADER_LEN] >> 4 {
+        4 => usize::from(frame[ETHERNET_HEADER_LEN] & 0x0f) * 4,
+        _ => IPV6_HEADER_LEN,
+    };
+    let ip_end = ETHERNET_HEADER_LEN + ip_len;
+    [ETHERNET_HEADER_LEN, ip_end, ip_end + UDP_HEADER_LEN]
+        .into_iter()
+        .flat_map(|end| [end - 1, end])
+        .map(|len| frame[..len].to_vec())
+        .collect()
+}
+
+/// Copy of an IPv4 `frame` with its IP header checksum broken
+pub fn bad_ip_checksum(frame: &[u8]) -> Vec<u8> {
+    let mut frame = frame.to_vec();
+    frame[ETHERNET_HEADER_LEN + 10] ^= 0xff;
+    frame
+}
+
+/// Copy of an untagged UDP `frame` with its UDP checksum broken
+pub fn bad_udp_checksum(frame: &[u8]) -> Vec<u8> {
+    let mut frame = frame.to_vec();
+    let at = truncations(&frame).last().unwrap().len() - 2;
+    frame[at] ^= 0xff;
+    frame
+}
+
+/// Copy of an untagged IPv4 `frame` marked as the fragment at `offset`
+/// bytes, with the more fragments flag set if `more`
+pub fn ipv4_fragment(frame: &[u8], offset: u16, more: bool) -> Vec<u8> {
+    let mut frame = frame.to_vec();
+    let mut ip = MutableIpv4Packet::new(&mut frame[ETHERNET_HEADER_LEN..]).unwrap();
+    ip.set_flags(if more { Ipv4Flags::MoreFragments } else { 0 });
+    ip.set_fragment_offset(offset / 8);
+    ip.set_checksum(ipv4::checksum(&ip.to_immutable()));
+    frame
+}
+
+/// Copy of an untagged IPv6 `frame` with a fragment header for the
+/// fragment at `offset` bytes, with the more fragments flag set if `more`
+pub fn ipv6_fragment(frame: &[u8], offset: u16, more: bool) -> Vec<u8> {
+    let mut frame = frame.to_vec();
+    let at = ETHERNET_HEADER_LEN + IPV6_HEADER_LEN;
+    let mut ip = MutableIpv6Packet::new(&mut frame[ETHERNET_HEADER_LEN..]).unwrap();
+    let next = ip.get_next_header();
+    ip.set_next_header(IpNextHeaderProtocols::Ipv6Frag);
+    ip.set_payload_length(ip.get_payload_length() + 8);
+    let [hi, lo] = (offset | u16::from(more)).to_be_bytes();
+    frame.splice(at..at, [next.0, 0, hi, lo, 0, 0, 0, 1]);
+    frame
+}
+
+/// Copy of an untagged `frame` tagged with VLAN `id`
+pub fn tagged(frame: &[u8], id: u16) -> Vec<u8> {
+    let mut frame = frame.to_vec();
+    vlan::tag(&mut frame, id, 0);
+    frame
+}
+
+/// Inputs the fuzz targets in `fuzz/` start from, by target and name
+pub fn fuzz_seeds() -> Vec<(&'static str, String, Vec<u8>)> {
+    let service = "_googlecast._tcp.local";
+    let query = mdns_query(service, DNS_TYPE_PTR);
+    // Answer whose PTR data points back at the owner name
+    let response = mdns_response(service, DNS_TYPE_PTR, 120, &[2, b't', b'v', 0xc0, 12]);
+    let notify = ssdp_notify("upnp:rootdevice", "ssdp:alive");
+    let search = ssdp_search_frame("ssdp:all");
+    let mdns_frame = |payload: &[u8]| {
+        let group = multicast_mac(MDNS_IPV4_GROUP.into());
+        udp_frame(
+            HOST_MAC,
+            group,
+            HOST_IP,
+            MDNS_IPV4_GROUP,
+            MDNS_PORT,
+            MDNS_PORT,
+            payload,
+        )
+    };
+
+    let mut seeds = vec![
+        ("mdns", "query".to_string(), query.clone()),
+        ("mdns", "response".to_string(), response.clone()),
+        ("ssdp", "search".to_string(), ssdp_search("ssdp:all")),
+        ("ssdp", "notify".to_string(), notify.clone()),
+        (
+            "ssdp",
+            "byebye".to_string(),
+            ssdp_notify("upnp:rootdevice", "ssdp:byebye"),
+        ),
+        ("frame", "ssdp-search".to_string(), search.clone()),
+        ("frame", "mdns-query".to_string(), mdns_frame(&query)),
+        ("frame", "mdns-response".to_string(), mdns_frame(&response)),
+        (
+            "frame",
+            "bad-ip-checksum".to_string(),
+            bad_ip_checksum(&search),
+        ),
+        (
+            "frame",
+            "bad-udp-checksum".to_string(),
+            bad_udp_checksum(&search),
+        ),
+        ("frame", "vlan-tagged".to_string(), tagged(&search, 10)),
+    ];
+    seeds.extend(
+        truncations(&search)
+            .into_iter()
+            .map(|frame| ("frame", format!("truncated-{}", frame.len()), frame)),
+    );
+    seeds
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    #[test]
+    #[ignore = "rewrites fuzz/seeds"]
+    fn write_fuzz_seeds() {
+        let seeds = Path::new(env!("CARGO_MANIFEST_DIR")).join("fuzz/seeds");
+        for (target, name, input) in fuzz_seeds() {
+            let dir = seeds.join(target);
+            std::fs::create_dir_all(&dir).unwrap();
+            std::fs::write(dir.join(name), input).unwrap();
+        }
+    }
+}
@@ -0,0 +1,183 @@
+//! Dedicated OS threads for the blocking capture and send loops, named after
+//! their interface so they can be told apart in `top -H`, and optionally
+//! pinned to a set of CPUs.
+
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io;
+use std::mem;
+use std::str::FromStr;
+use tokio::runtime::Handle;
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+use tracing::{debug, warn};
+
+/// CPUs for the threads of one interface, or of every interface without an
+/// entry of its own, written as `[IFACE=]CPUS` with CPUS a list such as
+/// `2-3,6`
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Affinity {
+    pub iface: Option<String>,
+    pub cpus: Vec<usize>,
+}
+
+impl Affinity {
+    /// CPUs the threads of `iface` are pinned to, if any
+    pub fn lookup<'a>(affinities: &'a [Affinity], iface: &str) -> Option<&'a [usize]> {
+        let own = affinities
+            .iter()
+            .find(|affinity| affinity.iface.as_deref() == Some(iface));
+        own.or_else(|| affinities.iter().find(|affinity| affinity.iface.is_none()))
+            .map(|affinity| affinity.cpus.as_slice())
+    }
+}
+
+impl FromStr for Affinity {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (iface, list) = match value.split_once('=') {
+            Some((iface, list)) => (Some(iface.trim().to_string()), list),
+            None => (None, value),
+        };
+        let cpu = |cpu: &str| match cpu.trim().parse::<usize>() {
+            Ok(cpu) if cpu < libc::CPU_SETSIZE as usize => Ok(cpu),
+            _ => Err(format!("'{}' is not a CPU number", cpu)),
+        };
+        let mut cpus = Vec::new();
+        for range in list.split(',') {
+            match range.split_once('-') {
+                Some((first, last)) => {
+                    let (first, last) = (cpu(first)?, cpu(last)?);
+                    if first > last {
+                        return Err(format!("'{}' is not a CPU range", range));
+                    }
+                    cpus.extend(first..=last);
+                }
+                None => cpus.push(cpu(range)?),
+            }
+        }
+        cpus.sort_unstable();
+        cpus.dedup();
+        Ok(Affinity { iface, cpus })
+    }
+}
+
+impl TryFrom<String> for Affinity {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<Affinity> for String {
+    fn from(affinity: Affinity) -> Self {
+        affinity.to_string()
+    }
+}
+
+impl fmt::Display for Affinity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(iface) = &self.iface {
+            write!(f, "{}=", iface)?;
+        }
+        let cpus: Vec<String> = self.cpus.iter().map(usize::to_string).collect();
+        write!(f, "{}", cpus.join(","))
+    }
+}
+
+/// Restricts the calling thread to `cpus`
+fn pin(cpus: &[usize]) -> io::Result<()> {
+    // SAFETY: cpu_set_t is a plain bit set, valid when zeroed, and every CPU
+    // is below CPU_SETSIZE
+    unsafe {
+        let mut set: libc::cpu_set_t = mem::zeroed();
+        for &cpu in cpus {
+            libc::CPU_SET(cpu, &mut set);
+        }
+        if libc::sched_setaffinity(0, mem::size_of_val(&set), &set) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Runs `work` on a new thread called `name`, pinned to `cpus` if given,
+/// that can still reach the runtime. The returned handle completes when the
+/// thread does and fails if it panicked, like one of `spawn_blocking`.
+pub fn spawn(
+    name: String,
+    cpus: Option<&[usize]>,
+    work: impl FnOnce() + Send + 'static,
+) -> JoinHandle<()> {
+    let runtime = Handle::current();
+    let cpus = cpus.map(<[usize]>::to_vec);
+    let (done, finished) = oneshot::channel();
+    let thread = name.clone();
+    std::thread::Builder::new()
+        .name(name.clone())
+        .spawn(move || {
+            let _runtime = runtime.enter();
+            if let Some(cpus) = cpus {
+                match pin(&cpus) {
+                    Ok(()) => debug!("Thread {} pinned to CPUs {:?}", thread, cpus),
+                    Err(e) => warn!("Pinning thread {} to CPUs {:?} failed: {}", thread, cpus, e),
+                }
+            }
+            work();
+            let _ = done.send(());
+        })
+        .expect("thread spawned");
+    tokio::spawn(async move {
+        if finished.await.is_err() {
+            panic!("thread {} panicked", name);
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_cpu_lists_per_interface() {
+        let all: Affinity = "0-2,6,1".parse().unwrap();
+        assert_eq!(
+            (all.iface.as_deref(), all.cpus.as_slice()),
+            (None, &[0, 1, 2, 6][..])
+        );
+        assert_eq!(all.to_string(), "0,1,2,6");
+        let eth0: Affinity = "eth0=4-5".parse().unwrap();
+        assert_eq!(eth0.iface.as_deref(), Some("eth0"));
+        for invalid in ["", "3-1", "a", "eth0=", "99999"] {
+            assert!(invalid.parse::<Affinity>().is_err(), "{}", invalid);
+        }
+
+        let affinities = [all, eth0];
+        assert_eq!(Affinity::lookup(&affinities, "eth0"), Some(&[4, 5][..]));
+        assert_eq!(
+            Affinity::lookup(&affinities, "eth1"),
+            Some(&[0, 1, 2, 6][..])
+        );
+        assert_eq!(Affinity::lookup(&affinities[1..], "eth1"), None);
+    }
+
+    #[tokio::test]
+    async fn runs_named_pinned_threads() {
+        let (tx, rx) = std::sync::mpsc::channel();
+        spawn("rx-test0".to_string(), Some(&[0]), move || {
+            let name = std::thread::current().name().map(str::to_string);
+            // SAFETY: sched_getcpu has no preconditions
+            let cpu = unsafe { libc::sched_getcpu() };
+            tx.send((name, cpu)).unwrap();
+        })
+        .await
+        .unwrap();
+        assert_eq!(rx.recv().unwrap(), (Some("rx-test0".to_string()), 0));
+
+        let panicked = spawn("rx-test1".to_string(), None, || panic!("capture failed"));
+        assert!(panicked.await.unwrap_err().is_panic());
+    }
+}
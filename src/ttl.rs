@@ -0,0 +1,282 @@
+//! Router-like TTL handling for `--decrement-ttl`: forwarded IP packets lose
+//! one hop, so a forwarding loop elsewhere dies out instead of circling
+//! through the forwarder for ever. Link-local name resolution is the
+//! exception: mDNS and LLMNR carry the TTL their receivers check, which is
+//! restored on the way out and optionally enforced on the way in.
+
+use crate::checksum;
+use crate::filter::{LLMNR_PORT, MDNS_PORT};
+use crate::rewrite::{Rewrite, UdpDatagram};
+use pnet::packet::ethernet::{EtherTypes, EthernetPacket};
+use std::collections::HashSet;
+use tracing::debug;
+
+const ETHERNET_HEADER_LEN: usize = 14;
+/// Offset of the IPv4 TTL, followed by the protocol
+const IPV4_TTL: usize = ETHERNET_HEADER_LEN + 8;
+const IPV4_CHECKSUM: usize = ETHERNET_HEADER_LEN + 10;
+const IPV6_HOP_LIMIT: usize = ETHERNET_HEADER_LEN + 7;
+
+/// Name of the stage, counted as `expired` rather than as a failed rewrite
+pub const DECREMENT_TTL: &str = "decrement-ttl";
+/// Name of the stage, counted as `off-link` rather than as a failed rewrite
+pub const ON_LINK_TTL: &str = "on-link-ttl";
+/// TTL of every mDNS packet, which only one sent on the link still has
+/// when it arrives (RFC 6762 section 11)
+const MDNS_TTL: u8 = 255;
+/// TTL of LLMNR packets to the multicast group, which a router would not
+/// pass on (RFC 4795 section 2.5)
+const LLMNR_MULTICAST_TTL: u8 = 1;
+
+/// Offset of the IPv4 TTL or IPv6 hop limit of `frame`, `None` if it is not
+/// IP or too short to hold it
+fn hop_count(frame: &[u8]) -> Option<usize> {
+    match EthernetPacket::new(frame)?.get_ethertype() {
+        EtherTypes::Ipv4 if frame.len() > IPV4_CHECKSUM + 1 => Some(IPV4_TTL),
+        EtherTypes::Ipv6 if frame.len() > IPV6_HOP_LIMIT => Some(IPV6_HOP_LIMIT),
+        _ => None,
+    }
+}
+
+/// Replaces the hop count at `at` by `ttl`, updating the IPv4 header
+/// checksum. IPv6 has none, and the UDP checksum leaves the hop limit out.
+fn set_hop_count(frame: &mut [u8], at: usize, ttl: u8) {
+    let old = frame[at];
+    frame[at] = ttl;
+    if at == IPV4_TTL {
+        let protocol = frame[at + 1];
+        let sum = u16::from_be_bytes([frame[IPV4_CHECKSUM], frame[IPV4_CHECKSUM + 1]]);
+        let sum = checksum::adjust(
+            sum,
+            u16::from_be_bytes([old, protocol]),
+            u16::from_be_bytes([ttl, protocol]),
+        );
+        frame[IPV4_CHECKSUM..IPV4_CHECKSUM + 2].copy_from_slice(&sum.to_be_bytes());
+    }
+}
+
+/// Decrements the IPv4 TTL or IPv6 hop limit, rejecting packets that have
+/// none left. UDP to or from the exempt ports passes unchanged: mDNS and
+/// LLMNR check the TTL on receipt to tell on-link senders apart.
+pub struct DecrementTtl {
+    exempt: HashSet<u16>,
+}
+
+impl DecrementTtl {
+    pub fn new(exempt: &[u16]) -> Self {
+        DecrementTtl {
+            exempt: exempt.iter().copied().collect(),
+        }
+    }
+
+    fn exempt(&self, frame: &[u8]) -> bool {
+        UdpDatagram::locate(frame).is_some_and(|datagram| {
+            datagram
+                .ports(frame)
+                .iter()
+                .any(|port| self.exempt.contains(port))
+        })
+    }
+}
+
+impl Rewrite for DecrementTtl {
+    fn name(&self) -> &str {
+        DECREMENT_TTL
+    }
+
+    fn apply(&self, frame: &mut Vec<u8>) -> bool {
+        if EthernetPacket::new(frame).is_none() {
+            return false;
+        }
+        // Only IP has a hop count
+        let Some(at) = hop_count(frame) else {
+            return true;
+        };
+        if self.exempt(frame) {
+            return true;
+        }
+        let ttl = frame[at];
+        if ttl <= 1 {
+            return false;
+        }
+        set_hop_count(frame, at, ttl - 1);
+        true
+    }
+}
+
+/// Sends mDNS with TTL 255 and LLMNR to the multicast group with TTL 1 as
+/// if from a sender on the egress link. With enforcement, frames arriving
+/// with another TTL are rejected as routed from off the link: mDNS from
+/// port 5353 below 255, as one-shot queries from other ports may use any
+/// TTL, and multicast LLMNR above 1. Unicast LLMNR is left alone.
+pub struct OnLinkTtl {
+    enforce_mdns: bool,
+    enforce_llmnr: bool,
+}
+
+impl OnLinkTtl {
+    pub fn new(enforce_mdns: bool, enforce_llmnr: bool) -> Self {
+        OnLinkTtl {
+            enforce_mdns,
+            enforce_llmnr,
+        }
+    }
+}
+
+impl Rewrite for OnLinkTtl {
+    fn name(&self) -> &str {
+        ON_LINK_TTL
+    }
+
+    fn apply(&self, frame: &mut Vec<u8>) -> bool {
+        let (Some(datagram), Some(at)) = (UdpDatagram::locate(frame), hop_count(frame)) else {
+            return true;
+        };
+        let [source, destination] = datagram.ports(frame);
+        let multicast = frame[0] & 1 == 1;
+        let ttl = frame[at];
+        let (protocol, expected, off_link) = if source == MDNS_PORT || destination == MDNS_PORT {
+            let off_link = self.enforce_mdns && source == MDNS_PORT && ttl < MDNS_TTL;
+            ("mDNS", MDNS_TTL, off_link)
+        } else if multicast && (source == LLMNR_PORT || destination == LLMNR_PORT) {
+            let off_link = self.enforce_llmnr && ttl > LLMNR_MULTICAST_TTL;
+            ("LLMNR", LLMNR_MULTICAST_TTL, off_link)
+        } else {
+            return true;
+        };
+        if off_link {
+            debug!(
+                "{} packet with TTL {} dropped as sent from off the link",
+                protocol, ttl
+            );
+            return false;
+        }
+        if ttl != expected {
+            set_hop_count(frame, at, expected);
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filter::{MDNS_PORT, SSDP_PORT};
+    use crate::testutil::{multicast_mac, udp_frame, HOST_IP, HOST_MAC, SSDP_IPV4_GROUP};
+    use pnet::packet::ipv4::{self, Ipv4Packet};
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+    #[test]
+    fn decrements_until_expired_and_keeps_the_checksum_valid() {
+        let stage = DecrementTtl::new(&[MDNS_PORT]);
+        let group = IpAddr::V4(SSDP_IPV4_GROUP);
+        let mut frame = udp_frame(
+            HOST_MAC,
+            multicast_mac(group),
+            HOST_IP,
+            group,
+            SSDP_PORT,
+            SSDP_PORT,
+            b"NOTIFY",
+        );
+        frame[IPV4_TTL] = 3;
+        frame[IPV4_CHECKSUM..IPV4_CHECKSUM + 2].fill(0);
+        let ip = Ipv4Packet::new(&frame[ETHERNET_HEADER_LEN..]).unwrap();
+        let sum = ipv4::checksum(&ip);
+        frame[IPV4_CHECKSUM..IPV4_CHECKSUM + 2].copy_from_slice(&sum.to_be_bytes());
+
+        for ttl in [2, 1] {
+            assert!(stage.apply(&mut frame));
+            let ip = Ipv4Packet::new(&frame[ETHERNET_HEADER_LEN..]).unwrap();
+            assert_eq!(ip.get_ttl(), ttl);
+            assert_eq!(ip.get_checksum(), ipv4::checksum(&ip));
+        }
+        assert!(!stage.apply(&mut frame), "TTL 1 expires");
+
+        let group = IpAddr::V6(Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 0xfb));
+        let source = IpAddr::V6(Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 5));
+        let mut mdns = udp_frame(
+            HOST_MAC,
+            multicast_mac(group),
+            source,
+            group,
+            MDNS_PORT,
+            MDNS_PORT,
+            b"",
+        );
+        mdns[IPV6_HOP_LIMIT] = 255;
+        let unchanged = mdns.clone();
+        assert!(stage.apply(&mut mdns));
+        assert_eq!(mdns, unchanged, "mDNS is exempt");
+
+        let mut ssdp = udp_frame(
+            HOST_MAC,
+            multicast_mac(group),
+            source,
+            group,
+            SSDP_PORT,
+            SSDP_PORT,
+            b"",
+        );
+        ssdp[IPV6_HOP_LIMIT] = 4;
+        assert!(stage.apply(&mut ssdp));
+        assert_eq!(ssdp[IPV6_HOP_LIMIT], 3);
+    }
+
+    #[test]
+    fn restores_and_enforces_on_link_ttls() {
+        let group = IpAddr::V4(Ipv4Addr::new(224, 0, 0, 251));
+        let frame = |source_port, ttl| {
+            let mut frame = udp_frame(
+                HOST_MAC,
+                multicast_mac(group),
+                HOST_IP,
+                group,
+                source_port,
+                MDNS_PORT,
+                b"",
+            );
+            frame[IPV4_TTL] = ttl;
+            frame[IPV4_CHECKSUM..IPV4_CHECKSUM + 2].fill(0);
+            let ip = Ipv4Packet::new(&frame[ETHERNET_HEADER_LEN..]).unwrap();
+            let sum = ipv4::checksum(&ip);
+            frame[IPV4_CHECKSUM..IPV4_CHECKSUM + 2].copy_from_slice(&sum.to_be_bytes());
+            frame
+        };
+
+        let lenient = OnLinkTtl::new(false, false);
+        let mut routed = frame(MDNS_PORT, 254);
+        assert!(lenient.apply(&mut routed));
+        let ip = Ipv4Packet::new(&routed[ETHERNET_HEADER_LEN..]).unwrap();
+        assert_eq!(ip.get_ttl(), 255);
+        assert_eq!(ip.get_checksum(), ipv4::checksum(&ip));
+
+        let strict = OnLinkTtl::new(true, true);
+        assert!(!strict.apply(&mut frame(MDNS_PORT, 254)));
+        let mut on_link = frame(MDNS_PORT, 255);
+        let unchanged = on_link.clone();
+        assert!(strict.apply(&mut on_link));
+        assert_eq!(on_link, unchanged);
+        // One-shot queries from other ports may use any TTL
+        let mut one_shot = frame(50000, 64);
+        assert!(strict.apply(&mut one_shot));
+        assert_eq!(one_shot[IPV4_TTL], 255);
+
+        let group = IpAddr::V6(Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 1, 3));
+        let source = IpAddr::V6(Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 5));
+        let mut llmnr = udp_frame(
+            HOST_MAC,
+            multicast_mac(group),
+            source,
+            group,
+            50000,
+            LLMNR_PORT,
+            b"",
+        );
+        llmnr[IPV6_HOP_LIMIT] = 64;
+        assert!(!strict.apply(&mut llmnr.clone()));
+        assert!(lenient.apply(&mut llmnr));
+        assert_eq!(llmnr[IPV6_HOP_LIMIT], 1);
+        assert!(strict.apply(&mut llmnr));
+    }
+}
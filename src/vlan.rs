@@ -0,0 +1,218 @@
+//! 802.1Q VLAN tags, taken off received frames ahead of the filters and put
+//! back or replaced on the way out.
+
+use crate::dscp::Marking;
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+
+const TPID_8021Q: u16 = 0x8100;
+const TPID_8021AD: u16 = 0x88a8;
+const TAG_LEN: usize = 4;
+/// Destination and source MAC, ahead of the first tag
+const ADDRESSES_LEN: usize = 12;
+/// One level of QinQ: an outer service tag and an inner customer tag
+const MAX_TAGS: usize = 2;
+/// Priority (PCP) and drop eligible (DEI) bits of a tag
+const PRIORITY_MASK: u16 = 0xf000;
+/// Priority code point alone, without the drop eligible bit
+const PCP_MASK: u16 = 0xe000;
+const PCP_SHIFT: u16 = 13;
+const ID_MASK: u16 = 0x0fff;
+/// Ethernet header of a frame carrying the most tags, on top of the MTU
+pub const MAX_HEADER_LEN: usize = ADDRESSES_LEN + MAX_TAGS * TAG_LEN + 2;
+
+/// What happens to the tags of a frame sent to an interface without a VLAN
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum VlanEgress {
+    /// Send it untagged
+    Strip,
+    /// Send it with the tags it was received with
+    Keep,
+}
+
+/// Tags a frame was received with, outer first
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Tags {
+    bytes: [u8; TAG_LEN * MAX_TAGS],
+    len: usize,
+}
+
+impl Tags {
+    fn outer_tci(&self) -> Option<u16> {
+        (self.len > 0).then(|| u16::from_be_bytes([self.bytes[2], self.bytes[3]]))
+    }
+
+    /// VLAN ID of the outer tag
+    pub fn id(&self) -> Option<u16> {
+        self.outer_tci().map(|tci| tci & ID_MASK)
+    }
+}
+
+/// Splits `frame` into the frame without its tags and the tags. Untagged
+/// frames are borrowed as they are.
+pub fn untag(frame: &[u8]) -> (Cow<'_, [u8]>, Tags) {
+    let mut tags = Tags::default();
+    let mut at = ADDRESSES_LEN;
+    while tags.len < tags.bytes.len() {
+        let Some(tag) = frame.get(at..at + TAG_LEN + 2) else {
+            break;
+        };
+        let tpid = u16::from_be_bytes([tag[0], tag[1]]);
+        if tpid != TPID_8021Q && tpid != TPID_8021AD {
+            break;
+        }
+        tags.bytes[tags.len..tags.len + TAG_LEN].copy_from_slice(&tag[..TAG_LEN]);
+        tags.len += TAG_LEN;
+        at += TAG_LEN;
+    }
+    if tags.len == 0 {
+        return (Cow::Borrowed(frame), tags);
+    }
+    let mut untagged = frame[..ADDRESSES_LEN].to_vec();
+    untagged.extend_from_slice(&frame[at..]);
+    (Cow::Owned(untagged), tags)
+}
+
+fn insert(frame: &mut Vec<u8>, tags: &[u8]) {
+    if frame.len() >= ADDRESSES_LEN {
+        frame.splice(ADDRESSES_LEN..ADDRESSES_LEN, tags.iter().copied());
+    }
+}
+
+/// Tags an untagged frame with VLAN `id` and the priority bits of `tci`
+pub fn tag(frame: &mut Vec<u8>, id: u16, tci: u16) {
+    let tci = (tci & PRIORITY_MASK) | id;
+    let [tpid_high, tpid_low] = TPID_8021Q.to_be_bytes();
+    let [tci_high, tci_low] = tci.to_be_bytes();
+    insert(frame, &[tpid_high, tpid_low, tci_high, tci_low]);
+}
+
+/// VLAN handling of one forwarding path
+#[derive(Debug, Clone, Copy, Default)]
+pub struct VlanPath {
+    /// VLAN of the ingress interface; frames on others are not accepted
+    pub ingress: Option<u16>,
+    /// VLAN of the egress interface, which every frame is tagged with
+    pub egress: Option<u16>,
+    /// Whether frames keep their tags towards an egress without a VLAN
+    pub keep: bool,
+    /// Marking whose DSCP sets the priority of the outer tag of marked
+    /// frames, if the priority follows it
+    pub priority: Option<Marking>,
+}
+
+impl VlanPath {
+    pub fn new(ingress: Option<u16>, egress: Option<u16>, untagged: VlanEgress) -> Self {
+        VlanPath {
+            ingress,
+            egress,
+            keep: untagged == VlanEgress::Keep,
+            priority: None,
+        }
+    }
+
+    /// Whether a frame received with `tags` is on the ingress VLAN
+    pub fn accepts(&self, tags: &Tags) -> bool {
+        self.ingress.is_none_or(|id| tags.id() == Some(id))
+    }
+
+    /// Tags a forwarded frame for the egress. Retagging keeps the priority
+    /// of the outer tag the frame was received with, unless the frame is of
+    /// a class marked with a DSCP the priority follows.
+    pub fn retag(&self, frame: &mut Vec<u8>, tags: &Tags) {
+        let pcp = self
+            .priority
+            .and_then(|marking| marking.dscp(frame))
+            .map(|dscp| u16::from(dscp.priority()) << PCP_SHIFT);
+        let prioritized = |tci: u16| match pcp {
+            Some(pcp) => (tci & !PCP_MASK) | pcp,
+            None => tci,
+        };
+        match self.egress {
+            Some(id) => tag(frame, id, prioritized(tags.outer_tci().unwrap_or(0))),
+            None if self.keep && tags.len > 0 => {
+                let mut bytes = tags.bytes;
+                let tci = prioritized(u16::from_be_bytes([bytes[2], bytes[3]]));
+                bytes[2..TAG_LEN].copy_from_slice(&tci.to_be_bytes());
+                insert(frame, &bytes[..tags.len]);
+            }
+            None => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{multicast_mac, udp_frame, HOST_IP, HOST_MAC, SSDP_IPV4_GROUP};
+    use std::net::IpAddr;
+
+    #[test]
+    fn untags_and_retags_with_priority() {
+        let untagged: Vec<u8> = (0..12).chain([0x08, 0x00, 0x45, 0]).collect();
+        let (frame, tags) = untag(&untagged);
+        assert!(matches!(frame, Cow::Borrowed(_)));
+        assert_eq!(tags.id(), None);
+
+        // QinQ: service tag 100 with priority 5, customer tag 10
+        let mut qinq = untagged.clone();
+        tag(&mut qinq, 10, 0);
+        insert(&mut qinq, &[0x88, 0xa8, 0xa0, 100]);
+        let (frame, tags) = untag(&qinq);
+        assert_eq!(frame.as_ref(), untagged.as_slice());
+        assert_eq!(tags.id(), Some(100));
+
+        let path = VlanPath::new(Some(100), Some(20), VlanEgress::Strip);
+        assert!(path.accepts(&tags));
+        assert!(!path.accepts(&Tags::default()));
+        let mut sent = frame.to_vec();
+        path.retag(&mut sent, &tags);
+        assert_eq!(&sent[12..18], &[0x81, 0x00, 0xa0, 20, 0x08, 0x00]);
+
+        let mut kept = frame.to_vec();
+        VlanPath::new(None, None, VlanEgress::Keep).retag(&mut kept, &tags);
+        assert_eq!(kept, qinq);
+        let mut stripped = frame.to_vec();
+        VlanPath::new(None, None, VlanEgress::Strip).retag(&mut stripped, &tags);
+        assert_eq!(stripped, untagged);
+    }
+
+    #[test]
+    fn sets_priority_of_marked_frames() {
+        let group = IpAddr::V4(SSDP_IPV4_GROUP);
+        let frame = udp_frame(
+            HOST_MAC,
+            multicast_mac(group),
+            HOST_IP,
+            group,
+            1900,
+            1900,
+            b"NOTIFY",
+        );
+        // Received with priority 5 and the drop eligible bit set
+        let mut received = frame.clone();
+        tag(&mut received, 10, 0xb000);
+        let (_, tags) = untag(&received);
+        let marking = Marking::new(&["discovery=af41".parse().unwrap()]);
+
+        let mut path = VlanPath::new(Some(10), Some(20), VlanEgress::Strip);
+        path.priority = Some(marking);
+        let mut sent = frame.clone();
+        path.retag(&mut sent, &tags);
+        assert_eq!(&sent[12..16], &[0x81, 0x00, 0x90, 20]);
+
+        let mut kept = VlanPath::new(None, None, VlanEgress::Keep);
+        kept.priority = Some(marking);
+        let mut sent = frame.clone();
+        kept.retag(&mut sent, &tags);
+        assert_eq!(&sent[12..16], &[0x81, 0x00, 0x90, 10]);
+
+        // Control traffic is not marked, so it keeps the received priority
+        path.priority = Some(Marking::new(&["control=af41".parse().unwrap()]));
+        let mut sent = frame;
+        path.retag(&mut sent, &tags);
+        assert_eq!(&sent[12..16], &[0x81, 0x00, 0xb0, 20]);
+    }
+}
@@ -0,0 +1,211 @@
+//! Wake-on-LAN magic packets, sent from the internal side to wake machines
+//! on the external segment. Both forms are recognised: raw frames of
+//! EtherType 0x0842 and UDP datagrams to the discard or echo port.
+
+use crate::filter::{Decision, Filter, PacketContext};
+use crate::logging::{debug_repeated, RepeatedMessages};
+use crate::pair::Direction;
+use pnet::packet::ethernet::EtherType;
+use pnet::packet::Packet;
+use pnet::util::MacAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+pub const WOL_ETHERTYPE: EtherType = EtherType(0x0842);
+/// Discard and echo, the UDP ports magic packets are sent to
+pub const WOL_PORTS: [u16; 2] = [9, 7];
+
+/// Name of the filter, whose forwarded frames and drops are counted apart
+pub const WAKE_ON_LAN: &str = "wake-on-lan";
+
+const SYNC_LEN: usize = 6;
+const REPETITIONS: usize = 16;
+const MAGIC_LEN: usize = SYNC_LEN + REPETITIONS * 6;
+/// SecureOn password some senders append, of 4 or 6 bytes
+const PASSWORD_LENS: [usize; 3] = [0, 4, 6];
+
+/// MAC a magic packet payload wakes: six bytes of 0xff followed by sixteen
+/// repetitions of the MAC, optionally followed by a SecureOn password
+pub fn target(payload: &[u8]) -> Option<MacAddr> {
+    let extra = payload.len().checked_sub(MAGIC_LEN)?;
+    if !PASSWORD_LENS.contains(&extra) || payload[..SYNC_LEN] != [0xff; SYNC_LEN] {
+        return None;
+    }
+    let mac = &payload[SYNC_LEN..SYNC_LEN + 6];
+    if !(SYNC_LEN..MAGIC_LEN)
+        .step_by(6)
+        .all(|at| &payload[at..at + 6] == mac)
+    {
+        return None;
+    }
+    Some(MacAddr::new(mac[0], mac[1], mac[2], mac[3], mac[4], mac[5]))
+}
+
+/// Forwards magic packets from the internal to the external side ahead of
+/// the protocol filters, if they wake one of `targets` or `targets` is
+/// empty. Magic packets the other way or for other machines are dropped,
+/// as are frames of the Wake-on-LAN EtherType without a valid payload; UDP
+/// to the discard and echo ports without one is left to the other filters.
+pub struct WakeOnLanFilter {
+    targets: Vec<MacAddr>,
+    malformed: AtomicU64,
+    wrong_direction: AtomicU64,
+    other_target: AtomicU64,
+    log: RepeatedMessages,
+}
+
+impl WakeOnLanFilter {
+    pub fn new(targets: Vec<MacAddr>) -> Self {
+        WakeOnLanFilter {
+            targets,
+            malformed: AtomicU64::new(0),
+            wrong_direction: AtomicU64::new(0),
+            other_target: AtomicU64::new(0),
+            log: RepeatedMessages::default(),
+        }
+    }
+
+    fn dropped(&self, counter: &AtomicU64, why: &str) -> Decision {
+        counter.fetch_add(1, Ordering::Relaxed);
+        debug_repeated!(
+            self.log,
+            "Wake-on-LAN packets dropped",
+            "Wake-on-LAN packet dropped: {}",
+            why
+        );
+        Decision::Drop
+    }
+}
+
+impl Filter for WakeOnLanFilter {
+    fn name(&self) -> &str {
+        WAKE_ON_LAN
+    }
+
+    fn evaluate(&self, ctx: &PacketContext) -> Decision {
+        let target = if ctx.ethertype() == WOL_ETHERTYPE {
+            match target(ctx.ethernet.payload()) {
+                Some(target) => target,
+                None => return self.dropped(&self.malformed, "malformed"),
+            }
+        } else {
+            let Some(udp) = ctx.udp() else {
+                return Decision::Continue;
+            };
+            if !WOL_PORTS.contains(&udp.get_destination()) {
+                return Decision::Continue;
+            }
+            match target(udp.payload()) {
+                Some(target) => target,
+                None => return Decision::Continue,
+            }
+        };
+        if ctx.direction != Direction::Outbound {
+            return self.dropped(&self.wrong_direction, "wrong direction");
+        }
+        if !self.targets.is_empty() && !self.targets.contains(&target) {
+            return self.dropped(&self.other_target, "target not allowed");
+        }
+        Decision::Forward
+    }
+
+    fn state(&self) -> Option<Vec<String>> {
+        Some(vec![format!(
+            "dropped malformed={} wrong-direction={} other-target={}",
+            self.malformed.load(Ordering::Relaxed),
+            self.wrong_direction.load(Ordering::Relaxed),
+            self.other_target.load(Ordering::Relaxed)
+        )])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{udp_frame, HOST_IP, HOST_MAC};
+    use std::net::Ipv4Addr;
+
+    const MEDIA_PC: MacAddr = MacAddr(0x02, 0x11, 0x22, 0x33, 0x44, 0x55);
+
+    fn magic(mac: MacAddr) -> Vec<u8> {
+        let mut payload = vec![0xff; SYNC_LEN];
+        for _ in 0..REPETITIONS {
+            payload.extend_from_slice(&mac.octets());
+        }
+        payload
+    }
+
+    fn raw_frame(payload: &[u8]) -> Vec<u8> {
+        let mut frame = MacAddr::broadcast().octets().to_vec();
+        frame.extend_from_slice(&HOST_MAC.octets());
+        frame.extend_from_slice(&WOL_ETHERTYPE.0.to_be_bytes());
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    fn udp(port: u16, payload: &[u8]) -> Vec<u8> {
+        udp_frame(
+            HOST_MAC,
+            MacAddr::broadcast(),
+            HOST_IP,
+            Ipv4Addr::BROADCAST,
+            40000,
+            port,
+            payload,
+        )
+    }
+
+    fn evaluate(filter: &WakeOnLanFilter, direction: Direction, frame: &[u8]) -> Decision {
+        filter.evaluate(&PacketContext::parse("vm0", direction, frame).unwrap())
+    }
+
+    #[test]
+    fn validates_magic_packets() {
+        assert_eq!(target(&magic(MEDIA_PC)), Some(MEDIA_PC));
+        let mut secure_on = magic(MEDIA_PC);
+        secure_on.extend_from_slice(&[1, 2, 3, 4]);
+        assert_eq!(target(&secure_on), Some(MEDIA_PC));
+        let mut broken = magic(MEDIA_PC);
+        broken[50] ^= 1;
+        assert_eq!(target(&broken), None);
+        assert_eq!(target(&magic(MEDIA_PC)[..MAGIC_LEN - 1]), None);
+        let mut padded = magic(MEDIA_PC);
+        padded.push(0);
+        assert_eq!(target(&padded), None);
+
+        let filter = WakeOnLanFilter::new(vec![MEDIA_PC]);
+        for frame in [raw_frame(&magic(MEDIA_PC)), udp(9, &magic(MEDIA_PC))] {
+            assert_eq!(
+                evaluate(&filter, Direction::Outbound, &frame),
+                Decision::Forward
+            );
+            assert_eq!(
+                evaluate(&filter, Direction::Inbound, &frame),
+                Decision::Drop
+            );
+        }
+        let other = MacAddr(0x02, 0, 0, 0, 0, 9);
+        assert_eq!(
+            evaluate(&filter, Direction::Outbound, &udp(7, &magic(other))),
+            Decision::Drop
+        );
+        assert_eq!(
+            evaluate(&filter, Direction::Outbound, &raw_frame(&broken)),
+            Decision::Drop
+        );
+        // Only the port is right, so it is not taken for a magic packet
+        assert_eq!(
+            evaluate(&filter, Direction::Outbound, &udp(9, &broken)),
+            Decision::Continue
+        );
+        assert_eq!(
+            filter.state().unwrap(),
+            ["dropped malformed=1 wrong-direction=2 other-target=1"]
+        );
+
+        let any = WakeOnLanFilter::new(Vec::new());
+        assert_eq!(
+            evaluate(&any, Direction::Outbound, &udp(7, &magic(other))),
+            Decision::Forward
+        );
+    }
+}
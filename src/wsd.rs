@@ -0,0 +1,194 @@
+//! WS-Discovery specific handling.
+
+use crate::filter::{Decision, Filter, PacketContext, WSD_PORT};
+use crate::logging::{debug_repeated, RepeatedMessages};
+use clap::ValueEnum;
+use pnet::packet::Packet;
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// SOAP action of a WS-Discovery message, the last segment of its
+/// `Action` URI
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum WsdAction {
+    Probe,
+    ProbeMatches,
+    Resolve,
+    ResolveMatches,
+    Hello,
+    Bye,
+}
+
+impl WsdAction {
+    fn from_uri(uri: &str) -> Option<Self> {
+        let name = uri.rsplit('/').next()?;
+        Some(match name {
+            "Probe" => WsdAction::Probe,
+            "ProbeMatches" => WsdAction::ProbeMatches,
+            "Resolve" => WsdAction::Resolve,
+            "ResolveMatches" => WsdAction::ResolveMatches,
+            "Hello" => WsdAction::Hello,
+            "Bye" => WsdAction::Bye,
+            _ => return None,
+        })
+    }
+
+    /// Whether the action asks for devices, as opposed to a device
+    /// answering or announcing itself
+    fn is_request(self) -> bool {
+        matches!(self, WsdAction::Probe | WsdAction::Resolve)
+    }
+}
+
+/// Outcome of looking for the SOAP action of a message
+#[derive(Debug, PartialEq, Eq)]
+enum Action<'a> {
+    Known(WsdAction),
+    Other(&'a str),
+}
+
+/// Finds the WS-Addressing `Action` element of a SOAP envelope, whatever
+/// its namespace prefix. Returns `None` if the payload is not UTF-8 or has
+/// no `Action` element.
+fn action(payload: &[u8]) -> Option<Action<'_>> {
+    let text = std::str::from_utf8(payload).ok()?;
+    let mut rest = text;
+    while let Some(start) = rest.find('<') {
+        let tag = &rest[start + 1..];
+        let end = tag.find('>')?;
+        let name = tag[..end].split_whitespace().next().unwrap_or_default();
+        let local = name.rsplit(':').next().unwrap_or_default();
+        rest = &tag[end + 1..];
+        if local == "Action" && !tag[..end].ends_with('/') {
+            let uri = rest[..rest.find('<')?].trim();
+            return Some(match WsdAction::from_uri(uri) {
+                Some(action) => Action::Known(action),
+                None => Action::Other(uri),
+            });
+        }
+    }
+    None
+}
+
+/// Lets through WS-Discovery messages with an allowed SOAP action. Probes
+/// and resolves are only accepted from the internal interface, and
+/// matches, hellos and byes only from the others, unless relaxed. Without
+/// an internal interface, as in bridge mode, direction is not checked.
+pub struct WsdMessageFilter {
+    actions: Vec<WsdAction>,
+    internal_iface: Option<String>,
+    external_probe: bool,
+    internal_announce: bool,
+    malformed: AtomicU64,
+    wrong_direction: AtomicU64,
+    other_action: AtomicU64,
+    log: RepeatedMessages,
+}
+
+impl WsdMessageFilter {
+    pub fn new(
+        actions: &[WsdAction],
+        internal_iface: Option<String>,
+        external_probe: bool,
+        internal_announce: bool,
+    ) -> Self {
+        WsdMessageFilter {
+            actions: actions.to_vec(),
+            internal_iface,
+            external_probe,
+            internal_announce,
+            malformed: AtomicU64::new(0),
+            wrong_direction: AtomicU64::new(0),
+            other_action: AtomicU64::new(0),
+            log: RepeatedMessages::default(),
+        }
+    }
+
+    fn dropped(&self, counter: &AtomicU64, what: &str) -> Decision {
+        counter.fetch_add(1, Ordering::Relaxed);
+        debug_repeated!(
+            self.log,
+            "WS-Discovery messages dropped",
+            "WS-Discovery message dropped: {}",
+            what
+        );
+        Decision::Drop
+    }
+}
+
+impl Filter for WsdMessageFilter {
+    fn name(&self) -> &str {
+        "wsd-messages"
+    }
+
+    fn evaluate(&self, ctx: &PacketContext) -> Decision {
+        let Some(udp) = ctx.udp() else {
+            return Decision::Continue;
+        };
+        if udp.get_source() != WSD_PORT && udp.get_destination() != WSD_PORT {
+            return Decision::Continue;
+        }
+        let action = match action(udp.payload()) {
+            Some(Action::Known(action)) => action,
+            Some(Action::Other(uri)) => return self.dropped(&self.other_action, uri),
+            None => return self.dropped(&self.malformed, "malformed"),
+        };
+        if let Some(internal) = &self.internal_iface {
+            let from_internal = ctx.ingress == internal;
+            let expected = if action.is_request() {
+                from_internal || self.external_probe
+            } else {
+                !from_internal || self.internal_announce
+            };
+            if !expected {
+                return self.dropped(&self.wrong_direction, "wrong direction");
+            }
+        }
+        if !self.actions.contains(&action) {
+            return self.dropped(&self.other_action, &format!("{:?}", action));
+        }
+        Decision::Continue
+    }
+
+    fn state(&self) -> Option<Vec<String>> {
+        Some(vec![format!(
+            "dropped malformed={} wrong-direction={} other-action={}",
+            self.malformed.load(Ordering::Relaxed),
+            self.wrong_direction.load(Ordering::Relaxed),
+            self.other_action.load(Ordering::Relaxed)
+        )])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_soap_action() {
+        let probe = br#"<?xml version="1.0" encoding="UTF-8"?>
+<soap:Envelope xmlns:soap="http://www.w3.org/2003/05/soap-envelope"
+    xmlns:wsa="http://schemas.xmlsoap.org/ws/2004/08/addressing">
+  <soap:Header>
+    <wsa:To>urn:schemas-xmlsoap-org:ws:2005:04:discovery</wsa:To>
+    <wsa:Action>
+      http://schemas.xmlsoap.org/ws/2005/04/discovery/Probe
+    </wsa:Action>
+  </soap:Header>
+  <soap:Body><wsd:Probe/></soap:Body>
+</soap:Envelope>"#;
+        assert_eq!(action(probe), Some(Action::Known(WsdAction::Probe)));
+
+        let hello = b"<Envelope><Header><Action a=\"1\">\
+            http://docs.oasis-open.org/ws-dd/ns/discovery/2009/01/Hello</Action>";
+        assert_eq!(action(hello), Some(Action::Known(WsdAction::Hello)));
+
+        let other = b"<s:Envelope><s:Header><a:Action>urn:example/Get</a:Action>";
+        assert_eq!(action(other), Some(Action::Other("urn:example/Get")));
+        assert_eq!(action(b"<a:Action/><b>x</b>"), None);
+        assert_eq!(action(b"not xml"), None);
+        assert_eq!(action(b"<a:Action>unterminated"), None);
+        assert!(WsdAction::Resolve.is_request() && !WsdAction::ResolveMatches.is_request());
+    }
+}
@@ -0,0 +1,1085 @@
+//! AF_XDP fast path for `--backend af-xdp`. An XDP program takes the UDP and
+//! TCP frames to the forwarded ports off the interface before the network
+//! stack sees them and hands them to AF_XDP sockets, one per receive queue,
+//! in zero-copy mode where the driver supports it. Every other frame passes
+//! on as usual and reaches the forwarder through the packet socket.
+
+use crate::kernelfilter::Interest;
+use crate::link::{PacketSink, PacketSource};
+use crate::packetsocket::{RawSink, RawSource};
+use crate::pool::PacketBuffer;
+use pnet::datalink::NetworkInterface;
+use socket2::{Domain, Socket as UdpSocket, Type};
+use std::collections::HashMap;
+use std::io;
+use std::mem;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::ptr;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{info, warn};
+
+const BPF_MAP_CREATE: libc::c_long = 0;
+const BPF_MAP_UPDATE_ELEM: libc::c_long = 2;
+const BPF_PROG_LOAD: libc::c_long = 5;
+const BPF_LINK_CREATE: libc::c_long = 28;
+const BPF_LINK_UPDATE: libc::c_long = 29;
+const BPF_MAP_TYPE_XSKMAP: u32 = 17;
+const BPF_PROG_TYPE_XDP: u32 = 6;
+const BPF_XDP: u32 = 37;
+const BPF_PSEUDO_MAP_FD: u8 = 1;
+const BPF_FUNC_REDIRECT_MAP: i32 = 51;
+const XDP_FLAGS_SKB_MODE: u32 = 1 << 1;
+const XDP_FLAGS_DRV_MODE: u32 = 1 << 2;
+const XDP_PASS: i32 = 2;
+const ETHTOOL_GCHANNELS: u32 = 0x3c;
+
+/// eBPF instructions the program is made of
+const LDX_W: u8 = 0x61;
+const LDX_H: u8 = 0x69;
+const LDX_B: u8 = 0x71;
+const MOV_K: u8 = 0xb7;
+const MOV_X: u8 = 0xbf;
+const ADD_K: u8 = 0x07;
+const ADD_X: u8 = 0x0f;
+const AND_K: u8 = 0x57;
+const LSH_K: u8 = 0x67;
+const TO_BE: u8 = 0xdc;
+const JA: u8 = 0x05;
+const JEQ_K: u8 = 0x15;
+const JNE_K: u8 = 0x55;
+const JGT_X: u8 = 0x2d;
+const CALL: u8 = 0x85;
+const EXIT: u8 = 0x95;
+const LD_IMM64: u8 = 0x18;
+
+/// UMEM chunk of one frame, enough for any frame of a standard MTU
+const FRAME_SIZE: u32 = 4096;
+/// Entries of every ring; as many chunks are received into and sent from
+const RING_SIZE: u32 = 512;
+
+const ETHERTYPE_IPV4: i32 = 0x0800;
+const ETHERTYPE_IPV6: i32 = 0x86dd;
+const PROTOCOL_TCP: i32 = 6;
+const PROTOCOL_UDP: i32 = 17;
+
+/// One eBPF instruction, `regs` holding the source register in the upper
+/// and the destination register in the lower nibble
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Insn {
+    code: u8,
+    regs: u8,
+    off: i16,
+    imm: i32,
+}
+
+fn insn(code: u8, dst: u8, src: u8, off: i16, imm: i32) -> Insn {
+    Insn {
+        code,
+        regs: src << 4 | dst,
+        off,
+        imm,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Label {
+    Ipv4,
+    Ipv6,
+    Transport,
+    Udp,
+    Tcp,
+    Redirect,
+    Pass,
+}
+
+/// Program under construction, with jumps to labels resolved at the end
+#[derive(Default)]
+struct Assembler {
+    code: Vec<Insn>,
+    labels: HashMap<Label, usize>,
+    jumps: Vec<(usize, Label)>,
+}
+
+impl Assembler {
+    fn op(&mut self, code: u8, dst: u8, src: u8, off: i16, imm: i32) {
+        self.code.push(insn(code, dst, src, off, imm));
+    }
+
+    fn jump(&mut self, code: u8, dst: u8, src: u8, imm: i32, target: Label) {
+        self.jumps.push((self.code.len(), target));
+        self.op(code, dst, src, 0, imm);
+    }
+
+    fn label(&mut self, label: Label) {
+        self.labels.insert(label, self.code.len());
+    }
+
+    /// Loads the 16-bit big-endian field at `off` from `src` into `dst`
+    fn load_be16(&mut self, dst: u8, src: u8, off: i16) {
+        self.op(LDX_H, dst, src, off, 0);
+        self.op(TO_BE, dst, 0, 0, 16);
+    }
+
+    /// Passes the frame unless `len` bytes from the packet pointer in `src`
+    /// are within it
+    fn check_len(&mut self, src: u8, len: i32) {
+        self.op(MOV_X, 4, src, 0, 0);
+        self.op(ADD_K, 4, 0, 0, len);
+        self.jump(JGT_X, 4, 3, 0, Label::Pass);
+    }
+
+    /// Redirects the frame if the port in `port` is one of `ports`
+    fn ports(&mut self, port: u8, ports: &[u16]) {
+        for &value in ports {
+            self.jump(JEQ_K, port, 0, value.into(), Label::Redirect);
+        }
+    }
+
+    fn finish(mut self) -> Vec<Insn> {
+        for (at, target) in self.jumps {
+            self.code[at].off = (self.labels[&target] as isize - at as isize - 1) as i16;
+        }
+        self.code
+    }
+}
+
+/// Program redirecting the untagged UDP and TCP frames to or from the ports
+/// of `interest` to the socket of their queue in the map `map`, and passing
+/// everything else, all of it without an interest
+fn program(interest: Option<&Interest>, map: RawFd) -> Vec<Insn> {
+    let mut a = Assembler::default();
+    let Some(interest) = interest else {
+        a.op(MOV_K, 0, 0, 0, XDP_PASS);
+        a.op(EXIT, 0, 0, 0, 0);
+        return a.finish();
+    };
+    let udp: Vec<u16> = interest.udp_ports.iter().copied().collect();
+    let tcp: Vec<u16> = interest.tcp_ports.iter().copied().collect();
+    // r6 context, r2 start and r3 end of the frame
+    a.op(MOV_X, 6, 1, 0, 0);
+    a.op(LDX_W, 2, 6, 0, 0);
+    a.op(LDX_W, 3, 6, 4, 0);
+    a.check_len(2, 14);
+    a.load_be16(5, 2, 12);
+    a.jump(JEQ_K, 5, 0, ETHERTYPE_IPV4, Label::Ipv4);
+    if interest.ipv6 {
+        a.jump(JEQ_K, 5, 0, ETHERTYPE_IPV6, Label::Ipv6);
+    }
+    a.jump(JA, 0, 0, 0, Label::Pass);
+
+    // r7 protocol and r8 transport header. Later fragments carry no ports.
+    a.label(Label::Ipv4);
+    a.check_len(2, 34);
+    a.load_be16(5, 2, 20);
+    a.op(AND_K, 5, 0, 0, 0x1fff);
+    a.jump(JNE_K, 5, 0, 0, Label::Pass);
+    a.op(LDX_B, 7, 2, 23, 0);
+    a.op(LDX_B, 5, 2, 14, 0);
+    a.op(AND_K, 5, 0, 0, 0xf);
+    a.op(LSH_K, 5, 0, 0, 2);
+    a.op(MOV_X, 8, 2, 0, 0);
+    a.op(ADD_X, 8, 5, 0, 0);
+    a.op(ADD_K, 8, 0, 0, 14);
+    a.jump(JA, 0, 0, 0, Label::Transport);
+
+    // Extension headers are left to the packet socket
+    a.label(Label::Ipv6);
+    a.check_len(2, 54);
+    a.op(LDX_B, 7, 2, 20, 0);
+    a.op(MOV_X, 8, 2, 0, 0);
+    a.op(ADD_K, 8, 0, 0, 54);
+
+    // r4 source and r5 destination port
+    a.label(Label::Transport);
+    a.check_len(8, 4);
+    a.load_be16(4, 8, 0);
+    a.load_be16(5, 8, 2);
+    if !udp.is_empty() {
+        a.jump(JEQ_K, 7, 0, PROTOCOL_UDP, Label::Udp);
+    }
+    if !tcp.is_empty() {
+        a.jump(JEQ_K, 7, 0, PROTOCOL_TCP, Label::Tcp);
+    }
+    a.jump(JA, 0, 0, 0, Label::Pass);
+    // The verifier refuses unreachable code
+    for (label, ports) in [(Label::Udp, &udp), (Label::Tcp, &tcp)] {
+        if ports.is_empty() {
+            continue;
+        }
+        a.label(label);
+        a.ports(4, ports);
+        a.ports(5, ports);
+        a.jump(JA, 0, 0, 0, Label::Pass);
+    }
+
+    // Frames of queues without a socket pass
+    a.label(Label::Redirect);
+    a.op(LDX_W, 2, 6, 16, 0);
+    a.op(LD_IMM64, 1, BPF_PSEUDO_MAP_FD, 0, map);
+    a.op(0, 0, 0, 0, 0);
+    a.op(MOV_K, 3, 0, 0, XDP_PASS);
+    a.op(CALL, 0, 0, 0, BPF_FUNC_REDIRECT_MAP);
+    a.op(EXIT, 0, 0, 0, 0);
+    a.label(Label::Pass);
+    a.op(MOV_K, 0, 0, 0, XDP_PASS);
+    a.op(EXIT, 0, 0, 0, 0);
+    a.finish()
+}
+
+#[repr(C)]
+struct MapCreate {
+    map_type: u32,
+    key_size: u32,
+    value_size: u32,
+    max_entries: u32,
+}
+
+#[repr(C)]
+struct MapUpdate {
+    map_fd: u32,
+    _pad: u32,
+    key: u64,
+    value: u64,
+    flags: u64,
+}
+
+#[repr(C)]
+struct ProgLoad {
+    prog_type: u32,
+    insn_cnt: u32,
+    insns: u64,
+    license: u64,
+    log_level: u32,
+    log_size: u32,
+    log_buf: u64,
+    kern_version: u32,
+    prog_flags: u32,
+    prog_name: [u8; 16],
+    prog_ifindex: u32,
+    expected_attach_type: u32,
+}
+
+#[repr(C)]
+struct LinkCreate {
+    prog_fd: u32,
+    target_ifindex: u32,
+    attach_type: u32,
+    flags: u32,
+}
+
+#[repr(C)]
+struct LinkUpdate {
+    link_fd: u32,
+    new_prog_fd: u32,
+    flags: u32,
+    old_prog_fd: u32,
+}
+
+/// Runs the `bpf` system call `cmd` on `attr`
+fn bpf<T>(cmd: libc::c_long, attr: &T) -> io::Result<libc::c_long> {
+    // SAFETY: every attribute struct is laid out like the kernel's for the
+    // command and the pointers in it are valid for the duration of the call
+    match unsafe { libc::syscall(libc::SYS_bpf, cmd, attr as *const T, mem::size_of::<T>()) } {
+        -1 => Err(io::Error::last_os_error()),
+        result => Ok(result),
+    }
+}
+
+/// Runs a `bpf` command returning a new descriptor
+fn bpf_fd<T>(cmd: libc::c_long, attr: &T) -> io::Result<OwnedFd> {
+    // SAFETY: the command returned a new descriptor nobody else owns
+    bpf(cmd, attr).map(|fd| unsafe { OwnedFd::from_raw_fd(fd as RawFd) })
+}
+
+/// Loads `program`, with the last line of the verifier log in the error if
+/// the kernel refuses it
+fn load(program: &[Insn]) -> io::Result<OwnedFd> {
+    let license = c"Apache-2.0";
+    let mut attr = ProgLoad {
+        prog_type: BPF_PROG_TYPE_XDP,
+        insn_cnt: program.len() as u32,
+        insns: program.as_ptr() as u64,
+        license: license.as_ptr() as u64,
+        log_level: 0,
+        log_size: 0,
+        log_buf: 0,
+        kern_version: 0,
+        prog_flags: 0,
+        prog_name: *b"nw_pckt_fwd\0\0\0\0\0",
+        prog_ifindex: 0,
+        expected_attach_type: BPF_XDP,
+    };
+    let e = match bpf_fd(BPF_PROG_LOAD, &attr) {
+        Ok(fd) => return Ok(fd),
+        Err(e)
+            if e.raw_os_error() != Some(libc::EACCES) && e.raw_os_error() != Some(libc::EINVAL) =>
+        {
+            return Err(e)
+        }
+        Err(e) => e,
+    };
+    let mut log = vec![0u8; 64 * 1024];
+    attr.log_level = 1;
+    attr.log_size = log.len() as u32;
+    attr.log_buf = log.as_mut_ptr() as u64;
+    if let Ok(fd) = bpf_fd(BPF_PROG_LOAD, &attr) {
+        drop(fd);
+    }
+    let log = String::from_utf8_lossy(&log);
+    // The reason comes last, before the statistics of the run
+    let last = log
+        .trim_end_matches('\0')
+        .lines()
+        .rfind(|line| !line.trim().is_empty() && !line.starts_with("processed "));
+    Err(match last {
+        Some(line) => io::Error::new(e.kind(), format!("{} ({})", e, line.trim())),
+        None => e,
+    })
+}
+
+/// Receive queues of `iface` as ethtool reports them, one if it does not
+fn queues(iface: &NetworkInterface) -> u32 {
+    #[repr(C)]
+    #[derive(Default)]
+    struct Channels {
+        cmd: u32,
+        max_rx: u32,
+        max_tx: u32,
+        max_other: u32,
+        max_combined: u32,
+        rx_count: u32,
+        tx_count: u32,
+        other_count: u32,
+        combined_count: u32,
+    }
+    let Ok(socket) = UdpSocket::new(Domain::IPV4, Type::DGRAM, None) else {
+        return 1;
+    };
+    let mut channels = Channels {
+        cmd: ETHTOOL_GCHANNELS,
+        ..Default::default()
+    };
+    // SAFETY: ifreq is plain data, valid when zeroed; the name fits as the
+    // kernel limits interface names to fewer bytes
+    let mut request: libc::ifreq = unsafe { mem::zeroed() };
+    for (to, from) in request.ifr_name.iter_mut().zip(iface.name.bytes()) {
+        *to = from as libc::c_char;
+    }
+    request.ifr_ifru.ifru_data = ptr::addr_of_mut!(channels).cast();
+    // SAFETY: the request points at the channels struct for the call
+    if unsafe { libc::ioctl(socket.as_raw_fd(), libc::SIOCETHTOOL as _, &mut request) } != 0 {
+        return 1;
+    }
+    (channels.rx_count + channels.combined_count).max(1)
+}
+
+/// XDP program attached to one interface through a link, detached once the
+/// last socket it redirects to is dropped
+pub struct Program {
+    iface: String,
+    map: OwnedFd,
+    link: OwnedFd,
+}
+
+impl Program {
+    /// Replaces the program with one for `interest`
+    pub fn update(&self, interest: Option<&Interest>) {
+        let replaced = load(&program(interest, self.map.as_raw_fd())).and_then(|fd| {
+            let attr = LinkUpdate {
+                link_fd: self.link.as_raw_fd() as u32,
+                new_prog_fd: fd.as_raw_fd() as u32,
+                flags: 0,
+                old_prog_fd: 0,
+            };
+            bpf(BPF_LINK_UPDATE, &attr)
+        });
+        match replaced {
+            Ok(_) => info!("XDP program replaced on {}", self.iface),
+            Err(e) => warn!("Replacing the XDP program on {} failed: {}", self.iface, e),
+        }
+    }
+}
+
+/// Memory mapped from the kernel or anonymous, unmapped when dropped
+struct Mapping {
+    ptr: *mut u8,
+    len: usize,
+}
+
+impl Mapping {
+    fn new(fd: RawFd, len: usize, offset: u64) -> io::Result<Self> {
+        let (flags, fd) = match fd {
+            -1 => (libc::MAP_PRIVATE | libc::MAP_ANONYMOUS, -1),
+            fd => (libc::MAP_SHARED | libc::MAP_POPULATE, fd),
+        };
+        // SAFETY: a new mapping nothing else refers to
+        let ptr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                flags,
+                fd,
+                offset as libc::off_t,
+            )
+        };
+        match ptr {
+            libc::MAP_FAILED => Err(io::Error::last_os_error()),
+            ptr => Ok(Mapping {
+                ptr: ptr.cast(),
+                len,
+            }),
+        }
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        // SAFETY: the mapping is no longer referred to
+        unsafe { libc::munmap(self.ptr.cast(), self.len) };
+    }
+}
+
+/// Ring shared with the kernel, of `T` entries
+struct Ring<T> {
+    mapping: Mapping,
+    producer: usize,
+    consumer: usize,
+    descs: usize,
+    entry: std::marker::PhantomData<T>,
+}
+
+impl<T> Ring<T> {
+    fn map(fd: RawFd, offsets: &libc::xdp_ring_offset, pgoff: u64) -> io::Result<Self> {
+        let len = offsets.desc as usize + RING_SIZE as usize * mem::size_of::<T>();
+        Ok(Ring {
+            mapping: Mapping::new(fd, len, pgoff)?,
+            producer: offsets.producer as usize,
+            consumer: offsets.consumer as usize,
+            descs: offsets.desc as usize,
+            entry: std::marker::PhantomData,
+        })
+    }
+
+    fn index(&self, at: usize) -> &AtomicU32 {
+        // SAFETY: the kernel places the aligned index at this offset of the
+        // mapping, which lives as long as the ring
+        unsafe { &*self.mapping.ptr.add(at).cast::<AtomicU32>() }
+    }
+
+    fn producer(&self) -> &AtomicU32 {
+        self.index(self.producer)
+    }
+
+    fn consumer(&self) -> &AtomicU32 {
+        self.index(self.consumer)
+    }
+
+    /// Entry `at`, wrapping around the ring
+    fn entry(&self, at: u32) -> *mut T {
+        let index = (at & (RING_SIZE - 1)) as usize;
+        // SAFETY: the index is within the entries of the mapping
+        unsafe { self.mapping.ptr.add(self.descs).cast::<T>().add(index) }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.producer().load(Ordering::Acquire) == self.consumer().load(Ordering::Relaxed)
+    }
+
+    /// Takes the next entry the kernel produced, if any. Only one thread
+    /// may consume from a ring.
+    fn pop(&self) -> Option<T> {
+        let at = self.consumer().load(Ordering::Relaxed);
+        if self.producer().load(Ordering::Acquire) == at {
+            return None;
+        }
+        // SAFETY: the kernel finished the entry before moving the producer
+        let entry = unsafe { self.entry(at).read() };
+        self.consumer().store(at.wrapping_add(1), Ordering::Release);
+        Some(entry)
+    }
+}
+
+/// AF_XDP socket bound to one receive queue, with its UMEM: the first
+/// `RING_SIZE` chunks are received into, the others sent from
+struct Socket {
+    fd: OwnedFd,
+    umem: Mapping,
+    fill: Ring<u64>,
+    completion: Ring<u64>,
+    rx: Ring<libc::xdp_desc>,
+    /// Only on the socket frames are sent through
+    tx: Option<Ring<libc::xdp_desc>>,
+    zerocopy: bool,
+}
+
+// SAFETY: the mappings are only written to through the rings, each used by
+// a single thread: the receiving half uses the fill and receive rings and the
+// chunks received into, the sending half the others
+unsafe impl Send for Socket {}
+unsafe impl Sync for Socket {}
+
+fn setsockopt<T>(fd: RawFd, name: libc::c_int, value: &T) -> io::Result<()> {
+    // SAFETY: the value is valid for the length given
+    match unsafe {
+        libc::setsockopt(
+            fd,
+            libc::SOL_XDP,
+            name,
+            (value as *const T).cast(),
+            mem::size_of::<T>() as libc::socklen_t,
+        )
+    } {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+fn getsockopt<T>(fd: RawFd, name: libc::c_int) -> io::Result<T> {
+    // SAFETY: only used with plain data structs, valid when zeroed and
+    // filled in by the kernel up to the length given
+    unsafe {
+        let mut value: T = mem::zeroed();
+        let mut len = mem::size_of::<T>() as libc::socklen_t;
+        match libc::getsockopt(
+            fd,
+            libc::SOL_XDP,
+            name,
+            ptr::addr_of_mut!(value).cast(),
+            &mut len,
+        ) {
+            0 => Ok(value),
+            _ => Err(io::Error::last_os_error()),
+        }
+    }
+}
+
+impl Socket {
+    fn open(ifindex: u32, queue: u32, sending: bool) -> io::Result<Self> {
+        // SAFETY: plain system call
+        let fd = match unsafe { libc::socket(libc::AF_XDP, libc::SOCK_RAW | libc::SOCK_CLOEXEC, 0) }
+        {
+            -1 => return Err(io::Error::last_os_error()),
+            // SAFETY: the descriptor is new and owned from here on
+            fd => unsafe { OwnedFd::from_raw_fd(fd) },
+        };
+        let raw = fd.as_raw_fd();
+        let umem = Mapping::new(-1, (2 * RING_SIZE * FRAME_SIZE) as usize, 0)?;
+        setsockopt(
+            raw,
+            libc::XDP_UMEM_REG,
+            &libc::xdp_umem_reg {
+                addr: umem.ptr as u64,
+                len: umem.len as u64,
+                chunk_size: FRAME_SIZE,
+                headroom: 0,
+                flags: 0,
+                tx_metadata_len: 0,
+            },
+        )?;
+        setsockopt(raw, libc::XDP_UMEM_FILL_RING, &RING_SIZE)?;
+        setsockopt(raw, libc::XDP_UMEM_COMPLETION_RING, &RING_SIZE)?;
+        setsockopt(raw, libc::XDP_RX_RING, &RING_SIZE)?;
+        if sending {
+            setsockopt(raw, libc::XDP_TX_RING, &RING_SIZE)?;
+        }
+        let offsets: libc::xdp_mmap_offsets = getsockopt(raw, libc::XDP_MMAP_OFFSETS)?;
+        let tx = match sending {
+            true => Some(Ring::map(raw, &offsets.tx, libc::XDP_PGOFF_TX_RING as u64)?),
+            false => None,
+        };
+        let socket = Socket {
+            fill: Ring::map(raw, &offsets.fr, libc::XDP_UMEM_PGOFF_FILL_RING)?,
+            completion: Ring::map(raw, &offsets.cr, libc::XDP_UMEM_PGOFF_COMPLETION_RING)?,
+            rx: Ring::map(raw, &offsets.rx, libc::XDP_PGOFF_RX_RING as u64)?,
+            tx,
+            umem,
+            fd,
+            zerocopy: false,
+        };
+        // Every chunk of the receiving half waits in the fill ring
+        for chunk in 0..RING_SIZE {
+            // SAFETY: the ring has room for all of them
+            unsafe {
+                socket
+                    .fill
+                    .entry(chunk)
+                    .write(u64::from(chunk * FRAME_SIZE))
+            };
+        }
+        socket.fill.producer().store(RING_SIZE, Ordering::Release);
+
+        // Without a mode flag the kernel tries zero-copy before copying
+        let address = libc::sockaddr_xdp {
+            sxdp_family: libc::AF_XDP as u16,
+            sxdp_flags: 0,
+            sxdp_ifindex: ifindex,
+            sxdp_queue_id: queue,
+            sxdp_shared_umem_fd: 0,
+        };
+        // SAFETY: the address is valid for the length given
+        if unsafe {
+            libc::bind(
+                raw,
+                ptr::addr_of!(address).cast(),
+                mem::size_of_val(&address) as libc::socklen_t,
+            )
+        } != 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+        let options: libc::xdp_options = getsockopt(raw, libc::XDP_OPTIONS)?;
+        Ok(Socket {
+            zerocopy: options.flags & libc::XDP_OPTIONS_ZEROCOPY != 0,
+            ..socket
+        })
+    }
+
+    /// Bytes of the UMEM chunk at `addr`
+    fn chunk(&self, addr: u64, len: usize) -> *mut u8 {
+        debug_assert!(addr as usize + len <= self.umem.len);
+        // SAFETY: the kernel only hands out addresses within the UMEM
+        unsafe { self.umem.ptr.add(addr as usize) }
+    }
+
+    /// Makes the kernel send what is in the transmit ring. It does up to a
+    /// budget per call and asks to be called again for the rest.
+    fn kick(&self) -> io::Result<()> {
+        for _ in 0..RING_SIZE / 32 {
+            // SAFETY: sending nothing, no buffer is passed
+            let sent = unsafe {
+                libc::sendto(
+                    self.fd.as_raw_fd(),
+                    ptr::null(),
+                    0,
+                    libc::MSG_DONTWAIT,
+                    ptr::null(),
+                    0,
+                )
+            };
+            if sent >= 0 {
+                return Ok(());
+            }
+            match io::Error::last_os_error() {
+                e if e.kind() == io::ErrorKind::WouldBlock => continue,
+                // The frames stay queued for the next call
+                e if matches!(e.raw_os_error(), Some(libc::EBUSY | libc::ENOBUFS)) => return Ok(()),
+                e => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// AF_XDP sockets of every receive queue of an interface with the program
+/// redirecting to them, not yet paired with the packet socket
+pub struct FastPath {
+    sockets: Vec<Arc<Socket>>,
+    program: Arc<Program>,
+}
+
+impl FastPath {
+    /// Binds a socket to each receive queue of `iface` and attaches the
+    /// program for `interest`, in driver mode if possible and generic mode
+    /// otherwise
+    pub fn open(iface: &NetworkInterface, interest: Option<&Interest>) -> io::Result<Self> {
+        let queues = queues(iface);
+        let map = bpf_fd(
+            BPF_MAP_CREATE,
+            &MapCreate {
+                map_type: BPF_MAP_TYPE_XSKMAP,
+                key_size: 4,
+                value_size: 4,
+                max_entries: queues,
+            },
+        )?;
+        let mut sockets = Vec::new();
+        for queue in 0..queues {
+            let socket = Socket::open(iface.index, queue, queue == 0)?;
+            let fd = socket.fd.as_raw_fd();
+            let attr = MapUpdate {
+                map_fd: map.as_raw_fd() as u32,
+                _pad: 0,
+                key: ptr::addr_of!(queue) as u64,
+                value: ptr::addr_of!(fd) as u64,
+                flags: 0,
+            };
+            bpf(BPF_MAP_UPDATE_ELEM, &attr)?;
+            sockets.push(Arc::new(socket));
+        }
+        let fd = load(&program(interest, map.as_raw_fd()))?;
+        let attach = |flags| {
+            bpf_fd(
+                BPF_LINK_CREATE,
+                &LinkCreate {
+                    prog_fd: fd.as_raw_fd() as u32,
+                    target_ifindex: iface.index,
+                    attach_type: BPF_XDP,
+                    flags,
+                },
+            )
+        };
+        let (link, mode) = match attach(XDP_FLAGS_DRV_MODE) {
+            Ok(link) => (link, "driver"),
+            Err(_) => (attach(XDP_FLAGS_SKB_MODE)?, "generic"),
+        };
+        let zerocopy = sockets.iter().all(|socket| socket.zerocopy);
+        info!(
+            "XDP program attached on {} in {} mode, {} AF_XDP socket(s) in {} mode",
+            iface.name,
+            mode,
+            sockets.len(),
+            if zerocopy { "zero-copy" } else { "copy" }
+        );
+        Ok(FastPath {
+            sockets,
+            program: Arc::new(Program {
+                iface: iface.name.clone(),
+                map,
+                link,
+            }),
+        })
+    }
+
+    pub fn program(&self) -> &Arc<Program> {
+        &self.program
+    }
+
+    /// Channel receiving from the sockets and `rx`, waiting up to `timeout`
+    /// for either, and sending through the socket of the first queue, or
+    /// `tx` for frames that do not fit a chunk. `rx` must not wait itself.
+    pub fn channel(
+        self,
+        tx: RawSink,
+        rx: RawSource,
+        timeout: Option<Duration>,
+        batch: usize,
+    ) -> (XdpSink, XdpSource) {
+        let sink = XdpSink {
+            socket: self.sockets[0].clone(),
+            fallback: tx,
+            free: (RING_SIZE..2 * RING_SIZE)
+                .map(|chunk| u64::from(chunk * FRAME_SIZE))
+                .collect(),
+            batch,
+            _program: self.program.clone(),
+        };
+        let source = XdpSource {
+            sockets: self.sockets,
+            fallback: rx,
+            timeout,
+            lent: None,
+            next: 0,
+            _program: self.program,
+        };
+        (sink, source)
+    }
+}
+
+/// Receiving half: frames the program redirected first, then those of the
+/// packet socket
+pub struct XdpSource {
+    sockets: Vec<Arc<Socket>>,
+    fallback: RawSource,
+    timeout: Option<Duration>,
+    /// Socket and chunk of the frame handed out last, given back to the
+    /// fill ring on the next read
+    lent: Option<(usize, u64)>,
+    /// Socket to look at first, so a busy queue does not starve the others
+    next: usize,
+    _program: Arc<Program>,
+}
+
+impl XdpSource {
+    fn give_back(&mut self) {
+        if let Some((index, addr)) = self.lent.take() {
+            let fill = &self.sockets[index].fill;
+            let at = fill.producer().load(Ordering::Relaxed);
+            // SAFETY: the ring has room for every chunk of the receiving half
+            unsafe { fill.entry(at).write(addr & !u64::from(FRAME_SIZE - 1)) };
+            fill.producer().store(at.wrapping_add(1), Ordering::Release);
+        }
+    }
+
+    /// Takes the next redirected frame of any socket
+    fn take(&mut self) -> Option<&[u8]> {
+        let count = self.sockets.len();
+        for offset in 0..count {
+            let index = (self.next + offset) % count;
+            let socket = &self.sockets[index];
+            if let Some(desc) = socket.rx.pop() {
+                self.next = (index + 1) % count;
+                self.lent = Some((index, desc.addr));
+                let len = desc.len as usize;
+                // SAFETY: the chunk is ours until given back to the kernel
+                return Some(unsafe {
+                    std::slice::from_raw_parts(socket.chunk(desc.addr, len), len)
+                });
+            }
+        }
+        None
+    }
+
+    /// Waits until a socket or the packet socket has frames
+    fn wait(&self) -> io::Result<()> {
+        let mut fds: Vec<libc::pollfd> = self
+            .sockets
+            .iter()
+            .map(|socket| socket.fd.as_raw_fd())
+            .chain([self.fallback.fd()])
+            .map(|fd| libc::pollfd {
+                fd,
+                events: libc::POLLIN,
+                revents: 0,
+            })
+            .collect();
+        let timeout = self
+            .timeout
+            .map_or(-1, |timeout| timeout.as_millis() as libc::c_int);
+        // SAFETY: the pollfds are valid for the duration of the call
+        match unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout) } {
+            -1 => Err(io::Error::last_os_error()),
+            0 => Err(io::Error::new(io::ErrorKind::TimedOut, "Timed out")),
+            _ if fds.iter().any(|fd| fd.revents & !libc::POLLIN != 0) => {
+                Err(io::Error::other("Unexpected poll event"))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+impl PacketSource for XdpSource {
+    fn next(&mut self) -> io::Result<&[u8]> {
+        self.give_back();
+        if !self.fallback.pending() && self.sockets.iter().all(|socket| socket.rx.is_empty()) {
+            self.wait()?;
+        }
+        if self.sockets.iter().any(|socket| !socket.rx.is_empty()) {
+            return self
+                .take()
+                .ok_or_else(|| io::Error::new(io::ErrorKind::TimedOut, "Timed out"));
+        }
+        self.fallback.next()
+    }
+}
+
+/// Sending half: frames are copied into the chunks of the sending half and
+/// queued on the transmit ring
+pub struct XdpSink {
+    socket: Arc<Socket>,
+    fallback: RawSink,
+    /// Chunks not queued to the kernel
+    free: Vec<u64>,
+    batch: usize,
+    _program: Arc<Program>,
+}
+
+impl XdpSink {
+    /// Queues as many of `frames` as there are free chunks and sends them
+    fn transmit<'a>(&mut self, frames: impl Iterator<Item = &'a [u8]>) -> io::Result<usize> {
+        while let Some(addr) = self.socket.completion.pop() {
+            self.free.push(addr);
+        }
+        let tx = self
+            .socket
+            .tx
+            .as_ref()
+            .expect("socket of the first queue sends");
+        let at = tx.producer().load(Ordering::Relaxed);
+        let mut queued = 0;
+        for frame in frames.take(self.batch) {
+            if frame.len() > FRAME_SIZE as usize {
+                break;
+            }
+            let Some(addr) = self.free.pop() else {
+                break;
+            };
+            let desc = libc::xdp_desc {
+                addr,
+                len: frame.len() as u32,
+                options: 0,
+            };
+            // SAFETY: the chunk is free and the ring has room for every
+            // chunk of the sending half
+            unsafe {
+                ptr::copy_nonoverlapping(
+                    frame.as_ptr(),
+                    self.socket.chunk(addr, frame.len()),
+                    frame.len(),
+                );
+                tx.entry(at.wrapping_add(queued)).write(desc);
+            }
+            queued += 1;
+        }
+        tx.producer()
+            .store(at.wrapping_add(queued), Ordering::Release);
+        self.socket.kick()?;
+        match queued {
+            // Completions come back once the kernel sent what is queued
+            0 => Err(io::Error::from(io::ErrorKind::WouldBlock)),
+            queued => Ok(queued as usize),
+        }
+    }
+}
+
+impl PacketSink for XdpSink {
+    fn send(&mut self, frame: &[u8]) -> io::Result<()> {
+        if frame.len() > FRAME_SIZE as usize {
+            return self.fallback.send(frame);
+        }
+        self.transmit(std::iter::once(frame)).map(|_| ())
+    }
+
+    fn batch_size(&self) -> usize {
+        self.batch
+    }
+
+    fn send_batch(&mut self, frames: &[PacketBuffer]) -> io::Result<usize> {
+        if frames[0].len() > FRAME_SIZE as usize {
+            return self.fallback.send(&frames[0]).map(|()| 1);
+        }
+        self.transmit(frames.iter().map(|frame| frame.as_slice()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const XDP_REDIRECT: u64 = 4;
+
+    /// Runs the subset of eBPF the programs use on `frame` received on
+    /// queue 1, failing on any access outside the frame
+    fn run(program: &[Insn], frame: &[u8]) -> u64 {
+        // Frame and context addresses as the program sees them
+        const DATA: u64 = 0x1000;
+        let mut r = [0u64; 11];
+        let mut pc = 0;
+        loop {
+            let op = program[pc];
+            let (dst, src) = ((op.regs & 0xf) as usize, (op.regs >> 4) as usize);
+            let imm = op.imm as i64 as u64;
+            let off = op.off as i64 as u64;
+            let read = |address: u64, len: usize| {
+                let at = address.checked_sub(DATA).expect("read before the frame") as usize;
+                assert!(at + len <= frame.len(), "read beyond the frame at {}", at);
+                frame[at..at + len]
+                    .iter()
+                    .rev()
+                    .fold(0, |value, &byte| value << 8 | u64::from(byte))
+            };
+            pc += 1;
+            match op.code {
+                LDX_W if src == 6 || src == 1 => {
+                    r[dst] = match op.off {
+                        0 => DATA,
+                        4 => DATA + frame.len() as u64,
+                        16 => 1,
+                        off => panic!("context field {}", off),
+                    }
+                }
+                LDX_H => r[dst] = read(r[src].wrapping_add(off), 2),
+                LDX_B => r[dst] = read(r[src].wrapping_add(off), 1),
+                MOV_K => r[dst] = imm,
+                MOV_X => r[dst] = r[src],
+                ADD_K => r[dst] = r[dst].wrapping_add(imm),
+                ADD_X => r[dst] = r[dst].wrapping_add(r[src]),
+                AND_K => r[dst] &= imm,
+                LSH_K => r[dst] <<= imm,
+                TO_BE => r[dst] = u64::from((r[dst] as u16).swap_bytes()),
+                JA => pc = (pc as isize + op.off as isize) as usize,
+                JEQ_K if r[dst] == imm => pc = (pc as isize + op.off as isize) as usize,
+                JNE_K if r[dst] != imm => pc = (pc as isize + op.off as isize) as usize,
+                JGT_X if r[dst] > r[src] => pc = (pc as isize + op.off as isize) as usize,
+                JEQ_K | JNE_K | JGT_X => {}
+                LD_IMM64 => {
+                    r[dst] = imm;
+                    pc += 1;
+                }
+                CALL => {
+                    assert_eq!((r[1], r[2], r[3]), (7, 1, XDP_PASS as u64));
+                    r[0] = XDP_REDIRECT;
+                }
+                EXIT => return r[0],
+                code => panic!("unexpected instruction {:#x}", code),
+            }
+        }
+    }
+
+    fn frame(ethertype: u16, protocol: u8, ports: (u16, u16), len: usize) -> Vec<u8> {
+        let mut frame = vec![0u8; 64];
+        frame[12..14].copy_from_slice(&ethertype.to_be_bytes());
+        let transport = if ethertype == ETHERTYPE_IPV6 as u16 {
+            frame[20] = protocol;
+            54
+        } else {
+            // IPv4 header with options, 24 bytes
+            frame[14] = 0x46;
+            frame[23] = protocol;
+            38
+        };
+        frame[transport..transport + 2].copy_from_slice(&ports.0.to_be_bytes());
+        frame[transport + 2..transport + 4].copy_from_slice(&ports.1.to_be_bytes());
+        frame.truncate(len);
+        frame
+    }
+
+    #[test]
+    fn redirects_only_the_forwarded_ports() {
+        let interest = Interest {
+            udp_ports: [1900, 5353].into(),
+            tcp_ports: [8009].into(),
+            arp: true,
+            ipv6: true,
+            control: true,
+            wol: false,
+            eapol: false,
+            lldp: false,
+        };
+        let program = program(Some(&interest), 7);
+        let (ipv4, ipv6) = (ETHERTYPE_IPV4 as u16, ETHERTYPE_IPV6 as u16);
+        let udp = PROTOCOL_UDP as u8;
+        let pass = XDP_PASS as u64;
+        assert_eq!(
+            run(&program, &frame(ipv4, udp, (50000, 1900), 64)),
+            XDP_REDIRECT
+        );
+        assert_eq!(
+            run(&program, &frame(ipv4, udp, (5353, 40000), 64)),
+            XDP_REDIRECT
+        );
+        assert_eq!(
+            run(&program, &frame(ipv6, udp, (50000, 5353), 64)),
+            XDP_REDIRECT
+        );
+        assert_eq!(
+            run(&program, &frame(ipv6, 6, (8009, 50000), 64)),
+            XDP_REDIRECT
+        );
+        assert_eq!(run(&program, &frame(ipv4, udp, (50000, 1901), 64)), pass);
+        assert_eq!(run(&program, &frame(ipv4, 6, (50000, 1900), 64)), pass);
+        // Control traffic, ARP and tagged frames are left to the packet socket
+        assert_eq!(run(&program, &frame(ipv4, 2, (0, 0), 64)), pass);
+        assert_eq!(run(&program, &frame(0x0806, 0, (0, 0), 64)), pass);
+        assert_eq!(run(&program, &frame(0x8100, 0, (0, 0), 64)), pass);
+        // Truncated frames and later fragments pass without reading beyond
+        for len in [0, 13, 14, 33, 40] {
+            assert_eq!(run(&program, &frame(ipv4, udp, (50000, 1900), len)), pass);
+        }
+        let mut fragment = frame(ipv4, udp, (50000, 1900), 64);
+        fragment[21] = 0x10;
+        assert_eq!(run(&program, &fragment), pass);
+
+        let ipv4_only = Interest {
+            ipv6: false,
+            ..interest
+        };
+        let program = super::program(Some(&ipv4_only), 7);
+        assert_eq!(run(&program, &frame(ipv6, udp, (50000, 5353), 64)), pass);
+        let program = super::program(None, 7);
+        assert_eq!(run(&program, &frame(ipv4, udp, (50000, 1900), 64)), pass);
+    }
+}
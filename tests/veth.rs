@@ -0,0 +1,216 @@
+//! End-to-end test of the real datalink path: the binary forwards between two
+//! veth pairs whose far ends sit in their own network namespaces.
+//!
+//! Needs root (or CAP_NET_ADMIN and CAP_SYS_ADMIN) and iproute2, so it only
+//! runs with `cargo test -- --ignored`.
+
+use pnet::datalink::{self, Channel, DataLinkReceiver, DataLinkSender};
+use pnet::packet::ethernet::{EtherTypes, EthernetPacket, MutableEthernetPacket};
+use pnet::packet::ip::IpNextHeaderProtocols;
+use pnet::packet::ipv4::{self, Ipv4Packet, MutableIpv4Packet};
+use pnet::packet::udp::{MutableUdpPacket, UdpPacket};
+use pnet::packet::Packet;
+use pnet::util::MacAddr;
+use std::fs::File;
+use std::io;
+use std::net::Ipv4Addr;
+use std::os::fd::AsRawFd;
+use std::process::{Child, Command, Stdio};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const SSDP_GROUP: Ipv4Addr = Ipv4Addr::new(239, 255, 255, 250);
+const SSDP_MAC: MacAddr = MacAddr(0x01, 0x00, 0x5e, 0x7f, 0xff, 0xfa);
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(10);
+const READ_TIMEOUT: Duration = Duration::from_millis(100);
+
+fn ip(args: &[&str]) {
+    let status = Command::new("ip")
+        .args(args)
+        .status()
+        .expect("iproute2 is installed");
+    assert!(status.success(), "ip {} failed", args.join(" "));
+}
+
+/// Network namespaces deleted on drop, taking the veth ends in them along
+struct Namespaces(Vec<String>);
+
+impl Namespaces {
+    fn create(names: &[&str]) -> Self {
+        let mut namespaces = Namespaces(Vec::new());
+        for name in names {
+            let name = format!("nwfwd-{}-{}", std::process::id(), name);
+            ip(&["netns", "add", &name]);
+            namespaces.0.push(name);
+        }
+        namespaces
+    }
+}
+
+impl Drop for Namespaces {
+    fn drop(&mut self) {
+        for name in &self.0 {
+            let _ = Command::new("ip").args(["netns", "del", name]).status();
+        }
+    }
+}
+
+/// Forwarder process killed on drop
+struct Forwarder(Child);
+
+impl Drop for Forwarder {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+/// Opens a datalink channel on `iface` in namespace `netns`. The sockets stay
+/// in that namespace whichever thread uses them afterwards.
+fn channel(netns: &str, iface: &str) -> (Box<dyn DataLinkSender>, Box<dyn DataLinkReceiver>) {
+    let path = format!("/var/run/netns/{}", netns);
+    let iface = iface.to_string();
+    thread::spawn(move || {
+        let netns = File::open(path)?;
+        if unsafe { libc::setns(netns.as_raw_fd(), libc::CLONE_NEWNET) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let interface = datalink::interfaces()
+            .into_iter()
+            .find(|i| i.name == iface)
+            .ok_or_else(|| io::Error::other("interface not found"))?;
+        let config = datalink::Config {
+            read_timeout: Some(READ_TIMEOUT),
+            ..Default::default()
+        };
+        match datalink::channel(&interface, config)? {
+            Channel::Ethernet(tx, rx) => Ok((tx, rx)),
+            _ => Err(io::Error::other("unsupported channel type")),
+        }
+    })
+    .join()
+    .unwrap()
+    .expect("channel opened")
+}
+
+fn udp_frame(dport: u16, payload: &[u8]) -> Vec<u8> {
+    let source = Ipv4Addr::new(192, 168, 100, 5);
+    let mut frame = vec![0; 14 + 20 + 8 + payload.len()];
+    let mut eth = MutableEthernetPacket::new(&mut frame).unwrap();
+    eth.set_source(MacAddr::new(0x02, 0, 0, 0, 0, 1));
+    eth.set_destination(SSDP_MAC);
+    eth.set_ethertype(EtherTypes::Ipv4);
+    let mut udp = MutableUdpPacket::new(&mut frame[34..]).unwrap();
+    udp.set_source(50000);
+    udp.set_destination(dport);
+    udp.set_length((8 + payload.len()) as u16);
+    udp.set_payload(payload);
+    let mut ip = MutableIpv4Packet::new(&mut frame[14..]).unwrap();
+    ip.set_version(4);
+    ip.set_header_length(5);
+    ip.set_total_length((20 + 8 + payload.len()) as u16);
+    ip.set_ttl(2);
+    ip.set_next_level_protocol(IpNextHeaderProtocols::Udp);
+    ip.set_source(source);
+    ip.set_destination(SSDP_GROUP);
+    ip.set_checksum(ipv4::checksum(&ip.to_immutable()));
+    frame
+}
+
+fn m_search(target: &str) -> Vec<u8> {
+    let message = format!(
+        "M-SEARCH * HTTP/1.1\r\nHOST: 239.255.255.250:1900\r\n\
+        MAN: \"ssdp:discover\"\r\nMX: 1\r\nST: {}\r\n\r\n",
+        target
+    );
+    udp_frame(1900, message.as_bytes())
+}
+
+/// UDP payloads of the IPv4 frames received on `rx` until `until` has been
+/// seen or `timeout` passed
+fn receive_until(rx: &mut dyn DataLinkReceiver, until: &[u8], timeout: Duration) -> Vec<Vec<u8>> {
+    let deadline = Instant::now() + timeout;
+    let mut payloads = Vec::new();
+    while Instant::now() < deadline {
+        let Ok(frame) = rx.next() else {
+            continue;
+        };
+        let Some(eth) = EthernetPacket::new(frame) else {
+            continue;
+        };
+        if eth.get_ethertype() != EtherTypes::Ipv4 {
+            continue;
+        }
+        let Some(udp) = Ipv4Packet::new(eth.payload())
+            .filter(|ip| ip.get_next_level_protocol() == IpNextHeaderProtocols::Udp)
+            .and_then(|ip| UdpPacket::owned(ip.payload().to_vec()))
+        else {
+            continue;
+        };
+        payloads.push(udp.payload().to_vec());
+        if udp.payload() == until {
+            break;
+        }
+    }
+    payloads
+}
+
+#[test]
+#[ignore = "needs root to create network namespaces"]
+fn forwards_ssdp_between_namespaces() {
+    let namespaces = Namespaces::create(&["fwd", "ext", "int"]);
+    let [fwd, ext, int] = [&namespaces.0[0], &namespaces.0[1], &namespaces.0[2]];
+    ip(&[
+        "link", "add", "ext0", "netns", fwd, "type", "veth", "peer", "ext1", "netns", ext,
+    ]);
+    ip(&[
+        "link", "add", "int0", "netns", fwd, "type", "veth", "peer", "int1", "netns", int,
+    ]);
+    for (netns, iface) in [(fwd, "ext0"), (fwd, "int0"), (ext, "ext1"), (int, "int1")] {
+        ip(&["-n", netns, "link", "set", iface, "up"]);
+    }
+
+    let _forwarder = Forwarder(
+        Command::new("ip")
+            .args(["netns", "exec", fwd, env!("CARGO_BIN_EXE_nw-pckt-fwd")])
+            .args(["--external-iface", "ext0", "--internal-iface", "int0"])
+            // Nothing joins the SSDP group on the external side
+            .args(["--no-snooping", "--log-level", "warn"])
+            .stdout(Stdio::null())
+            .spawn()
+            .expect("forwarder started"),
+    );
+    let (mut int_tx, _) = channel(int, "int1");
+    let (_, mut ext_rx) = channel(ext, "ext1");
+
+    // Keep searching until the forwarder has its channels open
+    let (stop, stopped) = mpsc::channel();
+    let search = m_search("ssdp:all");
+    let sender = thread::spawn({
+        let search = search.clone();
+        move || {
+            while stopped.recv_timeout(READ_TIMEOUT).is_err() {
+                let _ = int_tx.send_to(&search, None);
+            }
+            int_tx
+        }
+    });
+    let seen = receive_until(ext_rx.as_mut(), &search[42..], STARTUP_TIMEOUT);
+    stop.send(()).unwrap();
+    let mut int_tx = sender.join().unwrap();
+    assert!(
+        seen.contains(&search[42..].to_vec()),
+        "M-SEARCH not forwarded"
+    );
+
+    // The non-matching datagram is sent first, so it would arrive ahead of
+    // the marker search if it were forwarded
+    let other = udp_frame(5000, b"not forwarded");
+    let marker = m_search("urn:dial-multiscreen-org:service:dial:1");
+    int_tx.send_to(&other, None).unwrap().unwrap();
+    int_tx.send_to(&marker, None).unwrap().unwrap();
+    let seen = receive_until(ext_rx.as_mut(), &marker[42..], STARTUP_TIMEOUT);
+    assert_eq!(seen.last().map(Vec::as_slice), Some(&marker[42..]));
+    assert!(!seen.contains(&other[42..].to_vec()), "port 5000 forwarded");
+}